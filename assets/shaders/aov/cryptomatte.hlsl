@@ -0,0 +1,121 @@
+#include "../inc/frame_constants.hlsl"
+
+[[vk::binding(0)]] Texture2D<uint> object_id_tex;
+// Indexed by object ID - 1; the Cryptomatte hash of the object's name.
+[[vk::binding(1)]] StructuredBuffer<uint> object_hashes_dyn;
+[[vk::binding(2)]] Texture2D<float4> history0_tex;
+[[vk::binding(3)]] Texture2D<float4> history1_tex;
+[[vk::binding(4)]] Texture2D<float4> history2_tex;
+[[vk::binding(5)]] RWTexture2D<float4> output0_tex;
+[[vk::binding(6)]] RWTexture2D<float4> output1_tex;
+[[vk::binding(7)]] RWTexture2D<float4> output2_tex;
+[[vk::binding(8)]] cbuffer _ {
+    float4 input_tex_size;
+    // Frames in the history; zero starts over.
+    uint accumulated_frames;
+};
+
+// Must match `CRYPTOMATTE_RANKS` in `cryptomatte.rs`
+#define RANK_COUNT 6
+
+#define SAMPLE_COUNT 9
+
+// Of the pixel filter, in pixels
+static const float FILTER_SIGMA = 0.5;
+
+// The objects of this frame's samples, and of the history's ranks
+static uint ids[SAMPLE_COUNT + RANK_COUNT];
+static float weights[SAMPLE_COUNT + RANK_COUNT];
+static uint id_count = 0;
+
+void add_weight(uint id, float weight) {
+    uint i = 0;
+    for (; i < id_count && ids[i] != id; ++i) {}
+
+    if (i == id_count) {
+        ids[i] = id;
+        weights[i] = 0;
+        ++id_count;
+    }
+
+    weights[i] += weight;
+}
+
+void add_history_pair(float2 pair, float history_weight) {
+    // Empty ranks have no coverage.
+    if (pair.y > 0) {
+        add_weight(asuint(pair.x), pair.y * history_weight);
+    }
+}
+
+// Ranks the objects sampled around each pixel by their filtered coverage, averaged
+// with that of the previous `accumulated_frames` frames, at their jitter offsets.
+[numthreads(8, 8, 1)]
+void main(int2 px: SV_DispatchThreadID) {
+    // The jitter moves the rasterized samples away from pixel centers.
+    // Screen-space y points the other way from clip space.
+    const float2 sample_offset = frame_constants.view_constants.sample_offset_pixels * float2(1, -1);
+
+    float total_weight = 0;
+
+    for (int y = -1; y <= 1; ++y) {
+        for (int x = -1; x <= 1; ++x) {
+            const int2 sample_px = px + int2(x, y);
+            if (any(sample_px < 0) || any(sample_px >= int2(input_tex_size.xy))) {
+                continue;
+            }
+
+            const float2 offset = float2(x, y) + sample_offset;
+            const float weight = exp(-dot(offset, offset) / (2 * FILTER_SIGMA * FILTER_SIGMA));
+            total_weight += weight;
+
+            // The background has no ID, and only counts towards the total.
+            const uint object_id = object_id_tex[sample_px];
+            if (object_id != 0) {
+                add_weight(object_hashes_dyn[object_id - 1], weight);
+            }
+        }
+    }
+
+    // A running mean of the coverage, with this frame's normalized by the filter's total weight.
+    const float frame_weight = 1.0 / (accumulated_frames + 1);
+    for (uint i = 0; i < id_count; ++i) {
+        weights[i] *= frame_weight / total_weight;
+    }
+
+    if (accumulated_frames > 0) {
+        const float history_weight = 1.0 - frame_weight;
+        const float4 history[RANK_COUNT / 2] = { history0_tex[px], history1_tex[px], history2_tex[px] };
+        for (uint layer = 0; layer < RANK_COUNT / 2; ++layer) {
+            add_history_pair(history[layer].xy, history_weight);
+            add_history_pair(history[layer].zw, history_weight);
+        }
+    }
+
+    float4 output[RANK_COUNT / 2] = { 0.0.xxxx, 0.0.xxxx, 0.0.xxxx };
+
+    for (uint rank = 0; rank < min(id_count, RANK_COUNT); ++rank) {
+        uint best = rank;
+        for (uint i = rank + 1; i < id_count; ++i) {
+            if (weights[i] > weights[best]) {
+                best = i;
+            }
+        }
+
+        const uint best_id = ids[best];
+        const float best_weight = weights[best];
+        ids[best] = ids[rank];
+        weights[best] = weights[rank];
+
+        const float2 pair = float2(asfloat(best_id), best_weight);
+        if (rank % 2 == 0) {
+            output[rank / 2].xy = pair;
+        } else {
+            output[rank / 2].zw = pair;
+        }
+    }
+
+    output0_tex[px] = output[0];
+    output1_tex[px] = output[1];
+    output2_tex[px] = output[2];
+}
@@ -0,0 +1,53 @@
+#include "../inc/frame_constants.hlsl"
+#include "../inc/pack_unpack.hlsl"
+#include "../inc/gbuffer.hlsl"
+
+[[vk::binding(0)]] Texture2D<float4> gbuffer_tex;
+[[vk::binding(1)]] Texture2D<float> depth_tex;
+[[vk::binding(2)]] Texture2D<float4> velocity_tex;
+[[vk::binding(3)]] RWTexture2D<float4> output_tex;
+[[vk::binding(4)]] cbuffer _ {
+    uint aov_kind;
+};
+
+// Must match `AovKind` in `renderers/aov.rs`
+#define AOV_ALBEDO 0
+#define AOV_NORMAL 1
+#define AOV_ROUGHNESS 2
+#define AOV_VELOCITY 7
+#define AOV_DEPTH 8
+
+// Decodes one of the AOVs which come from the gbuffer into linear values.
+[numthreads(8, 8, 1)]
+void main(uint2 px: SV_DispatchThreadID) {
+    const float depth = depth_tex[px];
+
+    // Everything is zero over the sky.
+    if (depth == 0.0) {
+        output_tex[px] = 0.0;
+        return;
+    }
+
+    if (aov_kind == AOV_DEPTH) {
+        output_tex[px] = float4(-depth_to_view_z(depth), 0.0, 0.0, 1.0);
+        return;
+    }
+
+    if (aov_kind == AOV_VELOCITY) {
+        output_tex[px] = float4(velocity_tex[px].xyz, 1.0);
+        return;
+    }
+
+    const GbufferData gbuffer = GbufferDataPacked::from_uint4(asuint(gbuffer_tex[px])).unpack();
+
+    float4 output = 0.0;
+    if (aov_kind == AOV_ALBEDO) {
+        output = float4(gbuffer.albedo, 1.0);
+    } else if (aov_kind == AOV_NORMAL) {
+        output = float4(gbuffer.normal, 1.0);
+    } else if (aov_kind == AOV_ROUGHNESS) {
+        output = float4(roughness_to_perceptual_roughness(gbuffer.roughness), gbuffer.metalness, 0.0, 1.0);
+    }
+
+    output_tex[px] = output;
+}
@@ -0,0 +1,38 @@
+#include "../inc/uv.hlsl"
+#include "common.hlsl"
+
+[[vk::binding(0)]] Texture2D<float4> transmittance_lut;
+[[vk::binding(1)]] Texture2D<float4> multiscatter_lut;
+[[vk::binding(2)]] RWTexture3D<float4> output_tex;
+[[vk::binding(3)]] cbuffer _ {
+    AtmosphereConstants atmosphere_constants;
+};
+
+// In-scattered luminance (rgb) and mean transmittance (a) between the eye and a camera-aligned volume
+// of points. Slices are linearly distributed in distance from the eye, with texel centers
+// at `(slice + 0.5) / slice_count * aerial_perspective_distance`.
+[numthreads(4, 4, 4)]
+void main(uint3 px: SV_DispatchThreadID) {
+    const AtmosphereConstants atm = atmosphere_constants;
+    const float3 uvw = (px + 0.5) / AERIAL_PERSPECTIVE_SIZE;
+
+    const float3 dir_vs = normalize(mul(
+        frame_constants.view_constants.clip_to_view,
+        float4(uv_to_cs(uvw.xy), 0.0, 1.0)
+    ).xyz);
+    const float3 dir = normalize(direction_view_to_world(dir_vs));
+
+    const float3 pos = atmosphere_eye_position(atm);
+    const float distance = uvw.z * atm.aerial_perspective_distance;
+
+    // Nearby slices don't need as many samples.
+    const uint sample_count = max(1, min(px.z + 1, 16));
+
+    const ScatteringResult res = integrate_scattering(
+        atm, transmittance_lut, multiscatter_lut,
+        pos, dir, normalize(frame_constants.sun_direction.xyz), distance, sample_count, false
+    );
+
+    const float mean_transmittance = dot(res.transmittance, 1.0 / 3.0);
+    output_tex[px] = float4(res.luminance, mean_transmittance);
+}
@@ -0,0 +1,38 @@
+#include "../inc/uv.hlsl"
+#include "common.hlsl"
+
+[[vk::binding(0)]] Texture2D<float> depth_tex;
+[[vk::binding(1)]] Texture3D<float4> aerial_perspective_tex;
+[[vk::binding(2)]] RWTexture2D<float4> output_tex;
+[[vk::binding(3)]] cbuffer _ {
+    float4 output_tex_size;
+    AtmosphereConstants atmosphere_constants;
+};
+
+[numthreads(8, 8, 1)]
+void main(uint2 px: SV_DispatchThreadID) {
+    const AtmosphereConstants atm = atmosphere_constants;
+    const float depth = depth_tex[px];
+
+    // The sky already includes the atmosphere.
+    if (depth == 0.0) {
+        return;
+    }
+
+    const float2 uv = get_uv(px, output_tex_size);
+    const ViewRayContext view_ray_context = ViewRayContext::from_uv_and_depth(uv, depth);
+    const float distance = length(view_ray_context.ray_hit_vs()) * atm.world_scale;
+
+    const float w = distance / atm.aerial_perspective_distance;
+    float4 aerial_perspective = aerial_perspective_tex.SampleLevel(sampler_llc, float3(uv, w), 0);
+
+    // Within the first half of a slice, fade in from no atmosphere at the eye.
+    const float t = saturate(w * AERIAL_PERSPECTIVE_SIZE.z * 2.0);
+    aerial_perspective = lerp(float4(0.0.xxx, 1.0), aerial_perspective, t);
+
+    const float4 color = output_tex[px];
+    output_tex[px] = float4(
+        color.rgb * aerial_perspective.a + aerial_perspective.rgb * ATMOSPHERE_SUN_ILLUMINANCE,
+        color.a
+    );
+}
@@ -0,0 +1,312 @@
+#ifndef ATMOSPHERE_COMMON_HLSL
+#define ATMOSPHERE_COMMON_HLSL
+
+#include "../inc/frame_constants.hlsl"
+#include "../inc/math_const.hlsl"
+#include "../inc/samplers.hlsl"
+
+// Must match the `*_EXTENT` constants in `atmosphere.rs`.
+#define TRANSMITTANCE_LUT_SIZE uint2(256, 64)
+#define MULTISCATTER_LUT_SIZE uint2(32, 32)
+#define SKY_VIEW_LUT_SIZE uint2(192, 108)
+#define AERIAL_PERSPECTIVE_SIZE uint3(32, 32, 32)
+
+// Must match `OZONE_*` in `atmosphere.rs`.
+#define OZONE_CENTER_HEIGHT 25.0
+#define OZONE_HALF_WIDTH 15.0
+
+// The LUTs store radiance for a sun of unit illuminance. The procedural sky uses the same scale.
+#define ATMOSPHERE_SUN_ILLUMINANCE (20.0 * frame_constants.sun_color_multiplier.rgb)
+
+// Keeps the eye from sinking below the ground, where the parameterizations break down.
+#define ATMOSPHERE_MIN_EYE_ALTITUDE 0.01
+
+// Must match `AtmosphereConstants` in `atmosphere.rs`.
+// Distances are in kilometers, and coefficients per kilometer.
+struct AtmosphereConstants {
+    // rgb: Rayleigh scattering at ground level; a: Rayleigh scale height
+    float4 rayleigh_scattering;
+
+    // x: Mie scattering; y: Mie extinction; z: Mie scale height; w: Mie anisotropy
+    float4 mie;
+
+    float4 ozone_absorption;
+    float4 ground_albedo;
+
+    float bottom_radius;
+    float top_radius;
+
+    // Kilometers per world-space unit
+    float world_scale;
+    float aerial_perspective_distance;
+};
+
+struct MediumSample {
+    float3 rayleigh_scattering;
+    float mie_scattering;
+    float3 scattering;
+    float3 extinction;
+};
+
+MediumSample sample_medium(AtmosphereConstants atm, float altitude) {
+    const float rayleigh_density = exp(-altitude / atm.rayleigh_scattering.a);
+    const float mie_density = exp(-altitude / atm.mie.z);
+    const float ozone_density = max(0.0, 1.0 - abs(altitude - OZONE_CENTER_HEIGHT) / OZONE_HALF_WIDTH);
+
+    MediumSample res;
+    res.rayleigh_scattering = atm.rayleigh_scattering.rgb * rayleigh_density;
+    res.mie_scattering = atm.mie.x * mie_density;
+    res.scattering = res.rayleigh_scattering + res.mie_scattering;
+    res.extinction = res.rayleigh_scattering + atm.mie.y * mie_density + atm.ozone_absorption.rgb * ozone_density;
+    return res;
+}
+
+float atmosphere_rayleigh_phase(float cos_theta) {
+    return 3.0 / (16.0 * M_PI) * (1.0 + cos_theta * cos_theta);
+}
+
+float atmosphere_cornette_shanks_phase(float cos_theta, float g) {
+    const float g2 = g * g;
+    const float k = 3.0 / (8.0 * M_PI) * (1.0 - g2) / (2.0 + g2);
+    return k * (1.0 + cos_theta * cos_theta) / pow(max(1e-5, 1.0 + g2 - 2.0 * g * cos_theta), 1.5);
+}
+
+// Nearest non-negative distance along the ray to a sphere centered at the origin, or -1 if there is none.
+float atmosphere_ray_sphere(float3 origin, float3 dir, float radius) {
+    const float b = dot(origin, dir);
+    const float c = dot(origin, origin) - radius * radius;
+    const float discriminant = b * b - c;
+
+    if (discriminant < 0.0) {
+        return -1.0;
+    }
+
+    const float s = sqrt(discriminant);
+    if (-b - s >= 0.0) {
+        return -b - s;
+    }
+    if (-b + s >= 0.0) {
+        return -b + s;
+    }
+    return -1.0;
+}
+
+// `r` is the distance from the planet center; `mu` the cosine of the view zenith angle.
+bool atmosphere_ray_hits_ground(AtmosphereConstants atm, float r, float mu) {
+    return mu < 0.0 && r * r * (mu * mu - 1.0) + atm.bottom_radius * atm.bottom_radius >= 0.0;
+}
+
+float atmosphere_distance_to_top(AtmosphereConstants atm, float r, float mu) {
+    const float discriminant = r * r * (mu * mu - 1.0) + atm.top_radius * atm.top_radius;
+    return max(0.0, -r * mu + sqrt(max(0.0, discriminant)));
+}
+
+// Keep the outermost texel centers at the edges of the parameterized domain.
+float atmosphere_unit_to_texel_center(float x, uint size) {
+    return 0.5 / size + x * (1.0 - 1.0 / size);
+}
+
+float atmosphere_texel_center_to_unit(float u, uint size) {
+    return (u - 0.5 / size) / (1.0 - 1.0 / size);
+}
+
+// Bruneton's mapping, which spends resolution near the horizon.
+float2 transmittance_lut_r_mu_to_uv(AtmosphereConstants atm, float r, float mu) {
+    const float bottom2 = atm.bottom_radius * atm.bottom_radius;
+    const float h = sqrt(atm.top_radius * atm.top_radius - bottom2);
+    const float rho = sqrt(max(0.0, r * r - bottom2));
+
+    const float d = atmosphere_distance_to_top(atm, r, mu);
+    const float d_min = atm.top_radius - r;
+    const float d_max = rho + h;
+
+    const float x_mu = (d - d_min) / max(1e-5, d_max - d_min);
+    const float x_r = rho / h;
+
+    return float2(
+        atmosphere_unit_to_texel_center(x_mu, TRANSMITTANCE_LUT_SIZE.x),
+        atmosphere_unit_to_texel_center(x_r, TRANSMITTANCE_LUT_SIZE.y)
+    );
+}
+
+void transmittance_lut_uv_to_r_mu(AtmosphereConstants atm, float2 uv, out float r, out float mu) {
+    const float x_mu = atmosphere_texel_center_to_unit(uv.x, TRANSMITTANCE_LUT_SIZE.x);
+    const float x_r = atmosphere_texel_center_to_unit(uv.y, TRANSMITTANCE_LUT_SIZE.y);
+
+    const float bottom2 = atm.bottom_radius * atm.bottom_radius;
+    const float h = sqrt(atm.top_radius * atm.top_radius - bottom2);
+    const float rho = h * x_r;
+    r = sqrt(rho * rho + bottom2);
+
+    const float d_min = atm.top_radius - r;
+    const float d_max = rho + h;
+    const float d = d_min + x_mu * (d_max - d_min);
+
+    mu = d == 0.0 ? 1.0 : (h * h - rho * rho - d * d) / (2.0 * r * d);
+    mu = clamp(mu, -1.0, 1.0);
+}
+
+float3 sample_transmittance_lut(Texture2D<float4> lut, AtmosphereConstants atm, float r, float mu) {
+    return lut.SampleLevel(sampler_llc, transmittance_lut_r_mu_to_uv(atm, r, mu), 0).rgb;
+}
+
+float2 multiscatter_lut_r_mu_to_uv(AtmosphereConstants atm, float r, float sun_mu) {
+    return float2(
+        atmosphere_unit_to_texel_center(sun_mu * 0.5 + 0.5, MULTISCATTER_LUT_SIZE.x),
+        atmosphere_unit_to_texel_center(
+            saturate((r - atm.bottom_radius) / (atm.top_radius - atm.bottom_radius)),
+            MULTISCATTER_LUT_SIZE.y
+        )
+    );
+}
+
+float3 sample_multiscatter_lut(Texture2D<float4> lut, AtmosphereConstants atm, float r, float sun_mu) {
+    return lut.SampleLevel(sampler_llc, multiscatter_lut_r_mu_to_uv(atm, r, sun_mu), 0).rgb;
+}
+
+// The sky-view LUT is parameterized by the view zenith angle, with extra resolution near the horizon,
+// and by the azimuth relative to the sun, which the sky is symmetric about.
+float2 sky_view_lut_params_to_uv(AtmosphereConstants atm, float r, float view_zenith_cos, float light_view_cos) {
+    const float horizon_distance = sqrt(max(0.0, r * r - atm.bottom_radius * atm.bottom_radius));
+    const float beta = acos(horizon_distance / r);
+    const float zenith_horizon_angle = M_PI - beta;
+    const float view_zenith_angle = acos(clamp(view_zenith_cos, -1.0, 1.0));
+
+    float v;
+    if (view_zenith_angle < zenith_horizon_angle) {
+        const float coord = 1.0 - sqrt(max(0.0, 1.0 - view_zenith_angle / zenith_horizon_angle));
+        v = coord * 0.5;
+    } else {
+        const float coord = sqrt(max(0.0, (view_zenith_angle - zenith_horizon_angle) / beta));
+        v = coord * 0.5 + 0.5;
+    }
+
+    const float u = acos(clamp(light_view_cos, -1.0, 1.0)) / M_PI;
+
+    return float2(
+        atmosphere_unit_to_texel_center(u, SKY_VIEW_LUT_SIZE.x),
+        atmosphere_unit_to_texel_center(v, SKY_VIEW_LUT_SIZE.y)
+    );
+}
+
+void sky_view_lut_uv_to_params(
+    AtmosphereConstants atm,
+    float r,
+    float2 uv,
+    out float view_zenith_cos,
+    out float light_view_cos
+) {
+    uv = float2(
+        atmosphere_texel_center_to_unit(uv.x, SKY_VIEW_LUT_SIZE.x),
+        atmosphere_texel_center_to_unit(uv.y, SKY_VIEW_LUT_SIZE.y)
+    );
+
+    const float horizon_distance = sqrt(max(0.0, r * r - atm.bottom_radius * atm.bottom_radius));
+    const float beta = acos(horizon_distance / r);
+    const float zenith_horizon_angle = M_PI - beta;
+
+    float view_zenith_angle;
+    if (uv.y < 0.5) {
+        float coord = 1.0 - 2.0 * uv.y;
+        coord = 1.0 - coord * coord;
+        view_zenith_angle = zenith_horizon_angle * coord;
+    } else {
+        float coord = uv.y * 2.0 - 1.0;
+        view_zenith_angle = zenith_horizon_angle + beta * coord * coord;
+    }
+
+    view_zenith_cos = cos(view_zenith_angle);
+    light_view_cos = cos(uv.x * M_PI);
+}
+
+// Position of the eye relative to the planet center. The planet is large enough
+// for the scene's horizontal extent not to matter, so only the altitude is kept.
+float3 atmosphere_eye_position(AtmosphereConstants atm) {
+    const float altitude = max(ATMOSPHERE_MIN_EYE_ALTITUDE, get_eye_position().y * atm.world_scale);
+    return float3(0.0, atm.bottom_radius + altitude, 0.0);
+}
+
+struct ScatteringResult {
+    float3 luminance;
+    float3 transmittance;
+
+    // Transfer of a uniform, unit radiance field; only used to build the multiple scattering LUT.
+    float3 multiscatter_as_1;
+};
+
+// Integrates single scattering of the sun (of unit illuminance) plus the multiple scattering
+// approximation along a ray starting at `pos` within the atmosphere.
+//
+// When building the multiple scattering LUT, phase functions are isotropic,
+// the LUT itself is not used, and light reflected by the ground is included.
+ScatteringResult integrate_scattering(
+    AtmosphereConstants atm,
+    Texture2D<float4> transmittance_lut,
+    Texture2D<float4> multiscatter_lut,
+    float3 pos,
+    float3 dir,
+    float3 sun_dir,
+    float max_distance,
+    uint sample_count,
+    bool multiscatter_pass
+) {
+    ScatteringResult res;
+    res.luminance = 0.0;
+    res.transmittance = 1.0;
+    res.multiscatter_as_1 = 0.0;
+
+    const float t_top = atmosphere_ray_sphere(pos, dir, atm.top_radius);
+    if (t_top < 0.0) {
+        return res;
+    }
+
+    const float t_bottom = atmosphere_ray_sphere(pos, dir, atm.bottom_radius);
+    const bool hits_ground = t_bottom >= 0.0 && t_bottom <= max_distance;
+    const float t_max = min(max_distance, t_bottom >= 0.0 ? t_bottom : t_top);
+
+    const float cos_theta = dot(dir, sun_dir);
+    const float isotropic_phase = 1.0 / (4.0 * M_PI);
+    const float rayleigh_phase = multiscatter_pass ? isotropic_phase : atmosphere_rayleigh_phase(cos_theta);
+    const float mie_phase = multiscatter_pass ? isotropic_phase : atmosphere_cornette_shanks_phase(cos_theta, atm.mie.w);
+
+    const float dt = t_max / sample_count;
+
+    for (uint i = 0; i < sample_count; ++i) {
+        const float3 sample_pos = pos + dir * ((i + 0.5) * dt);
+        const float r = length(sample_pos);
+        const float sun_mu = dot(sun_dir, sample_pos / r);
+
+        const MediumSample medium = sample_medium(atm, r - atm.bottom_radius);
+        const float3 sample_transmittance = exp(-medium.extinction * dt);
+
+        const float3 sun_transmittance = sample_transmittance_lut(transmittance_lut, atm, r, sun_mu);
+        const float planet_shadow = atmosphere_ray_hits_ground(atm, r, sun_mu) ? 0.0 : 1.0;
+
+        float3 in_scattering = planet_shadow * sun_transmittance
+            * (medium.rayleigh_scattering * rayleigh_phase + medium.mie_scattering * mie_phase);
+
+        if (!multiscatter_pass) {
+            in_scattering += sample_multiscatter_lut(multiscatter_lut, atm, r, sun_mu) * medium.scattering;
+        }
+
+        // Analytical integration of the in-scattering over the segment, assuming constant extinction.
+        // See "Physically Based and Unified Volumetric Rendering in Frostbite" (Hillaire 2015).
+        const float3 safe_extinction = max(medium.extinction, 1e-7);
+        res.luminance += res.transmittance * (in_scattering - in_scattering * sample_transmittance) / safe_extinction;
+        res.multiscatter_as_1 += res.transmittance * (medium.scattering - medium.scattering * sample_transmittance) / safe_extinction;
+        res.transmittance *= sample_transmittance;
+    }
+
+    if (multiscatter_pass && hits_ground) {
+        const float3 ground_pos = pos + dir * t_bottom;
+        const float r = length(ground_pos);
+        const float sun_mu = dot(sun_dir, ground_pos / r);
+        const float3 sun_transmittance = sample_transmittance_lut(transmittance_lut, atm, r, sun_mu);
+
+        res.luminance += res.transmittance * sun_transmittance * saturate(sun_mu) * atm.ground_albedo.rgb / M_PI;
+    }
+
+    return res;
+}
+
+#endif  // ATMOSPHERE_COMMON_HLSL
@@ -0,0 +1,62 @@
+#include "common.hlsl"
+
+[[vk::binding(0)]] Texture2D<float4> transmittance_lut;
+[[vk::binding(1)]] RWTexture2D<float4> output_tex;
+[[vk::binding(2)]] cbuffer _ {
+    AtmosphereConstants atmosphere_constants;
+};
+
+static const uint DIRECTION_COUNT_SQRT = 8;
+static const uint SAMPLE_COUNT = 20;
+
+// Stores the isotropic multiple scattering transfer for a given altitude and sun zenith angle,
+// as the sum of an infinite geometric series of scattering orders.
+// See "A Scalable and Production Ready Sky and Atmosphere Rendering Technique" (Hillaire 2020).
+[numthreads(8, 8, 1)]
+void main(uint2 px: SV_DispatchThreadID) {
+    const AtmosphereConstants atm = atmosphere_constants;
+    const float2 uv = float2(
+        atmosphere_texel_center_to_unit((px.x + 0.5) / MULTISCATTER_LUT_SIZE.x, MULTISCATTER_LUT_SIZE.x),
+        atmosphere_texel_center_to_unit((px.y + 0.5) / MULTISCATTER_LUT_SIZE.y, MULTISCATTER_LUT_SIZE.y)
+    );
+
+    const float sun_mu = uv.x * 2.0 - 1.0;
+    const float r = clamp(
+        lerp(atm.bottom_radius, atm.top_radius, uv.y),
+        atm.bottom_radius + 1e-3,
+        atm.top_radius - 1e-3
+    );
+
+    const float3 pos = float3(0.0, r, 0.0);
+    const float3 sun_dir = float3(sqrt(saturate(1.0 - sun_mu * sun_mu)), sun_mu, 0.0);
+
+    float3 second_order = 0.0;
+    float3 transfer = 0.0;
+
+    for (uint j = 0; j < DIRECTION_COUNT_SQRT; ++j) {
+        for (uint i = 0; i < DIRECTION_COUNT_SQRT; ++i) {
+            // Uniformly distributed over the sphere
+            const float2 urand = (float2(i, j) + 0.5) / DIRECTION_COUNT_SQRT;
+            const float z = 1.0 - 2.0 * urand.x;
+            const float sin_z = sqrt(saturate(1.0 - z * z));
+            const float phi = 2.0 * M_PI * urand.y;
+            const float3 dir = float3(sin_z * cos(phi), z, sin_z * sin(phi));
+
+            // The multiple scattering LUT is not used in this pass; pass anything.
+            const ScatteringResult res = integrate_scattering(
+                atm, transmittance_lut, transmittance_lut,
+                pos, dir, sun_dir, 1e9, SAMPLE_COUNT, true
+            );
+
+            second_order += res.luminance;
+            transfer += res.multiscatter_as_1;
+        }
+    }
+
+    // Integrals over the sphere with the isotropic phase function reduce to averages.
+    const float direction_count = DIRECTION_COUNT_SQRT * DIRECTION_COUNT_SQRT;
+    second_order /= direction_count;
+    transfer /= direction_count;
+
+    output_tex[px] = float4(second_order / max(1e-5, 1.0 - transfer), 1.0);
+}
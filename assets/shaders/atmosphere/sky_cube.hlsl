@@ -0,0 +1,37 @@
+#include "../inc/cube_map.hlsl"
+#include "common.hlsl"
+
+[[vk::binding(0)]] Texture2D<float4> sky_view_lut;
+[[vk::binding(1)]] RWTexture2DArray<float4> output_tex;
+[[vk::binding(2)]] cbuffer _ {
+    AtmosphereConstants atmosphere_constants;
+};
+
+float3 sky_radiance(AtmosphereConstants atm, float3 dir) {
+    const float r = atmosphere_eye_position(atm).y;
+    const float3 sun_dir = normalize(frame_constants.sun_direction.xyz);
+
+    // Azimuth relative to the sun; directions straight up or down have none, but any value works for them.
+    const float2 dir_xz = dir.xz / max(1e-5, length(dir.xz));
+    const float2 sun_xz = sun_dir.xz / max(1e-5, length(sun_dir.xz));
+    const float light_view_cos = length(sun_dir.xz) > 1e-5 ? dot(dir_xz, sun_xz) : 1.0;
+
+    const float2 uv = sky_view_lut_params_to_uv(atm, r, dir.y, light_view_cos);
+    return sky_view_lut.SampleLevel(sampler_llc, uv, 0).rgb;
+}
+
+[numthreads(8, 8, 1)]
+void main(uint3 px: SV_DispatchThreadID) {
+    uint width, height, face_count;
+    output_tex.GetDimensions(width, height, face_count);
+
+    const uint face = px.z;
+    const float2 uv = (px.xy + 0.5) / width;
+    const float3 dir = normalize(mul(CUBE_MAP_FACE_ROTATIONS[face], float3(uv * 2.0 - 1.0, -1.0)));
+
+    const float3 radiance =
+        frame_constants.sky_ambient.rgb
+        + ATMOSPHERE_SUN_ILLUMINANCE * sky_radiance(atmosphere_constants, dir);
+
+    output_tex[px] = float4(radiance, 1.0);
+}
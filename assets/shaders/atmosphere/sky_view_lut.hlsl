@@ -0,0 +1,41 @@
+#include "common.hlsl"
+
+[[vk::binding(0)]] Texture2D<float4> transmittance_lut;
+[[vk::binding(1)]] Texture2D<float4> multiscatter_lut;
+[[vk::binding(2)]] RWTexture2D<float4> output_tex;
+[[vk::binding(3)]] cbuffer _ {
+    AtmosphereConstants atmosphere_constants;
+};
+
+static const uint SAMPLE_COUNT = 32;
+
+// Sky radiance around the eye, for a sun of unit illuminance.
+// Computed in a local frame with the sun in the XY plane.
+[numthreads(8, 8, 1)]
+void main(uint2 px: SV_DispatchThreadID) {
+    const AtmosphereConstants atm = atmosphere_constants;
+    const float2 uv = (px + 0.5) / SKY_VIEW_LUT_SIZE;
+
+    const float3 pos = atmosphere_eye_position(atm);
+    const float r = pos.y;
+
+    float view_zenith_cos, light_view_cos;
+    sky_view_lut_uv_to_params(atm, r, uv, view_zenith_cos, light_view_cos);
+
+    const float view_zenith_sin = sqrt(saturate(1.0 - view_zenith_cos * view_zenith_cos));
+    const float3 dir = float3(
+        view_zenith_sin * light_view_cos,
+        view_zenith_cos,
+        view_zenith_sin * sqrt(saturate(1.0 - light_view_cos * light_view_cos))
+    );
+
+    const float sun_mu = normalize(frame_constants.sun_direction.xyz).y;
+    const float3 sun_dir = float3(sqrt(saturate(1.0 - sun_mu * sun_mu)), sun_mu, 0.0);
+
+    const ScatteringResult res = integrate_scattering(
+        atm, transmittance_lut, multiscatter_lut,
+        pos, dir, sun_dir, 1e9, SAMPLE_COUNT, false
+    );
+
+    output_tex[px] = float4(res.luminance, 1.0);
+}
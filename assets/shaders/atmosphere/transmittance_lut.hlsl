@@ -0,0 +1,31 @@
+#include "common.hlsl"
+
+[[vk::binding(0)]] RWTexture2D<float4> output_tex;
+[[vk::binding(1)]] cbuffer _ {
+    AtmosphereConstants atmosphere_constants;
+};
+
+// The LUT is only rebuilt when the medium changes, so it can afford enough samples
+// to resolve the thin Mie layer along rays leaving the ground.
+static const uint SAMPLE_COUNT = 256;
+
+[numthreads(8, 8, 1)]
+void main(uint2 px: SV_DispatchThreadID) {
+    const AtmosphereConstants atm = atmosphere_constants;
+    const float2 uv = (px + 0.5) / TRANSMITTANCE_LUT_SIZE;
+
+    float r, mu;
+    transmittance_lut_uv_to_r_mu(atm, uv, r, mu);
+
+    const float distance = atmosphere_distance_to_top(atm, r, mu);
+    const float dt = distance / SAMPLE_COUNT;
+
+    float3 optical_depth = 0.0;
+    for (uint i = 0; i < SAMPLE_COUNT; ++i) {
+        const float t = (i + 0.5) * dt;
+        const float sample_r = sqrt(r * r + t * t + 2.0 * r * mu * t);
+        optical_depth += sample_medium(atm, sample_r - atm.bottom_radius).extinction * dt;
+    }
+
+    output_tex[px] = float4(exp(-optical_depth), 1.0);
+}
@@ -0,0 +1,52 @@
+#include "inc/frame_constants.hlsl"
+#include "inc/color/srgb.hlsl"
+#include "inc/atmosphere.hlsl"
+#include "inc/sun.hlsl"
+#include "inc/environment_cdf.hlsl"
+
+[[vk::binding(0)]] RWTexture2D<float> cdf_tex;
+
+groupshared float row_sums[ENVIRONMENT_CDF_HEIGHT];
+
+// Evaluates the environment at texel centers, and weighs it by their solid angle,
+// so that sampling the result is proportional to the environment's radiance.
+// One thread per row; dispatched as a single group.
+[numthreads(ENVIRONMENT_CDF_HEIGHT, 1, 1)]
+void main(uint row: SV_GroupThreadID) {
+    const float sin_theta = sin((row + 0.5) / ENVIRONMENT_CDF_HEIGHT * M_PI);
+
+    float row_sum = 0.0;
+    for (uint x = 0; x < ENVIRONMENT_CDF_WIDTH; ++x) {
+        const float2 uv = (float2(x, row) + 0.5) / float2(ENVIRONMENT_CDF_WIDTH, ENVIRONMENT_CDF_HEIGHT);
+        const float3 dir = environment_cdf_uv_to_dir(uv);
+
+        // Must match `sample_environment_light` in `reference_path_trace.rgen.hlsl`.
+        const float3 radiance = atmosphere_default(dir, SUN_DIRECTION);
+
+        // Keep every direction reachable, so that the estimator stays unbiased
+        // if the environment is black somewhere.
+        row_sum += max(1e-8, sRGB_to_luminance(radiance)) * sin_theta;
+        cdf_tex[uint2(x, row)] = row_sum;
+    }
+
+    row_sums[row] = row_sum;
+    GroupMemoryBarrierWithGroupSync();
+
+    float marginal_before = 0.0;
+    float total = 0.0;
+    for (uint y = 0; y < ENVIRONMENT_CDF_HEIGHT; ++y) {
+        total += row_sums[y];
+        if (y < row) {
+            marginal_before += row_sums[y];
+        }
+    }
+
+    for (uint x = 0; x < ENVIRONMENT_CDF_WIDTH; ++x) {
+        cdf_tex[uint2(x, row)] /= row_sum;
+    }
+
+    // Pin the ends to exactly 1, so that the search never runs past them.
+    cdf_tex[uint2(ENVIRONMENT_CDF_WIDTH - 1, row)] = 1.0;
+    cdf_tex[uint2(ENVIRONMENT_CDF_WIDTH, row)] =
+        row + 1 == ENVIRONMENT_CDF_HEIGHT ? 1.0 : (marginal_before + row_sum) / total;
+}
@@ -0,0 +1,28 @@
+#include "inc/samplers.hlsl"
+#include "inc/color/srgb.hlsl"
+
+[[vk::binding(0)]] Texture2D<float4> input_tex;
+[[vk::binding(1)]] Texture3D<float4> lut_tex;
+[[vk::binding(2)]] RWTexture2D<float4> output_tex;
+[[vk::binding(3)]] cbuffer _ {
+    float4 domain_min_strength;
+    float4 domain_max_size;
+};
+
+[numthreads(8, 8, 1)]
+void main(uint2 px: SV_DispatchThreadID) {
+    const float4 col = input_tex[px];
+
+    // `.cube` LUTs are authored for sRGB-encoded values.
+    const float3 encoded = sRGB_EOTF(saturate(col.rgb));
+
+    const float lut_size = domain_max_size.w;
+    float3 uvw = saturate((encoded - domain_min_strength.xyz) / (domain_max_size.xyz - domain_min_strength.xyz));
+
+    // Map [0, 1] onto the centers of the first and last texels.
+    uvw = (uvw * (lut_size - 1.0) + 0.5) / lut_size;
+
+    const float3 graded = sRGB_OETF(max(0.0, lut_tex.SampleLevel(sampler_llc, uvw, 0).rgb));
+
+    output_tex[px] = float4(lerp(col.rgb, graded, domain_min_strength.w), col.a);
+}
@@ -0,0 +1,18 @@
+#include "inc/samplers.hlsl"
+
+[[vk::binding(0)]] Texture2D<float4> overlay_tex;
+[[vk::binding(1)]] RWTexture2D<float4> output_tex;
+[[vk::binding(2)]] cbuffer _ {
+    float4 output_tex_size;
+};
+
+// Blends a premultiplied overlay at the internal resolution over the possibly upscaled output.
+[numthreads(8, 8, 1)]
+void main(uint2 px: SV_DispatchThreadID) {
+    const float2 uv = (px + 0.5) * output_tex_size.zw;
+    const float4 overlay = overlay_tex.SampleLevel(sampler_lnc, uv, 0);
+
+    if (overlay.a > 0) {
+        output_tex[px] = float4(output_tex[px].rgb * (1.0 - overlay.a) + overlay.rgb, 1);
+    }
+}
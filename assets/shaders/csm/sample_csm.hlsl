@@ -0,0 +1,168 @@
+#include "../inc/uv.hlsl"
+#include "../inc/frame_constants.hlsl"
+#include "../inc/blue_noise.hlsl"
+#include "../inc/math.hlsl"
+
+// Must match `MAX_CASCADES` in `csm.rs`
+#define MAX_CASCADES 4
+
+#define FILTER_PCF 0
+#define FILTER_PCSS 1
+
+// Fraction of each cascade over which it fades into the next one
+#define CASCADE_BLEND_FRACTION 0.1
+
+// Receivers are offset this many texels along their normal, away from their own shadow.
+#define NORMAL_OFFSET_TEXELS 1.5
+
+#define FILTER_TAPS 16
+#define MAX_PCSS_RADIUS_TEXELS 32.0
+#define GOLDEN_ANGLE 2.39996323
+
+[[vk::binding(0)]] Texture2D<float> depth_tex;
+[[vk::binding(1)]] Texture2D<float3> geometric_normal_tex;
+[[vk::binding(2)]] Texture2D<float> shadow_map_tex;
+[[vk::binding(3)]] RWTexture2D<float> output_tex;
+// Must match `SampleCsmConstants` in `csm.rs`
+[[vk::binding(4)]] cbuffer _ {
+    float4x4 world_to_shadow[MAX_CASCADES];
+    float4 cascade_far_distance;
+    float4 cascade_radius;
+    float4 cascade_depth_range;
+    uint cascade_count;
+    uint filter_mode;
+    float shadow_map_resolution;
+    float pcf_radius;
+};
+
+// Depth of the shadow map texel at `tile_uv` in the cascade's tile of the atlas
+float shadow_map_depth(uint cascade, float2 tile_uv) {
+    const int resolution = int(shadow_map_resolution);
+    const int2 texel = clamp(int2(floor(tile_uv * shadow_map_resolution)), 0, resolution - 1);
+    return shadow_map_tex[texel + int2(cascade * resolution, 0)];
+}
+
+// Vogel disk in the unit circle
+float2 disk_tap(uint i, float rotation) {
+    const float r = sqrt((i + 0.5) / FILTER_TAPS);
+    const float theta = i * GOLDEN_ANGLE + rotation;
+    return r * float2(cos(theta), sin(theta));
+}
+
+// Fraction of the disk of `radius_uv` around `tile_uv` which sees the sun
+float pcf_visibility(uint cascade, float2 tile_uv, float receiver_depth, float radius_uv, float rotation) {
+    float lit = 0.0;
+    for (uint i = 0; i < FILTER_TAPS; ++i) {
+        const float2 uv = tile_uv + disk_tap(i, rotation) * radius_uv;
+        lit += shadow_map_depth(cascade, uv) <= receiver_depth ? 1.0 : 0.0;
+    }
+    return lit / FILTER_TAPS;
+}
+
+float cascade_visibility(uint cascade, float3 pos_ws, float3 normal_ws, float rotation) {
+    const float texel_size = 2.0 * cascade_radius[cascade] / shadow_map_resolution;
+    const float depth_range = cascade_depth_range[cascade];
+
+    const float3 pos_shadow = mul(
+        world_to_shadow[cascade],
+        float4(pos_ws + normal_ws * texel_size * NORMAL_OFFSET_TEXELS, 1.0)
+    ).xyz;
+    const float2 tile_uv = cs_to_uv(pos_shadow.xy);
+    const float receiver_depth = pos_shadow.z + texel_size / depth_range;
+
+    const float texel_uv = 1.0 / shadow_map_resolution;
+    float radius_uv = pcf_radius * texel_uv;
+
+    if (filter_mode == FILTER_PCSS) {
+        const float cos_sun = frame_constants.sun_angular_radius_cos;
+        const float tan_sun = sqrt(max(0.0, 1.0 - cos_sun * cos_sun)) / cos_sun;
+
+        // World units per uv across the tile
+        const float uv_to_world = 2.0 * cascade_radius[cascade];
+
+        // Occluders up to the sun end of the map could shadow the receiver from this far.
+        const float search_radius_uv = clamp(
+            tan_sun * (1.0 - receiver_depth) * depth_range / uv_to_world,
+            radius_uv,
+            MAX_PCSS_RADIUS_TEXELS * texel_uv
+        );
+
+        float blocker_depth_sum = 0.0;
+        float blocker_count = 0.0;
+        for (uint i = 0; i < FILTER_TAPS; ++i) {
+            const float2 uv = tile_uv + disk_tap(i, rotation) * search_radius_uv;
+            const float depth = shadow_map_depth(cascade, uv);
+            if (depth > receiver_depth) {
+                blocker_depth_sum += depth;
+                blocker_count += 1.0;
+            }
+        }
+
+        if (blocker_count == 0.0) {
+            return 1.0;
+        }
+
+        const float occluder_distance = (blocker_depth_sum / blocker_count - receiver_depth) * depth_range;
+        radius_uv = clamp(
+            tan_sun * occluder_distance / uv_to_world,
+            radius_uv,
+            MAX_PCSS_RADIUS_TEXELS * texel_uv
+        );
+    }
+
+    return pcf_visibility(cascade, tile_uv, receiver_depth, radius_uv, rotation);
+}
+
+[numthreads(8, 8, 1)]
+void main(uint2 px: SV_DispatchThreadID) {
+    float2 output_size;
+    output_tex.GetDimensions(output_size.x, output_size.y);
+
+    const float z_over_w = depth_tex[px];
+    if (0.0 == z_over_w) {
+        output_tex[px] = 1.0;
+        return;
+    }
+
+    const float2 uv = (px + 0.5) / output_size;
+    float4 pt_cs = float4(uv_to_cs(uv), z_over_w, 1.0);
+    float4 pt_vs = mul(frame_constants.view_constants.sample_to_view, pt_cs);
+    float4 pt_ws = mul(frame_constants.view_constants.view_to_world, pt_vs);
+    pt_ws /= pt_ws.w;
+    pt_vs /= pt_vs.w;
+
+    const float3 normal_vs = geometric_normal_tex[px] * 2.0 - 1.0;
+    const float3 normal_ws = mul(frame_constants.view_constants.view_to_world, float4(normal_vs, 0.0)).xyz;
+
+    const float view_distance = -pt_vs.z;
+
+    uint cascade = 0;
+    while (cascade < cascade_count && view_distance > cascade_far_distance[cascade]) {
+        ++cascade;
+    }
+
+    // Beyond the last cascade
+    if (cascade == cascade_count) {
+        output_tex[px] = 1.0;
+        return;
+    }
+
+    const float rotation = blue_noise_for_pixel(px, frame_constants.frame_index).x * M_TAU;
+    float visibility = cascade_visibility(cascade, pt_ws.xyz, normal_ws, rotation);
+
+    // Fade into the next cascade towards the end of this one, hiding the change in resolution.
+    // The last one fades out to lit.
+    const float cascade_start = cascade > 0 ? cascade_far_distance[cascade - 1] : 0.0;
+    const float cascade_end = cascade_far_distance[cascade];
+    const float blend_length = (cascade_end - cascade_start) * CASCADE_BLEND_FRACTION;
+    const float blend = saturate((view_distance - (cascade_end - blend_length)) / blend_length);
+
+    if (blend > 0.0) {
+        const float next_visibility = cascade + 1 < cascade_count
+            ? cascade_visibility(cascade + 1, pt_ws.xyz, normal_ws, rotation)
+            : 1.0;
+        visibility = lerp(visibility, next_visibility, blend);
+    }
+
+    output_tex[px] = visibility;
+}
@@ -0,0 +1,29 @@
+#include "../inc/frame_constants.hlsl"
+#include "../inc/mesh.hlsl"
+#include "../inc/bindless.hlsl"
+
+// Must match `ShadowMapPushConstants` in `csm.rs`
+[[vk::push_constant]]
+struct {
+    float4x4 world_to_shadow;
+    uint draw_index;
+    uint mesh_index;
+} push_constants;
+
+struct InstanceTransform {
+    row_major float3x4 current;
+    row_major float3x4 previous;
+};
+
+[[vk::binding(0)]] StructuredBuffer<InstanceTransform> instance_transforms_dyn;
+
+// Depth only; there's no pixel shader.
+float4 main(uint vid: SV_VertexID): SV_Position {
+    const Mesh mesh = meshes[push_constants.mesh_index];
+
+    VertexPacked vp = VertexPacked(asfloat(vertices.Load4(vid * sizeof(float4) + mesh.vertex_core_offset)));
+    Vertex v = unpack_vertex(vp);
+
+    float3 ws_pos = mul(instance_transforms_dyn[push_constants.draw_index].current, float4(v.position, 1.0));
+    return mul(push_constants.world_to_shadow, float4(ws_pos, 1.0));
+}
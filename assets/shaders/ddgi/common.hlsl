@@ -0,0 +1,140 @@
+#ifndef DDGI_COMMON_HLSL
+#define DDGI_COMMON_HLSL
+
+#include "../inc/math_const.hlsl"
+#include "../inc/pack_unpack.hlsl"
+
+// Must match CPU code. Search token: 5d2b3c0e-0f3a-4a4c-9e5b-2f4c8a1b7d61
+#define DDGI_IRRADIANCE_PROBE_RES 6
+#define DDGI_VISIBILITY_PROBE_RES 14
+
+// Sharpness of the cosine lobe used when accumulating ray distances into visibility maps.
+#define DDGI_VISIBILITY_SHARPNESS 50.0
+
+// Defaults for `DdgiLeakReduction`; must match CPU code.
+// How far off the surface the lookup position is pushed, in units of probe spacing.
+#define DDGI_NORMAL_BIAS 0.2
+#define DDGI_VIEW_BIAS 0.1
+// Exponent applied to the Chebyshev visibility estimate.
+#define DDGI_CHEBYSHEV_SHARPNESS 3.0
+
+// Back-facing hits are stored with a negative, shortened distance. This pulls the visibility
+// of probes stuck inside geometry in, so that they don't contribute to the surfaces around them.
+#define DDGI_BACKFACE_DISTANCE_SCALE 0.2
+
+// Must match `DdgiConstants` in `ddgi.rs`.
+struct DdgiConstants {
+    // xyz: world-space position of the probe at the grid's minimum corner; w: probe spacing
+    float4 grid_origin;
+
+    // xyz: probe count along each axis; w: rays traced per probe per frame
+    uint4 probe_counts;
+
+    // Absolute cell of the grid's minimum corner, this frame and last
+    int4 origin_cell;
+    int4 prev_origin_cell;
+
+    // Rows of a random rotation applied to the ray directions each frame
+    float4 ray_rotation[3];
+
+    float hysteresis;
+    float max_visibility_distance;
+    uint reset_history;
+    uint pad0;
+};
+
+float ddgi_probe_spacing(DdgiConstants ddgi) {
+    return ddgi.grid_origin.w;
+}
+
+uint ddgi_rays_per_probe(DdgiConstants ddgi) {
+    return ddgi.probe_counts.w;
+}
+
+// Probes are stored by their absolute cell, wrapped around the grid dimensions.
+// This way, when the grid follows the camera, probes that remain within it keep their history.
+uint3 ddgi_cell_to_storage(DdgiConstants ddgi, int3 cell) {
+    const int3 counts = int3(ddgi.probe_counts.xyz);
+    return uint3(((cell % counts) + counts) % counts);
+}
+
+int3 ddgi_storage_to_cell(DdgiConstants ddgi, uint3 storage) {
+    const int3 counts = int3(ddgi.probe_counts.xyz);
+    const int3 origin_storage = int3(ddgi_cell_to_storage(ddgi, ddgi.origin_cell.xyz));
+    return ddgi.origin_cell.xyz + ((int3(storage) - origin_storage + counts) % counts);
+}
+
+uint ddgi_storage_to_index(DdgiConstants ddgi, uint3 storage) {
+    return storage.x + storage.y * ddgi.probe_counts.x + storage.z * ddgi.probe_counts.x * ddgi.probe_counts.y;
+}
+
+uint3 ddgi_index_to_storage(DdgiConstants ddgi, uint idx) {
+    return uint3(
+        idx % ddgi.probe_counts.x,
+        (idx / ddgi.probe_counts.x) % ddgi.probe_counts.y,
+        idx / (ddgi.probe_counts.x * ddgi.probe_counts.y)
+    );
+}
+
+float3 ddgi_cell_position(DdgiConstants ddgi, int3 cell) {
+    return ddgi.grid_origin.xyz + float3(cell - ddgi.origin_cell.xyz) * ddgi_probe_spacing(ddgi);
+}
+
+// Probes which weren't in the grid last frame have no history to blend with.
+bool ddgi_cell_is_new(DdgiConstants ddgi, int3 cell) {
+    return ddgi.reset_history != 0
+        || any(cell < ddgi.prev_origin_cell.xyz)
+        || any(cell >= ddgi.prev_origin_cell.xyz + int3(ddgi.probe_counts.xyz));
+}
+
+// Probe atlases lay out the probes of each XY slice side by side, and slices along Z next to each other.
+uint2 ddgi_probe_atlas_tile(DdgiConstants ddgi, uint3 storage) {
+    return uint2(storage.x + storage.z * ddgi.probe_counts.x, storage.y);
+}
+
+uint3 ddgi_atlas_tile_to_storage(DdgiConstants ddgi, uint2 tile) {
+    return uint3(tile.x % ddgi.probe_counts.x, tile.y, tile.x / ddgi.probe_counts.x);
+}
+
+// Each probe's octahedral map is surrounded by a one texel border, so that bilinear lookups
+// wrap around the octahedron seams. Returns the interior texel a border texel duplicates.
+uint2 ddgi_octa_texel_to_interior(uint2 texel, uint probe_res) {
+    uint2 res = texel;
+
+    if (texel.x == 0 || texel.x == probe_res + 1) {
+        res.y = probe_res + 1 - texel.y;
+    }
+
+    if (texel.y == 0 || texel.y == probe_res + 1) {
+        res.x = probe_res + 1 - texel.x;
+    }
+
+    return clamp(res, 1, probe_res) - 1;
+}
+
+float3 ddgi_octa_texel_direction(uint2 interior_texel, uint probe_res) {
+    return octa_decode((interior_texel + 0.5) / probe_res);
+}
+
+float2 ddgi_atlas_uv(DdgiConstants ddgi, uint3 storage, float3 dir, uint probe_res, float2 atlas_size) {
+    const float2 tile_origin = ddgi_probe_atlas_tile(ddgi, storage) * (probe_res + 2) + 1;
+    return (tile_origin + octa_encode(dir) * probe_res) / atlas_size;
+}
+
+float3 ddgi_ray_direction(DdgiConstants ddgi, uint ray_idx) {
+    // Spherical Fibonacci distribution
+    const float n = ddgi_rays_per_probe(ddgi);
+    const float golden_angle = M_PI * (3.0 - sqrt(5.0));
+    const float z = 1.0 - (2.0 * ray_idx + 1.0) / n;
+    const float r = sqrt(max(0.0, 1.0 - z * z));
+    const float phi = golden_angle * ray_idx;
+    const float3 dir = float3(cos(phi) * r, sin(phi) * r, z);
+
+    return float3(
+        dot(ddgi.ray_rotation[0].xyz, dir),
+        dot(ddgi.ray_rotation[1].xyz, dir),
+        dot(ddgi.ray_rotation[2].xyz, dir)
+    );
+}
+
+#endif  // DDGI_COMMON_HLSL
@@ -0,0 +1,97 @@
+#ifndef DDGI_LOOKUP_HLSL
+#define DDGI_LOOKUP_HLSL
+
+#include "../inc/samplers.hlsl"
+#include "common.hlsl"
+
+// Expects `ddgi_irradiance_tex` and `ddgi_visibility_tex` to be declared by the including shader.
+
+// Must match `DdgiLeakReduction` in `ddgi.rs`
+struct DdgiLeakReduction {
+    float normal_bias;
+    float view_bias;
+    float visibility_sharpness;
+
+    static DdgiLeakReduction from_defaults() {
+        DdgiLeakReduction res;
+        res.normal_bias = DDGI_NORMAL_BIAS;
+        res.view_bias = DDGI_VIEW_BIAS;
+        res.visibility_sharpness = DDGI_CHEBYSHEV_SHARPNESS;
+        return res;
+    }
+};
+
+// Irradiance (divided by pi) at `pos` facing `normal`, interpolated between the eight surrounding probes.
+// `view_dir` points from the eye towards `pos`.
+float3 ddgi_lookup_irradiance(DdgiConstants ddgi, DdgiLeakReduction leak_reduction, float3 pos, float3 normal, float3 view_dir) {
+    const float spacing = ddgi_probe_spacing(ddgi);
+    const float3 biased_pos = pos
+        + (normal * leak_reduction.normal_bias - view_dir * leak_reduction.view_bias) * spacing;
+
+    const float3 grid_pos = (biased_pos - ddgi.grid_origin.xyz) / spacing;
+    const int3 base_offset = clamp(int3(floor(grid_pos)), 0, int3(ddgi.probe_counts.xyz) - 2);
+    const float3 alpha = saturate(grid_pos - base_offset);
+
+    float2 irradiance_atlas_size;
+    ddgi_irradiance_tex.GetDimensions(irradiance_atlas_size.x, irradiance_atlas_size.y);
+
+    float2 visibility_atlas_size;
+    ddgi_visibility_tex.GetDimensions(visibility_atlas_size.x, visibility_atlas_size.y);
+
+    float3 irradiance_sum = 0.0.xxx;
+    float weight_sum = 0.0;
+
+    for (uint i = 0; i < 8; ++i) {
+        const int3 corner = int3(i & 1, (i >> 1) & 1, (i >> 2) & 1);
+        const int3 cell = ddgi.origin_cell.xyz + base_offset + corner;
+        const uint3 storage = ddgi_cell_to_storage(ddgi, cell);
+        const float3 probe_pos = ddgi_cell_position(ddgi, cell);
+
+        const float3 trilinear = lerp(1.0 - alpha, alpha, float3(corner));
+        float weight = trilinear.x * trilinear.y * trilinear.z;
+
+        // Smooth backface test; probes behind the surface still contribute a little,
+        // so that thin features don't end up black.
+        const float3 dir_to_probe = normalize(probe_pos - pos);
+        const float backface = (dot(dir_to_probe, normal) + 1.0) * 0.5;
+        float vis_weight = backface * backface + 0.2;
+
+        // Chebyshev visibility test against the distances the probe saw in this direction.
+        const float3 probe_to_pos = biased_pos - probe_pos;
+        const float dist_to_probe = length(probe_to_pos);
+        const float2 moments = ddgi_visibility_tex.SampleLevel(
+            sampler_lnc,
+            ddgi_atlas_uv(ddgi, storage, probe_to_pos / max(1e-5, dist_to_probe), DDGI_VISIBILITY_PROBE_RES, visibility_atlas_size),
+            0
+        ).xy;
+
+        if (dist_to_probe > moments.x) {
+            const float variance = abs(moments.y - moments.x * moments.x);
+            const float delta = dist_to_probe - moments.x;
+            const float chebyshev = variance / (variance + delta * delta);
+            vis_weight *= max(0.05, pow(chebyshev, leak_reduction.visibility_sharpness));
+        }
+
+        // Crush tiny weights, which would otherwise still leak light through walls.
+        vis_weight = max(1e-6, vis_weight);
+        const float crush_threshold = 0.2;
+        if (vis_weight < crush_threshold) {
+            vis_weight *= vis_weight * vis_weight / (crush_threshold * crush_threshold);
+        }
+
+        weight *= vis_weight;
+
+        const float3 irradiance = ddgi_irradiance_tex.SampleLevel(
+            sampler_lnc,
+            ddgi_atlas_uv(ddgi, storage, normal, DDGI_IRRADIANCE_PROBE_RES, irradiance_atlas_size),
+            0
+        ).rgb;
+
+        irradiance_sum += irradiance * weight;
+        weight_sum += weight;
+    }
+
+    return weight_sum > 0.0 ? irradiance_sum / weight_sum : 0.0.xxx;
+}
+
+#endif  // DDGI_LOOKUP_HLSL
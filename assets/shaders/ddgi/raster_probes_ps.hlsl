@@ -0,0 +1,32 @@
+#include "../inc/frame_constants.hlsl"
+#include "../inc/pack_unpack.hlsl"
+#include "../inc/gbuffer.hlsl"
+
+struct PsIn {
+    [[vk::location(0)]] float4 color: TEXCOORD0;
+    [[vk::location(1)]] float3 normal: TEXCOORD1;
+};
+
+struct PsOut {
+    float3 geometric_normal: SV_TARGET0;
+    float4 gbuffer: SV_TARGET1;
+    float4 velocity: SV_TARGET2;
+};
+
+// Probes are black and emissive, so that they show exactly the irradiance they store.
+PsOut main(PsIn ps) {
+    GbufferData gbuffer = GbufferData::create_zero();
+    gbuffer.albedo = 0.0;
+    gbuffer.emissive = ps.color.rgb;
+    gbuffer.normal = normalize(ps.normal);
+    gbuffer.roughness = 1.0;
+    gbuffer.metalness = 0.0;
+
+    float3 geometric_normal = mul(frame_constants.view_constants.world_to_view, float4(gbuffer.normal, 0)).xyz;
+
+    PsOut ps_out;
+    ps_out.geometric_normal = geometric_normal * 0.5 + 0.5;
+    ps_out.gbuffer = asfloat(gbuffer.pack().data0);
+    ps_out.velocity = 0.0;
+    return ps_out;
+}
@@ -0,0 +1,63 @@
+#include "../inc/frame_constants.hlsl"
+#include "../inc/samplers.hlsl"
+#include "common.hlsl"
+
+[[vk::binding(0)]] Texture2D<float4> ddgi_irradiance_tex;
+[[vk::binding(1)]] cbuffer _ {
+    DdgiConstants ddgi_constants;
+};
+
+// Must match `DEBUG_SPHERE_*` in `ddgi.rs`
+#define SPHERE_SEGMENTS 12
+#define SPHERE_RINGS 8
+
+// Fraction of the probe spacing used as the sphere radius
+#define SPHERE_RADIUS_SCALE 0.1
+
+struct VsOut {
+    float4 position: SV_Position;
+    [[vk::location(0)]] float4 color: TEXCOORD0;
+    [[vk::location(1)]] float3 normal: TEXCOORD1;
+};
+
+// One instance per probe, in storage order. Each sphere is a grid of quads in latitude/longitude.
+// Vertices are colored by the irradiance the probe stores for their normal's direction.
+VsOut main(uint vid: SV_VertexID, uint instance_index: SV_InstanceID) {
+    static const float2 quad_verts[6] = {
+        float2(0, 0),
+        float2(1, 1),
+        float2(1, 0),
+
+        float2(1, 1),
+        float2(0, 0),
+        float2(0, 1),
+    };
+
+    const DdgiConstants ddgi = ddgi_constants;
+    const uint3 storage = ddgi_index_to_storage(ddgi, instance_index);
+    const float3 probe_pos = ddgi_cell_position(ddgi, ddgi_storage_to_cell(ddgi, storage));
+
+    const uint quad_idx = vid / 6;
+    const float2 quad_vert = quad_verts[vid % 6];
+    const float theta = (quad_idx / SPHERE_SEGMENTS + quad_vert.y) * M_PI / SPHERE_RINGS;
+    const float phi = (quad_idx % SPHERE_SEGMENTS + quad_vert.x) * 2.0 * M_PI / SPHERE_SEGMENTS;
+    const float3 normal = float3(sin(theta) * cos(phi), cos(theta), sin(theta) * sin(phi));
+
+    const float3 ws_pos = probe_pos + normal * ddgi_probe_spacing(ddgi) * SPHERE_RADIUS_SCALE;
+    const float4 vs_pos = mul(frame_constants.view_constants.world_to_view, float4(ws_pos, 1.0));
+
+    float2 atlas_size;
+    ddgi_irradiance_tex.GetDimensions(atlas_size.x, atlas_size.y);
+
+    const float3 irradiance = ddgi_irradiance_tex.SampleLevel(
+        sampler_lnc,
+        ddgi_atlas_uv(ddgi, storage, normal, DDGI_IRRADIANCE_PROBE_RES, atlas_size),
+        0
+    ).rgb;
+
+    VsOut vsout;
+    vsout.position = mul(frame_constants.view_constants.view_to_sample, vs_pos);
+    vsout.color = float4(irradiance, 1);
+    vsout.normal = normal;
+    return vsout;
+}
@@ -0,0 +1,45 @@
+#include "../inc/frame_constants.hlsl"
+#include "../inc/gbuffer.hlsl"
+#include "../inc/uv.hlsl"
+#include "common.hlsl"
+
+[[vk::binding(0)]] Texture2D<float4> gbuffer_tex;
+[[vk::binding(1)]] Texture2D<float> depth_tex;
+[[vk::binding(2)]] Texture2D<float4> ddgi_irradiance_tex;
+[[vk::binding(3)]] Texture2D<float2> ddgi_visibility_tex;
+[[vk::binding(4)]] RWTexture2D<float4> output_tex;
+[[vk::binding(5)]] cbuffer _ {
+    float4 output_tex_size;
+    DdgiConstants ddgi_constants;
+};
+
+#include "lookup.hlsl"
+
+[[vk::push_constant]]
+DdgiLeakReduction push_constants;
+
+// Looks up the probe grid for every pixel of the G-buffer. The output can be used
+// in place of the screen-space diffuse GI.
+[numthreads(8, 8, 1)]
+void main(uint2 px: SV_DispatchThreadID) {
+    const float depth = depth_tex[px];
+
+    if (0.0 == depth) {
+        output_tex[px] = 0.0.xxxx;
+        return;
+    }
+
+    const float2 uv = get_uv(px, output_tex_size);
+    const ViewRayContext view_ray_context = ViewRayContext::from_uv_and_depth(uv, depth);
+    const float3 normal = GbufferDataPacked::from_uint4(asuint(gbuffer_tex[px])).unpack_normal();
+
+    const float3 irradiance = ddgi_lookup_irradiance(
+        ddgi_constants,
+        push_constants,
+        view_ray_context.ray_hit_ws(),
+        normal,
+        view_ray_context.ray_dir_ws()
+    );
+
+    output_tex[px] = float4(irradiance, 1);
+}
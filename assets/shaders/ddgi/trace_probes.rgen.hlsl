@@ -0,0 +1,106 @@
+#include "../inc/frame_constants.hlsl"
+#include "../inc/gbuffer.hlsl"
+#include "../inc/brdf.hlsl"
+#include "../inc/layered_brdf.hlsl"
+#include "../inc/rt.hlsl"
+#include "../inc/atmosphere.hlsl"
+#include "../inc/sun.hlsl"
+#include "common.hlsl"
+
+#define USE_EMISSIVE 1
+
+// Feed the probes' own irradiance back into hit shading, accumulating bounces over frames.
+#define USE_MULTI_BOUNCE 1
+
+[[vk::binding(0, 3)]] RaytracingAccelerationStructure acceleration_structure;
+
+[[vk::binding(0)]] Texture2D<float4> ddgi_irradiance_tex;
+[[vk::binding(1)]] Texture2D<float2> ddgi_visibility_tex;
+[[vk::binding(2)]] TextureCube<float4> sky_cube_tex;
+[[vk::binding(3)]] RWTexture2D<float4> ray_output_tex;
+[[vk::binding(4)]] cbuffer _ {
+    DdgiConstants ddgi_constants;
+};
+
+#include "lookup.hlsl"
+
+static const float SKY_DIST = 1e4;
+
+// One thread per ray; X indexes rays of a probe, Y indexes probes in storage order.
+// Outputs radiance along the ray, and the distance to the hit (negative for back faces).
+[shader("raygeneration")]
+void main() {
+    const uint ray_idx = DispatchRaysIndex().x;
+    const uint probe_idx = DispatchRaysIndex().y;
+
+    const DdgiConstants ddgi = ddgi_constants;
+    const int3 cell = ddgi_storage_to_cell(ddgi, ddgi_index_to_storage(ddgi, probe_idx));
+
+    RayDesc outgoing_ray;
+    outgoing_ray.Origin = ddgi_cell_position(ddgi, cell);
+    outgoing_ray.Direction = ddgi_ray_direction(ddgi, ray_idx);
+    outgoing_ray.TMin = 0;
+    outgoing_ray.TMax = SKY_DIST;
+
+    // Back faces must be hit, so that probes inside geometry can be detected.
+    const GbufferPathVertex primary_hit = GbufferRaytrace::with_ray(outgoing_ray)
+        .with_cone(RayCone::from_spread_angle(0.1))
+        .with_cull_back_faces(false)
+        .with_path_length(1)
+        .trace(acceleration_structure);
+
+    if (!primary_hit.is_hit) {
+        const float3 sky = sky_cube_tex.SampleLevel(sampler_llr, outgoing_ray.Direction, 0).rgb;
+        ray_output_tex[DispatchRaysIndex().xy] = float4(sky, ddgi.max_visibility_distance);
+        return;
+    }
+
+    GbufferData gbuffer = primary_hit.gbuffer_packed.unpack();
+
+    if (dot(gbuffer.normal, outgoing_ray.Direction) > 0.0) {
+        ray_output_tex[DispatchRaysIndex().xy] =
+            float4(0.0.xxx, -primary_hit.ray_t * DDGI_BACKFACE_DISTANCE_SCALE);
+        return;
+    }
+
+    const float3x3 tangent_to_world = gbuffer.tangent_to_world();
+    const float3 wo = mul(-outgoing_ray.Direction, tangent_to_world);
+    const LayeredBrdf brdf = LayeredBrdf::from_gbuffer_ndotv(gbuffer, wo.z);
+
+    float3 total_radiance = 0.0.xxx;
+
+    const float3 sun_radiance = SUN_COLOR;
+    if (any(sun_radiance > 0)) {
+        const float3 to_light_norm = SUN_DIRECTION;
+
+        const bool is_shadowed =
+            rt_is_shadowed(
+                acceleration_structure,
+                new_ray(
+                    primary_hit.position,
+                    to_light_norm,
+                    1e-4,
+                    SKY_DIST
+            ));
+
+        const float3 wi = mul(to_light_norm, tangent_to_world);
+        const float3 brdf_value = brdf.evaluate(wo, wi) * max(0.0, wi.z);
+        total_radiance += is_shadowed ? 0.0 : brdf_value * sun_radiance;
+    }
+
+    if (USE_EMISSIVE) {
+        total_radiance += gbuffer.emissive;
+    }
+
+    if (USE_MULTI_BOUNCE && ddgi.reset_history == 0) {
+        total_radiance += gbuffer.albedo * ddgi_lookup_irradiance(
+            ddgi,
+            DdgiLeakReduction::from_defaults(),
+            primary_hit.position,
+            gbuffer.normal,
+            outgoing_ray.Direction
+        );
+    }
+
+    ray_output_tex[DispatchRaysIndex().xy] = float4(total_radiance, primary_hit.ray_t);
+}
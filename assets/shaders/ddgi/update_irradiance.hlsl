@@ -0,0 +1,45 @@
+#include "common.hlsl"
+
+[[vk::binding(0)]] Texture2D<float4> ray_tex;
+[[vk::binding(1)]] Texture2D<float4> history_tex;
+[[vk::binding(2)]] RWTexture2D<float4> output_tex;
+[[vk::binding(3)]] cbuffer _ {
+    DdgiConstants ddgi_constants;
+};
+
+// One thread per atlas texel. Border texels compute the same value as the interior texel
+// they duplicate, so no separate pass is needed to fix up the borders.
+[numthreads(8, 8, 1)]
+void main(uint2 px: SV_DispatchThreadID) {
+    const DdgiConstants ddgi = ddgi_constants;
+    const uint tile_size = DDGI_IRRADIANCE_PROBE_RES + 2;
+
+    const uint3 storage = ddgi_atlas_tile_to_storage(ddgi, px / tile_size);
+    if (any(storage >= ddgi.probe_counts.xyz)) {
+        return;
+    }
+
+    const uint probe_idx = ddgi_storage_to_index(ddgi, storage);
+    const uint2 interior_texel = ddgi_octa_texel_to_interior(px % tile_size, DDGI_IRRADIANCE_PROBE_RES);
+    const float3 texel_dir = ddgi_octa_texel_direction(interior_texel, DDGI_IRRADIANCE_PROBE_RES);
+
+    float3 irradiance_sum = 0.0.xxx;
+    float weight_sum = 0.0;
+
+    for (uint ray_idx = 0; ray_idx < ddgi_rays_per_probe(ddgi); ++ray_idx) {
+        const float weight = max(0.0, dot(texel_dir, ddgi_ray_direction(ddgi, ray_idx)));
+        if (weight > 0.0) {
+            irradiance_sum += ray_tex[uint2(ray_idx, probe_idx)].rgb * weight;
+            weight_sum += weight;
+        }
+    }
+
+    const float3 irradiance = weight_sum > 0.0 ? irradiance_sum / weight_sum : 0.0.xxx;
+
+    if (ddgi_cell_is_new(ddgi, ddgi_storage_to_cell(ddgi, storage))) {
+        output_tex[px] = float4(irradiance, 1);
+    } else {
+        const float3 history = history_tex[px].rgb;
+        output_tex[px] = float4(lerp(irradiance, history, ddgi.hysteresis), 1);
+    }
+}
@@ -0,0 +1,50 @@
+#include "common.hlsl"
+
+[[vk::binding(0)]] Texture2D<float4> ray_tex;
+[[vk::binding(1)]] Texture2D<float2> history_tex;
+[[vk::binding(2)]] RWTexture2D<float2> output_tex;
+[[vk::binding(3)]] cbuffer _ {
+    DdgiConstants ddgi_constants;
+};
+
+// Accumulates the mean and mean squared distance to geometry for each texel of the probes'
+// octahedral maps. Border texels are handled the same way as in `update_irradiance.hlsl`.
+[numthreads(8, 8, 1)]
+void main(uint2 px: SV_DispatchThreadID) {
+    const DdgiConstants ddgi = ddgi_constants;
+    const uint tile_size = DDGI_VISIBILITY_PROBE_RES + 2;
+
+    const uint3 storage = ddgi_atlas_tile_to_storage(ddgi, px / tile_size);
+    if (any(storage >= ddgi.probe_counts.xyz)) {
+        return;
+    }
+
+    const uint probe_idx = ddgi_storage_to_index(ddgi, storage);
+    const uint2 interior_texel = ddgi_octa_texel_to_interior(px % tile_size, DDGI_VISIBILITY_PROBE_RES);
+    const float3 texel_dir = ddgi_octa_texel_direction(interior_texel, DDGI_VISIBILITY_PROBE_RES);
+
+    float2 moments_sum = 0.0.xx;
+    float weight_sum = 0.0;
+
+    for (uint ray_idx = 0; ray_idx < ddgi_rays_per_probe(ddgi); ++ray_idx) {
+        const float weight = pow(
+            max(0.0, dot(texel_dir, ddgi_ray_direction(ddgi, ray_idx))),
+            DDGI_VISIBILITY_SHARPNESS
+        );
+
+        if (weight > 0.0) {
+            const float dist = min(abs(ray_tex[uint2(ray_idx, probe_idx)].a), ddgi.max_visibility_distance);
+            moments_sum += float2(dist, dist * dist) * weight;
+            weight_sum += weight;
+        }
+    }
+
+    const float max_dist = ddgi.max_visibility_distance;
+    const float2 moments = weight_sum > 0.0 ? moments_sum / weight_sum : float2(max_dist, max_dist * max_dist);
+
+    if (ddgi_cell_is_new(ddgi, ddgi_storage_to_cell(ddgi, storage))) {
+        output_tex[px] = moments;
+    } else {
+        output_tex[px] = lerp(moments, history_tex[px], ddgi.hysteresis);
+    }
+}
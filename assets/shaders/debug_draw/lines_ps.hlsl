@@ -0,0 +1,8 @@
+struct PsIn {
+    [[vk::location(0)]] float4 color: COLOR0;
+};
+
+// Premultiplied color of the overlay
+float4 main(PsIn ps): SV_TARGET {
+    return ps.color;
+}
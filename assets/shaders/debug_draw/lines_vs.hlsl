@@ -0,0 +1,26 @@
+#include "../inc/frame_constants.hlsl"
+
+// Must match `DebugVertex` in `debug_draw.rs`
+struct DebugVertex {
+    float4 position;
+    float4 color;
+};
+
+[[vk::binding(0)]] StructuredBuffer<DebugVertex> vertices_dyn;
+
+struct VsOut {
+	float4 position: SV_Position;
+    [[vk::location(0)]] float4 color: COLOR0;
+};
+
+VsOut main(uint vid: SV_VertexID) {
+    const DebugVertex v = vertices_dyn[vid];
+
+    float4 vs_pos = mul(frame_constants.view_constants.world_to_view, float4(v.position.xyz, 1.0));
+
+    VsOut vsout;
+    vsout.position = mul(frame_constants.view_constants.view_to_sample, vs_pos);
+    vsout.color = float4(v.color.rgb * v.color.a, v.color.a);
+
+    return vsout;
+}
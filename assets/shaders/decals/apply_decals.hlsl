@@ -0,0 +1,111 @@
+#include "../inc/samplers.hlsl"
+#include "../inc/frame_constants.hlsl"
+#include "../inc/gbuffer.hlsl"
+#include "../inc/bindless_textures.hlsl"
+
+// Must match `DecalConstants` in `decals.rs`
+struct DecalConstants {
+    row_major float3x4 world_to_decal;
+    float4 tint_opacity;
+    // Negative to leave the surface's roughness unchanged
+    float roughness;
+    uint albedo_map;
+    uint normal_map;
+    uint blend_mode;
+};
+
+static const uint NO_TEXTURE = 0xffffffff;
+static const uint DECAL_BLEND_ALPHA = 0;
+static const uint DECAL_BLEND_MULTIPLY = 1;
+
+[[vk::binding(0)]] Texture2D<float> depth_tex;
+[[vk::binding(1)]] RWTexture2D<float4> gbuffer_tex;
+[[vk::binding(2)]] StructuredBuffer<DecalConstants> decals_dyn;
+[[vk::binding(3)]] cbuffer _ {
+    float4 output_tex_size;
+    uint decal_count;
+};
+
+// Mip level matching the footprint of a pixel on the decal, as there are no screen-space derivatives in compute.
+float decal_texture_lod(Texture2D tex, float uv_per_world_unit, float view_distance) {
+    float2 tex_size;
+    tex.GetDimensions(tex_size.x, tex_size.y);
+
+    const float pixel_world_size = view_distance * 2.0 / (frame_constants.view_constants.view_to_clip[1][1] * output_tex_size.y);
+    return log2(max(1e-8, pixel_world_size * uv_per_world_unit * max(tex_size.x, tex_size.y)));
+}
+
+[numthreads(8, 8, 1)]
+void main(uint2 px: SV_DispatchThreadID) {
+    const float depth = depth_tex[px];
+    if (depth == 0.0) {
+        return;
+    }
+
+    const float2 uv = get_uv(px, output_tex_size);
+    const ViewRayContext view_ray_context = ViewRayContext::from_uv_and_depth(uv, depth);
+    const float3 pos_ws = view_ray_context.ray_hit_ws();
+    const float view_distance = -view_ray_context.ray_hit_vs().z;
+
+    GbufferData gbuffer = GbufferDataPacked::from_uint4(asuint(gbuffer_tex[px])).unpack();
+    bool modified = false;
+
+    // In order of addition; later decals are applied over earlier ones.
+    for (uint decal_idx = 0; decal_idx < decal_count; ++decal_idx) {
+        const DecalConstants decal = decals_dyn[decal_idx];
+
+        // Matched by `Decal::uv_at` in `decals.rs`
+        const float3 local = mul(decal.world_to_decal, float4(pos_ws, 1.0));
+        if (any(abs(local) > 0.5)) {
+            continue;
+        }
+        const float2 decal_uv = float2(local.x + 0.5, 0.5 - local.y);
+
+        // The rows of `world_to_decal` are the decal's axes, divided by its size.
+        const float3 tangent = normalize(decal.world_to_decal[0].xyz);
+        const float3 bitangent = normalize(decal.world_to_decal[1].xyz);
+        const float3 projection_normal = normalize(decal.world_to_decal[2].xyz);
+        const float uv_per_world_unit = length(decal.world_to_decal[0].xyz);
+
+        // Fade out on surfaces parallel to the projection direction, and don't affect back-facing ones.
+        const float angle_fade = saturate((dot(gbuffer.normal, projection_normal) - 0.1) / 0.3);
+
+        float3 albedo = decal.tint_opacity.rgb;
+        float alpha = decal.tint_opacity.a * angle_fade;
+
+        if (decal.albedo_map != NO_TEXTURE) {
+            Texture2D tex = bindless_textures[NonUniformResourceIndex(decal.albedo_map)];
+            const float4 texel = tex.SampleLevel(sampler_llr, decal_uv, decal_texture_lod(tex, uv_per_world_unit, view_distance));
+            albedo *= texel.rgb;
+            alpha *= texel.a;
+        }
+
+        if (alpha <= 0.0) {
+            continue;
+        }
+
+        if (decal.blend_mode == DECAL_BLEND_MULTIPLY) {
+            gbuffer.albedo *= lerp(1.0.xxx, albedo, alpha);
+        } else {
+            gbuffer.albedo = lerp(gbuffer.albedo, albedo, alpha);
+        }
+
+        if (decal.normal_map != NO_TEXTURE) {
+            Texture2D tex = bindless_textures[NonUniformResourceIndex(decal.normal_map)];
+            const float3 ts_normal = tex.SampleLevel(sampler_llr, decal_uv, decal_texture_lod(tex, uv_per_world_unit, view_distance)).xyz * 2.0 - 1.0;
+
+            // Tilt the surface normal by the decal's, projected onto the decal's plane.
+            gbuffer.normal = normalize(gbuffer.normal + (tangent * ts_normal.x + bitangent * ts_normal.y) * alpha);
+        }
+
+        if (decal.roughness >= 0.0) {
+            gbuffer.roughness = lerp(gbuffer.roughness, decal.roughness, alpha);
+        }
+
+        modified = true;
+    }
+
+    if (modified) {
+        gbuffer_tex[px] = asfloat(gbuffer.pack().data0);
+    }
+}
@@ -0,0 +1,13 @@
+// Must match `EguiPushConstants` in `egui_renderer.rs`
+[[vk::push_constant]]
+struct {
+    float2 screen_size_points;
+    uint base_vertex;
+    uint texture;
+} push_constants;
+
+struct VsOut {
+	float4 position: SV_Position;
+    [[vk::location(0)]] float2 uv: TEXCOORD0;
+    [[vk::location(1)]] float4 color: COLOR0;
+};
@@ -0,0 +1,10 @@
+#include "../inc/samplers.hlsl"
+#include "../inc/bindless_textures.hlsl"
+#include "egui_common.hlsl"
+
+float4 main(VsOut ps): SV_TARGET {
+    const float4 tex = bindless_textures[NonUniformResourceIndex(push_constants.texture)]
+        .SampleLevel(sampler_llc, ps.uv, 0);
+
+    return ps.color * tex;
+}
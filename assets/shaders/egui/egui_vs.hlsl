@@ -0,0 +1,30 @@
+#include "egui_common.hlsl"
+
+// Must match `EguiVertex` in `egui_renderer.rs`
+struct EguiVertex {
+    float2 pos;
+    float2 uv;
+    uint color;
+    uint pad;
+};
+
+[[vk::binding(0)]] StructuredBuffer<EguiVertex> vertices_dyn;
+[[vk::binding(1)]] StructuredBuffer<uint> indices_dyn;
+
+// Drawn without an index buffer; `vid` starts at the first index of the mesh.
+VsOut main(uint vid: SV_VertexID) {
+    const EguiVertex v = vertices_dyn[push_constants.base_vertex + indices_dyn[vid]];
+
+    VsOut vsout;
+    vsout.position = float4(v.pos / push_constants.screen_size_points * 2.0 - 1.0, 0.0, 1.0);
+    vsout.uv = v.uv;
+    // Premultiplied sRGB; the UI is blended in gamma space, like Dear ImGui.
+    vsout.color = float4(
+        v.color & 0xff,
+        (v.color >> 8) & 0xff,
+        (v.color >> 16) & 0xff,
+        v.color >> 24
+    ) / 255.0;
+
+    return vsout;
+}
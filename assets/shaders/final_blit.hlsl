@@ -4,10 +4,43 @@
 [[vk::binding(3)]] cbuffer _ {
     float4 main_tex_size;
     float4 output_tex_size;
+    uint output_encoding;
+    float sdr_white_nits;
+    uint noise_frame;
+    float noise_amplitude;
+    // Offset and size of the main image within the output, in pixels; see `Viewport`.
+    float4 main_rect;
+    // Linear, drawn outside of `main_rect`
+    float4 bar_color;
 };
 
 #include "inc/image.hlsl"
+#include "inc/blue_noise.hlsl"
 #include "inc/color/srgb.hlsl"
+#include "inc/color/ictcp.hlsl"
+
+// Must match `SwapchainColorSpace` in the backend.
+#define OUTPUT_ENCODING_SDR 0
+#define OUTPUT_ENCODING_HDR10 1
+#define OUTPUT_ENCODING_SCRGB 2
+
+// scRGB defines 1.0 as 80 nits.
+static const float SCRGB_WHITE_NITS = 80.0;
+
+// Uniform noise to a triangular distribution in [-1, 1]
+float triangle_remap(float n) {
+    float origin = n * 2.0 - 1.0;
+    float v = origin * rsqrt(abs(origin));
+    v = max(-1.0, v);
+    v -= sign(origin);
+    return v;
+}
+
+// Dither and film grain, in units of the output encoding; see `FilmGrain`.
+float3 output_noise(uint2 px) {
+    const float3 noise = blue_noise_for_pixel(px, noise_frame).rgb;
+    return float3(triangle_remap(noise.x), triangle_remap(noise.y), triangle_remap(noise.z)) * noise_amplitude;
+}
 
 struct LinearToSrgbRemap {
     static LinearToSrgbRemap create() {
@@ -23,20 +56,58 @@ struct LinearToSrgbRemap {
 [numthreads(8, 8, 1)]
 void main(in uint2 px : SV_DispatchThreadID) {
     #if 1
-    float3 main;
-    if (any(main_tex_size.xy != output_tex_size.xy)) {
-        main = image_sample_catmull_rom(
-            TextureImage::from_parts(main_tex, main_tex_size.xy),
-            (px + 0.5) / output_tex_size.xy,
-            LinearToSrgbRemap::create()
-        ).rgb;
-    } else {
-        main = sRGB_EOTF(saturate(main_tex[px].rgb));
-    }
     float4 gui = gui_tex[px];
+    float3 result;
 
-    float3 result = main.rgb * (1.0 - gui.a) + gui.rgb;
-    //float3 result = lerp(main, gui.rgb, gui.a);
+    const float2 main_uv = (px + 0.5 - main_rect.xy) / main_rect.zw;
+    const bool in_bars = any(main_uv < 0.0) || any(main_uv >= 1.0);
+    const uint2 main_px = px - uint2(main_rect.xy);
+
+    if (output_encoding == OUTPUT_ENCODING_SDR) {
+        float3 main;
+        if (in_bars) {
+            main = sRGB_EOTF(saturate(bar_color.rgb));
+        } else if (any(main_tex_size.xy != main_rect.zw)) {
+            main = image_sample_catmull_rom(
+                TextureImage::from_parts(main_tex, main_tex_size.xy),
+                main_uv,
+                LinearToSrgbRemap::create()
+            ).rgb;
+        } else {
+            main = sRGB_EOTF(saturate(main_tex[main_px].rgb));
+        }
+
+        result = main.rgb * (1.0 - gui.a) + gui.rgb;
+        //result = lerp(main, gui.rgb, gui.a);
+
+        // Before quantization to the swapchain format
+        result = saturate(result + output_noise(px));
+    } else {
+        // HDR: the main image is linear, with 1.0 being SDR white,
+        // and values above it reaching up to the display's peak brightness.
+        float3 main;
+        if (in_bars) {
+            main = bar_color.rgb;
+        } else if (any(main_tex_size.xy != main_rect.zw)) {
+            main = image_sample_catmull_rom(
+                TextureImage::from_parts(main_tex, main_tex_size.xy),
+                main_uv
+            ).rgb;
+        } else {
+            main = main_tex[main_px].rgb;
+        }
+        main = max(0.0, main);
+
+        // The UI is sRGB-encoded and premultiplied; composite it in linear at SDR white.
+        float3 linear_gui = sRGB_OETF(gui.rgb);
+        float3 nits = (main * (1.0 - gui.a) + linear_gui) * sdr_white_nits;
+
+        if (output_encoding == OUTPUT_ENCODING_HDR10) {
+            result = saturate(linear_to_PQ(BT709_to_BT2020(nits)) + output_noise(px));
+        } else {
+            result = nits / SCRGB_WHITE_NITS;
+        }
+    }
     #else
     float3 result = float3(0.7, 0.4, 0.1);
     #endif
@@ -1,5 +1,10 @@
+#ifndef BINDLESS_HLSL
+#define BINDLESS_HLSL
+
 #include "mesh.hlsl"
 
 [[vk::binding(0, 1)]] StructuredBuffer<Mesh> meshes;
 [[vk::binding(1, 1)]] ByteAddressBuffer vertices;
 #include "bindless_textures.hlsl"
+
+#endif
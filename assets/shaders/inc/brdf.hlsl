@@ -1,4 +1,5 @@
 #include "math.hlsl"
+#include "thin_film.hlsl"
 
 static const uint2 BRDF_FG_LUT_DIMS = uint2(64, 64);
 static const float2 BRDF_FG_LUT_UV_SCALE = (BRDF_FG_LUT_DIMS - 1.0) / BRDF_FG_LUT_DIMS;
@@ -95,8 +96,13 @@ struct SmithShadowingMasking {
     float g_over_g1_wo;
 
     static float g_smith_ggx_correlated(float ndotv, float ndotl, float a2) {
-    	float lambda_v = ndotl * sqrt((-ndotv * a2 + ndotv) * ndotv + a2);
-    	float lambda_l = ndotv * sqrt((-ndotl * a2 + ndotl) * ndotl + a2);
+        return g_smith_ggx_correlated(ndotv, ndotl, a2, a2);
+    }
+
+    // With anisotropic roughness, `a2_v` and `a2_l` are the squared roughness along each direction.
+    static float g_smith_ggx_correlated(float ndotv, float ndotl, float a2_v, float a2_l) {
+    	float lambda_v = ndotl * sqrt((-ndotv * a2_v + ndotv) * ndotv + a2_v);
+    	float lambda_l = ndotv * sqrt((-ndotl * a2_l + ndotl) * ndotl + a2_l);
 
     	return 2.0 * ndotl * ndotv / (lambda_v + lambda_l);
     }
@@ -115,28 +121,91 @@ struct SmithShadowingMasking {
     }
 
     static SmithShadowingMasking eval(float ndotv, float ndotl, float a2) {
+        return eval(ndotv, ndotl, a2, a2);
+    }
+
+    static SmithShadowingMasking eval(float ndotv, float ndotl, float a2_v, float a2_l) {
         SmithShadowingMasking res;
     #if USE_GGX_CORRELATED_MASKING
-        res.g = g_smith_ggx_correlated(ndotv, ndotl, a2);
-        res.g_over_g1_wo = res.g / g_smith_ggx1(ndotv, a2);
+        res.g = g_smith_ggx_correlated(ndotv, ndotl, a2_v, a2_l);
+        res.g_over_g1_wo = res.g / g_smith_ggx1(ndotv, a2_v);
     #else
-        res.g = g_smith_ggx1(ndotl, a2) * g_smith_ggx1(ndotv, a2);
-        res.g_over_g1_wo = g_smith_ggx1(ndotl, a2);
+        res.g = g_smith_ggx1(ndotl, a2_l) * g_smith_ggx1(ndotv, a2_v);
+        res.g_over_g1_wo = g_smith_ggx1(ndotl, a2_l);
     #endif
         return res;
     }
 };
 
+// Anisotropic GGX. Tangent space X is the direction the highlight is stretched along,
+// e.g. across the grooves of brushed metal; `GbufferData::tangent_to_world` provides such a basis.
 struct SpecularBrdf {
     float roughness;
     float3 albedo;
     //float3 emission;
 
+    // 0 is isotropic; towards 1, `roughness` grows along X, and shrinks along Y.
+    float anisotropy;
+
+    // Blends the Fresnel term towards that of a thin film over the surface, from 0 to 1.
+    float iridescence;
+    float iridescence_ior;
+    // Nanometers
+    float iridescence_thickness;
+
+    float3 eval_fresnel(float cos_theta) {
+        const float3 fresnel = eval_fresnel_schlick(albedo, 1.0, cos_theta);
+        if (iridescence <= 0.0) {
+            return fresnel;
+        }
+
+        return lerp(
+            fresnel,
+            eval_thin_film_fresnel(1.0, iridescence_ior, cos_theta, iridescence_thickness, albedo),
+            iridescence
+        );
+    }
+
+    // Roughness along tangent space X and Y; the parameterization from Filament.
+    float2 alpha() {
+        if (anisotropy <= 0.0) {
+            return roughness.xx;
+        }
+
+        return float2(
+            roughness * (1.0 + anisotropy),
+            max(1e-4, roughness * (1.0 - anisotropy))
+        );
+    }
+
+    // Squared roughness along the projection of `w` onto the tangent plane.
+    float alpha2_along(float3 w) {
+        const float2 a = alpha();
+        if (anisotropy <= 0.0) {
+            return a.x * a.x;
+        }
+
+        const float sin2_theta = w.x * w.x + w.y * w.y;
+        return sin2_theta > 0.0
+            ? (a.x * a.x * w.x * w.x + a.y * a.y * w.y * w.y) / sin2_theta
+            : a.x * a.y;
+    }
+
 	static float ggx_ndf(float a2, float cos_theta) {
 		float denom_sqrt = cos_theta * cos_theta * (a2 - 1.0) + 1.0;
 		return a2 / (M_PI * denom_sqrt * denom_sqrt);
 	}
 
+    static float ggx_ndf(float2 alpha, float3 m) {
+        if (alpha.x == alpha.y) {
+            return ggx_ndf(alpha.x * alpha.x, m.z);
+        }
+
+        const float3 m_stretched = float3(m.x / alpha.x, m.y / alpha.y, m.z);
+        const float denom_sqrt = dot(m_stretched, m_stretched);
+        return 1.0 / (M_PI * alpha.x * alpha.y * denom_sqrt * denom_sqrt);
+    }
+
     static float pdf_ggx(float a2, float cos_theta) {
 		return ggx_ndf(a2, cos_theta) * cos_theta;
 	}
@@ -147,26 +216,36 @@ struct SpecularBrdf {
         return g1 * d * max(0.f, dot(wo, h)) / wo.z;
     }
 
+    float pdf_ggx_vn_anisotropic(float3 wo, float3 h) {
+        float g1 = SmithShadowingMasking::g_smith_ggx1(wo.z, alpha2_along(wo));
+        float d = ggx_ndf(alpha(), h);
+        return g1 * d * max(0.f, dot(wo, h)) / wo.z;
+    }
+
     NdfSample sample_ndf(float2 urand) {
-        const float a2 = roughness * roughness;
+        const float2 a = alpha();
+
+        // The azimuth is stretched with the roughness, and the elevation follows the roughness along it.
+        const float phi = atan2(a.y * sin(M_TAU * urand.y), a.x * cos(M_TAU * urand.y));
+        const float cos_phi = cos(phi);
+        const float sin_phi = sin(phi);
+        const float a2 = 1.0 / (cos_phi * cos_phi / (a.x * a.x) + sin_phi * sin_phi / (a.y * a.y));
 
 		const float cos2_theta = (1 - urand.x) / (1 - urand.x + a2 * urand.x);
 		const float cos_theta = sqrt(cos2_theta);
-		const float phi = M_TAU * urand.y;
 
 		const float sin_theta = sqrt(max(0.0, 1.0 - cos2_theta));
 
         NdfSample res;
-		res.m = float3(cos(phi) * sin_theta, sin(phi) * sin_theta, cos_theta);
-        res.pdf = pdf_ggx(a2, cos_theta);
+		res.m = float3(cos_phi * sin_theta, sin_phi * sin_theta, cos_theta);
+        res.pdf = ggx_ndf(a, res.m) * cos_theta;
 
         return res;
     }
 
     // From https://github.com/NVIDIAGameWorks/Falcor/blob/c0729e806045731d71cfaae9d31a992ac62070e7/Source/Falcor/Experimental/Scene/Material/Microfacet.slang
-    NdfSample sample_vndf(float alpha, float3 wo, float2 urand) {
-        float alpha_x = alpha, alpha_y = alpha;
-        float a2 = alpha_x * alpha_y;
+    NdfSample sample_vndf(float2 alpha, float3 wo, float2 urand) {
+        float alpha_x = alpha.x, alpha_y = alpha.y;
 
         // Transform the view vector to the hemisphere configuration.
         float3 Vh = normalize(float3(alpha_x * wo.x, alpha_y * wo.y, wo.z));
@@ -188,7 +267,7 @@ struct SpecularBrdf {
 
         // Transform the normal back to the ellipsoid configuration. This is our half vector.
         float3 h = normalize(float3(alpha_x * Nh.x, alpha_y * Nh.y, max(0.f, Nh.z)));
-        float pdf = pdf_ggx_vn(a2, wo, h);
+        float pdf = pdf_ggx_vn_anisotropic(wo, h);
 
         NdfSample res;
         res.m = h;
@@ -198,7 +277,7 @@ struct SpecularBrdf {
 
     BrdfSample sample(float3 wo, float2 urand) {
         #if USE_GGX_VNDF_SAMPLING
-            NdfSample ndf_sample = sample_vndf(roughness, wo, urand);
+            NdfSample ndf_sample = sample_vndf(alpha(), wo, urand);
         #else
             NdfSample ndf_sample = sample_ndf(urand);
         #endif
@@ -212,11 +291,10 @@ struct SpecularBrdf {
 		// Change of variables from the half-direction space to regular lighting geometry.
 		const float jacobian = 1.0 / (4.0 * dot(wi, ndf_sample.m));
 
-        const float3 fresnel = eval_fresnel_schlick(albedo, 1.0, dot(ndf_sample.m, wi));
-        const float a2 = roughness * roughness;
+        const float3 fresnel = eval_fresnel(dot(ndf_sample.m, wi));
         const float cos_theta = ndf_sample.m.z;
 
-        SmithShadowingMasking shadowing_masking = SmithShadowingMasking::eval(wo.z, wi.z, a2);
+        SmithShadowingMasking shadowing_masking = SmithShadowingMasking::eval(wo.z, wi.z, alpha2_along(wo), alpha2_along(wi));
 
         BrdfSample res;
 		res.pdf = ndf_sample.pdf * jacobian / wi.z;
@@ -238,7 +316,7 @@ struct SpecularBrdf {
         res.value =
                 fresnel
     			* shadowing_masking.g
-                * ggx_ndf(a2, cos_theta)
+                * ggx_ndf(alpha(), ndf_sample.m)
                 / (4 * wo.z * wi.z);
 
 		return res;
@@ -249,23 +327,21 @@ struct SpecularBrdf {
             return BrdfValue::invalid();
         }
 
-        const float a2 = roughness * roughness;
-
         const float3 m = normalize(wo + wi);
 
         const float cos_theta = m.z;
 
         #if USE_GGX_VNDF_SAMPLING
-            const float pdf_h = pdf_ggx_vn(a2, wo, m);
+            const float pdf_h = pdf_ggx_vn_anisotropic(wo, m);
         #else
-            const float pdf_h = pdf_ggx(a2, cos_theta);
+            const float pdf_h = ggx_ndf(alpha(), m) * cos_theta;
         #endif
 
         const float jacobian = 1.0 / (4.0 * dot(wi, m));
 
-        const float3 fresnel = eval_fresnel_schlick(albedo, 1.0, dot(m, wi));
+        const float3 fresnel = eval_fresnel(dot(m, wi));
 
-        SmithShadowingMasking shadowing_masking = SmithShadowingMasking::eval(wo.z, wi.z, a2);
+        SmithShadowingMasking shadowing_masking = SmithShadowingMasking::eval(wo.z, wi.z, alpha2_along(wo), alpha2_along(wi));
 
         BrdfValue res;
         res.pdf = pdf_h * jacobian / wi.z;
@@ -285,7 +361,7 @@ struct SpecularBrdf {
         res.value =
                 fresnel
     			* shadowing_masking.g
-                * ggx_ndf(a2, cos_theta)
+                * ggx_ndf(alpha(), m)
                 / (4 * wo.z * wi.z);
 
         return res;
@@ -0,0 +1,116 @@
+#ifndef ENVIRONMENT_CDF_HLSL
+#define ENVIRONMENT_CDF_HLSL
+
+#include "math.hlsl"
+
+// A 2D distribution over the luminance of the environment, in a latitude-longitude
+// parameterization with +Y at the top. Built by `build_environment_cdf.hlsl`.
+//
+// Texel `[x, y]` for `x < ENVIRONMENT_CDF_WIDTH` holds the conditional CDF of row `y`
+// at the end of column `x`, and `[ENVIRONMENT_CDF_WIDTH, y]` holds the marginal CDF
+// of the rows at the end of row `y`. Both end at 1.
+// Must match `ENVIRONMENT_CDF_EXTENT` in `renderers/reference.rs`.
+#define ENVIRONMENT_CDF_WIDTH 64
+#define ENVIRONMENT_CDF_HEIGHT 32
+
+float3 environment_cdf_uv_to_dir(float2 uv) {
+    const float phi = uv.x * M_TAU;
+    const float theta = uv.y * M_PI;
+    const float sin_theta = sin(theta);
+    return float3(sin_theta * cos(phi), cos(theta), sin_theta * sin(phi));
+}
+
+float2 environment_cdf_dir_to_uv(float3 dir) {
+    const float phi = atan2(dir.z, dir.x);
+    const float theta = acos(clamp(dir.y, -1.0, 1.0));
+    return float2(frac(phi / M_TAU + 1.0), theta / M_PI);
+}
+
+// Converts the probability of picking a texel to a density over solid angle.
+// The latitude-longitude mapping stretches texels near the poles, by `1 / sin(theta)`.
+float environment_cdf_texel_prob_to_pdf(float texel_prob, float3 dir) {
+    const float sin_theta = sqrt(max(0.0, 1.0 - dir.y * dir.y));
+    if (sin_theta <= 0.0) {
+        return 0.0;
+    }
+
+    return texel_prob * (ENVIRONMENT_CDF_WIDTH * ENVIRONMENT_CDF_HEIGHT) / (2.0 * M_PI * M_PI * sin_theta);
+}
+
+struct EnvironmentSample {
+    float3 dir;
+    // Over solid angle
+    float pdf;
+};
+
+// The first row whose marginal CDF exceeds `u`.
+uint environment_cdf_find_row(Texture2D<float> cdf_tex, float u) {
+    uint lo = 0;
+    uint hi = ENVIRONMENT_CDF_HEIGHT - 1;
+    while (lo < hi) {
+        const uint mid = (lo + hi) / 2;
+        if (cdf_tex[uint2(ENVIRONMENT_CDF_WIDTH, mid)] <= u) {
+            lo = mid + 1;
+        } else {
+            hi = mid;
+        }
+    }
+    return lo;
+}
+
+// The first column of `row` whose conditional CDF exceeds `u`.
+uint environment_cdf_find_column(Texture2D<float> cdf_tex, uint row, float u) {
+    uint lo = 0;
+    uint hi = ENVIRONMENT_CDF_WIDTH - 1;
+    while (lo < hi) {
+        const uint mid = (lo + hi) / 2;
+        if (cdf_tex[uint2(mid, row)] <= u) {
+            lo = mid + 1;
+        } else {
+            hi = mid;
+        }
+    }
+    return lo;
+}
+
+EnvironmentSample sample_environment_cdf(Texture2D<float> cdf_tex, float2 urand) {
+    const uint y = environment_cdf_find_row(cdf_tex, urand.y);
+    const float row_start = y > 0 ? cdf_tex[uint2(ENVIRONMENT_CDF_WIDTH, y - 1)] : 0.0;
+    const float row_prob = cdf_tex[uint2(ENVIRONMENT_CDF_WIDTH, y)] - row_start;
+
+    const uint x = environment_cdf_find_column(cdf_tex, y, urand.x);
+    const float column_start = x > 0 ? cdf_tex[uint2(x - 1, y)] : 0.0;
+    const float column_prob = cdf_tex[uint2(x, y)] - column_start;
+
+    // Re-use the remainder of the random numbers to place the sample within the texel.
+    const float2 texel_offset = saturate(float2(
+        (urand.x - column_start) / max(1e-20, column_prob),
+        (urand.y - row_start) / max(1e-20, row_prob)
+    ));
+
+    const float2 uv = (float2(x, y) + texel_offset) / float2(ENVIRONMENT_CDF_WIDTH, ENVIRONMENT_CDF_HEIGHT);
+
+    EnvironmentSample res;
+    res.dir = environment_cdf_uv_to_dir(uv);
+    res.pdf = environment_cdf_texel_prob_to_pdf(row_prob * column_prob, res.dir);
+    return res;
+}
+
+// The density over solid angle with which `sample_environment_cdf` picks `dir`.
+float environment_cdf_pdf(Texture2D<float> cdf_tex, float3 dir) {
+    const float2 uv = environment_cdf_dir_to_uv(dir);
+    const uint2 texel = min(
+        uint2(uv * float2(ENVIRONMENT_CDF_WIDTH, ENVIRONMENT_CDF_HEIGHT)),
+        uint2(ENVIRONMENT_CDF_WIDTH - 1, ENVIRONMENT_CDF_HEIGHT - 1)
+    );
+
+    const float row_start = texel.y > 0 ? cdf_tex[uint2(ENVIRONMENT_CDF_WIDTH, texel.y - 1)] : 0.0;
+    const float row_prob = cdf_tex[uint2(ENVIRONMENT_CDF_WIDTH, texel.y)] - row_start;
+
+    const float column_start = texel.x > 0 ? cdf_tex[uint2(texel.x - 1, texel.y)] : 0.0;
+    const float column_prob = cdf_tex[texel] - column_start;
+
+    return environment_cdf_texel_prob_to_pdf(row_prob * column_prob, dir);
+}
+
+#endif  // ENVIRONMENT_CDF_HLSL
@@ -47,9 +47,9 @@ struct FrameConstants {
     float4 sky_ambient;
 
     float world_gi_scale;
+    uint ray_origin_offset_method;
+    float ray_origin_offset_scale;
 	uint pad0;
-	uint pad1;
-	uint pad2;
 
     GiCascadeConstants gi_cascades[4];
 };
@@ -145,8 +145,11 @@ float3 get_eye_position() {
     return eye_pos_h.xyz / eye_pos_h.w;
 }
 
+// Depth is reverse Z; see `CameraLens`. Returns negative values in front of the camera,
+// and the far plane distance (-inf unless it's finite) for the background at depth 0.
 float depth_to_view_z(float depth) {
-    return rcp(depth * -frame_constants.view_constants.clip_to_view._43);
+    const float4x4 clip_to_view = frame_constants.view_constants.clip_to_view;
+    return rcp(-(depth * clip_to_view._43 + clip_to_view._44));
 }
 
 float3 direction_view_to_world(float3 v) {
@@ -2,6 +2,7 @@
 #define GBUFFER_HLSL
 
 #include "pack_unpack.hlsl"
+#include "math.hlsl"
 
 struct GbufferData;
 
@@ -27,6 +28,19 @@ struct GbufferData {
     float roughness;
     float metalness;
 
+    // Stretches the specular lobe along `anisotropy_direction`, from 0 (isotropic) to 1.
+    float anisotropy;
+    // World-space direction of the highlight's stretch, perpendicular to `normal`.
+    // Only stored to 8 bits, and only when `anisotropy` is above zero.
+    float3 anisotropy_direction;
+
+    // Thin-film interference on the specular reflection, from 0 to 1. Shares storage with the anisotropy,
+    // and takes precedence over it. Coarsely quantized: the strength to 3 bits, and the IOR to 4.
+    float iridescence;
+    float iridescence_ior;
+    // Nanometers, up to `GBUFFER_MAX_IRIDESCENCE_THICKNESS`
+    float iridescence_thickness;
+
     static GbufferData create_zero() {
         GbufferData res;
         res.albedo = 0;
@@ -34,9 +48,30 @@ struct GbufferData {
         res.normal = 0;
         res.roughness = 0;
         res.metalness = 0;
+        res.anisotropy = 0;
+        res.anisotropy_direction = 0;
+        res.iridescence = 0;
+        res.iridescence_ior = 1;
+        res.iridescence_thickness = 0;
         return res;
     }
 
+    // Basis for BRDF evaluation, with the normal along Z, and the anisotropy direction along X.
+    float3x3 tangent_to_world() {
+        if (anisotropy <= 0.0) {
+            return build_orthonormal_basis(normal);
+        }
+
+        const float3 t = normalize(anisotropy_direction - normal * dot(anisotropy_direction, normal));
+        const float3 b = cross(normal, t);
+
+        return float3x3(
+            t.x, b.x, normal.x,
+            t.y, b.y, normal.y,
+            t.z, b.z, normal.z
+        );
+    }
+
     GbufferDataPacked pack();
 };
 
@@ -48,13 +83,62 @@ float perceptual_roughness_to_roughness(float r) {
     return r * r;
 }
 
+// The anisotropy direction is stored as an angle in [0, pi) from the first axis of
+// `build_orthonormal_basis(normal)`; the lobe is symmetric, so opposite directions are the same.
+float anisotropy_direction_to_unorm(float3 normal, float3 direction) {
+    const float3x3 basis = build_orthonormal_basis(normal);
+    const float3 direction_ts = mul(direction, basis);
+    float angle = atan2(direction_ts.y, direction_ts.x);
+    if (angle < 0.0) {
+        angle += M_PI;
+    }
+    return angle / M_PI;
+}
+
+float3 anisotropy_direction_from_unorm(float3 normal, float v) {
+    const float angle = v * M_PI;
+    return mul(build_orthonormal_basis(normal), float3(cos(angle), sin(angle), 0.0));
+}
+
+static const float GBUFFER_MAX_IRIDESCENCE_THICKNESS = 1200.0;
+static const float GBUFFER_MIN_IRIDESCENCE_IOR = 1.0;
+static const float GBUFFER_MAX_IRIDESCENCE_IOR = 2.5;
+
+// Flags the top byte of `z` as holding iridescence rather than anisotropy
+static const uint GBUFFER_IRIDESCENCE_BIT = 0x80;
+
+// Layout:
+// x: albedo (8:8:8), anisotropy direction or iridescence thickness (8)
+// y: normal (11:10:11)
+// z: perceptual roughness (f16), metalness (8),
+//    and either anisotropy (7), or iridescence IOR (4) and strength (3), followed by `GBUFFER_IRIDESCENCE_BIT`
+// w: emissive (rgb9e5)
 GbufferDataPacked GbufferData::pack() {
     float4 res = 0.0.xxxx;
-    res.x = asfloat(pack_color_888(albedo));
+
+    uint albedo_extra = pack_color_888(albedo);
+    uint extra = 0;
+    if (iridescence > 0.0) {
+        albedo_extra |= pack_unorm(iridescence_thickness / GBUFFER_MAX_IRIDESCENCE_THICKNESS, 8) << 24;
+        extra = GBUFFER_IRIDESCENCE_BIT
+            | (max(1, pack_unorm(iridescence, 3)) << 4)
+            | pack_unorm(
+                (iridescence_ior - GBUFFER_MIN_IRIDESCENCE_IOR) / (GBUFFER_MAX_IRIDESCENCE_IOR - GBUFFER_MIN_IRIDESCENCE_IOR),
+                4
+            );
+    } else if (anisotropy > 0.0) {
+        albedo_extra |= pack_unorm(anisotropy_direction_to_unorm(normal, anisotropy_direction), 8) << 24;
+        extra = pack_unorm(anisotropy, 7);
+    }
+    res.x = asfloat(albedo_extra);
+
     res.y = pack_normal_11_10_11(normal);
 
-    float2 roughness_metalness = float2(roughness_to_perceptual_roughness(roughness), metalness);
-    res.z = asfloat(pack_2x16f_uint(roughness_metalness));
+    res.z = asfloat(
+        (f32tof16(roughness_to_perceptual_roughness(roughness)) & 0xffff)
+        | (pack_unorm(metalness, 8) << 16)
+        | (extra << 24)
+    );
     res.w = asfloat(float3_to_rgb9e5(emissive));
 
    GbufferDataPacked packed;
@@ -67,9 +151,25 @@ GbufferData GbufferDataPacked::unpack() {
     res.albedo = unpack_albedo();
     res.normal = unpack_normal();
 
-    float2 roughness_metalness = unpack_2x16f_uint(data0.z);
-    res.roughness = perceptual_roughness_to_roughness(roughness_metalness.x);
-    res.metalness = roughness_metalness.y;
+    res.roughness = perceptual_roughness_to_roughness(f16tof32(data0.z & 0xffff));
+    res.metalness = unpack_unorm(data0.z >> 16, 8);
+
+    const uint extra = data0.z >> 24;
+    if (extra & GBUFFER_IRIDESCENCE_BIT) {
+        res.anisotropy = 0.0;
+        res.anisotropy_direction = 0.0;
+        res.iridescence = unpack_unorm(extra >> 4, 3);
+        res.iridescence_ior = lerp(GBUFFER_MIN_IRIDESCENCE_IOR, GBUFFER_MAX_IRIDESCENCE_IOR, unpack_unorm(extra, 4));
+        res.iridescence_thickness = unpack_unorm(data0.x >> 24, 8) * GBUFFER_MAX_IRIDESCENCE_THICKNESS;
+    } else {
+        res.anisotropy = unpack_unorm(extra, 7);
+        res.anisotropy_direction = res.anisotropy > 0.0
+            ? anisotropy_direction_from_unorm(res.normal, unpack_unorm(data0.x >> 24, 8))
+            : 0.0.xxx;
+        res.iridescence = 0.0;
+        res.iridescence_ior = 1.0;
+        res.iridescence_thickness = 0.0;
+    }
     res.emissive = unpack_emissive();
 
     return res;
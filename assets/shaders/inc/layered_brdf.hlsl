@@ -44,6 +44,10 @@ struct LayeredBrdf {
         SpecularBrdf specular_brdf;
         specular_brdf.albedo = 0.04;
         specular_brdf.roughness = gbuffer.roughness;
+        specular_brdf.anisotropy = gbuffer.anisotropy;
+        specular_brdf.iridescence = gbuffer.iridescence;
+        specular_brdf.iridescence_ior = gbuffer.iridescence_ior;
+        specular_brdf.iridescence_thickness = gbuffer.iridescence_thickness;
 
         DiffuseBrdf diffuse_brdf;
         diffuse_brdf.albedo = gbuffer.albedo;
@@ -114,6 +118,46 @@ struct LayeredBrdf {
         );
     }
 
+    // The part of `evaluate_directional_light` which enters the surface, and leaves it diffusely.
+    float3 evaluate_directional_light_diffuse(float3 wo, float3 wi) {
+        if (wo.z <= 0 || wi.z <= 0) {
+            return 0;
+        }
+
+        const BrdfValue diff = diffuse_brdf.evaluate(wo, wi);
+        const BrdfValue spec = specular_brdf.evaluate(wo, wi);
+
+        return diff.value * spec.transmission_fraction;
+    }
+
+    // Probability of `sample` choosing the diffuse lobe under the specular interface.
+    float transmission_probability() {
+        const float spec_wt = sRGB_to_luminance(energy_preservation.preintegrated_reflection);
+        const float diffuse_wt = sRGB_to_luminance(energy_preservation.preintegrated_transmission_fraction * diffuse_brdf.albedo);
+        return diffuse_wt / (spec_wt + diffuse_wt);
+    }
+
+    // Density over projected solid angle with which `sample` picks `wi`.
+    float pdf(float3 wo, float3 wi) {
+        if (wo.z <= 0 || wi.z <= 0) {
+            return 0;
+        }
+
+        const BrdfValue diff = diffuse_brdf.evaluate(wo, wi);
+
+        #if LAYERED_BRDF_FORCE_DIFFUSE_ONLY
+            return diff.pdf;
+        #endif
+
+        const BrdfValue spec = specular_brdf.evaluate(wo, wi);
+
+        #if LAYERED_BRDF_FORCE_SPECULAR_ONLY
+            return spec.pdf;
+        #endif
+
+        return lerp(spec.pdf, diff.pdf, transmission_probability());
+    }
+
     BrdfSample sample(float3 wo, float3 urand) {
         #if LAYERED_BRDF_FORCE_DIFFUSE_ONLY
             return diffuse_brdf.sample(wo, urand.xy);
@@ -129,9 +173,7 @@ struct LayeredBrdf {
         // and reflect with the complement of that. However since we use a single ray,
         // we toss a coin, and choose between reflection and transmission.
 
-        const float spec_wt = sRGB_to_luminance(energy_preservation.preintegrated_reflection);
-        const float diffuse_wt = sRGB_to_luminance(energy_preservation.preintegrated_transmission_fraction * diffuse_brdf.albedo);
-        const float transmission_p = diffuse_wt / (spec_wt + diffuse_wt);
+        const float transmission_p = transmission_probability();
 
         const float lobe_xi = urand.z;
         if (lobe_xi < transmission_p) {
@@ -36,6 +36,10 @@ Vertex unpack_vertex(VertexPacked p) {
 }
 
 static const uint MESH_MATERIAL_FLAG_EMISSIVE_USED_AS_LIGHT = 1;
+static const uint MESH_MATERIAL_FLAG_DOUBLE_SIDED = 2;
+static const uint MESH_MATERIAL_FLAG_ALPHA_CUTOFF = 4;
+static const uint MESH_MATERIAL_FLAG_PARALLAX = 8;
+static const uint MESH_MATERIAL_FLAG_SUBSURFACE = 16;
 
 struct MeshMaterial {
     float base_color_mult[4];
@@ -48,6 +52,16 @@ struct MeshMaterial {
     float emissive[3];
     uint flags;
     float map_transforms[6 * 4];
+    float alpha_cutoff;
+    float parallax_height_scale;
+    uint parallax_max_steps;
+    float anisotropy;
+    float anisotropy_rotation;
+    float subsurface_color[3];
+    float subsurface_radius;
+    float iridescence;
+    float iridescence_ior;
+    float iridescence_thickness;
 };
 
 float2 transform_material_uv(MeshMaterial mat, float2 uv, uint map_idx) {
@@ -57,5 +71,20 @@ float2 transform_material_uv(MeshMaterial mat, float2 uv, uint map_idx) {
     return mul(rot_scl, uv) + offset;
 }
 
+// Perturbs the interpolated vertex `normal` by `ts_normal`, sampled from a tangent-space normal map.
+// Meshes without UVs have no tangent frame, leaving a zero or NaN bitangent; those keep `normal`.
+float3 apply_normal_map(float3 normal, float3 tangent, float3 bitangent, float3 ts_normal) {
+    if (dot(bitangent, bitangent) > 0.0) {
+        float3x3 tbn = float3x3(tangent, bitangent, normal);
+        normal = mul(ts_normal, tbn);
+    }
+    return normalize(normal);
+}
+
+// Direction the material's anisotropic highlight is stretched along, in the space of `tangent` and `bitangent`.
+float3 material_anisotropy_direction(MeshMaterial material, float3 tangent, float3 bitangent) {
+    return tangent * cos(material.anisotropy_rotation) + bitangent * sin(material.anisotropy_rotation);
+}
+
 
 #endif
\ No newline at end of file
@@ -0,0 +1,68 @@
+#ifndef PARALLAX_HLSL
+#define PARALLAX_HLSL
+
+#include "samplers.hlsl"
+#include "mesh.hlsl"
+#include "bindless.hlsl"
+
+// Below this cosine between the view direction and the surface normal, the marched ray gets too long
+// for the step count, and parallax occlusion mapping fades into simple parallax.
+static const float PARALLAX_GRAZING_COS = 0.2;
+
+// Offsets `uv` to where the view ray hits the height field in the alpha of `material`'s normal map,
+// with 1.0 at the surface, and 0.0 `parallax_height_scale` UV units below it.
+// `view_ts` points towards the viewer, in tangent space. `uv_dx` and `uv_dy` are the screen-space gradients
+// of `uv`, taken by the caller, as this may run in divergent control flow.
+float2 parallax_occlusion_uv(MeshMaterial material, float2 uv, float2 uv_dx, float2 uv_dy, float3 view_ts) {
+    Texture2D height_tex = bindless_textures[NonUniformResourceIndex(material.normal_map)];
+
+    // Gradients of the original UVs, so that mips don't jump at the discontinuities of the offset ones
+    const float2 height_uv = transform_material_uv(material, uv, 1);
+    const float2 height_uv_dx = transform_material_uv(material, uv + uv_dx, 1) - height_uv;
+    const float2 height_uv_dy = transform_material_uv(material, uv + uv_dy, 1) - height_uv;
+
+    const float height_scale = material.parallax_height_scale;
+    const float cos_theta = saturate(view_ts.z);
+
+    // Simple parallax, with offset limiting: stays bounded at any angle, but doesn't occlude.
+    const float surface_height = height_tex.SampleGrad(sampler_llr, height_uv, height_uv_dx, height_uv_dy).a;
+    const float2 simple_uv = uv - view_ts.xy * (1.0 - surface_height) * height_scale;
+
+    const float occlusion_weight = smoothstep(PARALLAX_GRAZING_COS, 2.0 * PARALLAX_GRAZING_COS, cos_theta);
+    if (occlusion_weight == 0.0) {
+        return simple_uv;
+    }
+
+    // Fewer steps looking straight at the surface, where the ray crosses few texels.
+    const uint step_count = max(1, uint(lerp(float(material.parallax_max_steps), 0.25 * material.parallax_max_steps, cos_theta)));
+    const float step_height = 1.0 / step_count;
+    const float2 uv_step = -view_ts.xy / max(view_ts.z, PARALLAX_GRAZING_COS) * height_scale * step_height;
+
+    // March down from the top of the height field, until the ray goes below it.
+    float2 ray_uv = uv;
+    float ray_height = 1.0;
+    float prev_height_above = 0.0;
+
+    for (uint i = 0; i <= step_count; ++i) {
+        const float height = height_tex.SampleGrad(
+            sampler_llr, transform_material_uv(material, ray_uv, 1), height_uv_dx, height_uv_dy).a;
+        const float height_above = ray_height - height;
+
+        if (height_above <= 0.0) {
+            // Interpolate between this step and the previous one, where the ray was still above.
+            if (i > 0) {
+                const float t = prev_height_above / (prev_height_above - height_above);
+                ray_uv -= uv_step * (1.0 - t);
+            }
+            break;
+        }
+
+        prev_height_above = height_above;
+        ray_uv += uv_step;
+        ray_height -= step_height;
+    }
+
+    return lerp(simple_uv, ray_uv, occlusion_weight);
+}
+
+#endif
@@ -0,0 +1,63 @@
+#ifndef RAY_ORIGIN_HLSL
+#define RAY_ORIGIN_HLSL
+
+#include "frame_constants.hlsl"
+
+// Matches `RayOriginOffset` in `world_renderer.rs`
+#define RAY_ORIGIN_OFFSET_EPSILON 0
+#define RAY_ORIGIN_OFFSET_ROBUST 1
+
+// Moves ray origins off the surfaces they start on, so that rays don't hit them again.
+struct RayOriginOffset {
+    uint method;
+    float scale;
+
+    static RayOriginOffset create(uint method, float scale) {
+        RayOriginOffset res;
+        res.method = method;
+        res.scale = scale;
+        return res;
+    }
+
+    // As tuned via `WorldRenderer::ray_origin_offset`
+    static RayOriginOffset from_frame_constants() {
+        return create(frame_constants.ray_origin_offset_method, frame_constants.ray_origin_offset_scale);
+    }
+
+    // `position` is on a surface with the geometric `normal`; the result is on the side
+    // which `direction` leaves towards.
+    float3 apply(float3 position, float3 normal, float3 direction) {
+        const float3 n = dot(normal, direction) >= 0.0 ? normal : -normal;
+
+        if (method == RAY_ORIGIN_OFFSET_ROBUST) {
+            // "A Fast and Robust Method for Avoiding Self-Intersection", Ray Tracing Gems, chapter 6.
+            // Offsets each coordinate by a number of its ulps, so that the offset grows with
+            // the distance from the world origin, as the error of hit positions does. Near
+            // the origin, where ulps get tiny, it's a small fixed distance instead.
+            const float origin = 1.0 / 32.0;
+            const float float_scale = 1.0 / 65536.0;
+            const float int_scale = 256.0;
+
+            const int3 offset_int = int3(int_scale * scale * n);
+            const float3 offset_position = asfloat(asint(position) + (position < 0.0 ? -offset_int : offset_int));
+
+            return abs(position) < origin ? position + float_scale * scale * n : offset_position;
+        } else {
+            return position + n * scale;
+        }
+    }
+};
+
+// For ray origins at hits of other rays
+float3 offset_surface_ray_origin(float3 position, float3 normal, float3 direction) {
+    return RayOriginOffset::from_frame_constants().apply(position, normal, direction);
+}
+
+// For ray origins reconstructed from depth, which are off by a fraction of their distance
+// from the camera, on top of the error of hits.
+float3 offset_reconstructed_ray_origin(float3 position, float3 normal, float3 direction, float view_distance) {
+    const float3 n = dot(normal, direction) >= 0.0 ? normal : -normal;
+    return offset_surface_ray_origin(position + n * view_distance * 1e-5, normal, direction);
+}
+
+#endif
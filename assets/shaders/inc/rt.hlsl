@@ -4,9 +4,12 @@
 #include "math_const.hlsl"
 #include "gbuffer.hlsl"
 #include "ray_cone.hlsl"
+#include "ray_origin.hlsl"
 
 struct GbufferRayPayload {
     GbufferDataPacked gbuffer_packed;
+    // World-space normal of the hit triangle, as opposed to the shading normal in `gbuffer_packed`.
+    float geometric_normal_packed;
     float t;
     RayCone ray_cone;
     uint path_length;
@@ -60,10 +63,12 @@ bool rt_is_shadowed(
     RayDesc ray
 ) {
     ShadowRayPayload shadow_payload = ShadowRayPayload::new_hit();
+
+    // Hit group 1 holds the any-hit shader for this payload, and miss shader 1 clears it.
     TraceRay(
         acceleration_structure,
         RAY_FLAG_ACCEPT_FIRST_HIT_AND_END_SEARCH | RAY_FLAG_SKIP_CLOSEST_HIT_SHADER,
-        0xff, 0, 0, 1, ray, shadow_payload
+        0xff, 1, 0, 1, ray, shadow_payload
     );
 
     return shadow_payload.is_shadowed;
@@ -72,8 +77,15 @@ bool rt_is_shadowed(
 struct GbufferPathVertex {
     bool is_hit;
     GbufferDataPacked gbuffer_packed;
+    float3 geometric_normal;
     float3 position;
     float ray_t;
+
+    // Origin for a ray leaving this vertex towards `direction`. It's nudged off the surface
+    // along the geometric normal, which a normal-mapped shading normal can't be trusted for.
+    float3 offset_ray_origin(float3 direction) {
+        return offset_surface_ray_origin(position, geometric_normal, direction);
+    }
 };
 
 struct GbufferRaytrace {
@@ -126,6 +138,7 @@ struct GbufferRaytrace {
             res.is_hit = true;
             res.position = ray.Origin + ray.Direction * payload.t;
             res.gbuffer_packed = payload.gbuffer_packed;
+            res.geometric_normal = unpack_normal_11_10_11(payload.geometric_normal_packed);
             res.ray_t = payload.t;
             return res;
         } else {
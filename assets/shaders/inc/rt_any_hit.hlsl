@@ -0,0 +1,60 @@
+#ifndef RT_ANY_HIT_HLSL
+#define RT_ANY_HIT_HLSL
+
+#include "samplers.hlsl"
+#include "mesh.hlsl"
+#include "bindless.hlsl"
+
+// Whether an any-hit shader should reject the current hit: cut out by an alpha-tested material,
+// or the back face of a single-sided one, when the ray culls those.
+// Instances with double-sided materials disable culling as a whole, so it's done per material here.
+bool rt_should_ignore_hit(float2 bary) {
+    const float3 barycentrics = float3(1.0 - bary.x - bary.y, bary.x, bary.y);
+
+    Mesh mesh = meshes[InstanceID()];
+
+    // Indices of the triangle
+    uint3 ind = uint3(
+        vertices.Load((PrimitiveIndex() * 3 + 0) * sizeof(uint) + mesh.index_offset),
+        vertices.Load((PrimitiveIndex() * 3 + 1) * sizeof(uint) + mesh.index_offset),
+        vertices.Load((PrimitiveIndex() * 3 + 2) * sizeof(uint) + mesh.index_offset)
+    );
+
+    uint material_id = vertices.Load(ind.x * sizeof(uint) + mesh.vertex_mat_offset);
+    MeshMaterial material = vertices.Load<MeshMaterial>(mesh.mat_data_offset + material_id * sizeof(MeshMaterial));
+
+    if ((RayFlags() & RAY_FLAG_CULL_BACK_FACING_TRIANGLES) != 0
+        && HitKind() == HIT_KIND_TRIANGLE_BACK_FACE
+        && 0 == (material.flags & MESH_MATERIAL_FLAG_DOUBLE_SIDED)
+    ) {
+        return true;
+    }
+
+    if (0 == (material.flags & MESH_MATERIAL_FLAG_ALPHA_CUTOFF)) {
+        return false;
+    }
+
+    float2 uv0 = asfloat(vertices.Load2(ind.x * sizeof(float2) + mesh.vertex_uv_offset));
+    float2 uv1 = asfloat(vertices.Load2(ind.y * sizeof(float2) + mesh.vertex_uv_offset));
+    float2 uv2 = asfloat(vertices.Load2(ind.z * sizeof(float2) + mesh.vertex_uv_offset));
+    float2 uv = uv0 * barycentrics.x + uv1 * barycentrics.y + uv2 * barycentrics.z;
+
+    float v_alpha = 1.0;
+    if (mesh.vertex_aux_offset != 0) {
+        float a0 = asfloat(vertices.Load(ind.x * sizeof(float4) + mesh.vertex_aux_offset + 3 * sizeof(float)));
+        float a1 = asfloat(vertices.Load(ind.y * sizeof(float4) + mesh.vertex_aux_offset + 3 * sizeof(float)));
+        float a2 = asfloat(vertices.Load(ind.z * sizeof(float4) + mesh.vertex_aux_offset + 3 * sizeof(float)));
+        v_alpha = a0 * barycentrics.x + a1 * barycentrics.y + a2 * barycentrics.z;
+    }
+
+    // No ray cone here; the cutout is tested at full resolution.
+    float2 albedo_uv = transform_material_uv(material, uv, 0);
+    Texture2D albedo_tex = bindless_textures[NonUniformResourceIndex(material.albedo_map)];
+    const float alpha = albedo_tex.SampleLevel(sampler_llr, albedo_uv, 0).a
+        * material.base_color_mult[3]
+        * v_alpha;
+
+    return alpha < material.alpha_cutoff;
+}
+
+#endif
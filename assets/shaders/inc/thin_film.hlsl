@@ -0,0 +1,91 @@
+#ifndef THIN_FILM_HLSL
+#define THIN_FILM_HLSL
+
+#include "math.hlsl"
+
+// Thin-film interference, from "A Practical Extension to Microfacet Theory for the Modeling of Varying Iridescence"
+// by Belcour and Barla, as adapted for `KHR_materials_iridescence` in the glTF sample viewer.
+
+float thin_film_ior_to_f0(float transmitted_ior, float incident_ior) {
+    return square((transmitted_ior - incident_ior) / (transmitted_ior + incident_ior));
+}
+
+float3 thin_film_ior_to_f0(float3 transmitted_ior, float incident_ior) {
+    return square((transmitted_ior - incident_ior) / (transmitted_ior + incident_ior));
+}
+
+float3 thin_film_schlick(float3 f0, float cos_theta) {
+    return lerp(f0, 1.0, pow(max(0.0, 1.0 - cos_theta), 5));
+}
+
+float3 thin_film_f0_to_ior(float3 f0) {
+    const float3 sqrt_f0 = sqrt(f0);
+    return (1.0 + sqrt_f0) / (1.0 - sqrt_f0);
+}
+
+// Fourier transform of the CIE XYZ sensitivity curves, fitted with Gaussians, converted to linear sRGB.
+float3 thin_film_eval_sensitivity(float opd, float3 shift) {
+    const float phase = 2.0 * M_PI * opd * 1.0e-9;
+    const float3 val = float3(5.4856e-13, 4.4201e-13, 5.2481e-13);
+    const float3 pos = float3(1.6810e+06, 1.7953e+06, 2.2084e+06);
+    const float3 var = float3(4.3278e+09, 9.3046e+09, 6.6121e+09);
+
+    float3 xyz = val * sqrt(2.0 * M_PI * var) * cos(pos * phase + shift) * exp(-square(phase) * var);
+    xyz.x += 9.7470e-14 * sqrt(2.0 * M_PI * 4.5282e+09) * cos(2.2399e+06 * phase + shift.x) * exp(-4.5282e+09 * square(phase));
+    xyz /= 1.0685e-7;
+
+    return float3(
+        dot(float3(3.2404542, -1.5371385, -0.4985314), xyz),
+        dot(float3(-0.9692660, 1.8760108, 0.0415560), xyz),
+        dot(float3(0.0556434, -0.2040259, 1.0572252), xyz)
+    );
+}
+
+// Fresnel reflectance of a film of `film_ior`, `thickness` nanometers thick, over a base with reflectance `base_f0`,
+// seen at `cos_theta1` from a medium of `outside_ior`.
+float3 eval_thin_film_fresnel(float outside_ior, float film_ior, float cos_theta1, float thickness, float3 base_f0) {
+    // The film vanishes as it gets thinner
+    const float ior = lerp(outside_ior, film_ior, smoothstep(0.0, 0.03, thickness));
+
+    // Snell's law into the film
+    const float sin_theta2_sq = square(outside_ior / ior) * (1.0 - square(cos_theta1));
+    const float cos_theta2_sq = 1.0 - sin_theta2_sq;
+    if (cos_theta2_sq < 0.0) {
+        // Total internal reflection
+        return 1.0;
+    }
+    const float cos_theta2 = sqrt(cos_theta2_sq);
+
+    // First interface
+    const float r12 = thin_film_schlick(thin_film_ior_to_f0(ior, outside_ior), cos_theta1).x;
+    const float t121 = 1.0 - r12;
+    const float phi12 = ior < outside_ior ? M_PI : 0.0;
+    const float phi21 = M_PI - phi12;
+
+    // Second interface
+    const float3 base_ior = thin_film_f0_to_ior(clamp(base_f0, 0.0, 0.9999));
+    const float3 r23 = thin_film_schlick(thin_film_ior_to_f0(base_ior, ior), cos_theta2);
+    const float3 phi23 = float3(base_ior < ior) * M_PI;
+
+    // Phase shift
+    const float opd = 2.0 * ior * thickness * cos_theta2;
+    const float3 phi = phi21 + phi23;
+
+    // Compound terms
+    const float3 r123 = clamp(r12 * r23, 1e-5, 0.9999);
+    const float3 sqrt_r123 = sqrt(r123);
+    const float3 rs = square(t121) * r23 / (1.0 - r123);
+
+    // The DC term, and then pairs of diracs
+    float3 res = r12 + rs;
+    float3 cm = rs - t121;
+    for (int m = 1; m <= 2; ++m) {
+        cm *= sqrt_r123;
+        res += cm * 2.0 * thin_film_eval_sensitivity(m * opd, m * phi);
+    }
+
+    // Out-of-gamut colors can come out negative
+    return max(res, 0.0);
+}
+
+#endif
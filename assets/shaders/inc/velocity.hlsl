@@ -0,0 +1,41 @@
+#ifndef VELOCITY_HLSL
+#define VELOCITY_HLSL
+
+#include "frame_constants.hlsl"
+#include "uv.hlsl"
+
+// The velocity written by `raster_meshes`, in the encoding selected by `VelocityEncoding`
+// via `VELOCITY_UNITS` and `VELOCITY_REMOVE_JITTER`. Velocity points from where a surface is
+// in this frame to where it was in the previous one, so `uv + velocity` is the previous position.
+//
+// * VIEW_SPACE: `prev_vs_pos - vs_pos` in xyz, both in this frame's view space.
+// * UV: the change in UV in xy, with +y down; z is the linear depth of the surface.
+// * PIXELS: like UV, but scaled by `render_extent`.
+//
+// Jitter only affects the screen-space encodings. Without `VELOCITY_REMOVE_JITTER`, the current
+// position is that of the jittered sample, and the previous one is unjittered.
+float4 encode_velocity(float3 vs_pos, float3 prev_vs_pos, float2 render_extent) {
+#if VELOCITY_UNITS == VELOCITY_UNITS_VIEW_SPACE
+    return float4(prev_vs_pos - vs_pos, 0);
+#else
+    const float4 cs_pos = mul(frame_constants.view_constants.view_to_clip, float4(vs_pos, 1));
+    const float4 prev_pcs_pos = mul(
+        frame_constants.view_constants.clip_to_prev_clip,
+        mul(frame_constants.view_constants.view_to_clip, float4(prev_vs_pos, 1)));
+
+    float2 ndc = cs_pos.xy / cs_pos.w;
+    #if !VELOCITY_REMOVE_JITTER
+        // Like `view_to_sample`
+        ndc -= frame_constants.view_constants.sample_offset_clip;
+    #endif
+
+    float2 velocity = cs_to_uv(prev_pcs_pos.xy / prev_pcs_pos.w) - cs_to_uv(ndc);
+    #if VELOCITY_UNITS == VELOCITY_UNITS_PIXELS
+        velocity *= render_extent;
+    #endif
+
+    return float4(velocity, -vs_pos.z, 0);
+#endif
+}
+
+#endif
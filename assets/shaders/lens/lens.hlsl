@@ -0,0 +1,35 @@
+#include "../inc/samplers.hlsl"
+
+[[vk::binding(0)]] Texture2D<float4> input_tex;
+[[vk::binding(1)]] RWTexture2D<float4> output_tex;
+[[vk::binding(2)]] cbuffer _ {
+    float4 output_tex_size;
+    float aspect_ratio;
+    float chromatic_aberration;
+    float distortion_k1;
+    float distortion_k2;
+};
+
+// Must match `LensRenderer::source_uv` in `lens.rs`
+float2 source_uv(float2 uv, float channel_scale) {
+    const float2 p = uv * 2.0 - 1.0;
+
+    // Radius 1 at the corners, regardless of the aspect ratio.
+    const float2 q = p * float2(aspect_ratio, 1.0) / length(float2(aspect_ratio, 1.0));
+    const float r2 = dot(q, q);
+    const float distortion = 1.0 + distortion_k1 * r2 + distortion_k2 * r2 * r2;
+
+    // Clamped, so that edges stretch instead of sampling outside of the image.
+    return saturate(p * distortion * channel_scale * 0.5 + 0.5);
+}
+
+[numthreads(8, 8, 1)]
+void main(uint2 px: SV_DispatchThreadID) {
+    const float2 uv = (px + 0.5) * output_tex_size.zw;
+
+    const float r = input_tex.SampleLevel(sampler_lnc, source_uv(uv, 1.0 + chromatic_aberration), 0).r;
+    const float4 ga = input_tex.SampleLevel(sampler_lnc, source_uv(uv, 1.0), 0);
+    const float b = input_tex.SampleLevel(sampler_lnc, source_uv(uv, 1.0 - chromatic_aberration), 0).b;
+
+    output_tex[px] = float4(r, ga.g, b, ga.a);
+}
@@ -32,9 +32,16 @@
 [[vk::binding(8)]] Texture3D<float4> csgi_indirect_tex[CSGI_CASCADE_COUNT];
 [[vk::binding(9)]] TextureCube<float4> unconvolved_sky_cube_tex;
 [[vk::binding(10)]] TextureCube<float4> sky_cube_tex;
-[[vk::binding(11)]] cbuffer _ {
+[[vk::binding(11)]] Texture2D<float4> subsurface_tex;
+[[vk::binding(12)]] RWTexture2D<float4> subsurface_diffuse_tex;
+[[vk::binding(13)]] RWTexture2D<float4> direct_aov_tex;
+[[vk::binding(14)]] RWTexture2D<float4> indirect_aov_tex;
+[[vk::binding(15)]] RWTexture2D<float4> reflections_aov_tex;
+[[vk::binding(16)]] cbuffer _ {
     float4 output_tex_size;
     uint debug_shading_mode;
+    uint use_subsurface;
+    uint write_lighting_aovs;
 };
 
 #define SHADING_MODE_DEFAULT 0
@@ -87,6 +94,12 @@ void main(in uint2 px : SV_DispatchThreadID) {
         
         temporal_output_tex[px] = float4(output, 1);
         output_tex[px] = float4(output, 1);
+
+        if (write_lighting_aovs) {
+            direct_aov_tex[px] = float4(output, 1);
+            indirect_aov_tex[px] = 0.0;
+            reflections_aov_tex[px] = 0.0;
+        }
         return;
     }
 
@@ -107,7 +120,7 @@ void main(in uint2 px : SV_DispatchThreadID) {
         gbuffer.albedo = 0.5;
     }
 
-    const float3x3 tangent_to_world = build_orthonormal_basis(gbuffer.normal);
+    const float3x3 tangent_to_world = gbuffer.tangent_to_world();
     const float3 wi = mul(to_light_norm, tangent_to_world);
     float3 wo = mul(-outgoing_ray.Direction, tangent_to_world);
 
@@ -126,6 +139,8 @@ void main(in uint2 px : SV_DispatchThreadID) {
 
     total_radiance += gbuffer.emissive;
 
+    const float3 direct_radiance = total_radiance;
+
     float3 gi_irradiance = 0.0.xxx;
 
     float3 csgi_irradiance = 0;
@@ -158,14 +173,14 @@ void main(in uint2 px : SV_DispatchThreadID) {
         gi_irradiance += biased_ssgi.rgb;
     #endif
 
-    total_radiance += gi_irradiance
+    const float3 gi_diffuse_radiance = gi_irradiance
         * brdf.diffuse_brdf.albedo
         * brdf.energy_preservation.preintegrated_transmission_fraction
         ;
+    total_radiance += gi_diffuse_radiance;
 
+    float3 rtr_radiance = 0.0.xxx;
     if (USE_RTR && debug_shading_mode != SHADING_MODE_RTX_OFF) {
-        float3 rtr_radiance;
-
         #if !RTR_RENDER_SCALED_BY_FG
             rtr_radiance = rtr_tex[px].xyz * brdf.energy_preservation.preintegrated_reflection;
         #else
@@ -183,6 +198,12 @@ void main(in uint2 px : SV_DispatchThreadID) {
 
     temporal_output_tex[px] = float4(total_radiance, 1.0);
 
+    if (write_lighting_aovs) {
+        direct_aov_tex[px] = float4(direct_radiance, 1.0);
+        indirect_aov_tex[px] = float4(gi_diffuse_radiance, 1.0);
+        reflections_aov_tex[px] = float4(rtr_radiance, 1.0);
+    }
+
     float3 output = total_radiance;
 
     if (debug_shading_mode == SHADING_MODE_REFLECTIONS) {
@@ -203,4 +224,19 @@ void main(in uint2 px : SV_DispatchThreadID) {
 
     //output = gbuffer.albedo;
     output_tex[px] = float4(output, 1.0);
+
+    if (use_subsurface) {
+        // `subsurface/scatter.hlsl` swaps this part of the output for its blurred version.
+        const bool scatters = any(subsurface_tex[px].rgb > 0.0)
+            && debug_shading_mode != SHADING_MODE_REFLECTIONS
+            && debug_shading_mode != SHADING_MODE_DIFFUSE_GI;
+
+        float3 diffuse_radiance = 0.0;
+        if (scatters) {
+            diffuse_radiance = brdf.evaluate_directional_light_diffuse(wo, wi) * max(0.0, wi.z) * light_radiance
+                + gi_diffuse_radiance;
+        }
+
+        subsurface_diffuse_tex[px] = float4(diffuse_radiance, 1.0);
+    }
 }
@@ -11,6 +11,10 @@ float2 integrate_brdf(float roughness, float ndotv) {
 
     SpecularBrdf brdf_a;
     brdf_a.roughness = roughness;
+    brdf_a.anisotropy = 0.0;
+    brdf_a.iridescence = 0.0;
+    brdf_a.iridescence_ior = 1.0;
+    brdf_a.iridescence_thickness = 0.0;
     brdf_a.albedo = 1.0.xxx;
 
     SpecularBrdf brdf_b = brdf_a;
@@ -0,0 +1,114 @@
+#include "../inc/frame_constants.hlsl"
+
+[[vk::binding(0)]] Texture2D<uint> object_id_tex;
+[[vk::binding(1)]] Texture2D<float> depth_tex;
+[[vk::binding(2)]] Texture2D<float3> geometric_normal_tex;
+// Indexed by object ID - 1; zero if not highlighted, and the color index + 1 otherwise.
+[[vk::binding(3)]] StructuredBuffer<uint> highlights_dyn;
+[[vk::binding(4)]] StructuredBuffer<float4> highlight_colors_dyn;
+[[vk::binding(5)]] RWTexture2D<float4> output_tex;
+[[vk::binding(6)]] cbuffer _ {
+    float4 input_tex_size;
+    float4 output_tex_size;
+    float width;
+    float inner_edge_strength;
+};
+
+// Must match `MAX_OUTLINE_WIDTH` in `outline.rs`
+static const int MAX_RADIUS = 8;
+
+uint2 output_px_to_input_px(int2 px) {
+    const float2 uv = (px + 0.5) * output_tex_size.zw;
+    return min(uint2(uv * input_tex_size.xy), uint2(input_tex_size.xy) - 1);
+}
+
+uint highlight_of(uint object_id) {
+    return object_id == 0 ? 0 : highlights_dyn[object_id - 1];
+}
+
+// Linear depth and normal discontinuities around `px`, via Sobel filters.
+float detect_inner_edge(int2 px) {
+    float depth_gx = 0;
+    float depth_gy = 0;
+    float3 normal_gx = 0;
+    float3 normal_gy = 0;
+
+    const float center_z = -depth_to_view_z(depth_tex[px]);
+
+    for (int y = -1; y <= 1; ++y) {
+        for (int x = -1; x <= 1; ++x) {
+            if (x == 0 && y == 0) {
+                continue;
+            }
+
+            const int2 sample_px = clamp(px + int2(x, y), 0, int2(input_tex_size.xy) - 1);
+            const float wx = x * (y == 0 ? 2.0 : 1.0);
+            const float wy = y * (x == 0 ? 2.0 : 1.0);
+
+            // Relative to the center, so edges don't fade out in the distance.
+            const float z = -depth_to_view_z(depth_tex[sample_px]) / center_z;
+            const float3 normal = geometric_normal_tex[sample_px] * 2.0 - 1.0;
+
+            depth_gx += z * wx;
+            depth_gy += z * wy;
+            normal_gx += normal * wx;
+            normal_gy += normal * wy;
+        }
+    }
+
+    const float depth_edge = smoothstep(0.05, 0.2, length(float2(depth_gx, depth_gy)));
+    const float normal_edge = smoothstep(0.5, 1.5, sqrt(dot(normal_gx, normal_gx) + dot(normal_gy, normal_gy)));
+
+    return max(depth_edge, normal_edge);
+}
+
+[numthreads(8, 8, 1)]
+void main(int2 px: SV_DispatchThreadID) {
+    if (any(px >= int2(output_tex_size.xy))) {
+        return;
+    }
+
+    const uint2 center_input_px = output_px_to_input_px(px);
+    const uint center_id = object_id_tex[center_input_px];
+
+    uint best_highlight = 0;
+    float best_coverage = 0;
+
+    // Silhouettes: the nearest pixel of another, highlighted object
+    const int radius = min(MAX_RADIUS, int(ceil(width)));
+    for (int y = -radius; y <= radius; ++y) {
+        for (int x = -radius; x <= radius; ++x) {
+            const int2 sample_px = px + int2(x, y);
+            if (any(sample_px < 0) || any(sample_px >= int2(output_tex_size.xy))) {
+                continue;
+            }
+
+            const uint id = object_id_tex[output_px_to_input_px(sample_px)];
+            const uint highlight = highlight_of(id);
+            if (id == center_id || highlight == 0) {
+                continue;
+            }
+
+            const float coverage = saturate(width + 1.0 - length(float2(x, y)));
+            if (coverage > best_coverage) {
+                best_coverage = coverage;
+                best_highlight = highlight;
+            }
+        }
+    }
+
+    // Creases within highlighted objects
+    const uint center_highlight = highlight_of(center_id);
+    if (inner_edge_strength > 0 && center_highlight != 0) {
+        const float coverage = inner_edge_strength * detect_inner_edge(center_input_px);
+        if (coverage > best_coverage) {
+            best_coverage = coverage;
+            best_highlight = center_highlight;
+        }
+    }
+
+    if (best_coverage > 0) {
+        const float3 color = highlight_colors_dyn[best_highlight - 1].rgb;
+        output_tex[px] = float4(lerp(output_tex[px].rgb, color, best_coverage), 1);
+    }
+}
@@ -0,0 +1,56 @@
+#ifndef PARTICLES_BILLBOARD_COMMON_HLSL
+#define PARTICLES_BILLBOARD_COMMON_HLSL
+
+#include "common.hlsl"
+
+// Must match `ParticleDrawConstants` in `particles.rs`
+struct ParticleDrawConstants {
+    float4 color_opacity;
+    // depth_scale, depth_exponent, min_weight, max_weight; see `OitWeightParams`
+    float4 oit_weight;
+    uint lit;
+};
+
+[[vk::push_constant]] ParticleDrawConstants push_constants;
+
+[[vk::binding(0)]] StructuredBuffer<Particle> particles_buf;
+[[vk::binding(1)]] Texture2D<float> scene_depth_tex;
+[[vk::binding(2)]] TextureCube<float4> sky_cube_tex;
+
+struct VsOut {
+    float4 position: SV_Position;
+    // Position within the billboard, in [-1, 1]
+    [[vk::location(0)]] float2 corner: TEXCOORD0;
+    // Not premultiplied
+    [[vk::location(1)]] float4 color_alpha: TEXCOORD1;
+    [[vk::location(2)]] float view_distance: TEXCOORD2;
+    [[vk::location(3)]] float radius: TEXCOORD3;
+};
+
+struct ParticleShading {
+    float3 color;
+    float alpha;
+    float view_distance;
+};
+
+ParticleShading shade_particle(VsOut ps) {
+    ParticleShading res;
+    res.color = ps.color_alpha.rgb;
+    res.view_distance = ps.view_distance;
+
+    // Round, soft-edged puffs
+    const float r2 = dot(ps.corner, ps.corner);
+    float alpha = ps.color_alpha.a * square(saturate(1.0 - r2));
+
+    // Soft particles: fade out where the billboard cuts into opaque geometry.
+    const float opaque_depth = scene_depth_tex[int2(ps.position.xy)];
+    if (opaque_depth != 0.0) {
+        const float opaque_distance = -depth_to_view_z(opaque_depth);
+        alpha *= saturate((opaque_distance - ps.view_distance) / ps.radius);
+    }
+
+    res.alpha = alpha;
+    return res;
+}
+
+#endif  // PARTICLES_BILLBOARD_COMMON_HLSL
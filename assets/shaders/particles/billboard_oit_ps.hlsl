@@ -0,0 +1,9 @@
+#include "../inc/frame_constants.hlsl"
+#include "../inc/math.hlsl"
+#include "../transparency/oit.hlsl"
+#include "billboard_common.hlsl"
+
+OitOutput main(VsOut ps) {
+    const ParticleShading shading = shade_particle(ps);
+    return oit_accumulate(push_constants.oit_weight, shading.color, shading.alpha, shading.view_distance);
+}
@@ -0,0 +1,10 @@
+#include "../inc/frame_constants.hlsl"
+#include "../inc/math.hlsl"
+#include "billboard_common.hlsl"
+
+float4 main(VsOut ps): SV_TARGET0 {
+    const ParticleShading shading = shade_particle(ps);
+
+    // Premultiplied alpha
+    return float4(shading.color * shading.alpha, shading.alpha);
+}
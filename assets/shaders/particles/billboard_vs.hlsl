@@ -0,0 +1,49 @@
+#include "../inc/samplers.hlsl"
+#include "../inc/frame_constants.hlsl"
+#include "../inc/math.hlsl"
+#include "../inc/math_const.hlsl"
+#include "../inc/atmosphere.hlsl"
+#include "../inc/sun.hlsl"
+#include "billboard_common.hlsl"
+
+static const float2 BILLBOARD_CORNERS[6] = {
+    float2(-1, -1), float2(1, -1), float2(1, 1),
+    float2(-1, -1), float2(1, 1), float2(-1, 1),
+};
+
+// Two triangles per particle, with no vertex buffer. Dead particles collapse to a degenerate quad.
+VsOut main(uint vid: SV_VertexID) {
+    const Particle p = particles_buf[vid / 6];
+    const float2 corner = BILLBOARD_CORNERS[vid % 6];
+
+    VsOut vsout = (VsOut)0;
+    if (!particle_is_alive(p)) {
+        return vsout;
+    }
+
+    const float radius = p.size_visibility.x * 0.5;
+    const float3 ws_pos =
+        p.position_age.xyz
+        + (direction_view_to_world(float3(1, 0, 0)) * corner.x + direction_view_to_world(float3(0, 1, 0)) * corner.y) * radius;
+
+    const float4 vs_pos = mul(frame_constants.view_constants.world_to_view, float4(ws_pos, 1));
+    vsout.position = mul(frame_constants.view_constants.view_to_sample, vs_pos);
+
+    // Fade in quickly after spawning, and out towards the end of the lifetime.
+    const float t = p.position_age.w / p.velocity_lifetime.w;
+    const float life_fade = saturate(t / 0.1) * saturate((1.0 - t) / 0.4);
+
+    float3 color = push_constants.color_opacity.rgb;
+    if (push_constants.lit != 0) {
+        // Shaded once per particle, like a diffuse surface facing every direction.
+        // The sky is sampled straight up; its radiance times pi is the irradiance from a uniform sky.
+        const float3 sky = sky_cube_tex.SampleLevel(sampler_llr, float3(0, 1, 0), 0).rgb;
+        color *= SUN_COLOR * p.size_visibility.y / M_PI + sky;
+    }
+
+    vsout.corner = corner;
+    vsout.color_alpha = float4(color, push_constants.color_opacity.a * life_fade);
+    vsout.view_distance = -vs_pos.z;
+    vsout.radius = max(radius, 1e-3);
+    return vsout;
+}
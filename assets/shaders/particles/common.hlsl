@@ -0,0 +1,51 @@
+#ifndef PARTICLES_COMMON_HLSL
+#define PARTICLES_COMMON_HLSL
+
+// Must match `PARTICLE_STRIDE` in `particles.rs`
+struct Particle {
+    // xyz: world-space position; w: age in seconds
+    float4 position_age;
+
+    // xyz: velocity; w: lifetime in seconds. The particle is dead once its age reaches its lifetime.
+    float4 velocity_lifetime;
+
+    // x: billboard diameter; y: sun visibility; zw: unused
+    float4 size_visibility;
+};
+
+bool particle_is_alive(Particle p) {
+    return p.position_age.w < p.velocity_lifetime.w;
+}
+
+// Must match `EMITTER_STATE_SIZE` in `particles.rs`
+struct ParticleEmitterState {
+    // Fractional particles left over from previous frames
+    float spawn_accumulator;
+
+    // Slot of the particle buffer to spawn into next; it's used as a ring buffer.
+    uint next_slot;
+
+    // Range of slots spawned into this frame
+    uint spawn_first;
+    uint spawn_count;
+};
+
+// Must match `ParticleEmitterConstants` in `particles.rs`
+struct ParticleEmitterConstants {
+    float4 position_radius;
+    float4 initial_velocity_randomness;
+    float4 acceleration_drag;
+    float emission_rate;
+    float lifetime;
+    float start_size;
+    float end_size;
+    uint max_particles;
+    uint reset;
+    uint seed;
+    uint pad0;
+
+    // xyz: subtracted from the positions of live particles, as the render origin moved
+    float4 origin_shift;
+};
+
+#endif  // PARTICLES_COMMON_HLSL
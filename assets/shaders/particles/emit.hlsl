@@ -0,0 +1,27 @@
+#include "../inc/frame_constants.hlsl"
+#include "common.hlsl"
+
+[[vk::binding(0)]] RWStructuredBuffer<ParticleEmitterState> emitter_state_buf;
+[[vk::binding(1)]] cbuffer _ {
+    ParticleEmitterConstants emitter;
+};
+
+[numthreads(1, 1, 1)]
+void main() {
+    ParticleEmitterState state = emitter_state_buf[0];
+    if (emitter.reset != 0) {
+        state.spawn_accumulator = 0.0;
+        state.next_slot = 0;
+    }
+
+    const float to_spawn = state.spawn_accumulator + emitter.emission_rate * frame_constants.delta_time_seconds;
+    const float spawn_count = floor(to_spawn);
+    state.spawn_accumulator = to_spawn - spawn_count;
+
+    // Spawning more than the buffer holds would overwrite particles spawned in the same frame.
+    state.spawn_first = state.next_slot;
+    state.spawn_count = min(uint(spawn_count), emitter.max_particles);
+    state.next_slot = (state.next_slot + state.spawn_count) % emitter.max_particles;
+
+    emitter_state_buf[0] = state;
+}
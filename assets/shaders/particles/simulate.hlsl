@@ -0,0 +1,73 @@
+#include "../inc/frame_constants.hlsl"
+#include "../inc/hash.hlsl"
+#include "../inc/math_const.hlsl"
+#include "common.hlsl"
+
+[[vk::binding(0)]] StructuredBuffer<ParticleEmitterState> emitter_state_buf;
+[[vk::binding(1)]] RWStructuredBuffer<Particle> particles_buf;
+[[vk::binding(2)]] cbuffer _ {
+    ParticleEmitterConstants emitter;
+};
+
+float3 uniform_sample_ball(inout uint rng) {
+    const float z = uint_to_u01_float(hash1_mut(rng)) * 2.0 - 1.0;
+    const float phi = uint_to_u01_float(hash1_mut(rng)) * M_TAU;
+    const float r = sqrt(max(0.0, 1.0 - z * z));
+    const float3 dir = float3(r * cos(phi), r * sin(phi), z);
+    return dir * pow(uint_to_u01_float(hash1_mut(rng)), 1.0 / 3.0);
+}
+
+[numthreads(64, 1, 1)]
+void main(uint idx: SV_DispatchThreadID) {
+    if (idx >= emitter.max_particles) {
+        return;
+    }
+
+    Particle p = particles_buf[idx];
+
+    if (emitter.reset != 0) {
+        p.position_age = 0.0.xxxx;
+        p.velocity_lifetime = 0.0.xxxx;
+        p.size_visibility = 0.0.xxxx;
+        particles_buf[idx] = p;
+        return;
+    }
+
+    const ParticleEmitterState state = emitter_state_buf[0];
+    const float dt = frame_constants.delta_time_seconds;
+
+    // Position relative to the first slot spawned into this frame, accounting for wrap-around
+    const uint spawn_offset = (idx + emitter.max_particles - state.spawn_first) % emitter.max_particles;
+
+    if (spawn_offset < state.spawn_count) {
+        uint rng = hash3(uint3(idx, frame_constants.frame_index, emitter.seed));
+
+        const float3 position = emitter.position_radius.xyz + uniform_sample_ball(rng) * emitter.position_radius.w;
+        const float3 velocity =
+            emitter.initial_velocity_randomness.xyz
+            + uniform_sample_ball(rng) * emitter.initial_velocity_randomness.w;
+
+        p.position_age = float4(position, 0.0);
+        p.velocity_lifetime = float4(velocity, emitter.lifetime);
+
+        // Lit until the visibility pass runs
+        p.size_visibility = float4(emitter.start_size, 1.0, 0.0, 0.0);
+    } else if (particle_is_alive(p)) {
+        p.position_age.xyz -= emitter.origin_shift.xyz;
+
+        float3 velocity = p.velocity_lifetime.xyz;
+        velocity += emitter.acceleration_drag.xyz * dt;
+        velocity *= exp(-emitter.acceleration_drag.w * dt);
+
+        p.position_age.xyz += velocity * dt;
+        p.position_age.w += dt;
+        p.velocity_lifetime.xyz = velocity;
+
+        const float t = saturate(p.position_age.w / p.velocity_lifetime.w);
+        p.size_visibility.x = lerp(emitter.start_size, emitter.end_size, t);
+    } else {
+        return;
+    }
+
+    particles_buf[idx] = p;
+}
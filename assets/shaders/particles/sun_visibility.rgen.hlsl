@@ -0,0 +1,34 @@
+#include "../inc/frame_constants.hlsl"
+#include "../inc/rt.hlsl"
+#include "../inc/atmosphere.hlsl"
+#include "../inc/sun.hlsl"
+#include "common.hlsl"
+
+[[vk::binding(0, 3)]] RaytracingAccelerationStructure acceleration_structure;
+
+[[vk::binding(0)]] RWStructuredBuffer<Particle> particles_buf;
+
+static const float SKY_DIST = 1e4;
+
+// Fraction of the previous visibility kept each frame, so that particles don't pop
+// in and out of shadow as they drift across its edge.
+#define VISIBILITY_HISTORY_BLEND 0.5
+
+// One thread per particle. The scene casts shadows onto particles, but particles don't cast any.
+[shader("raygeneration")]
+void main() {
+    const uint idx = DispatchRaysIndex().x;
+    Particle p = particles_buf[idx];
+
+    if (!particle_is_alive(p)) {
+        return;
+    }
+
+    const bool is_shadowed = rt_is_shadowed(
+        acceleration_structure,
+        new_ray(p.position_age.xyz, SUN_DIRECTION, 0.0, SKY_DIST)
+    );
+
+    p.size_visibility.y = lerp(is_shadowed ? 0.0 : 1.0, p.size_visibility.y, VISIBILITY_HISTORY_BLEND);
+    particles_buf[idx] = p;
+}
@@ -0,0 +1,152 @@
+#include "../inc/samplers.hlsl"
+#include "../inc/frame_constants.hlsl"
+#include "../inc/pack_unpack.hlsl"
+#include "../inc/brdf.hlsl"
+#include "../inc/brdf_lut.hlsl"
+#include "../inc/layered_brdf.hlsl"
+#include "../inc/uv.hlsl"
+#include "../inc/blue_noise.hlsl"
+#include "../inc/math.hlsl"
+
+// Must match `MAX_POINT_LIGHTS` in `point_lights.rs`
+#define MAX_POINT_LIGHTS 8
+
+// Receivers are offset this many texels along their normal, away from their own shadow.
+#define NORMAL_OFFSET_TEXELS 1.5
+
+#define FILTER_TAPS 16
+#define GOLDEN_ANGLE 2.39996323
+
+// Must match `PointLightConstants` in `point_lights.rs`
+struct PointLight {
+    float4x4 world_to_shadow[6];
+    float4 position_range;
+    float4 color;
+    uint2 atlas_offset;
+    float shadow_map_resolution;
+    uint pad0;
+};
+
+[[vk::binding(0)]] Texture2D<float4> gbuffer_tex;
+[[vk::binding(1)]] Texture2D<float> depth_tex;
+[[vk::binding(2)]] Texture2D<float3> geometric_normal_tex;
+[[vk::binding(3)]] Texture2D<float> shadow_map_tex;
+[[vk::binding(4)]] RWTexture2D<float4> output_tex;
+[[vk::binding(5)]] RWTexture2D<float4> direct_aov_tex;
+// Must match `LightPointLightsConstants` in `point_lights.rs`
+[[vk::binding(6)]] cbuffer _ {
+    PointLight lights[MAX_POINT_LIGHTS];
+    uint light_count;
+    uint write_direct_aov;
+    float pcf_radius;
+};
+
+// The face of the cube, in the order of `CUBE_FACES` in `point_lights.rs`, which `dir` points at
+uint cube_face(float3 dir) {
+    const float3 a = abs(dir);
+    if (a.x >= a.y && a.x >= a.z) {
+        return dir.x > 0.0 ? 0 : 1;
+    } else if (a.y >= a.z) {
+        return dir.y > 0.0 ? 2 : 3;
+    } else {
+        return dir.z > 0.0 ? 4 : 5;
+    }
+}
+
+// Depth of the shadow map texel at `tile_uv` in the tile of the light's `face`. Filter taps
+// which cross into the next face are clamped to the edge of this one.
+float shadow_map_depth(PointLight light, uint face, float2 tile_uv) {
+    const int resolution = int(light.shadow_map_resolution);
+    const int2 texel = clamp(int2(floor(tile_uv * light.shadow_map_resolution)), 0, resolution - 1);
+    return shadow_map_tex[texel + int2(light.atlas_offset) + int2(face * resolution, 0)];
+}
+
+// Vogel disk in the unit circle
+float2 disk_tap(uint i, float rotation) {
+    const float r = sqrt((i + 0.5) / FILTER_TAPS);
+    const float theta = i * GOLDEN_ANGLE + rotation;
+    return r * float2(cos(theta), sin(theta));
+}
+
+float point_light_visibility(PointLight light, float3 pos_ws, float3 normal_ws, float rotation) {
+    const float3 to_surface = pos_ws - light.position_range.xyz;
+
+    // Faces span 90 degrees, so texels grow with the distance to the light.
+    const float texel_size = 2.0 * length(to_surface) / light.shadow_map_resolution;
+
+    // Offset along the normal, and towards the light, against shadow acne
+    const float3 receiver_ws = pos_ws
+        + normal_ws * texel_size * NORMAL_OFFSET_TEXELS
+        - normalize(to_surface) * texel_size;
+    const uint face = cube_face(receiver_ws - light.position_range.xyz);
+
+    const float4 pos_shadow = mul(light.world_to_shadow[face], float4(receiver_ws, 1.0));
+    const float2 tile_uv = cs_to_uv(pos_shadow.xy / pos_shadow.w);
+    const float receiver_depth = pos_shadow.z / pos_shadow.w;
+
+    const float radius_uv = pcf_radius / light.shadow_map_resolution;
+
+    float lit = 0.0;
+    for (uint i = 0; i < FILTER_TAPS; ++i) {
+        const float2 uv = tile_uv + disk_tap(i, rotation) * radius_uv;
+        lit += shadow_map_depth(light, face, uv) <= receiver_depth ? 1.0 : 0.0;
+    }
+    return lit / FILTER_TAPS;
+}
+
+[numthreads(8, 8, 1)]
+void main(uint2 px: SV_DispatchThreadID) {
+    float2 output_size;
+    output_tex.GetDimensions(output_size.x, output_size.y);
+
+    const float z_over_w = depth_tex[px];
+    if (0.0 == z_over_w) {
+        return;
+    }
+
+    const float2 uv = (px + 0.5) / output_size;
+    float4 pt_cs = float4(uv_to_cs(uv), z_over_w, 1.0);
+    float4 pt_ws = mul(frame_constants.view_constants.view_to_world, mul(frame_constants.view_constants.sample_to_view, pt_cs));
+    pt_ws /= pt_ws.w;
+
+    const float3 normal_vs = geometric_normal_tex[px] * 2.0 - 1.0;
+    const float3 normal_ws = mul(frame_constants.view_constants.view_to_world, float4(normal_vs, 0.0)).xyz;
+
+    GbufferData gbuffer = GbufferDataPacked::from_uint4(asuint(gbuffer_tex[px])).unpack();
+    const float3x3 tangent_to_world = gbuffer.tangent_to_world();
+    float3 wo = mul(normalize(get_eye_position() - pt_ws.xyz), tangent_to_world);
+
+    // Like in `light_gbuffer.hlsl`
+    if (wo.z < 0.0) {
+        wo.z *= -0.25;
+        wo = normalize(wo);
+    }
+
+    LayeredBrdf brdf = LayeredBrdf::from_gbuffer_ndotv(gbuffer, wo.z);
+    const float rotation = blue_noise_for_pixel(px, frame_constants.frame_index).x * M_TAU;
+
+    float3 radiance = 0.0;
+    for (uint i = 0; i < light_count; ++i) {
+        const PointLight light = lights[i];
+        const float3 to_light = light.position_range.xyz - pt_ws.xyz;
+        const float dist2 = dot(to_light, to_light);
+        const float range = light.position_range.w;
+
+        // Inverse-square falloff, windowed to reach zero at the light's range
+        const float window = square(saturate(1.0 - square(dist2 / (range * range))));
+        const float3 wi = mul(to_light * rsqrt(dist2), tangent_to_world);
+        if (window == 0.0 || wi.z <= 0.0) {
+            continue;
+        }
+
+        const float3 brdf_value = brdf.evaluate_directional_light(wo, wi) * wi.z;
+        const float visibility = point_light_visibility(light, pt_ws.xyz, normal_ws, rotation);
+        radiance += brdf_value * light.color.rgb * (window * visibility / dist2);
+    }
+
+    output_tex[px] += float4(radiance, 0.0);
+
+    if (write_direct_aov) {
+        direct_aov_tex[px] += float4(radiance, 0.0);
+    }
+}
@@ -18,6 +18,8 @@ static float2 SAMPLE_BEZOLD_BRUCKE_LUT(float coord) {
 [[vk::binding(4)]] cbuffer _ {
     float4 output_tex_size;
     float ev_shift;
+    // Brightest output value relative to SDR white. Above 1.0 for HDR displays.
+    float display_max_brightness;
 };
 
 #define USE_GRADE 0
@@ -123,7 +125,7 @@ void main(uint2 px: SV_DispatchThreadID) {
 
 #if USE_DISPLAY_TRANSFORM
     // Apply a perceptually neutral display transform
-    col = display_transform_sRGB(col);
+    col = display_transform_sRGB(col / display_max_brightness) * display_max_brightness;
 #endif
 
     // Crank up the contrast
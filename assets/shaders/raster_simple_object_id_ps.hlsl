@@ -0,0 +1,2 @@
+#define RASTER_OBJECT_ID 1
+#include "raster_simple_ps.hlsl"
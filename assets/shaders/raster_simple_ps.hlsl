@@ -4,6 +4,8 @@
 #include "inc/pack_unpack.hlsl"
 #include "inc/bindless.hlsl"
 #include "inc/gbuffer.hlsl"
+#include "inc/parallax.hlsl"
+#include "inc/velocity.hlsl"
 
 struct PsIn {
     [[vk::location(0)]] float4 color: TEXCOORD0;
@@ -14,12 +16,14 @@ struct PsIn {
     [[vk::location(5)]] float3 bitangent: TEXCOORD5;
     [[vk::location(6)]] float3 vs_pos: TEXCOORD6;
     [[vk::location(7)]] float3 prev_vs_pos: TEXCOORD7;
+    [[vk::location(8)]] nointerpolation uint instance_index: TEXCOORD8;
 };
 
 [[vk::push_constant]]
 struct {
-    uint draw_index;
+    uint first_draw_instance;
     uint mesh_index;
+    float2 render_extent;
 } push_constants;
 
 struct InstanceTransform {
@@ -35,41 +39,63 @@ struct PsOut {
     float4 velocity: SV_TARGET2;
 };
 
-PsOut main(PsIn ps) {
+struct PsOutWithObjectId {
+    float3 geometric_normal: SV_TARGET0;
+    float4 gbuffer: SV_TARGET1;
+    float4 velocity: SV_TARGET2;
+    uint object_id: SV_TARGET3;
+    float4 subsurface: SV_TARGET4;
+};
+
+PsOut shade_gbuffer(PsIn ps) {
     Mesh mesh = meshes[push_constants.mesh_index];
     MeshMaterial material = vertices.Load<MeshMaterial>(mesh.mat_data_offset + ps.material_id * sizeof(MeshMaterial));
 
-    float2 albedo_uv = transform_material_uv(material, ps.uv, 0);
+    // Parallax occlusion mapping offsets the UVs of all the material's maps, so it goes first.
+    const float2 uv_dx = ddx(ps.uv);
+    const float2 uv_dy = ddy(ps.uv);
+    float2 uv = ps.uv;
+    if ((material.flags & MESH_MATERIAL_FLAG_PARALLAX) != 0 && dot(ps.bitangent, ps.bitangent) > 0.0) {
+        const float3x4 xform = instance_transforms_dyn[ps.instance_index].current;
+        const float3 view_ws = -direction_view_to_world(normalize(ps.vs_pos));
+        const float3 view_ts = float3(
+            dot(view_ws, normalize(mul(xform, float4(ps.tangent, 0.0)))),
+            dot(view_ws, normalize(mul(xform, float4(ps.bitangent, 0.0)))),
+            dot(view_ws, normalize(mul(xform, float4(ps.normal, 0.0))))
+        );
+        uv = parallax_occlusion_uv(material, ps.uv, uv_dx, uv_dy, view_ts);
+    }
+
+    float2 albedo_uv = transform_material_uv(material, uv, 0);
     Texture2D albedo_tex = bindless_textures[NonUniformResourceIndex(material.albedo_map)];
     float4 albedo_texel = albedo_tex.SampleBias(sampler_llr, albedo_uv, -0.5);
-    if (albedo_texel.a < 0.5) {
+    if ((material.flags & MESH_MATERIAL_FLAG_ALPHA_CUTOFF) != 0) {
+        // Like `rt_should_ignore_hit`
+        if (albedo_texel.a * material.base_color_mult[3] * ps.color.a < material.alpha_cutoff) {
+            discard;
+        }
+    } else if (albedo_texel.a < 0.5) {
         discard;
     }
 
     float3 albedo = albedo_texel.xyz * float4(material.base_color_mult).xyz * ps.color.xyz;
 
-    float2 spec_uv = transform_material_uv(material, ps.uv, 2);
+    float2 spec_uv = transform_material_uv(material, uv, 2);
     Texture2D spec_tex = bindless_textures[NonUniformResourceIndex(material.spec_map)];
     const float4 metalness_roughness = spec_tex.SampleBias(sampler_llr, spec_uv, -0.5);
     float perceptual_roughness = material.roughness_mult * metalness_roughness.y;
     float roughness = clamp(perceptual_roughness_to_roughness(perceptual_roughness), 1e-4, 1.0);
     float metalness = metalness_roughness.z * material.metalness_factor;
 
+    float2 normal_uv = transform_material_uv(material, uv, 1);
     Texture2D normal_tex = bindless_textures[NonUniformResourceIndex(material.normal_map)];
-    const float3 ts_normal = normal_tex.SampleBias(sampler_llr, ps.uv, -0.5).xyz * 2.0 - 1.0;
+    const float3 ts_normal = normal_tex.SampleBias(sampler_llr, normal_uv, -0.5).xyz * 2.0 - 1.0;
 
     float3 normal_ws; {
-        float3 normal_os = ps.normal;
-
-        if (true) {
-            if (dot(ps.bitangent, ps.bitangent) > 0.0) {
-                float3x3 tbn = float3x3(ps.tangent, ps.bitangent, ps.normal);
-                normal_os = mul(ts_normal, tbn);
-            }
-        }
+        float3 normal_os = apply_normal_map(ps.normal, ps.tangent, ps.bitangent, ts_normal);
 
         // Transform to world space
-        normal_ws = normalize(mul(instance_transforms_dyn[push_constants.draw_index].current, float4(normal_os, 0.0)));
+        normal_ws = normalize(mul(instance_transforms_dyn[ps.instance_index].current, float4(normal_os, 0.0)));
     }
 
     // Derive normal from depth
@@ -87,12 +113,12 @@ PsOut main(PsIn ps) {
     }
     //normal_ws = geometric_normal_ws;
 
-    float2 emissive_uv = transform_material_uv(material, ps.uv, 3);
+    float2 emissive_uv = transform_material_uv(material, uv, 3);
     Texture2D emissive_tex = bindless_textures[NonUniformResourceIndex(material.emissive_map)];
     float3 emissive = 1.0.xxx
         * emissive_tex.SampleBias(sampler_llr, emissive_uv, -0.5).rgb
         * float3(material.emissive)
-        * instance_dynamic_parameters_dyn[push_constants.draw_index].emissive_multiplier;
+        * instance_dynamic_parameters_dyn[ps.instance_index].emissive_multiplier;
 
     //albedo = float3(0.966653, 0.802156, 0.323968); // Au from Mitsuba
 
@@ -103,10 +129,54 @@ PsOut main(PsIn ps) {
     gbuffer.metalness = metalness;
     gbuffer.emissive = emissive;
 
+    if (material.anisotropy > 0.0 && dot(ps.bitangent, ps.bitangent) > 0.0) {
+        const float3 direction_os = material_anisotropy_direction(material, ps.tangent, ps.bitangent);
+        gbuffer.anisotropy = material.anisotropy;
+        gbuffer.anisotropy_direction = normalize(mul(instance_transforms_dyn[ps.instance_index].current, float4(direction_os, 0.0)));
+    }
+
+    gbuffer.iridescence = material.iridescence;
+    gbuffer.iridescence_ior = material.iridescence_ior;
+    gbuffer.iridescence_thickness = material.iridescence_thickness;
+
     PsOut ps_out;
     ps_out.geometric_normal = geometric_normal_vs * 0.5 + 0.5;
     ps_out.gbuffer = asfloat(gbuffer.pack().data0);
-    ps_out.velocity = float4(ps.prev_vs_pos - ps.vs_pos, 0);
+    ps_out.velocity = encode_velocity(ps.vs_pos, ps.prev_vs_pos, push_constants.render_extent);
 
     return ps_out;
 }
+
+// Per-channel distance that light scatters under the surface, in world units, or zero for opaque materials.
+float3 subsurface_scattering_distance(PsIn ps) {
+    Mesh mesh = meshes[push_constants.mesh_index];
+    MeshMaterial material = vertices.Load<MeshMaterial>(mesh.mat_data_offset + ps.material_id * sizeof(MeshMaterial));
+
+    if ((material.flags & MESH_MATERIAL_FLAG_SUBSURFACE) == 0) {
+        return 0.0;
+    }
+
+    return float3(material.subsurface_color[0], material.subsurface_color[1], material.subsurface_color[2])
+        * material.subsurface_radius;
+}
+
+#if RASTER_OBJECT_ID
+// Also writes the index of the instance being drawn + 1, leaving zero for the background,
+// and the subsurface scattering distance.
+PsOutWithObjectId main(PsIn ps) {
+    const PsOut gbuffer_out = shade_gbuffer(ps);
+
+    PsOutWithObjectId ps_out;
+    ps_out.geometric_normal = gbuffer_out.geometric_normal;
+    ps_out.gbuffer = gbuffer_out.gbuffer;
+    ps_out.velocity = gbuffer_out.velocity;
+    ps_out.object_id = ps.instance_index + 1;
+    ps_out.subsurface = float4(subsurface_scattering_distance(ps), 0.0);
+
+    return ps_out;
+}
+#else
+PsOut main(PsIn ps) {
+    return shade_gbuffer(ps);
+}
+#endif
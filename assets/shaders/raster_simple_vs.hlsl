@@ -4,8 +4,9 @@
 
 [[vk::push_constant]]
 struct {
-    uint draw_index;
+    uint first_draw_instance;
     uint mesh_index;
+    float2 render_extent;
 } push_constants;
 
 struct InstanceTransform {
@@ -15,6 +16,9 @@ struct InstanceTransform {
 
 [[vk::binding(0)]] StructuredBuffer<InstanceTransform> instance_transforms_dyn;
 
+// Indices of the instances drawn, grouped by mesh; each draw covers a range of them.
+[[vk::binding(1)]] StructuredBuffer<uint> draw_instances_dyn;
+
 struct VsOut {
 	float4 position: SV_Position;
     [[vk::location(0)]] float4 color: TEXCOORD0;
@@ -25,12 +29,14 @@ struct VsOut {
     [[vk::location(5)]] float3 bitangent: TEXCOORD5;
     [[vk::location(6)]] float3 vs_pos: TEXCOORD6;
     [[vk::location(7)]] float3 prev_vs_pos: TEXCOORD7;
+    [[vk::location(8)]] nointerpolation uint instance_index: TEXCOORD8;
 };
 
 VsOut main(uint vid: SV_VertexID, uint instance_index: SV_InstanceID) {
     VsOut vsout;
 
     const Mesh mesh = meshes[push_constants.mesh_index];
+    const uint instance = draw_instances_dyn[push_constants.first_draw_instance + instance_index];
 
     // TODO: replace with Load<float4> once there's a fast path for NV
     // https://github.com/microsoft/DirectXShaderCompiler/issues/2193
@@ -51,12 +57,12 @@ VsOut main(uint vid: SV_VertexID, uint instance_index: SV_InstanceID) {
     uint material_id = vertices.Load(vid * sizeof(uint) + mesh.vertex_mat_offset);
 
     //float3 ws_pos = v.position + float3(push_constants.instance_position);
-    float3 ws_pos = mul(instance_transforms_dyn[push_constants.draw_index].current, float4(v.position, 1.0));
+    float3 ws_pos = mul(instance_transforms_dyn[instance].current, float4(v.position, 1.0));
     
     float4 vs_pos = mul(frame_constants.view_constants.world_to_view, float4(ws_pos, 1.0));
     float4 cs_pos = mul(frame_constants.view_constants.view_to_sample, vs_pos);
 
-    float3 prev_ws_pos = mul(instance_transforms_dyn[push_constants.draw_index].previous, float4(v.position, 1.0));
+    float3 prev_ws_pos = mul(instance_transforms_dyn[instance].previous, float4(v.position, 1.0));
     float4 prev_vs_pos = mul(frame_constants.view_constants.world_to_view, float4(prev_ws_pos, 1.0));
     //float4 prev_cs_pos = mul(frame_constants.view_constants.view_to_sample, prev_vs_pos);
 
@@ -70,6 +76,7 @@ VsOut main(uint vid: SV_VertexID, uint instance_index: SV_InstanceID) {
 
     vsout.vs_pos = vs_pos.xyz / vs_pos.w;
     vsout.prev_vs_pos = prev_vs_pos.xyz / prev_vs_pos.w;
+    vsout.instance_index = instance;
 
     return vsout;
 }
\ No newline at end of file
@@ -0,0 +1,21 @@
+#ifndef ANALYTIC_HLSL
+#define ANALYTIC_HLSL
+
+// Analytic primitives, found by intersection shaders in the boxes of procedural BLASes.
+// See `analytic_primitives.rs`, which writes the record.
+struct AnalyticPrimitiveRecord {
+    // Shape parameters, e.g. the radius of a sphere, or the half extents of a box
+    float4 params;
+
+    // Albedo in `rgb`, and roughness in `a`
+    float4 albedo_roughness;
+};
+
+[[vk::shader_record_ext]] ConstantBuffer<AnalyticPrimitiveRecord> analytic_primitive;
+
+struct AnalyticHitAttrib {
+    // Object-space normal at the hit
+    float3 normal;
+};
+
+#endif  // ANALYTIC_HLSL
@@ -0,0 +1,20 @@
+#include "../../inc/pack_unpack.hlsl"
+#include "../../inc/rt.hlsl"
+#include "analytic.hlsl"
+
+// Shades hits on analytic primitives with the plain material in their record.
+[shader("closesthit")]
+void main(inout GbufferRayPayload payload: SV_RayPayload, in AnalyticHitAttrib attrib: SV_IntersectionAttributes) {
+    // Normals transform by the inverse transpose, so they stay perpendicular under non-uniform scaling.
+    const float3 normal = normalize(mul(attrib.normal, (float3x3)WorldToObject3x4()));
+
+    GbufferData gbuffer = GbufferData::create_zero();
+    gbuffer.albedo = analytic_primitive.albedo_roughness.rgb;
+    gbuffer.normal = normal;
+    gbuffer.roughness = analytic_primitive.albedo_roughness.a;
+    gbuffer.metalness = 0.0;
+
+    payload.gbuffer_packed = gbuffer.pack();
+    payload.geometric_normal_packed = pack_normal_11_10_11(normal);
+    payload.t = RayTCurrent();
+}
@@ -0,0 +1,17 @@
+#ifndef ANALYTIC_INTERSECTION_HLSL
+#define ANALYTIC_INTERSECTION_HLSL
+
+#include "analytic.hlsl"
+
+void report_analytic_hit(float t, float3 normal) {
+    AnalyticHitAttrib attrib;
+    attrib.normal = normal;
+    ReportHit(t, 0, attrib);
+}
+
+// Whether `t` is closer than the hits found so far, and not before the ray starts
+bool is_closer_hit(float t) {
+    return t >= RayTMin() && t <= RayTCurrent();
+}
+
+#endif  // ANALYTIC_INTERSECTION_HLSL
@@ -0,0 +1,42 @@
+#include "analytic_intersection.hlsl"
+
+// A box with half extents `params.xyz`, centered at the origin of the instance
+[shader("intersection")]
+void main() {
+    const float3 half_extents = analytic_primitive.params.xyz;
+    const float3 origin = ObjectRayOrigin();
+    const float3 direction = ObjectRayDirection();
+
+    // Slabs
+    const float3 inv_direction = 1.0 / direction;
+    const float3 t0 = (-half_extents - origin) * inv_direction;
+    const float3 t1 = (half_extents - origin) * inv_direction;
+    const float3 t_min3 = min(t0, t1);
+    const float3 t_max3 = max(t0, t1);
+    const float t_enter = max(t_min3.x, max(t_min3.y, t_min3.z));
+    const float t_exit = min(t_max3.x, min(t_max3.y, t_max3.z));
+
+    if (t_enter > t_exit) {
+        return;
+    }
+
+    // The near hit, or the far one for rays starting inside the box.
+    const float t = t_enter >= RayTMin() ? t_enter : t_exit;
+
+    if (is_closer_hit(t)) {
+        // The face is that of the axis the hit is furthest along, relative to the extents.
+        const float3 p = (origin + direction * t) / half_extents;
+        const float3 a = abs(p);
+
+        float3 normal;
+        if (a.x >= a.y && a.x >= a.z) {
+            normal = float3(sign(p.x), 0.0, 0.0);
+        } else if (a.y >= a.z) {
+            normal = float3(0.0, sign(p.y), 0.0);
+        } else {
+            normal = float3(0.0, 0.0, sign(p.z));
+        }
+
+        report_analytic_hit(t, normal);
+    }
+}
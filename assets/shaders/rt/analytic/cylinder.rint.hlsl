@@ -0,0 +1,58 @@
+#include "analytic_intersection.hlsl"
+
+// A capped cylinder along the Y axis of the instance, of radius `params.x`, with its caps
+// `params.y` above and below the origin.
+[shader("intersection")]
+void main() {
+    const float radius = analytic_primitive.params.x;
+    const float half_height = analytic_primitive.params.y;
+    const float3 origin = ObjectRayOrigin();
+    const float3 direction = ObjectRayDirection();
+
+    float best_t = RayTCurrent();
+    float3 best_normal = 0.0;
+    bool found = false;
+
+    // The side, where it's between the caps
+    const float a = dot(direction.xz, direction.xz);
+    if (a > 0.0) {
+        const float b = dot(origin.xz, direction.xz);
+        const float c = dot(origin.xz, origin.xz) - radius * radius;
+        const float discriminant = b * b - a * c;
+
+        if (discriminant >= 0.0) {
+            const float sqrt_discriminant = sqrt(discriminant);
+            const float side_t[2] = { (-b - sqrt_discriminant) / a, (-b + sqrt_discriminant) / a };
+
+            for (uint i = 0; i < 2; ++i) {
+                const float t = side_t[i];
+                const float3 p = origin + direction * t;
+
+                if (abs(p.y) <= half_height && t >= RayTMin() && t <= best_t) {
+                    best_t = t;
+                    best_normal = float3(p.x, 0.0, p.z) / radius;
+                    found = true;
+                }
+            }
+        }
+    }
+
+    // The caps, where they're within the radius
+    if (direction.y != 0.0) {
+        for (uint i = 0; i < 2; ++i) {
+            const float cap_y = i == 0 ? half_height : -half_height;
+            const float t = (cap_y - origin.y) / direction.y;
+            const float2 p = origin.xz + direction.xz * t;
+
+            if (dot(p, p) <= radius * radius && t >= RayTMin() && t <= best_t) {
+                best_t = t;
+                best_normal = float3(0.0, sign(cap_y), 0.0);
+                found = true;
+            }
+        }
+    }
+
+    if (found) {
+        report_analytic_hit(best_t, best_normal);
+    }
+}
@@ -0,0 +1,21 @@
+#include "analytic_intersection.hlsl"
+
+// A rectangle on the XZ plane of the instance, with half extents `params.xy`. It's double-sided:
+// the normal faces the ray.
+[shader("intersection")]
+void main() {
+    const float2 half_extents = analytic_primitive.params.xy;
+    const float3 origin = ObjectRayOrigin();
+    const float3 direction = ObjectRayDirection();
+
+    if (direction.y == 0.0) {
+        return;
+    }
+
+    const float t = -origin.y / direction.y;
+    const float2 p = origin.xz + direction.xz * t;
+
+    if (all(abs(p) <= half_extents) && is_closer_hit(t)) {
+        report_analytic_hit(t, float3(0.0, direction.y > 0.0 ? -1.0 : 1.0, 0.0));
+    }
+}
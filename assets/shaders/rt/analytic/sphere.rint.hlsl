@@ -0,0 +1,31 @@
+#include "analytic_intersection.hlsl"
+
+// A sphere of radius `params.x` at the origin of the instance
+[shader("intersection")]
+void main() {
+    const float radius = analytic_primitive.params.x;
+    const float3 origin = ObjectRayOrigin();
+    const float3 direction = ObjectRayDirection();
+
+    // The direction isn't normalized under scaling; `t` is in its units, like `RayTCurrent()`.
+    const float a = dot(direction, direction);
+    const float b = dot(origin, direction);
+    const float c = dot(origin, origin) - radius * radius;
+    const float discriminant = b * b - a * c;
+
+    if (discriminant < 0.0) {
+        return;
+    }
+
+    const float sqrt_discriminant = sqrt(discriminant);
+
+    // The near hit, or the far one for rays starting inside the sphere.
+    float t = (-b - sqrt_discriminant) / a;
+    if (t < RayTMin()) {
+        t = (-b + sqrt_discriminant) / a;
+    }
+
+    if (is_closer_hit(t)) {
+        report_analytic_hit(t, (origin + direction * t) / radius);
+    }
+}
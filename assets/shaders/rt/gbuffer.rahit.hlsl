@@ -0,0 +1,9 @@
+#include "../inc/rt.hlsl"
+#include "../inc/rt_any_hit.hlsl"
+
+[shader("anyhit")]
+void main(inout GbufferRayPayload payload: SV_RayPayload, in BuiltInTriangleIntersectionAttributes attrib: SV_IntersectionAttributes) {
+    if (rt_should_ignore_hit(attrib.barycentrics)) {
+        IgnoreHit();
+    }
+}
@@ -57,7 +57,6 @@ void main(inout GbufferRayPayload payload: SV_RayPayload, in RayHitAttrib attrib
     float3 normal = v0.normal * barycentrics.x + v1.normal * barycentrics.y + v2.normal * barycentrics.z;
 
     const float3 surf_normal = normalize(cross(v1.position - v0.position, v2.position - v0.position));
-    normal = surf_normal;
 
     float4 v_color = 1.0.xxxx;
     if (mesh.vertex_aux_offset != 0) {
@@ -100,7 +99,6 @@ void main(inout GbufferRayPayload payload: SV_RayPayload, in RayHitAttrib attrib
 
     //albedo *= lerp(0.75, 1.0, metalness);
 
-#if 0
     float4 v_tangent_packed0 =
         mesh.vertex_tangent_offset != 0
             ? asfloat(vertices.Load4(ind.x * sizeof(float4) + mesh.vertex_tangent_offset))
@@ -126,17 +124,12 @@ void main(inout GbufferRayPayload payload: SV_RayPayload, in RayHitAttrib attrib
     float3 tangent = tangent0 * barycentrics.x + tangent1 * barycentrics.y + tangent2 * barycentrics.z;
     float3 bitangent = bitangent0 * barycentrics.x + bitangent1 * barycentrics.y + bitangent2 * barycentrics.z;
 
-    float2 normal_uv = transform_material_uv(material, uv, 0);
+    float2 normal_uv = transform_material_uv(material, uv, 1);
     Texture2D normal_tex = bindless_textures[NonUniformResourceIndex(material.normal_map)];
     float normal_lod = compute_texture_lod(normal_tex, lod_triangle_constant, WorldRayDirection(), surf_normal, cone_width);
     float3 ts_normal = normal_tex.SampleLevel(sampler_llr, normal_uv, normal_lod).xyz * 2.0 - 1.0;
 
-    if (dot(bitangent, bitangent) > 0.0) {
-        float3x3 tbn = float3x3(tangent, bitangent, normal);
-        normal = mul(ts_normal, tbn);
-    }
-    normal = normalize(normal);
-#endif
+    normal = apply_normal_map(normal, tangent, bitangent, ts_normal);
 
     float2 emissive_uv = transform_material_uv(material, uv, 3);
     Texture2D emissive_tex = bindless_textures[NonUniformResourceIndex(material.emissive_map)];
@@ -160,11 +153,34 @@ void main(inout GbufferRayPayload payload: SV_RayPayload, in RayHitAttrib attrib
     GbufferData gbuffer = GbufferData::create_zero();
     gbuffer.albedo = albedo;
     gbuffer.normal = normalize(mul(ObjectToWorld3x4(), float4(normal, 0.0)));
+
+    // From world-space positions, so that it stays perpendicular under non-uniform scaling.
+    float3 geometric_normal = normalize(cross(v1_pos_ws - v0_pos_ws, v2_pos_ws - v0_pos_ws));
+
+    // Double-sided surfaces face the ray, whichever side it hits.
+    if ((material.flags & MESH_MATERIAL_FLAG_DOUBLE_SIDED) != 0 && dot(geometric_normal, WorldRayDirection()) > 0.0) {
+        geometric_normal = -geometric_normal;
+    }
+
+    // Fix invalid normals, like the raster gbuffer
+    if (dot(gbuffer.normal, geometric_normal) < 0.0) {
+        gbuffer.normal = -gbuffer.normal;
+    }
     gbuffer.roughness = roughness;
     //gbuffer.metalness = lerp(metalness_roughness.z, 1.0, material.metalness_factor);
     gbuffer.metalness = metalness;
     gbuffer.emissive = emissive;
 
+    if (material.anisotropy > 0.0 && dot(bitangent, bitangent) > 0.0) {
+        const float3 direction_os = material_anisotropy_direction(material, tangent, bitangent);
+        gbuffer.anisotropy = material.anisotropy;
+        gbuffer.anisotropy_direction = normalize(mul(ObjectToWorld3x4(), float4(direction_os, 0.0)));
+    }
+
+    gbuffer.iridescence = material.iridescence;
+    gbuffer.iridescence_ior = material.iridescence_ior;
+    gbuffer.iridescence_thickness = material.iridescence_thickness;
+
     //gbuffer.albedo = float3(0.966653, 0.802156, 0.323968); // Au from Mitsuba
     //gbuffer.metalness = 0;
     //gbuffer.roughness = 0.1;
@@ -173,5 +189,6 @@ void main(inout GbufferRayPayload payload: SV_RayPayload, in RayHitAttrib attrib
     //gbuffer.albedo = 0.7;
 
     payload.gbuffer_packed = gbuffer.pack();
+    payload.geometric_normal_packed = pack_normal_11_10_11(geometric_normal);
     payload.t = RayTCurrent();
 }
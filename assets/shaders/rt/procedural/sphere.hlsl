@@ -0,0 +1,9 @@
+#ifndef PROCEDURAL_SPHERE_HLSL
+#define PROCEDURAL_SPHERE_HLSL
+
+struct SphereHitAttrib {
+    // Object-space normal of the unit sphere; also the position of the hit
+    float3 normal;
+};
+
+#endif  // PROCEDURAL_SPHERE_HLSL
@@ -0,0 +1,20 @@
+#include "../../inc/pack_unpack.hlsl"
+#include "../../inc/rt.hlsl"
+#include "sphere.hlsl"
+
+// Shades hits found by `sphere.rint.hlsl` with a plain, rough material.
+[shader("closesthit")]
+void main(inout GbufferRayPayload payload: SV_RayPayload, in SphereHitAttrib attrib: SV_IntersectionAttributes) {
+    // Normals transform by the inverse transpose, so they stay perpendicular under non-uniform scaling.
+    const float3 normal = normalize(mul(attrib.normal, (float3x3)WorldToObject3x4()));
+
+    GbufferData gbuffer = GbufferData::create_zero();
+    gbuffer.albedo = 0.75;
+    gbuffer.normal = normal;
+    gbuffer.roughness = 0.5;
+    gbuffer.metalness = 0.0;
+
+    payload.gbuffer_packed = gbuffer.pack();
+    payload.geometric_normal_packed = pack_normal_11_10_11(normal);
+    payload.t = RayTCurrent();
+}
@@ -0,0 +1,32 @@
+#include "sphere.hlsl"
+
+// Finds hits on the unit sphere at the origin of the instance, whose box spans [-1, 1].
+[shader("intersection")]
+void main() {
+    const float3 origin = ObjectRayOrigin();
+    const float3 direction = ObjectRayDirection();
+
+    // The direction isn't normalized under scaling; `t` is in its units, like `RayTCurrent()`.
+    const float a = dot(direction, direction);
+    const float b = dot(origin, direction);
+    const float c = dot(origin, origin) - 1.0;
+    const float discriminant = b * b - a * c;
+
+    if (discriminant < 0.0) {
+        return;
+    }
+
+    const float sqrt_discriminant = sqrt(discriminant);
+
+    // The near hit, or the far one for rays starting inside the sphere.
+    float t = (-b - sqrt_discriminant) / a;
+    if (t < RayTMin()) {
+        t = (-b + sqrt_discriminant) / a;
+    }
+
+    if (t >= RayTMin() && t <= RayTCurrent()) {
+        SphereHitAttrib attrib;
+        attrib.normal = origin + direction * t;
+        ReportHit(t, 0, attrib);
+    }
+}
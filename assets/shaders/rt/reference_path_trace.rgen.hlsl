@@ -11,9 +11,11 @@
 #include "../inc/atmosphere.hlsl"
 #include "../inc/sun.hlsl"
 #include "../inc/lights/triangle.hlsl"
+#include "../inc/environment_cdf.hlsl"
 
 [[vk::binding(0, 3)]] RaytracingAccelerationStructure acceleration_structure;
 [[vk::binding(0, 0)]] RWTexture2D<float4> output_tex;
+[[vk::binding(1, 0)]] Texture2D<float> environment_cdf_tex;
 
 static const uint MAX_PATH_LENGTH = 30;
 static const uint RUSSIAN_ROULETTE_START_PATH_LENGTH = 3;
@@ -34,6 +36,10 @@ static const bool USE_SOFT_SHADOWS = true;
 
 static const bool USE_LIGHTS = true;
 static const bool USE_EMISSIVE = true;
+
+// Sample the sky in proportion to its brightness at every vertex, and combine
+// with rays which escape after BSDF sampling via multiple importance sampling.
+static const bool USE_ENVIRONMENT_NEE = true;
 static const bool RESET_ACCUMULATION = !true;
 static const bool ROLLING_ACCUMULATION = true;
 
@@ -51,6 +57,13 @@ float3 sample_environment_light(float3 dir) {
     return col;
 }
 
+// Veach's power heuristic, with a beta of 2; the weight of a sample from the strategy with `pdf`.
+float mis_power_heuristic(float pdf, float other_pdf) {
+    const float pdf2 = pdf * pdf;
+    const float other_pdf2 = other_pdf * other_pdf;
+    return pdf2 > 0.0 ? pdf2 / (pdf2 + other_pdf2) : 0.0;
+}
+
 // Approximate Gaussian remap
 // https://www.shadertoy.com/view/MlVSzw
 float inv_error_function(float x, float truncation) {
@@ -105,6 +118,11 @@ void main() {
 
         float roughness_bias = 0.0;
 
+        // For weighing the environment found by BSDF-sampled rays against environment NEE
+        // at the vertex they left. Over solid angle.
+        float prev_bsdf_pdf = 0.0;
+        bool prev_vertex_sampled_environment = false;
+
         RayCone ray_cone = pixel_ray_cone_from_image_height(
             DispatchRaysDimensions().y
         );
@@ -131,6 +149,8 @@ void main() {
                 .trace(acceleration_structure);
 
             if (primary_hit.is_hit) {
+                prev_vertex_sampled_environment = false;
+
                 // TODO
                 const float surface_spread_angle = 0.0;
                 ray_cone = ray_cone.propagate(surface_spread_angle, primary_hit.ray_t);
@@ -146,9 +166,9 @@ void main() {
                     rt_is_shadowed(
                         acceleration_structure,
                         new_ray(
-                            primary_hit.position,
+                            primary_hit.offset_ray_origin(to_light_norm),
                             to_light_norm,
-                            1e-4,
+                            0,
                             FLT_MAX
                     ));
 
@@ -198,7 +218,7 @@ void main() {
                 //gbuffer.roughness = 0.07;
                 //gbuffer.roughness = clamp((int(primary_hit.position.x * 0.2) % 5) / 5.0, 1e-4, 1.0);
 
-                const float3x3 tangent_to_world = build_orthonormal_basis(gbuffer.normal);
+                const float3x3 tangent_to_world = gbuffer.tangent_to_world();
                 const float3 wi = mul(to_light_norm, tangent_to_world);
 
                 float3 wo = mul(-outgoing_ray.Direction, tangent_to_world);
@@ -243,10 +263,10 @@ void main() {
 
                             TriangleLight triangle_light = TriangleLight::from_packed(triangle_lights_dyn[light_idx]);
                             LightSampleResultArea light_sample = sample_triangle_light(triangle_light.as_triangle(), urand);
-                            const float3 shadow_ray_origin = primary_hit.position;
                             const float3 to_light_ws = light_sample.pos - primary_hit.position;
                             const float dist_to_light2 = dot(to_light_ws, to_light_ws);
                             const float3 to_light_norm_ws = to_light_ws * rsqrt(dist_to_light2);
+                            const float3 shadow_ray_origin = primary_hit.offset_ray_origin(to_light_norm_ws);
 
                             const float to_psa_metric =
                                 max(0.0, dot(to_light_norm_ws, gbuffer.normal))
@@ -262,7 +282,7 @@ void main() {
                                         new_ray(
                                             shadow_ray_origin,
                                             to_light_norm_ws,
-                                            1e-3,
+                                            0,
                                             sqrt(dist_to_light2) - 2e-3
                                     ));
 
@@ -272,6 +292,38 @@ void main() {
                             }
                         }
                     }
+
+                    if (USE_ENVIRONMENT_NEE && !(INDIRECT_ONLY && path_length == 0)) {
+                        const EnvironmentSample env_sample = sample_environment_cdf(
+                            environment_cdf_tex,
+                            float2(uint_to_u01_float(hash1_mut(rng)), uint_to_u01_float(hash1_mut(rng)))
+                        );
+                        const float3 wi = mul(env_sample.dir, tangent_to_world);
+
+                        if (wi.z > 0.0 && env_sample.pdf > 0.0) {
+                            const bool is_shadowed =
+                                rt_is_shadowed(
+                                    acceleration_structure,
+                                    new_ray(
+                                        primary_hit.offset_ray_origin(env_sample.dir),
+                                        env_sample.dir,
+                                        0,
+                                        FLT_MAX
+                                ));
+
+                            if (!is_shadowed) {
+                                const float bsdf_pdf = brdf.pdf(wo, wi) * wi.z;
+                                const float mis_weight = mis_power_heuristic(env_sample.pdf, bsdf_pdf);
+
+                                total_radiance +=
+                                    throughput * brdf.evaluate(wo, wi) * wi.z
+                                    * sample_environment_light(env_sample.dir)
+                                    * mis_weight / env_sample.pdf;
+                            }
+                        }
+
+                        prev_vertex_sampled_environment = true;
+                    }
                 }
 
                 float3 urand;
@@ -305,10 +357,14 @@ void main() {
                         roughness_bias = lerp(roughness_bias, 1.0, 0.5 * brdf_sample.approx_roughness);
                     }
 
-                    outgoing_ray.Origin = primary_hit.position;
                     outgoing_ray.Direction = mul(tangent_to_world, brdf_sample.wi);
-                    outgoing_ray.TMin = 1e-4;
+                    outgoing_ray.Origin = primary_hit.offset_ray_origin(outgoing_ray.Direction);
+                    outgoing_ray.TMin = 0;
                     throughput *= brdf_sample.value_over_pdf;
+
+                    if (prev_vertex_sampled_environment) {
+                        prev_bsdf_pdf = brdf.pdf(wo, brdf_sample.wi) * brdf_sample.wi.z;
+                    }
                 } else {
                     break;
                 }
@@ -329,7 +385,15 @@ void main() {
                     }
                 }
             } else {
-                total_radiance += throughput * sample_environment_light(outgoing_ray.Direction);
+                float mis_weight = 1.0;
+                if (prev_vertex_sampled_environment) {
+                    mis_weight = mis_power_heuristic(
+                        prev_bsdf_pdf,
+                        environment_cdf_pdf(environment_cdf_tex, outgoing_ray.Direction)
+                    );
+                }
+
+                total_radiance += throughput * sample_environment_light(outgoing_ray.Direction) * mis_weight;
                 break;
             }
         }
@@ -0,0 +1,13 @@
+#include "../inc/rt_any_hit.hlsl"
+
+struct ShadowPayload {
+    bool is_shadowed;
+};
+
+// Shadow rays skip closest-hit shaders, so this is the only one to see their hits.
+[shader("anyhit")]
+void main(inout ShadowPayload payload : SV_RayPayload, in BuiltInTriangleIntersectionAttributes attrib: SV_IntersectionAttributes) {
+    if (rt_should_ignore_hit(attrib.barycentrics)) {
+        IgnoreHit();
+    }
+}
@@ -40,18 +40,17 @@ void main() {
     const float3 normal_vs = geometric_normal_tex[px] * 2.0 - 1.0;
     const float3 normal_ws = mul(frame_constants.view_constants.view_to_world, float4(normal_vs, 0.0)).xyz;
 
-    const float3 bias_dir = normal_ws;
-    const float bias_amount = (-pt_vs.z + length(pt_ws.xyz)) * 1e-5;
-    const float3 ray_origin = pt_ws.xyz + bias_dir * bias_amount;
+    const float3 to_sun = sample_sun_direction(
+        blue_noise_for_pixel(px, frame_constants.frame_index).xy,
+        USE_SOFT_SHADOWS
+    );
+    const float3 ray_origin = offset_reconstructed_ray_origin(pt_ws.xyz, normal_ws, to_sun, -pt_vs.z);
 
     const bool is_shadowed = rt_is_shadowed(
         acceleration_structure,
         new_ray(
             ray_origin,
-            sample_sun_direction(
-                blue_noise_for_pixel(px, frame_constants.frame_index).xy,
-                USE_SOFT_SHADOWS
-            ),
+            to_sun,
             0,
             FLT_MAX
         ));
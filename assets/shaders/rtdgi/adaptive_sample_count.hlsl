@@ -0,0 +1,58 @@
+#include "../inc/frame_constants.hlsl"
+
+// Must match `ADAPTIVE_SAMPLING_TILE_SIZE` in `rtdgi.rs`
+#define ADAPTIVE_SAMPLING_TILE_SIZE 8
+
+// Luminance moments from the full-res temporal filter, reprojected to the current frame.
+[[vk::binding(0)]] Texture2D<float2> variance_history_tex;
+[[vk::binding(1)]] Texture2D<float> tile_variance_history_tex;
+[[vk::binding(2)]] RWTexture2D<float> tile_variance_output_tex;
+[[vk::binding(3)]] RWTexture2D<uint> sample_count_output_tex;
+[[vk::binding(4)]] cbuffer _ {
+    float4 variance_tex_size;
+    uint min_sample_count;
+    uint max_sample_count;
+    float variance_scale;
+};
+
+// One thread per tile of the (half-res) trace. Estimates the relative variance
+// of GI in the tile, and picks the number of rays to trace for it next.
+[numthreads(8, 8, 1)]
+void main(uint2 tile: SV_DispatchThreadID) {
+    // Each trace pixel covers 2x2 pixels of the variance texture; take one of them.
+    const uint2 tile_origin = tile * ADAPTIVE_SAMPLING_TILE_SIZE * 2;
+
+    float rel_variance_sum = 0.0;
+    uint valid_count = 0;
+
+    for (uint y = 0; y < ADAPTIVE_SAMPLING_TILE_SIZE; ++y) {
+        for (uint x = 0; x < ADAPTIVE_SAMPLING_TILE_SIZE; ++x) {
+            const uint2 px = tile_origin + uint2(x, y) * 2;
+            if (any(px >= uint2(variance_tex_size.xy))) {
+                continue;
+            }
+
+            const float2 moments = variance_history_tex[px];
+            const float variance = max(0.0, moments.y - moments.x * moments.x);
+            rel_variance_sum += variance / max(1e-4, moments.x * moments.x);
+            valid_count += 1;
+        }
+    }
+
+    const float rel_variance = valid_count > 0 ? rel_variance_sum / valid_count : 0.0;
+
+    // The history is not reprojected, but the moments already are. This only smooths
+    // out flicker in the sample counts. Reject garbage in freshly created history.
+    float tile_variance_history = tile_variance_history_tex[tile];
+    if (!(tile_variance_history >= 0.0 && tile_variance_history < 1e4)) {
+        tile_variance_history = rel_variance;
+    }
+
+    const float tile_variance = lerp(tile_variance_history, rel_variance, 0.25);
+    tile_variance_output_tex[tile] = tile_variance;
+
+    // Relative standard deviation, mapped to the allowed range of sample counts
+    const float t = saturate(sqrt(tile_variance) * variance_scale);
+    const uint sample_count = uint(round(lerp(float(min_sample_count), float(max_sample_count), t)));
+    sample_count_output_tex[tile] = clamp(sample_count, min_sample_count, max_sample_count);
+}
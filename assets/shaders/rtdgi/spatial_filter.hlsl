@@ -18,6 +18,7 @@
 [[vk::binding(5)]] cbuffer _ {
     float4 output_tex_size;
     int4 spatial_resolve_offsets[16 * 4 * 8];
+    float strength;
 };
 
 [numthreads(8, 8, 1)]
@@ -26,6 +27,11 @@ void main(in uint2 px : SV_DispatchThreadID) {
         output_tex[px] = hit0_tex[px];
         return;
     #endif
+
+    if (strength <= 0.0) {
+        output_tex[px] = hit0_tex[px];
+        return;
+    }
     
     float4 sum = 0;
     float ex = 0;
@@ -51,7 +57,7 @@ void main(in uint2 px : SV_DispatchThreadID) {
     const uint filter_idx = 5;
 
     for (uint sample_i = 0; sample_i < sample_count; ++sample_i) {
-        const int2 sample_offset = spatial_resolve_offsets[(px_idx_in_quad * 16 + sample_i) + 64 * filter_idx].xy;
+        const int2 sample_offset = int2(round(spatial_resolve_offsets[(px_idx_in_quad * 16 + sample_i) + 64 * filter_idx].xy * strength));
         const int2 sample_px = px + sample_offset;
 
         const float3 sample_normal_vs = half_view_normal_tex[sample_px].rgb;
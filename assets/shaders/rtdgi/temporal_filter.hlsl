@@ -21,9 +21,11 @@
 [[vk::binding(8)]] RWTexture2D<float4> history_output_tex;
 [[vk::binding(9)]] RWTexture2D<float4> cv_history_output_tex;
 [[vk::binding(10)]] RWTexture2D<float4> output_tex;
-[[vk::binding(11)]] cbuffer _ {
+[[vk::binding(11)]] RWTexture2D<float4> noisy_output_tex;
+[[vk::binding(12)]] cbuffer _ {
     float4 output_tex_size;
     float4 gbuffer_tex_size;
+    float max_history_frames;
 };
 
 #include "../csgi/lookup.hlsl"
@@ -185,7 +187,9 @@ void main(uint2 px: SV_DispatchThreadID) {
     //clamped_history = history;
 
     // TODO: proper rejection (not "reproj_validity_dilated")
-    float3 res = lerp(clamped_history.rgb, center.rgb, 1.0 / lerp(1.0, 4.0, reproj_validity_dilated * light_stability));
+    // With no history allowed, this passes `center` through.
+    const float max_sample_count = min(4.0, 1.0 + max_history_frames);
+    float3 res = lerp(clamped_history.rgb, center.rgb, 1.0 / lerp(1.0, max_sample_count, reproj_validity_dilated * light_stability));
 
     const float smoothed_dev = lerp(dev_history, sRGB_to_luminance(abs(dev.rgb)), 0.1);
 
@@ -193,14 +197,20 @@ void main(uint2 px: SV_DispatchThreadID) {
     cv_history_output_tex[px] = float4(control_variate, smoothed_dev);
 
     float3 spatial_input;
+    float3 noisy;
     if (USE_RTDGI_CONTROL_VARIATES) {
         // Note: must not be clamped to properly temporally integrate.
         // This value could well end up being negative due to control variate noise,
         // but that is fine, as it will be clamped later.
         spatial_input = res + control_variate;
+        noisy = center.rgb + control_variate;
     } else {
         spatial_input = max(0.0.xxx, res);
+        noisy = center.rgb;
     }
 
     output_tex[px] = float4(spatial_input, light_stability);
+
+    // For comparisons with the denoised signal
+    noisy_output_tex[px] = float4(max(0.0.xxx, noisy), 1.0);
 }
@@ -14,6 +14,7 @@
 [[vk::binding(7)]] cbuffer _ {
     float4 output_tex_size;
     float4 gbuffer_tex_size;
+    float max_history_frames;
 };
 
 
@@ -105,8 +106,8 @@ void main(uint2 px: SV_DispatchThreadID) {
     const float remapped_temporal_change = smoothstep(0.01, 0.6, temporal_change);
     const float variance_adjusted_temporal_change = smoothstep(0.1, 1.0, 0.05 * temporal_change / center_temporal_dev);
 
-    float max_sample_count = 32;
-    max_sample_count = lerp(max_sample_count, 4, variance_adjusted_temporal_change);
+    float max_sample_count = max_history_frames;
+    max_sample_count = lerp(max_sample_count, min(4.0, max_history_frames), variance_adjusted_temporal_change);
     max_sample_count *= light_stability;
 
     float current_sample_count = history.a;
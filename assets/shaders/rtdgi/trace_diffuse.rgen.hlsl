@@ -30,6 +30,9 @@
 #define USE_EMISSIVE 1
 #define USE_LIGHTS 1
 
+// Must match `ADAPTIVE_SAMPLING_TILE_SIZE` in `rtdgi.rs`
+#define ADAPTIVE_SAMPLING_TILE_SIZE 8
+
 [[vk::binding(0, 3)]] RaytracingAccelerationStructure acceleration_structure;
 
 [[vk::binding(0)]] Texture2D<float4> gbuffer_tex;
@@ -45,6 +48,7 @@ DEFINE_BLUE_NOISE_SAMPLER_BINDINGS(4, 5, 6)
 [[vk::binding(12)]] cbuffer _ {
     float4 gbuffer_tex_size;
 };
+[[vk::binding(13)]] Texture2D<uint> sample_count_tex;
 
 #include "../csgi/lookup.hlsl"
 #include "../csgi/subray_lookup.hlsl"
@@ -156,7 +160,21 @@ void main() {
         }
     }
 
-    BrdfSample brdf_sample = brdf.sample(wo, urand);
+    // Every sample starts from the light sampling above, so their average keeps it as is.
+    const float3 direct_radiance = total_radiance;
+
+    // Trace more rays where the temporal variance is high. See `adaptive_sample_count.hlsl`.
+    const uint sample_count = sample_count_tex[px / ADAPTIVE_SAMPLING_TILE_SIZE];
+
+    float3 out_value_sum = 0.0.xxx;
+    uint valid_sample_count = 0;
+
+    for (uint sample_idx = 0; sample_idx < sample_count; ++sample_idx) {
+    // The R2 sequence, rotated by `urand`; the first sample uses `urand` as is
+    const float2 sample_urand = frac(urand + sample_idx * float2(0.7548776662, 0.5698402910));
+    float3 sample_radiance = direct_radiance;
+
+    BrdfSample brdf_sample = brdf.sample(wo, sample_urand);
 
     //const float origin_cascade_idx = csgi_blended_cascade_idx_for_pos(refl_ray_origin);
     const float origin_cascade_idx = csgi_cascade_idx_for_pos(refl_ray_origin);
@@ -243,7 +261,7 @@ void main() {
             }
 
             gbuffer.roughness = lerp(gbuffer.roughness, 1.0, ROUGHNESS_BIAS);
-            const float3x3 tangent_to_world = build_orthonormal_basis(gbuffer.normal);
+            const float3x3 tangent_to_world = gbuffer.tangent_to_world();
             const float3 wo = mul(-outgoing_ray.Direction, tangent_to_world);
             const LayeredBrdf brdf = LayeredBrdf::from_gbuffer_ndotv(gbuffer, wo.z);
 
@@ -268,15 +286,15 @@ void main() {
                 const float3 wi = mul(to_light_norm, tangent_to_world);
                 const float3 brdf_value = brdf.evaluate(wo, wi) * max(0.0, wi.z);
                 const float3 light_radiance = is_shadowed ? 0.0 : sun_radiance;
-                total_radiance += brdf_value * light_radiance;
+                sample_radiance += brdf_value * light_radiance;
             }
 
             if (USE_EMISSIVE) {
-                total_radiance += gbuffer.emissive;
+                sample_radiance += gbuffer.emissive;
             }
 
             if (USE_SCREEN_GI_REPROJECTION && is_on_screen) {
-                total_radiance += reprojected_radiance.rgb * gbuffer.albedo;
+                sample_radiance += reprojected_radiance.rgb * gbuffer.albedo;
             } else {
                 if (USE_LIGHTS) {
                     float2 urand = float2(
@@ -316,7 +334,7 @@ void main() {
                                 const float3 brdf_value = brdf.evaluate(wo, wi) * to_psa_metric;
                             #endif
 
-                            total_radiance +=
+                            sample_radiance +=
                                 !is_shadowed ? (triangle_light.radiance() * brdf_value / light_sample.pdf.value) : 0;
                         }
                     }
@@ -353,7 +371,7 @@ void main() {
                         lookup_params
                     );
 
-                    total_radiance += csgi * gbuffer.albedo;
+                    sample_radiance += csgi * gbuffer.albedo;
                 }
             }
         } else {
@@ -368,10 +386,10 @@ void main() {
                         int3 gi_vx = int3(floor(vol_pos / csgi_voxel_size(lookup_cascade_idx)));
                         uint3 vx = csgi_wrap_vx_within_cascade(gi_vx);
 
-                        total_radiance += subray_contrib * smoothstep(0.5, 1, csgi_opacity_tex[lookup_cascade_idx][vx]);
+                        sample_radiance += subray_contrib * smoothstep(0.5, 1, csgi_opacity_tex[lookup_cascade_idx][vx]);
                     }
                 #else
-                    total_radiance += lookup_csgi(
+                    sample_radiance += lookup_csgi(
                         csgi_lookup_pos,
 	                    0.0.xxx,    // don't offset by any normal
     	                CsgiLookupParams::make_default()
@@ -379,7 +397,7 @@ void main() {
                 );
                 #endif
             #else
-                total_radiance += sky_cube_tex.SampleLevel(sampler_llr, outgoing_ray.Direction, 0).rgb;
+                sample_radiance += sky_cube_tex.SampleLevel(sampler_llr, outgoing_ray.Direction, 0).rgb;
             #endif
         }
 
@@ -405,14 +423,20 @@ void main() {
         }
 
         #if USE_RTDGI_CONTROL_VARIATES
-            float3 out_value = total_radiance - control_variate;
+            float3 out_value = sample_radiance - control_variate;
             //float3 out_value = control_variate;
         #else
-            float3 out_value = total_radiance;
+            float3 out_value = sample_radiance;
         #endif
         //float3 out_value = control_variate;
 
-        out0_tex[px] = float4(out_value, 1);
+        out_value_sum += out_value;
+        valid_sample_count += 1;
+    }
+    } // sample_idx
+
+    if (valid_sample_count > 0) {
+        out0_tex[px] = float4(out_value_sum / valid_sample_count, 1);
     } else {
         out0_tex[px] = float4(0.0.xxx, 1);
     }
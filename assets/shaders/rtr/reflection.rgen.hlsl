@@ -62,6 +62,7 @@ DEFINE_BLUE_NOISE_SAMPLER_BINDINGS(2, 3, 4)
 [[vk::binding(11)]] cbuffer _ {
     float4 gbuffer_tex_size;
 };
+[[vk::binding(12)]] Texture2D<float3> geometric_normal_tex;
 
 #include "../csgi/lookup.hlsl"
 
@@ -95,9 +96,10 @@ void main() {
     GbufferData gbuffer = GbufferDataPacked::from_uint4(asuint(gbuffer_packed)).unpack();
     gbuffer.roughness = max(gbuffer.roughness, RTR_ROUGHNESS_CLAMP);
 
-    const float3x3 tangent_to_world = build_orthonormal_basis(gbuffer.normal);
+    const float3x3 tangent_to_world = gbuffer.tangent_to_world();
 
-    const float3 refl_ray_origin = view_ray_context.biased_secondary_ray_origin_ws();
+    const float3 geometric_normal_vs = geometric_normal_tex[hi_px] * 2.0 - 1.0;
+    const float3 geometric_normal_ws = direction_view_to_world(geometric_normal_vs);
 
     float3 wo = mul(-view_ray_context.ray_dir_ws(), tangent_to_world);
     const float3 primary_hit_normal = gbuffer.normal;
@@ -113,6 +115,10 @@ void main() {
     SpecularBrdf specular_brdf;
     specular_brdf.albedo = lerp(0.04, gbuffer.albedo, gbuffer.metalness);
     specular_brdf.roughness = gbuffer.roughness;
+    specular_brdf.anisotropy = gbuffer.anisotropy;
+    specular_brdf.iridescence = gbuffer.iridescence;
+    specular_brdf.iridescence_ior = gbuffer.iridescence_ior;
+    specular_brdf.iridescence_thickness = gbuffer.iridescence_thickness;
 
 #if USE_AGGRESSIVE_ROUGHNESS_BIAS
     const float roughness_bias = lerp(gbuffer.roughness, 1.0, 0.333);
@@ -150,14 +156,18 @@ void main() {
     }
 #endif
 
-    const uint cascade_idx = csgi_cascade_idx_for_pos(refl_ray_origin);
+    const uint cascade_idx = csgi_cascade_idx_for_pos(view_ray_context.ray_hit_ws());
 
     if (brdf_sample.is_valid()) {
         const bool use_short_ray = gbuffer.roughness > 0.55 && USE_SHORT_RAYS_FOR_ROUGH;
 
         RayDesc outgoing_ray;
         outgoing_ray.Direction = mul(tangent_to_world, brdf_sample.wi);
-        outgoing_ray.Origin = refl_ray_origin;
+        outgoing_ray.Origin = offset_reconstructed_ray_origin(
+            view_ray_context.ray_hit_ws(),
+            geometric_normal_ws,
+            outgoing_ray.Direction,
+            length(view_ray_context.ray_hit_vs()));
         outgoing_ray.TMin = 0;
 
         if (use_short_ray) {
@@ -181,7 +191,7 @@ void main() {
         if (primary_hit.is_hit) {
             GbufferData gbuffer = primary_hit.gbuffer_packed.unpack();
             gbuffer.roughness = lerp(gbuffer.roughness, 1.0, roughness_bias);
-            const float3x3 tangent_to_world = build_orthonormal_basis(gbuffer.normal);
+            const float3x3 tangent_to_world = gbuffer.tangent_to_world();
             const float3 wo = mul(-outgoing_ray.Direction, tangent_to_world);
             const LayeredBrdf brdf = LayeredBrdf::from_gbuffer_ndotv(gbuffer, wo.z);
 
@@ -245,10 +255,10 @@ void main() {
                         for (uint light_idx = 0; light_idx < frame_constants.triangle_light_count; light_idx += 1) {
                             TriangleLight triangle_light = TriangleLight::from_packed(triangle_lights_dyn[light_idx]);
                             LightSampleResultArea light_sample = sample_triangle_light(triangle_light.as_triangle(), urand);
-                            const float3 shadow_ray_origin = primary_hit.position;
-                            const float3 to_light_ws = light_sample.pos - shadow_ray_origin;
+                            const float3 to_light_ws = light_sample.pos - primary_hit.position;
                             const float dist_to_light2 = dot(to_light_ws, to_light_ws);
                             const float3 to_light_norm_ws = to_light_ws * rsqrt(dist_to_light2);
+                            const float3 shadow_ray_origin = primary_hit.offset_ray_origin(to_light_norm_ws);
 
                             const float to_psa_metric =
                                 max(0.0, dot(to_light_norm_ws, gbuffer.normal))
@@ -262,7 +272,7 @@ void main() {
                                         new_ray(
                                             shadow_ray_origin,
                                             to_light_norm_ws,
-                                            1e-4,
+                                            0,
                                             sqrt(dist_to_light2) - 2e-4
                                     ));
 
@@ -90,7 +90,7 @@ void main(const uint2 px : SV_DispatchThreadID) {
     // Clamp to fix moire on mirror-like surfaces
     gbuffer.roughness = max(gbuffer.roughness, RTR_ROUGHNESS_CLAMP);
 
-    const float3x3 tangent_to_world = build_orthonormal_basis(gbuffer.normal);
+    const float3x3 tangent_to_world = gbuffer.tangent_to_world();
     float3 wo = mul(-normalize(view_ray_context.ray_dir_ws()), tangent_to_world);
 
     // Hack for shading normals facing away from the outgoing ray's direction:
@@ -7,6 +7,8 @@
 [[vk::binding(3)]] RWTexture2D<float4> output_tex;
 [[vk::binding(4)]] cbuffer _ {
     int4 spatial_resolve_offsets[16 * 4 * 8];
+    float strength;
+    float max_history_frames;
 };
 
 #define SHUFFLE_SUBPIXELS 1
@@ -17,7 +19,8 @@ void main(int2 px: SV_DispatchThreadID) {
     const float center_depth = depth_tex[px];
     const float center_sample_count = center.w;
 
-    if (center_sample_count >= 16 || center_depth == 0.0) {
+    // Only cleans up pixels whose history is still short; never with the denoiser off.
+    if (strength <= 0.0 || center_sample_count >= min(16.0, max_history_frames + 1.0) || center_depth == 0.0) {
         output_tex[px] = center;
         return;
     }
@@ -33,12 +36,12 @@ void main(int2 px: SV_DispatchThreadID) {
 
     const uint sample_count = clamp(int(8 - center_sample_count / 2), 4, 16);
     //const uint sample_count = 8;
-    const uint kernel_scale = center_sample_count < 4 ? 2 : 1;
+    const float kernel_scale = (center_sample_count < 4 ? 2 : 1) * strength;
     const uint px_idx_in_quad = (((px.x & 1) | (px.y & 1) * 2) + (SHUFFLE_SUBPIXELS ? 1 : 0) * frame_constants.frame_index) & 3;
 
     for (uint sample_i = 0; sample_i < sample_count; ++sample_i) {
         // TODO: precalculate temporal variants
-        int2 sample_px = px + kernel_scale * spatial_resolve_offsets[(px_idx_in_quad * 16 + sample_i) + 64 * filter_idx].xy;
+        int2 sample_px = px + int2(round(kernel_scale * spatial_resolve_offsets[(px_idx_in_quad * 16 + sample_i) + 64 * filter_idx].xy));
 
         const float3 neigh = input_tex[sample_px].rgb;
         const float sample_depth = depth_tex[sample_px];
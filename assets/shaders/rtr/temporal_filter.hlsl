@@ -15,6 +15,7 @@
 [[vk::binding(5)]] RWTexture2D<float4> output_tex;
 [[vk::binding(6)]] cbuffer _ {
     float4 output_tex_size;
+    float max_history_frames;
 };
 
 // Should probably be 3 or 4
@@ -192,7 +193,8 @@ void main(uint2 px: SV_DispatchThreadID) {
 
     float4 history = history0 * h0_score + history1 * h1_score;
 
-    float target_sample_count = 4;
+    // With no history allowed, this passes `center` through.
+    float target_sample_count = min(4.0, 1.0 + max_history_frames);
     float4 res = lerp(history, center, lerp(1.0, 1.0 / target_sample_count, reproj_validity_dilated));
     res = working_to_linear(res);
 
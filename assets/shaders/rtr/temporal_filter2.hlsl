@@ -17,6 +17,7 @@
 [[vk::binding(5)]] RWTexture2D<float4> output_tex;
 [[vk::binding(6)]] cbuffer _ {
     float4 output_tex_size;
+    float max_history_frames;
 };
 
 #define ENCODING_SCHEME 0
@@ -217,7 +218,7 @@ void main(uint2 px: SV_DispatchThreadID) {
         clamped_history = history0 * h0_score + history1 * h1_score;
     #endif
 
-    float max_sample_count = 16;
+    float max_sample_count = max_history_frames;
     float current_sample_count = clamped_history.a;
 
     float4 filtered_center = center;
@@ -0,0 +1,98 @@
+#include "../inc/uv.hlsl"
+#include "../inc/frame_constants.hlsl"
+#include "../inc/pack_unpack.hlsl"
+#include "../inc/gbuffer.hlsl"
+
+[[vk::binding(0)]] Texture2D<float4> input_tex;
+[[vk::binding(1)]] Texture2D<float4> diffuse_tex;
+[[vk::binding(2)]] Texture2D<float4> subsurface_tex;
+[[vk::binding(3)]] Texture2D<float4> gbuffer_tex;
+[[vk::binding(4)]] Texture2D<float> depth_tex;
+[[vk::binding(5)]] RWTexture2D<float4> output_tex;
+[[vk::binding(6)]] cbuffer _ {
+    float4 output_tex_size;
+    int2 direction;
+    uint composite;
+};
+
+// Taps on each side of the center
+static const int SCATTER_TAP_COUNT = 12;
+
+// The profile is followed out to this many times the distance of its slower exponential, `3d`;
+// past that, less than 4% of the energy remains.
+static const float SCATTER_EXTENT = 3.0;
+
+// Keeps close-ups from sampling too sparsely.
+static const float SCATTER_MAX_EXTENT_PX = 96.0;
+
+// Christensen and Burley's fit of `s` for the surface albedo, when the profile is parameterized
+// by the diffuse mean free path, as in "Approximate Reflectance Profiles for Efficient Subsurface Scattering".
+float3 burley_scaling(float3 albedo) {
+    const float3 a = albedo - 0.8;
+    return 1.9 - albedo + 3.5 * a * a;
+}
+
+// Burley's normalized diffusion profile R(r), times the 2πr of the ring at radius `r`,
+// so that it integrates to one over [0, inf).
+float3 burley_radial_weight(float r, float3 d) {
+    return (exp(-r / d) + exp(-r / (3.0 * d))) / (4.0 * d);
+}
+
+float3 view_position(int2 px) {
+    const float2 uv = get_uv(px, output_tex_size);
+    return ViewRayContext::from_uv_and_depth(uv, depth_tex[px]).ray_hit_vs();
+}
+
+// One axis of a separable gather of the diffuse lighting, weighted by the Burley profile
+// of the distance between surface points. Neighbors which don't scatter keep their light to themselves,
+// so the center stands in for them.
+[numthreads(8, 8, 1)]
+void main(int2 px: SV_DispatchThreadID) {
+    const float3 scattering_distance = subsurface_tex[px].rgb;
+    if (all(scattering_distance <= 0.0)) {
+        return;
+    }
+
+    const float3 albedo = GbufferDataPacked::from_uint4(asuint(gbuffer_tex[px])).unpack_albedo();
+    const float3 d = max(1e-6, scattering_distance / burley_scaling(albedo));
+
+    const float3 center_vs = view_position(px);
+    const float3 center_input = input_tex[px].rgb;
+
+    // World units per pixel at the center's depth
+    const float pixel_size = 2.0 * -center_vs.z
+        / (frame_constants.view_constants.view_to_clip[1][1] * output_tex_size.y);
+    const float extent_px = min(SCATTER_MAX_EXTENT_PX, SCATTER_EXTENT * 3.0 * max3(d.x, d.y, d.z) / pixel_size);
+    const float tap_spacing_px = extent_px / SCATTER_TAP_COUNT;
+
+    float3 scattered = center_input;
+    if (extent_px >= 1.0) {
+        float3 sum = 0.0;
+        float3 weight_sum = 0.0;
+
+        for (int i = -SCATTER_TAP_COUNT; i <= SCATTER_TAP_COUNT; ++i) {
+            const int2 tap_px = px + direction * int(round(i * tap_spacing_px));
+
+            float r = abs(i * tap_spacing_px) * pixel_size;
+            float3 tap_input = center_input;
+
+            if (all(tap_px >= 0) && all(tap_px < int2(output_tex_size.xy)) && any(subsurface_tex[tap_px].rgb > 0.0)) {
+                r = length(view_position(tap_px) - center_vs);
+                tap_input = input_tex[tap_px].rgb;
+            }
+
+            const float3 weight = burley_radial_weight(r, d);
+            sum += weight * tap_input;
+            weight_sum += weight;
+        }
+
+        scattered = sum / max(1e-20, weight_sum);
+    }
+
+    if (composite) {
+        const float4 output = output_tex[px];
+        output_tex[px] = float4(output.rgb - diffuse_tex[px].rgb + scattered, output.a);
+    } else {
+        output_tex[px] = float4(scattered, 1.0);
+    }
+}
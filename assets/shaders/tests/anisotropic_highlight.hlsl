@@ -0,0 +1,45 @@
+#include "../inc/brdf.hlsl"
+
+// Angular widths of the highlight of a surface seen head-on, along tangent space X and Y:
+// how far the light can move off the mirror direction before the BRDF drops to half of its peak.
+// The first case is isotropic, the second has `push_constants.anisotropy`.
+[[vk::binding(0)]] RWStructuredBuffer<float2> output_buf;
+
+[[vk::push_constant]]
+struct {
+    float roughness;
+    float anisotropy;
+} push_constants;
+
+static const uint CASE_COUNT = 2;
+static const uint TILT_STEPS = 1024;
+
+[numthreads(CASE_COUNT, 1, 1)]
+void main(uint idx: SV_DispatchThreadID) {
+    SpecularBrdf brdf;
+    brdf.roughness = push_constants.roughness;
+    brdf.albedo = 1.0;
+    brdf.anisotropy = idx == 0 ? 0.0 : push_constants.anisotropy;
+    brdf.iridescence = 0.0;
+    brdf.iridescence_ior = 1.0;
+    brdf.iridescence_thickness = 0.0;
+
+    const float3 wo = float3(0.0, 0.0, 1.0);
+    const float peak = brdf.evaluate(wo, wo).value.x;
+
+    float2 half_width = 0.5 * M_PI;
+    for (uint axis = 0; axis < 2; ++axis) {
+        for (uint i = 1; i < TILT_STEPS; ++i) {
+            const float tilt = 0.5 * M_PI * i / TILT_STEPS;
+            float3 wi = float3(0.0, 0.0, cos(tilt));
+            wi[axis] = sin(tilt);
+
+            if (brdf.evaluate(wo, wi).value.x < 0.5 * peak) {
+                half_width[axis] = tilt;
+                break;
+            }
+        }
+    }
+
+    output_buf[idx] = half_width;
+}
@@ -0,0 +1,9 @@
+// Counts the threads dispatched into the first word of `counter_buf`, for feedback buffer tests.
+
+[[vk::binding(0)]] RWByteAddressBuffer counter_buf;
+
+[numthreads(64, 1, 1)]
+void main() {
+    uint prev;
+    counter_buf.InterlockedAdd(0, 1, prev);
+}
@@ -0,0 +1,18 @@
+#include "../inc/frame_constants.hlsl"
+#include "../inc/velocity.hlsl"
+
+[[vk::binding(0)]] RWStructuredBuffer<float4> output_buf;
+
+[[vk::push_constant]]
+struct {
+    float2 render_extent;
+} push_constants;
+
+// Encodes the velocity of static points on an 8x8 grid across the view.
+[numthreads(64, 1, 1)]
+void main(uint idx: SV_DispatchThreadID) {
+    const float2 uv = (float2(idx % 8, idx / 8) + 0.5) / 8.0;
+    const float4 vs_pos = mul(frame_constants.view_constants.clip_to_view, float4(uv_to_cs(uv), 0.5, 1));
+
+    output_buf[idx] = encode_velocity(vs_pos.xyz / vs_pos.w, vs_pos.xyz / vs_pos.w, push_constants.render_extent);
+}
@@ -0,0 +1,13 @@
+// Compiled by the `frame_constants_match_the_shader_layout` test, which compares the layout
+// of `FrameConstants` and its members with their Rust counterparts.
+
+#include "../inc/frame_constants.hlsl"
+
+[[vk::binding(0)]] RWStructuredBuffer<uint> output_buf;
+
+[numthreads(1, 1, 1)]
+void main() {
+    output_buf[0] = frame_constants.frame_index
+        + asuint(frame_constants.view_constants.sample_offset_clip.x)
+        + uint(frame_constants.gi_cascades[0].scroll_int.x);
+}
@@ -0,0 +1,30 @@
+#include "../inc/ray_origin.hlsl"
+
+[[vk::binding(0)]] RWStructuredBuffer<float> output_buf;
+
+[[vk::push_constant]]
+struct {
+    uint method;
+    float scale;
+} push_constants;
+
+// Offsets the origins of rays leaving points on a tilted plane a million units away from
+// the world origin, and writes their signed distances from the plane. Origins at or below it
+// would hit it again.
+[numthreads(64, 1, 1)]
+void main(uint idx: SV_DispatchThreadID) {
+    const float3 plane_origin = 1e6;
+    const float3 normal = normalize(float3(1, 2, 3));
+    const float3 tangent = normalize(cross(normal, float3(0, 0, 1)));
+    const float3 bitangent = cross(normal, tangent);
+
+    // Rounded to float precision, like the positions of ray hits
+    const float2 uv = float2(idx % 8, idx / 8) * 0.37;
+    const float3 position = plane_origin + tangent * uv.x + bitangent * uv.y;
+
+    const float3 ray_origin = RayOriginOffset::create(push_constants.method, push_constants.scale)
+        .apply(position, normal, normal);
+
+    // The subtraction is exact, as both are this close.
+    output_buf[idx] = dot(ray_origin - plane_origin, normal);
+}
@@ -0,0 +1,27 @@
+#include "../inc/brdf.hlsl"
+
+// Fresnel reflectance of a dielectric under a thin film, as shaded, at a few angles.
+// The last case has no film, for reference.
+[[vk::binding(0)]] RWStructuredBuffer<float4> output_buf;
+
+[[vk::push_constant]]
+struct {
+    float film_ior;
+    float film_thickness;
+} push_constants;
+
+static const uint CASE_COUNT = 6;
+static const float COS_THETA[CASE_COUNT] = { 1.0, 0.8, 0.6, 0.4, 0.2, 1.0 };
+
+[numthreads(CASE_COUNT, 1, 1)]
+void main(uint idx: SV_DispatchThreadID) {
+    SpecularBrdf brdf;
+    brdf.roughness = 0.1;
+    brdf.albedo = 0.04;
+    brdf.anisotropy = 0.0;
+    brdf.iridescence = 1.0;
+    brdf.iridescence_ior = push_constants.film_ior;
+    brdf.iridescence_thickness = idx + 1 < CASE_COUNT ? push_constants.film_thickness : 0.0;
+
+    output_buf[idx] = float4(brdf.eval_fresnel(COS_THETA[idx]), 1.0);
+}
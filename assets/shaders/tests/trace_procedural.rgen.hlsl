@@ -0,0 +1,23 @@
+#include "../inc/pack_unpack.hlsl"
+#include "../inc/rt.hlsl"
+
+[[vk::binding(0)]] RWStructuredBuffer<float4> output_buf;
+[[vk::binding(0, 3)]] RaytracingAccelerationStructure acceleration_structure;
+
+// Traces parallel rays down the -z axis, from a grid spanning [-2, 2] on the plane at z = 4.
+// Writes the geometric normal and distance of each hit, or a `t` of -1 for misses.
+[shader("raygeneration")]
+void main() {
+    const uint2 px = DispatchRaysIndex().xy;
+    const float2 xy = ((px + 0.5) / DispatchRaysDimensions().xy) * 4.0 - 2.0;
+
+    RayDesc ray;
+    ray.Origin = float3(xy, 4.0);
+    ray.Direction = float3(0.0, 0.0, -1.0);
+    ray.TMin = 0.0;
+    ray.TMax = FLT_MAX;
+
+    const GbufferPathVertex hit = GbufferRaytrace::with_ray(ray).trace(acceleration_structure);
+    output_buf[px.y * DispatchRaysDimensions().x + px.x] =
+        hit.is_hit ? float4(hit.geometric_normal, hit.ray_t) : float4(0.0, 0.0, 0.0, -1.0);
+}
@@ -0,0 +1,21 @@
+#ifndef TESTS_TRANSLUCENT_LAYER_COMMON_HLSL
+#define TESTS_TRANSLUCENT_LAYER_COMMON_HLSL
+
+// Must match `TranslucentLayer` in the tests of `transparency.rs`
+struct TranslucentLayer {
+    // x0, y0, x1, y1, in clip space
+    float4 rect;
+    // Not premultiplied
+    float4 color_alpha;
+    // depth_scale, depth_exponent, min_weight, max_weight; see `OitWeightParams`
+    float4 oit_weight;
+    float view_distance;
+};
+
+[[vk::push_constant]] TranslucentLayer push_constants;
+
+struct VsOut {
+    float4 position: SV_Position;
+};
+
+#endif  // TESTS_TRANSLUCENT_LAYER_COMMON_HLSL
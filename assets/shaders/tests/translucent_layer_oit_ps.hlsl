@@ -0,0 +1,6 @@
+#include "../transparency/oit.hlsl"
+#include "translucent_layer_common.hlsl"
+
+OitOutput main(VsOut ps) {
+    return oit_accumulate(push_constants.oit_weight, push_constants.color_alpha.rgb, push_constants.color_alpha.a, push_constants.view_distance);
+}
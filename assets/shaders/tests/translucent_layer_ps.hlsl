@@ -0,0 +1,6 @@
+#include "translucent_layer_common.hlsl"
+
+float4 main(VsOut ps): SV_TARGET0 {
+    // Premultiplied alpha, like `transparency/forward_ps.hlsl`
+    return float4(push_constants.color_alpha.rgb * push_constants.color_alpha.a, push_constants.color_alpha.a);
+}
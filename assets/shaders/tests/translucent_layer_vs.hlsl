@@ -0,0 +1,15 @@
+// Draws the rectangle of a flat translucent layer, for transparency tests.
+
+#include "translucent_layer_common.hlsl"
+
+VsOut main(uint vid: SV_VertexID) {
+    static const float2 CORNERS[6] = {
+        float2(0, 0), float2(1, 0), float2(0, 1),
+        float2(1, 0), float2(1, 1), float2(0, 1)
+    };
+    const float4 rect = push_constants.rect;
+
+    VsOut vsout;
+    vsout.position = float4(lerp(rect.xy, rect.zw, CORNERS[vid]), 0.5, 1.0);
+    return vsout;
+}
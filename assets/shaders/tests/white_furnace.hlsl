@@ -0,0 +1,50 @@
+#include "../inc/brdf.hlsl"
+#include "../inc/brdf_lut.hlsl"
+
+// Per case: what a conductor reflects of uniform, white incident light, without and with
+// the multiple scattering compensation of `SpecularBrdfEnergyPreservation`.
+[[vk::binding(0)]] RWStructuredBuffer<float2> output_buf;
+
+[[vk::push_constant]]
+struct {
+    float f0;
+} push_constants;
+
+static const uint CASE_COUNT = 9;
+static const float ROUGHNESS[3] = { 0.5, 0.75, 1.0 };
+static const float NDOTV[3] = { 0.2, 0.5, 0.9 };
+
+// Evaluates the BRDF over the hemisphere, independently of the sampling the LUT is integrated with.
+[numthreads(CASE_COUNT, 1, 1)]
+void main(uint idx: SV_DispatchThreadID) {
+    const float roughness = ROUGHNESS[idx / 3];
+    const float ndotv = NDOTV[idx % 3];
+    const float3 wo = float3(sqrt(1.0 - ndotv * ndotv), 0.0, ndotv);
+
+    SpecularBrdf brdf;
+    brdf.roughness = roughness;
+    brdf.albedo = push_constants.f0;
+    brdf.anisotropy = 0.0;
+    brdf.iridescence = 0.0;
+    brdf.iridescence_ior = 1.0;
+    brdf.iridescence_thickness = 0.0;
+
+    const float mult = SpecularBrdfEnergyPreservation::from_brdf_ndotv(brdf, ndotv).preintegrated_reflection_mult.x;
+
+    static const uint THETA_STEPS = 256;
+    static const uint PHI_STEPS = 512;
+    const float d_theta = 0.5 * M_PI / THETA_STEPS;
+    const float d_phi = 2.0 * M_PI / PHI_STEPS;
+
+    float sum = 0.0;
+    for (uint i = 0; i < THETA_STEPS; ++i) {
+        const float theta = (i + 0.5) * d_theta;
+        for (uint j = 0; j < PHI_STEPS; ++j) {
+            const float phi = (j + 0.5) * d_phi;
+            const float3 wi = float3(sin(theta) * cos(phi), sin(theta) * sin(phi), cos(theta));
+            sum += brdf.evaluate(wo, wi).value.x * wi.z * sin(theta) * d_theta * d_phi;
+        }
+    }
+
+    output_buf[idx] = float2(sum, sum * mult);
+}
@@ -0,0 +1,17 @@
+// A single triangle in clip space, with the barycentrics of `wireframe/wireframe_vs.hlsl`,
+// for wireframe tests.
+
+struct VsOut {
+    float4 position: SV_Position;
+    [[vk::location(0)]] float3 barycentrics: TEXCOORD0;
+};
+
+VsOut main(uint vid: SV_VertexID) {
+    // Must match `TRIANGLE` in the tests of `wireframe.rs`
+    static const float2 CORNERS[3] = { float2(-0.8, 0.8), float2(0.8, 0.8), float2(0.0, -0.8) };
+
+    VsOut vsout;
+    vsout.position = float4(CORNERS[vid], 0.5, 1.0);
+    vsout.barycentrics = float3(vid == 0, vid == 1, vid == 2);
+    return vsout;
+}
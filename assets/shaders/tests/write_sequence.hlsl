@@ -0,0 +1,13 @@
+// Writes `frame * 1000 + i` to element `i`, for readback tests.
+
+[[vk::push_constant]]
+struct {
+    uint frame;
+} push_constants;
+
+[[vk::binding(0)]] RWStructuredBuffer<uint> output_buf;
+
+[numthreads(64, 1, 1)]
+void main(uint idx: SV_DispatchThreadID) {
+    output_buf[idx] = push_constants.frame * 1000 + idx;
+}
@@ -0,0 +1,24 @@
+// Must match `GLYPH_WIDTH` and `GLYPH_HEIGHT` in `text.rs`
+static const uint GLYPH_WIDTH = 5;
+static const uint GLYPH_HEIGHT = 7;
+
+// Must match `GlyphQuad` in `text.rs`
+struct GlyphQuad {
+    float2 position;
+    float scale;
+    uint glyph;
+    float4 color;
+};
+
+[[vk::push_constant]]
+struct {
+    float4 output_tex_size;
+} push_constants;
+
+struct VsOut {
+	float4 position: SV_Position;
+    // Position within the glyph, in font pixels
+    [[vk::location(0)]] float2 texel: TEXCOORD0;
+    [[vk::location(1)]] nointerpolation float4 color: COLOR0;
+    [[vk::location(2)]] nointerpolation uint glyph: TEXCOORD1;
+};
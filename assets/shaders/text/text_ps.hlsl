@@ -0,0 +1,31 @@
+#include "text_common.hlsl"
+
+// One bit per texel, row by row
+[[vk::binding(1)]] StructuredBuffer<uint2> font_dyn;
+
+// Must match `SHADOW_OPACITY` in `text.rs`
+static const float SHADOW_OPACITY = 0.75;
+
+bool texel_is_set(uint glyph, int2 texel) {
+    if (any(texel < 0) || texel.x >= GLYPH_WIDTH || texel.y >= GLYPH_HEIGHT) {
+        return false;
+    }
+
+    const uint bit = texel.y * GLYPH_WIDTH + texel.x;
+    const uint2 bits = font_dyn[glyph];
+    return ((bit < 32 ? bits.x : bits.y) >> (bit % 32)) & 1;
+}
+
+// Premultiplied color of the overlay; a dark shadow offset by a font pixel keeps the text legible.
+float4 main(VsOut ps): SV_TARGET {
+    const int2 texel = int2(floor(ps.texel));
+
+    if (texel_is_set(ps.glyph, texel)) {
+        return float4(ps.color.rgb * ps.color.a, ps.color.a);
+    } else if (texel_is_set(ps.glyph, texel - 1)) {
+        return float4(0, 0, 0, ps.color.a * SHADOW_OPACITY);
+    }
+
+    discard;
+    return 0;
+}
@@ -0,0 +1,23 @@
+#include "text_common.hlsl"
+
+[[vk::binding(0)]] StructuredBuffer<GlyphQuad> glyphs_dyn;
+
+// Two triangles per glyph, one pixel larger than it to fit the shadow.
+VsOut main(uint vid: SV_VertexID) {
+    const GlyphQuad quad = glyphs_dyn[vid / 6];
+
+    static const float2 CORNERS[6] = {
+        float2(0, 0), float2(1, 0), float2(0, 1),
+        float2(1, 0), float2(1, 1), float2(0, 1),
+    };
+    const float2 texel = CORNERS[vid % 6] * float2(GLYPH_WIDTH + 1, GLYPH_HEIGHT + 1);
+    const float2 px = quad.position + texel * quad.scale;
+
+    VsOut vsout;
+    vsout.position = float4(px * push_constants.output_tex_size.zw * 2.0 - 1.0, 0.0, 1.0);
+    vsout.texel = texel;
+    vsout.color = quad.color;
+    vsout.glyph = quad.glyph;
+
+    return vsout;
+}
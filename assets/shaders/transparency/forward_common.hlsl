@@ -0,0 +1,37 @@
+#ifndef TRANSPARENCY_FORWARD_COMMON_HLSL
+#define TRANSPARENCY_FORWARD_COMMON_HLSL
+
+// Matches `TransparentPushConstants` in `transparency.rs`
+struct TransparentPushConstants {
+    float4 tint_opacity;
+    // depth_scale, depth_exponent, min_weight, max_weight; see `OitWeightParams`
+    float4 oit_weight;
+    float ior;
+    float refraction_strength;
+    float soft_intersection_distance;
+    uint draw_index;
+    uint mesh_index;
+};
+
+[[vk::push_constant]] TransparentPushConstants push_constants;
+
+struct InstanceTransform {
+    row_major float3x4 current;
+    row_major float3x4 previous;
+};
+
+[[vk::binding(0)]] StructuredBuffer<InstanceTransform> instance_transforms_dyn;
+
+struct VsOut {
+    float4 position: SV_Position;
+    [[vk::location(0)]] float4 color: TEXCOORD0;
+    [[vk::location(1)]] float2 uv: TEXCOORD1;
+    [[vk::location(2)]] float3 normal: TEXCOORD2;
+    [[vk::location(3)]] nointerpolation uint material_id: TEXCOORD3;
+    [[vk::location(4)]] float3 tangent: TEXCOORD4;
+    [[vk::location(5)]] float3 bitangent: TEXCOORD5;
+    [[vk::location(6)]] float3 ws_pos: TEXCOORD6;
+    [[vk::location(7)]] float3 vs_pos: TEXCOORD7;
+};
+
+#endif  // TRANSPARENCY_FORWARD_COMMON_HLSL
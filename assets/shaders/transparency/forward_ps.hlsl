@@ -0,0 +1,8 @@
+#include "shading.hlsl"
+
+float4 main(VsOut ps): SV_TARGET0 {
+    const TransparentShading shading = shade_transparent(ps);
+
+    // Premultiplied alpha
+    return float4(shading.color * shading.alpha, shading.alpha);
+}
@@ -0,0 +1,42 @@
+#include "../inc/frame_constants.hlsl"
+#include "../inc/mesh.hlsl"
+#include "../inc/bindless.hlsl"
+#include "forward_common.hlsl"
+
+VsOut main(uint vid: SV_VertexID) {
+    VsOut vsout;
+
+    const Mesh mesh = meshes[push_constants.mesh_index];
+
+    VertexPacked vp = VertexPacked(asfloat(vertices.Load4(vid * sizeof(float4) + mesh.vertex_core_offset)));
+    Vertex v = unpack_vertex(vp);
+
+    float4 v_color =
+        mesh.vertex_aux_offset != 0
+            ? asfloat(vertices.Load4(vid * sizeof(float4) + mesh.vertex_aux_offset))
+            : 1.0.xxxx;
+
+    float4 v_tangent_packed =
+        mesh.vertex_tangent_offset != 0
+            ? asfloat(vertices.Load4(vid * sizeof(float4) + mesh.vertex_tangent_offset))
+            : float4(1, 0, 0, 1);
+
+    float2 uv = asfloat(vertices.Load2(vid * sizeof(float2) + mesh.vertex_uv_offset));
+    uint material_id = vertices.Load(vid * sizeof(uint) + mesh.vertex_mat_offset);
+
+    float3 ws_pos = mul(instance_transforms_dyn[push_constants.draw_index].current, float4(v.position, 1.0));
+    float4 vs_pos = mul(frame_constants.view_constants.world_to_view, float4(ws_pos, 1.0));
+    float4 cs_pos = mul(frame_constants.view_constants.view_to_sample, vs_pos);
+
+    vsout.position = cs_pos;
+    vsout.color = v_color;
+    vsout.uv = uv;
+    vsout.normal = v.normal;
+    vsout.material_id = material_id;
+    vsout.tangent = v_tangent_packed.xyz;
+    vsout.bitangent = normalize(cross(v.normal, vsout.tangent) * v_tangent_packed.w);
+    vsout.ws_pos = ws_pos;
+    vsout.vs_pos = vs_pos.xyz / vs_pos.w;
+
+    return vsout;
+}
@@ -0,0 +1,28 @@
+#ifndef TRANSPARENCY_OIT_HLSL
+#define TRANSPARENCY_OIT_HLSL
+
+// See `OitWeightParams` in `transparency.rs`. `params` holds depth_scale, depth_exponent, min_weight, max_weight.
+float oit_weight(float4 params, float alpha, float view_distance) {
+    const float depth_term = pow(params.x / max(view_distance, 1e-5), params.y);
+    return alpha * clamp(depth_term, params.z, params.w);
+}
+
+struct OitOutput {
+    float4 accum: SV_TARGET0;
+    float optical_depth: SV_TARGET1;
+};
+
+// `color` is not premultiplied.
+OitOutput oit_accumulate(float4 params, float3 color, float alpha, float view_distance) {
+    const float weight = oit_weight(params, alpha, view_distance);
+
+    OitOutput res;
+    res.accum = float4(color * weight, weight);
+
+    // Revealage is the product of `1 - alpha` over all layers. Accumulating its negative log instead
+    // lets both targets use additive blending; `oit_resolve.hlsl` takes `exp(-optical_depth)`.
+    res.optical_depth = -log(1.0 - min(alpha, 0.999));
+    return res;
+}
+
+#endif  // TRANSPARENCY_OIT_HLSL
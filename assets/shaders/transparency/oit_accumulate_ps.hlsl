@@ -0,0 +1,7 @@
+#include "shading.hlsl"
+#include "oit.hlsl"
+
+OitOutput main(VsOut ps) {
+    const TransparentShading shading = shade_transparent(ps);
+    return oit_accumulate(push_constants.oit_weight, shading.color, shading.alpha, shading.view_distance);
+}
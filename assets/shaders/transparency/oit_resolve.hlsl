@@ -0,0 +1,18 @@
+[[vk::binding(0)]] Texture2D<float4> accum_tex;
+[[vk::binding(1)]] Texture2D<float> optical_depth_tex;
+[[vk::binding(2)]] RWTexture2D<float4> output_tex;
+
+[numthreads(8, 8, 1)]
+void main(uint2 px: SV_DispatchThreadID) {
+    const float4 accum = accum_tex[px];
+    const float revealage = exp(-optical_depth_tex[px]);
+
+    // Nothing transparent covers this pixel
+    if (revealage == 1.0) {
+        return;
+    }
+
+    const float3 average_color = accum.rgb / max(accum.a, 1e-5);
+    const float4 dst = output_tex[px];
+    output_tex[px] = float4(dst.rgb * revealage + average_color * (1.0 - revealage), dst.a);
+}
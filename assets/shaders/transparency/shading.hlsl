@@ -0,0 +1,107 @@
+#ifndef TRANSPARENCY_SHADING_HLSL
+#define TRANSPARENCY_SHADING_HLSL
+
+#include "../inc/samplers.hlsl"
+#include "../inc/frame_constants.hlsl"
+#include "../inc/mesh.hlsl"
+#include "../inc/bindless.hlsl"
+#include "../inc/math_const.hlsl"
+#include "../inc/atmosphere.hlsl"
+#include "../inc/sun.hlsl"
+#include "forward_common.hlsl"
+
+[[vk::binding(1)]] Texture2D<float4> scene_color_tex;
+[[vk::binding(2)]] Texture2D<float> scene_depth_tex;
+[[vk::binding(3)]] Texture2D<float4> reflection_tex;
+[[vk::binding(4)]] TextureCube<float4> sky_cube_tex;
+
+// Sharp highlight of the sun on smooth glass and water
+static const float SUN_SPECULAR_EXPONENT = 1024.0;
+
+struct TransparentShading {
+    // Not premultiplied
+    float3 color;
+    float alpha;
+    float view_distance;
+};
+
+TransparentShading shade_transparent(VsOut ps) {
+    float2 output_size;
+    scene_color_tex.GetDimensions(output_size.x, output_size.y);
+    const int2 px = int2(ps.position.xy);
+    const float2 uv = ps.position.xy / output_size;
+
+    Mesh mesh = meshes[push_constants.mesh_index];
+    MeshMaterial material = vertices.Load<MeshMaterial>(mesh.mat_data_offset + ps.material_id * sizeof(MeshMaterial));
+
+    // The albedo map and base color filter the light seen through the surface, and their alpha scales coverage.
+    float2 albedo_uv = transform_material_uv(material, ps.uv, 0);
+    Texture2D albedo_tex = bindless_textures[NonUniformResourceIndex(material.albedo_map)];
+    const float4 albedo = albedo_tex.SampleBias(sampler_llr, albedo_uv, -0.5) * float4(material.base_color_mult) * ps.color;
+    const float3 tint = push_constants.tint_opacity.rgb * albedo.rgb;
+
+    Texture2D normal_tex = bindless_textures[NonUniformResourceIndex(material.normal_map)];
+    const float3 ts_normal = normal_tex.SampleBias(sampler_llr, ps.uv, -0.5).xyz * 2.0 - 1.0;
+
+    float3 normal_os = ps.normal;
+    if (dot(ps.bitangent, ps.bitangent) > 0.0) {
+        float3x3 tbn = float3x3(ps.tangent, ps.bitangent, ps.normal);
+        normal_os = mul(ts_normal, tbn);
+    }
+    float3 normal = normalize(mul(instance_transforms_dyn[push_constants.draw_index].current, float4(normal_os, 0.0)));
+
+    // Towards the eye. Transparent surfaces are double-sided.
+    const float3 wo = normalize(get_eye_position() - ps.ws_pos);
+    if (dot(normal, wo) < 0.0) {
+        normal = -normal;
+    }
+
+    const float n_dot_v = saturate(dot(normal, wo));
+    const float f0 = pow((push_constants.ior - 1.0) / (push_constants.ior + 1.0), 2.0);
+    const float fresnel = f0 + (1.0 - f0) * pow(1.0 - n_dot_v, 5.0);
+
+    // Refraction: offset the lookup into the opaque scene by the normal's tilt,
+    // unless that would pick up something in front of the surface.
+    const float3 normal_vs = direction_world_to_view(normal);
+    float2 refraction_uv = uv + float2(normal_vs.x, -normal_vs.y) * push_constants.refraction_strength;
+    if (any(refraction_uv != saturate(refraction_uv))
+        || scene_depth_tex.SampleLevel(sampler_nnc, refraction_uv, 0) > ps.position.z) {
+        refraction_uv = uv;
+    }
+    const float3 refracted = scene_color_tex.SampleLevel(sampler_lnc, refraction_uv, 0).rgb * tint;
+
+    // Specular: re-use the reflections of the opaque surface behind, or the sky where there is none.
+    const float opaque_depth = scene_depth_tex[px];
+    float3 reflected;
+    if (opaque_depth == 0.0) {
+        reflected = sky_cube_tex.SampleLevel(sampler_llr, reflect(-wo, normal), 0).rgb;
+    } else {
+        reflected = reflection_tex.SampleLevel(sampler_lnc, uv, 0).rgb;
+    }
+
+    const float3 wi = normalize(SUN_DIRECTION);
+    const float3 h = normalize(wi + wo);
+    const float sun_specular =
+        (SUN_SPECULAR_EXPONENT + 8.0) / (8.0 * M_PI)
+        * pow(saturate(dot(normal, h)), SUN_SPECULAR_EXPONENT)
+        * saturate(dot(normal, wi))
+        * fresnel;
+
+    const float3 color = lerp(refracted, reflected, fresnel) + SUN_COLOR * sun_specular;
+
+    // Fade out where the surface meets opaque geometry, to hide the intersection line.
+    float soft_fade = 1.0;
+    if (opaque_depth != 0.0 && push_constants.soft_intersection_distance > 0.0) {
+        const float opaque_distance = -depth_to_view_z(opaque_depth);
+        const float surface_distance = -ps.vs_pos.z;
+        soft_fade = saturate((opaque_distance - surface_distance) / push_constants.soft_intersection_distance);
+    }
+
+    TransparentShading res;
+    res.color = color;
+    res.alpha = push_constants.tint_opacity.a * albedo.a * soft_fade;
+    res.view_distance = -ps.vs_pos.z;
+    return res;
+}
+
+#endif  // TRANSPARENCY_SHADING_HLSL
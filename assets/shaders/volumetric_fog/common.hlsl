@@ -0,0 +1,47 @@
+#ifndef VOLUMETRIC_FOG_COMMON_HLSL
+#define VOLUMETRIC_FOG_COMMON_HLSL
+
+#include "../inc/frame_constants.hlsl"
+#include "../inc/math_const.hlsl"
+#include "../inc/uv.hlsl"
+
+// Must match `VolumetricFogConstants` in `volumetric_fog.rs`.
+struct VolumetricFogConstants {
+    uint4 froxel_dims;
+
+    // rgb: scattering coefficient; a: extinction coefficient (scattering + absorption)
+    float4 scattering_extinction;
+
+    float near_distance;
+    float max_distance;
+    float anisotropy;
+    uint reset_history;
+};
+
+// Froxel slices are distributed exponentially in view depth, so that resolution
+// is concentrated close to the camera.
+float fog_slice_to_view_depth(VolumetricFogConstants fog, float w) {
+    return fog.near_distance * pow(fog.max_distance / fog.near_distance, w);
+}
+
+float fog_view_depth_to_slice(VolumetricFogConstants fog, float view_depth) {
+    return log(max(view_depth, fog.near_distance) / fog.near_distance)
+        / log(fog.max_distance / fog.near_distance);
+}
+
+// View-space position of a point within the froxel volume; `uvw` is in [0, 1].
+float3 fog_uvw_to_vs(VolumetricFogConstants fog, float3 uvw) {
+    const float3 dir_vs = normalize(mul(
+        frame_constants.view_constants.clip_to_view,
+        float4(uv_to_cs(uvw.xy), 0.0, 1.0)
+    ).xyz);
+
+    return dir_vs * (fog_slice_to_view_depth(fog, uvw.z) / -dir_vs.z);
+}
+
+float fog_henyey_greenstein(float cos_theta, float g) {
+    const float g2 = g * g;
+    return (1.0 - g2) / (4.0 * M_PI * pow(max(1e-5, 1.0 + g2 - 2.0 * g * cos_theta), 1.5));
+}
+
+#endif  // VOLUMETRIC_FOG_COMMON_HLSL
@@ -0,0 +1,40 @@
+#include "../inc/samplers.hlsl"
+#include "common.hlsl"
+
+[[vk::binding(0)]] Texture2D<float> depth_tex;
+[[vk::binding(1)]] Texture3D<float4> integrated_tex;
+[[vk::binding(2)]] RWTexture2D<float4> output_tex;
+[[vk::binding(3)]] cbuffer _ {
+    float4 output_tex_size;
+    VolumetricFogConstants fog_constants;
+};
+
+// Attenuates the lit image by the fog between the eye and each pixel, and adds light scattered towards the eye.
+[numthreads(8, 8, 1)]
+void main(uint2 px: SV_DispatchThreadID) {
+    const VolumetricFogConstants fog = fog_constants;
+    const float2 uv = get_uv(px, output_tex_size);
+    const float depth = depth_tex[px];
+
+    float view_depth = fog.max_distance;
+    if (depth != 0.0) {
+        const ViewRayContext view_ray_context = ViewRayContext::from_uv_and_depth(uv, depth);
+        view_depth = min(view_depth, -view_ray_context.ray_hit_vs().z);
+    }
+
+    // Integrated values are stored at the far end of each froxel, which is where texel centers map to.
+    const float slice = fog_view_depth_to_slice(fog, view_depth) * fog.froxel_dims.z;
+
+    float4 fog_value;
+    if (slice >= 1.0) {
+        fog_value = integrated_tex.SampleLevel(sampler_lnc, float3(uv, (slice - 0.5) / fog.froxel_dims.z), 0);
+    } else {
+        // Within the first slice; fade in from no fog at the eye.
+        const float4 first_slice = integrated_tex.SampleLevel(sampler_lnc, float3(uv, 0.5 / fog.froxel_dims.z), 0);
+        const float t = saturate(view_depth / fog_slice_to_view_depth(fog, 1.0 / fog.froxel_dims.z));
+        fog_value = lerp(float4(0.0.xxx, 1.0), first_slice, t);
+    }
+
+    const float4 color = output_tex[px];
+    output_tex[px] = float4(color.rgb * fog_value.a + fog_value.rgb, color.a);
+}
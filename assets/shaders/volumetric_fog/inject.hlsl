@@ -0,0 +1,102 @@
+#include "../inc/frame_constants.hlsl"
+#include "../inc/samplers.hlsl"
+#include "../inc/hash.hlsl"
+#include "../inc/uv.hlsl"
+#include "../inc/atmosphere.hlsl"
+#include "../inc/sun.hlsl"
+#include "common.hlsl"
+
+[[vk::binding(0)]] Texture3D<float4> history_tex;
+[[vk::binding(1)]] TextureCube<float4> sky_cube_tex;
+[[vk::binding(2)]] Texture2D<float> depth_tex;
+[[vk::binding(3)]] Texture2D<float> shadow_mask_tex;
+[[vk::binding(4)]] RWTexture3D<float4> output_tex;
+[[vk::binding(5)]] cbuffer _ {
+    VolumetricFogConstants fog_constants;
+};
+
+// Fraction of the reprojected history kept each frame
+#define HISTORY_BLEND 0.9
+
+#define SUN_MARCH_STEPS 16
+
+// Sun visibility at `pos_ws`, from the screen-space sun shadow mask.
+//
+// Sunlight passing through `pos_ws` goes on to light the surface it reaches next, so that surface
+// is lit exactly when `pos_ws` is, barring occluders between the two. The sun's ray is followed
+// in screen space until it goes behind the depth buffer; without such a surface on screen,
+// the one behind `pos_ws` along the view ray stands in.
+float sun_visibility(VolumetricFogConstants fog, float3 pos_ws, float2 fallback_uv) {
+    for (uint step_idx = 1; step_idx <= SUN_MARCH_STEPS; ++step_idx) {
+        const float t = float(step_idx) / SUN_MARCH_STEPS;
+        const float3 sample_ws = pos_ws - SUN_DIRECTION * (t * t * fog.max_distance);
+
+        const float4 sample_vs = mul(frame_constants.view_constants.world_to_view, float4(sample_ws, 1));
+        if (sample_vs.z >= 0.0) {
+            break;
+        }
+
+        const float4 sample_cs = mul(frame_constants.view_constants.view_to_sample, sample_vs);
+        const float3 sample_ndc = sample_cs.xyz / sample_cs.w;
+        const float2 sample_uv = cs_to_uv(sample_ndc.xy);
+        if (any(sample_uv < 0.0) || any(sample_uv > 1.0)) {
+            break;
+        }
+
+        // Reverse Z: the sample is behind the surface when its depth is lower.
+        const float surface_depth = depth_tex.SampleLevel(sampler_nnc, sample_uv, 0);
+        if (sample_ndc.z <= surface_depth) {
+            return shadow_mask_tex.SampleLevel(sampler_nnc, sample_uv, 0);
+        }
+    }
+
+    return shadow_mask_tex.SampleLevel(sampler_lnc, fallback_uv, 0);
+}
+
+// One thread per froxel. Evaluates in-scattered light at a jittered position within the froxel,
+// and accumulates it over time. Outputs in-scattered radiance per unit length, and extinction.
+[numthreads(8, 8, 1)]
+void main(uint3 froxel: SV_DispatchThreadID) {
+    const VolumetricFogConstants fog = fog_constants;
+    if (any(froxel >= fog.froxel_dims.xyz)) {
+        return;
+    }
+
+    uint rng = hash4(uint4(froxel, frame_constants.frame_index));
+    const float3 jitter = float3(0.5, 0.5, uint_to_u01_float(hash1_mut(rng)));
+    const float3 uvw = (froxel + jitter) / float3(fog.froxel_dims.xyz);
+
+    const float3 pos_vs = fog_uvw_to_vs(fog, uvw);
+    const float3 pos_ws = mul(frame_constants.view_constants.view_to_world, float4(pos_vs, 1)).xyz;
+    const float3 eye_ws = mul(frame_constants.view_constants.view_to_world, float4(0, 0, 0, 1)).xyz;
+    const float3 view_dir_ws = normalize(pos_ws - eye_ws);
+
+    float3 in_scattering =
+        SUN_COLOR
+        * sun_visibility(fog, pos_ws, uvw.xy)
+        * fog_henyey_greenstein(dot(view_dir_ws, SUN_DIRECTION), fog.anisotropy);
+
+    // Isotropic ambient from the sky
+    in_scattering += sky_cube_tex.SampleLevel(sampler_llr, float3(0, 1, 0), 0).rgb / (4.0 * M_PI);
+
+    float4 value = float4(in_scattering * fog.scattering_extinction.rgb, fog.scattering_extinction.a);
+
+    // Reproject the history, using the froxel's center
+    if (fog.reset_history == 0) {
+        const float3 center_vs = fog_uvw_to_vs(fog, (froxel + 0.5) / float3(fog.froxel_dims.xyz));
+        const float3 center_ws = mul(frame_constants.view_constants.view_to_world, float4(center_vs, 1)).xyz;
+        const float4 prev_vs = mul(frame_constants.view_constants.prev_world_to_prev_view, float4(center_ws, 1));
+        const float4 prev_cs = mul(frame_constants.view_constants.prev_view_to_prev_clip, prev_vs);
+        const float3 prev_uvw = float3(
+            cs_to_uv(prev_cs.xy / prev_cs.w),
+            fog_view_depth_to_slice(fog, -prev_vs.z / prev_vs.w)
+        );
+
+        if (all(prev_uvw >= 0.0) && all(prev_uvw <= 1.0) && prev_vs.z < 0.0) {
+            const float4 history = history_tex.SampleLevel(sampler_lnc, prev_uvw, 0);
+            value = lerp(value, history, HISTORY_BLEND);
+        }
+    }
+
+    output_tex[froxel] = value;
+}
@@ -0,0 +1,40 @@
+#include "common.hlsl"
+
+[[vk::binding(0)]] Texture3D<float4> scattering_tex;
+[[vk::binding(1)]] RWTexture3D<float4> output_tex;
+[[vk::binding(2)]] cbuffer _ {
+    VolumetricFogConstants fog_constants;
+};
+
+// One thread per froxel column. Marches front to back, and stores the in-scattered light
+// and transmittance from the eye up to the far end of each froxel.
+[numthreads(8, 8, 1)]
+void main(uint2 px: SV_DispatchThreadID) {
+    const VolumetricFogConstants fog = fog_constants;
+    if (any(px >= fog.froxel_dims.xy)) {
+        return;
+    }
+
+    float3 accum_scattering = 0.0.xxx;
+    float transmittance = 1.0;
+    float prev_depth = 0.0;
+
+    for (uint z = 0; z < fog.froxel_dims.z; ++z) {
+        const float depth = fog_slice_to_view_depth(fog, float(z + 1) / fog.froxel_dims.z);
+        const float step_length = depth - prev_depth;
+        prev_depth = depth;
+
+        const float4 scattering_extinction = scattering_tex[uint3(px, z)];
+        const float extinction = max(1e-6, scattering_extinction.a);
+        const float step_transmittance = exp(-extinction * step_length);
+
+        // Energy-conserving integration of in-scattering over the step.
+        // Hillaire, "Physically Based and Unified Volumetric Rendering in Frostbite" (2015)
+        const float3 step_scattering = scattering_extinction.rgb * (1.0 - step_transmittance) / extinction;
+
+        accum_scattering += transmittance * step_scattering;
+        transmittance *= step_transmittance;
+
+        output_tex[uint3(px, z)] = float4(accum_scattering, transmittance);
+    }
+}
@@ -0,0 +1,2 @@
+#define WIREFRAME_BARYCENTRIC 1
+#include "wireframe_ps.hlsl"
@@ -0,0 +1,29 @@
+// Must match `WireframePushConstants` in `wireframe.rs`
+[[vk::push_constant]]
+struct {
+    float4 color_opacity;
+    uint draw_index;
+    uint mesh_index;
+} push_constants;
+
+struct PsIn {
+    [[vk::location(0)]] float3 barycentrics: TEXCOORD0;
+};
+
+// Premultiplied color of the overlay
+float4 main(PsIn ps): SV_TARGET {
+#if WIREFRAME_BARYCENTRIC
+    // Filled triangles; keep a pixel-wide band along their edges.
+    const float3 edge_distance_px = ps.barycentrics / max(1e-8, fwidth(ps.barycentrics));
+    const float coverage = saturate(1.5 - min(edge_distance_px.x, min(edge_distance_px.y, edge_distance_px.z)));
+
+    if (coverage <= 0) {
+        discard;
+    }
+#else
+    const float coverage = 1.0;
+#endif
+
+    const float alpha = push_constants.color_opacity.a * coverage;
+    return float4(push_constants.color_opacity.rgb * alpha, alpha);
+}
@@ -0,0 +1,43 @@
+#include "../inc/frame_constants.hlsl"
+#include "../inc/mesh.hlsl"
+#include "../inc/bindless.hlsl"
+
+// Must match `WireframePushConstants` in `wireframe.rs`
+[[vk::push_constant]]
+struct {
+    float4 color_opacity;
+    uint draw_index;
+    uint mesh_index;
+} push_constants;
+
+struct InstanceTransform {
+    row_major float3x4 current;
+    row_major float3x4 previous;
+};
+
+[[vk::binding(0)]] StructuredBuffer<InstanceTransform> instance_transforms_dyn;
+
+struct VsOut {
+	float4 position: SV_Position;
+    [[vk::location(0)]] float3 barycentrics: TEXCOORD0;
+};
+
+// Drawn without an index buffer, so that every triangle gets its own corners for the barycentrics.
+VsOut main(uint vid: SV_VertexID) {
+    const Mesh mesh = meshes[push_constants.mesh_index];
+    const uint vertex_index = vertices.Load(mesh.index_offset + vid * sizeof(uint));
+
+    VertexPacked vp = VertexPacked(asfloat(vertices.Load4(vertex_index * sizeof(float4) + mesh.vertex_core_offset)));
+    Vertex v = unpack_vertex(vp);
+
+    float3 ws_pos = mul(instance_transforms_dyn[push_constants.draw_index].current, float4(v.position, 1.0));
+    float4 vs_pos = mul(frame_constants.view_constants.world_to_view, float4(ws_pos, 1.0));
+
+    const uint corner = vid % 3;
+
+    VsOut vsout;
+    vsout.position = mul(frame_constants.view_constants.view_to_sample, vs_pos);
+    vsout.barycentrics = float3(corner == 0, corner == 1, corner == 2);
+
+    return vsout;
+}
@@ -13,4 +13,11 @@ $BAKE --scene "assets/meshes/dp3_homework_4/scene.gltf" --scale 0.05 -o mini_bat
 $BAKE --scene "assets/meshes/painting_xyz_homework/scene.gltf" --scale 0.0025 -o painting_xyz_homework
 $BAKE --scene "assets/meshes/conference/scene.gltf" --scale 1.0 -o conference
 $BAKE --scene "assets/meshes/roughness-scale/scene.gltf" --scale 0.5 -o roughness-scale
-$BAKE --scene "assets/meshes/emissive/triangle.glb" --scale 0.333 -o emissive-triangle
\ No newline at end of file
+$BAKE --scene "assets/meshes/emissive/triangle.glb" --scale 0.333 -o emissive-triangle
+$BAKE --scene "assets/meshes/cutout_leaf/scene.gltf" --scale 1.0 -o cutout_leaf
+$BAKE --scene "assets/meshes/normal_mapped_panel/scene.gltf" --scale 1.0 -o normal_mapped_panel
+$BAKE --scene "assets/meshes/flat_panel/scene.gltf" --scale 1.0 -o flat_panel
+$BAKE --scene "assets/meshes/parallax_bricks/scene.gltf" --scale 1.0 -o parallax_bricks
+$BAKE --scene "assets/meshes/brushed_metal/scene.gltf" --scale 1.0 -o brushed_metal
+$BAKE --scene "assets/meshes/wax_spheres/scene.gltf" --scale 1.0 -o wax_spheres
+$BAKE --scene "assets/meshes/iridescent_spheres/scene.gltf" --scale 1.0 -o iridescent_spheres
\ No newline at end of file
@@ -0,0 +1,337 @@
+// Renders a scene without a window at fixed settings, and reports its frame timings as JSON,
+// for tracking performance across commits.
+//
+// Usage: `cargo run --bin bench --release -- --scene car [--frames 200] [--warmup 30] [-o bench.json]`
+//
+// The report is a `BenchReport`; see the README for a sample. Timings are in milliseconds,
+// summarized by `Stats` over the measured frames.
+
+use std::{
+    ffi::CStr,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use glam::{Quat, Vec3};
+use kajiya::{
+    backend::{gpu_profiler, HeadlessRenderBackend, HeadlessRenderBackendConfig},
+    camera::*,
+    frame_desc::{FrameTime, WorldFrameDesc},
+    rg,
+    world_renderer::WorldRenderer,
+};
+use serde::Serialize;
+use structopt::StructOpt;
+use turbosloth::*;
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "bench", about = "Kajiya frame timing benchmark.")]
+struct Opt {
+    /// Loaded from `assets/scenes/<scene>.ron`
+    #[structopt(long, default_value = "car")]
+    scene: String,
+
+    #[structopt(long, default_value = "1280")]
+    width: u32,
+
+    #[structopt(long, default_value = "720")]
+    height: u32,
+
+    /// The number of frames measured
+    #[structopt(long, default_value = "200")]
+    frames: u32,
+
+    /// The number of frames rendered before measuring, for temporal effects to settle
+    #[structopt(long, default_value = "30")]
+    warmup: u32,
+
+    #[structopt(long, default_value = "2")]
+    frames_in_flight: usize,
+
+    /// Recorded in the report, e.g. a commit hash
+    #[structopt(long)]
+    label: Option<String>,
+
+    /// Where to write the report; standard output by default
+    #[structopt(short = "o", parse(from_os_str))]
+    output: Option<PathBuf>,
+}
+
+#[derive(Serialize)]
+struct BenchReport {
+    label: Option<String>,
+    scene: String,
+    device: String,
+    extent: [u32; 2],
+    frames_in_flight: usize,
+    warmup_frames: u32,
+    frames: u32,
+
+    /// Wall time from the start of one frame to the start of the next
+    frame_ms: Stats,
+
+    /// CPU time spent preparing the render graph of a frame
+    cpu_ms: Stats,
+
+    /// Sum of the passes of a frame
+    gpu_ms: Stats,
+
+    /// GPU time of each pass, in the order they first ran. Passes of the same name are summed;
+    /// frames without a pass count as zero for it.
+    passes: Vec<PassReport>,
+}
+
+#[derive(Serialize)]
+struct PassReport {
+    name: String,
+    ms: Stats,
+}
+
+/// Summary of a non-empty set of samples. Percentiles are nearest-rank.
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+struct Stats {
+    mean: f64,
+    min: f64,
+    p50: f64,
+    p95: f64,
+    p99: f64,
+    max: f64,
+}
+
+impl Stats {
+    fn from_samples(samples: &[f64]) -> Self {
+        assert!(!samples.is_empty());
+
+        let mut sorted = samples.to_vec();
+        sorted.sort_by(|a, b| a.partial_cmp(b).expect("timings can't be NaN"));
+
+        let percentile = |p: f64| {
+            let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+            sorted[rank.clamp(1, sorted.len()) - 1]
+        };
+
+        Self {
+            mean: sorted.iter().sum::<f64>() / sorted.len() as f64,
+            min: sorted[0],
+            p50: percentile(50.0),
+            p95: percentile(95.0),
+            p99: percentile(99.0),
+            max: *sorted.last().unwrap(),
+        }
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    kajiya::logging::set_up_logging(log::LevelFilter::Warn)?;
+
+    let opt = Opt::from_args();
+    anyhow::ensure!(opt.frames > 0, "Need at least one frame to measure");
+
+    let backend = HeadlessRenderBackend::new(HeadlessRenderBackendConfig {
+        frames_in_flight: opt.frames_in_flight,
+        ..Default::default()
+    })?;
+    let device_name = unsafe {
+        CStr::from_ptr(
+            backend
+                .device
+                .physical_device()
+                .properties
+                .device_name
+                .as_ptr(),
+        )
+    }
+    .to_string_lossy()
+    .into_owned();
+
+    let extent = [opt.width, opt.height];
+    let lazy_cache = LazyCache::create();
+    let mut world_renderer = WorldRenderer::new(extent, extent, &backend.device, &lazy_cache)?;
+    let scene = world_renderer.load_scene_desc(format!("assets/scenes/{}.ron", opt.scene))?;
+
+    let lens = CameraLens {
+        aspect_ratio: extent[0] as f32 / extent[1] as f32,
+        ..Default::default()
+    };
+    let (position, rotation, lens) = match &scene.camera {
+        Some(camera) => (
+            camera.position.into(),
+            camera.rotation(),
+            CameraLens {
+                vertical_fov: camera.vertical_fov.unwrap_or(lens.vertical_fov),
+                ..lens
+            },
+        ),
+        None => (Vec3::new(0.0, 1.0, 8.0), Quat::IDENTITY, lens),
+    };
+
+    let mut frame_desc = WorldFrameDesc {
+        camera_matrices: (position, rotation).through(&lens),
+        render_extent: extent,
+        sun_direction: scene
+            .sun_direction
+            .unwrap_or_else(|| Vec3::new(4.0, 1.0, 1.0).normalize()),
+        time: FrameTime::fixed_step(0, 1.0 / 60.0),
+    };
+
+    let mut rg_renderer = rg::renderer::Renderer::new(&backend.device)?;
+
+    // The first frame registers the pipelines of its passes. Finish building any it left,
+    // so that compiles don't show up in the measurements.
+    render_frame(&mut rg_renderer, &mut world_renderer, &frame_desc)?;
+    rg_renderer
+        .compile_all_pipelines(|done, total| log::info!("Compiled {}/{} pipelines", done, total))
+        .map_err(|errors| anyhow::anyhow!("Failed to compile pipelines: {:?}", errors))?;
+
+    for frame_idx in 1..=opt.warmup {
+        frame_desc.time = FrameTime::fixed_step(frame_idx, 1.0 / 60.0);
+        render_frame(&mut rg_renderer, &mut world_renderer, &frame_desc)?;
+    }
+
+    let mut frame_ms = Vec::new();
+    let mut cpu_ms = Vec::new();
+    let mut gpu_passes = Vec::new();
+
+    let mut frame_start = Instant::now();
+    for frame_idx in opt.warmup + 1..=opt.warmup + opt.frames {
+        frame_desc.time = FrameTime::fixed_step(frame_idx, 1.0 / 60.0);
+        let cpu_time = render_frame(&mut rg_renderer, &mut world_renderer, &frame_desc)?;
+
+        let now = Instant::now();
+        frame_ms.push(millis(now - frame_start));
+        frame_start = now;
+
+        cpu_ms.push(millis(cpu_time));
+
+        // Of the latest frame the GPU is done with, which lags behind by the frames in flight.
+        gpu_passes.push(gpu_pass_times());
+    }
+
+    backend.device.wait_idle()?;
+
+    let report = BenchReport {
+        label: opt.label,
+        scene: opt.scene,
+        device: device_name,
+        extent,
+        frames_in_flight: backend.device.frames_in_flight(),
+        warmup_frames: opt.warmup,
+        frames: opt.frames,
+        frame_ms: Stats::from_samples(&frame_ms),
+        cpu_ms: Stats::from_samples(&cpu_ms),
+        gpu_ms: Stats::from_samples(
+            &gpu_passes
+                .iter()
+                .map(|passes| passes.iter().map(|(_, ms)| ms).sum())
+                .collect::<Vec<f64>>(),
+        ),
+        passes: pass_reports(&gpu_passes),
+    };
+
+    let json = serde_json::to_string_pretty(&report)?;
+    if let Some(output) = &opt.output {
+        std::fs::write(output, json)?;
+        println!(
+            "{:.2} ms per frame on average, {:.2} ms at p99; wrote {:?}",
+            report.frame_ms.mean, report.frame_ms.p99, output
+        );
+    } else {
+        println!("{}", json);
+    }
+
+    Ok(())
+}
+
+// Renders one frame, and returns the time spent preparing its render graph.
+fn render_frame(
+    rg_renderer: &mut rg::renderer::Renderer,
+    world_renderer: &mut WorldRenderer,
+    frame_desc: &WorldFrameDesc,
+) -> anyhow::Result<Duration> {
+    let start = Instant::now();
+    rg_renderer.prepare_frame(|rg| {
+        world_renderer.prepare_render_graph(rg, frame_desc);
+    })?;
+    let cpu_time = start.elapsed();
+
+    rg_renderer.draw_frame_headless(|dynamic_constants| {
+        world_renderer.prepare_frame_constants(dynamic_constants, frame_desc)
+    })?;
+    world_renderer.retire_frame();
+
+    Ok(cpu_time)
+}
+
+// The GPU time of each pass in the profiler's latest frame, summed by name, in order.
+fn gpu_pass_times() -> Vec<(String, f64)> {
+    let mut passes: Vec<(String, f64)> = Vec::new();
+
+    for (scope, ms) in gpu_profiler::get_stats().get_ordered() {
+        match passes.iter_mut().find(|(name, _)| *name == scope.name) {
+            Some((_, total)) => *total += ms,
+            None => passes.push((scope.name, ms)),
+        }
+    }
+
+    passes
+}
+
+fn pass_reports(frames: &[Vec<(String, f64)>]) -> Vec<PassReport> {
+    let mut names: Vec<&str> = Vec::new();
+    for (name, _) in frames.iter().flatten() {
+        if !names.contains(&name.as_str()) {
+            names.push(name);
+        }
+    }
+
+    names
+        .into_iter()
+        .map(|name| {
+            let samples: Vec<f64> = frames
+                .iter()
+                .map(|passes| {
+                    passes
+                        .iter()
+                        .find(|(pass, _)| pass == name)
+                        .map_or(0.0, |(_, ms)| *ms)
+                })
+                .collect();
+
+            PassReport {
+                name: name.to_owned(),
+                ms: Stats::from_samples(&samples),
+            }
+        })
+        .collect()
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stats_use_nearest_rank_percentiles() {
+        let samples: Vec<f64> = (1..=100).rev().map(f64::from).collect();
+        let stats = Stats::from_samples(&samples);
+        assert_eq!(stats.mean, 50.5);
+        assert_eq!([stats.min, stats.max], [1.0, 100.0]);
+        assert_eq!([stats.p50, stats.p95, stats.p99], [50.0, 95.0, 99.0]);
+
+        let single = Stats::from_samples(&[4.0]);
+        assert_eq!([single.p50, single.p99, single.mean], [4.0, 4.0, 4.0]);
+
+        // Missing passes count as zero.
+        let frames = vec![
+            vec![("gbuffer".to_owned(), 2.0), ("taa".to_owned(), 1.0)],
+            vec![("taa".to_owned(), 3.0)],
+        ];
+        let passes = pass_reports(&frames);
+        assert_eq!(passes[0].name, "gbuffer");
+        assert_eq!(passes[0].ms.mean, 1.0);
+        assert_eq!(passes[1].ms.max, 3.0);
+    }
+}
@@ -0,0 +1,493 @@
+// Renders one frame without a window, and writes it to an OpenEXR file.
+//
+// Usage: `cargo run --bin headless --release -- [output.exr] [--multilayer] [aov...]`
+//
+// Each AOV named after the output goes to its own file, e.g. `headless.normal.exr`;
+// see `AovKind::name` for the names. With `--multilayer`, they go into layers of the output
+// file instead.
+//
+// With the `oidn` feature, `[output.exr] --denoise [samples]` renders the reference instead,
+// at 16 samples per pixel by default, and writes it denoised by Open Image Denoise, along with
+// the noisy original, e.g. `headless.noisy.exr`.
+//
+// `[output] --turntable [frames] [fps]` renders an orbit around the scene instead, 120 frames
+// at 30 fps by default. Outputs ending in `.png` or `.exr`, or without an extension, name
+// a directory for an image sequence; others, like `orbit.mp4`, are encoded by `ffmpeg`.
+
+use std::{collections::VecDeque, f32::consts::TAU, sync::Arc, task::Poll};
+
+use anyhow::Context as _;
+use glam::{Affine3A, Quat, Vec3};
+use kajiya::{
+    backend::{HeadlessRenderBackend, HeadlessRenderBackendConfig},
+    camera::*,
+    camera_path::{CameraKeyframe, CameraPath},
+    capture::{CaptureOutput, FrameWriter},
+    frame_desc::{FrameTime, WorldFrameDesc},
+    renderers::{aov::AovKind, readback::ReadbackImage},
+    rg,
+    world_renderer::{AddMeshOptions, WorldRenderer},
+};
+use turbosloth::*;
+
+const EXTENT: [u32; 2] = [1280, 720];
+
+fn main() -> anyhow::Result<()> {
+    kajiya::logging::set_up_logging(log::LevelFilter::Warn)?;
+
+    let mut args = std::env::args().skip(1).peekable();
+    let output_path = args.next().unwrap_or_else(|| "headless.exr".to_owned());
+    let multilayer = args.next_if(|arg| arg == "--multilayer").is_some();
+    let denoise = args.next_if(|arg| arg == "--denoise").is_some();
+    let denoise_samples: u32 = args
+        .next_if(|arg| denoise && arg.parse::<u32>().is_ok())
+        .map_or(16, |samples| samples.parse().unwrap());
+    let turntable = args.next_if(|arg| arg == "--turntable").is_some();
+    let turntable_frames: u32 = args
+        .next_if(|arg| turntable && arg.parse::<u32>().is_ok())
+        .map_or(120, |frames| frames.parse().unwrap());
+    let turntable_fps: f32 = args
+        .next_if(|arg| turntable && arg.parse::<f32>().is_ok())
+        .map_or(30.0, |fps| fps.parse().unwrap());
+    anyhow::ensure!(turntable_frames > 0, "A turntable needs at least one frame");
+    anyhow::ensure!(
+        turntable_fps.is_finite() && turntable_fps > 0.0,
+        "A turntable needs a positive frame rate, not {}",
+        turntable_fps
+    );
+    let aovs = args
+        .map(|name| {
+            AovKind::from_name(&name).ok_or_else(|| anyhow::anyhow!("Unknown AOV {:?}", name))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let backend = HeadlessRenderBackend::new(HeadlessRenderBackendConfig::default())?;
+    let stem = output_path.strip_suffix(".exr").unwrap_or(&output_path);
+
+    if denoise {
+        #[cfg(feature = "oidn")]
+        {
+            let noisy_path = format!("{}.noisy.exr", stem);
+            let (noisy, denoised) = render_denoised_reference(&backend, denoise_samples)?;
+            noisy.write_exr(&noisy_path)?;
+            denoised.write_exr(&output_path)?;
+            println!("Wrote {} and {}", noisy_path, output_path);
+            return Ok(());
+        }
+
+        #[cfg(not(feature = "oidn"))]
+        anyhow::bail!(
+            "Can't denoise {} samples; built without the `oidn` feature",
+            denoise_samples
+        );
+    }
+
+    if turntable {
+        let output = CaptureOutput::from_path(&output_path, turntable_fps);
+        render_turntable(&backend, output, turntable_frames, turntable_fps)?;
+        println!("Wrote {} frames to {}", turntable_frames, output_path);
+        return Ok(());
+    }
+
+    if multilayer {
+        render_multilayer_exr(
+            &backend,
+            FrameTime::fixed_step(0, 1.0 / 60.0),
+            &output_path,
+            &aovs,
+        )?;
+        println!("Wrote {}", output_path);
+        return Ok(());
+    }
+
+    let pixels = render_frame(&backend, FrameTime::fixed_step(0, 1.0 / 60.0))?;
+
+    exr::prelude::write_rgb_file(
+        &output_path,
+        EXTENT[0] as usize,
+        EXTENT[1] as usize,
+        |x, y| {
+            let [r, g, b] = pixels[y * EXTENT[0] as usize + x];
+            (r, g, b)
+        },
+    )?;
+
+    println!("Wrote {}", output_path);
+
+    for aov in aovs {
+        let aov_path = format!("{}.{}.exr", stem, aov.name());
+        render_aov(&backend, FrameTime::fixed_step(0, 1.0 / 60.0), aov)?.write_exr(&aov_path)?;
+        println!("Wrote {}", aov_path);
+    }
+
+    Ok(())
+}
+
+// A fresh `WorldRenderer` with the sample scene, and the view of it.
+fn create_world_renderer(
+    backend: &HeadlessRenderBackend,
+    lazy_cache: &Arc<LazyCache>,
+    time: FrameTime,
+) -> anyhow::Result<(WorldRenderer, WorldFrameDesc)> {
+    let mut world_renderer = WorldRenderer::new(EXTENT, EXTENT, &backend.device, lazy_cache)?;
+
+    let car_mesh = world_renderer.add_baked_mesh("/baked/336_lrm.mesh", AddMeshOptions::new())?;
+    world_renderer.add_instance(car_mesh, Affine3A::IDENTITY);
+
+    let frame_desc = WorldFrameDesc {
+        camera_matrices: (
+            Vec3::new(0.0, 1.0, 2.5),
+            Quat::from_rotation_x(-18.0f32.to_radians()),
+        )
+            .through(&lens()),
+        render_extent: EXTENT,
+        sun_direction: Vec3::new(4.0, 1.0, 1.0).normalize(),
+        time,
+    };
+
+    Ok((world_renderer, frame_desc))
+}
+
+fn lens() -> CameraLens {
+    CameraLens {
+        aspect_ratio: EXTENT[0] as f32 / EXTENT[1] as f32,
+        ..Default::default()
+    }
+}
+
+// Renders the sample scene with a fresh `WorldRenderer`, at the given time.
+fn render_frame(backend: &HeadlessRenderBackend, time: FrameTime) -> anyhow::Result<Vec<[f32; 3]>> {
+    let lazy_cache = LazyCache::create();
+    let (mut world_renderer, frame_desc) = create_world_renderer(backend, &lazy_cache, time)?;
+    let mut rg_renderer = rg::renderer::Renderer::new(&backend.device)?;
+
+    let mut readback = rg::readback::AsyncReadback::default();
+    let mut token = None;
+
+    rg_renderer.prepare_frame(|rg| {
+        let main_img = world_renderer.prepare_render_graph(rg, &frame_desc);
+        readback.begin_frame();
+
+        // The output of the world renderer is `B10G11R11_UFLOAT_PACK32`, at 4 bytes per pixel.
+        token = Some(readback.copy_image(rg, &main_img, std::mem::size_of::<u32>()));
+    })?;
+
+    rg_renderer.draw_frame_headless(|dynamic_constants| {
+        world_renderer.prepare_frame_constants(dynamic_constants, &frame_desc)
+    })?;
+    world_renderer.retire_frame();
+    readback.retire_frame(backend.device.frame_counter());
+
+    let texels = token
+        .and_then(|token| readback.wait(&backend.device, token))
+        .context("Could not read the frame back")?;
+
+    Ok(texels
+        .chunks_exact(4)
+        .map(|px| unpack_b10g11r11(u32::from_le_bytes([px[0], px[1], px[2], px[3]])))
+        .collect())
+}
+
+// Renders the sample scene with a fresh `WorldRenderer` until the `aov` of its first frame is read back.
+fn render_aov(
+    backend: &HeadlessRenderBackend,
+    time: FrameTime,
+    aov: AovKind,
+) -> anyhow::Result<ReadbackImage> {
+    let lazy_cache = LazyCache::create();
+    let (mut world_renderer, frame_desc) = create_world_renderer(backend, &lazy_cache, time)?;
+
+    let token = world_renderer.request_aov_readback(aov);
+    render_until_ready(
+        backend,
+        &mut world_renderer,
+        &frame_desc,
+        |world_renderer| {
+            world_renderer.poll_aov_readback(token).map(|image| {
+                image.ok_or_else(|| anyhow::anyhow!("The {} AOV isn't available", aov.name()))
+            })
+        },
+    )
+}
+
+// Renders the sample scene with a fresh `WorldRenderer`, and exports the lit image of its first
+// frame, before tonemapping, along with `aovs` to a multi-layer EXR.
+fn render_multilayer_exr(
+    backend: &HeadlessRenderBackend,
+    time: FrameTime,
+    path: &str,
+    aovs: &[AovKind],
+) -> anyhow::Result<()> {
+    let lazy_cache = LazyCache::create();
+    let (mut world_renderer, frame_desc) = create_world_renderer(backend, &lazy_cache, time)?;
+
+    let token = world_renderer.export_multilayer_exr(path, aovs);
+    render_until_ready(
+        backend,
+        &mut world_renderer,
+        &frame_desc,
+        |world_renderer| world_renderer.poll_exr_export(token),
+    )
+}
+
+// Accumulates `sample_count` paths per pixel of the sample scene in reference mode, and returns
+// the radiance as of the last one, before and after denoising with Open Image Denoise.
+#[cfg(feature = "oidn")]
+fn render_denoised_reference(
+    backend: &HeadlessRenderBackend,
+    sample_count: u32,
+) -> anyhow::Result<(ReadbackImage, ReadbackImage)> {
+    let sample_count = sample_count.max(1);
+    let lazy_cache = LazyCache::create();
+    let (mut world_renderer, mut frame_desc) =
+        create_world_renderer(backend, &lazy_cache, FrameTime::fixed_step(0, 1.0 / 60.0))?;
+    world_renderer.render_mode = kajiya::world_renderer::RenderMode::Reference;
+
+    // The accumulation lives in the temporal resources of the render graph, so all the frames
+    // must go through the same renderer.
+    let mut rg_renderer = rg::renderer::Renderer::new(&backend.device)?;
+
+    let mut tokens = None;
+    let (mut noisy, mut denoised) = (None, None);
+
+    for frame_idx in 0..sample_count + backend.device.frames_in_flight() as u32 + 1 {
+        if frame_idx + 1 == sample_count {
+            tokens = Some((
+                world_renderer.request_aov_readback(AovKind::Radiance),
+                world_renderer.request_oidn_denoise(),
+            ));
+        }
+
+        frame_desc.time = FrameTime::fixed_step(frame_idx, 1.0 / 60.0);
+        rg_renderer.prepare_frame(|rg| {
+            world_renderer.prepare_render_graph(rg, &frame_desc);
+        })?;
+
+        rg_renderer.draw_frame_headless(|dynamic_constants| {
+            world_renderer.prepare_frame_constants(dynamic_constants, &frame_desc)
+        })?;
+        world_renderer.retire_frame();
+
+        if let Some((noisy_token, denoise_token)) = tokens {
+            if noisy.is_none() {
+                if let Poll::Ready(image) = world_renderer.poll_aov_readback(noisy_token) {
+                    noisy = Some(image.ok_or_else(|| anyhow::anyhow!("No noisy radiance"))?);
+                }
+            }
+
+            if denoised.is_none() {
+                if let Poll::Ready(image) = world_renderer.poll_oidn_denoise(denoise_token) {
+                    denoised = Some(image?);
+                }
+            }
+        }
+
+        if noisy.is_some() && denoised.is_some() {
+            return Ok((noisy.unwrap(), denoised.unwrap()));
+        }
+    }
+
+    anyhow::bail!("Timed out waiting for the denoiser")
+}
+
+// Renders `frame_count` frames of one orbit around the sample scene, a fixed `1 / fps` apart,
+// and writes them to `output`. The frames past the last one loop back to the start.
+fn render_turntable(
+    backend: &HeadlessRenderBackend,
+    output: CaptureOutput,
+    frame_count: u32,
+    fps: f32,
+) -> anyhow::Result<()> {
+    let lazy_cache = LazyCache::create();
+    let (mut world_renderer, mut frame_desc) =
+        create_world_renderer(backend, &lazy_cache, FrameTime::fixed_step(0, 1.0 / fps))?;
+    let mut rg_renderer = rg::renderer::Renderer::new(&backend.device)?;
+
+    // Around the car, through eight keyframes on a circle
+    let duration = frame_count as f32 / fps;
+    let orbit = CameraPath::new(
+        (0..8)
+            .map(|idx| {
+                let angle = idx as f32 / 8.0 * TAU;
+                CameraKeyframe::new(
+                    idx as f32 / 8.0 * duration,
+                    Vec3::new(2.5 * angle.sin(), 1.0, 2.5 * angle.cos()),
+                    Vec3::new(0.0, 0.2, 0.0),
+                )
+            })
+            .collect(),
+    )
+    .looping(duration);
+
+    let mut writer = FrameWriter::new(output)?;
+    let mut pending = VecDeque::new();
+
+    // A few frames past the last one, for its readback to complete
+    for frame_idx in 0..frame_count + backend.device.frames_in_flight() as u32 + 1 {
+        if frame_idx < frame_count {
+            pending.push_back(world_renderer.request_final_image_readback());
+        }
+
+        frame_desc.time = FrameTime::fixed_step(frame_idx, 1.0 / fps);
+        frame_desc.camera_matrices = orbit.camera_matrices(frame_desc.time.time_seconds, &lens());
+
+        rg_renderer.prepare_frame(|rg| {
+            world_renderer.prepare_render_graph(rg, &frame_desc);
+        })?;
+
+        rg_renderer.draw_frame_headless(|dynamic_constants| {
+            world_renderer.prepare_frame_constants(dynamic_constants, &frame_desc)
+        })?;
+        world_renderer.retire_frame();
+
+        // Readbacks complete in order.
+        while let Some(token) = pending.front() {
+            match world_renderer.poll_aov_readback(*token) {
+                Poll::Ready(frame) => {
+                    writer.write_frame(&frame.context("The frame wasn't read back")?)?;
+                    pending.pop_front();
+                }
+                Poll::Pending => break,
+            }
+        }
+    }
+
+    anyhow::ensure!(pending.is_empty(), "Timed out waiting for a readback");
+    writer.finish()
+}
+
+// Renders frames until `poll` is ready with the results of a readback scheduled before the first.
+fn render_until_ready<T>(
+    backend: &HeadlessRenderBackend,
+    world_renderer: &mut WorldRenderer,
+    frame_desc: &WorldFrameDesc,
+    mut poll: impl FnMut(&mut WorldRenderer) -> Poll<anyhow::Result<T>>,
+) -> anyhow::Result<T> {
+    let mut rg_renderer = rg::renderer::Renderer::new(&backend.device)?;
+
+    // The result is ready once the GPU is done with the frame, which takes as many more frames
+    // as there can be in flight.
+    for _ in 0..=backend.device.frames_in_flight() + 1 {
+        rg_renderer.prepare_frame(|rg| {
+            world_renderer.prepare_render_graph(rg, frame_desc);
+        })?;
+
+        rg_renderer.draw_frame_headless(|dynamic_constants| {
+            world_renderer.prepare_frame_constants(dynamic_constants, frame_desc)
+        })?;
+        world_renderer.retire_frame();
+
+        if let Poll::Ready(result) = poll(world_renderer) {
+            return result;
+        }
+    }
+
+    anyhow::bail!("Timed out waiting for a readback")
+}
+
+// Unsigned 11, 11, and 10-bit floats with 5-bit exponents, red in the lowest bits.
+fn unpack_b10g11r11(packed: u32) -> [f32; 3] {
+    fn unpack(bits: u32, mantissa_bits: u32) -> f32 {
+        let mantissa = bits & ((1 << mantissa_bits) - 1);
+        let exponent = bits >> mantissa_bits;
+        let fraction = mantissa as f32 / (1 << mantissa_bits) as f32;
+
+        match exponent {
+            0 => fraction * 2f32.powi(-14),
+            31 => {
+                if mantissa == 0 {
+                    f32::INFINITY
+                } else {
+                    f32::NAN
+                }
+            }
+            _ => (1.0 + fraction) * 2f32.powi(exponent as i32 - 15),
+        }
+    }
+
+    [
+        unpack(packed & 0x7ff, 6),
+        unpack((packed >> 11) & 0x7ff, 6),
+        unpack(packed >> 22, 5),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[ignore = "needs a Vulkan device and the baked meshes"]
+    fn same_frame_index_renders_the_same() {
+        let backend = HeadlessRenderBackend::new(HeadlessRenderBackendConfig::default()).unwrap();
+        let time = FrameTime::fixed_step(7, 1.0 / 60.0);
+
+        let first = render_frame(&backend, time).unwrap();
+        let second = render_frame(&backend, time).unwrap();
+
+        // Compare bits, so that NaNs compare equal too.
+        let bits = |pixels: &[[f32; 3]]| -> Vec<[u32; 3]> {
+            pixels.iter().map(|px| px.map(f32::to_bits)).collect()
+        };
+        assert!(bits(&first) == bits(&second));
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device and the baked meshes"]
+    fn normal_aov_reads_back_unit_normals() {
+        let backend = HeadlessRenderBackend::new(HeadlessRenderBackendConfig::default()).unwrap();
+        let normals = render_aov(
+            &backend,
+            FrameTime::fixed_step(0, 1.0 / 60.0),
+            AovKind::Normal,
+        )
+        .unwrap();
+
+        assert_eq!(normals.extent, EXTENT);
+        let texels = normals.to_rgba_f32().unwrap();
+
+        // The car covers the middle of the view; the sky is zero.
+        let center = texels[(EXTENT[1] / 2 * EXTENT[0] + EXTENT[0] / 2) as usize];
+        let length = Vec3::new(center[0], center[1], center[2]).length();
+        assert!((length - 1.0).abs() < 0.01, "{:?}", center);
+        assert_eq!(texels[0], [0.0; 4]);
+
+        let covered = texels.iter().filter(|texel| texel[3] > 0.0);
+        assert!(covered.clone().count() > texels.len() / 20);
+        assert!(covered.all(|texel| {
+            let length = Vec3::new(texel[0], texel[1], texel[2]).length();
+            (length - 1.0).abs() < 0.01
+        }));
+    }
+
+    #[cfg(feature = "oidn")]
+    #[test]
+    #[ignore = "needs a Vulkan device with ray tracing, the baked meshes, and Open Image Denoise"]
+    fn denoising_smooths_a_low_sample_reference() {
+        let backend = HeadlessRenderBackend::new(HeadlessRenderBackendConfig::default()).unwrap();
+        let (noisy, denoised) = render_denoised_reference(&backend, 4).unwrap();
+
+        assert_eq!(noisy.extent, EXTENT);
+        assert_eq!(denoised.extent, EXTENT);
+
+        // Sum of differences between horizontal neighbors, which noise inflates.
+        let variation = |image: &ReadbackImage| -> f64 {
+            let texels = image.to_rgba_f32().unwrap();
+            texels
+                .chunks_exact(EXTENT[0] as usize)
+                .flat_map(|row| row.windows(2))
+                .map(|pair| {
+                    (0..3)
+                        .map(|c| (pair[0][c] - pair[1][c]).abs() as f64)
+                        .sum::<f64>()
+                })
+                .sum()
+        };
+
+        let denoised_texels = denoised.to_rgba_f32().unwrap();
+        assert!(denoised_texels
+            .iter()
+            .all(|texel| texel.iter().all(|c| c.is_finite() && *c >= 0.0)));
+        assert!(variation(&denoised) * 2.0 < variation(&noisy));
+    }
+}
@@ -0,0 +1,55 @@
+use kajiya::world_renderer::AddMeshOptions;
+use kajiya_simple::*;
+
+fn main() -> anyhow::Result<()> {
+    let mut kajiya = SimpleMainLoop::builder().resolution([1920, 1080]).build(
+        WindowBuilder::new()
+            .with_title("hello-kajiya-egui")
+            .with_resizable(false),
+    )?;
+
+    let camera = (
+        Vec3::new(0.0, 1.0, 2.5),
+        Quat::from_rotation_x(-18.0f32.to_radians()),
+    );
+
+    let lens = CameraLens {
+        aspect_ratio: kajiya.window_aspect_ratio(),
+        ..Default::default()
+    };
+
+    let car_mesh = kajiya
+        .world_renderer
+        .add_baked_mesh("/baked/336_lrm.mesh", AddMeshOptions::new())?;
+
+    kajiya.world_renderer.add_instance(
+        car_mesh,
+        Affine3A::from_rotation_translation(Quat::IDENTITY, Vec3::ZERO),
+    );
+
+    // In degrees
+    let mut sun_azimuth = 76.0f32;
+    let mut sun_elevation = 14.0f32;
+
+    kajiya.run(move |ctx| {
+        if let Some(egui) = ctx.egui {
+            egui.frame(|ctx| {
+                egui::Window::new("Sun").show(ctx, |ui| {
+                    ui.add(egui::Slider::new(&mut sun_azimuth, -180.0..=180.0).text("azimuth"));
+                    ui.add(egui::Slider::new(&mut sun_elevation, 0.0..=90.0).text("elevation"));
+                });
+            });
+        }
+
+        let sun_direction = Quat::from_rotation_y(sun_azimuth.to_radians())
+            * Quat::from_rotation_x(-sun_elevation.to_radians())
+            * Vec3::Z;
+
+        WorldFrameDesc {
+            camera_matrices: camera.through(&lens),
+            render_extent: ctx.render_extent,
+            sun_direction,
+            time: ctx.time,
+        }
+    })
+}
@@ -2,6 +2,16 @@ use kajiya::world_renderer::AddMeshOptions;
 use kajiya_simple::*;
 
 fn main() -> anyhow::Result<()> {
+    // `--motion-blur-demo` spins the car fast enough to blur, while the static camera keeps
+    // the background sharp; `--no-motion-blur` shows the same without the blur.
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let has_arg = |name: &str| args.iter().any(|arg| arg == name);
+    let car_spin_speed = if has_arg("--motion-blur-demo") {
+        4.0 * std::f32::consts::PI
+    } else {
+        0.5
+    };
+
     let mut kajiya = SimpleMainLoop::builder().resolution([1920, 1080]).build(
         WindowBuilder::new()
             .with_title("hello-kajiya")
@@ -27,10 +37,12 @@ fn main() -> anyhow::Result<()> {
         Affine3A::from_rotation_translation(Quat::IDENTITY, Vec3::ZERO),
     );
 
+    kajiya.world_renderer.use_motion_blur = !has_arg("--no-motion-blur");
+
     let mut car_rot = 0.0f32;
 
     kajiya.run(move |ctx| {
-        car_rot += 0.5 * ctx.dt_filtered;
+        car_rot += car_spin_speed * ctx.dt_filtered;
         ctx.world_renderer.set_instance_transform(
             car_inst,
             Affine3A::from_rotation_translation(Quat::from_rotation_y(car_rot), Vec3::ZERO),
@@ -40,6 +52,7 @@ fn main() -> anyhow::Result<()> {
             camera_matrices: camera.through(&lens),
             render_extent: ctx.render_extent,
             sun_direction: Vec3::new(4.0, 1.0, 1.0).normalize(),
+            time: ctx.time,
         }
     })
 }
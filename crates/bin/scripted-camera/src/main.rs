@@ -0,0 +1,69 @@
+// Flies the camera around a car along a scripted path. There's no input handling: each frame
+// is rendered with `SimpleMainLoop::render`, through camera matrices computed from the time.
+//
+// Usage: `cargo run --bin scripted-camera --release`
+
+use kajiya::world_renderer::AddMeshOptions;
+use kajiya_simple::*;
+
+// Camera waypoints, looped through with a Catmull-Rom spline
+const PATH: [[f32; 3]; 4] = [
+    [2.5, 1.0, 0.0],
+    [0.0, 0.6, 2.5],
+    [-2.5, 1.2, 0.0],
+    [0.0, 1.8, -2.5],
+];
+
+const SECONDS_PER_WAYPOINT: f32 = 3.0;
+const LOOK_AT: [f32; 3] = [0.0, 0.4, 0.0];
+
+fn main() -> anyhow::Result<()> {
+    let mut kajiya = SimpleMainLoop::builder().resolution([1920, 1080]).build(
+        WindowBuilder::new()
+            .with_title("scripted-camera")
+            .with_resizable(false),
+    )?;
+
+    let lens = CameraLens {
+        aspect_ratio: kajiya.window_aspect_ratio(),
+        ..Default::default()
+    };
+
+    let car_mesh = kajiya
+        .world_renderer
+        .add_baked_mesh("/baked/336_lrm.mesh", AddMeshOptions::new())?;
+    kajiya
+        .world_renderer
+        .add_instance(car_mesh, Affine3A::IDENTITY);
+
+    let sun_direction = Vec3::new(4.0, 1.0, 1.0).normalize();
+
+    let path = CameraPath::new(
+        PATH.iter()
+            .enumerate()
+            .map(|(idx, position)| {
+                CameraKeyframe::new(
+                    idx as f32 * SECONDS_PER_WAYPOINT,
+                    Vec3::from(*position),
+                    Vec3::from(LOOK_AT),
+                )
+            })
+            .collect(),
+    )
+    .looping(PATH.len() as f32 * SECONDS_PER_WAYPOINT);
+
+    // A fixed time step, so the flight is the same regardless of the frame rate.
+    let mut time = FrameTime::fixed_step(0, 1.0 / 60.0);
+
+    loop {
+        let camera_matrices = path.camera_matrices(time.time_seconds, &lens);
+
+        if !kajiya.render(camera_matrices, sun_direction, time)? {
+            break;
+        }
+
+        time = time.next(time.dt);
+    }
+
+    Ok(())
+}
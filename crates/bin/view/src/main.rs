@@ -1,11 +1,19 @@
-use anyhow::Context;
-
 use dolly::prelude::*;
 use imgui::im_str;
-use kajiya::{rg::GraphDebugHook, world_renderer::AddMeshOptions};
+use kajiya::{
+    renderers::{
+        csm::{ShadowFilter, ShadowUpdatePolicy},
+        picking::PickToken,
+        post::Exposure,
+        transparency::TransparencyMode,
+    },
+    rg::GraphDebugHook,
+    scene_desc::LiveSceneDesc,
+    world_renderer::{InstanceHandle, RayOriginOffset},
+};
 use kajiya_simple::*;
 
-use std::fs::File;
+use std::{fs::File, path::PathBuf, task::Poll};
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
@@ -26,6 +34,22 @@ struct Opt {
     #[structopt(long)]
     no_vsync: bool,
 
+    #[structopt(long, default_value = "2")]
+    frames_in_flight: usize,
+
+    /// Wait for the GPU before reading input for each frame, instead of queueing frames.
+    #[structopt(long)]
+    low_latency: bool,
+
+    #[structopt(long)]
+    hdr: bool,
+
+    #[structopt(long, default_value = "1000")]
+    hdr_peak_nits: f32,
+
+    #[structopt(long)]
+    target_frame_ms: Option<f32>,
+
     #[structopt(long)]
     no_window_decorations: bool,
 
@@ -35,19 +59,28 @@ struct Opt {
     #[structopt(long)]
     no_debug: bool,
 
+    #[structopt(long)]
+    verbose_validation: bool,
+
     #[structopt(long, default_value = "1.0")]
     gi_volume_scale: f32,
-}
 
-#[derive(serde::Deserialize)]
-struct SceneDesc {
-    instances: Vec<SceneInstanceDesc>,
-}
+    #[structopt(long)]
+    ddgi: bool,
 
-#[derive(serde::Deserialize)]
-struct SceneInstanceDesc {
-    position: [f32; 3],
-    mesh: String,
+    #[structopt(long)]
+    volumetric_fog: bool,
+
+    #[structopt(long)]
+    atmosphere: bool,
+
+    /// Grade the output with a `.cube` 3D LUT.
+    #[structopt(long)]
+    color_lut: Option<PathBuf>,
+
+    /// Fixed aspect ratio of the image (e.g. 2.39), with black bars filling the rest of the window.
+    #[structopt(long)]
+    letterbox: Option<f32>,
 }
 
 #[derive(Clone, serde::Serialize, serde::Deserialize)]
@@ -67,6 +100,13 @@ impl SunState {
 
         spherical_to_cartesian(self.theta, self.phi)
     }
+
+    pub fn from_direction(direction: Vec3) -> Self {
+        Self {
+            theta: direction.z.atan2(direction.x),
+            phi: direction.y.clamp(-1.0, 1.0).acos(),
+        }
+    }
 }
 
 #[derive(Clone, serde::Serialize, serde::Deserialize)]
@@ -104,16 +144,29 @@ fn main() -> anyhow::Result<()> {
         .ok()
         .and_then(|f| ron::de::from_reader(f).ok());
 
-    let scene_file = format!("assets/scenes/{}.ron", opt.scene);
-    let scene_desc: SceneDesc = ron::de::from_reader(
-        File::open(&scene_file).with_context(|| format!("Opening scene file {}", scene_file))?,
-    )?;
-
     let mut kajiya = SimpleMainLoop::builder()
         .resolution([opt.width, opt.height])
         .vsync(!opt.no_vsync)
-        .graphics_debugging(!opt.no_debug)
+        .frames_in_flight(opt.frames_in_flight)
+        .low_latency(opt.low_latency)
+        .hdr_output(opt.hdr)
+        .hdr_peak_nits(opt.hdr_peak_nits)
+        .dynamic_resolution(
+            opt.target_frame_ms
+                .map(|target_frame_ms| DynamicResolutionDesc {
+                    target_frame_ms,
+                    ..Default::default()
+                }),
+        )
+        .validation(ValidationConfig::with_level(if opt.no_debug {
+            ValidationLevel::Off
+        } else if opt.verbose_validation {
+            ValidationLevel::Verbose
+        } else {
+            ValidationLevel::ErrorsOnly
+        }))
         .temporal_upsampling(opt.temporal_upsampling)
+        .letterbox(opt.letterbox.map(Letterbox::new))
         .default_log_level(log::LevelFilter::Info)
         .fullscreen(opt.fullscreen.then(|| FullscreenMode::Exclusive))
         .build(
@@ -124,6 +177,21 @@ fn main() -> anyhow::Result<()> {
         )?;
 
     kajiya.world_renderer.world_gi_scale = opt.gi_volume_scale;
+    kajiya.world_renderer.use_ddgi = opt.ddgi;
+    kajiya.world_renderer.use_volumetric_fog = opt.volumetric_fog;
+    kajiya.world_renderer.use_atmosphere = opt.atmosphere;
+
+    let color_grading = opt.color_lut.is_some();
+    if let Some(color_lut) = &opt.color_lut {
+        let lut = kajiya::renderers::color_grading::CubeLut::load(color_lut)?;
+        kajiya.world_renderer.set_color_grading_lut(Some(&lut))?;
+    }
+
+    // Edits to the scene file are applied while running.
+    let mut scene = LiveSceneDesc::load(
+        &mut kajiya.world_renderer,
+        format!("assets/scenes/{}.ron", opt.scene),
+    )?;
 
     // Mitsuba match
     /*let mut camera = camera::FirstPersonCamera::new(Vec3::new(-2.0, 4.0, 8.0));
@@ -138,6 +206,8 @@ fn main() -> anyhow::Result<()> {
     let mut camera = {
         let (position, rotation) = if let Some(state) = &persisted_app_state {
             (state.camera_position, state.camera_rotation)
+        } else if let Some(camera) = &scene.loaded().camera {
+            (camera.position.into(), camera.rotation())
         } else {
             (Vec3::new(0.0, 1.0, 8.0), Quat::IDENTITY)
         };
@@ -168,18 +238,6 @@ fn main() -> anyhow::Result<()> {
     )?;
     let mut light_instances = Vec::new();*/
 
-    let mut render_instances = vec![];
-    for instance in scene_desc.instances {
-        let mesh = kajiya.world_renderer.add_baked_mesh(
-            format!("/baked/{}.mesh", instance.mesh),
-            AddMeshOptions::new(),
-        )?;
-        render_instances.push(kajiya.world_renderer.add_instance(
-            mesh,
-            Affine3A::from_rotation_translation(Quat::IDENTITY, instance.position.into()),
-        ));
-    }
-
     /*let car_mesh = kajiya
         .world_renderer
         .add_baked_mesh("/baked/336_lrm.mesh")?;
@@ -195,11 +253,20 @@ fn main() -> anyhow::Result<()> {
             camera_position: camera.final_transform.position,
             camera_rotation: camera.final_transform.rotation,
             emissive_multiplier: 1.0,
-            vertical_fov: 52.0,
-            sun: SunState {
-                theta: -4.54,
-                phi: 1.48,
-            },
+            vertical_fov: scene
+                .loaded()
+                .camera
+                .as_ref()
+                .and_then(|camera| camera.vertical_fov)
+                .unwrap_or(52.0),
+            sun: scene
+                .loaded()
+                .sun_direction
+                .map(SunState::from_direction)
+                .unwrap_or(SunState {
+                    theta: -4.54,
+                    phi: 1.48,
+                }),
             lights: LocalLightsState {
                 theta: 1.0,
                 phi: 1.0,
@@ -213,15 +280,20 @@ fn main() -> anyhow::Result<()> {
         let state = &mut state;
 
         let mut show_gui = false;
+        let mut show_stats_overlay = false;
         let mut sun_direction_interp = state.sun.direction();
         let left_click_edit_mode = LeftClickEditMode::MoveSun;
 
         const MAX_FPS_LIMIT: u32 = 256;
         let mut max_fps = MAX_FPS_LIMIT;
         let mut debug_gi_cascade_idx: u32 = 0;
+        let mut manual_exposure: Option<f32> = None;
 
         let mut locked_rg_debug_hook: Option<GraphDebugHook> = None;
 
+        let mut pending_pick: Option<PickToken> = None;
+        let mut selected_instance: Option<InstanceHandle> = None;
+
         kajiya.run(move |mut ctx| {
             // Limit framerate. Not particularly precise.
             if max_fps != MAX_FPS_LIMIT {
@@ -267,10 +339,56 @@ fn main() -> anyhow::Result<()> {
                 Quat::from_rotation_y(car_rot),
             );*/
 
-            for inst in &render_instances {
+            match scene.reload_if_changed(&mut ctx.world_renderer) {
+                Ok(true) => {
+                    // The selection may have been removed from the world.
+                    selected_instance =
+                        selected_instance.filter(|inst| scene.loaded().instances.contains(inst));
+
+                    if let Some(sun_direction) = scene.loaded().sun_direction {
+                        state.sun = SunState::from_direction(sun_direction);
+                    }
+                }
+                Ok(false) => {}
+                Err(err) => log::error!("{:#}", err),
+            }
+
+            for (inst, instance_desc) in
+                scene.loaded().instances.iter().zip(&scene.desc().instances)
+            {
                 ctx.world_renderer
                     .get_instance_dynamic_parameters_mut(*inst)
-                    .emissive_multiplier = state.emissive_multiplier;
+                    .emissive_multiplier =
+                    instance_desc.emissive_multiplier * state.emissive_multiplier;
+            }
+
+            // Middle-click to select the object under the cursor. The result arrives a few frames later.
+            if mouse.buttons_pressed & (1 << 1) != 0 {
+                if let Some(uv) = ctx
+                    .viewport
+                    .to_uv([mouse.physical_position.x, mouse.physical_position.y])
+                {
+                    let output_extent = ctx.world_renderer.temporal_upscale_extent();
+                    pending_pick = Some(ctx.world_renderer.request_pick(
+                        (uv[0] * output_extent[0] as f32) as i32,
+                        (uv[1] * output_extent[1] as f32) as i32,
+                    ));
+                }
+            }
+
+            if let Some(token) = pending_pick {
+                if let Poll::Ready(picked) = ctx.world_renderer.poll_pick(token) {
+                    pending_pick = None;
+
+                    if let Some(inst) = selected_instance.take() {
+                        ctx.world_renderer.set_instance_highlight(inst, None);
+                    }
+
+                    if let Some(inst) = picked {
+                        ctx.world_renderer.set_instance_highlight(inst, Some(0));
+                        selected_instance = Some(inst);
+                    }
+                }
             }
 
             if keyboard.was_just_pressed(VirtualKeyCode::Space) {
@@ -373,12 +491,50 @@ fn main() -> anyhow::Result<()> {
                     .through(&lens),
                 render_extent: ctx.render_extent,
                 sun_direction: sun_direction_interp,
+                time: ctx.time,
             };
 
             if keyboard.was_just_pressed(VirtualKeyCode::Tab) {
                 show_gui = !show_gui;
             }
 
+            if keyboard.was_just_pressed(VirtualKeyCode::P) {
+                show_stats_overlay = !show_stats_overlay;
+            }
+
+            if show_stats_overlay {
+                let ordered_scopes = gpu_profiler::get_stats().get_ordered();
+                let gpu_time_ms: f64 = ordered_scopes.iter().map(|(_, ms)| ms).sum();
+
+                let mut stats = format!(
+                    "FPS: {:.0}\nCPU: {:.2}ms\nGPU: {:.2}ms\n",
+                    1.0 / ctx.dt_filtered.max(1e-6),
+                    ctx.dt_filtered * 1000.0,
+                    gpu_time_ms
+                );
+
+                stats += &format!("Queued frames: {}\n", ctx.queued_frames);
+                if let Some(latency) = ctx.input_to_gpu_latency {
+                    stats += &format!("Input to GPU: {:.2}ms\n", latency.as_secs_f64() * 1000.0);
+                }
+
+                for (scope, ms) in ordered_scopes {
+                    if scope.name == "debug" || scope.name.starts_with('_') {
+                        continue;
+                    }
+
+                    stats += &format!("\n{}: {:.3}ms", scope.name, ms);
+                }
+
+                ctx.world_renderer
+                    .text_overlay
+                    .text(Vec2::new(8.0, 8.0), 2, Vec4::ONE, &stats);
+            }
+
+            if keyboard.was_just_pressed(VirtualKeyCode::F) {
+                ctx.world_renderer.wireframe.enabled = !ctx.world_renderer.wireframe.enabled;
+            }
+
             if keyboard.was_just_pressed(VirtualKeyCode::C) {
                 println!(
                     "position: {}, look_at: {}",
@@ -401,6 +557,18 @@ fn main() -> anyhow::Result<()> {
                             .speed(0.01)
                             .build(ui, &mut state.ev_shift);
 
+                        let mut use_manual_exposure = manual_exposure.is_some();
+                        ui.checkbox(im_str!("Manual exposure"), &mut use_manual_exposure);
+                        if use_manual_exposure {
+                            let ev = manual_exposure.get_or_insert(-state.ev_shift);
+                            imgui::Drag::<f32>::new(im_str!("EV"))
+                                .range(-8.0..=8.0)
+                                .speed(0.01)
+                                .build(ui, ev);
+                        } else {
+                            manual_exposure = None;
+                        }
+
                         imgui::Drag::<f32>::new(im_str!("Emissive multiplier"))
                             .range(0.0..=10.0)
                             .speed(0.1)
@@ -421,6 +589,29 @@ fn main() -> anyhow::Result<()> {
                             .speed(0.02)
                             .build(ui, &mut ctx.world_renderer.sun_size_multiplier);
 
+                        let ray_origin_offset = &mut ctx.world_renderer.ray_origin_offset;
+                        let mut robust = matches!(ray_origin_offset, RayOriginOffset::Robust(_));
+                        ui.checkbox(im_str!("Robust ray origin offsets"), &mut robust);
+                        *ray_origin_offset = match (robust, *ray_origin_offset) {
+                            (true, RayOriginOffset::Epsilon(_)) => RayOriginOffset::default(),
+                            (false, RayOriginOffset::Robust(_)) => RayOriginOffset::Epsilon(1e-4),
+                            (_, offset) => offset,
+                        };
+                        match ray_origin_offset {
+                            RayOriginOffset::Robust(scale) => {
+                                imgui::Drag::<f32>::new(im_str!("Ray origin offset scale"))
+                                    .range(0.0..=16.0)
+                                    .speed(0.05)
+                                    .build(ui, scale);
+                            }
+                            RayOriginOffset::Epsilon(epsilon) => {
+                                imgui::Drag::<f32>::new(im_str!("Ray origin offset"))
+                                    .range(0.0..=0.1)
+                                    .speed(1e-5)
+                                    .build(ui, epsilon);
+                            }
+                        }
+
                         /*if ui.radio_button_bool(
                             im_str!("Move sun"),
                             left_click_edit_mode == LeftClickEditMode::MoveSun,
@@ -445,6 +636,386 @@ fn main() -> anyhow::Result<()> {
                         }
                     }
 
+                    if imgui::CollapsingHeader::new(im_str!("Probe grid GI"))
+                        .default_open(false)
+                        .build(ui)
+                    {
+                        ui.checkbox(
+                            im_str!("Enable probe grid"),
+                            &mut ctx.world_renderer.use_ddgi,
+                        );
+
+                        let ddgi = &mut ctx.world_renderer.ddgi;
+
+                        imgui::Drag::<f32>::new(im_str!("Probe spacing"))
+                            .range(0.1..=10.0)
+                            .speed(0.01)
+                            .build(ui, &mut ddgi.grid.probe_spacing);
+
+                        imgui::Drag::<u32>::new(im_str!("Rays per probe"))
+                            .range(16..=256)
+                            .build(ui, &mut ddgi.rays_per_probe);
+
+                        imgui::Drag::<f32>::new(im_str!("Hysteresis"))
+                            .range(0.0..=0.99)
+                            .speed(0.001)
+                            .build(ui, &mut ddgi.hysteresis);
+
+                        imgui::Drag::<f32>::new(im_str!("Normal bias"))
+                            .range(0.0..=1.0)
+                            .speed(0.005)
+                            .build(ui, &mut ddgi.leak_reduction.normal_bias);
+
+                        imgui::Drag::<f32>::new(im_str!("View bias"))
+                            .range(0.0..=1.0)
+                            .speed(0.005)
+                            .build(ui, &mut ddgi.leak_reduction.view_bias);
+
+                        imgui::Drag::<f32>::new(im_str!("Visibility sharpness"))
+                            .range(0.0..=10.0)
+                            .speed(0.05)
+                            .build(ui, &mut ddgi.leak_reduction.visibility_sharpness);
+                    }
+
+                    if imgui::CollapsingHeader::new(im_str!("Denoisers"))
+                        .default_open(false)
+                        .build(ui)
+                    {
+                        let rtdgi = &mut ctx.world_renderer.rtdgi.denoiser;
+
+                        ui.checkbox(im_str!("Denoise GI"), &mut rtdgi.enabled);
+
+                        imgui::Drag::<f32>::new(im_str!("GI strength"))
+                            .range(0.0..=2.0)
+                            .speed(0.01)
+                            .build(ui, &mut rtdgi.strength);
+
+                        imgui::Drag::<f32>::new(im_str!("GI history frames"))
+                            .range(0.0..=64.0)
+                            .speed(0.1)
+                            .build(ui, &mut rtdgi.max_history_frames);
+
+                        let rtr = &mut ctx.world_renderer.rtr.denoiser;
+
+                        ui.checkbox(im_str!("Denoise reflections"), &mut rtr.enabled);
+
+                        imgui::Drag::<f32>::new(im_str!("Reflection strength"))
+                            .range(0.0..=2.0)
+                            .speed(0.01)
+                            .build(ui, &mut rtr.strength);
+
+                        imgui::Drag::<f32>::new(im_str!("Reflection history frames"))
+                            .range(0.0..=64.0)
+                            .speed(0.1)
+                            .build(ui, &mut rtr.max_history_frames);
+                    }
+
+                    if imgui::CollapsingHeader::new(im_str!("Motion blur"))
+                        .default_open(false)
+                        .build(ui)
+                    {
+                        ui.checkbox(
+                            im_str!("Enable motion blur"),
+                            &mut ctx.world_renderer.use_motion_blur,
+                        );
+
+                        imgui::Drag::<f32>::new(im_str!("Shutter"))
+                            .range(0.0..=1.0)
+                            .speed(0.01)
+                            .build(ui, &mut ctx.world_renderer.motion_blur.shutter);
+                    }
+
+                    if imgui::CollapsingHeader::new(im_str!("Sun shadow maps"))
+                        .default_open(false)
+                        .build(ui)
+                    {
+                        ui.checkbox(
+                            im_str!("Use shadow maps"),
+                            &mut ctx.world_renderer.use_sun_shadow_maps,
+                        );
+
+                        let csm = &mut ctx.world_renderer.csm.desc;
+
+                        let mut cascade_count = csm.cascade_count as u32;
+                        imgui::Drag::<u32>::new(im_str!("Cascades"))
+                            .range(1..=kajiya::renderers::csm::MAX_CASCADES as u32)
+                            .speed(0.05)
+                            .build(ui, &mut cascade_count);
+                        csm.cascade_count = cascade_count as usize;
+
+                        imgui::Drag::<f32>::new(im_str!("Logarithmic splits"))
+                            .range(0.0..=1.0)
+                            .speed(0.01)
+                            .build(ui, &mut csm.split_lambda);
+
+                        imgui::Drag::<f32>::new(im_str!("Max distance"))
+                            .range(1.0..=1000.0)
+                            .speed(1.0)
+                            .build(ui, &mut csm.max_distance);
+
+                        let mut pcss = csm.filter == ShadowFilter::Pcss;
+                        ui.checkbox(im_str!("Contact-hardening (PCSS)"), &mut pcss);
+                        csm.filter = if pcss {
+                            ShadowFilter::Pcss
+                        } else {
+                            ShadowFilter::Pcf
+                        };
+
+                        let mut static_cache = csm.update_policy == ShadowUpdatePolicy::Static;
+                        ui.checkbox(im_str!("Cache static shadows"), &mut static_cache);
+                        csm.update_policy = if static_cache {
+                            ShadowUpdatePolicy::Static
+                        } else {
+                            ShadowUpdatePolicy::Dynamic
+                        };
+
+                        let mut max_cascade_updates = csm.max_cascade_updates as u32;
+                        imgui::Drag::<u32>::new(im_str!("Cascade updates per frame"))
+                            .range(1..=kajiya::renderers::csm::MAX_CASCADES as u32)
+                            .speed(0.05)
+                            .build(ui, &mut max_cascade_updates);
+                        csm.max_cascade_updates = max_cascade_updates as usize;
+
+                        ui.text(format!(
+                            "Cascades updated: {}",
+                            ctx.world_renderer.csm.updated_cascade_count()
+                        ));
+                    }
+
+                    if color_grading
+                        && imgui::CollapsingHeader::new(im_str!("Color grading"))
+                            .default_open(false)
+                            .build(ui)
+                    {
+                        imgui::Drag::<f32>::new(im_str!("LUT strength"))
+                            .range(0.0..=1.0)
+                            .speed(0.01)
+                            .build(ui, &mut ctx.world_renderer.color_grading.strength);
+                    }
+
+                    if imgui::CollapsingHeader::new(im_str!("Lens"))
+                        .default_open(false)
+                        .build(ui)
+                    {
+                        let lens = &mut ctx.world_renderer.lens;
+
+                        imgui::Drag::<f32>::new(im_str!("Chromatic aberration"))
+                            .range(0.0..=0.05)
+                            .speed(0.0005)
+                            .build(ui, &mut lens.chromatic_aberration);
+
+                        imgui::Drag::<f32>::new(im_str!("Distortion k1"))
+                            .range(-0.5..=0.5)
+                            .speed(0.005)
+                            .build(ui, &mut lens.distortion_k1);
+
+                        imgui::Drag::<f32>::new(im_str!("Distortion k2"))
+                            .range(-0.5..=0.5)
+                            .speed(0.005)
+                            .build(ui, &mut lens.distortion_k2);
+                    }
+
+                    if imgui::CollapsingHeader::new(im_str!("Film grain"))
+                        .default_open(false)
+                        .build(ui)
+                    {
+                        let film_grain = &mut ctx.world_renderer.film_grain;
+
+                        ui.checkbox(im_str!("Dither"), &mut film_grain.dither);
+
+                        imgui::Drag::<f32>::new(im_str!("Grain intensity"))
+                            .range(0.0..=16.0)
+                            .speed(0.05)
+                            .build(ui, &mut film_grain.intensity);
+
+                        ui.checkbox(im_str!("Animated"), &mut film_grain.animated);
+                    }
+
+                    if imgui::CollapsingHeader::new(im_str!("Volumetric fog"))
+                        .default_open(false)
+                        .build(ui)
+                    {
+                        ui.checkbox(
+                            im_str!("Enable fog"),
+                            &mut ctx.world_renderer.use_volumetric_fog,
+                        );
+
+                        let fog = &mut ctx.world_renderer.volumetric_fog.params;
+
+                        imgui::Drag::<f32>::new(im_str!("Density"))
+                            .range(0.0..=1.0)
+                            .speed(0.001)
+                            .build(ui, &mut fog.density);
+
+                        let mut scattering: [f32; 3] = fog.scattering.into();
+                        if imgui::ColorEdit::new(im_str!("Scattering"), &mut scattering).build(ui) {
+                            fog.scattering = scattering.into();
+                        }
+
+                        imgui::Drag::<f32>::new(im_str!("Absorption"))
+                            .range(0.0..=1.0)
+                            .speed(0.001)
+                            .build(ui, &mut fog.absorption);
+
+                        imgui::Drag::<f32>::new(im_str!("Anisotropy"))
+                            .range(-0.95..=0.95)
+                            .speed(0.01)
+                            .build(ui, &mut fog.anisotropy);
+
+                        imgui::Drag::<f32>::new(im_str!("Max distance"))
+                            .range(1.0..=1000.0)
+                            .speed(0.5)
+                            .build(ui, &mut fog.max_distance);
+                    }
+
+                    if imgui::CollapsingHeader::new(im_str!("Atmosphere"))
+                        .default_open(false)
+                        .build(ui)
+                    {
+                        ui.checkbox(
+                            im_str!("Enable atmosphere"),
+                            &mut ctx.world_renderer.use_atmosphere,
+                        );
+
+                        let atmosphere = &mut ctx.world_renderer.atmosphere;
+
+                        imgui::Drag::<f32>::new(im_str!("World scale (km)"))
+                            .range(0.0..=1.0)
+                            .speed(0.0001)
+                            .build(ui, &mut atmosphere.world_scale);
+
+                        let sky = &mut atmosphere.params;
+
+                        // Shown per 1000 km, as the coefficients are tiny.
+                        let mut rayleigh: [f32; 3] = (sky.rayleigh_scattering * 1000.0).into();
+                        if imgui::Drag::<f32>::new(im_str!("Rayleigh scattering"))
+                            .range(0.0..=100.0)
+                            .speed(0.1)
+                            .build_array(ui, &mut rayleigh)
+                        {
+                            sky.rayleigh_scattering = Vec3::from(rayleigh) / 1000.0;
+                        }
+
+                        let mut mie = sky.mie_scattering * 1000.0;
+                        if imgui::Drag::<f32>::new(im_str!("Mie scattering"))
+                            .range(0.0..=100.0)
+                            .speed(0.1)
+                            .build(ui, &mut mie)
+                        {
+                            sky.mie_scattering = mie / 1000.0;
+                        }
+
+                        imgui::Drag::<f32>::new(im_str!("Mie anisotropy"))
+                            .range(-0.95..=0.95)
+                            .speed(0.01)
+                            .build(ui, &mut sky.mie_anisotropy);
+
+                        let mut ground_albedo: [f32; 3] = sky.ground_albedo.into();
+                        if imgui::ColorEdit::new(im_str!("Ground albedo"), &mut ground_albedo)
+                            .build(ui)
+                        {
+                            sky.ground_albedo = ground_albedo.into();
+                        }
+                    }
+
+                    if imgui::CollapsingHeader::new(im_str!("Transparency"))
+                        .default_open(false)
+                        .build(ui)
+                    {
+                        let transparency = &mut ctx.world_renderer.transparency;
+
+                        if ui.radio_button_bool(
+                            im_str!("Sorted"),
+                            transparency.mode == TransparencyMode::Sorted,
+                        ) {
+                            transparency.mode = TransparencyMode::Sorted;
+                        }
+
+                        if ui.radio_button_bool(
+                            im_str!("Weighted blended OIT"),
+                            transparency.mode == TransparencyMode::WeightedBlended,
+                        ) {
+                            transparency.mode = TransparencyMode::WeightedBlended;
+                        }
+
+                        let weight = &mut transparency.oit_weight;
+
+                        imgui::Drag::<f32>::new(im_str!("OIT depth scale"))
+                            .range(0.01..=100.0)
+                            .speed(0.05)
+                            .build(ui, &mut weight.depth_scale);
+
+                        imgui::Drag::<f32>::new(im_str!("OIT depth exponent"))
+                            .range(0.0..=8.0)
+                            .speed(0.05)
+                            .build(ui, &mut weight.depth_exponent);
+
+                        imgui::Drag::<f32>::new(im_str!("OIT min weight"))
+                            .range(0.0..=1.0)
+                            .speed(0.001)
+                            .build(ui, &mut weight.min_weight);
+
+                        imgui::Drag::<f32>::new(im_str!("OIT max weight"))
+                            .range(1.0..=10000.0)
+                            .speed(10.0)
+                            .build(ui, &mut weight.max_weight);
+                    }
+
+                    if imgui::CollapsingHeader::new(im_str!("Wireframe"))
+                        .default_open(false)
+                        .build(ui)
+                    {
+                        let wireframe = &mut ctx.world_renderer.wireframe;
+
+                        ui.checkbox(im_str!("All instances (F)"), &mut wireframe.enabled);
+
+                        let mut rgb: [f32; 3] = wireframe.color.into();
+                        if imgui::ColorEdit::new(im_str!("Color"), &mut rgb).build(ui) {
+                            wireframe.color = rgb.into();
+                        }
+
+                        imgui::Drag::<f32>::new(im_str!("Opacity"))
+                            .range(0.0..=1.0)
+                            .speed(0.01)
+                            .build(ui, &mut wireframe.opacity);
+
+                        imgui::Drag::<f32>::new(im_str!("Depth bias"))
+                            .range(0.0..=256.0)
+                            .speed(0.1)
+                            .build(ui, &mut wireframe.depth_bias[0]);
+
+                        imgui::Drag::<f32>::new(im_str!("Slope depth bias"))
+                            .range(0.0..=16.0)
+                            .speed(0.01)
+                            .build(ui, &mut wireframe.depth_bias[1]);
+                    }
+
+                    if imgui::CollapsingHeader::new(im_str!("Outline"))
+                        .default_open(false)
+                        .build(ui)
+                    {
+                        let outline = &mut ctx.world_renderer.outline;
+
+                        imgui::Drag::<f32>::new(im_str!("Width"))
+                            .range(0.0..=kajiya::renderers::outline::MAX_OUTLINE_WIDTH)
+                            .speed(0.05)
+                            .build(ui, &mut outline.width);
+
+                        imgui::Drag::<f32>::new(im_str!("Inner edges"))
+                            .range(0.0..=1.0)
+                            .speed(0.01)
+                            .build(ui, &mut outline.inner_edge_strength);
+
+                        for (i, color) in outline.highlight_colors.iter_mut().enumerate() {
+                            let mut rgb: [f32; 3] = (*color).into();
+                            if imgui::ColorEdit::new(&im_str!("Highlight {}", i), &mut rgb)
+                                .build(ui)
+                            {
+                                *color = rgb.into();
+                            }
+                        }
+                    }
+
                     /*if imgui::CollapsingHeader::new(im_str!("csgi"))
                         .default_open(true)
                         .build(ui)
@@ -501,6 +1072,14 @@ fn main() -> anyhow::Result<()> {
                             ctx.world_renderer.debug_mode = RenderDebugMode::CsgiRadiance;
                         }
 
+                        #[cfg(feature = "gi-debug")]
+                        if ui.radio_button_bool(
+                            im_str!("GI probes"),
+                            ctx.world_renderer.debug_mode == RenderDebugMode::DdgiProbes,
+                        ) {
+                            ctx.world_renderer.debug_mode = RenderDebugMode::DdgiProbes;
+                        }
+
                         imgui::ComboBox::new(im_str!("Shading")).build_simple_string(
                             ui,
                             &mut ctx.world_renderer.debug_shading_mode,
@@ -572,7 +1151,12 @@ fn main() -> anyhow::Result<()> {
                 });
             }
 
-            ctx.world_renderer.ev_shift = state.ev_shift;
+            ctx.world_renderer.set_exposure(match manual_exposure {
+                Some(ev) => Exposure::Manual(ev),
+                None => Exposure::Auto {
+                    compensation: state.ev_shift,
+                },
+            });
 
             frame_desc
         })?;
@@ -8,8 +8,17 @@ use crate::image::ImageSource;
 
 type BufferBytes = Bytes;
 
+/// The `extensions` object of each material, indexed like `Document::materials`,
+/// for extensions which the `gltf` crate doesn't parse.
+pub type MaterialExtensions = Vec<Option<gltf::json::Value>>;
+
 /// Return type of `import`.
-type Import = (Document, Vec<BufferBytes>, Vec<ImageSource>);
+type Import = (
+    Document,
+    Vec<BufferBytes>,
+    Vec<ImageSource>,
+    MaterialExtensions,
+);
 
 /// Represents the set of URI schemes the importer supports.
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
@@ -154,18 +163,45 @@ pub fn import_image_data(
     Ok(images)
 }
 
-fn import_impl(Gltf { document, blob }: Gltf, base: Option<&Path>) -> Result<Import> {
+/// Extracts `MaterialExtensions` from glTF JSON. Malformed JSON has none; `Gltf` reports the errors.
+pub fn material_extensions(json: &[u8]) -> MaterialExtensions {
+    let root: Option<gltf::json::Value> = std::str::from_utf8(json)
+        .ok()
+        .and_then(|json| json.parse().ok());
+
+    root.as_ref()
+        .and_then(|root| root.get("materials")?.as_array())
+        .map(|materials| {
+            materials
+                .iter()
+                .map(|material| material.get("extensions").cloned())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn import_impl(
+    Gltf { document, blob }: Gltf,
+    base: Option<&Path>,
+    material_extensions: MaterialExtensions,
+) -> Result<Import> {
     let buffer_data = import_buffer_data(&document, base, blob)?;
     let image_data = import_image_data(&document, base, &buffer_data)?;
-    let import = (document, buffer_data, image_data);
+    let import = (document, buffer_data, image_data, material_extensions);
     Ok(import)
 }
 
 fn import_path(path: &Path) -> Result<Import> {
     let base = path.parent().unwrap_or_else(|| Path::new("./"));
-    let file = fs::File::open(path).map_err(Error::Io)?;
-    let reader = io::BufReader::new(file);
-    import_impl(Gltf::from_reader(reader)?, Some(base))
+    let data = read_to_end(path)?;
+
+    // Binary glTF has its JSON in the first chunk
+    let material_extensions = match gltf::Glb::from_slice(&data) {
+        Ok(glb) => material_extensions(&glb.json),
+        Err(_) => material_extensions(&data),
+    };
+
+    import_impl(Gltf::from_slice(&data)?, Some(base), material_extensions)
 }
 
 /// Import some glTF 2.0 from the file system.
@@ -49,6 +49,18 @@ pub enum MeshMaterialMap {
 pub struct MeshMaterialFlags;
 impl MeshMaterialFlags {
     pub const MESH_MATERIAL_FLAG_EMISSIVE_USED_AS_LIGHT: u32 = 1;
+
+    /// Both sides are shaded, facing the viewer, and neither is culled.
+    pub const MESH_MATERIAL_FLAG_DOUBLE_SIDED: u32 = 2;
+
+    /// Surfaces with alpha below `MeshMaterial::alpha_cutoff` are cut out, as with glTF's `MASK` mode.
+    pub const MESH_MATERIAL_FLAG_ALPHA_CUTOFF: u32 = 4;
+
+    /// Parallax occlusion mapping, with heights in the normal map's alpha channel; see `gltf_material_parallax`.
+    pub const MESH_MATERIAL_FLAG_PARALLAX: u32 = 8;
+
+    /// Diffuse light scatters under the surface, blurred in screen space; see `gltf_material_subsurface`.
+    pub const MESH_MATERIAL_FLAG_SUBSURFACE: u32 = 16;
 }
 
 #[derive(Clone, Copy)]
@@ -61,6 +73,48 @@ pub struct MeshMaterial {
     pub emissive: [f32; 3],
     pub flags: u32,
     pub map_transforms: [[f32; 6]; 4],
+    pub alpha_cutoff: f32,
+
+    /// Depth of the height field, in UV units.
+    pub parallax_height_scale: f32,
+    pub parallax_max_steps: u32,
+
+    /// Stretches the specular highlight along the tangent rotated by `anisotropy_rotation`, from 0 to 1.
+    pub anisotropy: f32,
+
+    /// Radians, from the tangent towards the bitangent.
+    pub anisotropy_rotation: f32,
+
+    /// Tint of the light scattered under the surface, scaling `subsurface_radius` per channel.
+    pub subsurface_color: [f32; 3],
+
+    /// How far light travels under the surface before leaving it, in world units.
+    pub subsurface_radius: f32,
+
+    /// Blends the specular Fresnel term towards thin-film interference, from 0 (off) to 1.
+    pub iridescence: f32,
+    pub iridescence_ior: f32,
+
+    /// Thickness of the film, in nanometers.
+    pub iridescence_thickness: f32,
+}
+
+impl MeshMaterial {
+    pub fn is_double_sided(&self) -> bool {
+        self.flags & MeshMaterialFlags::MESH_MATERIAL_FLAG_DOUBLE_SIDED != 0
+    }
+
+    pub fn is_alpha_tested(&self) -> bool {
+        self.flags & MeshMaterialFlags::MESH_MATERIAL_FLAG_ALPHA_CUTOFF != 0
+    }
+
+    pub fn uses_parallax(&self) -> bool {
+        self.flags & MeshMaterialFlags::MESH_MATERIAL_FLAG_PARALLAX != 0
+    }
+
+    pub fn uses_subsurface(&self) -> bool {
+        self.flags & MeshMaterialFlags::MESH_MATERIAL_FLAG_SUBSURFACE != 0
+    }
 }
 
 #[derive(Clone, Default)]
@@ -101,6 +155,7 @@ fn get_gltf_texture_source(tex: gltf::texture::Texture) -> Option<String> {
 fn load_gltf_material(
     mat: &gltf::material::Material,
     document_images: &[ImageSource],
+    extensions: Option<&gltf::json::Value>,
 ) -> (Vec<MeshMaterialMap>, MeshMaterial) {
     const DEFAULT_MAP_TRANSFORM: [f32; 6] = [1.0, 0.0, 0.0, 1.0, 0.0, 0.0];
     let mut map_transforms: [[f32; 6]; 4] = [DEFAULT_MAP_TRANSFORM; 4];
@@ -209,6 +264,39 @@ fn load_gltf_material(
 
     //mata.normal_texture().and_then(|tex| tex.transform())
 
+    let mut flags = 0;
+    if mat.double_sided() {
+        flags |= MeshMaterialFlags::MESH_MATERIAL_FLAG_DOUBLE_SIDED;
+    }
+
+    let alpha_cutoff = if mat.alpha_mode() == gltf::material::AlphaMode::Mask {
+        flags |= MeshMaterialFlags::MESH_MATERIAL_FLAG_ALPHA_CUTOFF;
+        mat.alpha_cutoff().unwrap_or(0.5)
+    } else {
+        0.0
+    };
+
+    let (parallax_height_scale, parallax_max_steps) =
+        if let Some(parallax) = gltf_material_parallax(mat) {
+            flags |= MeshMaterialFlags::MESH_MATERIAL_FLAG_PARALLAX;
+            parallax
+        } else {
+            (0.0, 0)
+        };
+
+    let (anisotropy, anisotropy_rotation) = gltf_material_anisotropy(mat).unwrap_or((0.0, 0.0));
+
+    let (subsurface_color, subsurface_radius) =
+        if let Some(subsurface) = gltf_material_subsurface(mat) {
+            flags |= MeshMaterialFlags::MESH_MATERIAL_FLAG_SUBSURFACE;
+            subsurface
+        } else {
+            ([0.0; 3], 0.0)
+        };
+
+    let (iridescence, iridescence_ior, iridescence_thickness) =
+        gltf_material_iridescence(extensions).unwrap_or((0.0, 1.0, 0.0));
+
     (
         vec![normal_map, spec_map, albedo_map, emissive_map],
         MeshMaterial {
@@ -217,12 +305,100 @@ fn load_gltf_material(
             roughness_mult,
             metalness_factor,
             emissive,
-            flags: 0,
+            flags,
             map_transforms,
+            alpha_cutoff,
+            parallax_height_scale,
+            parallax_max_steps,
+            anisotropy,
+            anisotropy_rotation,
+            subsurface_color,
+            subsurface_radius,
+            iridescence,
+            iridescence_ior,
+            iridescence_thickness,
         },
     )
 }
 
+fn gltf_material_extras(mat: &gltf::material::Material) -> Option<gltf::json::Value> {
+    mat.extras().as_ref()?.get().parse().ok()
+}
+
+/// glTF has no parallax mapping, so it's opted into in the material's extras, as in
+/// `"extras": { "parallax": { "height_scale": 0.05, "max_steps": 32 } }`.
+/// Heights are read from the normal map's alpha, with 1.0 at the surface, and 0.0 `height_scale` below it.
+fn gltf_material_parallax(mat: &gltf::material::Material) -> Option<(f32, u32)> {
+    const DEFAULT_MAX_STEPS: u64 = 32;
+
+    let extras = gltf_material_extras(mat)?;
+    let parallax = extras.get("parallax")?;
+
+    let height_scale = parallax.get("height_scale")?.as_f64()? as f32;
+    let max_steps = parallax
+        .get("max_steps")
+        .and_then(|steps| steps.as_u64())
+        .unwrap_or(DEFAULT_MAX_STEPS)
+        .clamp(1, 256) as u32;
+
+    (height_scale > 0.0).then(|| (height_scale, max_steps))
+}
+
+/// Anisotropic specular, from the material's extras, following `KHR_materials_anisotropy`
+/// (which the `gltf` crate doesn't support), but without its direction texture:
+/// `"extras": { "anisotropy": { "strength": 0.8, "rotation": 1.57 } }`.
+fn gltf_material_anisotropy(mat: &gltf::material::Material) -> Option<(f32, f32)> {
+    let extras = gltf_material_extras(mat)?;
+    let anisotropy = extras.get("anisotropy")?;
+
+    let strength = anisotropy.get("strength")?.as_f64()? as f32;
+    let rotation = anisotropy
+        .get("rotation")
+        .and_then(|rotation| rotation.as_f64())
+        .unwrap_or(0.0) as f32;
+
+    // Fully stretched lobes have no width across, so stop just short of that.
+    Some((strength.clamp(0.0, 0.95), rotation))
+}
+
+/// Subsurface scattering for skin, wax and the like, from the material's extras:
+/// `"extras": { "subsurface": { "radius": 0.01, "color": [1.0, 0.35, 0.2] } }`.
+/// `radius` is in world units, and `color` (white by default) scales it per channel,
+/// so that e.g. red light travels further in skin.
+fn gltf_material_subsurface(mat: &gltf::material::Material) -> Option<([f32; 3], f32)> {
+    let extras = gltf_material_extras(mat)?;
+    let subsurface = extras.get("subsurface")?;
+
+    let radius = subsurface.get("radius")?.as_f64()? as f32;
+    let mut color = [1.0f32; 3];
+    if let Some(channels) = subsurface.get("color").and_then(|color| color.as_array()) {
+        for (dst, src) in color.iter_mut().zip(channels) {
+            *dst = src.as_f64().unwrap_or(1.0).max(0.0) as f32;
+        }
+    }
+
+    (radius > 0.0).then(|| (color, radius))
+}
+
+/// Thin-film iridescence from `KHR_materials_iridescence`, which the `gltf` crate doesn't parse.
+/// Its textures aren't supported, so the film is `iridescenceThicknessMaximum` thick throughout,
+/// as the extension specifies in the absence of a thickness texture.
+fn gltf_material_iridescence(extensions: Option<&gltf::json::Value>) -> Option<(f32, f32, f32)> {
+    let iridescence = extensions?.get("KHR_materials_iridescence")?;
+    let get = |name: &str, default: f64| {
+        iridescence
+            .get(name)
+            .and_then(|value| value.as_f64())
+            .unwrap_or(default) as f32
+    };
+
+    let factor = get("iridescenceFactor", 0.0);
+    let ior = get("iridescenceIor", 1.3);
+    let thickness = get("iridescenceThicknessMaximum", 400.0);
+
+    (factor > 0.0).then(|| (factor.min(1.0), ior, thickness.max(0.0)))
+}
+
 #[derive(Clone)]
 pub struct LoadGltfScene {
     pub path: PathBuf,
@@ -246,7 +422,7 @@ impl LazyWorker for LoadGltfScene {
     type Output = anyhow::Result<TriangleMesh>;
 
     async fn run(self, _ctx: RunContext) -> Self::Output {
-        let (gltf, buffers, imgs) = crate::import_gltf::import(&self.path)
+        let (gltf, buffers, imgs, material_extensions) = crate::import_gltf::import(&self.path)
             .with_context(|| format!("Loading GLTF scene from {:?}", self.path))?;
 
         if let Some(scene) = gltf.default_scene().or_else(|| gltf.scenes().next()) {
@@ -262,8 +438,13 @@ impl LazyWorker for LoadGltfScene {
                         let res_material_index = res.materials.len() as u32;
 
                         {
-                            let (mut maps, mut material) =
-                                load_gltf_material(&prim.material(), imgs.as_slice());
+                            let (mut maps, mut material) = load_gltf_material(
+                                &prim.material(),
+                                imgs.as_slice(),
+                                prim.material()
+                                    .index()
+                                    .and_then(|idx| material_extensions.get(idx)?.as_ref()),
+                            );
 
                             let map_base = res.maps.len() as u32;
                             for id in material.maps.iter_mut() {
@@ -872,3 +1053,208 @@ impl<'a> mikktspace::Geometry for TangentCalcContext<'a> {
         self.tangents[self.indices[face * 3 + vert] as usize] = tangent;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load_only_material(gltf_json: &str) -> MeshMaterial {
+        let gltf = gltf::Gltf::from_slice(gltf_json.as_bytes()).unwrap();
+        let mat = gltf.materials().next().unwrap();
+        let extensions = crate::import_gltf::material_extensions(gltf_json.as_bytes());
+        load_gltf_material(&mat, &[], extensions.first().and_then(Option::as_ref)).1
+    }
+
+    #[test]
+    fn generated_tangents_follow_the_uv_directions() {
+        // A unit quad facing +Z, with U along +X and V along -Y, like in glTF
+        let positions = [
+            [0.0, 0.0, 0.0],
+            [1.0, 0.0, 0.0],
+            [1.0, 1.0, 0.0],
+            [0.0, 1.0, 0.0],
+        ];
+        let normals = [[0.0, 0.0, 1.0]; 4];
+        let uvs = [[0.0, 1.0], [1.0, 1.0], [1.0, 0.0], [0.0, 0.0]];
+        let indices = [0, 1, 2, 0, 2, 3];
+        let mut tangents = [[0.0; 4]; 4];
+
+        mikktspace::generate_tangents(&mut TangentCalcContext {
+            indices: &indices,
+            positions: &positions,
+            normals: &normals,
+            uvs: &uvs,
+            tangents: &mut tangents,
+        });
+
+        // `mesh.hlsl` perturbs normals along these; a bump's slope along +U then tilts the normal towards +X.
+        for tangent in tangents {
+            assert!(Vec3::new(tangent[0], tangent[1], tangent[2]).abs_diff_eq(Vec3::X, 1e-5));
+
+            // ... and with V along -Y, the bitangent `cross(normal, tangent) * w` must point there too.
+            let bitangent = Vec3::Z.cross(Vec3::X) * tangent[3];
+            assert!(bitangent.abs_diff_eq(-Vec3::Y, 1e-5));
+        }
+    }
+
+    #[test]
+    fn gltf_mask_and_double_sided_become_material_flags() {
+        let leaf = load_only_material(
+            r#"{
+                "asset": { "version": "2.0" },
+                "materials": [{ "doubleSided": true, "alphaMode": "MASK", "alphaCutoff": 0.3 }]
+            }"#,
+        );
+        assert!(leaf.is_double_sided());
+        assert!(leaf.is_alpha_tested());
+        assert_eq!(leaf.alpha_cutoff, 0.3);
+
+        // glTF's default cutoff
+        let mask = load_only_material(
+            r#"{
+                "asset": { "version": "2.0" },
+                "materials": [{ "alphaMode": "MASK" }]
+            }"#,
+        );
+        assert!(!mask.is_double_sided());
+        assert!(mask.is_alpha_tested());
+        assert_eq!(mask.alpha_cutoff, 0.5);
+
+        let opaque = load_only_material(
+            r#"{
+                "asset": { "version": "2.0" },
+                "materials": [{ "alphaMode": "OPAQUE" }]
+            }"#,
+        );
+        assert_eq!(opaque.flags, 0);
+    }
+
+    #[test]
+    fn gltf_extras_opt_into_parallax() {
+        let bricks = load_only_material(
+            r#"{
+                "asset": { "version": "2.0" },
+                "materials": [{ "extras": { "parallax": { "height_scale": 0.04, "max_steps": 16 } } }]
+            }"#,
+        );
+        assert!(bricks.uses_parallax());
+        assert_eq!(bricks.parallax_height_scale, 0.04);
+        assert_eq!(bricks.parallax_max_steps, 16);
+
+        let default_steps = load_only_material(
+            r#"{
+                "asset": { "version": "2.0" },
+                "materials": [{ "extras": { "parallax": { "height_scale": 0.04 } } }]
+            }"#,
+        );
+        assert!(default_steps.uses_parallax());
+        assert_eq!(default_steps.parallax_max_steps, 32);
+
+        // Other extras, and flat height fields, stay on the fast path
+        for extras in [
+            r#"{ "author": "someone" }"#,
+            r#"{ "parallax": { "height_scale": 0.0 } }"#,
+        ] {
+            let flat = load_only_material(&format!(
+                r#"{{
+                    "asset": {{ "version": "2.0" }},
+                    "materials": [{{ "extras": {} }}]
+                }}"#,
+                extras
+            ));
+            assert!(!flat.uses_parallax());
+        }
+    }
+
+    #[test]
+    fn gltf_extras_set_anisotropy() {
+        let brushed = load_only_material(
+            r#"{
+                "asset": { "version": "2.0" },
+                "materials": [{ "extras": { "anisotropy": { "strength": 0.8, "rotation": 1.5 } } }]
+            }"#,
+        );
+        assert_eq!(brushed.anisotropy, 0.8);
+        assert_eq!(brushed.anisotropy_rotation, 1.5);
+
+        let clamped = load_only_material(
+            r#"{
+                "asset": { "version": "2.0" },
+                "materials": [{ "extras": { "anisotropy": { "strength": 2.0 } } }]
+            }"#,
+        );
+        assert_eq!(clamped.anisotropy, 0.95);
+        assert_eq!(clamped.anisotropy_rotation, 0.0);
+    }
+
+    #[test]
+    fn gltf_extras_opt_into_subsurface() {
+        let skin = load_only_material(
+            r#"{
+                "asset": { "version": "2.0" },
+                "materials": [{ "extras": { "subsurface": { "radius": 0.01, "color": [1.0, 0.35, 0.2] } } }]
+            }"#,
+        );
+        assert!(skin.uses_subsurface());
+        assert_eq!(skin.subsurface_radius, 0.01);
+        assert_eq!(skin.subsurface_color, [1.0, 0.35, 0.2]);
+
+        let wax = load_only_material(
+            r#"{
+                "asset": { "version": "2.0" },
+                "materials": [{ "extras": { "subsurface": { "radius": 0.02 } } }]
+            }"#,
+        );
+        assert!(wax.uses_subsurface());
+        assert_eq!(wax.subsurface_color, [1.0; 3]);
+
+        let opaque = load_only_material(
+            r#"{
+                "asset": { "version": "2.0" },
+                "materials": [{ "extras": { "subsurface": { "radius": 0.0 } } }]
+            }"#,
+        );
+        assert!(!opaque.uses_subsurface());
+    }
+
+    #[test]
+    fn gltf_iridescence_extension_is_parsed() {
+        let bubble = load_only_material(
+            r#"{
+                "asset": { "version": "2.0" },
+                "extensionsUsed": ["KHR_materials_iridescence"],
+                "materials": [{
+                    "extensions": {
+                        "KHR_materials_iridescence": {
+                            "iridescenceFactor": 1.0,
+                            "iridescenceIor": 1.33,
+                            "iridescenceThicknessMaximum": 550.0
+                        }
+                    }
+                }]
+            }"#,
+        );
+        assert_eq!(bubble.iridescence, 1.0);
+        assert_eq!(bubble.iridescence_ior, 1.33);
+        assert_eq!(bubble.iridescence_thickness, 550.0);
+
+        // Defaults from the extension, which enables nothing without a factor
+        let coated = load_only_material(
+            r#"{
+                "asset": { "version": "2.0" },
+                "materials": [{ "extensions": { "KHR_materials_iridescence": { "iridescenceFactor": 0.5 } } }]
+            }"#,
+        );
+        assert_eq!(coated.iridescence, 0.5);
+        assert_eq!(coated.iridescence_ior, 1.3);
+        assert_eq!(coated.iridescence_thickness, 400.0);
+
+        let plain = load_only_material(
+            r#"{
+                "asset": { "version": "2.0" },
+                "materials": [{ "extensions": { "KHR_materials_iridescence": {} } }]
+            }"#,
+        );
+        assert_eq!(plain.iridescence, 0.0);
+    }
+}
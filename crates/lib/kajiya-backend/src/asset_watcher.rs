@@ -0,0 +1,301 @@
+// A single file watcher for shaders, scenes, and other assets, with debouncing.
+//
+// Editors tend to emit several events per save (truncate, write, close, sometimes a rename);
+// each watched path is only reported once its events stop arriving for the debounce delay.
+
+use anyhow::Context as _;
+use lazy_static::lazy_static;
+use notify::Watcher as _;
+use parking_lot::Mutex;
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc},
+    time::{Duration, Instant},
+};
+
+lazy_static! {
+    static ref ASSET_WATCHER: AssetWatcher =
+        AssetWatcher::new(Duration::from_millis(100)).expect("AssetWatcher");
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct AssetWatchId(u64);
+
+// Returns false to unsubscribe.
+type Callback = Box<dyn FnMut() -> bool + Send>;
+
+struct Subscription {
+    id: AssetWatchId,
+    path: PathBuf,
+    callback: Callback,
+}
+
+#[derive(Default)]
+struct Subscribers {
+    subscriptions: Vec<Subscription>,
+    next_id: u64,
+}
+
+impl Subscribers {
+    fn add(&mut self, path: PathBuf, callback: Callback) -> AssetWatchId {
+        let id = AssetWatchId(self.next_id);
+        self.next_id += 1;
+        self.subscriptions.push(Subscription { id, path, callback });
+        id
+    }
+
+    // Calls the subscribers of `changed_path`, or of any of its parent directories.
+    fn notify(&mut self, changed_path: &Path) {
+        let mut idx = 0;
+        while idx < self.subscriptions.len() {
+            let sub = &mut self.subscriptions[idx];
+            if changed_path.starts_with(&sub.path) && !(sub.callback)() {
+                self.subscriptions.remove(idx);
+            } else {
+                idx += 1;
+            }
+        }
+    }
+}
+
+/// Watches files and directories for changes, and notifies the subscribers of each path
+/// once per burst of events. Callbacks run on the watcher thread, and must not call back into
+/// the watcher.
+///
+/// `AssetWatcher::global` is shared by the shader pipelines (through `LoadFile`), the Rust shader
+/// builder, and scene hot-reload, so that they don't each spawn a watcher.
+pub struct AssetWatcher {
+    watcher: Mutex<notify::RecommendedWatcher>,
+    watched_paths: Mutex<HashSet<PathBuf>>,
+    subscribers: Arc<Mutex<Subscribers>>,
+}
+
+impl AssetWatcher {
+    pub fn global() -> &'static AssetWatcher {
+        &ASSET_WATCHER
+    }
+
+    /// Paths are reported once no event arrives for `debounce_delay`.
+    pub fn new(debounce_delay: Duration) -> anyhow::Result<Self> {
+        let (tx, rx) = mpsc::channel();
+        let watcher = notify::raw_watcher(tx).context("Creating the file watcher")?;
+        let subscribers: Arc<Mutex<Subscribers>> = Default::default();
+
+        {
+            let subscribers = subscribers.clone();
+            std::thread::Builder::new()
+                .name("asset watcher".to_owned())
+                .spawn(move || watcher_thread(rx, Debouncer::new(debounce_delay), subscribers))?;
+        }
+
+        Ok(Self {
+            watcher: Mutex::new(watcher),
+            watched_paths: Default::default(),
+            subscribers,
+        })
+    }
+
+    /// Calls `on_change` after every change to the file at `path`, or to anything inside
+    /// the directory at `path`, until `unwatch` is called.
+    pub fn watch(
+        &self,
+        path: impl Into<PathBuf>,
+        mut on_change: impl FnMut() + Send + 'static,
+    ) -> anyhow::Result<AssetWatchId> {
+        self.subscribe(
+            path.into(),
+            Box::new(move || {
+                on_change();
+                true
+            }),
+        )
+    }
+
+    /// Calls `on_change` after the next change to `path`. Suits invalidation triggers,
+    /// which are subscribed again when their asset is reloaded.
+    pub fn watch_once(
+        &self,
+        path: impl Into<PathBuf>,
+        on_change: impl FnOnce() + Send + 'static,
+    ) -> anyhow::Result<AssetWatchId> {
+        let mut on_change = Some(on_change);
+        self.subscribe(
+            path.into(),
+            Box::new(move || {
+                if let Some(on_change) = on_change.take() {
+                    on_change();
+                }
+                false
+            }),
+        )
+    }
+
+    pub fn unwatch(&self, id: AssetWatchId) {
+        self.subscribers
+            .lock()
+            .subscriptions
+            .retain(|sub| sub.id != id);
+    }
+
+    fn subscribe(&self, path: PathBuf, callback: Callback) -> anyhow::Result<AssetWatchId> {
+        // Events are reported with canonical paths.
+        let path = path
+            .canonicalize()
+            .with_context(|| format!("AssetWatcher: trying to watch {:?}", path))?;
+
+        let mut watched_paths = self.watched_paths.lock();
+        if !watched_paths.contains(&path) {
+            self.watcher
+                .lock()
+                .watch(&path, notify::RecursiveMode::Recursive)
+                .with_context(|| format!("AssetWatcher: trying to watch {:?}", path))?;
+            watched_paths.insert(path.clone());
+        }
+
+        Ok(self.subscribers.lock().add(path, callback))
+    }
+}
+
+fn watcher_thread(
+    rx: mpsc::Receiver<notify::RawEvent>,
+    mut debouncer: Debouncer,
+    subscribers: Arc<Mutex<Subscribers>>,
+) {
+    loop {
+        let event = match debouncer.next_deadline() {
+            Some(deadline) => {
+                match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                    Ok(event) => Some(event),
+                    Err(mpsc::RecvTimeoutError::Timeout) => None,
+                    Err(mpsc::RecvTimeoutError::Disconnected) => return,
+                }
+            }
+            None => match rx.recv() {
+                Ok(event) => Some(event),
+                Err(_) => return,
+            },
+        };
+
+        if let Some(notify::RawEvent {
+            path: Some(path),
+            op: Ok(op),
+            ..
+        }) = event
+        {
+            if op.intersects(
+                notify::Op::WRITE
+                    | notify::Op::CLOSE_WRITE
+                    | notify::Op::CREATE
+                    | notify::Op::RENAME,
+            ) {
+                debouncer.event(path, Instant::now());
+            }
+        }
+
+        for path in debouncer.take_ready(Instant::now()) {
+            subscribers.lock().notify(&path);
+        }
+    }
+}
+
+// Coalesces bursts of events per path.
+struct Debouncer {
+    delay: Duration,
+    deadlines: HashMap<PathBuf, Instant>,
+}
+
+impl Debouncer {
+    fn new(delay: Duration) -> Self {
+        Self {
+            delay,
+            deadlines: Default::default(),
+        }
+    }
+
+    // Every event pushes the report of its path back.
+    fn event(&mut self, path: PathBuf, now: Instant) {
+        self.deadlines.insert(path, now + self.delay);
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.deadlines.values().min().copied()
+    }
+
+    fn take_ready(&mut self, now: Instant) -> Vec<PathBuf> {
+        let ready: Vec<PathBuf> = self
+            .deadlines
+            .iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(path, _)| path.clone())
+            .collect();
+
+        for path in &ready {
+            self.deadlines.remove(path);
+        }
+
+        ready
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn rapid_changes_notify_once() {
+        let notifications = Arc::new(AtomicUsize::new(0));
+        let once_notifications = Arc::new(AtomicUsize::new(0));
+
+        let mut subscribers = Subscribers::default();
+        subscribers.add(PathBuf::from("/assets/scenes"), {
+            let notifications = notifications.clone();
+            Box::new(move || {
+                notifications.fetch_add(1, Ordering::Relaxed);
+                true
+            })
+        });
+        subscribers.add(PathBuf::from("/assets/scenes/sample.ron"), {
+            let once_notifications = once_notifications.clone();
+            Box::new(move || {
+                once_notifications.fetch_add(1, Ordering::Relaxed);
+                false
+            })
+        });
+        subscribers.add(PathBuf::from("/assets/shaders"), Box::new(|| panic!()));
+
+        let mut debouncer = Debouncer::new(Duration::from_millis(100));
+        let path = PathBuf::from("/assets/scenes/sample.ron");
+        let t0 = Instant::now();
+        let ms = |ms| t0 + Duration::from_millis(ms);
+
+        // An editor saving the file: several events within a few milliseconds
+        for t in [0, 2, 3, 10, 30] {
+            debouncer.event(path.clone(), ms(t));
+            assert!(debouncer.take_ready(ms(t)).is_empty());
+        }
+
+        assert_eq!(debouncer.next_deadline(), Some(ms(130)));
+        assert!(debouncer.take_ready(ms(129)).is_empty());
+
+        let ready = debouncer.take_ready(ms(130));
+        assert_eq!(ready, vec![path.clone()]);
+        for path in &ready {
+            subscribers.notify(path);
+        }
+
+        assert_eq!(notifications.load(Ordering::Relaxed), 1);
+        assert_eq!(once_notifications.load(Ordering::Relaxed), 1);
+        assert!(debouncer.take_ready(ms(1000)).is_empty());
+
+        // A later save notifies again, but not the `watch_once`-style subscriber.
+        debouncer.event(path.clone(), ms(2000));
+        for path in debouncer.take_ready(ms(2100)) {
+            subscribers.notify(&path);
+        }
+
+        assert_eq!(notifications.load(Ordering::Relaxed), 2);
+        assert_eq!(once_notifications.load(Ordering::Relaxed), 1);
+    }
+}
@@ -6,7 +6,6 @@ use std::mem::{align_of, size_of};
 use vulkan::buffer::Buffer;
 
 pub const DYNAMIC_CONSTANTS_SIZE_BYTES: usize = 1024 * 1024 * 16;
-pub const DYNAMIC_CONSTANTS_BUFFER_COUNT: usize = 2;
 
 // Generally supported minimum uniform buffer size across vendors (maxUniformBufferRange)
 // Could be bumped to 65536 if needed.
@@ -24,19 +23,25 @@ pub struct DynamicConstants {
     pub buffer: Buffer,
     frame_offset_bytes: usize,
     frame_parity: usize,
+    buffer_count: usize,
 }
 
 impl DynamicConstants {
-    pub fn new(buffer: Buffer) -> Self {
+    /// `buffer` must hold `buffer_count * DYNAMIC_CONSTANTS_SIZE_BYTES`;
+    /// one region per frame in flight.
+    pub fn new(buffer: Buffer, buffer_count: usize) -> Self {
+        assert!(buffer.desc.size >= buffer_count * DYNAMIC_CONSTANTS_SIZE_BYTES);
+
         Self {
             buffer,
             frame_offset_bytes: 0,
             frame_parity: 0,
+            buffer_count,
         }
     }
 
     pub fn advance_frame(&mut self) {
-        self.frame_parity = (self.frame_parity + 1) % DYNAMIC_CONSTANTS_BUFFER_COUNT;
+        self.frame_parity = (self.frame_parity + 1) % self.buffer_count;
         self.frame_offset_bytes = 0;
     }
 
@@ -20,3 +20,135 @@ impl From<ash::vk::Result> for BackendError {
         }
     }
 }
+
+/// Errors returned from the public API, classified so that applications can react to them.
+///
+/// Internally, most code uses `anyhow`; converting from `anyhow::Error` looks through
+/// the error chain for a known cause, and falls back to `Other`.
+#[derive(Debug, thiserror::Error)]
+pub enum KajiyaError {
+    #[error("Failed to compile shader {path}:\n{message}")]
+    ShaderCompile { path: String, message: String },
+
+    #[error("Failed to create pipeline {name}")]
+    PipelineCreation {
+        name: String,
+        #[source]
+        source: anyhow::Error,
+    },
+
+    #[error("Push constants at offset {offset} with size {size} don't fit the pipeline's {pipeline_bytes} byte range")]
+    PushConstantsOutOfRange {
+        offset: u32,
+        size: usize,
+        pipeline_bytes: usize,
+    },
+
+    #[error(
+        "Binding {name:?} is {descriptor_type} in the shader; the provided resource doesn't match"
+    )]
+    BindingTypeMismatch {
+        name: String,
+        descriptor_type: String,
+    },
+
+    #[error("The GPU device has been lost")]
+    DeviceLost,
+
+    #[error("Out of memory")]
+    OutOfMemory {
+        #[source]
+        source: BackendError,
+    },
+
+    #[error("Unsupported: {0}")]
+    Unsupported(String),
+
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+impl From<BackendError> for KajiyaError {
+    fn from(err: BackendError) -> Self {
+        match &err {
+            BackendError::Vulkan {
+                err: ash::vk::Result::ERROR_DEVICE_LOST,
+                ..
+            } => Self::DeviceLost,
+            BackendError::Vulkan {
+                err:
+                    ash::vk::Result::ERROR_OUT_OF_DEVICE_MEMORY
+                    | ash::vk::Result::ERROR_OUT_OF_HOST_MEMORY,
+                ..
+            }
+            | BackendError::Allocation {
+                inner: gpu_allocator::AllocationError::OutOfMemory,
+                ..
+            } => Self::OutOfMemory { source: err },
+            _ => Self::Other(err.into()),
+        }
+    }
+}
+
+impl From<ash::vk::Result> for KajiyaError {
+    fn from(err: ash::vk::Result) -> Self {
+        BackendError::from(err).into()
+    }
+}
+
+impl From<anyhow::Error> for KajiyaError {
+    fn from(err: anyhow::Error) -> Self {
+        // Errors which already are classified lose their context here, but preserve their type.
+        if err.is::<KajiyaError>() {
+            return err.downcast::<KajiyaError>().unwrap();
+        }
+
+        if err.is::<BackendError>() {
+            return err.downcast::<BackendError>().unwrap().into();
+        }
+
+        let cause = err.chain().find_map(|cause| {
+            if let Some(err) = cause.downcast_ref::<KajiyaError>() {
+                match err {
+                    KajiyaError::ShaderCompile { path, message } => {
+                        Some(KajiyaError::ShaderCompile {
+                            path: path.clone(),
+                            message: message.clone(),
+                        })
+                    }
+                    KajiyaError::PushConstantsOutOfRange {
+                        offset,
+                        size,
+                        pipeline_bytes,
+                    } => Some(KajiyaError::PushConstantsOutOfRange {
+                        offset: *offset,
+                        size: *size,
+                        pipeline_bytes: *pipeline_bytes,
+                    }),
+                    KajiyaError::BindingTypeMismatch {
+                        name,
+                        descriptor_type,
+                    } => Some(KajiyaError::BindingTypeMismatch {
+                        name: name.clone(),
+                        descriptor_type: descriptor_type.clone(),
+                    }),
+                    KajiyaError::DeviceLost => Some(KajiyaError::DeviceLost),
+                    KajiyaError::Unsupported(feature) => {
+                        Some(KajiyaError::Unsupported(feature.clone()))
+                    }
+                    _ => None,
+                }
+            } else if let Some(BackendError::Vulkan {
+                err: ash::vk::Result::ERROR_DEVICE_LOST,
+                ..
+            }) = cause.downcast_ref::<BackendError>()
+            {
+                Some(KajiyaError::DeviceLost)
+            } else {
+                None
+            }
+        });
+
+        cause.unwrap_or(Self::Other(err))
+    }
+}
@@ -1,16 +1,12 @@
 use anyhow::Context as _;
 use bytes::Bytes;
-use hotwatch::Hotwatch;
 use lazy_static::lazy_static;
 use normpath::PathExt;
 use parking_lot::Mutex;
 use std::{collections::HashMap, fs::File, path::PathBuf};
 use turbosloth::*;
 
-lazy_static! {
-    pub(crate) static ref FILE_WATCHER: Mutex<Hotwatch> =
-        Mutex::new(Hotwatch::new_with_custom_delay(std::time::Duration::from_millis(100)).unwrap());
-}
+use crate::asset_watcher::AssetWatcher;
 
 lazy_static! {
     static ref VFS_MOUNT_POINTS: Mutex<HashMap<String, PathBuf>> = Mutex::new(
@@ -122,15 +118,8 @@ impl LazyWorker for LoadFile {
     type Output = anyhow::Result<Bytes>;
 
     async fn run(self, ctx: RunContext) -> Self::Output {
-        let invalidation_trigger = ctx.get_invalidation_trigger();
-
-        FILE_WATCHER
-            .lock()
-            .watch(self.path.clone(), move |event| {
-                if matches!(event, hotwatch::Event::Write(_)) {
-                    invalidation_trigger();
-                }
-            })
+        AssetWatcher::global()
+            .watch_once(self.path.clone(), ctx.get_invalidation_trigger())
             .with_context(|| format!("LoadFile: trying to watch {:?}", self.path))?;
 
         let mut buffer = Vec::new();
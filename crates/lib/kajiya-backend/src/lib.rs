@@ -1,3 +1,4 @@
+pub mod asset_watcher;
 pub mod bytes;
 pub mod chunky_list;
 pub mod dynamic_constants;
@@ -7,13 +8,24 @@ pub mod gpu_profiler;
 pub mod pipeline_cache;
 pub mod rust_shader_compiler;
 pub mod shader_compiler;
+pub mod shader_variants;
+pub mod struct_layout;
 pub mod transient_resource_cache;
 pub mod vulkan;
 
 pub use ash;
-pub use error::BackendError;
+pub use error::{BackendError, KajiyaError};
 pub use file::{canonical_path_from_vfs, normalized_path_from_vfs, set_vfs_mount_point};
 pub use gpu_allocator;
 pub use rspirv_reflect;
 pub use vk_sync;
-pub use vulkan::{device::Device, image::*, shader::MAX_DESCRIPTOR_SETS, RenderBackend};
+pub use vulkan::{
+    descriptor_pool::{DescriptorPoolConfig, DescriptorPoolStats},
+    device::Device,
+    external_memory::{ExportedMemory, ExternalMemoryHandle},
+    image::*,
+    instance::{ValidationConfig, ValidationLevel},
+    shader::MAX_DESCRIPTOR_SETS,
+    swapchain::{PresentMode, SwapchainColorSpace},
+    HeadlessRenderBackend, HeadlessRenderBackendConfig, RenderBackend,
+};
@@ -1,14 +1,26 @@
 use crate::{
     rust_shader_compiler::CompileRustShader,
     shader_compiler::{CompileShader, CompiledShader},
+    shader_variants::{pipeline_shader_variants, ShaderFeatures, ShaderVariant},
     vulkan::{
-        ray_tracing::{create_ray_tracing_pipeline, RayTracingPipeline, RayTracingPipelineDesc},
+        memory_stats::ResourceCategory,
+        ray_tracing::{
+            create_linked_ray_tracing_pipeline, create_ray_tracing_pipeline, RayTracingPipeline,
+            RayTracingPipelineDesc, RayTracingPipelineLibraries, RayTracingPipelineLibraryStats,
+        },
         shader::*,
     },
+    KajiyaError,
 };
+use ash::vk;
+use bytes::Bytes;
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Instant,
+};
 use turbosloth::*;
 
 #[derive(Clone, Copy, Hash, Eq, PartialEq)]
@@ -17,7 +29,10 @@ pub struct ComputePipelineHandle(usize);
 struct ComputePipelineCacheEntry {
     lazy_handle: Lazy<CompiledShader>,
     desc: ComputePipelineDesc,
+    // Kept in use while its shaders are recompiled after a change
     pipeline: Option<Arc<ComputePipeline>>,
+    // The SPIR-V `pipeline` was built from
+    spirv: Vec<Bytes>,
 }
 
 #[derive(Clone, Copy, Hash, Eq, PartialEq)]
@@ -26,6 +41,13 @@ pub struct RasterPipelineHandle(usize);
 #[derive(Clone, Copy, Hash, Eq, PartialEq)]
 pub struct RtPipelineHandle(usize);
 
+#[derive(Clone, Copy, Hash, Eq, PartialEq)]
+enum PipelineHandle {
+    Compute(ComputePipelineHandle),
+    Raster(RasterPipelineHandle),
+    Rt(RtPipelineHandle),
+}
+
 pub struct CompiledPipelineShaders {
     shaders: Vec<PipelineShader<Arc<CompiledShader>>>,
 }
@@ -47,14 +69,17 @@ impl LazyWorker for CompilePipelineShaders {
                 }
                 .into_lazy()
                 .eval(&ctx),
-                ShaderSource::Hlsl { path } => CompileShader {
+                ShaderSource::Hlsl { path, defines } => CompileShader {
                     path: path.clone(),
+                    defines: defines.clone(),
                     profile: match desc.stage {
                         ShaderPipelineStage::Vertex => "vs".to_owned(),
                         ShaderPipelineStage::Pixel => "ps".to_owned(),
                         ShaderPipelineStage::RayGen
                         | ShaderPipelineStage::RayMiss
-                        | ShaderPipelineStage::RayClosestHit => "lib".to_owned(),
+                        | ShaderPipelineStage::RayClosestHit
+                        | ShaderPipelineStage::RayAnyHit
+                        | ShaderPipelineStage::RayIntersection => "lib".to_owned(),
                     },
                 }
                 .into_lazy()
@@ -79,13 +104,19 @@ impl LazyWorker for CompilePipelineShaders {
 struct RasterPipelineCacheEntry {
     lazy_handle: Lazy<CompiledPipelineShaders>,
     desc: RasterPipelineDesc,
+    // Kept in use while its shaders are recompiled after a change
     pipeline: Option<Arc<RasterPipeline>>,
+    // The SPIR-V `pipeline` was built from
+    spirv: Vec<Bytes>,
 }
 
 struct RtPipelineCacheEntry {
     lazy_handle: Lazy<CompiledPipelineShaders>,
     desc: RayTracingPipelineDesc,
+    // Kept in use while its shaders are recompiled after a change
     pipeline: Option<Arc<RayTracingPipeline>>,
+    // The SPIR-V `pipeline` was built from
+    spirv: Vec<Bytes>,
 }
 
 pub struct PipelineCache {
@@ -98,6 +129,47 @@ pub struct PipelineCache {
     compute_shader_to_handle: HashMap<ShaderSource, ComputePipelineHandle>,
     raster_shaders_to_handle: HashMap<Vec<PipelineShaderDesc>, RasterPipelineHandle>,
     rt_shaders_to_handle: HashMap<Vec<PipelineShaderDesc>, RtPipelineHandle>,
+
+    // Pipelines invalidated by shader reloads, tagged with the device frame
+    // at which they were retired. The GPU may still be using them in frames in flight.
+    retired_pipelines: Vec<RetiredPipeline>,
+
+    // Shader groups of ray tracing pipelines, shared between their rebuilds
+    rt_libraries: RayTracingPipelineLibraries,
+    use_rt_pipeline_libraries: bool,
+
+    // Stale pipelines kept as their recompiled shaders had the same SPIR-V
+    unchanged_pipeline_count: usize,
+
+    // Shaders of stale pipelines being recompiled on the executor. Frames keep using the stale
+    // pipelines meanwhile; `prepare_frame` swaps in the new ones as the compiles finish.
+    background_compiles: HashMap<PipelineHandle, smol::Task<anyhow::Result<CompileTaskOutput>>>,
+    shader_reload: Option<ShaderReload>,
+
+    max_concurrent_compiles: usize,
+}
+
+// Spans the frames from a shader change until all affected pipelines are rebuilt
+struct ShaderReload {
+    started: Instant,
+    pipelines: HashSet<PipelineHandle>,
+    unchanged_pipeline_count_at_start: usize,
+}
+
+struct RetiredPipeline {
+    retired_at_frame: u64,
+    pipeline: vk::Pipeline,
+    pipeline_layout: vk::PipelineLayout,
+}
+
+impl RetiredPipeline {
+    fn new(device: &crate::vulkan::device::Device, common: &ShaderPipelineCommon) -> Self {
+        Self {
+            retired_at_frame: device.frame_counter(),
+            pipeline: common.pipeline,
+            pipeline_layout: common.pipeline_layout,
+        }
+    }
 }
 
 impl PipelineCache {
@@ -113,9 +185,49 @@ impl PipelineCache {
 
             raster_shaders_to_handle: Default::default(),
             rt_shaders_to_handle: Default::default(),
+
+            retired_pipelines: Default::default(),
+
+            rt_libraries: Default::default(),
+            use_rt_pipeline_libraries: true,
+
+            unchanged_pipeline_count: 0,
+
+            background_compiles: Default::default(),
+            shader_reload: None,
+
+            max_concurrent_compiles: std::thread::available_parallelism()
+                .map_or(4, |count| count.get()),
         }
     }
 
+    /// The maximum number of pipelines whose shaders are compiled at the same time.
+    /// Defaults to the number of CPU cores.
+    pub fn max_concurrent_compiles(&self) -> usize {
+        self.max_concurrent_compiles
+    }
+
+    pub fn set_max_concurrent_compiles(&mut self, max_concurrent_compiles: usize) {
+        self.max_concurrent_compiles = max_concurrent_compiles.max(1);
+    }
+
+    /// Whether ray tracing pipelines are linked from a library per shader group, so that
+    /// rebuilding one after a shader change only compiles the groups which changed.
+    /// On by default; without device support, pipelines are built whole regardless.
+    pub fn set_ray_tracing_pipeline_libraries(&mut self, enabled: bool) {
+        self.use_rt_pipeline_libraries = enabled;
+    }
+
+    pub fn ray_tracing_pipeline_library_stats(&self) -> RayTracingPipelineLibraryStats {
+        self.rt_libraries.stats()
+    }
+
+    /// The number of pipelines which weren't rebuilt after a shader reload, as their
+    /// shaders compiled to the same SPIR-V as before.
+    pub fn unchanged_pipeline_count(&self) -> usize {
+        self.unchanged_pipeline_count
+    }
+
     // TODO: should probably use the `desc` as key as well
     pub fn register_compute(&mut self, desc: &ComputePipelineDesc) -> ComputePipelineHandle {
         match self.compute_shader_to_handle.entry(desc.source.clone()) {
@@ -127,8 +239,9 @@ impl PipelineCache {
                         entry: entry.clone(),
                     }
                     .into_lazy(),
-                    ShaderSource::Hlsl { path } => CompileShader {
+                    ShaderSource::Hlsl { path, defines } => CompileShader {
                         path: path.clone(),
+                        defines: defines.clone(),
                         profile: "cs".to_owned(),
                     }
                     .into_lazy(),
@@ -140,6 +253,7 @@ impl PipelineCache {
                         lazy_handle: compile_task,
                         desc: desc.clone(),
                         pipeline: None,
+                        spirv: Vec::new(),
                     },
                 );
                 vacant.insert(handle);
@@ -148,6 +262,29 @@ impl PipelineCache {
         }
     }
 
+    /// Registers `variant` of the HLSL shader of `desc`, which has `features`. Like other
+    /// pipelines, it's compiled on the next `prepare_frame`, and once per distinct variant.
+    pub fn register_compute_variant(
+        &mut self,
+        desc: &ComputePipelineDesc,
+        features: &ShaderFeatures,
+        variant: &ShaderVariant,
+    ) -> anyhow::Result<ComputePipelineHandle> {
+        let path = match &desc.source {
+            ShaderSource::Hlsl { path, .. } => path,
+            ShaderSource::Rust { entry } => {
+                anyhow::bail!("Shader variants need HLSL; {:?} is a Rust shader", entry)
+            }
+        };
+
+        let desc = ComputePipelineDesc {
+            source: ShaderSource::hlsl_variant(path.clone(), features, variant)?,
+            ..desc.clone()
+        };
+
+        Ok(self.register_compute(&desc))
+    }
+
     pub fn get_compute(&self, handle: ComputePipelineHandle) -> Arc<ComputePipeline> {
         self.compute_entries
             .get(&handle)
@@ -178,11 +315,25 @@ impl PipelineCache {
                 .into_lazy(),
                 desc: desc.clone(),
                 pipeline: None,
+                spirv: Vec::new(),
             },
         );
         handle
     }
 
+    /// Registers `variant` of the HLSL shaders of a raster pipeline, which share `features`;
+    /// see `register_compute_variant`.
+    pub fn register_raster_variant(
+        &mut self,
+        shaders: &[PipelineShaderDesc],
+        desc: &RasterPipelineDesc,
+        features: &ShaderFeatures,
+        variant: &ShaderVariant,
+    ) -> anyhow::Result<RasterPipelineHandle> {
+        let shaders = pipeline_shader_variants(shaders, features, variant)?;
+        Ok(self.register_raster(&shaders, desc))
+    }
+
     pub fn get_raster(&self, handle: RasterPipelineHandle) -> Arc<RasterPipeline> {
         self.raster_entries
             .get(&handle)
@@ -212,11 +363,25 @@ impl PipelineCache {
                 .into_lazy(),
                 desc: desc.clone(),
                 pipeline: None,
+                spirv: Vec::new(),
             },
         );
         handle
     }
 
+    /// Registers `variant` of the HLSL shaders of a ray tracing pipeline, which share `features`;
+    /// see `register_compute_variant`.
+    pub fn register_ray_tracing_variant(
+        &mut self,
+        shaders: &[PipelineShaderDesc],
+        desc: &RayTracingPipelineDesc,
+        features: &ShaderFeatures,
+        variant: &ShaderVariant,
+    ) -> anyhow::Result<RtPipelineHandle> {
+        let shaders = pipeline_shader_variants(shaders, features, variant)?;
+        Ok(self.register_ray_tracing(&shaders, desc))
+    }
+
     pub fn get_ray_tracing(&self, handle: RtPipelineHandle) -> Arc<RayTracingPipeline> {
         self.rt_entries
             .get(&handle)
@@ -226,149 +391,349 @@ impl PipelineCache {
             .unwrap()
     }
 
-    fn invalidate_stale_pipelines(&mut self) {
-        for entry in self.compute_entries.values_mut() {
-            if entry.pipeline.is_some() && entry.lazy_handle.is_stale() {
-                // TODO: release
-                entry.pipeline = None;
-            }
-        }
+    // Retires the pipeline being replaced, as the GPU may still be using it in frames in flight.
+    // Returns it, for releasing any other resources it holds.
+    fn replace_pipeline<P: std::ops::Deref<Target = ShaderPipelineCommon>>(
+        retired_pipelines: &mut Vec<RetiredPipeline>,
+        device: &crate::vulkan::device::Device,
+        pipeline: &mut Option<Arc<P>>,
+        new_pipeline: P,
+    ) -> Option<Arc<P>> {
+        let prev_pipeline = pipeline.replace(Arc::new(new_pipeline))?;
+        retired_pipelines.push(RetiredPipeline::new(device, &prev_pipeline));
+        Some(prev_pipeline)
+    }
 
-        for entry in self.raster_entries.values_mut() {
-            if entry.pipeline.is_some() && entry.lazy_handle.is_stale() {
-                // TODO: release
-                entry.pipeline = None;
+    // Destroys retired pipelines once every frame which could have referenced them
+    // has finished executing on the GPU.
+    fn release_retired_pipelines(&mut self, device: &crate::vulkan::device::Device) {
+        let frame_counter = device.frame_counter();
+        let frames_in_flight = device.frames_in_flight() as u64;
+
+        self.retired_pipelines.retain(|retired| {
+            if frame_counter < retired.retired_at_frame + frames_in_flight {
+                return true;
             }
-        }
 
-        for entry in self.rt_entries.values_mut() {
-            if entry.pipeline.is_some() && entry.lazy_handle.is_stale() {
-                // TODO: release
-                entry.pipeline = None;
+            device.untrack_resource(ResourceCategory::Pipeline, retired.pipeline);
+
+            unsafe {
+                device.raw.destroy_pipeline(retired.pipeline, None);
+                device
+                    .raw
+                    .destroy_pipeline_layout(retired.pipeline_layout, None);
             }
-        }
+
+            false
+        });
     }
 
-    pub fn parallel_compile_shaders(
-        &mut self,
-        device: &Arc<crate::vulkan::device::Device>,
-    ) -> anyhow::Result<()> {
-        // Prepare build tasks for compute
-        let compute = self.compute_entries.iter().filter_map(|(&handle, entry)| {
-            entry.pipeline.is_none().then(|| {
-                let task = entry.lazy_handle.eval(&self.lazy_cache);
-                smol::spawn(async move {
+    // All pipelines, along with whether they've been built, and whether their shaders
+    // changed since.
+    fn pipeline_states(&self) -> impl Iterator<Item = (PipelineHandle, bool, bool)> + '_ {
+        let compute = self.compute_entries.iter().map(|(&handle, entry)| {
+            (
+                PipelineHandle::Compute(handle),
+                entry.pipeline.is_some(),
+                entry.lazy_handle.is_stale(),
+            )
+        });
+        let raster = self.raster_entries.iter().map(|(&handle, entry)| {
+            (
+                PipelineHandle::Raster(handle),
+                entry.pipeline.is_some(),
+                entry.lazy_handle.is_stale(),
+            )
+        });
+        let rt = self.rt_entries.iter().map(|(&handle, entry)| {
+            (
+                PipelineHandle::Rt(handle),
+                entry.pipeline.is_some(),
+                entry.lazy_handle.is_stale(),
+            )
+        });
+
+        compute.chain(raster).chain(rt)
+    }
+
+    fn compile_task(&self, handle: PipelineHandle) -> CompileTask {
+        match handle {
+            PipelineHandle::Compute(handle) => {
+                let task = self.compute_entries[&handle]
+                    .lazy_handle
+                    .eval(&self.lazy_cache);
+                Box::pin(async move {
                     task.await
                         .map(|compiled| CompileTaskOutput::Compute { handle, compiled })
                 })
-            })
-        });
-
-        // Prepare build tasks for raster
-        let raster = self.raster_entries.iter().filter_map(|(&handle, entry)| {
-            entry.pipeline.is_none().then(|| {
-                let task = entry.lazy_handle.eval(&self.lazy_cache);
-                smol::spawn(async move {
+            }
+            PipelineHandle::Raster(handle) => {
+                let task = self.raster_entries[&handle]
+                    .lazy_handle
+                    .eval(&self.lazy_cache);
+                Box::pin(async move {
                     task.await
                         .map(|compiled| CompileTaskOutput::Raster { handle, compiled })
                 })
-            })
-        });
-
-        // Prepare build tasks for rt
-        let rt = self.rt_entries.iter().filter_map(|(&handle, entry)| {
-            entry.pipeline.is_none().then(|| {
-                let task = entry.lazy_handle.eval(&self.lazy_cache);
-                smol::spawn(async move {
+            }
+            PipelineHandle::Rt(handle) => {
+                let task = self.rt_entries[&handle].lazy_handle.eval(&self.lazy_cache);
+                Box::pin(async move {
                     task.await
                         .map(|compiled| CompileTaskOutput::Rt { handle, compiled })
                 })
+            }
+        }
+    }
+
+    // Prepares shader compilation tasks for all pipelines which don't exist yet.
+    fn pending_compile_tasks(&self) -> Vec<CompileTask> {
+        self.pipeline_states()
+            .filter(|&(_, built, _)| !built)
+            .map(|(handle, _, _)| self.compile_task(handle))
+            .collect()
+    }
+
+    // Starts recompiling the shaders of stale pipelines which aren't being compiled already,
+    // keeping at most `max_concurrent_compiles` in flight.
+    fn start_background_compiles(&mut self) {
+        let stale: Vec<PipelineHandle> = self
+            .pipeline_states()
+            .filter(|&(handle, built, stale)| {
+                built && stale && !self.background_compiles.contains_key(&handle)
             })
-        });
+            .map(|(handle, _, _)| handle)
+            .take(
+                self.max_concurrent_compiles
+                    .saturating_sub(self.background_compiles.len()),
+            )
+            .collect();
 
-        // Gather all the build tasks together
-        let shader_tasks: Vec<_> = compute.chain(raster).chain(rt).collect();
-
-        if !shader_tasks.is_empty() {
-            // Compile all the things
-            let compiled: Vec<CompileTaskOutput> =
-                smol::block_on(futures::future::try_join_all(shader_tasks))?;
-
-            // Build pipelines from all compiled shaders
-            for compiled in compiled {
-                match compiled {
-                    CompileTaskOutput::Compute { handle, compiled } => {
-                        let entry = self.compute_entries.get_mut(&handle).unwrap();
-                        log::trace!(
-                            "Creating compute pipeline {:?}:{:?}",
-                            compiled.name,
-                            entry.desc.source.entry(),
-                        );
-                        entry.pipeline = Some(Arc::new(create_compute_pipeline(
-                            &*device,
-                            &compiled.spirv,
-                            &entry.desc,
-                        )));
-                    }
-                    CompileTaskOutput::Raster { handle, compiled } => {
-                        let entry = self.raster_entries.get_mut(&handle).unwrap();
-                        log::trace!(
-                            "Creating raster pipeline {}",
-                            compiled
-                                .shaders
-                                .iter()
-                                .map(|shader| format!(
-                                    "{:?}:{:?}",
-                                    shader.desc.stage, shader.desc.entry
-                                ))
-                                .collect::<Vec<_>>()
-                                .join(", ")
-                        );
-
-                        let compiled_shaders = compiled
-                            .shaders
-                            .iter()
-                            .map(|shader| PipelineShader {
-                                code: shader.code.spirv.clone(),
-                                desc: shader.desc.clone(),
-                            })
-                            .collect::<Vec<_>>();
-
-                        // TODO: defer and handle the error
-                        entry.pipeline = Some(Arc::new(
-                            create_raster_pipeline(&*device, &compiled_shaders, &entry.desc)
-                                .expect("create_raster_pipeline"),
-                        ));
-                    }
-                    CompileTaskOutput::Rt { handle, compiled } => {
-                        let entry = self.rt_entries.get_mut(&handle).unwrap();
-                        log::trace!(
-                            "Creating rt pipeline {}",
-                            compiled
-                                .shaders
-                                .iter()
-                                .map(|shader| format!(
-                                    "{:?}:{:?}",
-                                    shader.desc.stage, shader.desc.entry
-                                ))
-                                .collect::<Vec<_>>()
-                                .join(", ")
-                        );
-
-                        let compiled_shaders = compiled
-                            .shaders
-                            .iter()
-                            .map(|shader| PipelineShader {
-                                code: shader.code.spirv.clone(),
-                                desc: shader.desc.clone(),
-                            })
-                            .collect::<Vec<_>>();
-
-                        // TODO: defer and handle the error
-                        entry.pipeline = Some(Arc::new(
-                            create_ray_tracing_pipeline(&*device, &compiled_shaders, &entry.desc)
-                                .expect("create_ray_tracing_pipeline"),
-                        ));
+        for handle in stale {
+            let task = smol::spawn(self.compile_task(handle));
+            self.background_compiles.insert(handle, task);
+
+            let unchanged_pipeline_count = self.unchanged_pipeline_count;
+            self.shader_reload
+                .get_or_insert_with(|| ShaderReload {
+                    started: Instant::now(),
+                    pipelines: HashSet::new(),
+                    unchanged_pipeline_count_at_start: unchanged_pipeline_count,
+                })
+                .pipelines
+                .insert(handle);
+        }
+    }
+
+    // Builds pipelines from the background compiles which have finished, without waiting
+    // for the others. Compiles which fail leave the stale pipelines in place; they're retried
+    // in later frames, like superseded ones.
+    fn finish_background_compiles(
+        &mut self,
+        device: &Arc<crate::vulkan::device::Device>,
+    ) -> Result<(), KajiyaError> {
+        use futures::FutureExt;
+
+        let mut finished = Vec::new();
+        self.background_compiles
+            .retain(|_, task| match task.now_or_never() {
+                Some(result) => {
+                    finished.push(result);
+                    false
+                }
+                None => true,
+            });
+
+        let mut result = Ok(());
+        for compiled in finished {
+            let compiled = match compiled {
+                Ok(compiled) => compiled,
+                Err(err) => {
+                    result = result.and(Err(err.into()));
+                    continue;
+                }
+            };
+
+            if !self.is_superseded(&compiled) {
+                result = result.and(self.create_pipeline(device, compiled));
+            }
+        }
+
+        result
+    }
+
+    // Logs how long it took to rebuild the pipelines affected by a shader change, once
+    // none are left compiling.
+    fn finish_shader_reload(&mut self) {
+        if !self.background_compiles.is_empty()
+            || self
+                .pipeline_states()
+                .any(|(_, built, stale)| built && stale)
+        {
+            return;
+        }
+
+        if let Some(reload) = self.shader_reload.take() {
+            info!(
+                "Reloaded the shaders of {} pipeline(s) in {:.1} ms; kept {}, as their SPIR-V didn't change",
+                reload.pipelines.len(),
+                reload.started.elapsed().as_secs_f64() * 1000.0,
+                self.unchanged_pipeline_count - reload.unchanged_pipeline_count_at_start,
+            );
+        }
+    }
+
+    // True if the shaders were modified after the compile task started.
+    fn is_superseded(&self, compiled: &CompileTaskOutput) -> bool {
+        let superseded = match compiled {
+            CompileTaskOutput::Compute { handle, .. } => {
+                self.compute_entries[handle].lazy_handle.is_stale()
+            }
+            CompileTaskOutput::Raster { handle, .. } => {
+                self.raster_entries[handle].lazy_handle.is_stale()
+            }
+            CompileTaskOutput::Rt { handle, .. } => self.rt_entries[handle].lazy_handle.is_stale(),
+        };
+
+        if superseded {
+            log::trace!("Dropping the result of a superseded shader compile");
+        }
+
+        superseded
+    }
+
+    fn create_pipeline(
+        &mut self,
+        device: &Arc<crate::vulkan::device::Device>,
+        compiled: CompileTaskOutput,
+    ) -> Result<(), KajiyaError> {
+        match compiled {
+            CompileTaskOutput::Compute { handle, compiled } => {
+                let entry = self.compute_entries.get_mut(&handle).unwrap();
+
+                // Stale pipelines are kept if their shaders produced the same SPIR-V, as with
+                // edits to comments, or to code the shaders don't use.
+                let spirv = vec![compiled.spirv.clone()];
+                if entry.pipeline.is_some() && entry.spirv == spirv {
+                    self.unchanged_pipeline_count += 1;
+                    return Ok(());
+                }
+
+                log::trace!(
+                    "Creating compute pipeline {:?}:{:?}",
+                    compiled.name,
+                    entry.desc.source.entry(),
+                );
+                let name = format!("{:?}:{:?}", compiled.name, entry.desc.source.entry());
+                let pipeline = create_compute_pipeline(&*device, &compiled.spirv, &entry.desc)
+                    .map_err(|source| KajiyaError::PipelineCreation {
+                        name: name.clone(),
+                        source,
+                    })?;
+                device.track_resource(ResourceCategory::Pipeline, pipeline.pipeline, name, 0);
+                Self::replace_pipeline(
+                    &mut self.retired_pipelines,
+                    device,
+                    &mut entry.pipeline,
+                    pipeline,
+                );
+                entry.spirv = spirv;
+            }
+            CompileTaskOutput::Raster { handle, compiled } => {
+                let entry = self.raster_entries.get_mut(&handle).unwrap();
+
+                // Stale pipelines are kept if their shaders produced the same SPIR-V, as with
+                // edits to comments, or to code the shaders don't use.
+                let spirv = compiled_spirv(&compiled);
+                if entry.pipeline.is_some() && entry.spirv == spirv {
+                    self.unchanged_pipeline_count += 1;
+                    return Ok(());
+                }
+
+                log::trace!("Creating raster pipeline {}", pipeline_name(&compiled));
+
+                let compiled_shaders = compiled
+                    .shaders
+                    .iter()
+                    .map(|shader| PipelineShader {
+                        code: shader.code.spirv.clone(),
+                        desc: shader.desc.clone(),
+                    })
+                    .collect::<Vec<_>>();
+
+                let pipeline = create_raster_pipeline(&*device, &compiled_shaders, &entry.desc)
+                    .map_err(|source| KajiyaError::PipelineCreation {
+                        name: pipeline_name(&compiled),
+                        source,
+                    })?;
+                device.track_resource(
+                    ResourceCategory::Pipeline,
+                    pipeline.pipeline,
+                    pipeline_name(&compiled),
+                    0,
+                );
+                Self::replace_pipeline(
+                    &mut self.retired_pipelines,
+                    device,
+                    &mut entry.pipeline,
+                    pipeline,
+                );
+                entry.spirv = spirv;
+            }
+            CompileTaskOutput::Rt { handle, compiled } => {
+                let entry = self.rt_entries.get_mut(&handle).unwrap();
+
+                // Stale pipelines are kept if their shaders produced the same SPIR-V, as with
+                // edits to comments, or to code the shaders don't use.
+                let spirv = compiled_spirv(&compiled);
+                if entry.pipeline.is_some() && entry.spirv == spirv {
+                    self.unchanged_pipeline_count += 1;
+                    return Ok(());
+                }
+
+                log::trace!("Creating rt pipeline {}", pipeline_name(&compiled));
+
+                let compiled_shaders = compiled
+                    .shaders
+                    .iter()
+                    .map(|shader| PipelineShader {
+                        code: shader.code.spirv.clone(),
+                        desc: shader.desc.clone(),
+                    })
+                    .collect::<Vec<_>>();
+
+                let pipeline = if self.use_rt_pipeline_libraries {
+                    create_linked_ray_tracing_pipeline(
+                        &*device,
+                        &compiled_shaders,
+                        &entry.desc,
+                        &mut self.rt_libraries,
+                    )
+                } else {
+                    create_ray_tracing_pipeline(&*device, &compiled_shaders, &entry.desc)
+                }
+                .map_err(|source| KajiyaError::PipelineCreation {
+                    name: pipeline_name(&compiled),
+                    source,
+                })?;
+                device.track_resource(
+                    ResourceCategory::Pipeline,
+                    pipeline.pipeline,
+                    pipeline_name(&compiled),
+                    0,
+                );
+                let prev_pipeline = Self::replace_pipeline(
+                    &mut self.retired_pipelines,
+                    device,
+                    &mut entry.pipeline,
+                    pipeline,
+                );
+                entry.spirv = spirv;
+
+                // Freed once frames in flight, and passes holding on to the pipeline, are done
+                if let Some(prev_pipeline) = prev_pipeline {
+                    for buffer in prev_pipeline.shader_table_buffers() {
+                        device.defer_release_buffer(buffer);
                     }
                 }
             }
@@ -377,17 +742,150 @@ impl PipelineCache {
         Ok(())
     }
 
+    pub fn parallel_compile_shaders(
+        &mut self,
+        device: &Arc<crate::vulkan::device::Device>,
+    ) -> Result<(), KajiyaError> {
+        let shader_tasks = self.pending_compile_tasks();
+        if shader_tasks.is_empty() {
+            return Ok(());
+        }
+
+        use futures::TryStreamExt;
+
+        // Compile all the things
+        let compiled: Vec<CompileTaskOutput> = smol::block_on(
+            run_compile_tasks(shader_tasks, self.max_concurrent_compiles).try_collect(),
+        )?;
+
+        // Build pipelines from all compiled shaders. Ones whose shaders were modified
+        // in the meantime are recompiled in the background on the next frame.
+        for compiled in compiled {
+            self.create_pipeline(device, compiled)?;
+        }
+
+        Ok(())
+    }
+
+    /// Compiles all registered pipelines which haven't been built yet, blocking until done.
+    /// Meant for warming up the cache ahead of the first frame, e.g. behind a loading screen.
+    ///
+    /// `progress` is called with the number of finished pipelines and the total count
+    /// once upfront, and then after each pipeline. Unlike `parallel_compile_shaders`,
+    /// this doesn't stop at the first failure; all errors are returned.
+    pub fn compile_all(
+        &mut self,
+        device: &Arc<crate::vulkan::device::Device>,
+        mut progress: impl FnMut(usize, usize),
+    ) -> Result<(), Vec<KajiyaError>> {
+        let shader_tasks = self.pending_compile_tasks();
+
+        let total = self.compute_entries.len() + self.raster_entries.len() + self.rt_entries.len();
+        let mut finished = total - shader_tasks.len();
+        progress(finished, total);
+
+        let mut errors = Vec::new();
+
+        smol::block_on(async {
+            use futures::StreamExt;
+
+            let mut compiled_stream = Box::pin(run_compile_tasks(
+                shader_tasks,
+                self.max_concurrent_compiles,
+            ));
+
+            while let Some(compiled) = compiled_stream.next().await {
+                let result = compiled
+                    .map_err(KajiyaError::from)
+                    .and_then(|compiled| self.create_pipeline(device, compiled));
+
+                if let Err(err) = result {
+                    errors.push(err);
+                }
+
+                finished += 1;
+                progress(finished, total);
+            }
+        });
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
     pub fn prepare_frame(
         &mut self,
         device: &Arc<crate::vulkan::device::Device>,
-    ) -> anyhow::Result<()> {
-        self.invalidate_stale_pipelines();
+    ) -> Result<(), KajiyaError> {
+        self.release_retired_pipelines(device);
+
+        // Only pipelines which were never built are waited on; stale ones are rebuilt
+        // in the background.
+        let background_result = self.finish_background_compiles(device);
+        self.start_background_compiles();
         self.parallel_compile_shaders(device)?;
+        self.retire_unused_rt_libraries(device);
+        self.finish_shader_reload();
 
-        Ok(())
+        background_result
+    }
+
+    // Libraries no longer linked into any pipeline are released like retired pipelines.
+    // Waits until all ray tracing pipelines are built, as pending ones may still reuse them.
+    fn retire_unused_rt_libraries(&mut self, device: &crate::vulkan::device::Device) {
+        let mut in_use = HashSet::new();
+        for entry in self.rt_entries.values() {
+            match &entry.pipeline {
+                Some(pipeline) => in_use.extend(pipeline.library_keys.iter().copied()),
+                None => return,
+            }
+        }
+
+        for library in self.rt_libraries.take_unused(&in_use) {
+            self.retired_pipelines.push(RetiredPipeline {
+                retired_at_frame: device.frame_counter(),
+                pipeline: library,
+                pipeline_layout: vk::PipelineLayout::null(),
+            });
+        }
     }
 }
 
+fn compiled_spirv(compiled: &CompiledPipelineShaders) -> Vec<Bytes> {
+    compiled
+        .shaders
+        .iter()
+        .map(|shader| shader.code.spirv.clone())
+        .collect()
+}
+
+fn pipeline_name(compiled: &CompiledPipelineShaders) -> String {
+    compiled
+        .shaders
+        .iter()
+        .map(|shader| format!("{:?}:{:?}", shader.desc.stage, shader.desc.entry))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// Runs the tasks on the executor, with at most `max_concurrent` in flight.
+// Results arrive in completion order.
+fn run_compile_tasks(
+    tasks: Vec<CompileTask>,
+    max_concurrent: usize,
+) -> impl futures::Stream<Item = anyhow::Result<CompileTaskOutput>> {
+    use futures::StreamExt;
+
+    futures::stream::iter(tasks)
+        .map(smol::spawn)
+        .buffer_unordered(max_concurrent)
+}
+
+type CompileTask =
+    std::pin::Pin<Box<dyn std::future::Future<Output = anyhow::Result<CompileTaskOutput>> + Send>>;
+
 enum CompileTaskOutput {
     Compute {
         handle: ComputePipelineHandle,
@@ -402,3 +900,139 @@ enum CompileTaskOutput {
         compiled: Arc<CompiledPipelineShaders>,
     },
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shader_variants_get_distinct_pipelines() {
+        let mut cache = PipelineCache::new(&LazyCache::create());
+        let desc = ComputePipelineDesc::builder()
+            .compute_hlsl("/shaders/copy_color.hlsl")
+            .build()
+            .unwrap();
+        let features = ShaderFeatures::new()
+            .bool("FLIP_Y")
+            .enumeration("FORMAT", &["RGBA8", "RGBA16F"]);
+
+        let plain = cache
+            .register_compute_variant(&desc, &features, &ShaderVariant::new())
+            .unwrap();
+        let flipped = cache
+            .register_compute_variant(
+                &desc,
+                &features,
+                &ShaderVariant::new().feature("FLIP_Y", true),
+            )
+            .unwrap();
+        assert!(plain != flipped);
+
+        // Requesting a variant again, or spelling out its defaults, reuses its pipeline.
+        let defaults = ShaderVariant::new()
+            .feature("FLIP_Y", false)
+            .value("FORMAT", "RGBA8");
+        assert!(
+            cache
+                .register_compute_variant(&desc, &features, &defaults)
+                .unwrap()
+                == plain
+        );
+
+        // Only the requested variants are compiled, not all four.
+        assert_eq!(cache.compute_entries.len(), 2);
+
+        let rust_desc = ComputePipelineDesc::builder()
+            .compute_rust("copy_color")
+            .build()
+            .unwrap();
+        assert!(cache
+            .register_compute_variant(&rust_desc, &features, &ShaderVariant::new())
+            .is_err());
+    }
+
+    #[test]
+    fn ray_tracing_variants_get_distinct_pipelines() {
+        let mut cache = PipelineCache::new(&LazyCache::create());
+        let desc = RayTracingPipelineDesc::default();
+        let features = ShaderFeatures::new().bool("USE_SHADOWS");
+
+        let shaders = [
+            PipelineShaderDesc::builder(ShaderPipelineStage::RayGen)
+                .hlsl_source("/shaders/rt/reference_path_trace.rgen.hlsl")
+                .build()
+                .unwrap(),
+            PipelineShaderDesc::builder(ShaderPipelineStage::RayMiss)
+                .rust_source("rt::miss")
+                .build()
+                .unwrap(),
+        ];
+
+        let plain = cache
+            .register_ray_tracing_variant(&shaders, &desc, &features, &ShaderVariant::new())
+            .unwrap();
+        let shadowed = cache
+            .register_ray_tracing_variant(
+                &shaders,
+                &desc,
+                &features,
+                &ShaderVariant::new().feature("USE_SHADOWS", true),
+            )
+            .unwrap();
+        assert!(plain != shadowed);
+        assert_eq!(cache.rt_entries.len(), 2);
+
+        // The HLSL shaders get the defines of the variant; the Rust ones are left as they are.
+        let registered = cache.rt_shaders_to_handle.keys().next().unwrap();
+        assert!(matches!(
+            &registered[0].source,
+            ShaderSource::Hlsl { defines, .. } if defines.len() == 1
+        ));
+        assert_eq!(registered[1].source, shaders[1].source);
+
+        assert!(cache
+            .register_ray_tracing_variant(&shaders[1..], &desc, &features, &ShaderVariant::new())
+            .is_err());
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device with ray tracing"]
+    fn rebuilt_ray_tracing_pipelines_release_their_shader_binding_tables() {
+        use crate::{file::set_standard_vfs_mount_points, vulkan::HeadlessRenderBackend};
+
+        set_standard_vfs_mount_points(concat!(env!("CARGO_MANIFEST_DIR"), "/../../.."));
+
+        let backend = HeadlessRenderBackend::new(Default::default()).unwrap();
+        let device = backend.device.clone();
+        let buffer_count = || device.memory_stats().get(ResourceCategory::Buffer).count;
+
+        let mut cache = PipelineCache::new(&LazyCache::create());
+        let shaders = [
+            PipelineShaderDesc::builder(ShaderPipelineStage::RayGen)
+                .source(ShaderSource::hlsl(
+                    "/shaders/rt/trace_sun_shadow_mask.rgen.hlsl",
+                ))
+                .build()
+                .unwrap(),
+            PipelineShaderDesc::builder(ShaderPipelineStage::RayMiss)
+                .source(ShaderSource::hlsl("/shaders/rt/shadow.rmiss.hlsl"))
+                .build()
+                .unwrap(),
+        ];
+        let handle = cache.register_ray_tracing(
+            &shaders,
+            &RayTracingPipelineDesc::default().max_pipeline_ray_recursion_depth(1),
+        );
+        cache.prepare_frame(&device).unwrap();
+        assert_eq!(cache.get_ray_tracing(handle).sbt.buffers().count(), 2);
+        let buffers_with_one_pipeline = buffer_count();
+
+        // As after a shader edit which changed the SPIR-V
+        let compiled = smol::block_on(cache.compile_task(PipelineHandle::Rt(handle))).unwrap();
+        cache.rt_entries.get_mut(&handle).unwrap().spirv.clear();
+        cache.create_pipeline(&device, compiled).unwrap();
+
+        // The new tables replace the old ones, which are freed once the frame is done with them.
+        assert_eq!(buffer_count(), buffers_with_one_pipeline);
+    }
+}
@@ -1,8 +1,17 @@
-use crate::{file::LoadFile, normalized_path_from_vfs, shader_compiler::CompiledShader};
+use crate::{
+    file::LoadFile, normalized_path_from_vfs, shader_compiler::CompiledShader, KajiyaError,
+};
 use anyhow::{Context, Result};
 use nanoserde::DeJson;
 use parking_lot::Mutex;
-use std::process::Command;
+use rspirv::{
+    binary::Assemble,
+    dr::{Instruction, Operand},
+};
+use std::{
+    collections::{HashMap, HashSet},
+    process::Command,
+};
 use turbosloth::*;
 
 #[derive(Clone, Hash)]
@@ -35,22 +44,159 @@ impl LazyWorker for CompileRustShader {
                     None
                 }
             })
-            .ok_or_else(|| {
-                anyhow::anyhow!("No Rust-GPU module found for entry point {}", self.entry)
+            .ok_or_else(|| KajiyaError::ShaderCompile {
+                path: format!("Rust-GPU entry point {}", self.entry),
+                message: LAST_BUILD_DIAGNOSTIC
+                    .lock()
+                    .clone()
+                    .unwrap_or_else(|| "No Rust-GPU module found for the entry point".to_owned()),
             })?;
 
-        let spirv_blob = LoadFile::new(format!("/rust-shaders-compiled/{}", shader_file))?
+        // The crate is compiled into a single module shared by all entry points, and loaded once.
+        // Each pipeline gets a copy with just its own entry point.
+        let module = LoadFile::new(format!("/rust-shaders-compiled/{}", shader_file))?
             .into_lazy()
             .eval(&ctx)
             .await?;
 
+        let t0 = std::time::Instant::now();
+        let spirv = extract_entry_point(&module, &self.entry)?;
+        log::debug!(
+            "Extracting Rust-GPU entry point {} took {:?}",
+            self.entry,
+            t0.elapsed()
+        );
+
         Ok(CompiledShader {
             name: "rust-gpu".to_owned(),
-            spirv: (*spirv_blob).clone(),
+            spirv: spirv.into(),
         })
     }
 }
 
+// Strips a module down to a single entry point, and the functions and global variables
+// it references. Reflection would otherwise report descriptor bindings of all entry points.
+fn extract_entry_point(spirv: &[u8], entry: &str) -> Result<Vec<u8>> {
+    let words: Vec<u32> = spirv
+        .chunks_exact(4)
+        .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+        .collect();
+
+    let mut loader = rspirv::dr::Loader::new();
+    rspirv::binary::parse_words(&words, &mut loader)
+        .map_err(|err| anyhow::anyhow!("Parsing the Rust-GPU SPIR-V module: {:?}", err))?;
+    let mut module = loader.module();
+
+    let entry_point = module
+        .entry_points
+        .iter()
+        .find(|inst| matches!(inst.operands.get(2), Some(Operand::LiteralString(name)) if name == entry))
+        .cloned()
+        .with_context(|| format!("Entry point {} not found in the Rust-GPU module", entry))?;
+
+    let entry_fn = match entry_point.operands.get(1) {
+        Some(Operand::IdRef(id)) => *id,
+        _ => anyhow::bail!("Malformed OpEntryPoint for {}", entry),
+    };
+
+    let function_indices: HashMap<u32, usize> = module
+        .functions
+        .iter()
+        .enumerate()
+        .filter_map(|(i, func)| Some((func.def.as_ref()?.result_id?, i)))
+        .collect();
+
+    let function_instructions = |func: &rspirv::dr::Function| -> Vec<Instruction> {
+        func.parameters
+            .iter()
+            .chain(
+                func.blocks
+                    .iter()
+                    .flat_map(|block| block.label.iter().chain(block.instructions.iter())),
+            )
+            .cloned()
+            .collect()
+    };
+
+    // Walk the call graph, collecting all referenced ids
+    let mut referenced_ids: HashSet<u32> = id_refs(&entry_point).collect();
+    let mut reachable_functions = HashSet::new();
+    let mut pending_functions = vec![entry_fn];
+
+    while let Some(func_id) = pending_functions.pop() {
+        if !reachable_functions.insert(func_id) {
+            continue;
+        }
+
+        let func = &module.functions[function_indices[&func_id]];
+        for inst in function_instructions(func) {
+            for id in id_refs(&inst) {
+                referenced_ids.insert(id);
+                if function_indices.contains_key(&id) {
+                    pending_functions.push(id);
+                }
+            }
+        }
+    }
+
+    module.entry_points = vec![entry_point];
+    module.execution_modes.retain(
+        |inst| matches!(inst.operands.first(), Some(Operand::IdRef(id)) if *id == entry_fn),
+    );
+    module.functions.retain(|func| {
+        func.def
+            .as_ref()
+            .and_then(|def| def.result_id)
+            .map_or(false, |id| reachable_functions.contains(&id))
+    });
+    module.types_global_values.retain(|inst| {
+        inst.class.opcode != rspirv::spirv::Op::Variable
+            || inst
+                .result_id
+                .map_or(true, |id| referenced_ids.contains(&id))
+    });
+
+    // Drop debug names and decorations of anything removed above
+    let mut defined_ids: HashSet<u32> = module
+        .types_global_values
+        .iter()
+        .filter_map(|inst| inst.result_id)
+        .collect();
+    for func in &module.functions {
+        defined_ids.extend(func.def.as_ref().and_then(|def| def.result_id));
+        defined_ids.extend(
+            function_instructions(func)
+                .iter()
+                .filter_map(|inst| inst.result_id),
+        );
+    }
+
+    let targets_defined_id = |inst: &Instruction| match inst.operands.first() {
+        Some(Operand::IdRef(id)) => defined_ids.contains(id),
+        _ => true,
+    };
+    module.debug_names.retain(targets_defined_id);
+    module.annotations.retain(targets_defined_id);
+
+    Ok(module
+        .assemble()
+        .into_iter()
+        .flat_map(|word| word.to_le_bytes())
+        .collect())
+}
+
+fn id_refs(inst: &Instruction) -> impl Iterator<Item = u32> + '_ {
+    inst.operands.iter().filter_map(|op| match op {
+        Operand::IdRef(id) => Some(*id),
+        _ => None,
+    })
+}
+
+lazy_static::lazy_static! {
+    // rustc diagnostics from the last Rust-GPU build, if it failed.
+    static ref LAST_BUILD_DIAGNOSTIC: Mutex<Option<String>> = Mutex::new(None);
+}
+
 #[derive(DeJson)]
 struct RustShaderCompileResult {
     // entry name -> shader path
@@ -92,9 +238,12 @@ impl LazyWorker for CompileRustShaderCrate {
         // Spawn the worker thread.
         std::thread::spawn(move || -> anyhow::Result<()> {
             log::info!("Building Rust-GPU shaders in the background...");
+            let t0 = std::time::Instant::now();
 
             if let Err(err) = compile_rust_shader_crate_thread(cancel_rx) {
                 log::error!("Failed to build Rust-GPU shaders. Falling back to the previously compiled ones. Error: {:?}", err);
+            } else {
+                log::info!("Building Rust-GPU shaders took {:?}", t0.elapsed());
             }
 
             Ok(())
@@ -107,14 +256,8 @@ impl LazyWorker for CompileRustShaderCrate {
         ];
 
         for src_dir in src_dirs {
-            let invalidation_trigger = ctx.get_invalidation_trigger();
-            crate::file::FILE_WATCHER
-                .lock()
-                .watch(src_dir.clone(), move |event| {
-                    if matches!(event, hotwatch::Event::Write(_)) {
-                        invalidation_trigger();
-                    }
-                })
+            crate::asset_watcher::AssetWatcher::global()
+                .watch_once(src_dir.clone(), ctx.get_invalidation_trigger())
                 .with_context(|| {
                     format!("CompileRustShaderCrate: trying to watch {:?}", src_dir)
                 })?;
@@ -180,10 +323,40 @@ fn compile_rust_shader_crate_thread(
     if !output.status.success() {
         let err = String::from_utf8(output.stderr)?;
         let out = String::from_utf8(output.stdout)?;
+        *LAST_BUILD_DIAGNOSTIC.lock() = Some(extract_rustc_diagnostics(&err));
         anyhow::bail!("Shader builder failed:\n {}\n{}", out, err)
     } else {
+        *LAST_BUILD_DIAGNOSTIC.lock() = None;
         log::info!("Rust-GPU cargo process finished.");
     }
 
     Ok(())
 }
+
+// Cargo's output is mostly progress lines; keep only the `error` blocks emitted by rustc.
+fn extract_rustc_diagnostics(stderr: &str) -> String {
+    let mut result = String::new();
+    let mut in_error = false;
+
+    for line in stderr.lines() {
+        if line.starts_with("error") {
+            in_error = true;
+        } else if line.trim().is_empty() {
+            if in_error {
+                result.push('\n');
+            }
+            in_error = false;
+        }
+
+        if in_error {
+            result += line;
+            result.push('\n');
+        }
+    }
+
+    if result.is_empty() {
+        stderr.to_owned()
+    } else {
+        result
+    }
+}
@@ -1,8 +1,8 @@
-use crate::file::LoadFile;
+use crate::{file::LoadFile, KajiyaError};
 use anyhow::{anyhow, bail, Context, Result};
 use bytes::Bytes;
 use relative_path::RelativePathBuf;
-use std::{path::PathBuf, sync::Arc};
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
 use turbosloth::*;
 
 pub struct CompiledShader {
@@ -14,6 +14,7 @@ pub struct CompiledShader {
 pub struct CompileShader {
     pub path: PathBuf,
     pub profile: String,
+    pub defines: Vec<(String, String)>,
 }
 
 #[async_trait]
@@ -41,16 +42,25 @@ impl LazyWorker for CompileShader {
             }
             "hlsl" => {
                 let file_path = self.path.to_str().unwrap().to_owned();
-                let source = shader_prepper::process_file(
-                    &file_path,
-                    &mut ShaderIncludeProvider { ctx },
-                    String::new(),
-                );
-                let source = source
-                    .map_err(|err| anyhow!("{}", err))
-                    .with_context(|| format!("shader path: {:?}", self.path))?;
+                let mut include_provider = ShaderIncludeProvider::new(ctx);
+                let source =
+                    shader_prepper::process_file(&file_path, &mut include_provider, String::new());
+                let source = source.map_err(|err| KajiyaError::ShaderCompile {
+                    path: file_path.clone(),
+                    message: format!("{:#}", err),
+                })?;
                 let target_profile = format!("{}_6_4", self.profile);
-                let spirv = compile_generic_shader_hlsl_impl(&name, &source, &target_profile)?;
+                let spirv = compile_generic_shader_hlsl_impl(
+                    &name,
+                    &source,
+                    &target_profile,
+                    &self.defines,
+                )
+                .map_err(|err| KajiyaError::ShaderCompile {
+                    message: SourceMap::new(&source, include_provider.include_parents)
+                        .annotate_diagnostic(&name, &err.to_string()),
+                    path: file_path,
+                })?;
 
                 Ok(CompiledShader { name, spirv })
             }
@@ -67,6 +77,7 @@ pub struct RayTracingShader {
 #[derive(Clone, Hash)]
 pub struct CompileRayTracingShader {
     pub path: PathBuf,
+    pub defines: Vec<(String, String)>,
 }
 
 #[async_trait]
@@ -75,12 +86,12 @@ impl LazyWorker for CompileRayTracingShader {
 
     async fn run(self, ctx: RunContext) -> Self::Output {
         let file_path = self.path.to_str().unwrap().to_owned();
-        let source = shader_prepper::process_file(
-            &file_path,
-            &mut ShaderIncludeProvider { ctx },
-            String::new(),
-        );
-        let source = source.map_err(|err| anyhow!("{}", err))?;
+        let mut include_provider = ShaderIncludeProvider::new(ctx);
+        let source = shader_prepper::process_file(&file_path, &mut include_provider, String::new());
+        let source = source.map_err(|err| KajiyaError::ShaderCompile {
+            path: file_path.clone(),
+            message: format!("{:#}", err),
+        })?;
 
         let ext = self
             .path
@@ -98,7 +109,13 @@ impl LazyWorker for CompileRayTracingShader {
             "glsl" => unimplemented!(),
             "hlsl" => {
                 let target_profile = "lib_6_4";
-                let spirv = compile_generic_shader_hlsl_impl(&name, &source, target_profile)?;
+                let spirv =
+                    compile_generic_shader_hlsl_impl(&name, &source, target_profile, &self.defines)
+                        .map_err(|err| KajiyaError::ShaderCompile {
+                            message: SourceMap::new(&source, include_provider.include_parents)
+                                .annotate_diagnostic(&name, &err.to_string()),
+                            path: file_path,
+                        })?;
 
                 Ok(RayTracingShader { name, spirv })
             }
@@ -109,6 +126,19 @@ impl LazyWorker for CompileRayTracingShader {
 
 struct ShaderIncludeProvider {
     ctx: RunContext,
+
+    // Included file -> the file which included it. Keyed by both the path
+    // as written in the `#include`, and the resolved one.
+    include_parents: HashMap<String, String>,
+}
+
+impl ShaderIncludeProvider {
+    fn new(ctx: RunContext) -> Self {
+        Self {
+            ctx,
+            include_parents: Default::default(),
+        }
+    }
 }
 
 impl<'a> shader_prepper::IncludeProvider for ShaderIncludeProvider {
@@ -137,10 +167,149 @@ impl<'a> shader_prepper::IncludeProvider for ShaderIncludeProvider {
                 .eval(&self.ctx),
         )?;
 
+        self.include_parents
+            .insert(path.to_owned(), parent_file.clone());
+        self.include_parents
+            .insert(resolved_path.clone(), parent_file.clone());
+
         Ok((String::from_utf8(blob.to_vec())?, resolved_path))
     }
 }
 
+// Maps lines of the preprocessed source passed to the compiler back to the original files.
+struct SourceMap {
+    chunks: Vec<SourceMapChunk>,
+    include_parents: HashMap<String, String>,
+}
+
+struct SourceMapChunk {
+    file: String,
+    // 1-based line in the preprocessed source at which this chunk begins
+    first_line: usize,
+    // 0-based line in `file` at which this chunk begins
+    line_offset: usize,
+}
+
+impl SourceMap {
+    fn new(
+        source: &[shader_prepper::SourceChunk],
+        include_parents: HashMap<String, String>,
+    ) -> Self {
+        Self::from_parts(
+            source.iter().map(|chunk| {
+                (
+                    chunk.file.as_str(),
+                    chunk.source.as_str(),
+                    chunk.line_offset,
+                )
+            }),
+            include_parents,
+        )
+    }
+
+    fn from_parts<'a>(
+        chunks: impl Iterator<Item = (&'a str, &'a str, usize)>,
+        include_parents: HashMap<String, String>,
+    ) -> Self {
+        let mut first_line = 1;
+        let chunks = chunks
+            .map(|(file, source, line_offset)| {
+                let chunk = SourceMapChunk {
+                    file: file.to_owned(),
+                    first_line,
+                    line_offset,
+                };
+                first_line += source.matches('\n').count();
+                chunk
+            })
+            .collect();
+
+        Self {
+            chunks,
+            include_parents,
+        }
+    }
+
+    // Returns the file and 1-based line within it
+    fn locate(&self, line: usize) -> Option<(&str, usize)> {
+        let chunk = self
+            .chunks
+            .iter()
+            .rev()
+            .find(|chunk| chunk.first_line <= line)?;
+
+        Some((
+            chunk.file.as_str(),
+            chunk.line_offset + line - chunk.first_line + 1,
+        ))
+    }
+
+    fn include_stack<'a>(&'a self, mut file: &'a str) -> Vec<&'a str> {
+        let mut stack = Vec::new();
+        while let Some(parent) = self.include_parents.get(file) {
+            // Guard against cycles
+            if stack.contains(&parent.as_str()) {
+                break;
+            }
+
+            stack.push(parent.as_str());
+            file = parent;
+        }
+        stack
+    }
+
+    // The compiler reports locations as `name:line:column: ...` within the preprocessed source.
+    // Rewrite them to point at the original files, and list the include stack for each.
+    fn annotate_diagnostic(&self, name: &str, diagnostic: &str) -> String {
+        let prefix = format!("{}:", name);
+        let mut result = String::new();
+
+        for line in diagnostic.lines() {
+            let location = line.strip_prefix(&prefix).and_then(|rest| {
+                let (line_number, rest) = rest.split_once(':')?;
+                let line_number: usize = line_number.parse().ok()?;
+                let (file, file_line) = self.locate(line_number)?;
+                Some((file, file_line, rest))
+            });
+
+            if let Some((file, file_line, rest)) = location {
+                result += &format!("{}:{}:{}\n", file, file_line, rest);
+                for parent in self.include_stack(file) {
+                    result += &format!("    included from {}\n", parent);
+                }
+            } else {
+                result += line;
+                result += "\n";
+            }
+        }
+
+        result
+    }
+}
+
+/// Highlights errors and warnings in a compiler diagnostic with ANSI colors,
+/// unless disabled via the `NO_COLOR` environment variable.
+pub fn colorize_diagnostic(diagnostic: &str) -> String {
+    if std::env::var_os("NO_COLOR").is_some() {
+        return diagnostic.to_owned();
+    }
+
+    diagnostic
+        .lines()
+        .map(|line| {
+            if line.contains("error:") || line.contains("error[") {
+                format!("\x1b[1;31m{}\x1b[0m\n", line)
+            } else if line.contains("warning:") || line.contains("warning[") {
+                format!("\x1b[1;33m{}\x1b[0m\n", line)
+            } else if line.contains("note:") || line.trim_start().starts_with("included from") {
+                format!("\x1b[36m{}\x1b[0m\n", line)
+            } else {
+                format!("{}\n", line)
+            }
+        })
+        .collect()
+}
+
 pub fn get_cs_local_size_from_spirv(spirv: &[u32]) -> Result<[u32; 3]> {
     let mut loader = rspirv::dr::Loader::new();
     rspirv::binary::parse_words(spirv, &mut loader).unwrap();
@@ -167,6 +336,7 @@ fn compile_generic_shader_hlsl_impl(
     name: &str,
     source: &[shader_prepper::SourceChunk],
     target_profile: &str,
+    defines: &[(String, String)],
 ) -> Result<Bytes> {
     let mut source_text = String::new();
     for s in source {
@@ -187,7 +357,10 @@ fn compile_generic_shader_hlsl_impl(
             "-WX",  // warnings as errors
             "-Ges", // strict mode
         ],
-        &[],
+        &defines
+            .iter()
+            .map(|(name, value)| (name.as_str(), Some(value.as_str())))
+            .collect::<Vec<_>>(),
     )
     .map_err(|err| anyhow!("{}", err))?;
 
@@ -195,3 +368,56 @@ fn compile_generic_shader_hlsl_impl(
 
     Ok(spirv.into())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn broken_shader_diagnostic_points_at_original_file() {
+        let main_source = "#include \"inc/math.hlsl\"\n[numthreads(8, 8, 1)]\nvoid main() {\n    float x = undeclared_thing;\n}\n";
+        let include_source = "float square(float x) {\n    return x * x;\n}\n";
+
+        // Chunks as produced by the preprocessor: the include replaces the first line of `main`.
+        let chunks = [
+            ("inc/math.hlsl", include_source, 0),
+            (
+                "/shaders/broken.hlsl",
+                &main_source[main_source.find('\n').unwrap() + 1..],
+                1,
+            ),
+        ];
+        let mut include_parents = HashMap::new();
+        include_parents.insert(
+            "inc/math.hlsl".to_owned(),
+            "/shaders/broken.hlsl".to_owned(),
+        );
+
+        let source_map = SourceMap::from_parts(chunks.iter().copied(), include_parents);
+
+        // Line 6 of the preprocessed source is line 4 of the main file.
+        let diagnostic = source_map.annotate_diagnostic(
+            "broken",
+            "broken:6:15: error: use of undeclared identifier 'undeclared_thing'\n    float x = undeclared_thing;\n              ^",
+        );
+        assert!(
+            diagnostic
+                .starts_with("/shaders/broken.hlsl:4:15: error: use of undeclared identifier"),
+            "{}",
+            diagnostic
+        );
+
+        let diagnostic = source_map
+            .annotate_diagnostic("broken", "broken:2:12: error: something in the include");
+        assert!(
+            diagnostic.contains("inc/math.hlsl:2:12: error"),
+            "{}",
+            diagnostic
+        );
+        assert!(
+            diagnostic.contains("included from /shaders/broken.hlsl"),
+            "{}",
+            diagnostic
+        );
+    }
+}
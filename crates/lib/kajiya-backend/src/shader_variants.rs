@@ -0,0 +1,224 @@
+// Permutations of HLSL shaders, selected by features declared up front.
+//
+// A shader declares its features with `ShaderFeatures`: booleans, and enums with a fixed set of
+// values. A `ShaderVariant` picks a value for some of them; the rest take their defaults. Each
+// variant compiles to its own pipeline, with the features passed to the compiler as defines:
+//
+// * `bool` features are defined as `1` or `0`, for `#if NAME`,
+// * `enum` features are defined as the index of their value, and each value `V` of feature
+//   `NAME` as `NAME_V`, for `#if NAME == NAME_V`.
+//
+// Variants are registered like any other pipeline (see `PipelineCache::register_compute_variant`,
+// `register_raster_variant` and `register_ray_tracing_variant`), so only those requested are ever
+// compiled, and each is cached by its defines. The shaders of a raster or ray tracing pipeline
+// share its features; each HLSL shader among them gets the same defines.
+
+use crate::vulkan::shader::{PipelineShaderDesc, ShaderSource};
+use anyhow::{bail, ensure, Context};
+use std::collections::BTreeMap;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum ShaderFeatureKind {
+    /// `false` by default
+    Bool,
+
+    /// The first value by default
+    Enum(Vec<String>),
+}
+
+/// The features a shader can be compiled with.
+#[derive(Clone, Default, Debug)]
+pub struct ShaderFeatures {
+    features: Vec<(String, ShaderFeatureKind)>,
+}
+
+impl ShaderFeatures {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn bool(mut self, name: impl Into<String>) -> Self {
+        self.features.push((name.into(), ShaderFeatureKind::Bool));
+        self
+    }
+
+    pub fn enumeration(mut self, name: impl Into<String>, values: &[&str]) -> Self {
+        assert!(
+            !values.is_empty(),
+            "An enum feature needs at least one value"
+        );
+
+        self.features.push((
+            name.into(),
+            ShaderFeatureKind::Enum(values.iter().map(|&value| value.to_owned()).collect()),
+        ));
+        self
+    }
+
+    /// The number of variants of the shader; only those requested are compiled.
+    pub fn permutation_count(&self) -> usize {
+        self.features
+            .iter()
+            .map(|(_, kind)| match kind {
+                ShaderFeatureKind::Bool => 2,
+                ShaderFeatureKind::Enum(values) => values.len(),
+            })
+            .product()
+    }
+
+    /// The defines compiling `variant`, sorted by name. Fails if it sets features which
+    /// weren't declared, or enum values which don't exist.
+    pub fn defines(&self, variant: &ShaderVariant) -> anyhow::Result<Vec<(String, String)>> {
+        if let Some(unknown) = variant
+            .values
+            .keys()
+            .find(|name| !self.features.iter().any(|(feature, _)| feature == *name))
+        {
+            bail!("Unknown shader feature {:?}", unknown);
+        }
+
+        let mut defines = Vec::new();
+        for (name, kind) in &self.features {
+            let value = variant.values.get(name);
+
+            match kind {
+                ShaderFeatureKind::Bool => {
+                    let enabled = match value.map(String::as_str) {
+                        None | Some("0") => false,
+                        Some("1") => true,
+                        Some(value) => bail!("{:?} is a bool feature, not {:?}", name, value),
+                    };
+                    defines.push((name.clone(), (enabled as u32).to_string()));
+                }
+                ShaderFeatureKind::Enum(values) => {
+                    let index = match value {
+                        Some(value) => {
+                            values.iter().position(|v| v == value).with_context(|| {
+                                format!(
+                                    "{:?} has no value {:?}; expected one of {:?}",
+                                    name, value, values
+                                )
+                            })?
+                        }
+                        None => 0,
+                    };
+
+                    defines.push((name.clone(), index.to_string()));
+                    defines.extend(
+                        values
+                            .iter()
+                            .enumerate()
+                            .map(|(i, value)| (format!("{}_{}", name, value), i.to_string())),
+                    );
+                }
+            }
+        }
+
+        defines.sort();
+        Ok(defines)
+    }
+}
+
+/// `shaders` of a raster or ray tracing pipeline, with the HLSL ones compiled as `variant`
+/// of `features`. Rust shaders are left as they are; fails if there are only Rust shaders.
+pub fn pipeline_shader_variants(
+    shaders: &[PipelineShaderDesc],
+    features: &ShaderFeatures,
+    variant: &ShaderVariant,
+) -> anyhow::Result<Vec<PipelineShaderDesc>> {
+    ensure!(
+        shaders
+            .iter()
+            .any(|shader| matches!(shader.source, ShaderSource::Hlsl { .. })),
+        "Shader variants need HLSL; the pipeline has only Rust shaders"
+    );
+
+    shaders
+        .iter()
+        .map(|shader| {
+            let source = match &shader.source {
+                ShaderSource::Hlsl { path, .. } => {
+                    ShaderSource::hlsl_variant(path.clone(), features, variant)?
+                }
+                ShaderSource::Rust { .. } => shader.source.clone(),
+            };
+
+            Ok(PipelineShaderDesc {
+                source,
+                ..shader.clone()
+            })
+        })
+        .collect()
+}
+
+/// Values of some of the features of a shader; see `ShaderFeatures`.
+#[derive(Clone, Default, Hash, PartialEq, Eq, Debug)]
+pub struct ShaderVariant {
+    values: BTreeMap<String, String>,
+}
+
+impl ShaderVariant {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets a `bool` feature.
+    pub fn feature(mut self, name: impl Into<String>, enabled: bool) -> Self {
+        self.values
+            .insert(name.into(), (enabled as u32).to_string());
+        self
+    }
+
+    /// Sets an `enum` feature.
+    pub fn value(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.values.insert(name.into(), value.into());
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn variants_define_every_declared_feature() {
+        let features = ShaderFeatures::new()
+            .bool("USE_SHADOWS")
+            .enumeration("QUALITY", &["LOW", "MEDIUM", "HIGH"]);
+        assert_eq!(features.permutation_count(), 6);
+
+        let define = |name: &str, value: &str| (name.to_owned(), value.to_owned());
+
+        // Unset features take their defaults.
+        assert_eq!(
+            features.defines(&ShaderVariant::new()).unwrap(),
+            vec![
+                define("QUALITY", "0"),
+                define("QUALITY_HIGH", "2"),
+                define("QUALITY_LOW", "0"),
+                define("QUALITY_MEDIUM", "1"),
+                define("USE_SHADOWS", "0"),
+            ]
+        );
+
+        let defines = features
+            .defines(
+                &ShaderVariant::new()
+                    .feature("USE_SHADOWS", true)
+                    .value("QUALITY", "HIGH"),
+            )
+            .unwrap();
+        assert!(defines.contains(&define("QUALITY", "2")));
+        assert!(defines.contains(&define("USE_SHADOWS", "1")));
+
+        let err = features
+            .defines(&ShaderVariant::new().value("QUALITY", "ULTRA"))
+            .unwrap_err();
+        assert!(err.to_string().contains("ULTRA"), "{}", err);
+
+        let err = features
+            .defines(&ShaderVariant::new().feature("USE_FOG", true))
+            .unwrap_err();
+        assert!(err.to_string().contains("USE_FOG"), "{}", err);
+    }
+}
@@ -0,0 +1,285 @@
+// Checks that structs shared between the CPU and shaders agree on their layout.
+//
+// A `StructLayout` is the byte offsets of the members of a struct, and its size when known. Those of
+// a shader struct are reflected from SPIR-V (`StructLayout::from_spirv`, or `from_hlsl` to compile
+// the shader first), and those of a `#[repr(C)]` Rust struct listed with `struct_layout!`.
+// `StructLayout::check` then names every member which differs:
+//
+//     let shader = StructLayout::from_hlsl("/shaders/foo.hlsl", "cs", "Foo")?;
+//     shader.check(&struct_layout!(Foo { a, b, c }))?;
+//
+// Members are matched by name. Padding (members whose names start with `pad`) is often named
+// differently on either side, so it's left out; the offsets of the members after it check it.
+
+use anyhow::{bail, Context, Result};
+use rspirv::{
+    dr::{
+        Module,
+        Operand::{Decoration, IdRef, LiteralInt32, LiteralString},
+    },
+    spirv::{self, Op, Word},
+};
+use turbosloth::*;
+
+use crate::shader_compiler::CompileShader;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct StructLayout {
+    pub name: String,
+
+    /// The stride of the struct in arrays. Shaders only define it for structs in arrays,
+    /// e.g. those of a `StructuredBuffer`.
+    pub size: Option<u32>,
+
+    /// Byte offsets of the members, in declaration order
+    pub members: Vec<(String, u32)>,
+}
+
+impl StructLayout {
+    /// Reflects the struct called `struct_name` from `spirv`, as laid out in buffers.
+    /// DXC names the block of a `ConstantBuffer<T>` `type.ConstantBuffer.T`, which `T` matches too.
+    pub fn from_spirv(spirv: &[u32], struct_name: &str) -> Result<Self> {
+        let mut loader = rspirv::dr::Loader::new();
+        rspirv::binary::parse_words(spirv, &mut loader)
+            .map_err(|err| anyhow::anyhow!("{:?}", err))?;
+        let module = loader.module();
+
+        // Copies of the struct outside of buffers are types of their own, without offsets.
+        let block_name = format!("type.ConstantBuffer.{}", struct_name);
+        let struct_id = module
+            .debug_names
+            .iter()
+            .find_map(|inst| match (inst.class.opcode, inst.operands.as_slice()) {
+                (Op::Name, [IdRef(id), LiteralString(name)])
+                    if (*name == struct_name || *name == block_name)
+                        && member_offset(&module, *id, 0).is_some() =>
+                {
+                    Some(*id)
+                }
+                _ => None,
+            })
+            .with_context(|| format!("No struct called {:?} in a buffer", struct_name))?;
+
+        let mut members: Vec<(u32, String)> = module
+            .debug_names
+            .iter()
+            .filter_map(|inst| match (inst.class.opcode, inst.operands.as_slice()) {
+                (Op::MemberName, [IdRef(id), LiteralInt32(member), LiteralString(name)])
+                    if *id == struct_id =>
+                {
+                    Some((*member, name.clone()))
+                }
+                _ => None,
+            })
+            .collect();
+        members.sort();
+
+        let members = members
+            .into_iter()
+            .map(|(member, name)| {
+                let offset = member_offset(&module, struct_id, member)
+                    .with_context(|| format!("{}.{} has no offset", struct_name, name))?;
+                Ok((name, offset))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            name: struct_name.to_owned(),
+            size: array_stride(&module, struct_id),
+            members,
+        })
+    }
+
+    /// Compiles the shader at the VFS `path` with the `profile` (`cs`, `vs`, ...),
+    /// and reflects the struct called `struct_name` from it; see `from_spirv`.
+    pub fn from_hlsl(path: &str, profile: &str, struct_name: &str) -> Result<Self> {
+        let shader = smol::block_on(
+            CompileShader {
+                path: path.into(),
+                profile: profile.to_owned(),
+                defines: Vec::new(),
+            }
+            .into_lazy()
+            .eval(&LazyCache::create()),
+        )?;
+
+        // `Bytes` aren't necessarily aligned for words.
+        let spirv: Vec<u32> = shader
+            .spirv
+            .chunks_exact(4)
+            .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+            .collect();
+
+        Self::from_spirv(&spirv, struct_name).with_context(|| format!("Reflecting {}", path))
+    }
+
+    /// Fails with every member whose offset differs between `self` (usually reflected from
+    /// a shader) and `cpu`, or which only one of them has, and with their sizes if both
+    /// are known and differ.
+    pub fn check(&self, cpu: &StructLayout) -> Result<()> {
+        let is_padding = |name: &str| name.starts_with("pad");
+        let offset_of = |layout: &StructLayout, name: &str| {
+            layout
+                .members
+                .iter()
+                .find(|(member, _)| member == name)
+                .map(|(_, offset)| *offset)
+        };
+
+        let mut names: Vec<&str> = Vec::new();
+        for (name, _) in self.members.iter().chain(cpu.members.iter()) {
+            if !is_padding(name) && !names.contains(&name.as_str()) {
+                names.push(name);
+            }
+        }
+
+        let mut mismatches = Vec::new();
+        for name in names {
+            match (offset_of(self, name), offset_of(cpu, name)) {
+                (Some(shader), Some(cpu)) if shader != cpu => mismatches.push(format!(
+                    "{}: at offset {} in the shader, but {} on the CPU",
+                    name, shader, cpu
+                )),
+                (Some(_), None) => mismatches.push(format!("{}: missing on the CPU", name)),
+                (None, Some(_)) => mismatches.push(format!("{}: missing in the shader", name)),
+                _ => {}
+            }
+        }
+
+        if let (Some(shader), Some(cpu)) = (self.size, cpu.size) {
+            if shader != cpu {
+                mismatches.push(format!(
+                    "size: {} bytes in the shader, but {} on the CPU",
+                    shader, cpu
+                ));
+            }
+        }
+
+        if !mismatches.is_empty() {
+            bail!(
+                "The layout of {} differs from that of {}:\n  {}",
+                cpu.name,
+                self.name,
+                mismatches.join("\n  ")
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// The `StructLayout` of a `#[repr(C)]` struct, with the listed fields:
+/// `struct_layout!(FrameConstants { view_constants, sun_direction, ... })`.
+#[macro_export]
+macro_rules! struct_layout {
+    ($ty:ty { $($field:ident),* $(,)? }) => {{
+        let value = ::std::mem::MaybeUninit::<$ty>::uninit();
+        let base = value.as_ptr();
+
+        $crate::struct_layout::StructLayout {
+            name: stringify!($ty).to_owned(),
+            size: Some(::std::mem::size_of::<$ty>() as u32),
+            members: vec![$((
+                stringify!($field).to_owned(),
+                // Safety: only takes the address of the field, without reading it.
+                (unsafe { ::std::ptr::addr_of!((*base).$field) } as usize - base as usize) as u32,
+            )),*],
+        }
+    }};
+}
+
+fn member_offset(module: &Module, struct_id: Word, member: u32) -> Option<u32> {
+    module
+        .annotations
+        .iter()
+        .find_map(|inst| match inst.operands.as_slice() {
+            [IdRef(id), LiteralInt32(m), Decoration(decoration), LiteralInt32(offset)]
+                if inst.class.opcode == Op::MemberDecorate
+                    && *decoration == spirv::Decoration::Offset
+                    && (*id, *m) == (struct_id, member) =>
+            {
+                Some(*offset)
+            }
+            _ => None,
+        })
+}
+
+// The stride of the first array of the struct with `struct_id`
+fn array_stride(module: &Module, struct_id: Word) -> Option<u32> {
+    module
+        .types_global_values
+        .iter()
+        .filter(|inst| {
+            matches!(inst.class.opcode, Op::TypeArray | Op::TypeRuntimeArray)
+                && matches!(inst.operands.first(), Some(IdRef(element)) if *element == struct_id)
+        })
+        .find_map(|array| {
+            module
+                .annotations
+                .iter()
+                .find_map(|inst| match inst.operands.as_slice() {
+                    [IdRef(id), Decoration(decoration), LiteralInt32(stride)]
+                        if inst.class.opcode == Op::Decorate
+                            && *decoration == spirv::Decoration::ArrayStride
+                            && Some(*id) == array.result_id =>
+                    {
+                        Some(*stride)
+                    }
+                    _ => None,
+                })
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[repr(C)]
+    #[allow(dead_code)]
+    struct Example {
+        a: [f32; 4],
+        pad0: u32,
+        b: u32,
+    }
+
+    #[test]
+    fn mismatches_name_the_differing_members() {
+        let cpu = struct_layout!(Example { a, pad0, b });
+        assert_eq!(cpu.size, Some(24));
+        assert_eq!(cpu.members[2], ("b".to_owned(), 20));
+
+        // Padding is named differently in the shader.
+        let shader = StructLayout {
+            name: "Example".to_owned(),
+            size: Some(24),
+            members: vec![
+                ("a".to_owned(), 0),
+                ("pad".to_owned(), 16),
+                ("b".to_owned(), 20),
+            ],
+        };
+        shader.check(&cpu).unwrap();
+
+        let shader = StructLayout {
+            size: Some(32),
+            members: vec![
+                ("a".to_owned(), 0),
+                ("b".to_owned(), 16),
+                ("c".to_owned(), 20),
+            ],
+            ..shader
+        };
+        let err = shader.check(&cpu).unwrap_err().to_string();
+        assert!(
+            err.contains("b: at offset 16 in the shader, but 20 on the CPU"),
+            "{}",
+            err
+        );
+        assert!(err.contains("c: missing on the CPU"), "{}", err);
+        assert!(
+            err.contains("size: 32 bytes in the shader, but 24"),
+            "{}",
+            err
+        );
+    }
+}
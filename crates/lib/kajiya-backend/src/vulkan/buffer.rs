@@ -1,8 +1,9 @@
 use crate::BackendError;
 
-use super::device::Device;
+use super::{device::Device, memory_stats::ResourceCategory};
 use ash::vk;
 use gpu_allocator::{AllocationCreateDesc, MemoryLocation};
+use std::sync::Arc;
 
 pub struct Buffer {
     pub raw: vk::Buffer,
@@ -120,6 +121,12 @@ impl Device {
         }
         let buffer =
             Self::create_buffer_impl(&self.raw, &mut self.global_allocator.lock(), desc, &name)?;
+        self.track_resource(
+            ResourceCategory::Buffer,
+            buffer.raw,
+            &name,
+            desc.size as u64,
+        );
 
         if let Some(initial_data) = initial_data {
             let scratch_desc = BufferDesc {
@@ -134,6 +141,12 @@ impl Device {
                 scratch_desc,
                 &format!("Initial data for {:?}", name),
             )?;
+            self.track_resource(
+                ResourceCategory::Buffer,
+                scratch_buffer.raw,
+                format!("Initial data for {:?}", name),
+                scratch_desc.size as u64,
+            );
 
             scratch_buffer.allocation.mapped_slice_mut().unwrap()[0..initial_data.len()]
                 .copy_from_slice(initial_data);
@@ -154,4 +167,38 @@ impl Device {
 
         Ok(buffer)
     }
+
+    /// Makes GPU writes to the mapped memory of `buffer` visible to the host. Needed before reading
+    /// it back unless the memory is host-coherent, which `GpuToCpu` memory doesn't have to be.
+    pub fn invalidate_mapped_buffer(&self, buffer: &Buffer) -> Result<(), BackendError> {
+        let atom_size = self.pdevice.properties.limits.non_coherent_atom_size.max(1);
+
+        // The range must be aligned to the atom size; the memory block is, by a wide margin.
+        let offset = buffer.allocation.offset() / atom_size * atom_size;
+        let end = buffer.allocation.offset() + buffer.allocation.size();
+        let size = (end - offset + atom_size - 1) / atom_size * atom_size;
+
+        unsafe {
+            self.raw
+                .invalidate_mapped_memory_ranges(&[vk::MappedMemoryRange::builder()
+                    .memory(buffer.allocation.memory())
+                    .offset(offset)
+                    .size(size)
+                    .build()])?;
+        }
+
+        Ok(())
+    }
+
+    /// Destroys `buffer` and frees its memory once the GPU is done with the frame being recorded,
+    /// which may still use it, and no other references to it remain. `buffer` must not be used
+    /// in later frames.
+    pub fn defer_release_buffer(&self, buffer: Arc<Buffer>) {
+        self.untrack_resource(ResourceCategory::Buffer, buffer.raw);
+
+        match self.take_dedicated_memory(ResourceCategory::Buffer, buffer.raw) {
+            Some(memory) => self.defer_release((buffer, memory)),
+            None => self.defer_release(buffer),
+        }
+    }
 }
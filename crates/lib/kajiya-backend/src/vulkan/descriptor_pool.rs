@@ -0,0 +1,295 @@
+// Allocation of the descriptor sets which render passes bind each frame.
+//
+// Every frame in flight has its own descriptor pools, sized by `DescriptorPoolConfig`, which are
+// reset all at once when the GPU is done with the frame. When they run out, another pool of the
+// same size is added, and kept for later frames; `Device::descriptor_pool_stats` tells how often
+// that happens. A set too large for a whole pool gets one of its own, released with the frame.
+//
+// Sets with update-after-bind bindings, like the bindless textures, aren't allocated here.
+
+use super::device::Device;
+use crate::BackendError;
+use ash::vk;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The size of each descriptor pool of a frame, including those added when the frame runs out.
+#[derive(Clone, Debug)]
+pub struct DescriptorPoolConfig {
+    pub max_sets: u32,
+
+    /// Descriptors of each type per pool. Sets with types not listed here get their own pools.
+    pub pool_sizes: Vec<vk::DescriptorPoolSize>,
+}
+
+impl Default for DescriptorPoolConfig {
+    fn default() -> Self {
+        let size = |ty, descriptor_count| vk::DescriptorPoolSize {
+            ty,
+            descriptor_count,
+        };
+
+        Self {
+            max_sets: 1024,
+            pool_sizes: vec![
+                size(vk::DescriptorType::SAMPLED_IMAGE, 8192),
+                size(vk::DescriptorType::STORAGE_IMAGE, 4096),
+                size(vk::DescriptorType::STORAGE_BUFFER, 4096),
+                size(vk::DescriptorType::STORAGE_BUFFER_DYNAMIC, 1024),
+                size(vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC, 1024),
+                size(vk::DescriptorType::UNIFORM_TEXEL_BUFFER, 256),
+                size(vk::DescriptorType::SAMPLER, 1024),
+                // Dropped on devices without ray tracing
+                size(vk::DescriptorType::ACCELERATION_STRUCTURE_KHR, 256),
+            ],
+        }
+    }
+}
+
+impl DescriptorPoolConfig {
+    pub fn max_sets(mut self, max_sets: u32) -> Self {
+        self.max_sets = max_sets;
+        self
+    }
+
+    /// Replaces the count of descriptors of type `ty`, or adds it.
+    pub fn descriptor_count(mut self, ty: vk::DescriptorType, descriptor_count: u32) -> Self {
+        if let Some(size) = self.pool_sizes.iter_mut().find(|size| size.ty == ty) {
+            size.descriptor_count = descriptor_count;
+        } else {
+            self.pool_sizes.push(vk::DescriptorPoolSize {
+                ty,
+                descriptor_count,
+            });
+        }
+        self
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct DescriptorPoolStats {
+    /// Across all frames in flight
+    pub pool_count: usize,
+
+    /// The number of pools added after a frame ran out; ideally zero once the first frames
+    /// have been rendered, and a hint to raise `DescriptorPoolConfig` otherwise.
+    pub growth_count: usize,
+
+    /// Sets which didn't fit in a whole pool
+    pub oversized_set_count: usize,
+}
+
+pub(crate) struct DescriptorPools {
+    pub config: DescriptorPoolConfig,
+    pool_count: AtomicUsize,
+    growth_count: AtomicUsize,
+    oversized_set_count: AtomicUsize,
+}
+
+impl DescriptorPools {
+    pub fn new(mut config: DescriptorPoolConfig, ray_tracing_enabled: bool) -> Self {
+        // The type only exists with the extension.
+        if !ray_tracing_enabled {
+            config
+                .pool_sizes
+                .retain(|size| size.ty != vk::DescriptorType::ACCELERATION_STRUCTURE_KHR);
+        }
+
+        Self {
+            config,
+            pool_count: Default::default(),
+            growth_count: Default::default(),
+            oversized_set_count: Default::default(),
+        }
+    }
+
+    // Whether a set needing `set_sizes` fits in an empty pool
+    fn fits(&self, set_sizes: &[vk::DescriptorPoolSize]) -> bool {
+        self.config.max_sets > 0
+            && set_sizes.iter().all(|needed| {
+                self.config.pool_sizes.iter().any(|size| {
+                    size.ty == needed.ty && size.descriptor_count >= needed.descriptor_count
+                })
+            })
+    }
+
+    pub(crate) fn record_oversized_set(&self) {
+        self.oversized_set_count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> DescriptorPoolStats {
+        DescriptorPoolStats {
+            pool_count: self.pool_count.load(Ordering::Relaxed),
+            growth_count: self.growth_count.load(Ordering::Relaxed),
+            oversized_set_count: self.oversized_set_count.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The descriptor pools of one frame in flight.
+#[derive(Default)]
+pub struct FrameDescriptorPools {
+    pools: Vec<vk::DescriptorPool>,
+
+    // Index into `pools` of the one being allocated from
+    current: usize,
+}
+
+impl FrameDescriptorPools {
+    /// Frees all sets allocated from the pools. The GPU must be done with the frame.
+    pub(crate) fn reset(&mut self, device: &ash::Device) {
+        for &pool in &self.pools {
+            unsafe {
+                device
+                    .reset_descriptor_pool(pool, vk::DescriptorPoolResetFlags::empty())
+                    .unwrap();
+            }
+        }
+
+        self.current = 0;
+    }
+
+    /// Allocates a set of `layout`, which needs `set_sizes`, adding a pool if the frame is out
+    /// of descriptors. Returns `None` if the set doesn't fit in a whole pool.
+    pub(crate) fn allocate(
+        &mut self,
+        device: &Device,
+        layout: vk::DescriptorSetLayout,
+        set_sizes: &[vk::DescriptorPoolSize],
+    ) -> Result<Option<vk::DescriptorSet>, BackendError> {
+        let pools = &device.descriptor_pools;
+        if !pools.fits(set_sizes) {
+            return Ok(None);
+        }
+
+        loop {
+            let created = self.current == self.pools.len();
+            if created {
+                // The first pool of a frame is expected; any others mean it ran out.
+                if !self.pools.is_empty() {
+                    let growth_count = pools.growth_count.fetch_add(1, Ordering::Relaxed) + 1;
+                    log::info!(
+                        "A frame ran out of descriptors in {} pool(s); adding another ({} added so far). \
+                        Raising `DescriptorPoolConfig` avoids this.",
+                        self.pools.len(),
+                        growth_count,
+                    );
+                }
+
+                self.pools.push(create_pool(
+                    &device.raw,
+                    &pools.config.pool_sizes,
+                    pools.config.max_sets,
+                )?);
+                pools.pool_count.fetch_add(1, Ordering::Relaxed);
+            }
+
+            match allocate_set(&device.raw, self.pools[self.current], layout) {
+                Ok(set) => return Ok(Some(set)),
+                Err(vk::Result::ERROR_OUT_OF_POOL_MEMORY | vk::Result::ERROR_FRAGMENTED_POOL) => {
+                    if created {
+                        return Ok(None);
+                    }
+
+                    self.current += 1;
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+}
+
+pub(crate) fn create_pool(
+    device: &ash::Device,
+    pool_sizes: &[vk::DescriptorPoolSize],
+    max_sets: u32,
+) -> Result<vk::DescriptorPool, BackendError> {
+    let create_info = vk::DescriptorPoolCreateInfo::builder()
+        .max_sets(max_sets)
+        .pool_sizes(pool_sizes);
+
+    Ok(unsafe { device.create_descriptor_pool(&create_info, None)? })
+}
+
+pub(crate) fn allocate_set(
+    device: &ash::Device,
+    pool: vk::DescriptorPool,
+    layout: vk::DescriptorSetLayout,
+) -> Result<vk::DescriptorSet, vk::Result> {
+    let allocate_info = vk::DescriptorSetAllocateInfo::builder()
+        .descriptor_pool(pool)
+        .set_layouts(std::slice::from_ref(&layout));
+
+    Ok(unsafe { device.allocate_descriptor_sets(&allocate_info)? }[0])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vulkan::{HeadlessRenderBackend, HeadlessRenderBackendConfig};
+
+    fn storage_images(descriptor_count: u32) -> vk::DescriptorPoolSize {
+        vk::DescriptorPoolSize {
+            ty: vk::DescriptorType::STORAGE_IMAGE,
+            descriptor_count,
+        }
+    }
+
+    fn create_layout(device: &Device, image_count: u32) -> vk::DescriptorSetLayout {
+        let binding = vk::DescriptorSetLayoutBinding::builder()
+            .binding(0)
+            .descriptor_count(image_count)
+            .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+            .stage_flags(vk::ShaderStageFlags::COMPUTE)
+            .build();
+
+        unsafe {
+            device.raw.create_descriptor_set_layout(
+                &vk::DescriptorSetLayoutCreateInfo::builder()
+                    .bindings(std::slice::from_ref(&binding))
+                    .build(),
+                None,
+            )
+        }
+        .unwrap()
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn exhausted_pools_grow_and_reset_each_frame() {
+        let backend = HeadlessRenderBackend::new(HeadlessRenderBackendConfig {
+            frames_in_flight: 2,
+            descriptor_pools: DescriptorPoolConfig {
+                max_sets: 8,
+                pool_sizes: vec![storage_images(8)],
+            },
+            ..Default::default()
+        })
+        .unwrap();
+        let device = &*backend.device;
+
+        let layout = create_layout(device, 1);
+        for _ in 0..6 {
+            let frame = device.begin_frame().unwrap();
+            for _ in 0..100 {
+                device
+                    .allocate_frame_descriptor_set(layout, &[storage_images(1)])
+                    .unwrap();
+            }
+            device.finish_frame(frame);
+        }
+
+        // Each of the two frames needs 13 pools for 100 sets, and reuses them once reset.
+        let stats = device.descriptor_pool_stats();
+        assert_eq!(stats.pool_count, 2 * 13);
+        assert_eq!(stats.growth_count, 2 * 12);
+        assert_eq!(stats.oversized_set_count, 0);
+
+        // More images than a whole pool has
+        let large_layout = create_layout(device, 16);
+        device
+            .allocate_frame_descriptor_set(large_layout, &[storage_images(16)])
+            .unwrap();
+        assert_eq!(device.descriptor_pool_stats().oversized_set_count, 1);
+        assert_eq!(device.descriptor_pool_stats().pool_count, 2 * 13);
+    }
+}
@@ -1,8 +1,14 @@
-use crate::{vulkan::buffer::BufferDesc, BackendError};
+use crate::{vulkan::buffer::BufferDesc, BackendError, KajiyaError};
 
 use super::{
     buffer::Buffer,
+    descriptor_pool::{
+        create_pool, DescriptorPoolConfig, DescriptorPoolStats, DescriptorPools,
+        FrameDescriptorPools,
+    },
     error::CrashMarkerNames,
+    external_memory::ExternalMemory,
+    memory_stats::{MemoryStats, ResourceCategory, ResourceTracker},
     physical_device::{PhysicalDevice, QueueFamily},
     profiler::VkProfilerData,
 };
@@ -26,12 +32,21 @@ use std::{
 /// in the same shader stage.
 pub const RESERVED_DESCRIPTOR_COUNT: u32 = 32;
 
+/// Upper bound on the number of frames the CPU can record ahead of the GPU.
+///
+/// One frame in flight serializes the CPU and GPU: every frame waits for the previous one
+/// to finish, which adds no queuing latency, and makes captures deterministic.
+/// Two frames let the CPU record the next frame while the GPU is busy with the current one.
+/// Three frames smooth over CPU/GPU spikes at the cost of an extra frame of input latency,
+/// and of memory for the per-frame ring buffers.
+pub const MAX_FRAMES_IN_FLIGHT: usize = 3;
+
 pub struct Queue {
     pub raw: vk::Queue,
     pub family: QueueFamily,
 }
 
-pub trait DeferredRelease: Copy {
+pub trait DeferredRelease {
     fn enqueue_release(self, pending: &mut PendingResourceReleases);
 }
 
@@ -41,17 +56,99 @@ impl DeferredRelease for vk::DescriptorPool {
     }
 }
 
+impl DeferredRelease for vk::ImageView {
+    fn enqueue_release(self, pending: &mut PendingResourceReleases) {
+        pending.image_views.push(self);
+    }
+}
+
+impl DeferredRelease for vk::Image {
+    fn enqueue_release(self, pending: &mut PendingResourceReleases) {
+        pending.images.push(self);
+    }
+}
+
+impl DeferredRelease for gpu_allocator::SubAllocation {
+    fn enqueue_release(self, pending: &mut PendingResourceReleases) {
+        pending.allocations.push(self);
+    }
+}
+
+impl DeferredRelease for vk::DeviceMemory {
+    fn enqueue_release(self, pending: &mut PendingResourceReleases) {
+        pending.memory.push(self);
+    }
+}
+
+impl DeferredRelease for Arc<Buffer> {
+    fn enqueue_release(self, pending: &mut PendingResourceReleases) {
+        pending.buffers.push((self, None));
+    }
+}
+
+// A buffer along with the dedicated memory it's bound to
+impl DeferredRelease for (Arc<Buffer>, vk::DeviceMemory) {
+    fn enqueue_release(self, pending: &mut PendingResourceReleases) {
+        pending.buffers.push((self.0, Some(self.1)));
+    }
+}
+
 #[derive(Default)]
 pub struct PendingResourceReleases {
     pub descriptor_pools: Vec<vk::DescriptorPool>,
+    pub image_views: Vec<vk::ImageView>,
+    pub images: Vec<vk::Image>,
+
+    // Memory of images, freed after the images
+    pub allocations: Vec<gpu_allocator::SubAllocation>,
+
+    // Dedicated allocations, freed after the images bound to them
+    pub memory: Vec<vk::DeviceMemory>,
+
+    // Destroyed along with their memory once nothing else refers to them
+    pub buffers: Vec<(Arc<Buffer>, Option<vk::DeviceMemory>)>,
 }
 
 impl PendingResourceReleases {
-    fn release_all(&mut self, device: &ash::Device) {
+    fn release_all(&mut self, device: &ash::Device, allocator: &mut VulkanAllocator) {
+        for (buffer, dedicated_memory) in std::mem::take(&mut self.buffers) {
+            match Arc::try_unwrap(buffer) {
+                Ok(buffer) => {
+                    unsafe { device.destroy_buffer(buffer.raw, None) };
+                    if let Err(err) = allocator.free(buffer.allocation) {
+                        error!("Failed to free the memory of a buffer: {:?}", err);
+                    }
+                    if let Some(memory) = dedicated_memory {
+                        unsafe { device.free_memory(memory, None) };
+                    }
+                }
+                Err(buffer) => self.buffers.push((buffer, dedicated_memory)),
+            }
+        }
+
         unsafe {
             for res in self.descriptor_pools.drain(..) {
                 device.destroy_descriptor_pool(res, None);
             }
+
+            // Views before the images they view
+            for res in self.image_views.drain(..) {
+                device.destroy_image_view(res, None);
+            }
+
+            for res in self.images.drain(..) {
+                device.destroy_image(res, None);
+            }
+
+            for res in self.memory.drain(..) {
+                device.free_memory(res, None);
+            }
+        }
+
+        for allocation in self.allocations.drain(..) {
+            if let Err(err) = allocator.free(allocation) {
+                error!("Failed to free the memory of an image: {:?}", err);
+            }
         }
     }
 }
@@ -63,6 +160,7 @@ pub struct DeviceFrame {
     pub main_command_buffer: CommandBuffer,
     pub presentation_command_buffer: CommandBuffer,
     pub pending_resource_releases: Mutex<PendingResourceReleases>,
+    pub descriptor_pools: Mutex<FrameDescriptorPools>,
     pub profiler_data: VkProfilerData,
 }
 
@@ -127,6 +225,7 @@ impl DeviceFrame {
             main_command_buffer: CommandBuffer::new(device, queue_family).unwrap(),
             presentation_command_buffer: CommandBuffer::new(device, queue_family).unwrap(),
             pending_resource_releases: Default::default(),
+            descriptor_pools: Default::default(),
             profiler_data: VkProfilerData::new(device, global_allocator),
         }
     }
@@ -149,9 +248,18 @@ pub struct Device {
     // pub ray_query_ext: khr::RayQuery,
     pub ray_tracing_pipeline_properties: vk::PhysicalDeviceRayTracingPipelinePropertiesKHR,
 
-    frames: [Mutex<Arc<DeviceFrame>>; 2],
+    frames: Vec<Mutex<Arc<DeviceFrame>>>,
+    frame_counter: std::sync::atomic::AtomicU64,
 
     ray_tracing_enabled: bool,
+    fill_mode_non_solid: bool,
+    timeline_semaphore: bool,
+    pub(crate) external_memory: Option<ExternalMemory>,
+    pub(crate) descriptor_pools: DescriptorPools,
+    pub(crate) lost: std::sync::atomic::AtomicBool,
+
+    resource_tracker: Mutex<ResourceTracker>,
+    leak_check: std::sync::atomic::AtomicBool,
 }
 
 // Allowing `Send` on `frames` is technically unsound. There are some checks
@@ -163,7 +271,19 @@ unsafe impl Send for Device {}
 unsafe impl Sync for Device {}
 
 impl Device {
-    pub fn create(pdevice: &Arc<PhysicalDevice>) -> Result<Arc<Self>> {
+    pub fn create(
+        pdevice: &Arc<PhysicalDevice>,
+        frames_in_flight: usize,
+        descriptor_pool_config: DescriptorPoolConfig,
+    ) -> Result<Arc<Self>> {
+        if !(1..=MAX_FRAMES_IN_FLIGHT).contains(&frames_in_flight) {
+            anyhow::bail!(
+                "frames_in_flight must be between 1 and {}; got {}",
+                MAX_FRAMES_IN_FLIGHT,
+                frames_in_flight
+            );
+        }
+
         let supported_extensions: HashSet<String> = unsafe {
             let extension_properties = pdevice
                 .instance
@@ -241,6 +361,18 @@ impl Device {
             device_extension_names.push(khr::Swapchain::name().as_ptr());
         }
 
+        // Optional; see `Device::external_memory_supported`.
+        let external_memory_enabled =
+            supported_extensions.contains(ExternalMemory::extension_name().to_str().unwrap());
+        if external_memory_enabled {
+            device_extension_names.push(ExternalMemory::extension_name().as_ptr());
+        } else {
+            log::info!(
+                "{:?} not supported; images can't be shared with other APIs",
+                ExternalMemory::extension_name()
+            );
+        }
+
         unsafe {
             for &ext in &device_extension_names {
                 let ext = std::ffi::CStr::from_ptr(ext).to_string_lossy();
@@ -278,6 +410,8 @@ impl Device {
         let mut vulkan_memory_model = vk::PhysicalDeviceVulkanMemoryModelFeaturesKHR::default();
         let mut get_buffer_device_address_features =
             ash::vk::PhysicalDeviceBufferDeviceAddressFeatures::default();
+        let mut timeline_semaphore_features =
+            vk::PhysicalDeviceTimelineSemaphoreFeatures::default();
 
         let mut acceleration_structure_features =
             ash::vk::PhysicalDeviceAccelerationStructureFeaturesKHR::default();
@@ -294,7 +428,8 @@ impl Device {
                 .push_next(&mut imageless_framebuffer)
                 .push_next(&mut shader_float16_int8)
                 .push_next(&mut vulkan_memory_model)
-                .push_next(&mut get_buffer_device_address_features);
+                .push_next(&mut get_buffer_device_address_features)
+                .push_next(&mut timeline_semaphore_features);
 
             if ray_tracing_enabled {
                 features2 = features2
@@ -308,6 +443,10 @@ impl Device {
                 .fp_v1_1()
                 .get_physical_device_features2(pdevice.raw, &mut features2);
 
+            // Optional; all supported core features get enabled via `features2`.
+            let fill_mode_non_solid = features2.features.fill_mode_non_solid != 0;
+            let timeline_semaphore = timeline_semaphore_features.timeline_semaphore != 0;
+
             debug!("{:#?}", &scalar_block);
             debug!("{:#?}", &descriptor_indexing);
             debug!("{:#?}", &imageless_framebuffer);
@@ -383,9 +522,17 @@ impl Device {
                 family: universal_queue,
             };
 
-            let frame0 = DeviceFrame::new(&device, &mut global_allocator, &universal_queue.family);
-            let frame1 = DeviceFrame::new(&device, &mut global_allocator, &universal_queue.family);
-            //let frame2 = DeviceFrame::new(&device, &mut global_allocator, &universal_queue.family);
+            let frames = (0..frames_in_flight)
+                .map(|_| {
+                    Mutex::new(Arc::new(DeviceFrame::new(
+                        &device,
+                        &mut global_allocator,
+                        &universal_queue.family,
+                    )))
+                })
+                .collect();
+
+            info!("Frames in flight: {}", frames_in_flight);
 
             let immutable_samplers = Self::create_samplers(&device);
             let setup_cb = CommandBuffer::new(&device, &universal_queue.family).unwrap();
@@ -398,6 +545,9 @@ impl Device {
             let ray_tracing_pipeline_properties =
                 khr::RayTracingPipeline::get_properties(&pdevice.instance.raw, pdevice.raw);
 
+            let external_memory = external_memory_enabled
+                .then(|| ExternalMemory::new(&pdevice.instance.raw, &device));
+
             let crash_tracking_buffer = Self::create_buffer_impl(
                 &device,
                 &mut global_allocator,
@@ -419,12 +569,16 @@ impl Device {
                 ray_tracing_pipeline_ext,
                 // ray_query_ext,
                 ray_tracing_pipeline_properties,
-                frames: [
-                    Mutex::new(Arc::new(frame0)),
-                    Mutex::new(Arc::new(frame1)),
-                    //Mutex::new(Arc::new(frame2)),
-                ],
+                frames,
+                frame_counter: Default::default(),
                 ray_tracing_enabled,
+                fill_mode_non_solid,
+                timeline_semaphore,
+                external_memory,
+                descriptor_pools: DescriptorPools::new(descriptor_pool_config, ray_tracing_enabled),
+                lost: Default::default(),
+                resource_tracker: Default::default(),
+                leak_check: Default::default(),
             }))
         }
     }
@@ -485,7 +639,7 @@ impl Device {
             .unwrap_or_else(|| panic!("Sampler not found: {:?}", desc))
     }
 
-    pub fn begin_frame(&self) -> Arc<DeviceFrame> {
+    pub fn begin_frame(&self) -> Result<Arc<DeviceFrame>, KajiyaError> {
         let mut frame0 = self.frames[0].lock();
         {
             let frame0: &mut DeviceFrame = Arc::get_mut(&mut frame0).unwrap_or_else(|| {
@@ -513,8 +667,7 @@ impl Device {
                         true,
                         std::u64::MAX,
                     )
-                    .map_err(|err| self.report_error(err.into()))
-                    .expect("Wait for fence failed.");
+                    .map_err(|err| self.report_error(err.into()))?;
             }
 
             // Report GPU timings
@@ -539,16 +692,56 @@ impl Device {
             frame0
                 .pending_resource_releases
                 .get_mut()
-                .release_all(&self.raw);
+                .release_all(&self.raw, &mut self.global_allocator.lock());
+            frame0.descriptor_pools.get_mut().reset(&self.raw);
         }
 
-        frame0.clone()
+        self.frame_counter
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+
+        Ok(frame0.clone())
     }
 
     pub fn defer_release(&self, resource: impl DeferredRelease) {
         resource.enqueue_release(&mut self.frames[0].lock().pending_resource_releases.lock());
     }
 
+    /// Allocates a descriptor set of `layout` for the frame being recorded, which is freed
+    /// once the GPU is done with the frame. `set_sizes` are the descriptors of the set, by type.
+    pub fn allocate_frame_descriptor_set(
+        &self,
+        layout: vk::DescriptorSetLayout,
+        set_sizes: &[vk::DescriptorPoolSize],
+    ) -> Result<vk::DescriptorSet, BackendError> {
+        let set = self.frames[0]
+            .lock()
+            .descriptor_pools
+            .lock()
+            .allocate(self, layout, set_sizes)?;
+
+        if let Some(set) = set {
+            return Ok(set);
+        }
+
+        // Too large for the pools of the frame; it gets one of its own.
+        self.descriptor_pools.record_oversized_set();
+        let pool = create_pool(&self.raw, set_sizes, 1)?;
+        self.defer_release(pool);
+
+        Ok(super::descriptor_pool::allocate_set(
+            &self.raw, pool, layout,
+        )?)
+    }
+
+    /// The initial size of the descriptor pools of each frame.
+    pub fn descriptor_pool_config(&self) -> &DescriptorPoolConfig {
+        &self.descriptor_pools.config
+    }
+
+    pub fn descriptor_pool_stats(&self) -> DescriptorPoolStats {
+        self.descriptor_pools.stats()
+    }
+
     pub fn with_setup_cb(
         &self,
         callback: impl FnOnce(vk::CommandBuffer),
@@ -587,6 +780,11 @@ impl Device {
         }
     }
 
+    pub fn wait_idle(&self) -> Result<()> {
+        log::trace!("device_wait_idle");
+        unsafe { Ok(self.raw.device_wait_idle()?) }
+    }
+
     pub fn finish_frame(&self, frame: Arc<DeviceFrame>) {
         drop(frame);
 
@@ -595,16 +793,71 @@ impl Device {
             panic!("Unable to finish frame: frame data is being held by user code")
         });
 
-        {
-            let mut frame1 = self.frames[1].lock();
-            let frame1: &mut DeviceFrame = Arc::get_mut(&mut frame1).unwrap();
+        // Rotate the frames, so that the oldest one ends up at index 0,
+        // and will be the next one we wait on in `begin_frame`.
+        let mut other_frames = self.frames[1..]
+            .iter()
+            .map(|frame| frame.lock())
+            .collect::<Vec<_>>();
+
+        let mut prev_frame = frame0;
+        for frame in other_frames.iter_mut() {
+            let frame: &mut DeviceFrame = Arc::get_mut(frame).unwrap();
+
+            std::mem::swap(prev_frame, frame);
+            prev_frame = frame;
+        }
+    }
+
+    pub fn frames_in_flight(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Number of submitted frames the GPU hasn't finished yet; at most `frames_in_flight()`.
+    pub fn queued_frame_count(&self) -> usize {
+        self.frames
+            .iter()
+            .filter(|frame| {
+                let frame = frame.lock();
+                [
+                    frame.main_command_buffer.submit_done_fence,
+                    frame.presentation_command_buffer.submit_done_fence,
+                ]
+                .iter()
+                .any(|&fence| !unsafe { self.raw.get_fence_status(fence) }.unwrap_or(true))
+            })
+            .count()
+    }
+
+    /// Blocks until the GPU is done with the most recently submitted frame, rather than
+    /// the oldest one like `begin_frame`. Recording the next frame right after this
+    /// keeps the CPU from queueing work ahead of the GPU, at the cost of CPU/GPU overlap.
+    pub fn wait_for_last_frame(&self) -> Result<(), KajiyaError> {
+        // `finish_frame` rotates the last submitted frame to the end.
+        let fences = {
+            let frame = self.frames.last().unwrap().lock();
+            [
+                frame.main_command_buffer.submit_done_fence,
+                frame.presentation_command_buffer.submit_done_fence,
+            ]
+        };
 
-            //let mut frame2 = self.frames[2].lock();
-            //let frame2: &mut DeviceFrame = Arc::get_mut(&mut frame2).unwrap();
+        unsafe {
+            puffin::profile_scope!("wait last frame");
 
-            std::mem::swap(frame0, frame1);
-            //std::mem::swap(frame1, frame2);
+            self.raw
+                .wait_for_fences(&fences, true, std::u64::MAX)
+                .map_err(|err| self.report_error(err.into()))?;
         }
+
+        Ok(())
+    }
+
+    /// Number of frames begun so far. When `begin_frame` returns, the GPU is guaranteed
+    /// to be done with all frames up to `frame_counter() - frames_in_flight()`.
+    pub fn frame_counter(&self) -> u64 {
+        self.frame_counter
+            .load(std::sync::atomic::Ordering::Relaxed)
     }
 
     pub fn physical_device(&self) -> &PhysicalDevice {
@@ -628,6 +881,94 @@ impl Device {
     pub fn ray_tracing_enabled(&self) -> bool {
         self.ray_tracing_enabled
     }
+
+    /// Whether ray tracing pipelines can be linked from libraries. `VK_KHR_pipeline_library`
+    /// is required by the ray tracing extensions, so comes with them.
+    pub fn ray_tracing_pipeline_libraries_supported(&self) -> bool {
+        self.ray_tracing_enabled
+    }
+
+    /// Whether raster pipelines can draw triangles as lines (`RasterPipelineDesc::wireframe`).
+    pub fn fill_mode_non_solid_supported(&self) -> bool {
+        self.fill_mode_non_solid
+    }
+
+    /// Whether timeline semaphores can be created, such as to synchronize render graph imports
+    /// with external producers.
+    pub fn timeline_semaphore_supported(&self) -> bool {
+        self.timeline_semaphore
+    }
+
+    /// True once any operation reported `VK_ERROR_DEVICE_LOST`. The device can't be used
+    /// afterwards; see `RenderBackend::recreate_device`.
+    pub fn is_lost(&self) -> bool {
+        self.lost.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// Counts and sizes of the images, buffers, acceleration structures, and pipelines
+    /// created by this device which haven't been destroyed yet.
+    pub fn memory_stats(&self) -> MemoryStats {
+        self.resource_tracker.lock().stats()
+    }
+
+    /// Logs the resources which were never destroyed, with their debug names, when the device
+    /// is dropped.
+    pub fn set_leak_check(&self, enabled: bool) {
+        self.leak_check
+            .store(enabled, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    pub fn leak_check(&self) -> bool {
+        self.leak_check.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// Names `object` in captures and validation messages, via `VK_EXT_debug_utils`.
+    /// Does nothing unless the extension is enabled, which it is whenever it's available.
+    pub fn set_debug_name<H: vk::Handle>(&self, object: H, name: &str) {
+        let debug_utils = if let Some(debug_utils) = self.debug_utils() {
+            debug_utils
+        } else {
+            return;
+        };
+
+        let name = if let Ok(name) = std::ffi::CString::new(name) {
+            name
+        } else {
+            return;
+        };
+
+        let name_info = vk::DebugUtilsObjectNameInfoEXT::builder()
+            .object_type(H::TYPE)
+            .object_handle(object.as_raw())
+            .object_name(&name);
+
+        unsafe {
+            if let Err(err) = debug_utils.debug_utils_set_object_name(self.raw.handle(), &name_info)
+            {
+                warn!("Failed to set the debug name of {:?}: {:?}", name, err);
+            }
+        }
+    }
+
+    /// Also names the resource via `set_debug_name`.
+    pub(crate) fn track_resource<H: vk::Handle + Copy>(
+        &self,
+        category: ResourceCategory,
+        raw: H,
+        name: impl Into<String>,
+        bytes: u64,
+    ) {
+        let name = name.into();
+        self.set_debug_name(raw, &name);
+
+        self.resource_tracker
+            .lock()
+            .track(category, raw.as_raw(), name, bytes);
+    }
+
+    pub(crate) fn untrack_resource(&self, category: ResourceCategory, raw: impl vk::Handle) {
+        self.resource_tracker.lock().untrack(category, raw.as_raw());
+    }
 }
 
 impl Drop for Device {
@@ -636,6 +977,13 @@ impl Drop for Device {
             log::trace!("device_wait_idle");
             let _ = self.raw.device_wait_idle();
         }
+
+        if self.leak_check() {
+            let leak_count = self.resource_tracker.get_mut().log_leaks();
+            if leak_count > 0 {
+                log::warn!("{} GPU resources were never freed", leak_count);
+            }
+        }
     }
 }
 
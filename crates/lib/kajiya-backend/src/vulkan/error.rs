@@ -49,6 +49,8 @@ impl Device {
             ..
         } = &err
         {
+            self.lost.store(true, std::sync::atomic::Ordering::Relaxed);
+
             // Something went very wrong. Find the last marker which was successfully written
             // to the crash tracking buffer, and report its corresponding name.
             let last_marker = self
@@ -0,0 +1,547 @@
+// Sharing of images and buffers with other APIs and processes, such as CUDA, OptiX, or video
+// encoders, via `VK_KHR_external_memory_fd` or `VK_KHR_external_memory_win32`, depending on
+// the platform.
+//
+// Images created with `ImageDesc::external_memory`, and buffers created with
+// `Device::create_exportable_buffer`, get dedicated allocations outside of the global allocator,
+// whose memory can be exported as an OS handle with `Device::export_image_memory` or
+// `Device::export_buffer_memory`. `Device::import_image` and `Device::import_buffer` create
+// resources on top of memory exported elsewhere, from the same physical device. The dedicated
+// allocations are freed along with their resources, by `Device::defer_release_image`
+// and `Device::defer_release_buffer`.
+
+use super::{
+    buffer::{Buffer, BufferDesc},
+    device::Device,
+    image::{get_image_create_info, Image, ImageDesc},
+    memory_stats::ResourceCategory,
+};
+use crate::BackendError;
+use ash::vk;
+use parking_lot::Mutex;
+use std::{collections::HashMap, ffi::CStr};
+
+#[cfg(unix)]
+pub type ExternalMemoryHandle = std::os::unix::io::RawFd;
+
+#[cfg(windows)]
+pub type ExternalMemoryHandle = vk::HANDLE;
+
+#[cfg(unix)]
+const HANDLE_TYPE: vk::ExternalMemoryHandleTypeFlags = vk::ExternalMemoryHandleTypeFlags::OPAQUE_FD;
+
+#[cfg(windows)]
+const HANDLE_TYPE: vk::ExternalMemoryHandleTypeFlags =
+    vk::ExternalMemoryHandleTypeFlags::OPAQUE_WIN32;
+
+#[cfg(unix)]
+type ExternalMemoryFn = vk::KhrExternalMemoryFdFn;
+
+#[cfg(windows)]
+type ExternalMemoryFn = vk::KhrExternalMemoryWin32Fn;
+
+/// The memory of an image or buffer, as exported by `Device::export_image_memory`
+/// or `Device::export_buffer_memory`.
+///
+/// The handle belongs to whoever holds this. Importing a file descriptor into Vulkan
+/// transfers its ownership; Win32 handles stay owned by the application, and need closing.
+#[derive(Debug)]
+pub struct ExportedMemory {
+    pub handle: ExternalMemoryHandle,
+
+    /// Of the allocation, which must match on import.
+    pub size: vk::DeviceSize,
+}
+
+pub(crate) struct ExternalMemory {
+    fns: ExternalMemoryFn,
+
+    // Dedicated allocations of the resources created or imported with external memory,
+    // by their raw handles
+    dedicated_memory: Mutex<HashMap<(ResourceCategory, u64), vk::DeviceMemory>>,
+}
+
+impl ExternalMemory {
+    pub fn extension_name() -> &'static CStr {
+        ExternalMemoryFn::name()
+    }
+
+    pub fn new(instance: &ash::Instance, device: &ash::Device) -> Self {
+        let fns = ExternalMemoryFn::load(|name| unsafe {
+            std::mem::transmute(instance.get_device_proc_addr(device.handle(), name.as_ptr()))
+        });
+
+        Self {
+            fns,
+            dedicated_memory: Default::default(),
+        }
+    }
+}
+
+impl Device {
+    /// Whether images can be created with `ImageDesc::external_memory`, and images and buffers
+    /// exported and imported.
+    pub fn external_memory_supported(&self) -> bool {
+        self.external_memory.is_some()
+    }
+
+    fn external_memory(&self) -> Result<&ExternalMemory, BackendError> {
+        Ok(self
+            .external_memory
+            .as_ref()
+            .ok_or(vk::Result::ERROR_EXTENSION_NOT_PRESENT)?)
+    }
+
+    // Device-local memory if the resource can live there, or else the first type it can live in.
+    // Deterministic, so that imports of memory from the same physical device agree with the exporter.
+    fn external_memory_type_index(
+        &self,
+        requirements: &vk::MemoryRequirements,
+    ) -> Result<u32, BackendError> {
+        let memory_properties = &self.pdevice.memory_properties;
+        let allowed = |idx: &u32| requirements.memory_type_bits & (1 << idx) != 0;
+
+        let device_local = (0..memory_properties.memory_type_count)
+            .filter(allowed)
+            .find(|&idx| {
+                memory_properties.memory_types[idx as usize]
+                    .property_flags
+                    .contains(vk::MemoryPropertyFlags::DEVICE_LOCAL)
+            });
+
+        Ok(device_local
+            .or_else(|| (0..memory_properties.memory_type_count).find(allowed))
+            .ok_or(vk::Result::ERROR_OUT_OF_DEVICE_MEMORY)?)
+    }
+
+    // Allocates memory for one resource, either exportable, or imported from `import`.
+    fn allocate_dedicated_memory(
+        &self,
+        requirements: &vk::MemoryRequirements,
+        mut dedicated_info: vk::MemoryDedicatedAllocateInfoBuilder,
+        import: Option<&ExportedMemory>,
+    ) -> Result<(vk::DeviceMemory, vk::DeviceSize), BackendError> {
+        let memory_type_index = self.external_memory_type_index(requirements)?;
+        let allocation_size = import.map_or(requirements.size, |import| import.size);
+
+        let mut export_info = vk::ExportMemoryAllocateInfo::builder().handle_types(HANDLE_TYPE);
+
+        #[cfg(unix)]
+        let mut import_info = vk::ImportMemoryFdInfoKHR::builder()
+            .handle_type(HANDLE_TYPE)
+            .fd(import.map_or(-1, |import| import.handle));
+
+        #[cfg(windows)]
+        let mut import_info = vk::ImportMemoryWin32HandleInfoKHR::builder()
+            .handle_type(HANDLE_TYPE)
+            .handle(import.map_or(std::ptr::null_mut(), |import| import.handle));
+
+        let allocate_info = vk::MemoryAllocateInfo::builder()
+            .allocation_size(allocation_size)
+            .memory_type_index(memory_type_index)
+            .push_next(&mut dedicated_info);
+
+        let allocate_info = if import.is_some() {
+            allocate_info.push_next(&mut import_info)
+        } else {
+            allocate_info.push_next(&mut export_info)
+        };
+
+        let memory = unsafe { self.raw.allocate_memory(&allocate_info, None)? };
+        Ok((memory, allocation_size))
+    }
+
+    /// Creates an image with `create_info`, bound to a dedicated allocation which is either
+    /// exportable, or imported from `import`. Returns the image and the size of its memory.
+    pub(crate) fn create_external_image(
+        &self,
+        mut create_info: vk::ImageCreateInfo,
+        import: Option<&ExportedMemory>,
+    ) -> Result<(vk::Image, vk::DeviceSize), BackendError> {
+        let external_memory = self.external_memory()?;
+
+        let external_info = vk::ExternalMemoryImageCreateInfo::builder().handle_types(HANDLE_TYPE);
+        create_info.p_next = &*external_info as *const vk::ExternalMemoryImageCreateInfo as _;
+
+        let image = unsafe { self.raw.create_image(&create_info, None)? };
+        let requirements = unsafe { self.raw.get_image_memory_requirements(image) };
+
+        let bound = self
+            .allocate_dedicated_memory(
+                &requirements,
+                vk::MemoryDedicatedAllocateInfo::builder().image(image),
+                import,
+            )
+            .and_then(|(memory, size)| {
+                if let Err(err) = unsafe { self.raw.bind_image_memory(image, memory, 0) } {
+                    unsafe { self.raw.free_memory(memory, None) };
+                    return Err(err.into());
+                }
+                Ok((memory, size))
+            });
+
+        let (memory, size) = match bound {
+            Ok(bound) => bound,
+            Err(err) => {
+                unsafe { self.raw.destroy_image(image, None) };
+                return Err(err);
+            }
+        };
+
+        external_memory
+            .dedicated_memory
+            .lock()
+            .insert((ResourceCategory::Image, vk::Handle::as_raw(image)), memory);
+
+        Ok((image, size))
+    }
+
+    fn create_external_buffer(
+        &self,
+        desc: BufferDesc,
+        name: String,
+        import: Option<&ExportedMemory>,
+    ) -> Result<Buffer, BackendError> {
+        let external_memory = self.external_memory()?;
+
+        let mut external_info =
+            vk::ExternalMemoryBufferCreateInfo::builder().handle_types(HANDLE_TYPE);
+        let buffer_info = vk::BufferCreateInfo::builder()
+            .size(desc.size as u64)
+            .usage(desc.usage)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .push_next(&mut external_info);
+
+        let buffer = unsafe { self.raw.create_buffer(&buffer_info, None)? };
+        let requirements = unsafe { self.raw.get_buffer_memory_requirements(buffer) };
+
+        let bound = self
+            .allocate_dedicated_memory(
+                &requirements,
+                vk::MemoryDedicatedAllocateInfo::builder().buffer(buffer),
+                import,
+            )
+            .and_then(|(memory, size)| {
+                if let Err(err) = unsafe { self.raw.bind_buffer_memory(buffer, memory, 0) } {
+                    unsafe { self.raw.free_memory(memory, None) };
+                    return Err(err.into());
+                }
+                Ok((memory, size))
+            });
+
+        let (memory, size) = match bound {
+            Ok(bound) => bound,
+            Err(err) => {
+                unsafe { self.raw.destroy_buffer(buffer, None) };
+                return Err(err);
+            }
+        };
+
+        external_memory.dedicated_memory.lock().insert(
+            (ResourceCategory::Buffer, vk::Handle::as_raw(buffer)),
+            memory,
+        );
+        self.track_resource(ResourceCategory::Buffer, buffer, name, size);
+
+        Ok(Buffer {
+            raw: buffer,
+            desc,
+
+            // A null allocation, which the global allocator ignores when freeing the buffer.
+            // `defer_release_buffer` frees the dedicated memory instead.
+            allocation: Default::default(),
+        })
+    }
+
+    /// Creates a buffer of `desc` whose memory can be exported with `export_buffer_memory`.
+    /// It lives in device-local memory where it can, and isn't mapped, whatever
+    /// `desc.memory_location` says.
+    pub fn create_exportable_buffer(
+        &self,
+        desc: BufferDesc,
+        name: impl Into<String>,
+    ) -> Result<Buffer, BackendError> {
+        let name = name.into();
+        log::info!("Creating an exportable buffer {:?}: {:?}", name, desc);
+
+        self.create_external_buffer(desc, name, None)
+    }
+
+    /// Creates a buffer backed by `memory`, exported from another buffer, API, or process.
+    /// `desc` must describe the buffer the memory was exported from.
+    pub fn import_buffer(
+        &self,
+        desc: BufferDesc,
+        name: impl Into<String>,
+        memory: ExportedMemory,
+    ) -> Result<Buffer, BackendError> {
+        let name = name.into();
+        log::info!("Importing a buffer {:?}: {:?}", name, desc);
+
+        self.create_external_buffer(desc, name, Some(&memory))
+    }
+
+    /// Removes the dedicated memory of an image or buffer with external memory from the registry,
+    /// to be freed along with it. Returns `None` for other resources.
+    pub(crate) fn take_dedicated_memory(
+        &self,
+        category: ResourceCategory,
+        raw: impl vk::Handle,
+    ) -> Option<vk::DeviceMemory> {
+        self.external_memory.as_ref().and_then(|external_memory| {
+            external_memory
+                .dedicated_memory
+                .lock()
+                .remove(&(category, raw.as_raw()))
+        })
+    }
+
+    fn export_memory(
+        &self,
+        category: ResourceCategory,
+        raw: u64,
+        size: vk::DeviceSize,
+    ) -> Result<ExportedMemory, BackendError> {
+        let external_memory = self.external_memory()?;
+
+        let memory = *external_memory
+            .dedicated_memory
+            .lock()
+            .get(&(category, raw))
+            .ok_or(vk::Result::ERROR_INVALID_EXTERNAL_HANDLE)?;
+
+        #[cfg(unix)]
+        let handle = unsafe {
+            let info = vk::MemoryGetFdInfoKHR::builder()
+                .memory(memory)
+                .handle_type(HANDLE_TYPE);
+
+            let mut fd = -1;
+            match external_memory
+                .fns
+                .get_memory_fd_khr(self.raw.handle(), &*info, &mut fd)
+            {
+                vk::Result::SUCCESS => fd,
+                err => return Err(err.into()),
+            }
+        };
+
+        #[cfg(windows)]
+        let handle = unsafe {
+            let info = vk::MemoryGetWin32HandleInfoKHR::builder()
+                .memory(memory)
+                .handle_type(HANDLE_TYPE);
+
+            let mut handle = std::ptr::null_mut();
+            match external_memory.fns.get_memory_win32_handle_khr(
+                self.raw.handle(),
+                &*info,
+                &mut handle,
+            ) {
+                vk::Result::SUCCESS => handle,
+                err => return Err(err.into()),
+            }
+        };
+
+        Ok(ExportedMemory { handle, size })
+    }
+
+    /// Exports the memory of `image`, which must have been created with
+    /// `ImageDesc::external_memory`. Every call returns a new handle.
+    pub fn export_image_memory(&self, image: &Image) -> Result<ExportedMemory, BackendError> {
+        let size = unsafe { self.raw.get_image_memory_requirements(image.raw) }.size;
+        self.export_memory(ResourceCategory::Image, vk::Handle::as_raw(image.raw), size)
+    }
+
+    /// Exports the memory of `buffer`, which must have been created with
+    /// `create_exportable_buffer`. Every call returns a new handle.
+    pub fn export_buffer_memory(&self, buffer: &Buffer) -> Result<ExportedMemory, BackendError> {
+        let size = unsafe { self.raw.get_buffer_memory_requirements(buffer.raw) }.size;
+        self.export_memory(
+            ResourceCategory::Buffer,
+            vk::Handle::as_raw(buffer.raw),
+            size,
+        )
+    }
+
+    /// Creates an image backed by `memory`, exported from another image, API, or process.
+    /// `desc` must describe the image the memory was exported from.
+    pub fn import_image(
+        &self,
+        desc: ImageDesc,
+        name: impl Into<String>,
+        memory: ExportedMemory,
+    ) -> Result<Image, BackendError> {
+        let name = name.into();
+        let desc = desc.external_memory(true);
+        log::info!("Importing an image {:?}: {:?}", name, desc);
+
+        let (image, size) =
+            self.create_external_image(get_image_create_info(&desc, false), Some(&memory))?;
+        self.track_resource(ResourceCategory::Image, image, name, size);
+
+        Ok(Image {
+            raw: image,
+            desc,
+            views: Default::default(),
+            allocation: Default::default(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vulkan::{
+        barrier::{record_image_barrier, ImageBarrier},
+        buffer::BufferDesc,
+        device::Device,
+        HeadlessRenderBackend,
+    };
+    use crate::{ImageDesc, ImageSubResourceData};
+    use ash::vk;
+    use std::sync::Arc;
+
+    #[test]
+    #[ignore = "needs a Vulkan device with external memory"]
+    fn exported_image_can_be_imported() {
+        let backend = HeadlessRenderBackend::new(Default::default()).unwrap();
+        let device = &*backend.device;
+        assert!(device.external_memory_supported());
+
+        let desc = ImageDesc::new_2d(vk::Format::R8G8B8A8_UNORM, [4, 4])
+            .usage(vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_SRC)
+            .external_memory(true);
+        let texels: Vec<u8> = (0..64).collect();
+
+        let exported = device
+            .create_image(
+                desc,
+                "exported",
+                vec![ImageSubResourceData {
+                    data: &texels,
+                    row_pitch: 16,
+                    slice_pitch: 0,
+                }],
+            )
+            .unwrap();
+        let memory = device.export_image_memory(&exported).unwrap();
+        let imported = device.import_image(desc, "imported", memory).unwrap();
+
+        let readback = device
+            .create_buffer(
+                BufferDesc::new_gpu_to_cpu(texels.len(), vk::BufferUsageFlags::TRANSFER_DST),
+                "imported image readback",
+                None,
+            )
+            .unwrap();
+
+        // The initial data was uploaded through the other image, which left it ready for sampling.
+        device
+            .with_setup_cb(|cb| unsafe {
+                record_image_barrier(
+                    device,
+                    cb,
+                    ImageBarrier::new(
+                        imported.raw,
+                        vk_sync::AccessType::AnyShaderReadSampledImageOrUniformTexelBuffer,
+                        vk_sync::AccessType::TransferRead,
+                        vk::ImageAspectFlags::COLOR,
+                    ),
+                );
+
+                device.raw.cmd_copy_image_to_buffer(
+                    cb,
+                    imported.raw,
+                    vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                    readback.raw,
+                    &[vk::BufferImageCopy::builder()
+                        .image_subresource(
+                            vk::ImageSubresourceLayers::builder()
+                                .aspect_mask(vk::ImageAspectFlags::COLOR)
+                                .layer_count(1)
+                                .build(),
+                        )
+                        .image_extent(vk::Extent3D {
+                            width: 4,
+                            height: 4,
+                            depth: 1,
+                        })
+                        .build()],
+                );
+            })
+            .unwrap();
+
+        device.invalidate_mapped_buffer(&readback).unwrap();
+        assert_eq!(readback.allocation.mapped_slice().unwrap(), &texels[..]);
+
+        device.defer_release_image(&exported);
+        device.defer_release_image(&imported);
+        assert!(dedicated_memory_is_released(device));
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device with external memory"]
+    fn exported_buffer_can_be_imported() {
+        let backend = HeadlessRenderBackend::new(Default::default()).unwrap();
+        let device = &*backend.device;
+        assert!(device.external_memory_supported());
+
+        let desc = BufferDesc::new_gpu_only(
+            64,
+            vk::BufferUsageFlags::TRANSFER_SRC | vk::BufferUsageFlags::TRANSFER_DST,
+        );
+        let data: Vec<u8> = (0..64).collect();
+
+        let exported = device.create_exportable_buffer(desc, "exported").unwrap();
+        let memory = device.export_buffer_memory(&exported).unwrap();
+        let imported = device.import_buffer(desc, "imported", memory).unwrap();
+
+        let readback = device
+            .create_buffer(
+                BufferDesc::new_gpu_to_cpu(data.len(), vk::BufferUsageFlags::TRANSFER_DST),
+                "imported buffer readback",
+                None,
+            )
+            .unwrap();
+
+        // Written through one buffer, and read through the other
+        device
+            .with_setup_cb(|cb| unsafe {
+                device.raw.cmd_update_buffer(cb, exported.raw, 0, &data);
+
+                let barrier = vk::MemoryBarrier::builder()
+                    .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                    .dst_access_mask(vk::AccessFlags::TRANSFER_READ)
+                    .build();
+                device.raw.cmd_pipeline_barrier(
+                    cb,
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::DependencyFlags::empty(),
+                    &[barrier],
+                    &[],
+                    &[],
+                );
+
+                device.raw.cmd_copy_buffer(
+                    cb,
+                    imported.raw,
+                    readback.raw,
+                    &[vk::BufferCopy::builder().size(data.len() as u64).build()],
+                );
+            })
+            .unwrap();
+
+        device.invalidate_mapped_buffer(&readback).unwrap();
+        assert_eq!(readback.allocation.mapped_slice().unwrap(), &data[..]);
+
+        device.defer_release_buffer(Arc::new(exported));
+        device.defer_release_buffer(Arc::new(imported));
+        assert!(dedicated_memory_is_released(device));
+    }
+
+    fn dedicated_memory_is_released(device: &Device) -> bool {
+        let external_memory = device.external_memory.as_ref().unwrap();
+        external_memory.dedicated_memory.lock().is_empty()
+    }
+}
@@ -1,6 +1,6 @@
 use crate::BackendError;
 
-use super::device::Device;
+use super::{device::Device, memory_stats::ResourceCategory};
 use ash::vk;
 use derive_builder::Builder;
 use gpu_allocator::{AllocationCreateDesc, MemoryLocation};
@@ -28,6 +28,10 @@ pub struct ImageDesc {
     pub tiling: vk::ImageTiling,
     pub mip_levels: u16,
     pub array_elements: u32,
+
+    /// Backs the image with a dedicated allocation which can be shared with other APIs;
+    /// see `Device::export_image_memory`.
+    pub external_memory: bool,
 }
 
 fn mip_count_1d(extent: u32) -> u16 {
@@ -46,6 +50,7 @@ impl ImageDesc {
             tiling: vk::ImageTiling::OPTIMAL,
             mip_levels: 1,
             array_elements: 1,
+            external_memory: false,
         }
     }
 
@@ -72,6 +77,7 @@ impl ImageDesc {
             tiling: vk::ImageTiling::OPTIMAL,
             mip_levels: 1,
             array_elements: 6,
+            external_memory: false,
         }
     }
 
@@ -121,6 +127,11 @@ impl ImageDesc {
         self
     }
 
+    pub fn external_memory(mut self, external_memory: bool) -> Self {
+        self.external_memory = external_memory;
+        self
+    }
+
     pub fn div_up_extent(mut self, div_extent: [u32; 3]) -> Self {
         for (extent, &div_extent) in self.extent.iter_mut().zip(&div_extent) {
             *extent = ((*extent + div_extent - 1) / div_extent).max(1);
@@ -163,7 +174,10 @@ pub struct Image {
     pub raw: vk::Image,
     pub desc: ImageDesc,
     pub views: Mutex<HashMap<ImageViewDesc, vk::ImageView>>,
-    //allocation: gpu_allocator::SubAllocation,
+
+    // Taken when the image is released. `None` for images with external memory, and ones owned
+    // by the swapchain.
+    pub allocation: Mutex<Option<gpu_allocator::SubAllocation>>,
 }
 unsafe impl Send for Image {}
 unsafe impl Sync for Image {}
@@ -247,9 +261,11 @@ impl Device {
     pub fn create_image(
         &self,
         desc: ImageDesc,
+        name: impl Into<String>,
         initial_data: Vec<ImageSubResourceData>,
     ) -> Result<Image, BackendError> {
-        log::info!("Creating an image: {:?}", desc);
+        let name = name.into();
+        log::info!("Creating an image {:?}: {:?}", name, desc);
 
         let create_info = get_image_create_info(&desc, !initial_data.is_empty());
 
@@ -262,33 +278,42 @@ impl Device {
             .global_allocator
             .create_image(&create_info, &allocation_info)?;*/
 
-        let image = unsafe {
-            self.raw
-                .create_image(&create_info, None)
-                .expect("create_image")
+        let (image, memory_bytes, allocation) = if desc.external_memory {
+            let (image, memory_bytes) = self.create_external_image(create_info, None)?;
+            (image, memory_bytes, None)
+        } else {
+            let image = unsafe {
+                self.raw
+                    .create_image(&create_info, None)
+                    .expect("create_image")
+            };
+            let requirements = unsafe { self.raw.get_image_memory_requirements(image) };
+
+            let allocation = self
+                .global_allocator
+                .lock()
+                .allocate(&AllocationCreateDesc {
+                    name: &name,
+                    requirements,
+                    location: MemoryLocation::GpuOnly,
+                    linear: false,
+                })
+                .map_err(|err| BackendError::Allocation {
+                    inner: err,
+                    name: name.clone(),
+                })?;
+
+            // Bind memory to the image
+            unsafe {
+                self.raw
+                    .bind_image_memory(image, allocation.memory(), allocation.offset())
+                    .expect("bind_image_memory")
+            };
+
+            (image, requirements.size, Some(allocation))
         };
-        let requirements = unsafe { self.raw.get_image_memory_requirements(image) };
 
-        let allocation = self
-            .global_allocator
-            .lock()
-            .allocate(&AllocationCreateDesc {
-                name: "image",
-                requirements,
-                location: MemoryLocation::GpuOnly,
-                linear: false,
-            })
-            .map_err(|err| BackendError::Allocation {
-                inner: err,
-                name: "GpuOnly image".into(),
-            })?;
-
-        // Bind memory to the image
-        unsafe {
-            self.raw
-                .bind_image_memory(image, allocation.memory(), allocation.offset())
-                .expect("bind_image_memory")
-        };
+        self.track_resource(ResourceCategory::Image, image, &name, memory_bytes);
 
         if !initial_data.is_empty() {
             let total_initial_data_bytes = initial_data.iter().map(|d| d.data.len()).sum();
@@ -299,7 +324,7 @@ impl Device {
                     usage: vk::BufferUsageFlags::TRANSFER_SRC,
                     memory_location: MemoryLocation::CpuToGpu,
                 },
-                "Image initial data buffer",
+                format!("Initial data for {:?}", name),
                 None,
             )?;
 
@@ -376,12 +401,32 @@ impl Device {
         ImageHandle(handle)*/
         Ok(Image {
             raw: image,
-            //allocation,
             desc,
             views: Default::default(),
+            allocation: Mutex::new(allocation),
         })
     }
 
+    /// Destroys `image` and its views, and frees its memory, once the GPU is done with the frame
+    /// being recorded, which may still use it. `image` must not be used in later frames.
+    pub fn defer_release_image(&self, image: &Image) {
+        self.untrack_resource(ResourceCategory::Image, image.raw);
+
+        for (_, view) in image.views.lock().drain() {
+            self.defer_release(view);
+        }
+
+        self.defer_release(image.raw);
+
+        if let Some(allocation) = image.allocation.lock().take() {
+            self.defer_release(allocation);
+        }
+
+        if let Some(memory) = self.take_dedicated_memory(ResourceCategory::Image, image.raw) {
+            self.defer_release(memory);
+        }
+    }
+
     fn create_image_view(
         &self,
         desc: ImageViewDesc,
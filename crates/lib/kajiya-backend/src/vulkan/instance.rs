@@ -4,14 +4,65 @@ use ash::{extensions::ext, vk};
 use log::{debug, error, info, trace, warn};
 use std::{
     ffi::{c_void, CStr, CString},
-    os::raw::c_char,
     sync::Arc,
 };
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValidationLevel {
+    Off,
+    /// Only validation errors are reported.
+    ErrorsOnly,
+    /// All messages, including warnings, performance warnings, and info.
+    Verbose,
+}
+
+/// Controls the Vulkan validation layers. Their messages are routed to the `log` crate,
+/// at the level matching their severity.
+#[derive(Clone, Debug)]
+pub struct ValidationConfig {
+    pub level: ValidationLevel,
+
+    /// Message ID names (e.g. `VUID-VkWriteDescriptorSet-descriptorType-00322`)
+    /// which should not be reported.
+    pub suppressed_message_ids: Vec<String>,
+}
+
+impl Default for ValidationConfig {
+    fn default() -> Self {
+        Self::with_level(ValidationLevel::Off)
+    }
+}
+
+impl ValidationConfig {
+    pub fn with_level(level: ValidationLevel) -> Self {
+        Self {
+            level,
+            suppressed_message_ids: KNOWN_BENIGN_MESSAGE_IDS
+                .iter()
+                .map(|id| id.to_string())
+                .collect(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.level != ValidationLevel::Off
+    }
+}
+
+// Validation layers incorrectly report an error in pushing immutable sampler descriptors.
+//
+// https://www.khronos.org/registry/vulkan/specs/1.2-extensions/man/html/vkCmdPushDescriptorSetKHR.html
+// This documentation claims that it's necessary to push immutable samplers.
+const KNOWN_BENIGN_MESSAGE_IDS: &[&str] = &[
+    "VUID-VkWriteDescriptorSet-descriptorType-00322",
+    "VUID-VkWriteDescriptorSet-descriptorType-02752",
+];
+
 #[derive(Default)]
 pub struct DeviceBuilder {
     pub required_extensions: Vec<&'static CStr>,
-    pub graphics_debugging: bool,
+    pub validation: ValidationConfig,
+    pub hdr_output: bool,
 }
 
 impl DeviceBuilder {
@@ -24,8 +75,24 @@ impl DeviceBuilder {
         self
     }
 
+    pub fn validation(mut self, validation: ValidationConfig) -> Self {
+        self.validation = validation;
+        self
+    }
+
+    /// Shorthand for `validation`: `ErrorsOnly` when enabled.
     pub fn graphics_debugging(mut self, graphics_debugging: bool) -> Self {
-        self.graphics_debugging = graphics_debugging;
+        self.validation.level = if graphics_debugging {
+            ValidationLevel::ErrorsOnly
+        } else {
+            ValidationLevel::Off
+        };
+        self
+    }
+
+    /// Exposes HDR surface color spaces if the implementation supports them.
+    pub fn hdr_output(mut self, hdr_output: bool) -> Self {
+        self.hdr_output = hdr_output;
         self
     }
 }
@@ -34,11 +101,12 @@ pub struct Instance {
     pub(crate) entry: ash::Entry,
     pub raw: ash::Instance,
     #[allow(dead_code)]
-    pub(crate) debug_callback: Option<vk::DebugReportCallbackEXT>,
+    pub(crate) debug_messenger: Option<vk::DebugUtilsMessengerEXT>,
+    pub(crate) debug_utils: Option<ext::DebugUtils>,
+
+    // Referenced by the debug messenger callback; must outlive it.
     #[allow(dead_code)]
-    #[allow(deprecated)]
-    pub(crate) debug_loader: Option<ext::DebugReport>,
-    pub(crate) debug_utils: Option<ash::extensions::ext::DebugUtils>,
+    validation: Box<ValidationConfig>,
 }
 
 impl Instance {
@@ -46,13 +114,39 @@ impl Instance {
         DeviceBuilder::default()
     }
 
-    fn extension_names(builder: &DeviceBuilder) -> Vec<*const i8> {
+    fn is_extension_supported(entry: &ash::Entry, name: &CStr) -> bool {
+        entry
+            .enumerate_instance_extension_properties()
+            .unwrap_or_default()
+            .iter()
+            .any(|ext| unsafe { CStr::from_ptr(ext.extension_name.as_ptr()) } == name)
+    }
+
+    // Debug names and labels are useful in captures without validation too, so the extension
+    // is enabled wherever it's available. The validation layer provides it when the driver doesn't.
+    fn use_debug_utils(entry: &ash::Entry, builder: &DeviceBuilder) -> bool {
+        builder.validation.is_enabled()
+            || Self::is_extension_supported(entry, ext::DebugUtils::name())
+    }
+
+    fn extension_names(
+        entry: &ash::Entry,
+        builder: &DeviceBuilder,
+        use_debug_utils: bool,
+    ) -> Vec<*const i8> {
         let mut names = vec![vk::KhrGetPhysicalDeviceProperties2Fn::name().as_ptr()];
 
-        if builder.graphics_debugging {
-            #[allow(deprecated)]
-            names.push(ext::DebugReport::name().as_ptr());
-            names.push(vk::ExtDebugUtilsFn::name().as_ptr());
+        if use_debug_utils {
+            names.push(ext::DebugUtils::name().as_ptr());
+        }
+
+        if builder.hdr_output {
+            let colorspace_ext = vk::ExtSwapchainColorspaceFn::name();
+            if Self::is_extension_supported(entry, colorspace_ext) {
+                names.push(colorspace_ext.as_ptr());
+            } else {
+                warn!("{:?} not supported; HDR output unavailable", colorspace_ext);
+            }
         }
 
         names
@@ -60,7 +154,7 @@ impl Instance {
 
     fn layer_names(builder: &DeviceBuilder) -> Vec<CString> {
         let mut layer_names = Vec::new();
-        if builder.graphics_debugging {
+        if builder.validation.is_enabled() {
             layer_names.push(CString::new("VK_LAYER_KHRONOS_validation").unwrap());
         }
         layer_names
@@ -68,11 +162,12 @@ impl Instance {
 
     fn create(builder: DeviceBuilder) -> Result<Self> {
         let entry = unsafe { ash::Entry::new()? };
+        let use_debug_utils = Self::use_debug_utils(&entry, &builder);
         let instance_extensions = builder
             .required_extensions
             .iter()
             .map(|ext| ext.as_ptr())
-            .chain(Self::extension_names(&builder).into_iter())
+            .chain(Self::extension_names(&entry, &builder, use_debug_utils).into_iter())
             .collect::<Vec<_>>();
 
         let layer_names = Self::layer_names(&builder);
@@ -91,68 +186,86 @@ impl Instance {
         let instance = unsafe { entry.create_instance(&instance_desc, None)? };
         info!("Created a Vulkan instance");
 
-        let (debug_loader, debug_callback, debug_utils) = if builder.graphics_debugging {
-            let debug_info = ash::vk::DebugReportCallbackCreateInfoEXT {
-                flags: ash::vk::DebugReportFlagsEXT::ERROR
-                    | ash::vk::DebugReportFlagsEXT::WARNING
-                    | ash::vk::DebugReportFlagsEXT::PERFORMANCE_WARNING,
-                pfn_callback: Some(vulkan_debug_callback),
-                ..Default::default()
-            };
+        let validation = Box::new(builder.validation);
 
-            #[allow(deprecated)]
-            let debug_loader = ext::DebugReport::new(&entry, &instance);
+        let debug_utils = use_debug_utils.then(|| ext::DebugUtils::new(&entry, &instance));
 
-            let debug_callback = unsafe {
-                #[allow(deprecated)]
-                debug_loader
-                    .create_debug_report_callback(&debug_info, None)
-                    .unwrap()
-            };
+        // Only validation reports anything through the messenger.
+        let debug_messenger = match debug_utils.as_ref() {
+            Some(debug_utils) if validation.is_enabled() => {
+                let (message_severity, message_type) = match validation.level {
+                    ValidationLevel::Verbose => (
+                        vk::DebugUtilsMessageSeverityFlagsEXT::all(),
+                        vk::DebugUtilsMessageTypeFlagsEXT::all(),
+                    ),
+                    _ => (
+                        vk::DebugUtilsMessageSeverityFlagsEXT::ERROR,
+                        vk::DebugUtilsMessageTypeFlagsEXT::GENERAL
+                            | vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION,
+                    ),
+                };
 
-            let debug_utils = ash::extensions::ext::DebugUtils::new(&entry, &instance);
+                let messenger_info = vk::DebugUtilsMessengerCreateInfoEXT::builder()
+                    .message_severity(message_severity)
+                    .message_type(message_type)
+                    .pfn_user_callback(Some(vulkan_debug_callback))
+                    .user_data(&*validation as *const ValidationConfig as *mut c_void);
 
-            (Some(debug_loader), Some(debug_callback), Some(debug_utils))
-        } else {
-            (None, None, None)
+                Some(unsafe { debug_utils.create_debug_utils_messenger(&messenger_info, None)? })
+            }
+            _ => None,
         };
 
         Ok(Self {
             entry,
             raw: instance,
-            debug_callback,
-            debug_loader,
+            debug_messenger,
             debug_utils,
+            validation,
         })
     }
 }
 
 unsafe extern "system" fn vulkan_debug_callback(
-    _flags: vk::DebugReportFlagsEXT,
-    _obj_type: vk::DebugReportObjectTypeEXT,
-    _src_obj: u64,
-    _location: usize,
-    _msg_code: i32,
-    _layer_prefix: *const c_char,
-    message: *const c_char,
-    _user_data: *mut c_void,
-) -> u32 {
-    let message = CStr::from_ptr(message).to_str().unwrap();
-
-    #[allow(clippy::if_same_then_else)]
-    if message.starts_with("Validation Error: [ VUID-VkWriteDescriptorSet-descriptorType-00322")
-        || message.starts_with("Validation Error: [ VUID-VkWriteDescriptorSet-descriptorType-02752")
-    {
-        // Validation layers incorrectly report an error in pushing immutable sampler descriptors.
-        //
-        // https://www.khronos.org/registry/vulkan/specs/1.2-extensions/man/html/vkCmdPushDescriptorSetKHR.html
-        // This documentation claims that it's necessary to push immutable samplers.
-    } else if message.starts_with("Validation Performance Warning") {
-    } else if message.starts_with("Validation Warning: [ VUID_Undefined ]") {
-        log::warn!("{}\n", message);
+    message_severity: vk::DebugUtilsMessageSeverityFlagsEXT,
+    message_type: vk::DebugUtilsMessageTypeFlagsEXT,
+    callback_data: *const vk::DebugUtilsMessengerCallbackDataEXT,
+    user_data: *mut c_void,
+) -> vk::Bool32 {
+    let callback_data = &*callback_data;
+    let validation = &*(user_data as *const ValidationConfig);
+
+    let message_id_name = if callback_data.p_message_id_name.is_null() {
+        Default::default()
     } else {
-        log::error!("{}\n", message);
+        CStr::from_ptr(callback_data.p_message_id_name).to_string_lossy()
+    };
+
+    if validation
+        .suppressed_message_ids
+        .iter()
+        .any(|id| *id == message_id_name)
+    {
+        return vk::FALSE;
     }
 
-    ash::vk::FALSE
+    let message = if callback_data.p_message.is_null() {
+        Default::default()
+    } else {
+        CStr::from_ptr(callback_data.p_message).to_string_lossy()
+    };
+
+    let level = if message_severity.contains(vk::DebugUtilsMessageSeverityFlagsEXT::ERROR) {
+        log::Level::Error
+    } else if message_severity.contains(vk::DebugUtilsMessageSeverityFlagsEXT::WARNING) {
+        log::Level::Warn
+    } else if message_severity.contains(vk::DebugUtilsMessageSeverityFlagsEXT::INFO) {
+        log::Level::Info
+    } else {
+        log::Level::Debug
+    };
+
+    log::log!(level, "[{:?}] {}\n", message_type, message);
+
+    vk::FALSE
 }
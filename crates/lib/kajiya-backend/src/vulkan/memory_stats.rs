@@ -0,0 +1,163 @@
+// Bookkeeping of live GPU resources, for tracking down leaks.
+//
+// Resources are recorded by category and raw Vulkan handle when the device creates them,
+// along with their debug names and sizes, and forgotten when they're destroyed. Whatever
+// is still recorded when the device is dropped was never freed; see `Device::set_leak_check`.
+
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, Hash, PartialEq, Eq, Debug)]
+pub enum ResourceCategory {
+    Image,
+    Buffer,
+    AccelerationStructure,
+    Pipeline,
+}
+
+impl ResourceCategory {
+    pub const ALL: [ResourceCategory; 4] = [
+        ResourceCategory::Image,
+        ResourceCategory::Buffer,
+        ResourceCategory::AccelerationStructure,
+        ResourceCategory::Pipeline,
+    ];
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct CategoryStats {
+    pub count: usize,
+
+    /// Of device memory. Pipelines count as zero, as their memory isn't visible to the application.
+    pub bytes: u64,
+}
+
+/// Live resources by category, from `Device::memory_stats`.
+#[derive(Clone, Default, Debug)]
+pub struct MemoryStats {
+    categories: HashMap<ResourceCategory, CategoryStats>,
+}
+
+impl MemoryStats {
+    pub fn get(&self, category: ResourceCategory) -> CategoryStats {
+        self.categories.get(&category).copied().unwrap_or_default()
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.categories.values().map(|stats| stats.bytes).sum()
+    }
+}
+
+struct LiveResource {
+    name: String,
+    bytes: u64,
+}
+
+#[derive(Default)]
+pub(crate) struct ResourceTracker {
+    live: HashMap<(ResourceCategory, u64), LiveResource>,
+}
+
+impl ResourceTracker {
+    /// `raw` is the Vulkan handle of the resource, as from `vk::Handle::as_raw`.
+    pub fn track(
+        &mut self,
+        category: ResourceCategory,
+        raw: u64,
+        name: impl Into<String>,
+        bytes: u64,
+    ) {
+        self.live.insert(
+            (category, raw),
+            LiveResource {
+                name: name.into(),
+                bytes,
+            },
+        );
+    }
+
+    pub fn untrack(&mut self, category: ResourceCategory, raw: u64) {
+        self.live.remove(&(category, raw));
+    }
+
+    pub fn stats(&self) -> MemoryStats {
+        let mut stats = MemoryStats::default();
+        for ((category, _), resource) in &self.live {
+            let category = stats.categories.entry(*category).or_default();
+            category.count += 1;
+            category.bytes += resource.bytes;
+        }
+        stats
+    }
+
+    /// Logs every live resource, largest first, and returns their count.
+    pub fn log_leaks(&self) -> usize {
+        let mut leaks: Vec<_> = self.live.iter().collect();
+        leaks.sort_by_key(|(_, resource)| std::cmp::Reverse(resource.bytes));
+
+        for ((category, raw), resource) in &leaks {
+            log::warn!(
+                "Leaked {:?} {:?} (0x{:x}): {} bytes",
+                category,
+                resource.name,
+                raw,
+                resource.bytes
+            );
+        }
+
+        leaks.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stats_reflect_live_allocations() {
+        let mut tracker = ResourceTracker::default();
+        assert_eq!(tracker.stats().total_bytes(), 0);
+
+        tracker.track(ResourceCategory::Buffer, 1, "vertex buffer", 1024);
+        tracker.track(ResourceCategory::Buffer, 2, "index buffer", 256);
+        tracker.track(ResourceCategory::Image, 1, "gbuffer", 4096);
+        tracker.track(ResourceCategory::Pipeline, 7, "light_gbuffer.hlsl", 0);
+
+        let stats = tracker.stats();
+        assert_eq!(
+            stats.get(ResourceCategory::Buffer),
+            CategoryStats {
+                count: 2,
+                bytes: 1280
+            }
+        );
+        assert_eq!(
+            stats.get(ResourceCategory::Image),
+            CategoryStats {
+                count: 1,
+                bytes: 4096
+            }
+        );
+        assert_eq!(stats.get(ResourceCategory::Pipeline).count, 1);
+        assert_eq!(
+            stats.get(ResourceCategory::AccelerationStructure),
+            CategoryStats::default()
+        );
+        assert_eq!(stats.total_bytes(), 5376);
+
+        // Handles are only unique within a category.
+        tracker.untrack(ResourceCategory::Buffer, 1);
+        tracker.untrack(ResourceCategory::Pipeline, 7);
+
+        let stats = tracker.stats();
+        assert_eq!(
+            stats.get(ResourceCategory::Buffer),
+            CategoryStats {
+                count: 1,
+                bytes: 256
+            }
+        );
+        assert_eq!(stats.get(ResourceCategory::Image).count, 1);
+        assert_eq!(stats.get(ResourceCategory::Pipeline).count, 0);
+        assert_eq!(tracker.log_leaks(), 2);
+    }
+}
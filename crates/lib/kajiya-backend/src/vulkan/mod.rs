@@ -1,12 +1,16 @@
 pub mod barrier;
 pub mod buffer;
+pub mod descriptor_pool;
 pub mod device;
 pub mod error;
+pub mod external_memory;
 pub mod image;
 pub mod instance;
+pub mod memory_stats;
 pub mod physical_device;
 pub mod profiler;
 pub mod ray_tracing;
+pub mod reflection;
 pub mod shader;
 pub mod surface;
 pub mod swapchain;
@@ -14,80 +18,125 @@ pub mod swapchain;
 use ash::vk;
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
+#[cfg(feature = "window")]
 use raw_window_handle::HasRawWindowHandle;
 use std::sync::Arc;
 
-fn select_surface_format(formats: Vec<vk::SurfaceFormatKHR>) -> Option<vk::SurfaceFormatKHR> {
-    let preferred = vk::SurfaceFormatKHR {
+#[cfg(feature = "window")]
+fn select_surface_format(
+    formats: Vec<vk::SurfaceFormatKHR>,
+    hdr_output: bool,
+) -> Option<vk::SurfaceFormatKHR> {
+    let sdr = vk::SurfaceFormatKHR {
         format: vk::Format::B8G8R8A8_UNORM,
         color_space: vk::ColorSpaceKHR::SRGB_NONLINEAR,
     };
 
-    if formats.contains(&preferred) {
-        Some(preferred)
-    } else {
-        None
+    let mut preferred = Vec::new();
+    if hdr_output {
+        preferred.push(vk::SurfaceFormatKHR {
+            format: vk::Format::A2B10G10R10_UNORM_PACK32,
+            color_space: vk::ColorSpaceKHR::HDR10_ST2084_EXT,
+        });
+        preferred.push(vk::SurfaceFormatKHR {
+            format: vk::Format::R16G16B16A16_SFLOAT,
+            color_space: vk::ColorSpaceKHR::EXTENDED_SRGB_LINEAR_EXT,
+        });
     }
+    preferred.push(sdr);
+
+    let selected = preferred
+        .into_iter()
+        .find(|format| formats.contains(format));
+
+    if hdr_output && selected == Some(sdr) {
+        warn!("No HDR surface formats available; falling back to SDR");
+    }
+
+    selected
 }
 
+// Prefers discrete GPUs, then integrated ones, keeping the enumeration order among equals.
+fn select_physical_device(
+    physical_devices: Vec<physical_device::PhysicalDevice>,
+) -> anyhow::Result<Arc<physical_device::PhysicalDevice>> {
+    info!(
+        "Available physical devices: {:#?}",
+        physical_devices
+            .iter()
+            .map(|dev| unsafe {
+                ::std::ffi::CStr::from_ptr(
+                    dev.properties.device_name.as_ptr() as *const std::os::raw::c_char
+                )
+            })
+            .collect::<Vec<_>>()
+    );
+
+    let physical_device = physical_devices
+        .into_iter()
+        // If there are multiple devices with the same score, `max_by_key` would choose the last,
+        // and we want to preserve the order of devices from `enumerate_physical_devices`.
+        .rev()
+        .max_by_key(|device| match device.properties.device_type {
+            vk::PhysicalDeviceType::INTEGRATED_GPU => 200,
+            vk::PhysicalDeviceType::DISCRETE_GPU => 1000,
+            vk::PhysicalDeviceType::VIRTUAL_GPU => 1,
+            _ => 0,
+        })
+        .ok_or_else(|| anyhow::anyhow!("No suitable physical device found"))?;
+
+    info!("Selected physical device: {:#?}", physical_device);
+
+    Ok(Arc::new(physical_device))
+}
+
+/// A device presenting to a window. All presentation goes through `swapchain`;
+/// see `HeadlessRenderBackend` for rendering without a window.
 pub struct RenderBackend {
     pub device: Arc<device::Device>,
     pub surface: Arc<surface::Surface>,
     pub swapchain: swapchain::Swapchain,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone)]
 pub struct RenderBackendConfig {
     pub swapchain_extent: [u32; 2],
-    pub vsync: bool,
-    pub graphics_debugging: bool,
+    pub present_mode: swapchain::PresentMode,
+    pub validation: instance::ValidationConfig,
+
+    /// Present in HDR if the display supports it. Query `Swapchain::color_space` for the result.
+    pub hdr_output: bool,
+
+    /// Between 1 and `device::MAX_FRAMES_IN_FLIGHT`; see its docs for the tradeoffs.
+    pub frames_in_flight: usize,
+
+    /// The initial size of the descriptor pools of each frame in flight, which grow when exhausted.
+    pub descriptor_pools: descriptor_pool::DescriptorPoolConfig,
 }
 
 impl RenderBackend {
+    #[cfg(feature = "window")]
     pub fn new(
         window: &impl HasRawWindowHandle,
         config: RenderBackendConfig,
     ) -> anyhow::Result<Self> {
         let instance = instance::Instance::builder()
             .required_extensions(ash_window::enumerate_required_extensions(window).unwrap())
-            .graphics_debugging(config.graphics_debugging)
+            .validation(config.validation)
+            .hdr_output(config.hdr_output)
             .build()?;
         let surface = surface::Surface::create(&instance, window)?;
 
         use physical_device::*;
-        let physical_devices =
-            enumerate_physical_devices(&instance)?.with_presentation_support(&surface);
-
-        info!(
-            "Available physical devices: {:#?}",
-            physical_devices
-                .iter()
-                .map(|dev| unsafe {
-                    ::std::ffi::CStr::from_ptr(
-                        dev.properties.device_name.as_ptr() as *const std::os::raw::c_char
-                    )
-                })
-                .collect::<Vec<_>>()
-        );
-
-        let physical_device = Arc::new(
-            physical_devices
-                .into_iter()
-                // If there are multiple devices with the same score, `max_by_key` would choose the last,
-                // and we want to preserve the order of devices from `enumerate_physical_devices`.
-                .rev()
-                .max_by_key(|device| match device.properties.device_type {
-                    vk::PhysicalDeviceType::INTEGRATED_GPU => 200,
-                    vk::PhysicalDeviceType::DISCRETE_GPU => 1000,
-                    vk::PhysicalDeviceType::VIRTUAL_GPU => 1,
-                    _ => 0,
-                })
-                .unwrap(),
-        );
-
-        info!("Selected physical device: {:#?}", *physical_device);
-
-        let device = device::Device::create(&physical_device)?;
+        let physical_device = select_physical_device(
+            enumerate_physical_devices(&instance)?.with_presentation_support(&surface),
+        )?;
+
+        let device = device::Device::create(
+            &physical_device,
+            config.frames_in_flight,
+            config.descriptor_pools,
+        )?;
         let surface_formats = swapchain::Swapchain::enumerate_surface_formats(&device, &surface)?;
 
         info!("Available surface formats: {:#?}", surface_formats);
@@ -96,12 +145,13 @@ impl RenderBackend {
             &device,
             &surface,
             swapchain::SwapchainDesc {
-                format: select_surface_format(surface_formats).expect("suitable surface format"),
+                format: select_surface_format(surface_formats, config.hdr_output)
+                    .expect("suitable surface format"),
                 dims: vk::Extent2D {
                     width: config.swapchain_extent[0],
                     height: config.swapchain_extent[1],
                 },
-                vsync: config.vsync,
+                present_mode: config.present_mode,
             },
         )?;
 
@@ -112,7 +162,119 @@ impl RenderBackend {
         })
     }
 
+    /// Re-creates the swapchain with a different presentation mode.
+    /// Returns the mode actually chosen, which may differ if `present_mode` is not supported.
+    pub fn set_present_mode(
+        &mut self,
+        present_mode: swapchain::PresentMode,
+    ) -> anyhow::Result<swapchain::PresentMode> {
+        if present_mode != self.swapchain.desc.present_mode {
+            self.swapchain.recreate(swapchain::SwapchainDesc {
+                present_mode,
+                ..self.swapchain.desc
+            })?;
+        }
+
+        Ok(self.swapchain.present_mode())
+    }
+
+    /// Re-creates the swapchain after the window has been resized.
+    pub fn resize_swapchain(&mut self, extent: [u32; 2]) -> anyhow::Result<()> {
+        self.swapchain.recreate(swapchain::SwapchainDesc {
+            dims: vk::Extent2D {
+                width: extent[0],
+                height: extent[1],
+            },
+            ..self.swapchain.desc
+        })
+    }
+
+    /// Attempts recovery after `KajiyaError::DeviceLost` (e.g. following a driver timeout),
+    /// by creating a new device on the same physical device, and a new swapchain for it.
+    ///
+    /// The instance, surface, and swapchain settings survive. Everything created from
+    /// the old device does not: the render graph `Renderer` (with its pipeline cache,
+    /// transient and temporal resources), as well as world and UI renderers along with
+    /// their meshes and images. Those must be dropped and created again by the application.
+    pub fn recreate_device(&mut self) -> anyhow::Result<()> {
+        log::warn!("Re-creating the GPU device");
+
+        let device = device::Device::create(
+            &self.device.pdevice,
+            self.device.frames_in_flight(),
+            self.device.descriptor_pool_config().clone(),
+        )?;
+        device.set_leak_check(self.device.leak_check());
+        self.swapchain.recreate_on_device(&device)?;
+        self.device = device;
+
+        Ok(())
+    }
+
     /*fn maintain(&mut self) {
         self.images.maintain();
     }*/
 }
+
+#[derive(Clone)]
+pub struct HeadlessRenderBackendConfig {
+    pub validation: instance::ValidationConfig,
+
+    /// Between 1 and `device::MAX_FRAMES_IN_FLIGHT`; see its docs for the tradeoffs.
+    pub frames_in_flight: usize,
+
+    /// The initial size of the descriptor pools of each frame in flight, which grow when exhausted.
+    pub descriptor_pools: descriptor_pool::DescriptorPoolConfig,
+}
+
+impl Default for HeadlessRenderBackendConfig {
+    fn default() -> Self {
+        Self {
+            validation: Default::default(),
+            frames_in_flight: 1,
+            descriptor_pools: Default::default(),
+        }
+    }
+}
+
+/// A device without a window surface or swapchain, for rendering offscreen: in CI, on servers,
+/// or for captures. Neither `VK_KHR_surface` nor `VK_KHR_swapchain` is enabled, while ray tracing
+/// is, if supported. Frames are submitted with `Renderer::draw_frame_headless`, and must not use
+/// the render graph's swapchain image.
+pub struct HeadlessRenderBackend {
+    pub device: Arc<device::Device>,
+}
+
+impl HeadlessRenderBackend {
+    pub fn new(config: HeadlessRenderBackendConfig) -> anyhow::Result<Self> {
+        let instance = instance::Instance::builder()
+            .validation(config.validation)
+            .build()?;
+
+        let physical_device =
+            select_physical_device(physical_device::enumerate_physical_devices(&instance)?)?;
+
+        let device = device::Device::create(
+            &physical_device,
+            config.frames_in_flight,
+            config.descriptor_pools,
+        )?;
+
+        Ok(Self { device })
+    }
+
+    /// Attempts recovery after `KajiyaError::DeviceLost`; see `RenderBackend::recreate_device`.
+    pub fn recreate_device(&mut self) -> anyhow::Result<()> {
+        log::warn!("Re-creating the GPU device");
+
+        let device = device::Device::create(
+            &self.device.pdevice,
+            self.device.frames_in_flight(),
+            self.device.descriptor_pool_config().clone(),
+        )?;
+        device.set_leak_check(self.device.leak_check());
+        self.device = device;
+
+        Ok(())
+    }
+}
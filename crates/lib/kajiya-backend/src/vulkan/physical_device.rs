@@ -79,7 +79,8 @@ pub fn enumerate_physical_devices(instance: &Arc<Instance>) -> Result<Vec<Physic
                 PhysicalDevice {
                     raw: pdevice,
                     queue_families,
-                    presentation_requested: true,
+                    // Set by `with_presentation_support`
+                    presentation_requested: false,
                     instance: instance.clone(),
                     properties,
                     memory_properties,
@@ -1,14 +1,21 @@
-use std::sync::Arc;
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    hash::{Hash, Hasher},
+    sync::Arc,
+};
 
 use crate::{dynamic_constants::DynamicConstants, BackendError, MAX_DESCRIPTOR_SETS};
 
 use super::{
     device::Device,
+    memory_stats::ResourceCategory,
+    reflection::ShaderReflection,
     shader::{
-        merge_shader_stage_layouts, DescriptorSetLayoutOpts, PipelineShader, ShaderPipelineCommon,
-        ShaderPipelineStage,
+        DescriptorSetLayout, DescriptorSetLayoutOpts, PipelineShader, ShaderPipelineCommon,
+        ShaderPipelineStage, ShaderSource,
     },
 };
+use anyhow::Context as _;
 use ash::vk;
 use byte_slice_cast::AsSliceOf;
 use bytes::Bytes;
@@ -23,6 +30,7 @@ pub enum RayTracingGeometryType {
 
 #[derive(Clone, Copy, Debug)]
 pub struct RayTracingGeometryPart {
+    /// The number of boxes for `BoundingBox` geometry
     pub index_count: usize,
     pub index_offset: usize, // offset into the index buffer in bytes
     pub max_vertex: u32, // the highest index of a vertex that will be addressed by a build command using this structure
@@ -31,6 +39,8 @@ pub struct RayTracingGeometryPart {
 #[derive(Clone, Debug)]
 pub struct RayTracingGeometryDesc {
     pub geometry_type: RayTracingGeometryType,
+
+    /// `RayTracingAabb`s for `BoundingBox` geometry, `vertex_stride` bytes apart
     pub vertex_buffer: vk::DeviceAddress,
     pub index_buffer: vk::DeviceAddress,
     pub vertex_format: vk::Format,
@@ -38,11 +48,73 @@ pub struct RayTracingGeometryDesc {
     pub parts: Vec<RayTracingGeometryPart>,
 }
 
+impl RayTracingGeometryDesc {
+    /// Procedural geometry: `count` boxes in `aabb_buffer`, which needs the
+    /// `ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR` and `SHADER_DEVICE_ADDRESS` usages.
+    /// Rays entering a box run the intersection shader of the instance's hit group,
+    /// with the index of the box as `PrimitiveIndex()`.
+    pub fn aabbs(aabb_buffer: vk::DeviceAddress, count: usize) -> Self {
+        Self {
+            geometry_type: RayTracingGeometryType::BoundingBox,
+            vertex_buffer: aabb_buffer,
+            index_buffer: 0,
+            vertex_format: vk::Format::UNDEFINED,
+            vertex_stride: std::mem::size_of::<RayTracingAabb>(),
+            parts: vec![RayTracingGeometryPart {
+                index_count: count,
+                index_offset: 0,
+                max_vertex: 0,
+            }],
+        }
+    }
+
+    fn primitive_count(&self) -> u32 {
+        match self.geometry_type {
+            RayTracingGeometryType::Triangle => self.parts[0].index_count as u32 / 3,
+            RayTracingGeometryType::BoundingBox => self.parts[0].index_count as u32,
+        }
+    }
+}
+
+/// The bounds of a procedural primitive, in the space of its BLAS; `VkAabbPositionsKHR`.
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct RayTracingAabb {
+    pub min: [f32; 3],
+    pub max: [f32; 3],
+}
+
 #[derive(Clone)]
 pub struct RayTracingInstanceDesc {
     pub blas: Arc<RayTracingAcceleration>,
     pub transformation: Affine3A,
     pub mesh_index: u32,
+
+    /// Opaque instances are hit without running any-hit shaders.
+    pub opaque: bool,
+
+    /// Disables back-face culling for the instance, regardless of ray flags.
+    pub double_sided: bool,
+
+    /// Added to the hit group index of rays hitting the instance. Selects the hit groups
+    /// (with intersection shaders) of instances of procedural geometry.
+    pub hit_group_offset: u32,
+}
+
+impl RayTracingInstanceDesc {
+    fn flags(&self) -> vk::GeometryInstanceFlagsKHR {
+        let mut flags = if self.opaque {
+            vk::GeometryInstanceFlagsKHR::FORCE_OPAQUE
+        } else {
+            vk::GeometryInstanceFlagsKHR::FORCE_NO_OPAQUE
+        };
+
+        if self.double_sided {
+            flags |= vk::GeometryInstanceFlagsKHR::TRIANGLE_FACING_CULL_DISABLE;
+        }
+
+        flags
+    }
 }
 
 #[derive(Clone)]
@@ -61,6 +133,22 @@ pub struct RayTracingShaderTableDesc {
     pub raygen_entry_count: u32,
     pub hit_entry_count: u32,
     pub miss_entry_count: u32,
+
+    /// Written after the handle in the record of each shader group, in pipeline group order.
+    /// Missing entries are empty.
+    pub shader_record_data: Vec<Vec<u8>>,
+
+    /// Appended to the hit table, after the records of the hit groups.
+    pub extra_hit_records: Vec<HitShaderRecord>,
+}
+
+/// A hit table record reusing the shaders of a hit group with its own shader record data,
+/// so that many instances can share one hit group, each with their own parameters.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HitShaderRecord {
+    /// Index of the hit group, counted from the first hit group of the pipeline
+    pub hit_group: u32,
+    pub data: Vec<u8>,
 }
 
 pub struct RayTracingAcceleration {
@@ -93,9 +181,35 @@ impl Device {
         })
     }
 
+    /// A buffer holding `aabbs`, for `RayTracingGeometryDesc::aabbs`. Shaders can read it too,
+    /// e.g. as a `StructuredBuffer` indexed by `PrimitiveIndex()`.
+    pub fn create_ray_tracing_aabb_buffer(
+        &self,
+        aabbs: &[RayTracingAabb],
+        name: impl Into<String>,
+    ) -> Result<super::buffer::Buffer, BackendError> {
+        let size = std::mem::size_of_val(aabbs);
+
+        self.create_buffer(
+            super::buffer::BufferDesc::new_gpu_only(
+                size.max(1),
+                vk::BufferUsageFlags::STORAGE_BUFFER
+                    | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS
+                    | vk::BufferUsageFlags::ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR,
+            ),
+            name,
+            // Safety: `RayTracingAabb` is plain floats.
+            unsafe {
+                (!aabbs.is_empty())
+                    .then(|| std::slice::from_raw_parts(aabbs.as_ptr() as *const u8, size))
+            },
+        )
+    }
+
     pub fn create_ray_tracing_bottom_acceleration(
         &self,
         desc: &RayTracingBottomAccelerationDesc,
+        name: impl Into<String>,
         scratch_buffer: &RayTracingAccelerationScratchBuffer,
     ) -> Result<RayTracingAcceleration, BackendError> {
         //log::trace!("Creating ray tracing bottom acceleration: {:?}", desc);
@@ -107,6 +221,22 @@ impl Device {
                 |desc| -> Result<ash::vk::AccelerationStructureGeometryKHR, BackendError> {
                     let part: RayTracingGeometryPart = desc.parts[0];
 
+                    if desc.geometry_type == RayTracingGeometryType::BoundingBox {
+                        return Ok(ash::vk::AccelerationStructureGeometryKHR::builder()
+                            .geometry_type(ash::vk::GeometryTypeKHR::AABBS)
+                            .geometry(ash::vk::AccelerationStructureGeometryDataKHR {
+                                aabbs: ash::vk::AccelerationStructureGeometryAabbsDataKHR::builder(
+                                )
+                                .data(ash::vk::DeviceOrHostAddressConstKHR {
+                                    device_address: desc.vertex_buffer,
+                                })
+                                .stride(desc.vertex_stride as _)
+                                .build(),
+                            })
+                            .flags(ash::vk::GeometryFlagsKHR::OPAQUE)
+                            .build());
+                    }
+
                     let geometry = ash::vk::AccelerationStructureGeometryKHR::builder()
                         .geometry_type(ash::vk::GeometryTypeKHR::TRIANGLES)
                         .geometry(ash::vk::AccelerationStructureGeometryDataKHR {
@@ -138,7 +268,7 @@ impl Device {
             .iter()
             .map(|desc| {
                 ash::vk::AccelerationStructureBuildRangeInfoKHR::builder()
-                    .primitive_count(desc.parts[0].index_count as u32 / 3)
+                    .primitive_count(desc.primitive_count())
                     .build()
             })
             .collect();
@@ -153,7 +283,7 @@ impl Device {
         let max_primitive_counts: Vec<_> = desc
             .geometries
             .iter()
-            .map(|desc| desc.parts[0].index_count as u32 / 3)
+            .map(RayTracingGeometryDesc::primitive_count)
             .collect();
 
         // Create bottom-level acceleration structure
@@ -165,6 +295,7 @@ impl Device {
             &build_range_infos,
             &max_primitive_counts,
             preallocate_bytes,
+            name.into(),
             scratch_buffer,
         )
     }
@@ -172,6 +303,7 @@ impl Device {
     pub fn create_ray_tracing_top_acceleration(
         &self,
         desc: &RayTracingTopAccelerationDesc,
+        name: impl Into<String>,
         scratch_buffer: &RayTracingAccelerationScratchBuffer,
     ) -> Result<RayTracingAcceleration, BackendError> {
         //log::trace!("Creating ray tracing top acceleration: {:?}", desc);
@@ -210,10 +342,8 @@ impl Device {
                     transform,
                     desc.mesh_index, /* instance id */
                     0xff,
-                    0,
-                    /*ash::vk::GeometryInstanceFlagsKHR::TRIANGLE_FACING_CULL_DISABLE
-                    | */
-                    ash::vk::GeometryInstanceFlagsKHR::FORCE_OPAQUE,
+                    desc.hit_group_offset,
+                    desc.flags(),
                     blas_address,
                 )
             })
@@ -271,6 +401,7 @@ impl Device {
             &build_range_infos,
             &max_primitive_counts,
             desc.preallocate_bytes,
+            name.into(),
             scratch_buffer,
         )
     }
@@ -282,6 +413,7 @@ impl Device {
         build_range_infos: &[vk::AccelerationStructureBuildRangeInfoKHR],
         max_primitive_counts: &[u32],
         preallocate_bytes: usize,
+        name: String,
         scratch_buffer: &RayTracingAccelerationScratchBuffer,
     ) -> Result<RayTracingAcceleration, BackendError> {
         let memory_requirements = unsafe {
@@ -308,7 +440,7 @@ impl Device {
                 vk::BufferUsageFlags::ACCELERATION_STRUCTURE_STORAGE_KHR
                     | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS,
             ),
-            "Acceleration structure buffer",
+            format!("{} buffer", name),
             None,
         )?;
 
@@ -325,6 +457,15 @@ impl Device {
                 //.context("create_acceleration_structure")?;
                 ?;
 
+            // The backing buffer counts towards the acceleration structure instead.
+            self.untrack_resource(ResourceCategory::Buffer, accel_buffer.raw);
+            self.track_resource(
+                ResourceCategory::AccelerationStructure,
+                accel_raw,
+                name,
+                backing_buffer_size as u64,
+            );
+
             let scratch_buffer = scratch_buffer.buffer.lock();
             assert!(
                 memory_requirements.build_scratch_size as usize <= scratch_buffer.desc.size,
@@ -421,10 +562,8 @@ impl Device {
                 transform,
                 desc.mesh_index, /* instance id */
                 0xff,
-                0,
-                /*ash::vk::GeometryInstanceFlagsKHR::TRIANGLE_FACING_CULL_DISABLE
-                | */
-                ash::vk::GeometryInstanceFlagsKHR::FORCE_OPAQUE,
+                desc.hit_group_offset,
+                desc.flags(),
                 blas_address,
             )
         }));
@@ -569,30 +708,46 @@ impl Device {
                 ?
         };
 
-        let prog_size = shader_group_handle_size;
+        let shader_group_handle_alignment = self
+            .ray_tracing_pipeline_properties
+            .shader_group_handle_alignment as usize;
+
+        let group_records = |entry_offset: u32, entry_count: u32| {
+            (entry_offset as usize..(entry_offset + entry_count) as usize)
+                .map(|group| {
+                    let data = desc
+                        .shader_record_data
+                        .get(group)
+                        .map_or(&[][..], Vec::as_slice);
+                    (group, data)
+                })
+                .collect::<Vec<_>>()
+        };
 
         let create_binding_table =
-            |entry_offset: u32,
-             entry_count: u32|
-             -> Result<Option<crate::vulkan::buffer::Buffer>, BackendError> {
-                if 0 == entry_count {
-                    return Ok(None);
-                }
+            |records: Vec<(usize, &[u8])>| -> Result<(Option<_>, usize), BackendError> {
+                let stride = shader_record_stride(
+                    shader_group_handle_size,
+                    shader_group_handle_alignment,
+                    records
+                        .iter()
+                        .map(|(_, data)| data.len())
+                        .max()
+                        .unwrap_or(0),
+                );
 
-                let mut shader_binding_table_data =
-                    vec![0u8; (entry_count as usize * prog_size) as _];
-
-                for dst in 0..(entry_count as usize) {
-                    let src = dst + entry_offset as usize;
-                    shader_binding_table_data
-                        [dst * prog_size..dst * prog_size + shader_group_handle_size]
-                        .copy_from_slice(
-                            &group_handles[src * shader_group_handle_size
-                                ..src * shader_group_handle_size + shader_group_handle_size],
-                        );
+                if records.is_empty() {
+                    return Ok((None, stride));
                 }
 
-                Ok(Some(self.create_buffer(
+                let shader_binding_table_data = write_shader_binding_table(
+                    &group_handles,
+                    shader_group_handle_size,
+                    stride,
+                    records.into_iter(),
+                );
+
+                let buffer = self.create_buffer(
                     super::buffer::BufferDesc::new_gpu_only(
                         shader_binding_table_data.len(),
                         vk::BufferUsageFlags::TRANSFER_SRC
@@ -601,16 +756,28 @@ impl Device {
                     ),
                     "SBT sub-buffer",
                     Some(&shader_binding_table_data),
-                )?))
+                )?;
+
+                Ok((Some(Arc::new(buffer)), stride))
             };
 
-        let raygen_shader_binding_table = create_binding_table(0, desc.raygen_entry_count)?;
-        let miss_shader_binding_table =
-            create_binding_table(desc.raygen_entry_count, desc.miss_entry_count)?;
-        let hit_shader_binding_table = create_binding_table(
-            desc.raygen_entry_count + desc.miss_entry_count,
-            desc.hit_entry_count,
-        )?;
+        let hit_group_offset = desc.raygen_entry_count + desc.miss_entry_count;
+        let mut hit_records = group_records(hit_group_offset, desc.hit_entry_count);
+        for record in &desc.extra_hit_records {
+            hit_records.push((
+                (hit_group_offset + record.hit_group) as usize,
+                record.data.as_slice(),
+            ));
+        }
+        let hit_record_count = hit_records.len();
+
+        let (raygen_shader_binding_table, raygen_stride) =
+            create_binding_table(group_records(0, desc.raygen_entry_count))?;
+        let (miss_shader_binding_table, miss_stride) = create_binding_table(group_records(
+            desc.raygen_entry_count,
+            desc.miss_entry_count,
+        ))?;
+        let (hit_shader_binding_table, hit_stride) = create_binding_table(hit_records)?;
 
         Ok(RayTracingShaderTable {
             raygen_shader_binding_table: vk::StridedDeviceAddressRegionKHR {
@@ -618,8 +785,8 @@ impl Device {
                     .as_ref()
                     .map(|b| b.device_address(self))
                     .unwrap_or(0),
-                stride: prog_size as u64,
-                size: (prog_size * desc.raygen_entry_count as usize) as u64,
+                stride: raygen_stride as u64,
+                size: (raygen_stride * desc.raygen_entry_count as usize) as u64,
             },
             raygen_shader_binding_table_buffer: raygen_shader_binding_table,
             miss_shader_binding_table: vk::StridedDeviceAddressRegionKHR {
@@ -627,8 +794,8 @@ impl Device {
                     .as_ref()
                     .map(|b| b.device_address(self))
                     .unwrap_or(0),
-                stride: prog_size as u64,
-                size: (prog_size * desc.miss_entry_count as usize) as u64,
+                stride: miss_stride as u64,
+                size: (miss_stride * desc.miss_entry_count as usize) as u64,
             },
             miss_shader_binding_table_buffer: miss_shader_binding_table,
             hit_shader_binding_table: vk::StridedDeviceAddressRegionKHR {
@@ -636,8 +803,8 @@ impl Device {
                     .as_ref()
                     .map(|b| b.device_address(self))
                     .unwrap_or(0),
-                stride: prog_size as u64,
-                size: (prog_size * desc.hit_entry_count as usize) as u64,
+                stride: hit_stride as u64,
+                size: (hit_stride * hit_record_count) as u64,
             },
             hit_shader_binding_table_buffer: hit_shader_binding_table,
             callable_shader_binding_table_buffer: None,
@@ -650,9 +817,122 @@ impl Device {
     }
 }
 
+// Records start with the shader group handle, followed by the group's shader record data.
+// All records of a table share the stride, so that it fits the largest.
+fn shader_record_stride(
+    handle_size: usize,
+    handle_alignment: usize,
+    max_record_data_size: usize,
+) -> usize {
+    let size = handle_size + max_record_data_size;
+    (size + handle_alignment - 1) / handle_alignment * handle_alignment
+}
+
+fn write_shader_binding_table<'a>(
+    group_handles: &[u8],
+    handle_size: usize,
+    stride: usize,
+    records: impl ExactSizeIterator<Item = (usize, &'a [u8])>,
+) -> Vec<u8> {
+    let mut table = vec![0u8; records.len() * stride];
+
+    for (record, (group, record_data)) in table.chunks_exact_mut(stride).zip(records) {
+        record[..handle_size]
+            .copy_from_slice(&group_handles[group * handle_size..(group + 1) * handle_size]);
+        record[handle_size..handle_size + record_data.len()].copy_from_slice(record_data);
+    }
+
+    table
+}
+
 pub struct RayTracingPipeline {
     pub common: ShaderPipelineCommon,
     pub sbt: RayTracingShaderTable,
+    pub sbt_layout: ShaderBindingTableLayout,
+
+    // Keys into `RayTracingPipelineLibraries` of the libraries linked, if any
+    pub(crate) library_keys: Vec<u64>,
+
+    // For rebuilding the shader table with extra hit records
+    sbt_desc: RayTracingShaderTableDesc,
+    // The table last built with extra hit records, and the records
+    hit_records_sbt: Mutex<Option<(Vec<HitShaderRecord>, Arc<RayTracingShaderTable>)>>,
+}
+
+impl RayTracingPipeline {
+    /// The shader table with `records` appended to the hit table. It is only rebuilt when
+    /// the records change; the table it replaces is released once the frame is done with it.
+    pub fn shader_table_with_hit_records(
+        &self,
+        device: &Device,
+        records: &[HitShaderRecord],
+    ) -> anyhow::Result<Arc<RayTracingShaderTable>> {
+        let mut hit_records_sbt = self.hit_records_sbt.lock();
+
+        if let Some((cached_records, sbt)) = hit_records_sbt.as_ref() {
+            if cached_records.as_slice() == records {
+                return Ok(sbt.clone());
+            }
+        }
+
+        let props = &device.ray_tracing_pipeline_properties;
+        for record in records {
+            anyhow::ensure!(
+                record.hit_group < self.sbt_desc.hit_entry_count,
+                "A hit record uses hit group {}, but the pipeline has {}",
+                record.hit_group,
+                self.sbt_desc.hit_entry_count
+            );
+
+            let stride = shader_record_stride(
+                props.shader_group_handle_size as usize,
+                props.shader_group_handle_alignment as usize,
+                record.data.len(),
+            );
+            anyhow::ensure!(
+                stride <= props.max_shader_group_stride as usize,
+                "{} bytes of hit record data exceed the device's max shader group stride of {}",
+                record.data.len(),
+                props.max_shader_group_stride
+            );
+        }
+
+        let sbt = Arc::new(
+            device
+                .create_ray_tracing_shader_table(
+                    &RayTracingShaderTableDesc {
+                        extra_hit_records: records.to_vec(),
+                        ..self.sbt_desc.clone()
+                    },
+                    self.common.pipeline,
+                )
+                .map_err(|err| device.report_error(err))?,
+        );
+
+        if let Some((_, prev_sbt)) = hit_records_sbt.replace((records.to_vec(), sbt.clone())) {
+            for buffer in prev_sbt.buffers() {
+                device.defer_release_buffer(buffer.clone());
+            }
+        }
+
+        Ok(sbt)
+    }
+
+    /// The buffers of all shader tables built for the pipeline, to be released along with it.
+    pub fn shader_table_buffers(&self) -> Vec<Arc<super::buffer::Buffer>> {
+        let hit_records_sbt = self.hit_records_sbt.lock();
+        self.sbt
+            .buffers()
+            .chain(hit_records_sbt.iter().flat_map(|(_, sbt)| sbt.buffers()))
+            .cloned()
+            .collect()
+    }
+
+    /// The records of the shader binding tables, for diagnosing miss and hit group index
+    /// mismatches; `Display` prints one record per line.
+    pub fn dump_sbt(&self) -> &ShaderBindingTableLayout {
+        &self.sbt_layout
+    }
 }
 
 impl std::ops::Deref for RayTracingPipeline {
@@ -666,13 +946,23 @@ impl std::ops::Deref for RayTracingPipeline {
 #[derive(Clone)]
 pub struct RayTracingPipelineDesc {
     pub descriptor_set_opts: [Option<(u32, DescriptorSetLayoutOpts)>; MAX_DESCRIPTOR_SETS],
+
+    /// How deep `TraceRayEXT` calls may nest: 1 when only the raygen shader traces rays,
+    /// as in iterative (loop-based) tracers, and more for recursive ones which trace from
+    /// hit or miss shaders. Pipeline creation fails if it exceeds the device's
+    /// `maxRayRecursionDepth`, rather than leaving the driver to clamp it.
     pub max_pipeline_ray_recursion_depth: u32,
+
+    /// Upper bound on the size of the ray payloads, in bytes. Only pipelines linked from
+    /// libraries declare it, as their shader groups are compiled without seeing each other.
+    pub max_pipeline_ray_payload_size: u32,
 }
 
 impl Default for RayTracingPipelineDesc {
     fn default() -> Self {
         Self {
             max_pipeline_ray_recursion_depth: 1,
+            max_pipeline_ray_payload_size: 64,
             descriptor_set_opts: Default::default(),
         }
     }
@@ -686,6 +976,400 @@ impl RayTracingPipelineDesc {
         self.max_pipeline_ray_recursion_depth = max_pipeline_ray_recursion_depth;
         self
     }
+
+    pub fn max_pipeline_ray_payload_size(mut self, max_pipeline_ray_payload_size: u32) -> Self {
+        self.max_pipeline_ray_payload_size = max_pipeline_ray_payload_size;
+        self
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ShaderBindingTableRecord {
+    /// Index within its table; what `TraceRayEXT` takes as the miss index,
+    /// or as the hit group offset.
+    pub index: u32,
+
+    /// Index of the shader group within the pipeline
+    pub group_index: u32,
+
+    pub source: ShaderSource,
+    pub entry: String,
+}
+
+/// A shader group of a ray tracing pipeline, as indices into its shaders.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum RayTracingShaderGroup {
+    General {
+        stage: ShaderPipelineStage,
+        shader: usize,
+    },
+    TrianglesHit {
+        closest_hit: Option<usize>,
+        any_hit: Option<usize>,
+    },
+    ProceduralHit {
+        intersection: usize,
+        closest_hit: Option<usize>,
+        any_hit: Option<usize>,
+    },
+}
+
+impl RayTracingShaderGroup {
+    // The shader whose name and record data the group goes by. That of procedural groups is
+    // the intersection shader, which defines the primitive.
+    fn main_shader(&self) -> usize {
+        match *self {
+            Self::General { shader, .. } => shader,
+            Self::TrianglesHit {
+                closest_hit,
+                any_hit,
+            } => closest_hit.or(any_hit).unwrap(),
+            Self::ProceduralHit { intersection, .. } => intersection,
+        }
+    }
+
+    fn is_hit(&self) -> bool {
+        matches!(self, Self::TrianglesHit { .. } | Self::ProceduralHit { .. })
+    }
+
+    // In the order of the pipeline
+    fn shaders(&self) -> Vec<usize> {
+        match *self {
+            Self::General { shader, .. } => vec![shader],
+            Self::TrianglesHit {
+                closest_hit,
+                any_hit,
+            } => closest_hit.into_iter().chain(any_hit).collect(),
+            Self::ProceduralHit {
+                intersection,
+                closest_hit,
+                any_hit,
+            } => std::iter::once(intersection)
+                .chain(closest_hit)
+                .chain(any_hit)
+                .collect(),
+        }
+    }
+
+    // With shaders at the indices `shader_index` maps them to
+    fn create_info(
+        &self,
+        shader_index: impl Fn(usize) -> u32,
+    ) -> vk::RayTracingShaderGroupCreateInfoKHR {
+        match *self {
+            Self::General { shader, .. } => vk::RayTracingShaderGroupCreateInfoKHR::builder()
+                .ty(vk::RayTracingShaderGroupTypeKHR::GENERAL)
+                .general_shader(shader_index(shader))
+                .closest_hit_shader(vk::SHADER_UNUSED_KHR)
+                .any_hit_shader(vk::SHADER_UNUSED_KHR)
+                .intersection_shader(vk::SHADER_UNUSED_KHR)
+                .build(),
+            Self::TrianglesHit {
+                closest_hit,
+                any_hit,
+            } => vk::RayTracingShaderGroupCreateInfoKHR::builder()
+                .ty(vk::RayTracingShaderGroupTypeKHR::TRIANGLES_HIT_GROUP)
+                .general_shader(vk::SHADER_UNUSED_KHR)
+                .closest_hit_shader(closest_hit.map_or(vk::SHADER_UNUSED_KHR, &shader_index))
+                .any_hit_shader(any_hit.map_or(vk::SHADER_UNUSED_KHR, &shader_index))
+                .intersection_shader(vk::SHADER_UNUSED_KHR)
+                .build(),
+            Self::ProceduralHit {
+                intersection,
+                closest_hit,
+                any_hit,
+            } => vk::RayTracingShaderGroupCreateInfoKHR::builder()
+                .ty(vk::RayTracingShaderGroupTypeKHR::PROCEDURAL_HIT_GROUP)
+                .general_shader(vk::SHADER_UNUSED_KHR)
+                .closest_hit_shader(closest_hit.map_or(vk::SHADER_UNUSED_KHR, &shader_index))
+                .any_hit_shader(any_hit.map_or(vk::SHADER_UNUSED_KHR, &shader_index))
+                .intersection_shader(shader_index(intersection))
+                .build(),
+        }
+    }
+}
+
+// Groups shaders in pipeline order. Any-hit shaders join the hit group of the closest-hit
+// shader right before them, so that they run for the same hits. Intersection shaders start
+// procedural hit groups, which the closest-hit and any-hit shaders right after them join.
+fn ray_tracing_shader_groups(
+    stages: impl IntoIterator<Item = ShaderPipelineStage>,
+) -> anyhow::Result<Vec<RayTracingShaderGroup>> {
+    let mut groups: Vec<RayTracingShaderGroup> = Vec::new();
+
+    for (shader, stage) in stages.into_iter().enumerate() {
+        match stage {
+            ShaderPipelineStage::RayGen | ShaderPipelineStage::RayMiss => {
+                groups.push(RayTracingShaderGroup::General { stage, shader })
+            }
+            ShaderPipelineStage::RayIntersection => {
+                groups.push(RayTracingShaderGroup::ProceduralHit {
+                    intersection: shader,
+                    closest_hit: None,
+                    any_hit: None,
+                })
+            }
+            ShaderPipelineStage::RayClosestHit => match groups.last_mut() {
+                Some(RayTracingShaderGroup::ProceduralHit {
+                    closest_hit: closest_hit @ None,
+                    any_hit: None,
+                    ..
+                }) => *closest_hit = Some(shader),
+                _ => groups.push(RayTracingShaderGroup::TrianglesHit {
+                    closest_hit: Some(shader),
+                    any_hit: None,
+                }),
+            },
+            ShaderPipelineStage::RayAnyHit => match groups.last_mut() {
+                Some(RayTracingShaderGroup::TrianglesHit {
+                    closest_hit: Some(_),
+                    any_hit: any_hit @ None,
+                })
+                | Some(RayTracingShaderGroup::ProceduralHit {
+                    any_hit: any_hit @ None,
+                    ..
+                }) => *any_hit = Some(shader),
+                _ => groups.push(RayTracingShaderGroup::TrianglesHit {
+                    closest_hit: None,
+                    any_hit: Some(shader),
+                }),
+            },
+            stage => anyhow::bail!("{:?} is not a ray tracing stage", stage),
+        }
+    }
+
+    Ok(groups)
+}
+
+/// The order of records in the shader binding tables of a ray tracing pipeline.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ShaderBindingTableLayout {
+    pub raygen: Vec<ShaderBindingTableRecord>,
+    pub miss: Vec<ShaderBindingTableRecord>,
+    pub hit: Vec<ShaderBindingTableRecord>,
+    pub callable: Vec<ShaderBindingTableRecord>,
+
+    /// Miss indices passed to `TraceRayEXT` by the pipeline's shaders.
+    /// Only constant indices are known; others aren't listed.
+    pub traced_miss_indices: Vec<u32>,
+}
+
+impl ShaderBindingTableLayout {
+    fn new(shaders: &[PipelineShader<Bytes>]) -> anyhow::Result<Self> {
+        let mut layout = Self::default();
+
+        let groups = ray_tracing_shader_groups(shaders.iter().map(|shader| shader.desc.stage))?;
+        for (group_index, group) in groups.iter().enumerate() {
+            let records = match group {
+                RayTracingShaderGroup::General {
+                    stage: ShaderPipelineStage::RayGen,
+                    ..
+                } => &mut layout.raygen,
+                RayTracingShaderGroup::General { .. } => &mut layout.miss,
+                RayTracingShaderGroup::TrianglesHit { .. }
+                | RayTracingShaderGroup::ProceduralHit { .. } => &mut layout.hit,
+            };
+
+            let shader = &shaders[group.main_shader()];
+            records.push(ShaderBindingTableRecord {
+                index: records.len() as u32,
+                group_index: group_index as u32,
+                source: shader.desc.source.clone(),
+                entry: shader.desc.entry.clone(),
+            });
+        }
+
+        for shader in shaders {
+            let mut loader = rspirv::dr::Loader::new();
+            rspirv::binary::parse_bytes(&shader.code[..], &mut loader).map_err(|err| {
+                anyhow::anyhow!("Parsing the SPIR-V of {:?}: {:?}", shader.desc.source, err)
+            })?;
+            layout
+                .traced_miss_indices
+                .extend(traced_miss_indices(&loader.module()));
+        }
+
+        layout.traced_miss_indices.sort_unstable();
+        layout.traced_miss_indices.dedup();
+
+        Ok(layout)
+    }
+
+    /// Checks that the shaders only trace rays with miss indices which have a record.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(!self.raygen.is_empty(), "No raygen shader");
+
+        for &miss_index in &self.traced_miss_indices {
+            anyhow::ensure!(
+                (miss_index as usize) < self.miss.len(),
+                "Rays are traced with miss index {}, but there are only {} miss records",
+                miss_index,
+                self.miss.len()
+            );
+        }
+
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for ShaderBindingTableLayout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (table, records) in [
+            ("raygen", &self.raygen),
+            ("miss", &self.miss),
+            ("hit", &self.hit),
+            ("callable", &self.callable),
+        ] {
+            for record in records {
+                writeln!(
+                    f,
+                    "{} {} (group {}): {:?}, entry {:?}",
+                    table, record.index, record.group_index, record.source, record.entry
+                )?;
+            }
+        }
+
+        write!(f, "traced miss indices: {:?}", self.traced_miss_indices)
+    }
+}
+
+// The constant miss indices of `OpTraceRayKHR` instructions in the module.
+fn traced_miss_indices(module: &rspirv::dr::Module) -> Vec<u32> {
+    use rspirv::{dr::Operand, spirv::Op};
+
+    let constants: std::collections::HashMap<u32, u32> = module
+        .types_global_values
+        .iter()
+        .filter_map(
+            |inst| match (inst.class.opcode, inst.result_id, inst.operands.first()) {
+                (Op::Constant, Some(id), Some(Operand::LiteralInt32(value))) => Some((id, *value)),
+                _ => None,
+            },
+        )
+        .collect();
+
+    module
+        .functions
+        .iter()
+        .flat_map(|function| function.blocks.iter())
+        .flat_map(|block| block.instructions.iter())
+        .filter(|inst| inst.class.opcode == Op::TraceRayKHR)
+        // Operands: acceleration structure, ray flags, cull mask, SBT offset, SBT stride, miss index, ...
+        .filter_map(|inst| match inst.operands.get(5) {
+            Some(Operand::IdRef(id)) => constants.get(id).copied(),
+            _ => None,
+        })
+        .collect()
+}
+
+// Newest SPIR-V version consumable by Vulkan 1.2
+const MAX_SPIRV_VERSION: (u8, u8) = (1, 5);
+
+// Stages of a ray tracing pipeline can come from different compilers (e.g. an HLSL raygen
+// with Rust-GPU hit shaders). Check that their modules can be linked together, so that
+// mismatches are reported clearly instead of failing in the driver.
+fn validate_ray_tracing_shaders(shaders: &[PipelineShader<Bytes>]) -> anyhow::Result<()> {
+    use rspirv::spirv::{Capability, ExecutionModel};
+
+    for shader in shaders {
+        let what = || format!("{:?} shader {:?}", shader.desc.stage, shader.desc.source);
+
+        let mut loader = rspirv::dr::Loader::new();
+        rspirv::binary::parse_bytes(&shader.code[..], &mut loader)
+            .map_err(|err| anyhow::anyhow!("Parsing the SPIR-V of {}: {:?}", what(), err))?;
+        let module = loader.module();
+
+        let version = module
+            .header
+            .as_ref()
+            .map_or((0, 0), |header| header.version());
+        anyhow::ensure!(
+            version <= MAX_SPIRV_VERSION,
+            "{} uses SPIR-V {}.{}, but at most {}.{} is supported",
+            what(),
+            version.0,
+            version.1,
+            MAX_SPIRV_VERSION.0,
+            MAX_SPIRV_VERSION.1
+        );
+
+        let has_ray_tracing_capability = module.capabilities.iter().any(|inst| {
+            matches!(
+                inst.operands.first(),
+                Some(rspirv::dr::Operand::Capability(Capability::RayTracingKHR))
+            )
+        });
+        anyhow::ensure!(
+            has_ray_tracing_capability,
+            "{} doesn't declare the RayTracingKHR capability",
+            what()
+        );
+
+        let expected_model = match shader.desc.stage {
+            ShaderPipelineStage::RayGen => ExecutionModel::RayGenerationKHR,
+            ShaderPipelineStage::RayMiss => ExecutionModel::MissKHR,
+            ShaderPipelineStage::RayClosestHit => ExecutionModel::ClosestHitKHR,
+            ShaderPipelineStage::RayAnyHit => ExecutionModel::AnyHitKHR,
+            ShaderPipelineStage::RayIntersection => ExecutionModel::IntersectionKHR,
+            stage => anyhow::bail!("{:?} is not a ray tracing stage", stage),
+        };
+
+        let has_entry_point = module.entry_points.iter().any(|inst| {
+            matches!(
+                (inst.operands.get(0), inst.operands.get(2)),
+                (
+                    Some(rspirv::dr::Operand::ExecutionModel(model)),
+                    Some(rspirv::dr::Operand::LiteralString(name)),
+                ) if *model == expected_model && *name == shader.desc.entry
+            )
+        });
+        anyhow::ensure!(
+            has_entry_point,
+            "{} has no {:?} entry point named {:?}",
+            what(),
+            expected_model,
+            shader.desc.entry
+        );
+    }
+
+    Ok(())
+}
+
+fn validate_ray_recursion_depth(depth: u32, device_limit: u32) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        depth <= device_limit,
+        "Ray tracing pipeline requests a max ray recursion depth of {}, but the device supports at most {}. Trace iteratively from the raygen shader instead",
+        depth,
+        device_limit
+    );
+
+    Ok(())
+}
+
+fn validate_shader_record_data(
+    device: &Device,
+    shaders: &[PipelineShader<Bytes>],
+) -> anyhow::Result<()> {
+    let props = &device.ray_tracing_pipeline_properties;
+    let max_record_data_size = shaders
+        .iter()
+        .map(|shader| shader.desc.shader_record_data.len())
+        .max()
+        .unwrap_or(0);
+
+    let stride = shader_record_stride(
+        props.shader_group_handle_size as usize,
+        props.shader_group_handle_alignment as usize,
+        max_record_data_size,
+    );
+    anyhow::ensure!(
+        stride <= props.max_shader_group_stride as usize,
+        "{} bytes of shader record data don't fit in the device's max shader group stride of {}",
+        max_record_data_size,
+        props.max_shader_group_stride
+    );
+
+    Ok(())
 }
 
 pub fn create_ray_tracing_pipeline(
@@ -693,21 +1377,59 @@ pub fn create_ray_tracing_pipeline(
     shaders: &[PipelineShader<Bytes>],
     desc: &RayTracingPipelineDesc,
 ) -> anyhow::Result<RayTracingPipeline> {
-    let stage_layouts = shaders
-        .iter()
-        .map(|desc| {
-            rspirv_reflect::Reflection::new_from_spirv(&desc.code)
-                .unwrap_or_else(|err| panic!("Failed compiling shader {:?}:\n{:?}", desc.desc, err))
-                .get_descriptor_sets()
-                .unwrap()
-        })
-        .collect::<Vec<_>>();
+    create_ray_tracing_pipeline_impl(device, shaders, desc, None)
+}
 
-    //log::info!("{:#?}", stage_layouts);
+/// Like `create_ray_tracing_pipeline`, but compiles each shader group into a pipeline library
+/// of its own, reusing those in `libraries` which have the same shaders and layout, and links
+/// them. Falls back to a monolithic pipeline if libraries aren't supported, or fail.
+pub fn create_linked_ray_tracing_pipeline(
+    device: &Device,
+    shaders: &[PipelineShader<Bytes>],
+    desc: &RayTracingPipelineDesc,
+    libraries: &mut RayTracingPipelineLibraries,
+) -> anyhow::Result<RayTracingPipeline> {
+    create_ray_tracing_pipeline_impl(device, shaders, desc, Some(libraries))
+}
+
+fn create_ray_tracing_pipeline_impl(
+    device: &Device,
+    shaders: &[PipelineShader<Bytes>],
+    desc: &RayTracingPipelineDesc,
+    libraries: Option<&mut RayTracingPipelineLibraries>,
+) -> anyhow::Result<RayTracingPipeline> {
+    validate_ray_recursion_depth(
+        desc.max_pipeline_ray_recursion_depth,
+        device
+            .ray_tracing_pipeline_properties
+            .max_ray_recursion_depth,
+    )?;
+    validate_ray_tracing_shaders(shaders)?;
+    validate_shader_record_data(device, shaders)?;
+    let sbt_layout = ShaderBindingTableLayout::new(shaders)?;
+
+    let reflection = ShaderReflection::merge(
+        shaders
+            .iter()
+            .map(|shader| {
+                ShaderReflection::new(&shader.code)
+                    .with_context(|| format!("Reflecting shader {:?}", shader.desc))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?,
+    )?;
+    reflection.validate_descriptor_set_opts(&desc.descriptor_set_opts)?;
+
+    // The pipeline layout doesn't have a push constant range
+    anyhow::ensure!(
+        reflection.push_constants_bytes == 0,
+        "Ray tracing shaders don't support push constants"
+    );
+
+    //log::info!("{:#?}", reflection);
 
     let (descriptor_set_layouts, set_layout_info) = super::shader::create_descriptor_set_layouts(
         device,
-        &merge_shader_stage_layouts(stage_layouts),
+        &reflection.descriptor_sets,
         vk::ShaderStageFlags::ALL,
         //desc.descriptor_set_layout_flags.unwrap_or(&[]),  // TODO: merge flags
         &desc.descriptor_set_opts,
@@ -723,16 +1445,11 @@ pub fn create_ray_tracing_pipeline(
             .create_pipeline_layout(&layout_create_info, None)
             .unwrap();
 
-        let mut shader_groups: Vec<vk::RayTracingShaderGroupCreateInfoKHR> = Vec::new();
         let mut shader_stages: Vec<vk::PipelineShaderStageCreateInfo> = Vec::new();
 
         // Keep entry point names alive, since build() forgets references.
         let mut entry_points: Vec<std::ffi::CString> = Vec::new();
 
-        let mut raygen_entry_count = 0;
-        let mut miss_entry_count = 0;
-        let mut hit_entry_count = 0;
-
         let create_shader_module =
             |desc: &PipelineShader<Bytes>| -> (ash::vk::ShaderModule, String) {
                 let shader_info = vk::ShaderModuleCreateInfo::builder()
@@ -749,116 +1466,129 @@ pub fn create_ray_tracing_pipeline(
         let mut prev_stage: Option<ShaderPipelineStage> = None;
 
         for desc in shaders {
-            let group_idx = shader_stages.len();
-
-            match desc.desc.stage {
+            let stage = match desc.desc.stage {
                 ShaderPipelineStage::RayGen => {
                     assert!(prev_stage == None || prev_stage == Some(ShaderPipelineStage::RayGen));
-                    raygen_entry_count += 1;
-
-                    let (module, entry_point) = create_shader_module(desc);
-
-                    entry_points.push(std::ffi::CString::new(entry_point).unwrap());
-                    let entry_point = &**entry_points.last().unwrap();
-
-                    let stage = ash::vk::PipelineShaderStageCreateInfo::builder()
-                        .stage(ash::vk::ShaderStageFlags::RAYGEN_KHR)
-                        .module(module)
-                        .name(entry_point)
-                        .build();
-
-                    let group = ash::vk::RayTracingShaderGroupCreateInfoKHR::builder()
-                        .ty(ash::vk::RayTracingShaderGroupTypeKHR::GENERAL)
-                        .general_shader(group_idx as _)
-                        .closest_hit_shader(ash::vk::SHADER_UNUSED_KHR)
-                        .any_hit_shader(ash::vk::SHADER_UNUSED_KHR)
-                        .intersection_shader(ash::vk::SHADER_UNUSED_KHR)
-                        .build();
-
-                    shader_stages.push(stage);
-                    shader_groups.push(group);
+                    ash::vk::ShaderStageFlags::RAYGEN_KHR
                 }
                 ShaderPipelineStage::RayMiss => {
                     assert!(
                         prev_stage == Some(ShaderPipelineStage::RayGen)
                             || prev_stage == Some(ShaderPipelineStage::RayMiss)
                     );
-                    miss_entry_count += 1;
-
-                    let (module, entry_point) = create_shader_module(desc);
-
-                    entry_points.push(std::ffi::CString::new(entry_point).unwrap());
-                    let entry_point = &**entry_points.last().unwrap();
-
-                    let stage = ash::vk::PipelineShaderStageCreateInfo::builder()
-                        .stage(ash::vk::ShaderStageFlags::MISS_KHR)
-                        .module(module)
-                        .name(entry_point)
-                        .build();
-
-                    let group = ash::vk::RayTracingShaderGroupCreateInfoKHR::builder()
-                        .ty(ash::vk::RayTracingShaderGroupTypeKHR::GENERAL)
-                        .general_shader(group_idx as _)
-                        .closest_hit_shader(ash::vk::SHADER_UNUSED_KHR)
-                        .any_hit_shader(ash::vk::SHADER_UNUSED_KHR)
-                        .intersection_shader(ash::vk::SHADER_UNUSED_KHR)
-                        .build();
-
-                    shader_stages.push(stage);
-                    shader_groups.push(group);
+                    ash::vk::ShaderStageFlags::MISS_KHR
                 }
-                ShaderPipelineStage::RayClosestHit => {
+                ShaderPipelineStage::RayClosestHit
+                | ShaderPipelineStage::RayAnyHit
+                | ShaderPipelineStage::RayIntersection => {
                     assert!(
                         prev_stage == Some(ShaderPipelineStage::RayMiss)
                             || prev_stage == Some(ShaderPipelineStage::RayClosestHit)
+                            || prev_stage == Some(ShaderPipelineStage::RayAnyHit)
+                            || prev_stage == Some(ShaderPipelineStage::RayIntersection)
                     );
-                    hit_entry_count += 1;
-
-                    let (module, entry_point) = create_shader_module(desc);
+                    match desc.desc.stage {
+                        ShaderPipelineStage::RayClosestHit => {
+                            ash::vk::ShaderStageFlags::CLOSEST_HIT_KHR
+                        }
+                        ShaderPipelineStage::RayAnyHit => ash::vk::ShaderStageFlags::ANY_HIT_KHR,
+                        _ => ash::vk::ShaderStageFlags::INTERSECTION_KHR,
+                    }
+                }
+                _ => unimplemented!(),
+            };
 
-                    entry_points.push(std::ffi::CString::new(entry_point).unwrap());
-                    let entry_point = &**entry_points.last().unwrap();
+            let (module, entry_point) = create_shader_module(desc);
 
-                    let stage = ash::vk::PipelineShaderStageCreateInfo::builder()
-                        .stage(ash::vk::ShaderStageFlags::CLOSEST_HIT_KHR)
-                        .module(module)
-                        .name(entry_point)
-                        .build();
+            entry_points.push(std::ffi::CString::new(entry_point).unwrap());
+            let entry_point = &**entry_points.last().unwrap();
 
-                    let group = ash::vk::RayTracingShaderGroupCreateInfoKHR::builder()
-                        .ty(ash::vk::RayTracingShaderGroupTypeKHR::TRIANGLES_HIT_GROUP)
-                        .general_shader(ash::vk::SHADER_UNUSED_KHR)
-                        .closest_hit_shader(group_idx as _)
-                        .any_hit_shader(ash::vk::SHADER_UNUSED_KHR)
-                        .intersection_shader(ash::vk::SHADER_UNUSED_KHR)
-                        .build();
-
-                    shader_stages.push(stage);
-                    shader_groups.push(group);
-                }
-                _ => unimplemented!(),
-            }
+            shader_stages.push(
+                ash::vk::PipelineShaderStageCreateInfo::builder()
+                    .stage(stage)
+                    .module(module)
+                    .name(entry_point)
+                    .build(),
+            );
 
             prev_stage = Some(desc.desc.stage);
         }
 
+        let groups = ray_tracing_shader_groups(shaders.iter().map(|shader| shader.desc.stage))?;
+        let count_groups = |pred: fn(&RayTracingShaderGroup) -> bool| {
+            groups.iter().filter(|group| pred(group)).count() as u32
+        };
+
+        let raygen_entry_count = count_groups(|group| {
+            matches!(
+                group,
+                RayTracingShaderGroup::General {
+                    stage: ShaderPipelineStage::RayGen,
+                    ..
+                }
+            )
+        });
+        let miss_entry_count = count_groups(|group| {
+            matches!(
+                group,
+                RayTracingShaderGroup::General {
+                    stage: ShaderPipelineStage::RayMiss,
+                    ..
+                }
+            )
+        });
+        let hit_entry_count = count_groups(RayTracingShaderGroup::is_hit);
+
         assert!(raygen_entry_count > 0);
         assert!(miss_entry_count > 0);
 
-        let pipeline = device
-            .ray_tracing_pipeline_ext
-            .create_ray_tracing_pipelines(
-                vk::DeferredOperationKHR::null(),
-                vk::PipelineCache::null(),
-                &[ash::vk::RayTracingPipelineCreateInfoKHR::builder()
-                    .stages(&shader_stages)
-                    .groups(&shader_groups)
-                    .max_pipeline_ray_recursion_depth(desc.max_pipeline_ray_recursion_depth) // TODO
-                    .layout(pipeline_layout)
-                    .build()],
-                None,
-            )
-            .unwrap()[0];
+        let linked = libraries
+            .filter(|_| device.ray_tracing_pipeline_libraries_supported())
+            .and_then(|libraries| {
+                let layout_signature =
+                    descriptor_layout_signature(&reflection, &desc.descriptor_set_opts);
+
+                libraries
+                    .link(
+                        device,
+                        shaders,
+                        &shader_stages,
+                        &groups,
+                        desc,
+                        pipeline_layout,
+                        &layout_signature,
+                    )
+                    .map_err(|err| {
+                        log::warn!("{:#}; falling back to a monolithic pipeline", err);
+                    })
+                    .ok()
+            });
+
+        let (pipeline, library_keys) = if let Some(linked) = linked {
+            linked
+        } else {
+            let shader_groups: Vec<vk::RayTracingShaderGroupCreateInfoKHR> = groups
+                .iter()
+                .map(|group| group.create_info(|shader| shader as u32))
+                .collect();
+
+            let pipeline = device
+                .ray_tracing_pipeline_ext
+                .create_ray_tracing_pipelines(
+                    vk::DeferredOperationKHR::null(),
+                    vk::PipelineCache::null(),
+                    &[ash::vk::RayTracingPipelineCreateInfoKHR::builder()
+                        .stages(&shader_stages)
+                        .groups(&shader_groups)
+                        .max_pipeline_ray_recursion_depth(desc.max_pipeline_ray_recursion_depth)
+                        .layout(pipeline_layout)
+                        .build()],
+                    None,
+                )
+                .unwrap()[0];
+
+            (pipeline, Vec::new())
+        };
 
         let mut descriptor_pool_sizes: Vec<vk::DescriptorPoolSize> = Vec::new();
         for bindings in set_layout_info.iter() {
@@ -875,15 +1605,18 @@ pub fn create_ray_tracing_pipeline(
             }
         }
 
+        let sbt_desc = RayTracingShaderTableDesc {
+            raygen_entry_count,
+            hit_entry_count,
+            miss_entry_count,
+            shader_record_data: groups
+                .iter()
+                .map(|group| shaders[group.main_shader()].desc.shader_record_data.clone())
+                .collect(),
+            extra_hit_records: Vec::new(),
+        };
         let sbt = device
-            .create_ray_tracing_shader_table(
-                &RayTracingShaderTableDesc {
-                    raygen_entry_count,
-                    hit_entry_count,
-                    miss_entry_count,
-                },
-                pipeline,
-            )
+            .create_ray_tracing_shader_table(&sbt_desc, pipeline)
             .map_err(|err| device.report_error(err))?;
 
         Ok(RayTracingPipeline {
@@ -895,23 +1628,222 @@ pub fn create_ray_tracing_pipeline(
                 descriptor_pool_sizes,
                 descriptor_set_layouts,
                 pipeline_bind_point: vk::PipelineBindPoint::RAY_TRACING_KHR,
+                push_constants_bytes: 0,
+                reflection,
             },
             sbt,
+            sbt_layout,
+            library_keys,
+            sbt_desc,
+            hit_records_sbt: Default::default(),
         })
     }
 }
 
+// Identifies the descriptor set layouts of a pipeline, which its libraries must share.
+fn descriptor_layout_signature(
+    reflection: &ShaderReflection,
+    opts: &[Option<(u32, DescriptorSetLayoutOpts)>],
+) -> String {
+    fn sorted(set: &DescriptorSetLayout) -> Vec<(u32, String)> {
+        let mut bindings: Vec<(u32, String)> = set
+            .iter()
+            .map(|(binding, info)| (*binding, format!("{:?}", info)))
+            .collect();
+        bindings.sort();
+        bindings
+    }
+
+    let mut sets: Vec<_> = reflection
+        .descriptor_sets
+        .iter()
+        .map(|(set_index, set)| (*set_index, sorted(set)))
+        .collect();
+    sets.sort();
+
+    let opts: Vec<_> = opts
+        .iter()
+        .flatten()
+        .map(|(set_index, opts)| (*set_index, opts.flags, opts.replace.as_ref().map(sorted)))
+        .collect();
+
+    format!("{:?} {:?}", sets, opts)
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct RayTracingPipelineLibraryStats {
+    /// Libraries compiled so far
+    pub compiled: usize,
+
+    /// Times a library was linked into another pipeline instead of being compiled again
+    pub reused: usize,
+}
+
+/// Shader groups of ray tracing pipelines, compiled on their own as pipeline libraries
+/// (`VK_KHR_pipeline_library`), to be linked by `create_linked_ray_tracing_pipeline`.
+/// Adding a hit group to a pipeline then only compiles that group; the others are reused,
+/// as long as the descriptor set layouts of the pipeline stay the same.
+#[derive(Default)]
+pub struct RayTracingPipelineLibraries {
+    libraries: HashMap<u64, vk::Pipeline>,
+    stats: RayTracingPipelineLibraryStats,
+}
+
+impl RayTracingPipelineLibraries {
+    pub fn stats(&self) -> RayTracingPipelineLibraryStats {
+        self.stats
+    }
+
+    /// Forgets the libraries which `in_use` doesn't list, and returns them for destruction
+    /// once no pipelines linked from them are in flight.
+    pub(crate) fn take_unused(&mut self, in_use: &HashSet<u64>) -> Vec<vk::Pipeline> {
+        let mut unused = Vec::new();
+        self.libraries.retain(|key, library| {
+            let keep = in_use.contains(key);
+            if !keep {
+                unused.push(*library);
+            }
+            keep
+        });
+        unused
+    }
+
+    // Compiles the libraries of `groups` which don't exist yet, and links all of them.
+    // Returns the pipeline, and the keys of its libraries.
+    #[allow(clippy::too_many_arguments)]
+    fn link(
+        &mut self,
+        device: &Device,
+        shaders: &[PipelineShader<Bytes>],
+        shader_stages: &[vk::PipelineShaderStageCreateInfo],
+        groups: &[RayTracingShaderGroup],
+        desc: &RayTracingPipelineDesc,
+        pipeline_layout: vk::PipelineLayout,
+        layout_signature: &str,
+    ) -> anyhow::Result<(vk::Pipeline, Vec<u64>)> {
+        // Must match between the libraries and the pipeline linking them
+        let interface = vk::RayTracingPipelineInterfaceCreateInfoKHR::builder()
+            .max_pipeline_ray_payload_size(desc.max_pipeline_ray_payload_size)
+            .max_pipeline_ray_hit_attribute_size(
+                device
+                    .ray_tracing_pipeline_properties
+                    .max_ray_hit_attribute_size,
+            )
+            .build();
+
+        let mut keys = Vec::with_capacity(groups.len());
+
+        for group in groups {
+            let group_shaders = group.shaders();
+
+            let mut hasher = DefaultHasher::new();
+            for &shader in &group_shaders {
+                shaders[shader].desc.stage.hash(&mut hasher);
+                shaders[shader].desc.entry.hash(&mut hasher);
+                shaders[shader].code.hash(&mut hasher);
+            }
+            layout_signature.hash(&mut hasher);
+            desc.max_pipeline_ray_recursion_depth.hash(&mut hasher);
+            desc.max_pipeline_ray_payload_size.hash(&mut hasher);
+            let key = hasher.finish();
+
+            if self.libraries.contains_key(&key) {
+                self.stats.reused += 1;
+            } else {
+                let stages: Vec<vk::PipelineShaderStageCreateInfo> = group_shaders
+                    .iter()
+                    .map(|&shader| shader_stages[shader])
+                    .collect();
+                let group_info = group.create_info(|shader| {
+                    group_shaders.iter().position(|&s| s == shader).unwrap() as u32
+                });
+
+                let library = unsafe {
+                    device
+                        .ray_tracing_pipeline_ext
+                        .create_ray_tracing_pipelines(
+                            vk::DeferredOperationKHR::null(),
+                            vk::PipelineCache::null(),
+                            &[vk::RayTracingPipelineCreateInfoKHR::builder()
+                                .flags(vk::PipelineCreateFlags::LIBRARY_KHR)
+                                .stages(&stages)
+                                .groups(std::slice::from_ref(&group_info))
+                                .max_pipeline_ray_recursion_depth(
+                                    desc.max_pipeline_ray_recursion_depth,
+                                )
+                                .library_interface(&interface)
+                                .layout(pipeline_layout)
+                                .build()],
+                            None,
+                        )
+                }
+                .map_err(|err| anyhow::anyhow!("Creating a pipeline library: {:?}", err))?[0];
+
+                let main_shader = &shaders[group.main_shader()].desc;
+                device.track_resource(
+                    ResourceCategory::Pipeline,
+                    library,
+                    format!("{:?}:{:?} library", main_shader.stage, main_shader.entry),
+                    0,
+                );
+
+                self.stats.compiled += 1;
+                self.libraries.insert(key, library);
+            }
+
+            keys.push(key);
+        }
+
+        // Groups of the linked pipeline follow the order of the libraries.
+        let libraries: Vec<vk::Pipeline> = keys.iter().map(|key| self.libraries[key]).collect();
+        let library_info = vk::PipelineLibraryCreateInfoKHR::builder().libraries(&libraries);
+
+        let pipeline = unsafe {
+            device
+                .ray_tracing_pipeline_ext
+                .create_ray_tracing_pipelines(
+                    vk::DeferredOperationKHR::null(),
+                    vk::PipelineCache::null(),
+                    &[vk::RayTracingPipelineCreateInfoKHR::builder()
+                        .library_info(&library_info)
+                        .library_interface(&interface)
+                        .max_pipeline_ray_recursion_depth(desc.max_pipeline_ray_recursion_depth)
+                        .layout(pipeline_layout)
+                        .build()],
+                    None,
+                )
+        }
+        .map_err(|err| anyhow::anyhow!("Linking pipeline libraries: {:?}", err))?[0];
+
+        Ok((pipeline, keys))
+    }
+}
+
 pub struct RayTracingShaderTable {
-    pub raygen_shader_binding_table_buffer: Option<super::buffer::Buffer>,
+    pub raygen_shader_binding_table_buffer: Option<Arc<super::buffer::Buffer>>,
     pub raygen_shader_binding_table: vk::StridedDeviceAddressRegionKHR,
-    pub miss_shader_binding_table_buffer: Option<super::buffer::Buffer>,
+    pub miss_shader_binding_table_buffer: Option<Arc<super::buffer::Buffer>>,
     pub miss_shader_binding_table: vk::StridedDeviceAddressRegionKHR,
-    pub hit_shader_binding_table_buffer: Option<super::buffer::Buffer>,
+    pub hit_shader_binding_table_buffer: Option<Arc<super::buffer::Buffer>>,
     pub hit_shader_binding_table: vk::StridedDeviceAddressRegionKHR,
-    pub callable_shader_binding_table_buffer: Option<super::buffer::Buffer>,
+    pub callable_shader_binding_table_buffer: Option<Arc<super::buffer::Buffer>>,
     pub callable_shader_binding_table: vk::StridedDeviceAddressRegionKHR,
 }
 
+impl RayTracingShaderTable {
+    /// The buffers holding the tables, to be released along with the pipeline.
+    pub fn buffers(&self) -> impl Iterator<Item = &Arc<super::buffer::Buffer>> {
+        [
+            &self.raygen_shader_binding_table_buffer,
+            &self.miss_shader_binding_table_buffer,
+            &self.hit_shader_binding_table_buffer,
+            &self.callable_shader_binding_table_buffer,
+        ]
+        .into_iter()
+        .flatten()
+    }
+}
+
 #[repr(C)]
 #[derive(Clone, Debug, Copy)]
 struct GeometryInstance {
@@ -963,3 +1895,492 @@ impl GeometryInstance {
         self.instance_sbt_offset_and_flags |= flags << 24;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vulkan::shader::{PipelineShaderDesc, ShaderSource};
+    use rspirv::{
+        binary::Assemble,
+        spirv::{AddressingModel, Capability, ExecutionModel, FunctionControl, MemoryModel},
+    };
+
+    fn make_module(
+        version: (u8, u8),
+        capabilities: &[Capability],
+        model: ExecutionModel,
+        entry: &str,
+    ) -> Bytes {
+        let mut b = rspirv::dr::Builder::new();
+        b.set_version(version.0, version.1);
+        for &cap in capabilities {
+            b.capability(cap);
+        }
+        b.memory_model(AddressingModel::Logical, MemoryModel::GLSL450);
+
+        let void = b.type_void();
+        let fn_type = b.type_function(void, Vec::<u32>::new());
+        let func = b
+            .begin_function(void, None, FunctionControl::NONE, fn_type)
+            .unwrap();
+        b.begin_block(None).unwrap();
+        b.ret().unwrap();
+        b.end_function().unwrap();
+        b.entry_point(model, func, entry, Vec::<u32>::new());
+
+        b.module()
+            .assemble()
+            .into_iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect::<Vec<u8>>()
+            .into()
+    }
+
+    // A raygen shader which traces rays with the given miss index.
+    fn make_tracing_raygen(miss_index: u32) -> Bytes {
+        use rspirv::{
+            dr::{Instruction, Operand},
+            spirv::Op,
+        };
+
+        let mut b = rspirv::dr::Builder::new();
+        b.set_version(1, 5);
+        b.capability(Capability::Shader);
+        b.capability(Capability::RayTracingKHR);
+        b.memory_model(AddressingModel::Logical, MemoryModel::GLSL450);
+
+        let void = b.type_void();
+        let uint = b.type_int(32, 0);
+        let miss_index_id = b.id();
+        let fn_type = b.type_function(void, Vec::<u32>::new());
+        let func = b
+            .begin_function(void, None, FunctionControl::NONE, fn_type)
+            .unwrap();
+        b.begin_block(None).unwrap();
+        b.ret().unwrap();
+        b.end_function().unwrap();
+        b.entry_point(
+            ExecutionModel::RayGenerationKHR,
+            func,
+            "main",
+            Vec::<u32>::new(),
+        );
+
+        let mut module = b.module();
+        module.types_global_values.push(Instruction::new(
+            Op::Constant,
+            Some(uint),
+            Some(miss_index_id),
+            vec![Operand::LiteralInt32(miss_index)],
+        ));
+
+        // Only the miss index is inspected; the other operands are placeholders.
+        let trace_ray = Instruction::new(
+            Op::TraceRayKHR,
+            None,
+            None,
+            (0..11)
+                .map(|operand| Operand::IdRef(if operand == 5 { miss_index_id } else { uint }))
+                .collect(),
+        );
+        let instructions = &mut module.functions[0].blocks[0].instructions;
+        instructions.insert(instructions.len() - 1, trace_ray);
+
+        module
+            .assemble()
+            .into_iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect::<Vec<u8>>()
+            .into()
+    }
+
+    fn shader(
+        stage: ShaderPipelineStage,
+        source: ShaderSource,
+        code: Bytes,
+    ) -> PipelineShader<Bytes> {
+        PipelineShader {
+            code,
+            desc: PipelineShaderDesc {
+                stage,
+                descriptor_set_layout_flags: None,
+                push_constants_bytes: 0,
+                entry: source.entry().to_owned(),
+                source,
+                shader_record_data: Vec::new(),
+            },
+        }
+    }
+
+    #[test]
+    fn mixed_source_ray_tracing_pipeline() {
+        // DXC targets Vulkan 1.2, and Rust-GPU targets Vulkan 1.1 with named entry points.
+        let hlsl_raygen = || {
+            shader(
+                ShaderPipelineStage::RayGen,
+                ShaderSource::hlsl("/shaders/rt/reference_path_trace.rgen.hlsl"),
+                make_module(
+                    (1, 5),
+                    &[Capability::Shader, Capability::RayTracingKHR],
+                    ExecutionModel::RayGenerationKHR,
+                    "main",
+                ),
+            )
+        };
+        let rust_miss = || {
+            shader(
+                ShaderPipelineStage::RayMiss,
+                ShaderSource::rust("rt::miss"),
+                make_module(
+                    (1, 3),
+                    &[Capability::Shader, Capability::RayTracingKHR],
+                    ExecutionModel::MissKHR,
+                    "rt::miss",
+                ),
+            )
+        };
+        let rust_hit = |capabilities: &[Capability], version| {
+            shader(
+                ShaderPipelineStage::RayClosestHit,
+                ShaderSource::rust("rt::closest_hit"),
+                make_module(
+                    version,
+                    capabilities,
+                    ExecutionModel::ClosestHitKHR,
+                    "rt::closest_hit",
+                ),
+            )
+        };
+
+        validate_ray_tracing_shaders(&[
+            hlsl_raygen(),
+            rust_miss(),
+            rust_hit(&[Capability::Shader, Capability::RayTracingKHR], (1, 3)),
+        ])
+        .unwrap();
+
+        let err = validate_ray_tracing_shaders(&[
+            hlsl_raygen(),
+            rust_miss(),
+            rust_hit(&[Capability::Shader], (1, 3)),
+        ])
+        .unwrap_err();
+        assert!(err.to_string().contains("RayTracingKHR"), "{}", err);
+
+        let err = validate_ray_tracing_shaders(&[
+            hlsl_raygen(),
+            rust_miss(),
+            rust_hit(&[Capability::Shader, Capability::RayTracingKHR], (1, 6)),
+        ])
+        .unwrap_err();
+        assert!(err.to_string().contains("SPIR-V 1.6"), "{}", err);
+    }
+
+    #[test]
+    fn ray_recursion_depth_above_device_limit() {
+        // NVIDIA reports 31; the spec only guarantees 1.
+        validate_ray_recursion_depth(1, 31).unwrap();
+        validate_ray_recursion_depth(31, 31).unwrap();
+
+        let err = validate_ray_recursion_depth(32, 31).unwrap_err();
+        assert!(err.to_string().contains("at most 31"), "{}", err);
+    }
+
+    #[test]
+    fn sbt_layout_matches_traced_miss_indices() {
+        // Like `rt_is_shadowed` in `rt.hlsl`, which traces with miss index 1
+        let shadow_raygen = || {
+            shader(
+                ShaderPipelineStage::RayGen,
+                ShaderSource::hlsl("/shaders/rt/trace_sun_shadow_mask.rgen.hlsl"),
+                make_tracing_raygen(1),
+            )
+        };
+        let shadow_miss = || {
+            shader(
+                ShaderPipelineStage::RayMiss,
+                ShaderSource::hlsl("/shaders/rt/shadow.rmiss.hlsl"),
+                make_module(
+                    (1, 5),
+                    &[Capability::Shader, Capability::RayTracingKHR],
+                    ExecutionModel::MissKHR,
+                    "main",
+                ),
+            )
+        };
+
+        let layout =
+            ShaderBindingTableLayout::new(&[shadow_raygen(), shadow_miss(), shadow_miss()])
+                .unwrap();
+        assert_eq!(layout.traced_miss_indices, vec![1]);
+        assert_eq!(
+            layout
+                .miss
+                .iter()
+                .map(|record| (record.index, record.group_index))
+                .collect::<Vec<_>>(),
+            vec![(0, 1), (1, 2)]
+        );
+        layout.validate().unwrap();
+
+        let layout = ShaderBindingTableLayout::new(&[shadow_raygen(), shadow_miss()]).unwrap();
+        let err = layout.validate().unwrap_err();
+        assert!(
+            err.to_string().contains("miss index 1"),
+            "{}\n{}",
+            err,
+            layout
+        );
+    }
+
+    #[test]
+    fn intersection_shaders_start_procedural_hit_groups() {
+        use ShaderPipelineStage::*;
+
+        // Closest-hit and any-hit shaders join the procedural group before them.
+        let groups = ray_tracing_shader_groups([
+            RayGen,
+            RayMiss,
+            RayIntersection,
+            RayClosestHit,
+            RayIntersection,
+            RayClosestHit,
+            RayAnyHit,
+        ])
+        .unwrap();
+        assert_eq!(
+            groups[2..],
+            [
+                RayTracingShaderGroup::ProceduralHit {
+                    intersection: 2,
+                    closest_hit: Some(3),
+                    any_hit: None,
+                },
+                RayTracingShaderGroup::ProceduralHit {
+                    intersection: 4,
+                    closest_hit: Some(5),
+                    any_hit: Some(6),
+                },
+            ]
+        );
+        assert_eq!(groups[3].shaders(), vec![4, 5, 6]);
+        assert_eq!(groups[3].main_shader(), 4);
+
+        // Groups with only an intersection shader report hits, e.g. for shadow rays.
+        let groups = ray_tracing_shader_groups([
+            RayGen,
+            RayMiss,
+            RayIntersection,
+            RayIntersection,
+            RayAnyHit,
+        ])
+        .unwrap();
+        assert_eq!(
+            groups[2..],
+            [
+                RayTracingShaderGroup::ProceduralHit {
+                    intersection: 2,
+                    closest_hit: None,
+                    any_hit: None,
+                },
+                RayTracingShaderGroup::ProceduralHit {
+                    intersection: 3,
+                    closest_hit: None,
+                    any_hit: Some(4),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn any_hit_shaders_join_the_preceding_hit_group() {
+        use ShaderPipelineStage::*;
+
+        // Gbuffer rays with an alpha-testing any-hit, then shadow rays with only an any-hit
+        let groups = ray_tracing_shader_groups([
+            RayGen,
+            RayMiss,
+            RayMiss,
+            RayClosestHit,
+            RayAnyHit,
+            RayAnyHit,
+        ])
+        .unwrap();
+        assert_eq!(
+            groups,
+            vec![
+                RayTracingShaderGroup::General {
+                    stage: RayGen,
+                    shader: 0
+                },
+                RayTracingShaderGroup::General {
+                    stage: RayMiss,
+                    shader: 1
+                },
+                RayTracingShaderGroup::General {
+                    stage: RayMiss,
+                    shader: 2
+                },
+                RayTracingShaderGroup::TrianglesHit {
+                    closest_hit: Some(3),
+                    any_hit: Some(4)
+                },
+                RayTracingShaderGroup::TrianglesHit {
+                    closest_hit: None,
+                    any_hit: Some(5)
+                },
+            ]
+        );
+
+        let any_hit = || {
+            shader(
+                RayAnyHit,
+                ShaderSource::hlsl("/shaders/rt/shadow.rahit.hlsl"),
+                make_module(
+                    (1, 5),
+                    &[Capability::Shader, Capability::RayTracingKHR],
+                    ExecutionModel::AnyHitKHR,
+                    "main",
+                ),
+            )
+        };
+        validate_ray_tracing_shaders(&[any_hit()]).unwrap();
+
+        let raygen = shader(
+            RayGen,
+            ShaderSource::hlsl("/shaders/rt/trace_sun_shadow_mask.rgen.hlsl"),
+            make_tracing_raygen(0),
+        );
+        let miss = shader(
+            RayMiss,
+            ShaderSource::hlsl("/shaders/rt/shadow.rmiss.hlsl"),
+            make_module(
+                (1, 5),
+                &[Capability::Shader, Capability::RayTracingKHR],
+                ExecutionModel::MissKHR,
+                "main",
+            ),
+        );
+        let layout = ShaderBindingTableLayout::new(&[raygen, miss, any_hit(), any_hit()]).unwrap();
+        assert_eq!(
+            layout
+                .hit
+                .iter()
+                .map(|record| (record.index, record.group_index))
+                .collect::<Vec<_>>(),
+            vec![(0, 2), (1, 3)]
+        );
+
+        assert!(ray_tracing_shader_groups([RayGen, Vertex]).is_err());
+    }
+
+    #[test]
+    fn shader_record_data_follows_handles() {
+        const HANDLE_SIZE: usize = 32;
+
+        // Per-material constants for the second hit group, as read in a closest-hit shader by
+        // `[[vk::shader_record_ext]] ConstantBuffer<Material> material;`
+        let material: Vec<u8> = [0.8f32, 0.2, 0.1, 1.5]
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect();
+        let group_handles: Vec<u8> = (0..3)
+            .flat_map(|group| std::iter::repeat(group as u8 + 1).take(HANDLE_SIZE))
+            .collect();
+
+        let stride = shader_record_stride(HANDLE_SIZE, 32, material.len());
+        assert_eq!(stride, 64);
+        assert_eq!(shader_record_stride(HANDLE_SIZE, 32, 0), HANDLE_SIZE);
+
+        let table = write_shader_binding_table(
+            &group_handles,
+            HANDLE_SIZE,
+            stride,
+            [(1, &[][..]), (2, &material[..])].into_iter(),
+        );
+        assert_eq!(table.len(), 2 * stride);
+
+        let (first, second) = table.split_at(stride);
+        assert!(first[..HANDLE_SIZE].iter().all(|&b| b == 2));
+        assert!(first[HANDLE_SIZE..].iter().all(|&b| b == 0));
+        assert!(second[..HANDLE_SIZE].iter().all(|&b| b == 3));
+
+        let record_data = &second[HANDLE_SIZE..];
+        let read_f32 =
+            |idx: usize| f32::from_le_bytes(record_data[idx * 4..idx * 4 + 4].try_into().unwrap());
+        assert_eq!(
+            [read_f32(0), read_f32(1), read_f32(2), read_f32(3)],
+            [0.8, 0.2, 0.1, 1.5]
+        );
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device with ray tracing"]
+    fn linking_reuses_libraries_of_unchanged_groups() {
+        use crate::vulkan::{HeadlessRenderBackend, HeadlessRenderBackendConfig};
+
+        let backend = HeadlessRenderBackend::new(HeadlessRenderBackendConfig::default()).unwrap();
+        let device = &*backend.device;
+        assert!(device.ray_tracing_pipeline_libraries_supported());
+
+        let rt_caps = [Capability::Shader, Capability::RayTracingKHR];
+        let module = |model, entry: &str| make_module((1, 5), &rt_caps, model, entry);
+
+        // A raygen and a miss shader, then a hit group per material
+        let shaders = |material_count: usize| {
+            let mut shaders = vec![
+                shader(
+                    ShaderPipelineStage::RayGen,
+                    ShaderSource::rust("rt::raygen"),
+                    module(ExecutionModel::RayGenerationKHR, "rt::raygen"),
+                ),
+                shader(
+                    ShaderPipelineStage::RayMiss,
+                    ShaderSource::rust("rt::miss"),
+                    module(ExecutionModel::MissKHR, "rt::miss"),
+                ),
+            ];
+            shaders.extend((0..material_count).map(|material| {
+                let entry = format!("rt::material_{}", material);
+                shader(
+                    ShaderPipelineStage::RayClosestHit,
+                    ShaderSource::rust(entry.as_str()),
+                    module(ExecutionModel::ClosestHitKHR, &entry),
+                )
+            }));
+            shaders
+        };
+
+        let desc = RayTracingPipelineDesc::default();
+        let mut libraries = RayTracingPipelineLibraries::default();
+
+        let pipeline =
+            create_linked_ray_tracing_pipeline(device, &shaders(32), &desc, &mut libraries)
+                .unwrap();
+        assert_eq!(pipeline.library_keys.len(), 34);
+        assert_eq!(
+            libraries.stats(),
+            RayTracingPipelineLibraryStats {
+                compiled: 34,
+                reused: 0
+            }
+        );
+
+        // Adding a material only compiles its hit group
+        let pipeline =
+            create_linked_ray_tracing_pipeline(device, &shaders(33), &desc, &mut libraries)
+                .unwrap();
+
+        assert_eq!(
+            libraries.stats(),
+            RayTracingPipelineLibraryStats {
+                compiled: 35,
+                reused: 34
+            }
+        );
+        assert_eq!(pipeline.sbt_layout.hit.len(), 33);
+        assert!(libraries
+            .take_unused(&pipeline.library_keys.iter().copied().collect())
+            .is_empty());
+    }
+}
@@ -0,0 +1,408 @@
+use super::shader::{
+    merge_shader_stage_layouts, DescriptorSetLayoutOpts, StageDescriptorSetLayouts,
+};
+use crate::MAX_DESCRIPTOR_SETS;
+use anyhow::{anyhow, bail, ensure, Context as _};
+use rspirv::{
+    dr::{Instruction, Operand},
+    spirv::{Decoration, Op, StorageClass},
+};
+use std::collections::HashMap;
+
+/// Pipeline layout information derived from the SPIR-V of a shader, or of all stages of a pipeline.
+#[derive(Default, Debug)]
+pub struct ShaderReflection {
+    pub descriptor_sets: StageDescriptorSetLayouts,
+
+    /// Size of the push constant block, or zero if there isn't one.
+    pub push_constants_bytes: usize,
+}
+
+impl ShaderReflection {
+    pub fn new(spirv: &[u8]) -> anyhow::Result<Self> {
+        let descriptor_sets = rspirv_reflect::Reflection::new_from_spirv(spirv)
+            .and_then(|reflection| reflection.get_descriptor_sets())
+            .map_err(|err| anyhow!("Reflecting descriptor sets: {:?}", err))?;
+
+        let mut loader = rspirv::dr::Loader::new();
+        rspirv::binary::parse_bytes(spirv, &mut loader)
+            .map_err(|err| anyhow!("Parsing SPIR-V: {:?}", err))?;
+        let push_constants_bytes = push_constants_size(&loader.module())?;
+
+        Ok(Self {
+            descriptor_sets,
+            push_constants_bytes,
+        })
+    }
+
+    /// Combines the reflection of all stages of a pipeline.
+    pub fn merge(stages: Vec<ShaderReflection>) -> anyhow::Result<Self> {
+        let push_constants_bytes = stages
+            .iter()
+            .map(|stage| stage.push_constants_bytes)
+            .max()
+            .unwrap_or(0);
+
+        Ok(Self {
+            descriptor_sets: merge_shader_stage_layouts(
+                stages
+                    .into_iter()
+                    .map(|stage| stage.descriptor_sets)
+                    .collect(),
+            )?,
+            push_constants_bytes,
+        })
+    }
+
+    /// The push constant range size for a pipeline. Zero in the desc means "as reflected";
+    /// explicit sizes may be larger than what the shader declares, but not smaller.
+    pub fn resolve_push_constants_bytes(
+        &self,
+        desc_push_constants_bytes: usize,
+    ) -> anyhow::Result<usize> {
+        if desc_push_constants_bytes == 0 {
+            return Ok(self.push_constants_bytes);
+        }
+
+        ensure!(
+            desc_push_constants_bytes >= self.push_constants_bytes,
+            "The shader declares {} bytes of push constants, but the pipeline desc specifies {}",
+            self.push_constants_bytes,
+            desc_push_constants_bytes
+        );
+
+        Ok(desc_push_constants_bytes)
+    }
+
+    /// Finds a descriptor by the name of its shader variable, returning its set and binding indices.
+    pub fn find_binding(&self, name: &str) -> Option<(u32, u32, &rspirv_reflect::DescriptorInfo)> {
+        self.descriptor_sets.iter().find_map(|(set_idx, set)| {
+            set.iter()
+                .find(|(_, binding)| binding.name == name)
+                .map(|(binding_idx, binding)| (*set_idx, *binding_idx, binding))
+        })
+    }
+
+    /// Checks that descriptor set layouts replaced via `DescriptorSetLayoutOpts::replace`
+    /// provide all the bindings the shader uses, with compatible types.
+    pub fn validate_descriptor_set_opts(
+        &self,
+        set_opts: &[Option<(u32, DescriptorSetLayoutOpts)>; MAX_DESCRIPTOR_SETS],
+    ) -> anyhow::Result<()> {
+        for (set_idx, opts) in set_opts.iter().flatten() {
+            let (replacement, reflected) = match (&opts.replace, self.descriptor_sets.get(set_idx))
+            {
+                (Some(replacement), Some(reflected)) => (replacement, reflected),
+                _ => continue,
+            };
+
+            for (binding_idx, binding) in reflected {
+                let replaced = replacement.get(binding_idx).with_context(|| {
+                    format!(
+                        "Set {} binding {} ({:?}) is used by the shader, but missing from the layout override",
+                        set_idx, binding_idx, binding.name
+                    )
+                })?;
+
+                ensure!(
+                    descriptor_types_compatible(&replaced.ty, &binding.ty),
+                    "Set {} binding {} ({:?}) is {:?} in the shader, but {:?} in the layout override",
+                    set_idx,
+                    binding_idx,
+                    binding.name,
+                    binding.ty,
+                    replaced.ty
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Whether a buffer is bound with a dynamic offset is up to the pipeline layout.
+fn descriptor_types_compatible(
+    a: &rspirv_reflect::DescriptorType,
+    b: &rspirv_reflect::DescriptorType,
+) -> bool {
+    use rspirv_reflect::DescriptorType as Ty;
+
+    let is_uniform = |ty: &Ty| *ty == Ty::UNIFORM_BUFFER || *ty == Ty::UNIFORM_BUFFER_DYNAMIC;
+    let is_storage = |ty: &Ty| *ty == Ty::STORAGE_BUFFER || *ty == Ty::STORAGE_BUFFER_DYNAMIC;
+
+    a == b || (is_uniform(a) && is_uniform(b)) || (is_storage(a) && is_storage(b))
+}
+
+fn push_constants_size(module: &rspirv::dr::Module) -> anyhow::Result<usize> {
+    let types = SpirvTypes::new(module);
+
+    let push_constants_var = module.types_global_values.iter().find(|inst| {
+        inst.class.opcode == Op::Variable
+            && matches!(
+                inst.operands.first(),
+                Some(Operand::StorageClass(StorageClass::PushConstant))
+            )
+    });
+
+    let pointer_type = match push_constants_var.and_then(|var| var.result_type) {
+        Some(pointer_type) => pointer_type,
+        None => return Ok(0),
+    };
+
+    match types.get(pointer_type)?.operands.get(1) {
+        Some(Operand::IdRef(pointee)) => types.size_of(*pointee),
+        _ => bail!("Malformed push constant pointer type"),
+    }
+}
+
+struct SpirvTypes<'a> {
+    defs: HashMap<u32, &'a Instruction>,
+    member_offsets: HashMap<(u32, u32), u32>,
+    array_strides: HashMap<u32, u32>,
+}
+
+impl<'a> SpirvTypes<'a> {
+    fn new(module: &'a rspirv::dr::Module) -> Self {
+        let defs = module
+            .types_global_values
+            .iter()
+            .filter_map(|inst| Some((inst.result_id?, inst)))
+            .collect();
+
+        let mut member_offsets = HashMap::new();
+        let mut array_strides = HashMap::new();
+
+        for inst in &module.annotations {
+            match (inst.class.opcode, inst.operands.as_slice()) {
+                (
+                    Op::MemberDecorate,
+                    [Operand::IdRef(ty), Operand::LiteralInt32(member), Operand::Decoration(Decoration::Offset), Operand::LiteralInt32(offset)],
+                ) => {
+                    member_offsets.insert((*ty, *member), *offset);
+                }
+                (
+                    Op::Decorate,
+                    [Operand::IdRef(ty), Operand::Decoration(Decoration::ArrayStride), Operand::LiteralInt32(stride)],
+                ) => {
+                    array_strides.insert(*ty, *stride);
+                }
+                _ => (),
+            }
+        }
+
+        Self {
+            defs,
+            member_offsets,
+            array_strides,
+        }
+    }
+
+    fn get(&self, id: u32) -> anyhow::Result<&'a Instruction> {
+        self.defs
+            .get(&id)
+            .copied()
+            .with_context(|| format!("SPIR-V id {} not found", id))
+    }
+
+    fn literal(&self, inst: &Instruction, idx: usize) -> anyhow::Result<usize> {
+        match inst.operands.get(idx) {
+            Some(Operand::LiteralInt32(value)) => Ok(*value as usize),
+            _ => bail!("Expected a literal in {:?}", inst.class.opcode),
+        }
+    }
+
+    fn id_ref(&self, inst: &Instruction, idx: usize) -> anyhow::Result<u32> {
+        match inst.operands.get(idx) {
+            Some(Operand::IdRef(id)) => Ok(*id),
+            _ => bail!("Expected an id in {:?}", inst.class.opcode),
+        }
+    }
+
+    fn size_of(&self, ty: u32) -> anyhow::Result<usize> {
+        let inst = self.get(ty)?;
+
+        Ok(match inst.class.opcode {
+            Op::TypeBool => 4,
+            Op::TypeInt | Op::TypeFloat => self.literal(inst, 0)? / 8,
+            Op::TypeVector | Op::TypeMatrix => {
+                self.size_of(self.id_ref(inst, 0)?)? * self.literal(inst, 1)?
+            }
+            Op::TypeArray => {
+                let len = self.literal(self.get(self.id_ref(inst, 1)?)?, 0)?;
+                let stride = match self.array_strides.get(&ty) {
+                    Some(stride) => *stride as usize,
+                    None => self.size_of(self.id_ref(inst, 0)?)?,
+                };
+                len * stride
+            }
+            Op::TypeStruct => {
+                let mut size = 0;
+                for (member, _) in inst.operands.iter().enumerate() {
+                    let member_ty = self.id_ref(inst, member)?;
+                    let offset = match self.member_offsets.get(&(ty, member as u32)) {
+                        Some(offset) => *offset as usize,
+                        None => size,
+                    };
+                    size = size.max(offset + self.size_of(member_ty)?);
+                }
+                size
+            }
+            op => bail!("Unsupported type in push constants: {:?}", op),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rspirv::{
+        binary::Assemble,
+        spirv::{AddressingModel, Capability, ExecutionModel, FunctionControl, MemoryModel},
+    };
+
+    // The equivalent of:
+    //
+    // [[vk::binding(0)]] RWStructuredBuffer<float4> output_buf;
+    // [[vk::binding(0, 2)]] cbuffer frame_constants { float4 data; };
+    // struct Constants { float4 color; uint count; };
+    // [[vk::push_constant]] Constants push_constants;
+    fn make_module() -> Vec<u8> {
+        let mut b = rspirv::dr::Builder::new();
+        b.set_version(1, 3);
+        b.capability(Capability::Shader);
+        b.memory_model(AddressingModel::Logical, MemoryModel::GLSL450);
+
+        let float = b.type_float(32);
+        let uint = b.type_int(32, 0);
+        let float4 = b.type_vector(float, 4);
+
+        let push_constants_struct = b.type_struct([float4, uint]);
+        b.decorate(push_constants_struct, Decoration::Block, []);
+        b.member_decorate(
+            push_constants_struct,
+            0,
+            Decoration::Offset,
+            [Operand::LiteralInt32(0)],
+        );
+        b.member_decorate(
+            push_constants_struct,
+            1,
+            Decoration::Offset,
+            [Operand::LiteralInt32(16)],
+        );
+        let push_constants_ptr =
+            b.type_pointer(None, StorageClass::PushConstant, push_constants_struct);
+        b.variable(push_constants_ptr, None, StorageClass::PushConstant, None);
+
+        let runtime_array = b.type_runtime_array(float4);
+        b.decorate(
+            runtime_array,
+            Decoration::ArrayStride,
+            [Operand::LiteralInt32(16)],
+        );
+        let storage_struct = b.type_struct([runtime_array]);
+        b.decorate(storage_struct, Decoration::Block, []);
+        b.member_decorate(
+            storage_struct,
+            0,
+            Decoration::Offset,
+            [Operand::LiteralInt32(0)],
+        );
+        let storage_ptr = b.type_pointer(None, StorageClass::StorageBuffer, storage_struct);
+        let output_buf = b.variable(storage_ptr, None, StorageClass::StorageBuffer, None);
+        b.name(output_buf, "output_buf");
+        b.decorate(
+            output_buf,
+            Decoration::DescriptorSet,
+            [Operand::LiteralInt32(0)],
+        );
+        b.decorate(output_buf, Decoration::Binding, [Operand::LiteralInt32(0)]);
+
+        let uniform_struct = b.type_struct([float4]);
+        b.decorate(uniform_struct, Decoration::Block, []);
+        b.member_decorate(
+            uniform_struct,
+            0,
+            Decoration::Offset,
+            [Operand::LiteralInt32(0)],
+        );
+        let uniform_ptr = b.type_pointer(None, StorageClass::Uniform, uniform_struct);
+        let frame_constants = b.variable(uniform_ptr, None, StorageClass::Uniform, None);
+        b.name(frame_constants, "frame_constants");
+        b.decorate(
+            frame_constants,
+            Decoration::DescriptorSet,
+            [Operand::LiteralInt32(2)],
+        );
+        b.decorate(
+            frame_constants,
+            Decoration::Binding,
+            [Operand::LiteralInt32(0)],
+        );
+
+        let void = b.type_void();
+        let fn_type = b.type_function(void, Vec::<u32>::new());
+        let func = b
+            .begin_function(void, None, FunctionControl::NONE, fn_type)
+            .unwrap();
+        b.begin_block(None).unwrap();
+        b.ret().unwrap();
+        b.end_function().unwrap();
+        b.entry_point(ExecutionModel::GLCompute, func, "main", Vec::<u32>::new());
+
+        b.module()
+            .assemble()
+            .into_iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn reflect_bindings_and_push_constants() {
+        let reflection = ShaderReflection::new(&make_module()).unwrap();
+
+        assert_eq!(reflection.push_constants_bytes, 20);
+
+        let output_buf = &reflection.descriptor_sets[&0][&0];
+        assert_eq!(
+            output_buf.ty,
+            rspirv_reflect::DescriptorType::STORAGE_BUFFER
+        );
+
+        let frame_constants = &reflection.descriptor_sets[&2][&0];
+        assert_eq!(
+            frame_constants.ty,
+            rspirv_reflect::DescriptorType::UNIFORM_BUFFER
+        );
+
+        assert_eq!(reflection.resolve_push_constants_bytes(0).unwrap(), 20);
+        assert_eq!(reflection.resolve_push_constants_bytes(32).unwrap(), 32);
+        assert!(reflection.resolve_push_constants_bytes(16).is_err());
+
+        // The frame constants override in `kajiya-rg` uses a dynamic uniform buffer
+        let mut set_opts: [Option<(u32, DescriptorSetLayoutOpts)>; MAX_DESCRIPTOR_SETS] =
+            Default::default();
+        set_opts[2] = Some((
+            2,
+            DescriptorSetLayoutOpts {
+                flags: None,
+                replace: Some(
+                    [(
+                        0,
+                        rspirv_reflect::DescriptorInfo {
+                            ty: rspirv_reflect::DescriptorType::UNIFORM_BUFFER_DYNAMIC,
+                            dimensionality: rspirv_reflect::DescriptorDimensionality::Single,
+                            name: Default::default(),
+                        },
+                    )]
+                    .into_iter()
+                    .collect(),
+                ),
+            },
+        ));
+        reflection.validate_descriptor_set_opts(&set_opts).unwrap();
+
+        set_opts[2].as_mut().unwrap().1.replace = Some(Default::default());
+        assert!(reflection.validate_descriptor_set_opts(&set_opts).is_err());
+    }
+}
@@ -3,8 +3,14 @@
 use super::{
     device::{Device, SamplerDesc},
     image::ImageDesc,
+    reflection::ShaderReflection,
 };
-use crate::{chunky_list::TempList, shader_compiler::get_cs_local_size_from_spirv};
+use crate::{
+    chunky_list::TempList,
+    shader_compiler::get_cs_local_size_from_spirv,
+    shader_variants::{ShaderFeatures, ShaderVariant},
+};
+use anyhow::Context as _;
 use arrayvec::ArrayVec;
 use ash::vk;
 use byte_slice_cast::AsSliceOf as _;
@@ -20,8 +26,8 @@ use std::{
 
 pub const MAX_DESCRIPTOR_SETS: usize = 4;
 
-type DescriptorSetLayout = HashMap<u32, rspirv_reflect::DescriptorInfo>;
-type StageDescriptorSetLayouts = HashMap<u32, DescriptorSetLayout>;
+pub type DescriptorSetLayout = HashMap<u32, rspirv_reflect::DescriptorInfo>;
+pub type StageDescriptorSetLayouts = HashMap<u32, DescriptorSetLayout>;
 
 pub struct ShaderPipelineCommon {
     pub pipeline_layout: vk::PipelineLayout,
@@ -30,6 +36,12 @@ pub struct ShaderPipelineCommon {
     pub descriptor_pool_sizes: Vec<vk::DescriptorPoolSize>,
     pub descriptor_set_layouts: Vec<vk::DescriptorSetLayout>,
     pub pipeline_bind_point: vk::PipelineBindPoint,
+
+    /// Size of the push constant range in the pipeline layout.
+    pub push_constants_bytes: usize,
+
+    /// Layout information reflected from the shaders, before any overrides.
+    pub reflection: ShaderReflection,
 }
 pub struct ComputePipeline {
     pub common: ShaderPipelineCommon,
@@ -326,8 +338,14 @@ impl DescriptorSetLayoutOpts {
 
 #[derive(Clone, Hash, PartialEq, Eq, Debug)]
 pub enum ShaderSource {
-    Rust { entry: String },
-    Hlsl { path: PathBuf },
+    Rust {
+        entry: String,
+    },
+    Hlsl {
+        path: PathBuf,
+        /// Passed to the compiler, sorted by name. See `ShaderSource::hlsl_variant`.
+        defines: Vec<(String, String)>,
+    },
 }
 
 impl ShaderSource {
@@ -338,7 +356,24 @@ impl ShaderSource {
     }
 
     pub fn hlsl(path: impl Into<PathBuf>) -> Self {
-        ShaderSource::Hlsl { path: path.into() }
+        ShaderSource::Hlsl {
+            path: path.into(),
+            defines: Vec::new(),
+        }
+    }
+
+    /// The HLSL shader at `path`, compiled with the defines of `variant` of its `features`.
+    pub fn hlsl_variant(
+        path: impl Into<PathBuf>,
+        features: &ShaderFeatures,
+        variant: &ShaderVariant,
+    ) -> anyhow::Result<Self> {
+        let path = path.into();
+        let defines = features
+            .defines(variant)
+            .with_context(|| format!("Requesting a variant of {:?}", path))?;
+
+        Ok(ShaderSource::Hlsl { path, defines })
     }
 
     pub fn entry(&self) -> &str {
@@ -392,13 +427,15 @@ pub fn create_compute_pipeline(
     device: &Device,
     spirv: &[u8],
     desc: &ComputePipelineDesc,
-) -> ComputePipeline {
+) -> anyhow::Result<ComputePipeline> {
+    let reflection = ShaderReflection::new(spirv)?;
+    reflection.validate_descriptor_set_opts(&desc.descriptor_set_opts)?;
+    let push_constants_bytes =
+        reflection.resolve_push_constants_bytes(desc.push_constants_bytes)?;
+
     let (descriptor_set_layouts, set_layout_info) = super::shader::create_descriptor_set_layouts(
         device,
-        &rspirv_reflect::Reflection::new_from_spirv(spirv)
-            .unwrap()
-            .get_descriptor_sets()
-            .unwrap(),
+        &reflection.descriptor_sets,
         vk::ShaderStageFlags::COMPUTE,
         &desc.descriptor_set_opts,
     );
@@ -411,10 +448,10 @@ pub fn create_compute_pipeline(
     let push_constant_ranges = vk::PushConstantRange {
         stage_flags: vk::ShaderStageFlags::COMPUTE,
         offset: 0,
-        size: desc.push_constants_bytes as _,
+        size: push_constants_bytes as _,
     };
 
-    if desc.push_constants_bytes > 0 {
+    if push_constants_bytes > 0 {
         layout_create_info =
             layout_create_info.push_constant_ranges(std::slice::from_ref(&push_constant_ranges));
     }
@@ -464,7 +501,7 @@ pub fn create_compute_pipeline(
             }
         }
 
-        ComputePipeline {
+        Ok(ComputePipeline {
             common: ShaderPipelineCommon {
                 pipeline_layout,
                 pipeline,
@@ -472,9 +509,11 @@ pub fn create_compute_pipeline(
                 descriptor_pool_sizes,
                 descriptor_set_layouts,
                 pipeline_bind_point: vk::PipelineBindPoint::COMPUTE,
+                push_constants_bytes,
+                reflection,
             },
-            group_size: get_cs_local_size_from_spirv(spirv.as_slice_of::<u32>().unwrap()).unwrap(),
-        }
+            group_size: get_cs_local_size_from_spirv(spirv.as_slice_of::<u32>().unwrap())?,
+        })
     }
 }
 
@@ -485,6 +524,13 @@ pub enum ShaderPipelineStage {
     RayGen,
     RayMiss,
     RayClosestHit,
+
+    /// Joins the hit group of the closest-hit shader right before it, or forms a group on its own.
+    RayAnyHit,
+
+    /// Starts a procedural hit group, for AABB geometry. The closest-hit and any-hit shaders
+    /// right after it join the group.
+    RayIntersection,
 }
 
 #[derive(Builder, Hash, PartialEq, Eq, Clone, Debug)]
@@ -498,6 +544,12 @@ pub struct PipelineShaderDesc {
     #[builder(default = "\"main\".to_owned()")]
     pub entry: String,
     pub source: ShaderSource,
+
+    /// For ray tracing shaders: data written after the shader group handle in its shader
+    /// binding table record, e.g. per-material constants for a hit group. Shaders access it
+    /// via `[[vk::shader_record_ext]] ConstantBuffer<T>` in HLSL.
+    #[builder(default)]
+    pub shader_record_data: Vec<u8>,
 }
 
 impl PipelineShaderDesc {
@@ -513,7 +565,10 @@ impl PipelineShaderDescBuilder {
         self
     }
 
+    /// Also sets the `entry`, since Rust-GPU modules name entry points after their functions.
     pub fn rust_source(mut self, entry: impl Into<String>) -> Self {
+        let entry = entry.into();
+        self.entry = Some(entry.clone());
         self.source = Some(ShaderSource::rust(entry));
 
         self
@@ -528,8 +583,25 @@ pub struct RasterPipelineDesc {
     pub render_pass: Arc<RenderPass>,
     #[builder(default)]
     pub face_cull: bool,
+    #[builder(default = "true")]
+    pub depth_test: bool,
+    #[builder(default = "true")]
+    pub depth_write: bool,
+    #[builder(default)]
+    pub blend_mode: RasterBlendMode,
     #[builder(default)]
     pub push_constants_bytes: usize,
+    /// Draws the edges of triangles instead of filling them.
+    /// Requires `Device::fill_mode_non_solid_supported`.
+    #[builder(default)]
+    pub wireframe: bool,
+    /// Constant and slope-scaled depth bias, disabled if both are zero.
+    /// Positive values move towards the camera, as depth is reversed.
+    #[builder(default)]
+    pub depth_bias: [f32; 2],
+    /// Every pair of vertices forms a line, instead of every three a triangle.
+    #[builder(default)]
+    pub line_list: bool,
 }
 
 impl RasterPipelineDesc {
@@ -538,6 +610,62 @@ impl RasterPipelineDesc {
     }
 }
 
+/// How the output of the pixel shader is combined with the contents of the color attachments.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RasterBlendMode {
+    /// The output replaces the attachment contents.
+    Opaque,
+
+    /// `src + dst * (1 - src.a)`; the color output must be multiplied by its alpha.
+    PremultipliedAlpha,
+
+    /// `src + dst`, for all channels; used for accumulation.
+    Additive,
+}
+
+impl Default for RasterBlendMode {
+    fn default() -> Self {
+        Self::Opaque
+    }
+}
+
+impl RasterBlendMode {
+    fn to_vk(self) -> vk::PipelineColorBlendAttachmentState {
+        match self {
+            RasterBlendMode::Opaque => vk::PipelineColorBlendAttachmentState {
+                blend_enable: 0,
+                src_color_blend_factor: vk::BlendFactor::SRC_COLOR,
+                dst_color_blend_factor: vk::BlendFactor::ONE_MINUS_DST_COLOR,
+                color_blend_op: vk::BlendOp::ADD,
+                src_alpha_blend_factor: vk::BlendFactor::ZERO,
+                dst_alpha_blend_factor: vk::BlendFactor::ZERO,
+                alpha_blend_op: vk::BlendOp::ADD,
+                color_write_mask: vk::ColorComponentFlags::all(),
+            },
+            RasterBlendMode::PremultipliedAlpha => vk::PipelineColorBlendAttachmentState {
+                blend_enable: 1,
+                src_color_blend_factor: vk::BlendFactor::ONE,
+                dst_color_blend_factor: vk::BlendFactor::ONE_MINUS_SRC_ALPHA,
+                color_blend_op: vk::BlendOp::ADD,
+                src_alpha_blend_factor: vk::BlendFactor::ONE,
+                dst_alpha_blend_factor: vk::BlendFactor::ONE_MINUS_SRC_ALPHA,
+                alpha_blend_op: vk::BlendOp::ADD,
+                color_write_mask: vk::ColorComponentFlags::all(),
+            },
+            RasterBlendMode::Additive => vk::PipelineColorBlendAttachmentState {
+                blend_enable: 1,
+                src_color_blend_factor: vk::BlendFactor::ONE,
+                dst_color_blend_factor: vk::BlendFactor::ONE,
+                color_blend_op: vk::BlendOp::ADD,
+                src_alpha_blend_factor: vk::BlendFactor::ONE,
+                dst_alpha_blend_factor: vk::BlendFactor::ONE,
+                alpha_blend_op: vk::BlendOp::ADD,
+                color_write_mask: vk::ColorComponentFlags::all(),
+            },
+        }
+    }
+}
+
 /*pub struct RasterPipeline {
     pub pipeline_layout: vk::PipelineLayout,
     pub pipeline: vk::Pipeline,
@@ -553,6 +681,7 @@ pub struct RenderPassAttachmentDesc {
     pub load_op: vk::AttachmentLoadOp,
     pub store_op: vk::AttachmentStoreOp,
     pub samples: vk::SampleCountFlags,
+    pub read_only: bool,
 }
 
 impl RenderPassAttachmentDesc {
@@ -562,9 +691,17 @@ impl RenderPassAttachmentDesc {
             load_op: vk::AttachmentLoadOp::LOAD,
             store_op: vk::AttachmentStoreOp::STORE,
             samples: vk::SampleCountFlags::TYPE_1,
+            read_only: false,
         }
     }
 
+    /// For depth attachments which are tested against, but not written. The image must then
+    /// be accessed with `AccessType::DepthStencilAttachmentRead`.
+    pub fn read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
     pub fn garbage_input(mut self) -> Self {
         self.load_op = vk::AttachmentLoadOp::DONT_CARE;
         self
@@ -720,6 +857,13 @@ pub struct RenderPass {
 }
 
 pub fn create_render_pass(device: &Device, desc: RenderPassDesc<'_>) -> Arc<RenderPass> {
+    let depth_layout = match desc.depth_attachment {
+        Some(RenderPassAttachmentDesc {
+            read_only: true, ..
+        }) => vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL,
+        _ => vk::ImageLayout::DEPTH_ATTACHMENT_STENCIL_READ_ONLY_OPTIMAL,
+    };
+
     let renderpass_attachments = desc
         .color_attachments
         .iter()
@@ -729,12 +873,11 @@ pub fn create_render_pass(device: &Device, desc: RenderPassDesc<'_>) -> Arc<Rend
                 vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
             )
         })
-        .chain(desc.depth_attachment.as_ref().map(|a| {
-            a.to_vk(
-                vk::ImageLayout::DEPTH_ATTACHMENT_STENCIL_READ_ONLY_OPTIMAL,
-                vk::ImageLayout::DEPTH_ATTACHMENT_STENCIL_READ_ONLY_OPTIMAL,
-            )
-        }))
+        .chain(
+            desc.depth_attachment
+                .as_ref()
+                .map(|a| a.to_vk(depth_layout, depth_layout)),
+        )
         .collect::<Vec<_>>();
 
     let color_attachment_refs = (0..desc.color_attachments.len() as u32)
@@ -746,7 +889,7 @@ pub fn create_render_pass(device: &Device, desc: RenderPassDesc<'_>) -> Arc<Rend
 
     let depth_attachment_ref = vk::AttachmentReference {
         attachment: desc.color_attachments.len() as u32,
-        layout: vk::ImageLayout::DEPTH_ATTACHMENT_STENCIL_READ_ONLY_OPTIMAL,
+        layout: depth_layout,
     };
 
     // TODO: Calculate optimal dependencies. using implicit dependencies for now.
@@ -827,19 +970,19 @@ pub fn create_raster_pipeline(
     shaders: &[PipelineShader<Bytes>],
     desc: &RasterPipelineDesc,
 ) -> anyhow::Result<RasterPipeline> {
-    let stage_layouts = shaders
-        .iter()
-        .map(|shader| {
-            rspirv_reflect::Reflection::new_from_spirv(&shader.code)
-                .unwrap()
-                .get_descriptor_sets()
-                .unwrap()
-        })
-        .collect::<Vec<_>>();
+    let reflection = ShaderReflection::merge(
+        shaders
+            .iter()
+            .map(|shader| ShaderReflection::new(&shader.code))
+            .collect::<anyhow::Result<Vec<_>>>()?,
+    )?;
+    reflection.validate_descriptor_set_opts(&desc.descriptor_set_opts)?;
+    let push_constants_bytes =
+        reflection.resolve_push_constants_bytes(desc.push_constants_bytes)?;
 
     let (descriptor_set_layouts, set_layout_info) = super::shader::create_descriptor_set_layouts(
         device,
-        &merge_shader_stage_layouts(stage_layouts),
+        &reflection.descriptor_sets,
         vk::ShaderStageFlags::ALL_GRAPHICS,
         //desc.descriptor_set_layout_flags.unwrap_or(&[]),  // TODO: merge flags
         &desc.descriptor_set_opts,
@@ -852,10 +995,10 @@ pub fn create_raster_pipeline(
         let push_constant_ranges = vk::PushConstantRange {
             stage_flags: vk::ShaderStageFlags::ALL_GRAPHICS,
             offset: 0,
-            size: desc.push_constants_bytes as _,
+            size: push_constants_bytes as _,
         };
 
-        if desc.push_constants_bytes > 0 {
+        if push_constants_bytes > 0 {
             layout_create_info = layout_create_info
                 .push_constant_ranges(std::slice::from_ref(&push_constant_ranges));
         }
@@ -899,7 +1042,11 @@ pub fn create_raster_pipeline(
             ..Default::default()
         };
         let vertex_input_assembly_state_info = vk::PipelineInputAssemblyStateCreateInfo {
-            topology: vk::PrimitiveTopology::TRIANGLE_LIST,
+            topology: if desc.line_list {
+                vk::PrimitiveTopology::LINE_LIST
+            } else {
+                vk::PrimitiveTopology::TRIANGLE_LIST
+            },
             ..Default::default()
         };
 
@@ -910,12 +1057,19 @@ pub fn create_raster_pipeline(
         let rasterization_info = vk::PipelineRasterizationStateCreateInfo {
             front_face: vk::FrontFace::COUNTER_CLOCKWISE,
             line_width: 1.0,
-            polygon_mode: vk::PolygonMode::FILL,
+            polygon_mode: if desc.wireframe {
+                vk::PolygonMode::LINE
+            } else {
+                vk::PolygonMode::FILL
+            },
             cull_mode: if desc.face_cull {
                 ash::vk::CullModeFlags::BACK
             } else {
                 ash::vk::CullModeFlags::NONE
             },
+            depth_bias_enable: (desc.depth_bias != [0.0, 0.0]) as u32,
+            depth_bias_constant_factor: desc.depth_bias[0],
+            depth_bias_slope_factor: desc.depth_bias[1],
             ..Default::default()
         };
         let multisample_state_info = vk::PipelineMultisampleStateCreateInfo {
@@ -930,8 +1084,8 @@ pub fn create_raster_pipeline(
             ..Default::default()
         };
         let depth_state_info = vk::PipelineDepthStencilStateCreateInfo {
-            depth_test_enable: 1,
-            depth_write_enable: 1,
+            depth_test_enable: desc.depth_test as u32,
+            depth_write_enable: desc.depth_write as u32,
             depth_compare_op: vk::CompareOp::GREATER_OR_EQUAL,
             front: noop_stencil_state,
             back: noop_stencil_state,
@@ -941,19 +1095,7 @@ pub fn create_raster_pipeline(
 
         let color_attachment_count = desc.render_pass.framebuffer_cache.color_attachment_count;
 
-        let color_blend_attachment_states = vec![
-            vk::PipelineColorBlendAttachmentState {
-                blend_enable: 0,
-                src_color_blend_factor: vk::BlendFactor::SRC_COLOR,
-                dst_color_blend_factor: vk::BlendFactor::ONE_MINUS_DST_COLOR,
-                color_blend_op: vk::BlendOp::ADD,
-                src_alpha_blend_factor: vk::BlendFactor::ZERO,
-                dst_alpha_blend_factor: vk::BlendFactor::ZERO,
-                alpha_blend_op: vk::BlendOp::ADD,
-                color_write_mask: vk::ColorComponentFlags::all(),
-            };
-            color_attachment_count
-        ];
+        let color_blend_attachment_states = vec![desc.blend_mode.to_vk(); color_attachment_count];
         let color_blend_state = vk::PipelineColorBlendStateCreateInfo::builder()
             .attachments(&color_blend_attachment_states);
 
@@ -1007,15 +1149,19 @@ pub fn create_raster_pipeline(
                 descriptor_pool_sizes,
                 descriptor_set_layouts,
                 pipeline_bind_point: vk::PipelineBindPoint::GRAPHICS,
+                push_constants_bytes,
+                reflection,
             },
         })
     }
 }
 
+// Stages may come from different shader languages (e.g. HLSL and Rust-GPU),
+// so disagreements are reported as errors rather than assumed to be bugs.
 fn merge_shader_stage_layout_pair(
     src: StageDescriptorSetLayouts,
     dst: &mut StageDescriptorSetLayouts,
-) {
+) -> anyhow::Result<()> {
     for (set_idx, set) in src.into_iter() {
         match dst.entry(set_idx) {
             Entry::Occupied(mut existing) => {
@@ -1024,15 +1170,22 @@ fn merge_shader_stage_layout_pair(
                     match existing.entry(binding_idx) {
                         Entry::Occupied(existing) => {
                             let existing = existing.get();
-                            assert_eq!(
-                                existing.ty, binding.ty,
-                                "binding idx: {}, name: {:?}",
-                                binding_idx, binding.name
+                            anyhow::ensure!(
+                                existing.ty == binding.ty,
+                                "Shader stages disagree on the type of set {} binding {} ({:?}): {:?} vs {:?}",
+                                set_idx,
+                                binding_idx,
+                                binding.name,
+                                existing.ty,
+                                binding.ty
                             );
-                            assert_eq!(
-                                existing.name, binding.name,
-                                "binding idx: {}, name: {:?}",
-                                binding_idx, binding.name
+                            anyhow::ensure!(
+                                existing.name == binding.name,
+                                "Shader stages disagree on the name of set {} binding {}: {:?} vs {:?}",
+                                set_idx,
+                                binding_idx,
+                                existing.name,
+                                binding.name
                             );
                         }
                         Entry::Vacant(vacant) => {
@@ -1046,17 +1199,19 @@ fn merge_shader_stage_layout_pair(
             }
         }
     }
+
+    Ok(())
 }
 
 pub(crate) fn merge_shader_stage_layouts(
     stages: Vec<StageDescriptorSetLayouts>,
-) -> StageDescriptorSetLayouts {
+) -> anyhow::Result<StageDescriptorSetLayouts> {
     let mut stages = stages.into_iter();
     let mut result = stages.next().unwrap_or_default();
 
     for stage in stages {
-        merge_shader_stage_layout_pair(stage, &mut result);
+        merge_shader_stage_layout_pair(stage, &mut result)?;
     }
 
-    result
+    Ok(result)
 }
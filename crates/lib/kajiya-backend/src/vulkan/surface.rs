@@ -8,6 +8,7 @@ pub struct Surface {
 }
 
 impl Surface {
+    #[cfg(feature = "window")]
     pub fn create(
         instance: &super::instance::Instance,
         window: &impl raw_window_handle::HasRawWindowHandle,
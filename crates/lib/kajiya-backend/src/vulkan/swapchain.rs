@@ -1,21 +1,108 @@
 use super::{device::Device, surface::Surface};
+use crate::KajiyaError;
 use anyhow::Result;
 use ash::{extensions::khr, vk};
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
 use std::sync::Arc;
 
+/// Preferred presentation mode. If the surface doesn't support it, the closest
+/// supported mode is used instead; see `Swapchain::present_mode` for the actual one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PresentMode {
+    /// VSync. Never tears, and is always supported.
+    Fifo,
+
+    /// VSync, but tears instead of stalling when a frame misses the vertical blank.
+    FifoRelaxed,
+
+    /// Never tears, but doesn't cap the frame rate; queued frames are replaced with newer ones.
+    Mailbox,
+
+    /// No VSync. Lowest latency, but tears. Good for uncapped benchmarking.
+    Immediate,
+}
+
+impl Default for PresentMode {
+    fn default() -> Self {
+        Self::FifoRelaxed
+    }
+}
+
+impl PresentMode {
+    // Candidates in order of preference. FIFO is required by the spec, so it always terminates.
+    fn fallback_chain(self) -> &'static [vk::PresentModeKHR] {
+        match self {
+            PresentMode::Fifo => &[vk::PresentModeKHR::FIFO],
+            PresentMode::FifoRelaxed => {
+                &[vk::PresentModeKHR::FIFO_RELAXED, vk::PresentModeKHR::FIFO]
+            }
+            PresentMode::Mailbox => &[
+                vk::PresentModeKHR::MAILBOX,
+                vk::PresentModeKHR::IMMEDIATE,
+                vk::PresentModeKHR::FIFO,
+            ],
+            PresentMode::Immediate => &[
+                vk::PresentModeKHR::IMMEDIATE,
+                vk::PresentModeKHR::MAILBOX,
+                vk::PresentModeKHR::FIFO,
+            ],
+        }
+    }
+
+    fn from_vk(mode: vk::PresentModeKHR) -> Option<Self> {
+        match mode {
+            vk::PresentModeKHR::FIFO => Some(PresentMode::Fifo),
+            vk::PresentModeKHR::FIFO_RELAXED => Some(PresentMode::FifoRelaxed),
+            vk::PresentModeKHR::MAILBOX => Some(PresentMode::Mailbox),
+            vk::PresentModeKHR::IMMEDIATE => Some(PresentMode::Immediate),
+            _ => None,
+        }
+    }
+}
+
+/// How the swapchain images are to be encoded for the display.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SwapchainColorSpace {
+    /// sRGB transfer function, Rec.709 primaries.
+    Sdr = 0,
+
+    /// ST.2084 (PQ) transfer function, Rec.2020 primaries, absolute luminance.
+    Hdr10 = 1,
+
+    /// Linear Rec.709 in floating point, where 1.0 corresponds to 80 nits.
+    ScRgb = 2,
+}
+
+impl SwapchainColorSpace {
+    pub fn from_surface_format(format: vk::SurfaceFormatKHR) -> Self {
+        match format.color_space {
+            vk::ColorSpaceKHR::HDR10_ST2084_EXT => Self::Hdr10,
+            vk::ColorSpaceKHR::EXTENDED_SRGB_LINEAR_EXT => Self::ScRgb,
+            _ => Self::Sdr,
+        }
+    }
+
+    pub fn is_hdr(self) -> bool {
+        self != Self::Sdr
+    }
+}
+
 #[derive(Clone, Copy, Default)]
 pub struct SwapchainDesc {
     pub format: vk::SurfaceFormatKHR,
     pub dims: vk::Extent2D,
-    pub vsync: bool,
+    pub present_mode: PresentMode,
 }
 
 pub struct Swapchain {
     pub(crate) fns: khr::Swapchain,
     pub(crate) raw: vk::SwapchainKHR,
     pub desc: SwapchainDesc,
+
+    // The mode actually in use, after falling back from `desc.present_mode`
+    present_mode: PresentMode,
+
     pub images: Vec<Arc<crate::Image>>,
     pub acquire_semaphores: Vec<vk::Semaphore>,
 
@@ -41,6 +128,7 @@ pub struct SwapchainImage {
 
 pub enum SwapchainAcquireImageErr {
     RecreateFramebuffer,
+    DeviceLost,
 }
 
 impl Swapchain {
@@ -56,6 +144,39 @@ impl Swapchain {
     }
 
     pub fn new(device: &Arc<Device>, surface: &Arc<Surface>, desc: SwapchainDesc) -> Result<Self> {
+        Self::create(device, surface, desc, vk::SwapchainKHR::null())
+    }
+
+    /// Re-creates the swapchain with a new `desc`, e.g. to change the presentation mode.
+    /// Waits for the GPU to go idle, so that none of the old images are still in flight.
+    pub fn recreate(&mut self, desc: SwapchainDesc) -> Result<()> {
+        self.device.wait_idle()?;
+
+        let device = self.device.clone();
+        let surface = self.surface.clone();
+        let new_swapchain = Self::create(&device, &surface, desc, self.raw)?;
+        let old_swapchain = std::mem::replace(self, new_swapchain);
+
+        // Nothing is in flight anymore, so the old semaphores can go.
+        unsafe {
+            for semaphore in old_swapchain
+                .acquire_semaphores
+                .iter()
+                .chain(old_swapchain.rendering_finished_semaphores.iter())
+            {
+                device.raw.destroy_semaphore(*semaphore, None);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn create(
+        device: &Arc<Device>,
+        surface: &Arc<Surface>,
+        desc: SwapchainDesc,
+        old_swapchain: vk::SwapchainKHR,
+    ) -> Result<Self> {
         let surface_capabilities = unsafe {
             surface
                 .fns
@@ -82,10 +203,10 @@ impl Swapchain {
             anyhow::bail!("Swapchain resolution cannot be zero");
         }
 
-        let present_mode_preference = if desc.vsync {
-            vec![vk::PresentModeKHR::FIFO_RELAXED, vk::PresentModeKHR::FIFO]
-        } else {
-            vec![vk::PresentModeKHR::MAILBOX, vk::PresentModeKHR::IMMEDIATE]
+        // The surface dictates the extent on most platforms; keep track of the real one.
+        let desc = SwapchainDesc {
+            dims: surface_resolution,
+            ..desc
         };
 
         let present_modes = unsafe {
@@ -94,10 +215,22 @@ impl Swapchain {
                 .get_physical_device_surface_present_modes(device.pdevice.raw, surface.raw)
         }?;
 
-        let present_mode = present_mode_preference
-            .into_iter()
+        let vk_present_mode = desc
+            .present_mode
+            .fallback_chain()
+            .iter()
+            .copied()
             .find(|mode| present_modes.contains(mode))
             .unwrap_or(vk::PresentModeKHR::FIFO);
+        let present_mode = PresentMode::from_vk(vk_present_mode).unwrap();
+
+        if present_mode != desc.present_mode {
+            log::warn!(
+                "Presentation mode {:?} not supported; falling back to {:?}",
+                desc.present_mode,
+                present_mode
+            );
+        }
         log::info!("Presentation mode: {:?}", present_mode);
 
         let pre_transform = if surface_capabilities
@@ -119,8 +252,9 @@ impl Swapchain {
             .image_sharing_mode(vk::SharingMode::EXCLUSIVE)
             .pre_transform(pre_transform)
             .composite_alpha(vk::CompositeAlphaFlagsKHR::OPAQUE)
-            .present_mode(present_mode)
+            .present_mode(vk_present_mode)
             .clipped(true)
+            .old_swapchain(old_swapchain)
             .image_array_layers(1)
             .build();
 
@@ -167,13 +301,15 @@ impl Swapchain {
                         image_type: crate::ImageType::Tex2d,
                         usage: vk::ImageUsageFlags::STORAGE,
                         flags: vk::ImageCreateFlags::empty(),
-                        format: vk::Format::B8G8R8A8_UNORM,
+                        format: desc.format.format,
                         extent: [desc.dims.width, desc.dims.height, 0],
                         tiling: vk::ImageTiling::OPTIMAL,
                         mip_levels: 1,
                         array_elements: 1,
+                        external_memory: false,
                     },
                     views: Default::default(),
+                    allocation: Default::default(),
                 })
             })
             .collect();
@@ -206,6 +342,7 @@ impl Swapchain {
             fns,
             raw: swapchain,
             desc,
+            present_mode,
             images,
             acquire_semaphores,
             rendering_finished_semaphores,
@@ -219,6 +356,15 @@ impl Swapchain {
         [self.desc.dims.width, self.desc.dims.height]
     }
 
+    pub fn color_space(&self) -> SwapchainColorSpace {
+        SwapchainColorSpace::from_surface_format(self.desc.format)
+    }
+
+    /// The presentation mode in use, which may differ from the one requested in `desc`.
+    pub fn present_mode(&self) -> PresentMode {
+        self.present_mode
+    }
+
     pub fn acquire_next_image(
         &mut self,
     ) -> std::result::Result<SwapchainImage, SwapchainAcquireImageErr> {
@@ -255,13 +401,17 @@ impl Swapchain {
             {
                 Err(SwapchainAcquireImageErr::RecreateFramebuffer)
             }
+            Err(err) if err == vk::Result::ERROR_DEVICE_LOST => {
+                self.device.report_error(err.into());
+                Err(SwapchainAcquireImageErr::DeviceLost)
+            }
             err => {
                 panic!("Could not acquire swapchain image: {:?}", err);
             }
         }
     }
 
-    pub fn present_image(&self, image: SwapchainImage) {
+    pub fn present_image(&self, image: SwapchainImage) -> Result<(), KajiyaError> {
         puffin::profile_function!();
 
         let present_info = vk::PresentInfoKHR::builder()
@@ -274,12 +424,16 @@ impl Swapchain {
                 .fns
                 .queue_present(self.device.universal_queue.raw, &present_info)
             {
-                Ok(_) => (),
+                Ok(_) => Ok(()),
                 Err(err)
                     if err == vk::Result::ERROR_OUT_OF_DATE_KHR
                         || err == vk::Result::SUBOPTIMAL_KHR =>
                 {
                     // Handled in the next frame
+                    Ok(())
+                }
+                Err(err) if err == vk::Result::ERROR_DEVICE_LOST => {
+                    Err(self.device.report_error(err.into()).into())
                 }
                 err => {
                     panic!("Could not present image: {:?}", err);
@@ -287,6 +441,20 @@ impl Swapchain {
             }
         }
     }
+
+    // Used when the device the swapchain was created with has been lost.
+    pub(crate) fn recreate_on_device(&mut self, device: &Arc<Device>) -> Result<()> {
+        // The old swapchain must be gone before the surface can get a new one.
+        unsafe {
+            self.fns.destroy_swapchain(self.raw, None);
+        }
+        self.raw = vk::SwapchainKHR::null();
+
+        let surface = self.surface.clone();
+        *self = Self::create(device, &surface, self.desc, vk::SwapchainKHR::null())?;
+
+        Ok(())
+    }
 }
 
 impl Drop for Swapchain {
@@ -0,0 +1,92 @@
+use std::sync::Arc;
+
+use kajiya::{
+    backend::{ash::vk, vulkan::image::*, Device},
+    rg,
+    world_renderer::WorldRenderer,
+};
+
+use crate::EguiRenderer;
+
+/// Runs egui with input from winit, and draws it over the UI image of the frame.
+pub struct EguiBackend {
+    pub ctx: egui::CtxRef,
+    state: egui_winit::State,
+    renderer: EguiRenderer,
+}
+
+impl EguiBackend {
+    pub fn new(device: Arc<Device>, window: &winit::window::Window) -> Self {
+        Self {
+            ctx: Default::default(),
+            state: egui_winit::State::new(window),
+            renderer: EguiRenderer::new(device),
+        }
+    }
+
+    /// Returns true if egui consumed the event, and it shouldn't be handled by the application.
+    pub fn handle_event(&mut self, event: &winit::event::WindowEvent<'_>) -> bool {
+        self.state.on_event(&self.ctx, event)
+    }
+
+    pub fn wants_pointer_input(&self) -> bool {
+        self.ctx.wants_pointer_input()
+    }
+
+    pub fn wants_keyboard_input(&self) -> bool {
+        self.ctx.wants_keyboard_input()
+    }
+
+    /// Builds the UI for this frame in `callback`, and tessellates it for `render`.
+    pub fn frame(&mut self, window: &winit::window::Window, callback: impl FnOnce(&egui::CtxRef)) {
+        let raw_input = self.state.take_egui_input(window);
+        self.ctx.begin_frame(raw_input);
+
+        callback(&self.ctx);
+
+        let (output, shapes) = self.ctx.end_frame();
+        self.state.handle_output(window, &self.ctx, output);
+
+        let meshes = self.ctx.tessellate(shapes);
+        self.renderer
+            .set_meshes(meshes, self.ctx.pixels_per_point());
+    }
+
+    /// Uploads textures changed by the last `frame`. Must be called before `render`.
+    pub fn update_textures(&mut self, world_renderer: &mut WorldRenderer) {
+        // egui has no texture before its first frame.
+        if self.renderer.is_empty() {
+            return;
+        }
+
+        let texture = self.ctx.texture();
+        self.renderer.update_font_texture(&texture, world_renderer);
+    }
+
+    /// Draws the UI of the last `frame` over `ui_img`, which is replaced by an image of `extent`
+    /// if it's of a different size.
+    pub fn render(
+        &mut self,
+        rg: &mut rg::RenderGraph,
+        bindless_descriptor_set: vk::DescriptorSet,
+        ui_img: rg::Handle<Image>,
+        extent: [u32; 2],
+    ) -> rg::Handle<Image> {
+        if self.renderer.is_empty() {
+            return ui_img;
+        }
+
+        let mut target = if ui_img.desc().extent_2d() == extent {
+            ui_img
+        } else {
+            let mut img = rg.create(ImageDesc::new_2d(vk::Format::R8G8B8A8_UNORM, extent));
+            rg::imageops::clear_color(rg, &mut img, [0.0; 4]);
+            img
+        };
+
+        self.renderer
+            .render(rg, bindless_descriptor_set, &mut target);
+
+        target
+    }
+}
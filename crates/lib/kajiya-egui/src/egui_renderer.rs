@@ -0,0 +1,379 @@
+use std::sync::Arc;
+
+use kajiya::{
+    backend::{
+        ash::vk,
+        dynamic_constants::MAX_DYNAMIC_CONSTANTS_STORAGE_BUFFER_BYTES,
+        vk_sync::AccessType,
+        vulkan::{image::*, shader::*},
+        Device,
+    },
+    rg::{self, IntoRenderPassPipelineBinding, RenderPassBinding},
+    world_renderer::{BindlessImageHandle, WorldRenderer},
+};
+
+/// Vertices beyond this many per frame are dropped, along with the meshes using them.
+pub const MAX_EGUI_VERTICES: usize =
+    MAX_DYNAMIC_CONSTANTS_STORAGE_BUFFER_BYTES / std::mem::size_of::<EguiVertex>();
+
+/// Indices beyond this many per frame are dropped, along with the meshes using them.
+pub const MAX_EGUI_INDICES: usize =
+    MAX_DYNAMIC_CONSTANTS_STORAGE_BUFFER_BYTES / std::mem::size_of::<u32>();
+
+// Must match `EguiVertex` in `egui/egui_vs.hlsl`
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct EguiVertex {
+    pos: [f32; 2],
+    uv: [f32; 2],
+    // Premultiplied sRGBA, 8 bits per channel
+    color: u32,
+    _pad: u32,
+}
+
+// Must match `egui/egui_vs.hlsl` and `egui/egui_ps.hlsl`
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct EguiPushConstants {
+    screen_size_points: [f32; 2],
+    base_vertex: u32,
+    texture: u32,
+}
+
+unsafe impl bytemuck::Zeroable for EguiPushConstants {}
+unsafe impl bytemuck::Pod for EguiPushConstants {}
+
+// A mesh within the per-frame vertex and index buffers
+struct EguiDraw {
+    // In physical pixels: min x, min y, max x, max y
+    scissor: [u32; 4],
+    texture: BindlessImageHandle,
+    base_vertex: u32,
+    first_index: u32,
+    index_count: u32,
+}
+
+/// Draws egui's tessellated meshes through the render graph. egui's font texture is uploaded
+/// into the bindless table; `egui::TextureId::User(id)` refers to the kajiya `BindlessImageHandle(id)`,
+/// which should hold premultiplied color.
+pub struct EguiRenderer {
+    device: Arc<Device>,
+    render_pass: Arc<RenderPass>,
+
+    // egui's version of the texture, and its handle
+    font_texture: Option<(u64, BindlessImageHandle)>,
+
+    meshes: Vec<egui::ClippedMesh>,
+    pixels_per_point: f32,
+}
+
+impl EguiRenderer {
+    pub fn new(device: Arc<Device>) -> Self {
+        let render_pass = create_render_pass(
+            device.as_ref(),
+            RenderPassDesc {
+                color_attachments: &[RenderPassAttachmentDesc::new(vk::Format::R8G8B8A8_UNORM)],
+                depth_attachment: None,
+            },
+        );
+
+        Self {
+            device,
+            render_pass,
+            font_texture: None,
+            meshes: Vec::new(),
+            pixels_per_point: 1.0,
+        }
+    }
+
+    /// Sets the meshes to draw in the next `render`.
+    pub fn set_meshes(&mut self, meshes: Vec<egui::ClippedMesh>, pixels_per_point: f32) {
+        self.meshes = meshes;
+        self.pixels_per_point = pixels_per_point;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.meshes.is_empty()
+    }
+
+    /// Uploads egui's font texture if it changed since the last call. Old versions of the texture
+    /// aren't removed from the bindless table, but egui only changes it when fonts are reconfigured.
+    pub fn update_font_texture(
+        &mut self,
+        texture: &egui::Texture,
+        world_renderer: &mut WorldRenderer,
+    ) {
+        if self
+            .font_texture
+            .map_or(false, |(version, _)| version == texture.version)
+        {
+            return;
+        }
+
+        // Coverage to premultiplied white
+        let pixels: Vec<u8> = texture
+            .pixels
+            .iter()
+            .flat_map(|&coverage| [coverage; 4])
+            .collect();
+
+        let image = self
+            .device
+            .create_image(
+                ImageDesc::new_2d(
+                    vk::Format::R8G8B8A8_UNORM,
+                    [texture.width as u32, texture.height as u32],
+                )
+                .usage(vk::ImageUsageFlags::SAMPLED),
+                "egui texture",
+                vec![ImageSubResourceData {
+                    data: &pixels,
+                    row_pitch: texture.width * 4,
+                    slice_pitch: 0,
+                }],
+            )
+            .expect("egui font texture");
+
+        let handle = world_renderer.add_image(Arc::new(image));
+        self.font_texture = Some((texture.version, handle));
+    }
+
+    /// Draws the meshes set by `set_meshes` over `target`, and clears them.
+    pub fn render(
+        &mut self,
+        rg: &mut rg::RenderGraph,
+        bindless_descriptor_set: vk::DescriptorSet,
+        target: &mut rg::Handle<Image>,
+    ) {
+        let font_texture = match self.font_texture {
+            Some((_, handle)) => handle,
+            None => {
+                self.meshes.clear();
+                return;
+            }
+        };
+
+        let [width, height, _] = target.desc().extent;
+        let (vertices, indices, draws) = self.prepare_draws(font_texture, [width, height]);
+
+        if draws.is_empty() {
+            return;
+        }
+
+        let mut pass = rg.add_pass("egui");
+
+        let pipeline = pass.register_raster_pipeline(
+            &[
+                PipelineShaderDesc::builder(ShaderPipelineStage::Vertex)
+                    .hlsl_source("/shaders/egui/egui_vs.hlsl")
+                    .build()
+                    .unwrap(),
+                PipelineShaderDesc::builder(ShaderPipelineStage::Pixel)
+                    .hlsl_source("/shaders/egui/egui_ps.hlsl")
+                    .build()
+                    .unwrap(),
+            ],
+            RasterPipelineDesc::builder()
+                .render_pass(self.render_pass.clone())
+                .depth_test(false)
+                .depth_write(false)
+                .blend_mode(RasterBlendMode::PremultipliedAlpha)
+                .push_constants_bytes(std::mem::size_of::<EguiPushConstants>()),
+        );
+
+        let target_ref = pass.raster(target, AccessType::ColorAttachmentReadWrite);
+
+        let render_pass = self.render_pass.clone();
+        let screen_size_points = [
+            width as f32 / self.pixels_per_point,
+            height as f32 / self.pixels_per_point,
+        ];
+
+        pass.render(move |api| {
+            let vertices_offset = api.dynamic_constants().push_from_iter(vertices.into_iter());
+            let indices_offset = api.dynamic_constants().push_from_iter(indices.into_iter());
+
+            api.begin_render_pass(
+                &*render_pass,
+                [width, height],
+                &[(target_ref, &ImageViewDesc::default())],
+                None,
+            );
+
+            api.set_default_view_and_scissor([width, height]);
+
+            let pipeline = match api.bind_raster_pipeline(
+                pipeline
+                    .into_binding()
+                    .storage_buffer(
+                        "vertices_dyn",
+                        RenderPassBinding::DynamicConstantsStorageBuffer(vertices_offset),
+                    )
+                    .storage_buffer(
+                        "indices_dyn",
+                        RenderPassBinding::DynamicConstantsStorageBuffer(indices_offset),
+                    )
+                    .bindless(bindless_descriptor_set),
+            ) {
+                Ok(pipeline) => pipeline,
+                Err(err) => {
+                    log::error!("egui: {}", err);
+                    api.end_render_pass();
+                    return;
+                }
+            };
+
+            unsafe {
+                let raw_device = &api.device().raw;
+                let cb = api.cb;
+
+                for draw in draws {
+                    raw_device.cmd_set_scissor(
+                        cb.raw,
+                        0,
+                        &[vk::Rect2D {
+                            offset: vk::Offset2D {
+                                x: draw.scissor[0] as i32,
+                                y: draw.scissor[1] as i32,
+                            },
+                            extent: vk::Extent2D {
+                                width: draw.scissor[2] - draw.scissor[0],
+                                height: draw.scissor[3] - draw.scissor[1],
+                            },
+                        }],
+                    );
+
+                    let push_constants = EguiPushConstants {
+                        screen_size_points,
+                        base_vertex: draw.base_vertex,
+                        texture: draw.texture.0,
+                    };
+
+                    if let Err(err) = pipeline.push_constants(
+                        cb.raw,
+                        vk::ShaderStageFlags::ALL_GRAPHICS,
+                        0,
+                        bytemuck::bytes_of(&push_constants),
+                    ) {
+                        log::error!("egui: {}", err);
+                        break;
+                    }
+
+                    // Indices are fetched in the vertex shader.
+                    raw_device.cmd_draw(cb.raw, draw.index_count, 1, draw.first_index, 0);
+                }
+            }
+
+            api.end_render_pass();
+        });
+    }
+
+    // Packs the meshes into per-frame vertex and index buffers, and takes them.
+    fn prepare_draws(
+        &mut self,
+        font_texture: BindlessImageHandle,
+        extent: [u32; 2],
+    ) -> (Vec<EguiVertex>, Vec<u32>, Vec<EguiDraw>) {
+        let mut vertices = Vec::new();
+        let mut indices = Vec::new();
+        let mut draws = Vec::new();
+
+        for egui::ClippedMesh(clip_rect, mesh) in std::mem::take(&mut self.meshes) {
+            if mesh.indices.is_empty() {
+                continue;
+            }
+
+            if vertices.len() + mesh.vertices.len() > MAX_EGUI_VERTICES
+                || indices.len() + mesh.indices.len() > MAX_EGUI_INDICES
+            {
+                log::warn!("Too many egui vertices; some of the UI won't be drawn");
+                break;
+            }
+
+            let scissor = match clip_rect_to_scissor(clip_rect, self.pixels_per_point, extent) {
+                Some(scissor) => scissor,
+                None => continue,
+            };
+
+            let texture = match mesh.texture_id {
+                egui::TextureId::Egui => font_texture,
+                egui::TextureId::User(id) => BindlessImageHandle(id as u32),
+            };
+
+            draws.push(EguiDraw {
+                scissor,
+                texture,
+                base_vertex: vertices.len() as u32,
+                first_index: indices.len() as u32,
+                index_count: mesh.indices.len() as u32,
+            });
+
+            vertices.extend(mesh.vertices.iter().map(|v| EguiVertex {
+                pos: [v.pos.x, v.pos.y],
+                uv: [v.uv.x, v.uv.y],
+                color: u32::from_le_bytes(v.color.to_array()),
+                _pad: 0,
+            }));
+            indices.extend_from_slice(&mesh.indices);
+        }
+
+        (vertices, indices, draws)
+    }
+}
+
+/// `clip_rect` in egui points to a scissor rectangle in physical pixels, clamped to `extent`.
+/// `None` if nothing is visible.
+fn clip_rect_to_scissor(
+    clip_rect: egui::Rect,
+    pixels_per_point: f32,
+    extent: [u32; 2],
+) -> Option<[u32; 4]> {
+    let to_px =
+        |points: f32, max: u32| ((points * pixels_per_point).round().max(0.0) as u32).min(max);
+
+    let scissor = [
+        to_px(clip_rect.min.x, extent[0]),
+        to_px(clip_rect.min.y, extent[1]),
+        to_px(clip_rect.max.x, extent[0]),
+        to_px(clip_rect.max.y, extent[1]),
+    ];
+
+    if scissor[0] < scissor[2] && scissor[1] < scissor[3] {
+        Some(scissor)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clip_rects_become_clamped_scissors() {
+        let rect = |min: [f32; 2], max: [f32; 2]| {
+            egui::Rect::from_min_max(egui::pos2(min[0], min[1]), egui::pos2(max[0], max[1]))
+        };
+
+        assert_eq!(
+            clip_rect_to_scissor(rect([10.0, 20.0], [110.0, 70.0]), 2.0, [1920, 1080]),
+            Some([20, 40, 220, 140])
+        );
+
+        // Clamped to the screen
+        assert_eq!(
+            clip_rect_to_scissor(rect([-50.0, -50.0], [5000.0, 5000.0]), 1.0, [1920, 1080]),
+            Some([0, 0, 1920, 1080])
+        );
+
+        // Off-screen or empty
+        assert_eq!(
+            clip_rect_to_scissor(rect([2000.0, 0.0], [2100.0, 100.0]), 1.0, [1920, 1080]),
+            None
+        );
+        assert_eq!(
+            clip_rect_to_scissor(rect([10.0, 10.0], [10.0, 50.0]), 1.0, [1920, 1080]),
+            None
+        );
+    }
+}
@@ -0,0 +1,6 @@
+mod egui_backend;
+mod egui_renderer;
+
+pub use egui;
+pub use egui_backend::*;
+pub use egui_renderer::*;
@@ -89,7 +89,6 @@ impl ImGuiBackend {
             .create_graphics_resources(self.device.as_ref(), surface_resolution);
     }
 
-    #[allow(dead_code)]
     pub fn destroy_graphics_resources(&mut self) {
         let device = &self.device.raw;
 
@@ -316,6 +315,7 @@ fn create_imgui_framebuffer(
         .create_image(
             ImageDesc::new_2d(vk::Format::R8G8B8A8_UNORM, surface_resolution)
                 .usage(vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::COLOR_ATTACHMENT),
+            "imgui framebuffer",
             vec![],
         )
         .unwrap();
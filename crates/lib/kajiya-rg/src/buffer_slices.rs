@@ -0,0 +1,275 @@
+// Small temporal buffers, suballocated from a few large ones; see
+// `TemporalRenderGraph::get_or_create_temporal_slice`.
+//
+// Effects which keep many small histories (reservoirs, probes, ...) would otherwise create a buffer
+// per key. Instead, slices are carved out of shared chunks, and new chunks are only created once
+// the existing ones are full. Each slice is imported into the graph as a handle to its chunk,
+// and bound with its offset and size. The graph's buffer barriers are global, so slices of
+// the same chunk don't synchronize any more than separate buffers would.
+
+use std::{ops::Range, sync::Arc};
+
+use kajiya_backend::{
+    ash::vk,
+    vulkan::buffer::{Buffer, BufferDesc},
+    Device,
+};
+
+use crate::Handle;
+
+/// Slices are carved out of chunks of this many bytes.
+pub const BUFFER_SLICE_CHUNK_SIZE: u64 = 4 << 20;
+
+/// Larger temporal buffers need buffers of their own, via `get_or_create_temporal`.
+pub const MAX_BUFFER_SLICE_SIZE: u64 = 64 << 10;
+
+/// A temporal buffer suballocated from a buffer shared with other slices.
+pub struct TemporalBufferSlice {
+    /// The whole shared buffer; only `offset..offset + size` belongs to the slice.
+    pub buffer: Handle<Buffer>,
+    pub offset: u64,
+    pub size: u64,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) struct BufferSlot {
+    pub chunk: usize,
+    pub offset: u64,
+
+    // Rounded up to the alignment of slices
+    pub size: u64,
+}
+
+// The free ranges of a chunk, sorted and coalesced
+#[derive(Clone)]
+struct ChunkRanges {
+    free: Vec<Range<u64>>,
+}
+
+impl ChunkRanges {
+    fn new(size: u64) -> Self {
+        Self {
+            free: vec![0..size],
+        }
+    }
+
+    // First fit
+    fn allocate(&mut self, size: u64, align: u64) -> Option<u64> {
+        let (idx, offset) = self.free.iter().enumerate().find_map(|(idx, range)| {
+            let offset = align_up(range.start, align);
+            (offset + size <= range.end).then(|| (idx, offset))
+        })?;
+
+        let range = self.free.remove(idx);
+        if offset + size < range.end {
+            self.free.insert(idx, offset + size..range.end);
+        }
+        if range.start < offset {
+            self.free.insert(idx, range.start..offset);
+        }
+
+        Some(offset)
+    }
+
+    fn free(&mut self, range: Range<u64>) {
+        let idx = self.free.partition_point(|free| free.start < range.start);
+        self.free.insert(idx, range);
+
+        if idx + 1 < self.free.len() && self.free[idx].end == self.free[idx + 1].start {
+            self.free[idx].end = self.free.remove(idx + 1).end;
+        }
+        if idx > 0 && self.free[idx - 1].end == self.free[idx].start {
+            self.free[idx - 1].end = self.free.remove(idx).end;
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct SliceAllocator {
+    chunks: Vec<ChunkRanges>,
+}
+
+impl SliceAllocator {
+    // `None` if all chunks are full; see `add_chunk`.
+    pub fn allocate(&mut self, size: u64, align: u64) -> Option<BufferSlot> {
+        let size = align_up(size.max(1), align);
+
+        self.chunks
+            .iter_mut()
+            .enumerate()
+            .find_map(|(chunk, ranges)| {
+                let offset = ranges.allocate(size, align)?;
+                Some(BufferSlot {
+                    chunk,
+                    offset,
+                    size,
+                })
+            })
+    }
+
+    pub fn add_chunk(&mut self) {
+        self.chunks.push(ChunkRanges::new(BUFFER_SLICE_CHUNK_SIZE));
+    }
+
+    pub fn free(&mut self, slot: BufferSlot) {
+        self.chunks[slot.chunk].free(slot.offset..slot.offset + slot.size);
+    }
+
+    pub fn chunk_count(&self) -> usize {
+        self.chunks.len()
+    }
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct TemporalBufferPool {
+    allocator: SliceAllocator,
+    chunks: Vec<Arc<Buffer>>,
+
+    // Slots of replaced slices, with the frame counter of the last frame which may use them
+    pending_frees: Vec<(u64, BufferSlot)>,
+}
+
+impl TemporalBufferPool {
+    pub fn allocate(
+        &mut self,
+        device: &Device,
+        size: u64,
+    ) -> anyhow::Result<(Arc<Buffer>, BufferSlot)> {
+        self.reclaim(device);
+
+        let align = slice_alignment(device);
+        let slot = if let Some(slot) = self.allocator.allocate(size, align) {
+            slot
+        } else {
+            log::debug!(
+                "Creating temporal buffer slice chunk #{}",
+                self.chunks.len()
+            );
+
+            let chunk = device.create_buffer(
+                BufferDesc::new_gpu_only(
+                    BUFFER_SLICE_CHUNK_SIZE as usize,
+                    vk::BufferUsageFlags::STORAGE_BUFFER
+                        | vk::BufferUsageFlags::TRANSFER_SRC
+                        | vk::BufferUsageFlags::TRANSFER_DST,
+                ),
+                "temporal buffer slices",
+                None,
+            )?;
+            self.chunks.push(Arc::new(chunk));
+            self.allocator.add_chunk();
+
+            self.allocator
+                .allocate(size, align)
+                .expect("slices are smaller than chunks")
+        };
+
+        Ok((self.chunks[slot.chunk].clone(), slot))
+    }
+
+    // Frees `slot` once frames in flight are done with it.
+    pub fn release(&mut self, device: &Device, slot: BufferSlot) {
+        self.pending_frees.push((device.frame_counter(), slot));
+    }
+
+    // Takes over chunks created by `other`, a later version of this pool, but not its slices.
+    // For frames which failed to render; see `Renderer::prepare_frame`.
+    pub fn keep_new_chunks(&mut self, other: Self) {
+        for chunk in other.chunks.into_iter().skip(self.chunks.len()) {
+            self.chunks.push(chunk);
+            self.allocator.add_chunk();
+        }
+    }
+
+    fn reclaim(&mut self, device: &Device) {
+        let completed_frame = match device
+            .frame_counter()
+            .checked_sub(device.frames_in_flight() as u64)
+        {
+            Some(frame) => frame,
+            None => return,
+        };
+
+        let allocator = &mut self.allocator;
+        self.pending_frees.retain(|&(last_used_in_frame, slot)| {
+            if last_used_in_frame <= completed_frame {
+                allocator.free(slot);
+                false
+            } else {
+                true
+            }
+        });
+    }
+}
+
+// Slices are bound with their offsets, so those must suit any buffer descriptor.
+fn slice_alignment(device: &Device) -> u64 {
+    let limits = &device.physical_device().properties.limits;
+    limits
+        .min_storage_buffer_offset_alignment
+        .max(limits.min_uniform_buffer_offset_alignment)
+        .max(16)
+}
+
+fn align_up(x: u64, align: u64) -> u64 {
+    (x + align - 1) / align * align
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn many_small_slices_share_few_chunks() {
+        const ALIGN: u64 = 256;
+
+        let mut allocator = SliceAllocator::default();
+        let allocate = |allocator: &mut SliceAllocator, size: u64| {
+            allocator.allocate(size, ALIGN).unwrap_or_else(|| {
+                allocator.add_chunk();
+                allocator.allocate(size, ALIGN).unwrap()
+            })
+        };
+
+        // 10000 slices of 4..1000 bytes; about 6 MiB once aligned
+        let slots: Vec<BufferSlot> = (0..10000u64)
+            .map(|i| allocate(&mut allocator, 4 + (i * 7919) % 997))
+            .collect();
+        assert_eq!(allocator.chunk_count(), 2);
+
+        let assert_disjoint = |slots: &[BufferSlot]| {
+            let mut slots = slots.to_vec();
+            slots.sort_by_key(|slot| (slot.chunk, slot.offset));
+
+            for slot in &slots {
+                assert_eq!(slot.offset % ALIGN, 0);
+                assert!(slot.offset + slot.size <= BUFFER_SLICE_CHUNK_SIZE);
+            }
+            for pair in slots.windows(2) {
+                if pair[0].chunk == pair[1].chunk {
+                    assert!(pair[0].offset + pair[0].size <= pair[1].offset);
+                }
+            }
+        };
+        assert_disjoint(&slots);
+
+        // Freed ranges are reused before growing again.
+        for slot in slots.iter().skip(1).step_by(2) {
+            allocator.free(*slot);
+        }
+        let mut slots: Vec<BufferSlot> = slots.iter().step_by(2).copied().collect();
+        for i in 0..5000u64 {
+            slots.push(allocate(&mut allocator, 4 + (i * 104729) % 997));
+        }
+        assert_eq!(allocator.chunk_count(), 2);
+        assert_disjoint(&slots);
+
+        // Freeing everything coalesces each chunk back into a single range.
+        for slot in &slots {
+            allocator.free(*slot);
+        }
+        for chunk in &allocator.chunks {
+            assert_eq!(chunk.free, vec![0..BUFFER_SLICE_CHUNK_SIZE]);
+        }
+    }
+}
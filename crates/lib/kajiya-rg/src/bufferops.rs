@@ -0,0 +1,20 @@
+use crate::{self as rg, RenderGraph};
+use kajiya_backend::{ash::vk, vk_sync::AccessType, vulkan::buffer::Buffer};
+
+/// Fills `buf` with repeated copies of `value`. Recorded as a transfer write,
+/// so passes reading the buffer afterwards are ordered after the clear.
+pub fn clear_buffer(rg: &mut RenderGraph, buf: &mut rg::Handle<Buffer>, value: u32) {
+    let mut pass = rg.add_pass("clear buffer");
+    let output_ref = pass.write(buf, AccessType::TransferWrite);
+
+    pass.render(move |api| {
+        let raw_device = &api.device().raw;
+        let cb = api.cb;
+
+        let buffer = api.resources.buffer(output_ref);
+
+        unsafe {
+            raw_device.cmd_fill_buffer(cb.raw, buffer.raw, 0, vk::WHOLE_SIZE, value);
+        }
+    });
+}
@@ -0,0 +1,167 @@
+// Small GPU-computed values read on the CPU, a few frames late: statistics for adaptive sampling,
+// texture streaming requests, luminance for auto-exposure, and the like.
+//
+// Each frame, `FeedbackBuffer::create` adds a zeroed buffer holding one `T` to the graph, which
+// passes write; `copy_to_host` then reads it back via `AsyncReadback`. `latest` is the most recent
+// value the GPU is done with. Nothing waits on the GPU, so values lag behind by the frames in flight.
+
+use std::{collections::VecDeque, marker::PhantomData, task::Poll};
+
+use kajiya_backend::{
+    ash::vk,
+    vulkan::buffer::{Buffer, BufferDesc},
+    Device,
+};
+
+use crate::{
+    readback::{AsyncReadback, ReadbackToken},
+    Handle, TemporalRenderGraph,
+};
+
+pub struct FeedbackBuffer<T: bytemuck::Pod> {
+    readback: AsyncReadback,
+
+    // Oldest first, with the frame counter of the frame which wrote each, once submitted
+    in_flight: VecDeque<(ReadbackToken, Option<u64>)>,
+
+    latest: Option<(T, u64)>,
+    marker: PhantomData<T>,
+}
+
+impl<T: bytemuck::Pod> Default for FeedbackBuffer<T> {
+    fn default() -> Self {
+        Self {
+            readback: Default::default(),
+            in_flight: Default::default(),
+            latest: None,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<T: bytemuck::Pod> FeedbackBuffer<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds this frame's buffer to `rg`, with `T` zeroed, for passes to write.
+    /// Pass it to `copy_to_host` after the last of them.
+    pub fn create(&mut self, rg: &mut TemporalRenderGraph) -> Handle<Buffer> {
+        // `vkCmdFillBuffer` clears whole words.
+        let size = (std::mem::size_of::<T>() + 3) & !3;
+
+        self.readback.begin_frame();
+
+        let mut buffer = rg.create(BufferDesc::new_gpu_only(
+            size,
+            vk::BufferUsageFlags::STORAGE_BUFFER,
+        ));
+        rg.clear_buffer(&mut buffer, 0);
+        buffer
+    }
+
+    /// Reads `buffer`, as returned by `create`, back once the frame is done.
+    pub fn copy_to_host(&mut self, rg: &mut TemporalRenderGraph, buffer: &Handle<Buffer>) {
+        let token = self.readback.copy_buffer(rg, buffer);
+        self.in_flight.push_back((token, None));
+    }
+
+    /// Marks the copy made since `create` as submitted in the frame `frame_counter`.
+    pub fn retire_frame(&mut self, frame_counter: u64) {
+        self.readback.retire_frame(frame_counter);
+
+        for (_, written_in_frame) in &mut self.in_flight {
+            written_in_frame.get_or_insert(frame_counter);
+        }
+    }
+
+    /// The value of the most recent frame which the GPU is done with, or `None` before
+    /// the first one. Stays the same until a later frame is done.
+    pub fn latest(&mut self, device: &Device) -> Option<T> {
+        self.poll(device);
+        self.latest.map(|(value, _)| value)
+    }
+
+    /// The frame counter of the frame which wrote the value returned by `latest`.
+    pub fn latest_frame(&mut self, device: &Device) -> Option<u64> {
+        self.poll(device);
+        self.latest.map(|(_, frame)| frame)
+    }
+
+    fn poll(&mut self, device: &Device) {
+        while let Some(&(token, written_in_frame)) = self.in_flight.front() {
+            let bytes = match self.readback.poll(device, token) {
+                Poll::Ready(bytes) => bytes,
+                Poll::Pending => break,
+            };
+            self.in_flight.pop_front();
+
+            // Frames which failed to render have nothing to read.
+            if let (Some(bytes), Some(frame)) = (bytes, written_in_frame) {
+                let mut value = T::zeroed();
+                let value_bytes = bytemuck::bytes_of_mut(&mut value);
+                value_bytes.copy_from_slice(&bytes[..value_bytes.len()]);
+
+                self.latest = Some((value, frame));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        renderer::{FrameConstantsLayout, Renderer},
+        SimpleRenderPass,
+    };
+    use kajiya_backend::{file::set_standard_vfs_mount_points, HeadlessRenderBackend};
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn reads_a_counter_once_its_frame_retires() {
+        set_standard_vfs_mount_points(concat!(env!("CARGO_MANIFEST_DIR"), "/../../.."));
+
+        let backend = HeadlessRenderBackend::new(Default::default()).unwrap();
+        let device = backend.device.clone();
+        let mut renderer = Renderer::new(&device).unwrap();
+        let mut feedback = FeedbackBuffer::<u32>::new();
+
+        // Each thread increments the counter, so frame `i` counts `64 * (i + 1)`.
+        let mut frames = Vec::new();
+        for frame in 0..device.frames_in_flight() as u32 + 3 {
+            renderer
+                .prepare_frame(|rg| {
+                    let mut counter = feedback.create(rg);
+                    SimpleRenderPass::new_compute(
+                        rg.add_pass("count threads"),
+                        "/shaders/tests/count_threads.hlsl",
+                    )
+                    .write(&mut counter)
+                    .dispatch([64 * (frame + 1), 1, 1]);
+                    feedback.copy_to_host(rg, &counter);
+                })
+                .unwrap();
+            renderer
+                .draw_frame_headless(|_| FrameConstantsLayout {
+                    globals_offset: 0,
+                    instance_dynamic_parameters_offset: 0,
+                    triangle_lights_offset: 0,
+                })
+                .unwrap();
+
+            let frame_counter = device.frame_counter();
+            feedback.retire_frame(frame_counter);
+            frames.push(frame_counter);
+
+            // Never the frame just submitted
+            assert_ne!(feedback.latest_frame(&device), Some(frame_counter));
+        }
+
+        // Some frames have retired by now; the value is that of the latest of them.
+        let latest_frame = feedback.latest_frame(&device).unwrap();
+        let frame = frames.iter().position(|&f| f == latest_frame).unwrap() as u32;
+        assert!(frame + 1 < frames.len() as u32);
+        assert_eq!(feedback.latest(&device), Some(64 * (frame + 1)));
+    }
+}
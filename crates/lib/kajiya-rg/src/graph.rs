@@ -117,10 +117,109 @@ pub(crate) struct RgRtPipeline {
     pub(crate) desc: RayTracingPipelineDesc,
 }
 
+/// Descriptor set index conventionally used for the bindless resources of the world renderer.
+pub const BINDLESS_DESCRIPTOR_SET_IDX: u32 = 1;
+
 pub struct PredefinedDescriptorSet {
     pub bindings: HashMap<u32, rspirv_reflect::DescriptorInfo>,
 }
 
+/// A semaphore shared with work outside of the render graph, such as another renderer
+/// or a video decoder.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ExternalSemaphore {
+    pub raw: vk::Semaphore,
+
+    /// The value to wait for or signal, for timeline semaphores. `None` for binary ones.
+    pub timeline_value: Option<u64>,
+}
+
+impl ExternalSemaphore {
+    pub fn binary(raw: vk::Semaphore) -> Self {
+        Self {
+            raw,
+            timeline_value: None,
+        }
+    }
+
+    pub fn timeline(raw: vk::Semaphore, value: u64) -> Self {
+        Self {
+            raw,
+            timeline_value: Some(value),
+        }
+    }
+}
+
+/// Synchronization of an imported resource with its producer or consumer outside of the graph;
+/// see `RenderGraph::import_with_sync`.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct ExternalSync {
+    /// Signaled once the resource is ready. Waited for before the first pass of the graph.
+    pub wait: Option<ExternalSemaphore>,
+
+    /// Signaled after the last pass of the graph, once it's done with the resource.
+    pub signal: Option<ExternalSemaphore>,
+}
+
+#[derive(Default)]
+pub(crate) struct ExternalSemaphores {
+    pub waits: Vec<ExternalSemaphore>,
+    pub signals: Vec<ExternalSemaphore>,
+}
+
+impl ExternalSemaphores {
+    pub fn add(&mut self, sync: ExternalSync) {
+        Self::add_semaphore(&mut self.waits, sync.wait);
+        Self::add_semaphore(&mut self.signals, sync.signal);
+    }
+
+    /// Takes over the semaphores of a frame which was dropped before it was submitted,
+    /// so that the external work synchronizes with a later frame instead.
+    pub fn carry_over(&mut self, dropped: ExternalSemaphores) {
+        let current = std::mem::take(self);
+        *self = dropped;
+
+        for wait in current.waits {
+            Self::add_semaphore(&mut self.waits, Some(wait));
+        }
+        for signal in current.signals {
+            Self::add_semaphore(&mut self.signals, Some(signal));
+        }
+    }
+
+    // A semaphore can only appear once per submission. Waiting for or signaling a later value
+    // of a timeline semaphore subsumes the earlier ones.
+    fn add_semaphore(
+        semaphores: &mut Vec<ExternalSemaphore>,
+        semaphore: Option<ExternalSemaphore>,
+    ) {
+        let semaphore = if let Some(semaphore) = semaphore {
+            semaphore
+        } else {
+            return;
+        };
+
+        if let Some(existing) = semaphores
+            .iter_mut()
+            .find(|existing| existing.raw == semaphore.raw)
+        {
+            existing.timeline_value = existing.timeline_value.max(semaphore.timeline_value);
+        } else {
+            semaphores.push(semaphore);
+        }
+    }
+}
+
+/// The raw semaphores to submit, and their values for `vk::TimelineSemaphoreSubmitInfo`.
+/// Values of binary semaphores are ignored by Vulkan, so those are zero.
+pub(crate) fn submit_semaphores(
+    semaphores: impl Iterator<Item = ExternalSemaphore>,
+) -> (Vec<vk::Semaphore>, Vec<u64>) {
+    semaphores
+        .map(|semaphore| (semaphore.raw, semaphore.timeline_value.unwrap_or(0)))
+        .unzip()
+}
+
 #[derive(Clone, PartialEq, Eq)]
 pub struct GraphDebugHook {
     pub render_scope: gpu_profiler::RenderScopeDesc,
@@ -130,6 +229,7 @@ pub struct RenderGraph {
     passes: Vec<RecordedPass>,
     resources: Vec<GraphResourceInfo>,
     exported_resources: Vec<(ExportableGraphResource, vk_sync::AccessType)>,
+    external_semaphores: ExternalSemaphores,
     pub(crate) compute_pipelines: Vec<RgComputePipeline>,
     pub(crate) raster_pipelines: Vec<RgRasterPipeline>,
     pub(crate) rt_pipelines: Vec<RgRtPipeline>,
@@ -300,6 +400,7 @@ impl RenderGraph {
             passes: Vec::new(),
             resources: Vec::new(),
             exported_resources: Vec::new(),
+            external_semaphores: Default::default(),
             compute_pipelines: Vec::new(),
             raster_pipelines: Vec::new(),
             rt_pipelines: Vec::new(),
@@ -348,6 +449,24 @@ impl RenderGraph {
         ImportExportToRenderGraph::import(resource, self, access_type_at_import_time)
     }
 
+    /// Like `import`, for resources produced or consumed outside of the graph. The semaphores
+    /// of `sync` apply to the whole graph rather than the passes using the resource: all of it
+    /// waits for `sync.wait`, and `sync.signal` is signaled after all of it. If the frame is
+    /// dropped before it's submitted, the next one waits and signals in its place.
+    pub fn import_with_sync<Res: ImportExportToRenderGraph>(
+        &mut self,
+        resource: Arc<Res>,
+        access_type_at_import_time: vk_sync::AccessType,
+        sync: ExternalSync,
+    ) -> Handle<Res> {
+        self.external_semaphores.add(sync);
+        self.import(resource, access_type_at_import_time)
+    }
+
+    pub(crate) fn carry_over_external_semaphores(&mut self, dropped: ExternalSemaphores) {
+        self.external_semaphores.carry_over(dropped);
+    }
+
     pub fn export<Res: ImportExportToRenderGraph>(
         &mut self,
         resource: Handle<Res>,
@@ -407,11 +526,42 @@ pub struct CompiledRenderGraph {
     pipelines: RenderGraphPipelines,
 }
 
+impl CompiledRenderGraph {
+    /// For graphs which are dropped without executing: the semaphores of `import_with_sync`.
+    pub(crate) fn take_external_semaphores(&mut self) -> ExternalSemaphores {
+        std::mem::take(&mut self.rg.external_semaphores)
+    }
+}
+
 struct PendingDebugPass {
     img: Handle<Image>,
 }
 
 impl RenderGraph {
+    pub fn clear_image(&mut self, img: &mut Handle<Image>, value: crate::imageops::ClearValue) {
+        crate::imageops::clear_image(self, img, value)
+    }
+
+    pub fn clear_depth(&mut self, img: &mut Handle<Image>, depth: f32) {
+        crate::imageops::clear_image(
+            self,
+            img,
+            crate::imageops::ClearValue::DepthStencil { depth, stencil: 0 },
+        )
+    }
+
+    pub fn clear_buffer(&mut self, buf: &mut Handle<Buffer>, value: u32) {
+        crate::bufferops::clear_buffer(self, buf, value)
+    }
+
+    pub fn copy_image(&mut self, src: &Handle<Image>, dst: &mut Handle<Image>) {
+        crate::imageops::copy_image(self, src, dst)
+    }
+
+    pub fn blit_image(&mut self, src: &Handle<Image>, dst: &mut Handle<Image>, filter: vk::Filter) {
+        crate::imageops::blit_image(self, src, dst, filter)
+    }
+
     pub fn add_pass<'s>(&'s mut self, name: &str) -> PassBuilder<'s> {
         let pass_idx = self.passes.len();
 
@@ -708,77 +858,78 @@ impl CompiledRenderGraph {
         dynamic_constants: &'constants mut DynamicConstants,
     ) -> ExecutingRenderGraph<'exec_params, 'constants> {
         let device = params.device;
-        let resources: Vec<RegistryResource> = self
-            .rg
-            .resources
-            .iter()
-            .enumerate()
-            .map(|(resource_idx, resource)| match resource {
-                GraphResourceInfo::Created(create_info) => match create_info.desc {
-                    GraphResourceDesc::Image(mut desc) => {
-                        desc.usage = self.resource_info.image_usage_flags[resource_idx];
-
-                        let image = transient_resource_cache
-                            .get_image(&desc)
-                            .unwrap_or_else(|| device.create_image(desc, vec![]).unwrap());
-
-                        RegistryResource {
-                            access_type: vk_sync::AccessType::Nothing,
-                            resource: AnyRenderResource::OwnedImage(image),
-                        }
-                    }
-                    GraphResourceDesc::Buffer(mut desc) => {
-                        desc.usage = self.resource_info.buffer_usage_flags[resource_idx];
-
-                        let buffer =
-                            transient_resource_cache
-                                .get_buffer(&desc)
-                                .unwrap_or_else(|| {
-                                    device.create_buffer(desc, "rg buffer", None).unwrap()
-                                });
-
-                        RegistryResource {
-                            resource: AnyRenderResource::OwnedBuffer(buffer),
-                            access_type: vk_sync::AccessType::Nothing,
+        let resources: Vec<RegistryResource> =
+            self.rg
+                .resources
+                .iter()
+                .enumerate()
+                .map(|(resource_idx, resource)| match resource {
+                    GraphResourceInfo::Created(create_info) => {
+                        match create_info.desc {
+                            GraphResourceDesc::Image(mut desc) => {
+                                desc.usage = self.resource_info.image_usage_flags[resource_idx];
+
+                                let image =
+                                    transient_resource_cache.get_image(&desc).unwrap_or_else(
+                                        || device.create_image(desc, "rg image", vec![]).unwrap(),
+                                    );
+
+                                RegistryResource {
+                                    access_type: vk_sync::AccessType::Nothing,
+                                    resource: AnyRenderResource::OwnedImage(image),
+                                }
+                            }
+                            GraphResourceDesc::Buffer(mut desc) => {
+                                desc.usage = self.resource_info.buffer_usage_flags[resource_idx];
+
+                                let buffer =
+                                    transient_resource_cache.get_buffer(&desc).unwrap_or_else(
+                                        || device.create_buffer(desc, "rg buffer", None).unwrap(),
+                                    );
+
+                                RegistryResource {
+                                    resource: AnyRenderResource::OwnedBuffer(buffer),
+                                    access_type: vk_sync::AccessType::Nothing,
+                                }
+                            }
+                            GraphResourceDesc::RayTracingAcceleration(_) => {
+                                unimplemented!();
+                            }
                         }
                     }
-                    GraphResourceDesc::RayTracingAcceleration(_) => {
-                        unimplemented!();
-                    }
-                },
-                GraphResourceInfo::Imported(import_info) => match import_info {
-                    GraphResourceImportInfo::Image {
-                        resource,
-                        access_type,
-                    } => RegistryResource {
-                        resource: AnyRenderResource::ImportedImage(resource.clone()),
-                        access_type: *access_type,
-                    },
-                    GraphResourceImportInfo::Buffer {
-                        resource,
-                        access_type,
-                    } => RegistryResource {
-                        resource: AnyRenderResource::ImportedBuffer(resource.clone()),
-                        access_type: *access_type,
-                    },
-                    GraphResourceImportInfo::RayTracingAcceleration {
-                        resource,
-                        access_type,
-                    } => RegistryResource {
-                        resource: AnyRenderResource::ImportedRayTracingAcceleration(
-                            resource.clone(),
-                        ),
-                        access_type: *access_type,
-                    },
-                    GraphResourceImportInfo::SwapchainImage => RegistryResource {
-                        resource: AnyRenderResource::Pending(PendingRenderResourceInfo {
-                            resource: resource.clone(),
-                        }),
-                        access_type: vk_sync::AccessType::ComputeShaderWrite,
+                    GraphResourceInfo::Imported(import_info) => match import_info {
+                        GraphResourceImportInfo::Image {
+                            resource,
+                            access_type,
+                        } => RegistryResource {
+                            resource: AnyRenderResource::ImportedImage(resource.clone()),
+                            access_type: *access_type,
+                        },
+                        GraphResourceImportInfo::Buffer {
+                            resource,
+                            access_type,
+                        } => RegistryResource {
+                            resource: AnyRenderResource::ImportedBuffer(resource.clone()),
+                            access_type: *access_type,
+                        },
+                        GraphResourceImportInfo::RayTracingAcceleration {
+                            resource,
+                            access_type,
+                        } => RegistryResource {
+                            resource: AnyRenderResource::ImportedRayTracingAcceleration(
+                                resource.clone(),
+                            ),
+                            access_type: *access_type,
+                        },
+                        GraphResourceImportInfo::SwapchainImage => RegistryResource {
+                            resource: AnyRenderResource::Pending(PendingRenderResourceInfo {
+                                resource: resource.clone(),
+                            }),
+                            access_type: vk_sync::AccessType::ComputeShaderWrite,
+                        },
                     },
-                },
-            })
-            .collect();
+                })
+                .collect();
 
         let resource_registry = ResourceRegistry {
             execution_params: params,
@@ -792,6 +943,7 @@ impl CompiledRenderGraph {
             passes: self.rg.passes.into(),
             resources: self.rg.resources,
             exported_resources: self.rg.exported_resources,
+            external_semaphores: self.rg.external_semaphores,
         }
     }
 }
@@ -800,10 +952,16 @@ pub struct ExecutingRenderGraph<'exec_params, 'constants> {
     passes: VecDeque<RecordedPass>,
     resources: Vec<GraphResourceInfo>,
     exported_resources: Vec<(ExportableGraphResource, vk_sync::AccessType)>,
+    external_semaphores: ExternalSemaphores,
     resource_registry: ResourceRegistry<'exec_params, 'constants>,
 }
 
 impl<'exec_params, 'constants> ExecutingRenderGraph<'exec_params, 'constants> {
+    /// For the submission of the command buffers: the semaphores of `import_with_sync`.
+    pub(crate) fn take_external_semaphores(&mut self) -> ExternalSemaphores {
+        std::mem::take(&mut self.external_semaphores)
+    }
+
     pub fn record_main_cb(&mut self, cb: &CommandBuffer) {
         let mut first_presentation_pass: usize = self.passes.len();
 
@@ -831,7 +989,7 @@ impl<'exec_params, 'constants> ExecutingRenderGraph<'exec_params, 'constants> {
     pub fn record_presentation_cb(
         mut self,
         cb: &CommandBuffer,
-        swapchain_image: Arc<Image>,
+        swapchain_image: Option<Arc<Image>>,
     ) -> RetiredRenderGraph {
         let params = &self.resource_registry.execution_params;
 
@@ -848,7 +1006,11 @@ impl<'exec_params, 'constants> ExecutingRenderGraph<'exec_params, 'constants> {
             if let AnyRenderResource::Pending(pending) = &mut res.resource {
                 match pending.resource {
                     GraphResourceInfo::Imported(GraphResourceImportInfo::SwapchainImage) => {
-                        res.resource = AnyRenderResource::ImportedImage(swapchain_image.clone());
+                        res.resource = AnyRenderResource::ImportedImage(
+                            swapchain_image
+                                .clone()
+                                .expect("The swapchain image can't be used in headless rendering"),
+                        );
                     }
                     _ => panic!("Only swapchain can be currently pending"),
                 }
@@ -1092,3 +1254,238 @@ impl RecordedPass {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kajiya_backend::vulkan::buffer::BufferDesc;
+
+    fn buffer_desc() -> BufferDesc {
+        BufferDesc::new_gpu_only(256, vk::BufferUsageFlags::STORAGE_BUFFER)
+    }
+
+    #[test]
+    fn in_place_update_is_a_single_general_access() {
+        let mut rg = RenderGraph::new();
+        let mut counter = rg.create(buffer_desc());
+
+        for _ in 0..2 {
+            let mut pass = rg.add_pass("increment");
+            let counter_ref = pass.read_write(&mut counter);
+            assert_eq!(counter_ref.handle.version, counter.raw.version + 1);
+        }
+
+        assert_eq!(rg.passes.len(), 2);
+        for pass in &rg.passes {
+            assert!(pass.read.is_empty());
+            assert_eq!(pass.write.len(), 1);
+            assert_eq!(pass.write[0].handle.id, counter.raw.id);
+            assert_eq!(
+                pass.write[0].access.access_type,
+                vk_sync::AccessType::General
+            );
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn read_write_conflicts_with_read() {
+        let mut rg = RenderGraph::new();
+        let mut counter = rg.create(buffer_desc());
+
+        let mut pass = rg.add_pass("increment");
+        pass.read(&counter, vk_sync::AccessType::ComputeShaderReadOther);
+        pass.read_write(&mut counter);
+    }
+
+    #[test]
+    fn clears_are_recorded_as_writes() {
+        let mut rg = RenderGraph::new();
+        let mut buf = rg.create(buffer_desc());
+        let mut img = rg.create(ImageDesc::new_2d(vk::Format::R16G16B16A16_SFLOAT, [64, 64]));
+
+        rg.clear_buffer(&mut buf, 0);
+        rg.clear_image(&mut img, crate::imageops::ClearValue::Color([0.0; 4]));
+
+        {
+            let mut pass = rg.add_pass("accumulate");
+            pass.read(&buf, vk_sync::AccessType::ComputeShaderReadOther);
+            pass.write(&mut img, vk_sync::AccessType::ComputeShaderWrite);
+        }
+
+        for (pass, handle) in rg.passes[..2].iter().zip([buf.raw.id, img.raw.id]) {
+            assert!(pass.read.is_empty());
+            assert_eq!(pass.write.len(), 1);
+            assert_eq!(pass.write[0].handle.id, handle);
+            assert_eq!(
+                pass.write[0].access.access_type,
+                vk_sync::AccessType::TransferWrite
+            );
+        }
+
+        // Passes touching the same resources are ordered after the clears
+        assert_eq!(rg.passes[2].read[0].handle.id, buf.raw.id);
+        assert_eq!(rg.passes[2].write[0].handle.id, img.raw.id);
+    }
+
+    #[test]
+    fn copy_and_blit_are_ordered_by_the_graph() {
+        let mut rg = RenderGraph::new();
+        let desc = ImageDesc::new_2d(vk::Format::R16G16B16A16_SFLOAT, [128, 64]);
+        let src = rg.create(desc);
+        let mut copy = rg.create(desc);
+        let mut half = rg.create(desc.extent([64, 32, 1]));
+
+        rg.copy_image(&src, &mut copy);
+        rg.blit_image(&copy, &mut half, vk::Filter::LINEAR);
+
+        let accesses = |pass: &RecordedPass| {
+            (
+                pass.read[0].handle.id,
+                pass.read[0].access.access_type,
+                pass.write[0].handle.id,
+                pass.write[0].access.access_type,
+            )
+        };
+
+        use vk_sync::AccessType::{TransferRead, TransferWrite};
+        assert_eq!(
+            accesses(&rg.passes[0]),
+            (src.raw.id, TransferRead, copy.raw.id, TransferWrite)
+        );
+        assert_eq!(
+            accesses(&rg.passes[1]),
+            (copy.raw.id, TransferRead, half.raw.id, TransferWrite)
+        );
+    }
+
+    #[test]
+    fn ping_pong_alternates_handles() {
+        let mut rg = RenderGraph::new();
+        let mut pp = crate::PingPong::new(&mut rg, buffer_desc());
+        let (a, b) = (pp.src().raw.id, pp.src_dst().1.raw.id);
+
+        for _ in 0..3 {
+            let mut pass = rg.add_pass("blur");
+            let (src, dst) = pp.src_dst();
+            pass.read(src, vk_sync::AccessType::ComputeShaderReadOther);
+            pass.write(dst, vk_sync::AccessType::ComputeShaderWrite);
+            drop(pass);
+            pp.swap();
+        }
+
+        let ids = |pass: &RecordedPass| (pass.read[0].handle.id, pass.write[0].handle.id);
+        assert_eq!(ids(&rg.passes[0]), (a, b));
+        assert_eq!(ids(&rg.passes[1]), (b, a));
+        assert_eq!(ids(&rg.passes[2]), (a, b));
+
+        // The last iteration wrote to `b`
+        assert_eq!(pp.into_src().raw.id, b);
+    }
+
+    #[test]
+    fn import_waits_for_and_signals_a_timeline_semaphore() {
+        use kajiya_backend::ash::vk::Handle as _;
+
+        let decoded_frames = vk::Semaphore::from_raw(1);
+        let desc = ImageDesc::new_2d(vk::Format::R8G8B8A8_UNORM, [1920, 1080])
+            .usage(vk::ImageUsageFlags::SAMPLED);
+        let video_frame = Arc::new(Image {
+            raw: vk::Image::null(),
+            desc,
+            views: Default::default(),
+        });
+
+        let mut rg = RenderGraph::new();
+        let frame = rg.import_with_sync(
+            video_frame,
+            vk_sync::AccessType::AnyShaderReadSampledImageOrUniformTexelBuffer,
+            ExternalSync {
+                // The decoder signals 7 once the frame is written, and reuses the image after 8.
+                wait: Some(ExternalSemaphore::timeline(decoded_frames, 7)),
+                signal: Some(ExternalSemaphore::timeline(decoded_frames, 8)),
+            },
+        );
+        assert_eq!(frame.desc().extent, desc.extent);
+
+        // Imports without sync don't add semaphores.
+        let other = rg.import(
+            Arc::new(Image {
+                raw: vk::Image::null(),
+                desc,
+                views: Default::default(),
+            }),
+            vk_sync::AccessType::Nothing,
+        );
+        assert_ne!(frame.raw.id, other.raw.id);
+
+        let (waits, wait_values) = submit_semaphores(rg.external_semaphores.waits.iter().copied());
+        assert_eq!(waits, [decoded_frames]);
+        assert_eq!(wait_values, [7]);
+
+        // Binary semaphores, like the swapchain's, can share the submission.
+        let rendering_finished = vk::Semaphore::from_raw(2);
+        let (signals, signal_values) = submit_semaphores(
+            std::iter::once(ExternalSemaphore::binary(rendering_finished))
+                .chain(rg.external_semaphores.signals.iter().copied()),
+        );
+        assert_eq!(signals, [rendering_finished, decoded_frames]);
+        assert_eq!(signal_values, [0, 8]);
+    }
+
+    #[test]
+    fn dropped_frames_pass_their_semaphores_on() {
+        use kajiya_backend::ash::vk::Handle as _;
+
+        let decoded_frames = vk::Semaphore::from_raw(1);
+        let uploaded = vk::Semaphore::from_raw(2);
+        let import = |rg: &mut RenderGraph, sync| {
+            rg.import_with_sync(
+                Arc::new(Image {
+                    raw: vk::Image::null(),
+                    desc: ImageDesc::new_2d(vk::Format::R8G8B8A8_UNORM, [1, 1]),
+                    views: Default::default(),
+                }),
+                vk_sync::AccessType::Nothing,
+                sync,
+            )
+        };
+
+        let mut dropped = RenderGraph::new();
+        import(
+            &mut dropped,
+            ExternalSync {
+                wait: Some(ExternalSemaphore::timeline(decoded_frames, 7)),
+                signal: Some(ExternalSemaphore::timeline(decoded_frames, 8)),
+            },
+        );
+        import(
+            &mut dropped,
+            ExternalSync {
+                wait: Some(ExternalSemaphore::binary(uploaded)),
+                signal: None,
+            },
+        );
+
+        let mut next = RenderGraph::new();
+        import(
+            &mut next,
+            ExternalSync {
+                wait: Some(ExternalSemaphore::timeline(decoded_frames, 9)),
+                signal: Some(ExternalSemaphore::timeline(decoded_frames, 10)),
+            },
+        );
+        next.carry_over_external_semaphores(std::mem::take(&mut dropped.external_semaphores));
+
+        // Each semaphore appears once, at the latest of its values.
+        let (waits, wait_values) =
+            submit_semaphores(next.external_semaphores.waits.iter().copied());
+        assert_eq!(waits, [decoded_frames, uploaded]);
+        assert_eq!(wait_values, [9, 0]);
+
+        let (signals, signal_values) =
+            submit_semaphores(next.external_semaphores.signals.iter().copied());
+        assert_eq!(signals, [decoded_frames]);
+        assert_eq!(signal_values, [10]);
+    }
+}
@@ -1,10 +1,12 @@
 use kajiya_backend::{
     ash::vk,
     dynamic_constants,
+    shader_variants::{ShaderFeatures, ShaderVariant},
     vk_sync::AccessType,
     vulkan::{
+        buffer::BufferDesc,
         image::*,
-        ray_tracing::{RayTracingAcceleration, RayTracingPipelineDesc},
+        ray_tracing::{HitShaderRecord, RayTracingAcceleration, RayTracingPipelineDesc},
         shader::{ComputePipelineDesc, PipelineShaderDesc, ShaderPipelineStage, ShaderSource},
     },
 };
@@ -12,8 +14,9 @@ use kajiya_backend::{
 use crate::Image;
 
 use super::{
-    BindRgRef, Buffer, GpuSrv, GpuUav, Handle, PassBuilder, Ref, RenderPassApi, RenderPassBinding,
-    Resource, RgComputePipelineHandle, RgRtPipelineHandle,
+    BindRgRef, Buffer, GpuSrv, GpuUav, Handle, PassBuilder, Ref, RenderGraph, RenderPassApi,
+    RenderPassBinding, Resource, ResourceDesc, RgComputePipelineHandle, RgRtPipelineHandle,
+    TemporalBufferSlice, TypeEquals, BINDLESS_DESCRIPTOR_SET_IDX,
 };
 
 pub trait ConstBlob {
@@ -52,8 +55,11 @@ where
 pub struct SimpleRenderPassState<RgPipelineHandle> {
     pipeline: RgPipelineHandle,
     bindings: Vec<RenderPassBinding>,
+    named_bindings: Vec<(&'static str, RenderPassBinding)>,
     const_blobs: Vec<(usize, Box<dyn ConstBlob>)>,
     raw_descriptor_sets: Vec<(u32, vk::DescriptorSet)>,
+    push_constants: Option<Vec<u8>>,
+    hit_records: Vec<HitShaderRecord>,
 }
 
 impl<RgPipelineHandle> SimpleRenderPassState<RgPipelineHandle>
@@ -64,8 +70,11 @@ where
         Self {
             pipeline,
             bindings: Vec::new(),
+            named_bindings: Vec::new(),
             const_blobs: Vec::new(),
             raw_descriptor_sets: Vec::new(),
+            push_constants: None,
+            hit_records: Vec::new(),
         }
     }
 
@@ -93,6 +102,10 @@ where
             .into_binding()
             .descriptor_set(0, &self.bindings);
 
+        for (name, binding) in self.named_bindings.drain(..) {
+            res = res.named_binding(name, binding);
+        }
+
         for &(set_idx, binding) in &self.raw_descriptor_sets {
             res = res.raw_descriptor_set(set_idx, binding);
         }
@@ -116,6 +129,21 @@ impl<'rg> SimpleRenderPass<'rg, RgComputePipelineHandle> {
         }
     }
 
+    /// Like `new_compute`, with `variant` of the shader's `features`; see `ShaderFeatures`.
+    pub fn new_compute_variant(
+        mut pass: PassBuilder<'rg>,
+        pipeline_path: &str,
+        features: &ShaderFeatures,
+        variant: &ShaderVariant,
+    ) -> Self {
+        let pipeline = pass.register_compute_pipeline_variant(pipeline_path, features, variant);
+
+        Self {
+            pass,
+            state: SimpleRenderPassState::new(pipeline),
+        }
+    }
+
     pub fn new_compute_rust(mut pass: PassBuilder<'rg>, entry_name: &str) -> Self {
         let pipeline = pass.register_compute_pipeline_with_desc(
             ComputePipelineDesc::builder()
@@ -130,38 +158,245 @@ impl<'rg> SimpleRenderPass<'rg, RgComputePipelineHandle> {
         }
     }
 
+    /// Uploads `consts` as the push constants of the pipeline. The size is checked
+    /// against the push constant range reflected from the shader.
+    ///
+    /// ```ignore
+    /// #[repr(C)]
+    /// #[derive(Clone, Copy)]
+    /// struct BlurConstants {
+    ///     direction: [f32; 2],
+    ///     radius: u32,
+    ///     _pad: u32,
+    /// }
+    ///
+    /// unsafe impl bytemuck::Zeroable for BlurConstants {}
+    /// unsafe impl bytemuck::Pod for BlurConstants {}
+    ///
+    /// SimpleRenderPass::new_compute(rg.add_pass("blur"), "/shaders/blur.hlsl")
+    ///     .read(&input)
+    ///     .write(&mut output)
+    ///     .push_constants(&BlurConstants { direction: [1.0, 0.0], radius: 4, _pad: 0 })
+    ///     .dispatch(output.desc().extent);
+    /// ```
+    pub fn push_constants<T: bytemuck::Pod>(mut self, consts: &T) -> Self {
+        self.state.push_constants = Some(bytemuck::bytes_of(consts).to_vec());
+        self
+    }
+
     pub fn dispatch(self, extent: [u32; 3]) {
         let mut state = self.state;
 
         self.pass.render(move |api| {
             state.patch_const_blobs(api);
 
-            let pipeline = api.bind_compute_pipeline(state.create_pipeline_binding());
+            let cb = api.cb.raw;
+            let pipeline = match api.bind_compute_pipeline(state.create_pipeline_binding()) {
+                Ok(pipeline) => pipeline,
+                Err(err) => {
+                    log::error!("Skipping dispatch: {}", err);
+                    return;
+                }
+            };
+
+            if let Some(push_constants) = &state.push_constants {
+                if let Err(err) = pipeline.push_constants(cb, 0, push_constants) {
+                    log::error!("Skipping dispatch: {}", err);
+                    return;
+                }
+            }
 
             pipeline.dispatch(extent);
         });
     }
 
+    /// Dispatches with workgroup counts read from a `VkDispatchIndirectCommand`
+    /// in `args_buffer`, typically written by a previous pass.
     pub fn dispatch_indirect(mut self, args_buffer: &Handle<Buffer>, args_buffer_offset: u64) {
+        validate_dispatch_indirect_args(args_buffer.desc(), args_buffer_offset)
+            .expect("dispatch_indirect");
+
         let args_buffer_ref = self.pass.read(args_buffer, AccessType::IndirectBuffer);
         let mut state = self.state;
 
         self.pass.render(move |api| {
             state.patch_const_blobs(api);
 
-            let pipeline = api.bind_compute_pipeline(state.create_pipeline_binding());
+            let cb = api.cb.raw;
+            let pipeline = match api.bind_compute_pipeline(state.create_pipeline_binding()) {
+                Ok(pipeline) => pipeline,
+                Err(err) => {
+                    log::error!("Skipping dispatch: {}", err);
+                    return;
+                }
+            };
+
+            if let Some(push_constants) = &state.push_constants {
+                if let Err(err) = pipeline.push_constants(cb, 0, push_constants) {
+                    log::error!("Skipping dispatch: {}", err);
+                    return;
+                }
+            }
 
             pipeline.dispatch_indirect(args_buffer_ref, args_buffer_offset);
         });
     }
+
+    /// Records one indirect dispatch per offset into `args_buffer`, sharing the pipeline
+    /// and bindings. Useful when a culling pass produces several batches of work.
+    pub fn dispatch_indirect_multi(
+        mut self,
+        args_buffer: &Handle<Buffer>,
+        args_buffer_offsets: &[u64],
+    ) {
+        for &offset in args_buffer_offsets {
+            validate_dispatch_indirect_args(args_buffer.desc(), offset)
+                .expect("dispatch_indirect_multi");
+        }
+
+        let args_buffer_ref = self.pass.read(args_buffer, AccessType::IndirectBuffer);
+        let args_buffer_offsets = args_buffer_offsets.to_vec();
+        let mut state = self.state;
+
+        self.pass.render(move |api| {
+            state.patch_const_blobs(api);
+
+            let cb = api.cb.raw;
+            let pipeline = match api.bind_compute_pipeline(state.create_pipeline_binding()) {
+                Ok(pipeline) => pipeline,
+                Err(err) => {
+                    log::error!("Skipping dispatch: {}", err);
+                    return;
+                }
+            };
+
+            if let Some(push_constants) = &state.push_constants {
+                if let Err(err) = pipeline.push_constants(cb, 0, push_constants) {
+                    log::error!("Skipping dispatch: {}", err);
+                    return;
+                }
+            }
+
+            for offset in args_buffer_offsets {
+                pipeline.dispatch_indirect(args_buffer_ref, offset);
+            }
+        });
+    }
+}
+
+fn validate_dispatch_indirect_args(desc: &BufferDesc, offset: u64) -> anyhow::Result<()> {
+    let args_size = std::mem::size_of::<vk::DispatchIndirectCommand>() as u64;
+
+    anyhow::ensure!(
+        offset % 4 == 0,
+        "The args buffer offset {} must be a multiple of 4",
+        offset
+    );
+    anyhow::ensure!(
+        offset + args_size <= desc.size as u64,
+        "Dispatch args at offset {} don't fit in the {} byte args buffer",
+        offset,
+        desc.size
+    );
+
+    Ok(())
+}
+
+/// Shaders run for ray hits on triangles, or on procedural geometry with an intersection shader,
+/// at one index of the hit shader binding table.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct RayHitGroup {
+    pub closest_hit: Option<ShaderSource>,
+    pub any_hit: Option<ShaderSource>,
+    pub intersection: Option<ShaderSource>,
+
+    /// Written after the group's handle in the shader binding table; see
+    /// `PipelineShaderDesc::shader_record_data`. All shaders of the group see the same data.
+    pub shader_record_data: Vec<u8>,
+}
+
+impl RayHitGroup {
+    pub fn new(closest_hit: ShaderSource) -> Self {
+        Self {
+            closest_hit: Some(closest_hit),
+            any_hit: None,
+            intersection: None,
+            shader_record_data: Vec::new(),
+        }
+    }
+
+    /// For instances of procedural geometry, whose BLAS holds boxes instead of triangles.
+    /// The intersection shader finds hits within the boxes, which the closest-hit and any-hit
+    /// shaders then handle as usual. Without those, hits are simply accepted, as shadow rays want.
+    pub fn procedural(intersection: ShaderSource) -> Self {
+        Self {
+            closest_hit: None,
+            any_hit: None,
+            intersection: Some(intersection),
+            shader_record_data: Vec::new(),
+        }
+    }
+
+    pub fn closest_hit(mut self, closest_hit: ShaderSource) -> Self {
+        self.closest_hit = Some(closest_hit);
+        self
+    }
+
+    /// For rays which skip closest-hit shaders, like shadow rays.
+    ///
+    /// Can't directly follow a group with only a closest-hit shader, as the any-hit shader
+    /// would join that group instead.
+    pub fn any_hit_only(any_hit: ShaderSource) -> Self {
+        Self {
+            closest_hit: None,
+            any_hit: Some(any_hit),
+            intersection: None,
+            shader_record_data: Vec::new(),
+        }
+    }
+
+    /// Runs on every candidate hit of non-opaque instances, and can reject it with `IgnoreHit`.
+    pub fn any_hit(mut self, any_hit: ShaderSource) -> Self {
+        self.any_hit = Some(any_hit);
+        self
+    }
+
+    pub fn shader_record_data(mut self, data: Vec<u8>) -> Self {
+        self.shader_record_data = data;
+        self
+    }
+}
+
+impl From<ShaderSource> for RayHitGroup {
+    fn from(closest_hit: ShaderSource) -> Self {
+        Self::new(closest_hit)
+    }
 }
 
 impl<'rg> SimpleRenderPass<'rg, RgRtPipelineHandle> {
+    /// Traces rays iteratively from the raygen shader, with a max recursion depth of 1.
     pub fn new_rt(
+        pass: PassBuilder<'rg>,
+        rgen: ShaderSource,
+        miss: impl IntoIterator<Item = ShaderSource>,
+        hit: impl IntoIterator<Item = impl Into<RayHitGroup>>,
+    ) -> Self {
+        Self::new_rt_with_desc(
+            pass,
+            rgen,
+            miss,
+            hit,
+            RayTracingPipelineDesc::default().max_pipeline_ray_recursion_depth(1),
+        )
+    }
+
+    /// For recursive tracers, which call `TraceRayEXT` from hit or miss shaders.
+    pub fn new_rt_with_desc(
         mut pass: PassBuilder<'rg>,
         rgen: ShaderSource,
         miss: impl IntoIterator<Item = ShaderSource>,
-        hit: impl IntoIterator<Item = ShaderSource>,
+        hit: impl IntoIterator<Item = impl Into<RayHitGroup>>,
+        desc: RayTracingPipelineDesc,
     ) -> Self {
         let miss = miss.into_iter();
         let hit = hit.into_iter();
@@ -183,19 +418,56 @@ impl<'rg> SimpleRenderPass<'rg, RgRtPipelineHandle> {
             );
         }
 
-        for source in hit {
-            shaders.push(
-                PipelineShaderDesc::builder(ShaderPipelineStage::RayClosestHit)
-                    .source(source)
-                    .build()
-                    .unwrap(),
-            );
+        // Groups are told apart by the stages of their shaders; see `ray_tracing_shader_groups`
+        let mut prev_group: Option<RayHitGroup> = None;
+        for group in hit {
+            let group: RayHitGroup = group.into();
+
+            if let (Some(prev), None) = (&prev_group, &group.intersection) {
+                let prev_is_open_to_any_hit = prev.any_hit.is_none()
+                    && (prev.closest_hit.is_some() || prev.intersection.is_some());
+                assert!(
+                    !(prev_is_open_to_any_hit && group.closest_hit.is_none()),
+                    "An any-hit-only group can't follow one without an any-hit shader"
+                );
+                assert!(
+                    !(prev.intersection.is_some()
+                        && prev.closest_hit.is_none()
+                        && prev.any_hit.is_none()),
+                    "A triangle hit group can't follow a procedural one with only an intersection shader"
+                );
+            }
+
+            let stages = [
+                (
+                    ShaderPipelineStage::RayIntersection,
+                    group.intersection.clone(),
+                ),
+                (
+                    ShaderPipelineStage::RayClosestHit,
+                    group.closest_hit.clone(),
+                ),
+                (ShaderPipelineStage::RayAnyHit, group.any_hit.clone()),
+            ];
+
+            // The group's record data is that of its first shader
+            let mut shader_record_data = Some(group.shader_record_data.clone());
+            prev_group = Some(group);
+
+            for (stage, source) in stages {
+                if let Some(source) = source {
+                    shaders.push(
+                        PipelineShaderDesc::builder(stage)
+                            .source(source)
+                            .shader_record_data(shader_record_data.take().unwrap_or_default())
+                            .build()
+                            .unwrap(),
+                    );
+                }
+            }
         }
 
-        let pipeline = pass.register_ray_tracing_pipeline(
-            &shaders,
-            RayTracingPipelineDesc::default().max_pipeline_ray_recursion_depth(1),
-        );
+        let pipeline = pass.register_ray_tracing_pipeline(&shaders, desc);
 
         Self {
             pass,
@@ -203,6 +475,13 @@ impl<'rg> SimpleRenderPass<'rg, RgRtPipelineHandle> {
         }
     }
 
+    /// Appends `records` to the hit table, after the records of the hit groups; instances
+    /// select them with their SBT offset. See `HitShaderRecord`.
+    pub fn hit_records(mut self, records: Vec<HitShaderRecord>) -> Self {
+        self.state.hit_records = records;
+        self
+    }
+
     pub fn trace_rays(mut self, tlas: &Handle<RayTracingAcceleration>, extent: [u32; 3]) {
         let tlas_ref = self.pass.read(tlas, AccessType::AnyShaderReadOther);
         let mut state = self.state;
@@ -210,11 +489,19 @@ impl<'rg> SimpleRenderPass<'rg, RgRtPipelineHandle> {
         self.pass.render(move |api| {
             state.patch_const_blobs(api);
 
-            let pipeline = api.bind_ray_tracing_pipeline(
+            let hit_records = std::mem::take(&mut state.hit_records);
+            let pipeline = match api.bind_ray_tracing_pipeline(
                 state
                     .create_pipeline_binding()
-                    .descriptor_set(3, &[tlas_ref.bind()]),
-            );
+                    .descriptor_set(3, &[tlas_ref.bind()])
+                    .hit_records(&hit_records),
+            ) {
+                Ok(pipeline) => pipeline,
+                Err(err) => {
+                    log::error!("Skipping trace: {}", err);
+                    return;
+                }
+            };
 
             pipeline.trace_rays(extent);
         });
@@ -233,11 +520,19 @@ impl<'rg> SimpleRenderPass<'rg, RgRtPipelineHandle> {
         self.pass.render(move |api| {
             state.patch_const_blobs(api);
 
-            let pipeline = api.bind_ray_tracing_pipeline(
+            let hit_records = std::mem::take(&mut state.hit_records);
+            let pipeline = match api.bind_ray_tracing_pipeline(
                 state
                     .create_pipeline_binding()
-                    .descriptor_set(3, &[tlas_ref.bind()]),
-            );
+                    .descriptor_set(3, &[tlas_ref.bind()])
+                    .hit_records(&hit_records),
+            ) {
+                Ok(pipeline) => pipeline,
+                Err(err) => {
+                    log::error!("Skipping trace: {}", err);
+                    return;
+                }
+            };
 
             pipeline.trace_rays_indirect(args_buffer_ref, args_buffer_offset);
         });
@@ -318,6 +613,19 @@ impl<'rg, RgPipelineHandle> SimpleRenderPass<'rg, RgPipelineHandle> {
         self
     }
 
+    /// Binds a resource for in-place updates. See `PassBuilder::read_write`.
+    pub fn read_write<Res>(mut self, handle: &mut Handle<Res>) -> Self
+    where
+        Res: Resource + 'static,
+        Ref<Res, GpuUav>: BindRgRef,
+    {
+        let handle_ref = self.pass.read_write(handle);
+
+        self.state.bindings.push(BindRgRef::bind(&handle_ref));
+
+        self
+    }
+
     pub fn write_view(
         mut self,
         handle: &mut Handle<Image>,
@@ -365,8 +673,144 @@ impl<'rg, RgPipelineHandle> SimpleRenderPass<'rg, RgPipelineHandle> {
         self
     }
 
+    /// Binds the bytes of a `TemporalBufferSlice`.
+    pub fn read_slice(mut self, slice: &TemporalBufferSlice) -> Self {
+        let handle_ref = self
+            .pass
+            .read(&slice.buffer, AccessType::AnyShaderReadOther);
+
+        self.state
+            .bindings
+            .push(handle_ref.bind_range(slice.offset, slice.size));
+
+        self
+    }
+
+    /// Like `read_slice`, but for slices the shader writes to.
+    pub fn write_slice(mut self, slice: &mut TemporalBufferSlice) -> Self {
+        let handle_ref = self
+            .pass
+            .write(&mut slice.buffer, AccessType::AnyShaderWrite);
+
+        self.state
+            .bindings
+            .push(handle_ref.bind_range(slice.offset, slice.size));
+
+        self
+    }
+
+    /// Binds a buffer to the shader variable called `name`, resolved via shader reflection.
+    pub fn storage_buffer(mut self, name: &'static str, handle: &Handle<Buffer>) -> Self {
+        let handle_ref = self.pass.read(handle, AccessType::AnyShaderReadOther);
+
+        self.state
+            .named_bindings
+            .push((name, BindRgRef::bind(&handle_ref)));
+
+        self
+    }
+
+    /// Like `storage_buffer`, but for buffers the shader writes to.
+    pub fn storage_buffer_mut(mut self, name: &'static str, handle: &mut Handle<Buffer>) -> Self {
+        let handle_ref = self.pass.write(handle, AccessType::AnyShaderWrite);
+
+        self.state
+            .named_bindings
+            .push((name, BindRgRef::bind(&handle_ref)));
+
+        self
+    }
+
+    pub fn bindless(mut self, set: vk::DescriptorSet) -> Self {
+        self.state
+            .raw_descriptor_sets
+            .push((BINDLESS_DESCRIPTOR_SET_IDX, set));
+        self
+    }
+
     pub fn raw_descriptor_set(mut self, set_idx: u32, set: vk::DescriptorSet) -> Self {
         self.state.raw_descriptor_sets.push((set_idx, set));
         self
     }
 }
+
+/// A pair of resources for iterative passes which read the previous state and write the next,
+/// such as à-trous filters.
+///
+/// Each iteration is a separate pass reading `src` and writing `dst`; the graph inserts
+/// the barriers between them as for any other pair of passes. Call `swap` after each iteration.
+///
+/// ```ignore
+/// let mut pp = PingPong::new(rg, desc);
+/// for _ in 0..4 {
+///     let (src, dst) = pp.src_dst();
+///     SimpleRenderPass::new_compute(rg.add_pass("atrous"), "/shaders/atrous.hlsl")
+///         .read(src)
+///         .write(dst)
+///         .dispatch(desc.extent);
+///     pp.swap();
+/// }
+/// let filtered = pp.into_src();
+/// ```
+pub struct PingPong<Res: Resource> {
+    src: Handle<Res>,
+    dst: Handle<Res>,
+}
+
+impl<Res: Resource> PingPong<Res> {
+    pub fn new<Desc>(rg: &mut RenderGraph, desc: Desc) -> Self
+    where
+        Desc: ResourceDesc<Resource = Res> + TypeEquals<Other = <Res as Resource>::Desc>,
+    {
+        Self {
+            src: rg.create(desc.clone()),
+            dst: rg.create(desc),
+        }
+    }
+
+    /// Starts from existing contents in `src`; `dst` gets overwritten by the first iteration.
+    pub fn from_handles(src: Handle<Res>, dst: Handle<Res>) -> Self {
+        Self { src, dst }
+    }
+
+    /// The result of the previous iteration.
+    pub fn src(&self) -> &Handle<Res> {
+        &self.src
+    }
+
+    pub fn src_dst(&mut self) -> (&Handle<Res>, &mut Handle<Res>) {
+        (&self.src, &mut self.dst)
+    }
+
+    pub fn swap(&mut self) {
+        std::mem::swap(&mut self.src, &mut self.dst);
+    }
+
+    /// The result of the last iteration.
+    pub fn into_src(self) -> Handle<Res> {
+        self.src
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dispatch_indirect_args_bounds() {
+        // Three dispatches, e.g. written by a GPU culling pass. The graph adds the
+        // `INDIRECT_BUFFER` usage flag based on how the buffer is accessed.
+        let desc = BufferDesc::new_gpu_only(
+            3 * std::mem::size_of::<vk::DispatchIndirectCommand>(),
+            vk::BufferUsageFlags::STORAGE_BUFFER,
+        );
+
+        for offset in [0, 12, 24] {
+            assert!(validate_dispatch_indirect_args(&desc, offset).is_ok());
+        }
+
+        assert!(validate_dispatch_indirect_args(&desc, 26).is_err());
+        assert!(validate_dispatch_indirect_args(&desc, 28).is_err());
+        assert!(validate_dispatch_indirect_args(&desc, 36).is_err());
+    }
+}
@@ -1,8 +1,21 @@
 use crate::{self as rg, RenderGraph};
-use kajiya_backend::{ash::vk, vk_sync::AccessType, vulkan::image::*};
+use kajiya_backend::{
+    ash::vk,
+    vk_sync::AccessType,
+    vulkan::{barrier::image_aspect_mask_from_format, image::*},
+};
 
-pub fn clear_depth(rg: &mut RenderGraph, img: &mut rg::Handle<Image>) {
-    let mut pass = rg.add_pass("clear depth");
+#[derive(Clone, Copy, Debug)]
+pub enum ClearValue {
+    Color([f32; 4]),
+    ColorUint([u32; 4]),
+    DepthStencil { depth: f32, stencil: u32 },
+}
+
+/// Clears all mips and array layers of `img`. Recorded as a transfer write,
+/// so passes reading the image afterwards are ordered after the clear.
+pub fn clear_image(rg: &mut RenderGraph, img: &mut rg::Handle<Image>, value: ClearValue) {
+    let mut pass = rg.add_pass("clear image");
     let output_ref = pass.write(img, AccessType::TransferWrite);
 
     pass.render(move |api| {
@@ -11,51 +24,269 @@ pub fn clear_depth(rg: &mut RenderGraph, img: &mut rg::Handle<Image>) {
 
         let image = api.resources.image(output_ref);
 
+        let range = vk::ImageSubresourceRange {
+            aspect_mask: image_aspect_mask_from_format(image.desc.format),
+            base_mip_level: 0,
+            level_count: vk::REMAINING_MIP_LEVELS,
+            base_array_layer: 0,
+            layer_count: vk::REMAINING_ARRAY_LAYERS,
+        };
+
+        unsafe {
+            match value {
+                ClearValue::Color(float32) => raw_device.cmd_clear_color_image(
+                    cb.raw,
+                    image.raw,
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    &vk::ClearColorValue { float32 },
+                    std::slice::from_ref(&range),
+                ),
+                ClearValue::ColorUint(uint32) => raw_device.cmd_clear_color_image(
+                    cb.raw,
+                    image.raw,
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    &vk::ClearColorValue { uint32 },
+                    std::slice::from_ref(&range),
+                ),
+                ClearValue::DepthStencil { depth, stencil } => raw_device
+                    .cmd_clear_depth_stencil_image(
+                        cb.raw,
+                        image.raw,
+                        vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                        &vk::ClearDepthStencilValue { depth, stencil },
+                        std::slice::from_ref(&range),
+                    ),
+            }
+        }
+    });
+}
+
+/// Clears a depth target to zero, which is the far plane with reverse Z.
+pub fn clear_depth(rg: &mut RenderGraph, img: &mut rg::Handle<Image>) {
+    clear_image(
+        rg,
+        img,
+        ClearValue::DepthStencil {
+            depth: 0.0,
+            stencil: 0,
+        },
+    );
+}
+
+pub fn clear_color(rg: &mut RenderGraph, img: &mut rg::Handle<Image>, clear_color: [f32; 4]) {
+    clear_image(rg, img, ClearValue::Color(clear_color));
+}
+
+/// Copies all mips and array layers of `src` into `dst`. The images must have
+/// the same format, extent, mip count and array layer count.
+pub fn copy_image(rg: &mut RenderGraph, src: &rg::Handle<Image>, dst: &mut rg::Handle<Image>) {
+    if let Err(err) = validate_copy(src.desc(), dst.desc()) {
+        panic!("copy_image: {}", err);
+    }
+
+    let mut pass = rg.add_pass("copy image");
+    let input_ref = pass.read(src, AccessType::TransferRead);
+    let output_ref = pass.write(dst, AccessType::TransferWrite);
+
+    pass.render(move |api| {
+        let raw_device = &api.device().raw;
+        let cb = api.cb;
+
+        let src = api.resources.image(input_ref);
+        let dst = api.resources.image(output_ref);
+
+        let regions = (0..src.desc.mip_levels as u32)
+            .map(|mip_level| {
+                let subresource = vk::ImageSubresourceLayers {
+                    aspect_mask: image_aspect_mask_from_format(src.desc.format),
+                    mip_level,
+                    base_array_layer: 0,
+                    layer_count: src.desc.array_elements,
+                };
+
+                let [width, height, depth] = src.desc.extent;
+
+                vk::ImageCopy {
+                    src_subresource: subresource,
+                    src_offset: vk::Offset3D::default(),
+                    dst_subresource: subresource,
+                    dst_offset: vk::Offset3D::default(),
+                    extent: vk::Extent3D {
+                        width: (width >> mip_level).max(1),
+                        height: (height >> mip_level).max(1),
+                        depth: (depth >> mip_level).max(1),
+                    },
+                }
+            })
+            .collect::<Vec<_>>();
+
         unsafe {
-            raw_device.cmd_clear_depth_stencil_image(
+            raw_device.cmd_copy_image(
                 cb.raw,
-                image.raw,
+                src.raw,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                dst.raw,
                 vk::ImageLayout::TRANSFER_DST_OPTIMAL,
-                &vk::ClearDepthStencilValue {
-                    depth: 0f32,
-                    stencil: 0,
-                },
-                std::slice::from_ref(&vk::ImageSubresourceRange {
-                    aspect_mask: vk::ImageAspectFlags::DEPTH,
-                    level_count: 1,
-                    layer_count: 1,
-                    ..Default::default()
-                }),
+                &regions,
             );
         }
     });
 }
 
-pub fn clear_color(rg: &mut RenderGraph, img: &mut rg::Handle<Image>, clear_color: [f32; 4]) {
-    let mut pass = rg.add_pass("clear color");
-    let output_ref = pass.write(img, AccessType::TransferWrite);
+/// Copies the top mip of `src` into the top mip of `dst`, converting between formats
+/// and resampling with `filter` if the extents differ.
+pub fn blit_image(
+    rg: &mut RenderGraph,
+    src: &rg::Handle<Image>,
+    dst: &mut rg::Handle<Image>,
+    filter: vk::Filter,
+) {
+    if let Err(err) = validate_blit(src.desc(), dst.desc(), filter) {
+        panic!("blit_image: {}", err);
+    }
+
+    let mut pass = rg.add_pass("blit image");
+    let input_ref = pass.read(src, AccessType::TransferRead);
+    let output_ref = pass.write(dst, AccessType::TransferWrite);
 
     pass.render(move |api| {
         let raw_device = &api.device().raw;
         let cb = api.cb;
 
-        let image = api.resources.image(output_ref);
+        let src = api.resources.image(input_ref);
+        let dst = api.resources.image(output_ref);
+
+        let subresource = |desc: &ImageDesc| vk::ImageSubresourceLayers {
+            aspect_mask: image_aspect_mask_from_format(desc.format),
+            mip_level: 0,
+            base_array_layer: 0,
+            layer_count: desc.array_elements,
+        };
+
+        let corner = |extent: [u32; 3]| vk::Offset3D {
+            x: extent[0] as i32,
+            y: extent[1] as i32,
+            z: extent[2].max(1) as i32,
+        };
 
         unsafe {
-            raw_device.cmd_clear_color_image(
+            raw_device.cmd_blit_image(
                 cb.raw,
-                image.raw,
+                src.raw,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                dst.raw,
                 vk::ImageLayout::TRANSFER_DST_OPTIMAL,
-                &vk::ClearColorValue {
-                    float32: clear_color,
-                },
-                std::slice::from_ref(&vk::ImageSubresourceRange {
-                    aspect_mask: vk::ImageAspectFlags::COLOR,
-                    level_count: 1,
-                    layer_count: 1,
-                    ..Default::default()
-                }),
+                &[vk::ImageBlit {
+                    src_subresource: subresource(&src.desc),
+                    src_offsets: [vk::Offset3D::default(), corner(src.desc.extent)],
+                    dst_subresource: subresource(&dst.desc),
+                    dst_offsets: [vk::Offset3D::default(), corner(dst.desc.extent)],
+                }],
+                filter,
             );
         }
     });
 }
+
+/// Copies `src` into `dst` with linear filtering, resampling if the extents differ.
+pub fn blit_rescale(rg: &mut RenderGraph, src: &rg::Handle<Image>, dst: &mut rg::Handle<Image>) {
+    blit_image(rg, src, dst, vk::Filter::LINEAR);
+}
+
+fn validate_copy(src: &ImageDesc, dst: &ImageDesc) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        src.format == dst.format,
+        "format mismatch: {:?} vs {:?}",
+        src.format,
+        dst.format
+    );
+    anyhow::ensure!(
+        src.extent == dst.extent,
+        "extent mismatch: {:?} vs {:?}",
+        src.extent,
+        dst.extent
+    );
+    anyhow::ensure!(
+        src.mip_levels == dst.mip_levels && src.array_elements == dst.array_elements,
+        "subresource mismatch: {} mips x {} layers vs {} mips x {} layers",
+        src.mip_levels,
+        src.array_elements,
+        dst.mip_levels,
+        dst.array_elements
+    );
+
+    Ok(())
+}
+
+fn validate_blit(src: &ImageDesc, dst: &ImageDesc, filter: vk::Filter) -> anyhow::Result<()> {
+    let is_depth_stencil = |desc: &ImageDesc| {
+        !(image_aspect_mask_from_format(desc.format)
+            & (vk::ImageAspectFlags::DEPTH | vk::ImageAspectFlags::STENCIL))
+            .is_empty()
+    };
+
+    if is_depth_stencil(src) || is_depth_stencil(dst) {
+        anyhow::ensure!(
+            src.format == dst.format,
+            "depth/stencil images can only be blitted to the same format"
+        );
+        anyhow::ensure!(
+            filter == vk::Filter::NEAREST,
+            "depth/stencil images can only be blitted with nearest filtering"
+        );
+    }
+
+    anyhow::ensure!(
+        src.array_elements == dst.array_elements,
+        "array layer count mismatch: {} vs {}",
+        src.array_elements,
+        dst.array_elements
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn copy_requires_matching_images() {
+        let desc = ImageDesc::new_2d(vk::Format::R16G16B16A16_SFLOAT, [128, 64]).all_mip_levels();
+
+        assert!(validate_copy(&desc, &desc).is_ok());
+        assert!(validate_copy(&desc, &desc.format(vk::Format::R32G32B32A32_SFLOAT)).is_err());
+        assert!(validate_copy(&desc, &desc.extent([64, 64, 1])).is_err());
+        assert!(validate_copy(&desc, &desc.mip_levels(1)).is_err());
+    }
+
+    #[test]
+    fn blit_converts_color_but_not_depth() {
+        let color = ImageDesc::new_2d(vk::Format::R16G16B16A16_SFLOAT, [128, 64]);
+        let depth = ImageDesc::new_2d(vk::Format::D32_SFLOAT, [128, 64]);
+
+        assert!(validate_blit(
+            &color,
+            &color
+                .format(vk::Format::B8G8R8A8_UNORM)
+                .extent([256, 128, 1]),
+            vk::Filter::LINEAR
+        )
+        .is_ok());
+
+        assert!(validate_blit(&depth, &depth.extent([64, 32, 1]), vk::Filter::NEAREST).is_ok());
+        assert!(validate_blit(&depth, &depth, vk::Filter::LINEAR).is_err());
+        assert!(validate_blit(&depth, &color, vk::Filter::NEAREST).is_err());
+    }
+
+    #[test]
+    #[should_panic]
+    fn copy_panics_on_mismatch() {
+        let mut rg = RenderGraph::new();
+        let desc = ImageDesc::new_2d(vk::Format::R16G16B16A16_SFLOAT, [128, 64]);
+        let src = rg.create(desc);
+        let mut dst = rg.create(desc.extent([64, 32, 1]));
+
+        rg.copy_image(&src, &mut dst);
+    }
+}
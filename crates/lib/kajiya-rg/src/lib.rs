@@ -1,3 +1,4 @@
+mod buffer_slices;
 mod graph;
 mod hl;
 mod pass_api;
@@ -6,9 +7,13 @@ mod resource;
 mod resource_registry;
 mod temporal;
 
+pub mod bufferops;
+pub mod feedback;
 pub mod imageops;
+pub mod readback;
 pub mod renderer;
 
+pub use buffer_slices::*;
 pub use graph::*;
 pub use hl::*;
 pub use pass_api::*;
@@ -1,10 +1,11 @@
-use std::{cell::UnsafeCell, sync::Arc};
+use std::{cell::UnsafeCell, collections::BTreeMap, sync::Arc};
 
 use arrayvec::ArrayVec;
 
 use super::{
     Buffer, GpuRt, GpuSrv, GpuUav, GraphRawResourceHandle, Image, Ref, ResourceRegistry,
     RgComputePipelineHandle, RgRasterPipelineHandle, RgRtPipelineHandle,
+    BINDLESS_DESCRIPTOR_SET_IDX,
 };
 
 use kajiya_backend::{
@@ -14,15 +15,19 @@ use kajiya_backend::{
         DynamicConstants, MAX_DYNAMIC_CONSTANTS_BYTES_PER_DISPATCH,
         MAX_DYNAMIC_CONSTANTS_STORAGE_BUFFER_BYTES,
     },
+    rspirv_reflect,
     vulkan::{
         device::{CommandBuffer, Device},
         image::*,
-        ray_tracing::{RayTracingAcceleration, RayTracingPipeline},
+        ray_tracing::{
+            HitShaderRecord, RayTracingAcceleration, RayTracingPipeline, RayTracingShaderTable,
+        },
         shader::{
             ComputePipeline, FramebufferCacheKey, RasterPipeline, ShaderPipelineCommon,
             MAX_COLOR_ATTACHMENTS,
         },
     },
+    KajiyaError,
 };
 
 pub struct RenderPassApi<'a, 'exec_params, 'constants> {
@@ -49,12 +54,14 @@ pub enum DescriptorSetBinding {
 pub struct RenderPassCommonShaderPipelineBinding<'a> {
     // TODO: fixed size
     bindings: Vec<(u32, &'a [RenderPassBinding])>,
+    named_bindings: Vec<(&'a str, RenderPassBinding)>,
     raw_bindings: Vec<(u32, vk::DescriptorSet)>,
 }
 
 pub struct RenderPassPipelineBinding<'a, HandleType> {
     pipeline: HandleType,
     binding: RenderPassCommonShaderPipelineBinding<'a>,
+    hit_records: &'a [HitShaderRecord],
 }
 
 impl<'a, HandleType> RenderPassPipelineBinding<'a, HandleType> {
@@ -62,6 +69,7 @@ impl<'a, HandleType> RenderPassPipelineBinding<'a, HandleType> {
         Self {
             pipeline,
             binding: Default::default(),
+            hit_records: &[],
         }
     }
 
@@ -74,12 +82,46 @@ impl<'a, HandleType> RenderPassPipelineBinding<'a, HandleType> {
         self.binding.raw_bindings.push((set_idx, binding));
         self
     }
+
+    /// Binds the bindless descriptor set at `BINDLESS_DESCRIPTOR_SET_IDX`.
+    pub fn bindless(self, set: vk::DescriptorSet) -> Self {
+        self.raw_descriptor_set(BINDLESS_DESCRIPTOR_SET_IDX, set)
+    }
+
+    /// Binds a resource to the shader variable called `name`. The set and binding indices
+    /// are resolved from shader reflection when the pipeline is bound.
+    pub fn named_binding(mut self, name: &'a str, binding: RenderPassBinding) -> Self {
+        self.binding.named_bindings.push((name, binding));
+        self
+    }
+
+    /// Like `named_binding`, but for buffers and dynamic storage buffers only.
+    pub fn storage_buffer(self, name: &'a str, binding: RenderPassBinding) -> Self {
+        assert!(
+            matches!(
+                binding,
+                RenderPassBinding::Buffer(_) | RenderPassBinding::DynamicConstantsStorageBuffer(_)
+            ),
+            "storage_buffer({:?}) requires a buffer binding",
+            name
+        );
+
+        self.named_binding(name, binding)
+    }
 }
 
 pub trait IntoRenderPassPipelineBinding: Sized {
     fn into_binding<'a>(self) -> RenderPassPipelineBinding<'a, Self>;
 }
 
+impl<'a> RenderPassPipelineBinding<'a, RgRtPipelineHandle> {
+    /// Traces with `records` appended to the hit table; see `HitShaderRecord`.
+    pub fn hit_records(mut self, records: &'a [HitShaderRecord]) -> Self {
+        self.hit_records = records;
+        self
+    }
+}
+
 impl IntoRenderPassPipelineBinding for RgComputePipelineHandle {
     fn into_binding<'a>(self) -> RenderPassPipelineBinding<'a, Self> {
         RenderPassPipelineBinding::new(self)
@@ -110,46 +152,53 @@ impl<'a, 'exec_params, 'constants> RenderPassApi<'a, 'exec_params, 'constants> {
     pub fn bind_compute_pipeline<'s>(
         &'s mut self,
         binding: RenderPassPipelineBinding<'_, RgComputePipelineHandle>,
-    ) -> BoundComputePipeline<'s, 'a, 'exec_params, 'constants> {
+    ) -> Result<BoundComputePipeline<'s, 'a, 'exec_params, 'constants>, KajiyaError> {
         let device = self.resources.execution_params.device;
         let pipeline_arc = self.resources.compute_pipeline(binding.pipeline);
 
-        self.bind_pipeline_common(device, pipeline_arc.as_ref(), &binding.binding);
+        self.bind_pipeline_common(device, pipeline_arc.as_ref(), &binding.binding)?;
 
-        BoundComputePipeline {
+        Ok(BoundComputePipeline {
             api: self,
             pipeline: pipeline_arc,
-        }
+        })
     }
 
     pub fn bind_raster_pipeline<'s>(
         &'s self,
         binding: RenderPassPipelineBinding<'_, RgRasterPipelineHandle>,
-    ) -> BoundRasterPipeline<'s, 'a, 'exec_params, 'constants> {
+    ) -> Result<BoundRasterPipeline<'s, 'a, 'exec_params, 'constants>, KajiyaError> {
         let device = self.resources.execution_params.device;
         let pipeline_arc = self.resources.raster_pipeline(binding.pipeline);
 
-        self.bind_pipeline_common(device, pipeline_arc.as_ref(), &binding.binding);
+        self.bind_pipeline_common(device, pipeline_arc.as_ref(), &binding.binding)?;
 
-        BoundRasterPipeline {
+        Ok(BoundRasterPipeline {
             api: self,
             pipeline: pipeline_arc,
-        }
+        })
     }
 
     pub fn bind_ray_tracing_pipeline<'s>(
         &'s mut self,
         binding: RenderPassPipelineBinding<'_, RgRtPipelineHandle>,
-    ) -> BoundRayTracingPipeline<'s, 'a, 'exec_params, 'constants> {
+    ) -> Result<BoundRayTracingPipeline<'s, 'a, 'exec_params, 'constants>, KajiyaError> {
         let device = self.resources.execution_params.device;
         let pipeline_arc = self.resources.ray_tracing_pipeline(binding.pipeline);
 
-        self.bind_pipeline_common(device, pipeline_arc.as_ref(), &binding.binding);
+        self.bind_pipeline_common(device, pipeline_arc.as_ref(), &binding.binding)?;
 
-        BoundRayTracingPipeline {
+        let hit_records_sbt = if binding.hit_records.is_empty() {
+            None
+        } else {
+            Some(pipeline_arc.shader_table_with_hit_records(device, binding.hit_records)?)
+        };
+
+        Ok(BoundRayTracingPipeline {
             api: self,
             pipeline: pipeline_arc,
-        }
+            hit_records_sbt,
+        })
     }
 
     fn bind_pipeline_common(
@@ -157,7 +206,44 @@ impl<'a, 'exec_params, 'constants> RenderPassApi<'a, 'exec_params, 'constants> {
         device: &Device,
         pipeline: &ShaderPipelineCommon,
         binding: &RenderPassCommonShaderPipelineBinding,
-    ) {
+    ) -> Result<(), KajiyaError> {
+        // Positional and named bindings, keyed by set, then by binding index.
+        let mut sets: BTreeMap<u32, BTreeMap<u32, &RenderPassBinding>> = BTreeMap::new();
+
+        for (set_idx, bindings) in &binding.bindings {
+            let set = sets.entry(*set_idx).or_default();
+            for (binding_idx, binding) in bindings.iter().enumerate() {
+                set.insert(binding_idx as u32, binding);
+            }
+        }
+
+        for (name, binding) in &binding.named_bindings {
+            match pipeline.reflection.find_binding(name) {
+                Some((set_idx, binding_idx, info)) => {
+                    if !binding_matches_descriptor_type(binding, &info.ty) {
+                        return Err(KajiyaError::BindingTypeMismatch {
+                            name: name.to_string(),
+                            descriptor_type: format!("{:?}", info.ty),
+                        });
+                    }
+
+                    let prev = sets
+                        .entry(set_idx)
+                        .or_default()
+                        .insert(binding_idx, binding);
+                    debug_assert!(
+                        prev.is_none(),
+                        "Binding {:?} (set {} binding {}) is also bound by index",
+                        name,
+                        set_idx,
+                        binding_idx
+                    );
+                }
+                // The compiler removes unused bindings, so this is not an error.
+                None => log::trace!("Binding {:?} not found in the pipeline", name),
+            }
+        }
+
         unsafe {
             device.raw.cmd_bind_pipeline(
                 self.cb.raw,
@@ -198,70 +284,14 @@ impl<'a, 'exec_params, 'constants> RenderPassApi<'a, 'exec_params, 'constants> {
             }
         }
 
-        for (set_idx, bindings) in &binding.bindings {
-            let set_idx = *set_idx;
+        for (set_idx, bindings) in sets {
             if pipeline.set_layout_info.get(set_idx as usize).is_none() {
                 continue;
             }
 
             let bindings = bindings
-                .iter()
-                .map(|binding| match binding {
-                    RenderPassBinding::Image(image) => DescriptorSetBinding::Image(
-                        vk::DescriptorImageInfo::builder()
-                            .image_layout(image.image_layout)
-                            .image_view(self.resources.image_view(image.handle, &image.view_desc))
-                            .build(),
-                    ),
-                    RenderPassBinding::ImageArray(images) => DescriptorSetBinding::ImageArray(
-                        images
-                            .iter()
-                            .map(|image| {
-                                vk::DescriptorImageInfo::builder()
-                                    .image_layout(image.image_layout)
-                                    .image_view(
-                                        self.resources.image_view(image.handle, &image.view_desc),
-                                    )
-                                    .build()
-                            })
-                            .collect(),
-                    ),
-                    RenderPassBinding::Buffer(buffer) => DescriptorSetBinding::Buffer(
-                        vk::DescriptorBufferInfo::builder()
-                            .buffer(
-                                self.resources
-                                    .buffer_from_raw_handle::<GpuSrv>(buffer.handle)
-                                    .raw,
-                            )
-                            .range(vk::WHOLE_SIZE)
-                            .build(),
-                    ),
-                    RenderPassBinding::RayTracingAcceleration(acc) => {
-                        DescriptorSetBinding::RayTracingAcceleration(
-                            self.resources
-                                .rt_acceleration_from_raw_handle::<GpuSrv>(acc.handle)
-                                .raw,
-                        )
-                    }
-                    RenderPassBinding::DynamicConstants(offset) => {
-                        DescriptorSetBinding::DynamicBuffer {
-                            buffer: vk::DescriptorBufferInfo::builder()
-                                .buffer(self.resources.dynamic_constants.buffer.raw)
-                                .range(MAX_DYNAMIC_CONSTANTS_BYTES_PER_DISPATCH as u64)
-                                .build(),
-                            offset: *offset,
-                        }
-                    }
-                    RenderPassBinding::DynamicConstantsStorageBuffer(offset) => {
-                        DescriptorSetBinding::DynamicStorageBuffer {
-                            buffer: vk::DescriptorBufferInfo::builder()
-                                .buffer(self.resources.dynamic_constants.buffer.raw)
-                                .range(MAX_DYNAMIC_CONSTANTS_STORAGE_BUFFER_BYTES as u64)
-                                .build(),
-                            offset: *offset,
-                        }
-                    }
-                })
+                .into_iter()
+                .map(|(binding_idx, binding)| (binding_idx, self.resolve_binding(binding)))
                 .collect::<Vec<_>>();
 
             bind_descriptor_set(
@@ -294,6 +324,64 @@ impl<'a, 'exec_params, 'constants> RenderPassApi<'a, 'exec_params, 'constants> {
                     );
             }
         }
+
+        Ok(())
+    }
+
+    fn resolve_binding(&self, binding: &RenderPassBinding) -> DescriptorSetBinding {
+        match binding {
+            RenderPassBinding::Image(image) => DescriptorSetBinding::Image(
+                vk::DescriptorImageInfo::builder()
+                    .image_layout(image.image_layout)
+                    .image_view(self.resources.image_view(image.handle, &image.view_desc))
+                    .build(),
+            ),
+            RenderPassBinding::ImageArray(images) => DescriptorSetBinding::ImageArray(
+                images
+                    .iter()
+                    .map(|image| {
+                        vk::DescriptorImageInfo::builder()
+                            .image_layout(image.image_layout)
+                            .image_view(self.resources.image_view(image.handle, &image.view_desc))
+                            .build()
+                    })
+                    .collect(),
+            ),
+            RenderPassBinding::Buffer(buffer) => DescriptorSetBinding::Buffer(
+                vk::DescriptorBufferInfo::builder()
+                    .buffer(
+                        self.resources
+                            .buffer_from_raw_handle::<GpuSrv>(buffer.handle)
+                            .raw,
+                    )
+                    .offset(buffer.offset)
+                    .range(buffer.range)
+                    .build(),
+            ),
+            RenderPassBinding::RayTracingAcceleration(acc) => {
+                DescriptorSetBinding::RayTracingAcceleration(
+                    self.resources
+                        .rt_acceleration_from_raw_handle::<GpuSrv>(acc.handle)
+                        .raw,
+                )
+            }
+            RenderPassBinding::DynamicConstants(offset) => DescriptorSetBinding::DynamicBuffer {
+                buffer: vk::DescriptorBufferInfo::builder()
+                    .buffer(self.resources.dynamic_constants.buffer.raw)
+                    .range(MAX_DYNAMIC_CONSTANTS_BYTES_PER_DISPATCH as u64)
+                    .build(),
+                offset: *offset,
+            },
+            RenderPassBinding::DynamicConstantsStorageBuffer(offset) => {
+                DescriptorSetBinding::DynamicStorageBuffer {
+                    buffer: vk::DescriptorBufferInfo::builder()
+                        .buffer(self.resources.dynamic_constants.buffer.raw)
+                        .range(MAX_DYNAMIC_CONSTANTS_STORAGE_BUFFER_BYTES as u64)
+                        .build(),
+                    offset: *offset,
+                }
+            }
+        }
     }
 
     pub fn begin_render_pass(
@@ -425,7 +513,14 @@ impl<'api, 'a, 'exec_params, 'constants> BoundComputePipeline<'api, 'a, 'exec_pa
         }
     }
 
-    pub fn push_constants(&self, command_buffer: vk::CommandBuffer, offset: u32, constants: &[u8]) {
+    pub fn push_constants(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        offset: u32,
+        constants: &[u8],
+    ) -> Result<(), KajiyaError> {
+        validate_push_constants(self.pipeline.push_constants_bytes, offset, constants.len())?;
+
         unsafe {
             self.api
                 .resources
@@ -440,6 +535,8 @@ impl<'api, 'a, 'exec_params, 'constants> BoundComputePipeline<'api, 'a, 'exec_pa
                     constants,
                 )
         }
+
+        Ok(())
     }
 }
 
@@ -455,7 +552,9 @@ impl<'api, 'a, 'exec_params, 'constants> BoundRasterPipeline<'api, 'a, 'exec_par
         stage_flags: vk::ShaderStageFlags,
         offset: u32,
         constants: &[u8],
-    ) {
+    ) -> Result<(), KajiyaError> {
+        validate_push_constants(self.pipeline.push_constants_bytes, offset, constants.len())?;
+
         unsafe {
             self.api
                 .resources
@@ -470,9 +569,31 @@ impl<'api, 'a, 'exec_params, 'constants> BoundRasterPipeline<'api, 'a, 'exec_par
                     constants,
                 )
         }
+
+        Ok(())
     }
 }
 
+/// Checks a push constant write against the pipeline's push constant range,
+/// so that mismatches don't end up as validation errors or GPU hangs.
+fn validate_push_constants(
+    pipeline_bytes: usize,
+    offset: u32,
+    size: usize,
+) -> Result<(), KajiyaError> {
+    let end = offset as usize + size;
+
+    if offset % 4 != 0 || size % 4 != 0 || end > pipeline_bytes {
+        return Err(KajiyaError::PushConstantsOutOfRange {
+            offset,
+            size,
+            pipeline_bytes,
+        });
+    }
+
+    Ok(())
+}
+
 pub struct RenderPassImageBinding {
     handle: GraphRawResourceHandle,
     view_desc: ImageViewDesc,
@@ -481,6 +602,8 @@ pub struct RenderPassImageBinding {
 
 pub struct RenderPassBufferBinding {
     handle: GraphRawResourceHandle,
+    offset: u64,
+    range: u64,
 }
 
 pub struct RenderPassRayTracingAccelerationBinding {
@@ -499,19 +622,28 @@ pub enum RenderPassBinding {
 pub struct BoundRayTracingPipeline<'api, 'a, 'exec_params, 'constants> {
     api: &'api mut RenderPassApi<'a, 'exec_params, 'constants>,
     pipeline: Arc<RayTracingPipeline>,
+    hit_records_sbt: Option<Arc<RayTracingShaderTable>>,
 }
 
 impl<'api, 'a, 'exec_params, 'constants>
     BoundRayTracingPipeline<'api, 'a, 'exec_params, 'constants>
 {
+    fn sbt(&self) -> &RayTracingShaderTable {
+        self.hit_records_sbt
+            .as_deref()
+            .unwrap_or(&self.pipeline.sbt)
+    }
+
     pub fn trace_rays(&self, threads: [u32; 3]) {
+        self.debug_assert_valid_sbt();
+
         unsafe {
             self.api.device().ray_tracing_pipeline_ext.cmd_trace_rays(
                 self.api.cb.raw,
-                &self.pipeline.sbt.raygen_shader_binding_table,
-                &self.pipeline.sbt.miss_shader_binding_table,
-                &self.pipeline.sbt.hit_shader_binding_table,
-                &self.pipeline.sbt.callable_shader_binding_table,
+                &self.sbt().raygen_shader_binding_table,
+                &self.sbt().miss_shader_binding_table,
+                &self.sbt().hit_shader_binding_table,
+                &self.sbt().callable_shader_binding_table,
                 threads[0],
                 threads[1],
                 threads[2],
@@ -520,16 +652,18 @@ impl<'api, 'a, 'exec_params, 'constants>
     }
 
     pub fn trace_rays_indirect(&self, args_buffer: Ref<Buffer, GpuSrv>, args_buffer_offset: u64) {
+        self.debug_assert_valid_sbt();
+
         unsafe {
             self.api
                 .device()
                 .ray_tracing_pipeline_ext
                 .cmd_trace_rays_indirect(
                     self.api.cb.raw,
-                    std::slice::from_ref(&self.pipeline.sbt.raygen_shader_binding_table),
-                    std::slice::from_ref(&self.pipeline.sbt.miss_shader_binding_table),
-                    std::slice::from_ref(&self.pipeline.sbt.hit_shader_binding_table),
-                    std::slice::from_ref(&self.pipeline.sbt.callable_shader_binding_table),
+                    std::slice::from_ref(&self.sbt().raygen_shader_binding_table),
+                    std::slice::from_ref(&self.sbt().miss_shader_binding_table),
+                    std::slice::from_ref(&self.sbt().hit_shader_binding_table),
+                    std::slice::from_ref(&self.sbt().callable_shader_binding_table),
                     self.api
                         .resources
                         .buffer(args_buffer)
@@ -538,6 +672,19 @@ impl<'api, 'a, 'exec_params, 'constants>
                 );
         }
     }
+
+    // Catches shaders tracing rays with miss indices the pipeline has no records for.
+    fn debug_assert_valid_sbt(&self) {
+        if cfg!(debug_assertions) {
+            if let Err(err) = self.pipeline.sbt_layout.validate() {
+                panic!(
+                    "{:#}. Shader binding table:\n{}",
+                    err,
+                    self.pipeline.dump_sbt()
+                );
+            }
+        }
+    }
 }
 
 pub trait BindRgRef {
@@ -595,16 +742,34 @@ impl Ref<Image, GpuUav> {
 
 impl BindRgRef for Ref<Buffer, GpuSrv> {
     fn bind(&self) -> RenderPassBinding {
+        self.bind_range(0, vk::WHOLE_SIZE)
+    }
+}
+
+impl Ref<Buffer, GpuSrv> {
+    /// Binds `range` bytes from `offset`, such as those of a `TemporalBufferSlice`.
+    pub fn bind_range(&self, offset: u64, range: u64) -> RenderPassBinding {
         RenderPassBinding::Buffer(RenderPassBufferBinding {
             handle: self.handle,
+            offset,
+            range,
         })
     }
 }
 
 impl BindRgRef for Ref<Buffer, GpuUav> {
     fn bind(&self) -> RenderPassBinding {
+        self.bind_range(0, vk::WHOLE_SIZE)
+    }
+}
+
+impl Ref<Buffer, GpuUav> {
+    /// Binds `range` bytes from `offset`, such as those of a `TemporalBufferSlice`.
+    pub fn bind_range(&self, offset: u64, range: u64) -> RenderPassBinding {
         RenderPassBinding::Buffer(RenderPassBufferBinding {
             handle: self.handle,
+            offset,
+            range,
         })
     }
 }
@@ -622,7 +787,7 @@ fn bind_descriptor_set(
     cb: &CommandBuffer,
     pipeline: &impl std::ops::Deref<Target = ShaderPipelineCommon>,
     set_index: u32,
-    bindings: &[DescriptorSetBinding],
+    bindings: &[(u32, DescriptorSetBinding)],
 ) {
     let shader_set_info = if let Some(info) = pipeline.set_layout_info.get(set_index as usize) {
         info
@@ -639,25 +804,25 @@ fn bind_descriptor_set(
     let accel_info: TempList<UnsafeCell<vk::WriteDescriptorSetAccelerationStructureKHR>> =
         TempList::new();
 
-    let raw_device = &device.raw;
-
-    let descriptor_pool = {
-        let descriptor_pool_create_info = vk::DescriptorPoolCreateInfo::builder()
-            .max_sets(1)
-            .pool_sizes(&pipeline.descriptor_pool_sizes);
-
-        unsafe { raw_device.create_descriptor_pool(&descriptor_pool_create_info, None) }.unwrap()
-    };
-    device.defer_release(descriptor_pool);
-
     let descriptor_set = {
-        let descriptor_set_allocate_info = vk::DescriptorSetAllocateInfo::builder()
-            .descriptor_pool(descriptor_pool)
-            .set_layouts(std::slice::from_ref(
-                &pipeline.descriptor_set_layouts[set_index as usize],
-            ));
+        let mut set_sizes: Vec<vk::DescriptorPoolSize> = Vec::new();
+        for ty in shader_set_info.values() {
+            if let Some(size) = set_sizes.iter_mut().find(|size| size.ty == *ty) {
+                size.descriptor_count += 1;
+            } else {
+                set_sizes.push(vk::DescriptorPoolSize {
+                    ty: *ty,
+                    descriptor_count: 1,
+                });
+            }
+        }
 
-        unsafe { raw_device.allocate_descriptor_sets(&descriptor_set_allocate_info) }.unwrap()[0]
+        device
+            .allocate_frame_descriptor_set(
+                pipeline.descriptor_set_layouts[set_index as usize],
+                &set_sizes,
+            )
+            .expect("allocate_frame_descriptor_set")
     };
 
     unsafe {
@@ -665,12 +830,11 @@ fn bind_descriptor_set(
         let descriptor_writes: Vec<vk::WriteDescriptorSet> =
             bindings
                 .iter()
-                .enumerate()
-                .filter(|(binding_idx, _)| shader_set_info.contains_key(&(*binding_idx as u32)))
+                .filter(|(binding_idx, _)| shader_set_info.contains_key(binding_idx))
                 .map(|(binding_idx, binding)| {
                     let write = vk::WriteDescriptorSet::builder()
                         .dst_set(descriptor_set)
-                        .dst_binding(binding_idx as _)
+                        .dst_binding(*binding_idx)
                         .dst_array_element(0);
 
                     match binding {
@@ -748,3 +912,52 @@ fn bind_descriptor_set(
         );
     }
 }
+
+fn binding_matches_descriptor_type(
+    binding: &RenderPassBinding,
+    ty: &rspirv_reflect::DescriptorType,
+) -> bool {
+    use rspirv_reflect::DescriptorType as Ty;
+
+    let image_type = |image: &RenderPassImageBinding| match image.image_layout {
+        vk::ImageLayout::GENERAL => Ty::STORAGE_IMAGE,
+        _ => Ty::SAMPLED_IMAGE,
+    };
+
+    match binding {
+        RenderPassBinding::Image(image) => *ty == image_type(image),
+        RenderPassBinding::ImageArray(images) => {
+            images.iter().all(|image| *ty == image_type(image))
+        }
+        RenderPassBinding::Buffer(_) | RenderPassBinding::DynamicConstantsStorageBuffer(_) => {
+            *ty == Ty::STORAGE_BUFFER || *ty == Ty::STORAGE_BUFFER_DYNAMIC
+        }
+        RenderPassBinding::DynamicConstants(_) => {
+            *ty == Ty::UNIFORM_BUFFER || *ty == Ty::UNIFORM_BUFFER_DYNAMIC
+        }
+        RenderPassBinding::RayTracingAcceleration(_) => *ty == Ty::ACCELERATION_STRUCTURE_KHR,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn push_constants_size_mismatch() {
+        assert!(validate_push_constants(8, 0, 8).is_ok());
+        assert!(validate_push_constants(8, 4, 4).is_ok());
+
+        assert!(matches!(
+            validate_push_constants(8, 0, 12),
+            Err(KajiyaError::PushConstantsOutOfRange {
+                offset: 0,
+                size: 12,
+                pipeline_bytes: 8
+            })
+        ));
+        assert!(validate_push_constants(8, 4, 8).is_err());
+        assert!(validate_push_constants(0, 0, 4).is_err());
+        assert!(validate_push_constants(8, 0, 6).is_err());
+    }
+}
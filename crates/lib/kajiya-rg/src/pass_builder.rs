@@ -10,6 +10,7 @@ use super::{
 };
 
 use kajiya_backend::{
+    shader_variants::{pipeline_shader_variants, ShaderFeatures, ShaderVariant},
     vk_sync::{self, AccessType},
     vulkan::{ray_tracing::RayTracingPipelineDesc, shader::*},
 };
@@ -96,6 +97,30 @@ impl<'rg> PassBuilder<'rg> {
         self.write_impl(handle, access_type)
     }
 
+    /// Declares a read-modify-write access to a resource, e.g. a UAV updated in place.
+    ///
+    /// The resource is transitioned once for the pass, to the `GENERAL` layout, with a barrier
+    /// making all prior accesses visible to both reads and writes in the pass. Subsequent passes
+    /// synchronize against it as a write. Within the pass, an invocation may read and then write
+    /// the same location, but the order of accesses across invocations is undefined, as with
+    /// any UAV.
+    ///
+    /// Must not be combined with `read` or `write` of the same resource in one pass.
+    pub fn read_write<Res: Resource>(&mut self, handle: &mut Handle<Res>) -> Ref<Res, GpuUav> {
+        let pass = self.pass.as_ref().unwrap();
+
+        if pass
+            .read
+            .iter()
+            .chain(pass.write.iter())
+            .any(|item| item.handle.id == handle.raw.id)
+        {
+            panic!("read_write must be the only access to a resource within one render pass");
+        }
+
+        self.write_impl(handle, AccessType::General)
+    }
+
     pub fn raster<Res: Resource>(
         &mut self,
         handle: &mut Handle<Res>,
@@ -103,7 +128,9 @@ impl<'rg> PassBuilder<'rg> {
     ) -> Ref<Res, GpuRt> {
         match access_type {
             AccessType::ColorAttachmentWrite
+            | AccessType::ColorAttachmentReadWrite
             | AccessType::DepthStencilAttachmentWrite
+            | AccessType::DepthStencilAttachmentRead
             | AccessType::DepthAttachmentWriteStencilReadOnly
             | AccessType::StencilAttachmentWriteDepthReadOnly => {}
             _ => {
@@ -186,6 +213,21 @@ impl<'rg> PassBuilder<'rg> {
         self.register_compute_pipeline_with_desc(desc)
     }
 
+    /// Registers `variant` of the compute shader at `path`, which has `features`.
+    /// Panics if the variant sets features or values the shader doesn't have.
+    pub fn register_compute_pipeline_variant(
+        &mut self,
+        path: impl AsRef<Path>,
+        features: &ShaderFeatures,
+        variant: &ShaderVariant,
+    ) -> RgComputePipelineHandle {
+        let desc = ComputePipelineDesc::builder()
+            .source(ShaderSource::hlsl_variant(path.as_ref(), features, variant).unwrap())
+            .build()
+            .unwrap();
+        self.register_compute_pipeline_with_desc(desc)
+    }
+
     pub fn register_compute_pipeline_with_desc(
         &mut self,
         mut desc: ComputePipelineDesc,
@@ -233,6 +275,32 @@ impl<'rg> PassBuilder<'rg> {
         RgRasterPipelineHandle { id }
     }
 
+    /// Registers `variant` of the HLSL shaders of a raster pipeline, which share `features`.
+    /// Panics if the variant sets features or values the shaders don't have.
+    pub fn register_raster_pipeline_variant(
+        &mut self,
+        shaders: &[PipelineShaderDesc],
+        desc: RasterPipelineDescBuilder,
+        features: &ShaderFeatures,
+        variant: &ShaderVariant,
+    ) -> RgRasterPipelineHandle {
+        let shaders = pipeline_shader_variants(shaders, features, variant).unwrap();
+        self.register_raster_pipeline(&shaders, desc)
+    }
+
+    /// Registers `variant` of the HLSL shaders of a ray tracing pipeline, which share `features`.
+    /// Panics if the variant sets features or values the shaders don't have.
+    pub fn register_ray_tracing_pipeline_variant(
+        &mut self,
+        shaders: &[PipelineShaderDesc],
+        desc: RayTracingPipelineDesc,
+        features: &ShaderFeatures,
+        variant: &ShaderVariant,
+    ) -> RgRtPipelineHandle {
+        let shaders = pipeline_shader_variants(shaders, features, variant).unwrap();
+        self.register_ray_tracing_pipeline(&shaders, desc)
+    }
+
     pub fn register_ray_tracing_pipeline(
         &mut self,
         shaders: &[PipelineShaderDesc],
@@ -0,0 +1,536 @@
+// Reads of GPU data back to the host, a few frames after it's written.
+//
+// `AsyncReadback` records copies of buffers and images into host-visible staging buffers, at their
+// position in the frame being recorded. The bytes are read once the GPU is done with that frame,
+// which the device's frame counter tells, so nothing waits on the GPU. Staging buffers are recycled
+// for later copies once read.
+//
+// Call `begin_frame` before recording copies into a frame, and `retire_frame` once it's submitted.
+// Requests made before the frame which copies them can `reserve` their token up front.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    task::Poll,
+};
+
+use kajiya_backend::{
+    ash::vk,
+    vk_sync::AccessType,
+    vulkan::{buffer::*, image::*},
+    Device,
+};
+
+use crate::{Handle, TemporalRenderGraph};
+
+#[derive(Clone, Copy, Hash, PartialEq, Eq, Debug)]
+pub struct ReadbackToken(u64);
+
+struct PendingReadback {
+    token: ReadbackToken,
+    buffer: Arc<Buffer>,
+    size: usize,
+
+    // Frame counter of the frame which wrote the buffer; `None` until it's submitted
+    written_in_frame: Option<u64>,
+}
+
+#[derive(Default)]
+pub struct AsyncReadback {
+    pending: Vec<PendingReadback>,
+
+    // The backend doesn't free buffers, so they are recycled for later copies instead.
+    free_buffers: Vec<Arc<Buffer>>,
+
+    results: HashMap<ReadbackToken, Option<Vec<u8>>>,
+
+    // Tokens handed out by `reserve`, whose copies aren't recorded yet
+    reserved: HashSet<ReadbackToken>,
+
+    // Tokens of copies in flight whose results are dropped instead of kept, see `discard`
+    discarded: HashSet<ReadbackToken>,
+
+    next_token: u64,
+}
+
+impl AsyncReadback {
+    /// Starts recording a new frame. Copies recorded into a frame which failed to render never
+    /// retire; they resolve to `None`.
+    pub fn begin_frame(&mut self) {
+        for readback in self
+            .pending
+            .iter()
+            .filter(|readback| readback.written_in_frame.is_none())
+        {
+            if !self.discarded.remove(&readback.token) {
+                self.results.insert(readback.token, None);
+            }
+            self.free_buffers.push(readback.buffer.clone());
+        }
+
+        self.pending
+            .retain(|readback| readback.written_in_frame.is_some());
+    }
+
+    /// Copies the whole of `buffer` to the host, at its position in the frame being recorded.
+    pub fn copy_buffer(
+        &mut self,
+        rg: &mut TemporalRenderGraph,
+        buffer: &Handle<Buffer>,
+    ) -> ReadbackToken {
+        let token = self.new_token();
+        let size = buffer.desc().size;
+
+        self.record_copy(token, rg, "buffer readback", size, |pass| {
+            let src_ref = pass.read(buffer, AccessType::TransferRead);
+
+            move |api, dst| {
+                let src = api.resources.buffer(src_ref);
+
+                unsafe {
+                    api.device().raw.cmd_copy_buffer(
+                        api.cb.raw,
+                        src.raw,
+                        dst,
+                        &[vk::BufferCopy {
+                            src_offset: 0,
+                            dst_offset: 0,
+                            size: size as u64,
+                        }],
+                    );
+                }
+            }
+        });
+
+        token
+    }
+
+    /// Copies the first mip of `image` to the host, in tightly packed rows of texels of
+    /// `bytes_per_texel` each, at its position in the frame being recorded.
+    pub fn copy_image(
+        &mut self,
+        rg: &mut TemporalRenderGraph,
+        image: &Handle<Image>,
+        bytes_per_texel: usize,
+    ) -> ReadbackToken {
+        let token = self.new_token();
+        self.copy_image_reserved(token, rg, image, bytes_per_texel);
+        token
+    }
+
+    /// Copies single texels of the first mip of `image` to the host, `bytes_per_texel` each,
+    /// tightly packed in the order of `texels`, at its position in the frame being recorded.
+    pub fn copy_image_texels(
+        &mut self,
+        rg: &mut TemporalRenderGraph,
+        image: &Handle<Image>,
+        texels: &[[u32; 2]],
+        bytes_per_texel: usize,
+    ) -> ReadbackToken {
+        let token = self.new_token();
+
+        let regions: Vec<vk::BufferImageCopy> = texels
+            .iter()
+            .enumerate()
+            .map(|(i, px)| vk::BufferImageCopy {
+                buffer_offset: (i * bytes_per_texel) as u64,
+                buffer_row_length: 0,
+                buffer_image_height: 0,
+                image_subresource: vk::ImageSubresourceLayers {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    mip_level: 0,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                },
+                image_offset: vk::Offset3D {
+                    x: px[0] as i32,
+                    y: px[1] as i32,
+                    z: 0,
+                },
+                image_extent: vk::Extent3D {
+                    width: 1,
+                    height: 1,
+                    depth: 1,
+                },
+            })
+            .collect();
+
+        let size = texels.len() * bytes_per_texel;
+        self.record_copy(token, rg, "texel readback", size, |pass| {
+            let src_ref = pass.read(image, AccessType::TransferRead);
+
+            move |api, dst| {
+                let src = api.resources.image(src_ref);
+
+                unsafe {
+                    api.device().raw.cmd_copy_image_to_buffer(
+                        api.cb.raw,
+                        src.raw,
+                        vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                        dst,
+                        &regions,
+                    );
+                }
+            }
+        });
+
+        token
+    }
+
+    /// A token which stays pending until its copy is recorded by `copy_image_reserved`,
+    /// or it's resolved to `None` by `cancel`; for requests made before the frame which copies them.
+    pub fn reserve(&mut self) -> ReadbackToken {
+        let token = self.new_token();
+        self.reserved.insert(token);
+        token
+    }
+
+    /// Like `copy_image`, for a token from `reserve`.
+    pub fn copy_image_reserved(
+        &mut self,
+        token: ReadbackToken,
+        rg: &mut TemporalRenderGraph,
+        image: &Handle<Image>,
+        bytes_per_texel: usize,
+    ) {
+        self.reserved.remove(&token);
+
+        let [width, height] = image.desc().extent_2d();
+        let size = width as usize * height as usize * bytes_per_texel;
+
+        self.record_copy(token, rg, "image readback", size, |pass| {
+            let src_ref = pass.read(image, AccessType::TransferRead);
+
+            move |api, dst| {
+                let src = api.resources.image(src_ref);
+
+                unsafe {
+                    api.device().raw.cmd_copy_image_to_buffer(
+                        api.cb.raw,
+                        src.raw,
+                        vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                        dst,
+                        &[vk::BufferImageCopy {
+                            buffer_offset: 0,
+                            buffer_row_length: 0,
+                            buffer_image_height: 0,
+                            image_subresource: vk::ImageSubresourceLayers {
+                                aspect_mask: vk::ImageAspectFlags::COLOR,
+                                mip_level: 0,
+                                base_array_layer: 0,
+                                layer_count: 1,
+                            },
+                            image_offset: vk::Offset3D { x: 0, y: 0, z: 0 },
+                            image_extent: vk::Extent3D {
+                                width,
+                                height,
+                                depth: 1,
+                            },
+                        }],
+                    );
+                }
+            }
+        });
+    }
+
+    /// A token which resolves to `None`, for copies which can't be made.
+    pub fn unavailable(&mut self) -> ReadbackToken {
+        let token = self.new_token();
+        self.results.insert(token, None);
+        token
+    }
+
+    /// Resolves a token from `reserve` to `None`, without copying anything.
+    pub fn cancel(&mut self, token: ReadbackToken) {
+        if self.reserved.remove(&token) {
+            self.results.insert(token, None);
+        }
+    }
+
+    /// Forgets `token`, whose result is no longer wanted: a reserved copy resolves to `None`
+    /// without being recorded, and the result of one in flight is dropped once it lands,
+    /// instead of being kept for `poll`.
+    pub fn discard(&mut self, token: ReadbackToken) {
+        self.reserved.remove(&token);
+        self.results.remove(&token);
+
+        if self.pending.iter().any(|readback| readback.token == token) {
+            self.discarded.insert(token);
+        }
+    }
+
+    /// Marks the copies recorded since `begin_frame` as submitted in the frame `frame_counter`.
+    pub fn retire_frame(&mut self, frame_counter: u64) {
+        for readback in &mut self.pending {
+            readback.written_in_frame.get_or_insert(frame_counter);
+        }
+    }
+
+    /// The bytes copied for `token`, or `None` if they couldn't be. Each result is returned once;
+    /// unknown tokens resolve to `None`.
+    pub fn poll(&mut self, device: &Device, token: ReadbackToken) -> Poll<Option<Vec<u8>>> {
+        if let Some(completed_frame) = device
+            .frame_counter()
+            .checked_sub(device.frames_in_flight() as u64)
+        {
+            self.harvest(device, completed_frame);
+        }
+
+        self.take_result(token)
+    }
+
+    /// Like `poll`, but waits for the GPU to finish the frame which copied `token` if needed,
+    /// along with all other work. For tools and tests; this stalls the pipeline.
+    pub fn wait(&mut self, device: &Device, token: ReadbackToken) -> Option<Vec<u8>> {
+        if let Poll::Ready(result) = self.poll(device, token) {
+            return result;
+        }
+
+        device.wait_idle().ok()?;
+        self.harvest(device, u64::MAX);
+
+        match self.take_result(token) {
+            Poll::Ready(result) => result,
+            // Not submitted yet
+            Poll::Pending => None,
+        }
+    }
+
+    /// Whether `token` is yet to resolve.
+    pub fn is_pending(&self, token: ReadbackToken) -> bool {
+        self.reserved.contains(&token)
+            || self.pending.iter().any(|readback| readback.token == token)
+    }
+
+    fn new_token(&mut self) -> ReadbackToken {
+        let token = ReadbackToken(self.next_token);
+        self.next_token += 1;
+        token
+    }
+
+    // Adds a pass copying into a staging buffer of at least `size` bytes, for `token`. `copy` declares
+    // the pass's reads, and returns the commands copying them into the raw staging buffer.
+    fn record_copy<CopyFn>(
+        &mut self,
+        token: ReadbackToken,
+        rg: &mut TemporalRenderGraph,
+        name: &str,
+        size: usize,
+        copy: impl FnOnce(&mut crate::PassBuilder<'_>) -> CopyFn,
+    ) where
+        CopyFn: FnOnce(&mut crate::RenderPassApi, vk::Buffer) + 'static,
+    {
+        let buffer = if let Some(idx) = self
+            .free_buffers
+            .iter()
+            .position(|buffer| buffer.desc.size >= size)
+        {
+            self.free_buffers.swap_remove(idx)
+        } else {
+            Arc::new(
+                rg.device()
+                    .create_buffer(
+                        BufferDesc::new_gpu_to_cpu(size, vk::BufferUsageFlags::TRANSFER_DST),
+                        name,
+                        None,
+                    )
+                    .expect("readback buffer"),
+            )
+        };
+
+        let mut staging_buf = rg.import(buffer.clone(), AccessType::Nothing);
+
+        let mut pass = rg.add_pass(name);
+        let copy = copy(&mut pass);
+        let dst_ref = pass.write(&mut staging_buf, AccessType::TransferWrite);
+
+        pass.render(move |api| {
+            let dst = api.resources.buffer(dst_ref).raw;
+            copy(api, dst);
+        });
+
+        rg.export(staging_buf, AccessType::HostRead);
+
+        self.pending.push(PendingReadback {
+            token,
+            buffer,
+            size,
+            written_in_frame: None,
+        });
+    }
+
+    fn take_result(&mut self, token: ReadbackToken) -> Poll<Option<Vec<u8>>> {
+        if let Some(result) = self.results.remove(&token) {
+            Poll::Ready(result)
+        } else if self.is_pending(token) {
+            Poll::Pending
+        } else {
+            Poll::Ready(None)
+        }
+    }
+
+    // Moves the results out of the buffers written in frames up to `completed_frame`,
+    // which the GPU is done with.
+    fn harvest(&mut self, device: &Device, completed_frame: u64) {
+        let (completed, in_flight): (Vec<_>, Vec<_>) = std::mem::take(&mut self.pending)
+            .into_iter()
+            .partition(|readback| {
+                readback
+                    .written_in_frame
+                    .map_or(false, |frame| frame <= completed_frame)
+            });
+        self.pending = in_flight;
+
+        for readback in completed {
+            if let Err(err) = device.invalidate_mapped_buffer(&readback.buffer) {
+                log::error!("Failed to invalidate a readback buffer: {:?}", err);
+            }
+
+            let result = readback
+                .buffer
+                .allocation
+                .mapped_slice()
+                .and_then(|bytes| bytes.get(..readback.size))
+                .map(<[u8]>::to_vec);
+
+            if !self.discarded.remove(&readback.token) {
+                self.results.insert(readback.token, result);
+            }
+            self.free_buffers.push(readback.buffer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        renderer::{FrameConstantsLayout, Renderer},
+        SimpleRenderPass,
+    };
+    use kajiya_backend::{file::set_standard_vfs_mount_points, HeadlessRenderBackend};
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn reads_back_a_compute_pass_output_without_stalling() {
+        set_standard_vfs_mount_points(concat!(env!("CARGO_MANIFEST_DIR"), "/../../.."));
+
+        let backend = HeadlessRenderBackend::new(Default::default()).unwrap();
+        let device = backend.device.clone();
+        let mut renderer = Renderer::new(&device).unwrap();
+        let mut readback = AsyncReadback::default();
+
+        const LEN: usize = 64;
+        let frame_count = device.frames_in_flight() as u32 + 2;
+
+        let mut tokens = Vec::new();
+        for frame in 0..frame_count {
+            readback.begin_frame();
+
+            let mut token = None;
+            renderer
+                .prepare_frame(|rg| {
+                    let mut buf = rg.create(BufferDesc::new_gpu_only(
+                        LEN * std::mem::size_of::<u32>(),
+                        vk::BufferUsageFlags::STORAGE_BUFFER,
+                    ));
+                    SimpleRenderPass::new_compute(
+                        rg.add_pass("write sequence"),
+                        "/shaders/tests/write_sequence.hlsl",
+                    )
+                    .write(&mut buf)
+                    .push_constants(&frame)
+                    .dispatch([LEN as u32, 1, 1]);
+
+                    token = Some(readback.copy_buffer(rg, &buf));
+                })
+                .unwrap();
+            renderer
+                .draw_frame_headless(|_| FrameConstantsLayout {
+                    globals_offset: 0,
+                    instance_dynamic_parameters_offset: 0,
+                    triangle_lights_offset: 0,
+                })
+                .unwrap();
+            readback.retire_frame(device.frame_counter());
+
+            // The frame was just submitted; polling doesn't wait for it.
+            let token = token.unwrap();
+            assert!(readback.poll(&device, token).is_pending());
+            tokens.push(token);
+        }
+
+        // Vecs of bytes aren't necessarily aligned for `bytemuck::cast_slice`.
+        let to_u32s = |bytes: Vec<u8>| -> Vec<u32> {
+            bytes
+                .chunks_exact(4)
+                .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+                .collect()
+        };
+        let expected =
+            |frame: u32| -> Vec<u32> { (0..LEN as u32).map(|i| frame * 1000 + i).collect() };
+
+        // Retired by the frames rendered after it
+        let first = match readback.poll(&device, tokens[0]) {
+            Poll::Ready(bytes) => bytes.unwrap(),
+            Poll::Pending => panic!("The first frame should have retired"),
+        };
+        assert_eq!(to_u32s(first), expected(0));
+        assert!(readback.poll(&device, tokens[0]) == Poll::Ready(None));
+
+        let last = readback.wait(&device, *tokens.last().unwrap()).unwrap();
+        assert_eq!(to_u32s(last), expected(frame_count - 1));
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn discarded_readbacks_are_not_kept() {
+        set_standard_vfs_mount_points(concat!(env!("CARGO_MANIFEST_DIR"), "/../../.."));
+
+        let backend = HeadlessRenderBackend::new(Default::default()).unwrap();
+        let device = backend.device.clone();
+        let mut renderer = Renderer::new(&device).unwrap();
+        let mut readback = AsyncReadback::default();
+
+        let reserved = readback.reserve();
+        readback.discard(reserved);
+        assert!(!readback.is_pending(reserved));
+
+        readback.begin_frame();
+        let mut in_flight = None;
+        renderer
+            .prepare_frame(|rg| {
+                let mut buf = rg.create(BufferDesc::new_gpu_only(
+                    16 * std::mem::size_of::<u32>(),
+                    vk::BufferUsageFlags::STORAGE_BUFFER,
+                ));
+                SimpleRenderPass::new_compute(
+                    rg.add_pass("write sequence"),
+                    "/shaders/tests/write_sequence.hlsl",
+                )
+                .write(&mut buf)
+                .push_constants(&0u32)
+                .dispatch([16, 1, 1]);
+
+                in_flight = Some(readback.copy_buffer(rg, &buf));
+            })
+            .unwrap();
+        renderer
+            .draw_frame_headless(|_| FrameConstantsLayout {
+                globals_offset: 0,
+                instance_dynamic_parameters_offset: 0,
+                triangle_lights_offset: 0,
+            })
+            .unwrap();
+        readback.retire_frame(device.frame_counter());
+
+        let in_flight = in_flight.unwrap();
+        readback.discard(in_flight);
+
+        device.wait_idle().unwrap();
+        readback.harvest(&device, u64::MAX);
+        assert!(readback.results.is_empty());
+        assert!(readback.discarded.is_empty());
+        assert_eq!(readback.free_buffers.len(), 1);
+    }
+}
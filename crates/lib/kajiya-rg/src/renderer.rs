@@ -1,7 +1,8 @@
 use crate::{
-    CompiledRenderGraph, ExecutingRenderGraph, ExportedTemporalRenderGraphState,
+    graph::{submit_semaphores, ExternalSemaphores},
+    CompiledRenderGraph, ExecutingRenderGraph, ExportedTemporalRenderGraphState, ExternalSemaphore,
     PredefinedDescriptorSet, RenderGraphExecutionParams, TemporalRenderGraph,
-    TemporalRenderGraphState, TemporalResourceState,
+    TemporalRenderGraphState, TemporalResource, TemporalResourceState,
 };
 use kajiya_backend::{
     ash::vk,
@@ -10,8 +11,11 @@ use kajiya_backend::{
     rspirv_reflect,
     transient_resource_cache::TransientResourceCache,
     vk_sync,
-    vulkan::{self, swapchain::Swapchain, RenderBackend},
-    Device,
+    vulkan::{
+        self,
+        swapchain::{Swapchain, SwapchainAcquireImageErr},
+    },
+    Device, KajiyaError,
 };
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
@@ -40,6 +44,9 @@ pub struct Renderer {
 
     compiled_rg: Option<CompiledRenderGraph>,
     temporal_rg_state: TemporalRg,
+
+    // Of a frame which failed to prepare, for the next one to wait for and signal
+    dropped_external_semaphores: ExternalSemaphores,
 }
 
 lazy_static::lazy_static! {
@@ -84,25 +91,27 @@ pub struct FrameConstantsLayout {
 }
 
 impl Renderer {
-    pub fn new(backend: &RenderBackend) -> anyhow::Result<Self> {
-        let dynamic_constants = DynamicConstants::new({
-            backend.device.create_buffer(
+    pub fn new(device: &Arc<Device>) -> anyhow::Result<Self> {
+        let frames_in_flight = device.frames_in_flight();
+        let dynamic_constants = DynamicConstants::new(
+            device.create_buffer(
                 BufferDesc::new_cpu_to_gpu(
-                    DYNAMIC_CONSTANTS_SIZE_BYTES * DYNAMIC_CONSTANTS_BUFFER_COUNT,
+                    DYNAMIC_CONSTANTS_SIZE_BYTES * frames_in_flight,
                     vk::BufferUsageFlags::UNIFORM_BUFFER
                         | vk::BufferUsageFlags::STORAGE_BUFFER
                         | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS,
                 ),
                 "dynamic constants buffer",
                 None,
-            )?
-        });
+            )?,
+            frames_in_flight,
+        );
 
         let frame_descriptor_set =
-            Self::create_frame_descriptor_set(backend, &dynamic_constants.buffer);
+            Self::create_frame_descriptor_set(device, &dynamic_constants.buffer);
 
         Ok(Renderer {
-            device: backend.device.clone(),
+            device: device.clone(),
             dynamic_constants,
             frame_descriptor_set,
             pipeline_cache: PipelineCache::new(&LazyCache::create()),
@@ -110,6 +119,7 @@ impl Renderer {
 
             compiled_rg: None,
             temporal_rg_state: Default::default(),
+            dropped_external_semaphores: Default::default(),
         })
     }
 
@@ -117,19 +127,44 @@ impl Renderer {
         &mut self,
         prepare_frame_constants: PrepareFrameConstantsFn,
         swapchain: &mut Swapchain,
-    ) where
+    ) -> Result<(), KajiyaError>
+    where
+        PrepareFrameConstantsFn: FnOnce(&mut DynamicConstants) -> FrameConstantsLayout,
+    {
+        self.draw_frame_impl(prepare_frame_constants, Some(swapchain))
+    }
+
+    /// Like `draw_frame`, but without presenting; for `HeadlessRenderBackend`. The render graph
+    /// must not use the swapchain image. Results can be exported from the graph, and read back
+    /// once the frame's commands are done.
+    pub fn draw_frame_headless<PrepareFrameConstantsFn>(
+        &mut self,
+        prepare_frame_constants: PrepareFrameConstantsFn,
+    ) -> Result<(), KajiyaError>
+    where
+        PrepareFrameConstantsFn: FnOnce(&mut DynamicConstants) -> FrameConstantsLayout,
+    {
+        self.draw_frame_impl(prepare_frame_constants, None)
+    }
+
+    fn draw_frame_impl<PrepareFrameConstantsFn>(
+        &mut self,
+        prepare_frame_constants: PrepareFrameConstantsFn,
+        swapchain: Option<&mut Swapchain>,
+    ) -> Result<(), KajiyaError>
+    where
         PrepareFrameConstantsFn: FnOnce(&mut DynamicConstants) -> FrameConstantsLayout,
     {
         let rg = if let Some(rg) = self.compiled_rg.take() {
             rg
         } else {
-            return;
+            return Ok(());
         };
 
         let device = &*self.device;
         let raw_device = &device.raw;
 
-        let current_frame = self.device.begin_frame();
+        let current_frame = self.device.begin_frame()?;
 
         // Both command buffers are accessible now, so begin recording.
         for cb in [
@@ -155,6 +190,7 @@ impl Renderer {
         let frame_constants_layout = prepare_frame_constants(&mut self.dynamic_constants);
 
         let mut executing_rg: ExecutingRenderGraph;
+        let external_semaphores;
 
         // Record and submit the main command buffer
         {
@@ -178,6 +214,8 @@ impl Renderer {
                 )
             };
 
+            external_semaphores = executing_rg.take_external_semaphores();
+
             // Record and submit the main command buffer
             unsafe {
                 puffin::profile_scope!("main cb");
@@ -189,8 +227,18 @@ impl Renderer {
 
                 raw_device.end_command_buffer(main_cb.raw).unwrap();
 
+                // Imports produced outside of the graph must be ready before any of it runs.
+                let (wait_semaphores, wait_values) =
+                    submit_semaphores(external_semaphores.waits.iter().copied());
+                let wait_stages = vec![vk::PipelineStageFlags::ALL_COMMANDS; wait_semaphores.len()];
+                let mut timeline_info =
+                    vk::TimelineSemaphoreSubmitInfo::builder().wait_semaphore_values(&wait_values);
+
                 let submit_info = [vk::SubmitInfo::builder()
+                    .wait_semaphores(&wait_semaphores)
+                    .wait_dst_stage_mask(&wait_stages)
                     .command_buffers(std::slice::from_ref(&main_cb.raw))
+                    .push_next(&mut timeline_info)
                     .build()];
 
                 raw_device
@@ -206,18 +254,28 @@ impl Renderer {
                         &submit_info,
                         main_cb.submit_done_fence,
                     )
-                    .map_err(|err| device.report_error(err.into()))
-                    .expect("main queue_submit failed");
+                    .map_err(|err| device.report_error(err.into()))?;
             };
         }
 
         // Now that we've done the main submission and the GPU is busy, acquire the presentation image.
         // This can block, so we're doing it as late as possible.
+        let presentation = match swapchain {
+            Some(swapchain) => {
+                let swapchain_image = match swapchain.acquire_next_image() {
+                    Ok(image) => image,
+                    Err(SwapchainAcquireImageErr::DeviceLost) => {
+                        return Err(KajiyaError::DeviceLost)
+                    }
+                    Err(SwapchainAcquireImageErr::RecreateFramebuffer) => {
+                        panic!("swapchain image: the swapchain needs to be re-created")
+                    }
+                };
 
-        let swapchain_image = swapchain
-            .acquire_next_image()
-            .ok()
-            .expect("swapchain image");
+                Some((swapchain, swapchain_image))
+            }
+            None => None,
+        };
 
         // Execute the rest of the render graph, and submit the presentation command buffer.
         let retired_rg = {
@@ -225,34 +283,42 @@ impl Renderer {
 
             let presentation_cb = &current_frame.presentation_command_buffer;
 
-            // Transition the swapchain to CS write
-            vulkan::barrier::record_image_barrier(
-                device,
-                presentation_cb.raw,
-                vulkan::barrier::ImageBarrier::new(
-                    swapchain_image.image.raw,
-                    vk_sync::AccessType::Present,
-                    vk_sync::AccessType::ComputeShaderWrite,
-                    vk::ImageAspectFlags::COLOR,
-                )
-                .with_discard(true),
-            );
+            if let Some((_, swapchain_image)) = &presentation {
+                // Transition the swapchain to CS write
+                vulkan::barrier::record_image_barrier(
+                    device,
+                    presentation_cb.raw,
+                    vulkan::barrier::ImageBarrier::new(
+                        swapchain_image.image.raw,
+                        vk_sync::AccessType::Present,
+                        vk_sync::AccessType::ComputeShaderWrite,
+                        vk::ImageAspectFlags::COLOR,
+                    )
+                    .with_discard(true),
+                );
+            }
 
-            let retired_rg =
-                executing_rg.record_presentation_cb(presentation_cb, swapchain_image.image.clone());
-
-            // Transition the swapchain to present
-            vulkan::barrier::record_image_barrier(
-                device,
-                presentation_cb.raw,
-                vulkan::barrier::ImageBarrier::new(
-                    swapchain_image.image.raw,
-                    vk_sync::AccessType::ComputeShaderWrite,
-                    vk_sync::AccessType::Present,
-                    vk::ImageAspectFlags::COLOR,
-                ),
+            let retired_rg = executing_rg.record_presentation_cb(
+                presentation_cb,
+                presentation
+                    .as_ref()
+                    .map(|(_, swapchain_image)| swapchain_image.image.clone()),
             );
 
+            if let Some((_, swapchain_image)) = &presentation {
+                // Transition the swapchain to present
+                vulkan::barrier::record_image_barrier(
+                    device,
+                    presentation_cb.raw,
+                    vulkan::barrier::ImageBarrier::new(
+                        swapchain_image.image.raw,
+                        vk_sync::AccessType::ComputeShaderWrite,
+                        vk_sync::AccessType::Present,
+                        vk::ImageAspectFlags::COLOR,
+                    ),
+                );
+            }
+
             current_frame
                 .profiler_data
                 .finish_frame(device, presentation_cb.raw);
@@ -261,13 +327,33 @@ impl Renderer {
             unsafe {
                 raw_device.end_command_buffer(presentation_cb.raw).unwrap();
 
+                let wait_semaphores: &[vk::Semaphore] = match &presentation {
+                    Some((_, swapchain_image)) => {
+                        std::slice::from_ref(&swapchain_image.acquire_semaphore)
+                    }
+                    None => &[],
+                };
+
+                // Consumers outside of the graph get signaled once all of it is done.
+                let (signal_semaphores, signal_values) = submit_semaphores(
+                    presentation
+                        .iter()
+                        .map(|(_, swapchain_image)| {
+                            ExternalSemaphore::binary(swapchain_image.rendering_finished_semaphore)
+                        })
+                        .chain(external_semaphores.signals.iter().copied()),
+                );
+                let mut timeline_info = vk::TimelineSemaphoreSubmitInfo::builder()
+                    .signal_semaphore_values(&signal_values);
+
                 let submit_info = [vk::SubmitInfo::builder()
-                    .wait_semaphores(std::slice::from_ref(&swapchain_image.acquire_semaphore))
-                    .signal_semaphores(std::slice::from_ref(
-                        &swapchain_image.rendering_finished_semaphore,
-                    ))
-                    .wait_dst_stage_mask(&[vk::PipelineStageFlags::COMPUTE_SHADER])
+                    .wait_semaphores(wait_semaphores)
+                    .signal_semaphores(&signal_semaphores)
+                    .wait_dst_stage_mask(
+                        &[vk::PipelineStageFlags::COMPUTE_SHADER][..wait_semaphores.len()],
+                    )
                     .command_buffers(std::slice::from_ref(&presentation_cb.raw))
+                    .push_next(&mut timeline_info)
                     .build()];
                 raw_device
                     .reset_fences(std::slice::from_ref(&presentation_cb.submit_done_fence))
@@ -280,11 +366,12 @@ impl Renderer {
                         &submit_info,
                         presentation_cb.submit_done_fence,
                     )
-                    .map_err(|err| device.report_error(err.into()))
-                    .expect("presentation queue_submit failed");
+                    .map_err(|err| device.report_error(err.into()))?;
             }
 
-            swapchain.present_image(swapchain_image);
+            if let Some((swapchain, swapchain_image)) = presentation {
+                swapchain.present_image(swapchain_image)?;
+            }
 
             retired_rg
         };
@@ -293,21 +380,25 @@ impl Renderer {
             TemporalRg::Inert(_) => {
                 panic!("Trying to retire the render graph, but it's inert. Was prepare_frame not caled?");
             }
-            TemporalRg::Exported(rg) => TemporalRg::Inert(rg.retire_temporal(&retired_rg)),
+            TemporalRg::Exported(rg) => {
+                TemporalRg::Inert(rg.retire_temporal(&self.device, &retired_rg))
+            }
         };
 
         retired_rg.release_resources(&mut self.transient_resource_cache);
 
         self.dynamic_constants.advance_frame();
         self.device.finish_frame(current_frame);
+
+        Ok(())
     }
 
     // Descriptor set for per-frame data
     fn create_frame_descriptor_set(
-        backend: &RenderBackend,
+        device: &Device,
         dynamic_constants: &Buffer,
     ) -> vk::DescriptorSet {
-        let device = &backend.device.raw;
+        let device = &device.raw;
 
         let set_binding_flags = [
             vk::DescriptorBindingFlags::PARTIALLY_BOUND,
@@ -426,13 +517,20 @@ impl Renderer {
     pub fn prepare_frame<PrepareRenderGraphFn>(
         &mut self,
         prepare_render_graph: PrepareRenderGraphFn,
-    ) -> anyhow::Result<()>
+    ) -> Result<(), KajiyaError>
     where
         PrepareRenderGraphFn: FnOnce(&mut TemporalRenderGraph),
     {
         let mut rg = TemporalRenderGraph::new(
-            match &self.temporal_rg_state {
-                TemporalRg::Inert(state) => state.clone_assuming_inert(),
+            match &mut self.temporal_rg_state {
+                TemporalRg::Inert(state) => {
+                    // Resources replaced by frames which failed to prepare are released
+                    // along with this one.
+                    let mut frame_state = state.clone_assuming_inert();
+                    frame_state.replaced_images = std::mem::take(&mut state.replaced_images);
+                    frame_state.replaced_buffers = std::mem::take(&mut state.replaced_buffers);
+                    frame_state
+                }
                 TemporalRg::Exported(_) => {
                     panic!("Trying to prepare_frame but render graph is still active")
                 }
@@ -447,6 +545,8 @@ impl Renderer {
             },
         );
 
+        rg.carry_over_external_semaphores(std::mem::take(&mut self.dropped_external_semaphores));
+
         prepare_render_graph(&mut rg);
         let (rg, temporal_rg_state) = rg.export_temporal();
 
@@ -463,16 +563,53 @@ impl Renderer {
                 // some temporal resources, and we can reuse them in the next attempt.
                 //
                 // Import any new resources into our temporal rg state, but reset their access modes.
+                // Buffer slices are the exception, since the pool they were allocated from is
+                // discarded; only its new backing buffers are kept.
+                //
+                // Resources the frame replaced or released are dropped from our state, and released
+                // along with the next frame which does get submitted.
+
+                // The graph won't run, but external work may be waiting on its semaphores.
+                if let Some(mut dropped_rg) = self.compiled_rg.take() {
+                    self.dropped_external_semaphores = dropped_rg.take_external_semaphores();
+                }
 
                 let self_temporal_rg_state = match &mut self.temporal_rg_state {
                     TemporalRg::Inert(state) => state,
                     TemporalRg::Exported(_) => unreachable!(),
                 };
 
-                for (res_key, res) in temporal_rg_state.0.resources {
-                    // `insert` is infrequent here, and we can avoid cloning the key.
-                    #[allow(clippy::map_entry)]
-                    if !self_temporal_rg_state.resources.contains_key(&res_key) {
+                let mut temporal_rg_state = temporal_rg_state.0;
+                self_temporal_rg_state
+                    .buffer_pool
+                    .keep_new_chunks(temporal_rg_state.buffer_pool);
+
+                let replaced_images = std::mem::take(&mut temporal_rg_state.replaced_images);
+                let replaced_buffers = std::mem::take(&mut temporal_rg_state.replaced_buffers);
+                self_temporal_rg_state
+                    .resources
+                    .retain(|_, res| match res.resource() {
+                        TemporalResource::Image(image) => !replaced_images
+                            .iter()
+                            .any(|replaced| Arc::ptr_eq(replaced, image)),
+                        TemporalResource::Buffer(buffer) => !replaced_buffers
+                            .iter()
+                            .any(|replaced| Arc::ptr_eq(replaced, buffer)),
+                        TemporalResource::BufferSlice { .. } => true,
+                    });
+                self_temporal_rg_state.replaced_images = replaced_images;
+                self_temporal_rg_state.replaced_buffers = replaced_buffers;
+
+                for (res_key, res) in temporal_rg_state.resources {
+                    let is_buffer_slice =
+                        matches!(res.resource(), TemporalResource::BufferSlice { .. });
+
+                    let is_new = self_temporal_rg_state
+                        .resources
+                        .get(&res_key)
+                        .map_or(true, |prev| !prev.resource().is_same(res.resource()));
+
+                    if !is_buffer_slice && is_new {
                         let res = match res {
                             res @ TemporalResourceState::Inert { .. } => res,
                             TemporalResourceState::Imported { resource, .. }
@@ -493,6 +630,14 @@ impl Renderer {
         }
     }
 
+    /// Builds all pipelines registered so far; see `PipelineCache::compile_all`.
+    pub fn compile_all_pipelines(
+        &mut self,
+        progress: impl FnMut(usize, usize),
+    ) -> Result<(), Vec<KajiyaError>> {
+        self.pipeline_cache.compile_all(&self.device, progress)
+    }
+
     pub fn device(&self) -> &Arc<Device> {
         &self.device
     }
@@ -5,11 +5,19 @@ use std::{
 
 use anyhow::Context;
 
-use kajiya_backend::{vk_sync::AccessType, Device, Image, ImageDesc};
+use kajiya_backend::{
+    ash::vk,
+    vk_sync::AccessType,
+    vulkan::barrier::{
+        image_aspect_mask_from_access_type_and_format, record_image_barrier, ImageBarrier,
+    },
+    Device, Image, ImageDesc, ImageType,
+};
 
 use super::{
-    Buffer, BufferDesc, ExportableGraphResource, ExportedHandle, Handle, RenderGraph, Resource,
-    ResourceDesc, RetiredRenderGraph, TypeEquals,
+    buffer_slices::{BufferSlot, TemporalBufferPool, TemporalBufferSlice, MAX_BUFFER_SLICE_SIZE},
+    imageops, Buffer, BufferDesc, ExportableGraphResource, ExportedHandle, Handle, RenderGraph,
+    Resource, ResourceDesc, RetiredRenderGraph, TypeEquals,
 };
 
 pub struct ReadOnlyHandle<ResType: Resource>(Handle<ResType>);
@@ -47,6 +55,33 @@ impl<'a> From<String> for TemporalResourceKey {
 pub(crate) enum TemporalResource {
     Image(Arc<Image>),
     Buffer(Arc<Buffer>),
+    BufferSlice {
+        buffer: Arc<Buffer>,
+        slot: BufferSlot,
+        size: u64,
+    },
+}
+
+impl TemporalResource {
+    pub(crate) fn is_same(&self, other: &Self) -> bool {
+        match (self, other) {
+            (TemporalResource::Image(a), TemporalResource::Image(b)) => Arc::ptr_eq(a, b),
+            (TemporalResource::Buffer(a), TemporalResource::Buffer(b)) => Arc::ptr_eq(a, b),
+            (
+                TemporalResource::BufferSlice {
+                    buffer: a,
+                    slot: slot_a,
+                    ..
+                },
+                TemporalResource::BufferSlice {
+                    buffer: b,
+                    slot: slot_b,
+                    ..
+                },
+            ) => Arc::ptr_eq(a, b) && slot_a == slot_b,
+            _ => false,
+        }
+    }
 }
 
 pub(crate) enum ExportedResourceHandle {
@@ -69,9 +104,27 @@ pub(crate) enum TemporalResourceState {
     },
 }
 
+impl TemporalResourceState {
+    pub(crate) fn resource(&self) -> &TemporalResource {
+        match self {
+            TemporalResourceState::Inert { resource, .. }
+            | TemporalResourceState::Imported { resource, .. }
+            | TemporalResourceState::Exported { resource, .. } => resource,
+        }
+    }
+}
+
 #[derive(Default)]
 pub struct TemporalRenderGraphState {
     pub(crate) resources: HashMap<TemporalResourceKey, TemporalResourceState>,
+
+    // Images replaced because their desc changed, released once the frame retires
+    pub(crate) replaced_images: Vec<Arc<Image>>,
+
+    // Likewise for buffers
+    pub(crate) replaced_buffers: Vec<Arc<Buffer>>,
+
+    pub(crate) buffer_pool: TemporalBufferPool,
 }
 
 impl TemporalRenderGraphState {
@@ -97,6 +150,9 @@ impl TemporalRenderGraphState {
                     }
                 })
                 .collect(),
+            replaced_images: Vec::new(),
+            replaced_buffers: Vec::new(),
+            buffer_pool: self.buffer_pool.clone(),
         }
     }
 }
@@ -107,6 +163,11 @@ pub struct TemporalRenderGraph {
     rg: RenderGraph,
     device: Arc<Device>,
     temporal_state: TemporalRenderGraphState,
+
+    /// When the extent of a temporal image changes, resample its previous contents
+    /// into the new image instead of starting from scratch. Only suitable if all temporal
+    /// images contain filterable data (e.g. color), rather than indices or packed values.
+    pub rescale_temporal_history: bool,
 }
 
 impl std::ops::Deref for TemporalRenderGraph {
@@ -129,12 +190,106 @@ impl TemporalRenderGraph {
             rg: RenderGraph::new(),
             device,
             temporal_state: state,
+            rescale_temporal_history: false,
         }
     }
 
     pub fn device(&self) -> &Device {
         self.device.as_ref()
     }
+
+    fn supports_linear_blit(&self, format: vk::Format) -> bool {
+        let pdevice = self.device.physical_device();
+        let properties = unsafe {
+            pdevice
+                .instance
+                .raw
+                .get_physical_device_format_properties(pdevice.raw, format)
+        };
+
+        properties.optimal_tiling_features.contains(
+            vk::FormatFeatureFlags::BLIT_SRC
+                | vk::FormatFeatureFlags::BLIT_DST
+                | vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR,
+        )
+    }
+}
+
+// Temporal images can be blitted from when rescaling history.
+fn temporal_image_desc(desc: ImageDesc) -> ImageDesc {
+    desc.usage(desc.usage | vk::ImageUsageFlags::TRANSFER_SRC | vk::ImageUsageFlags::TRANSFER_DST)
+}
+
+// History can be rescaled if only the extent of a single-mip 2d image changed.
+fn can_rescale_history(prev: &ImageDesc, desc: &ImageDesc) -> bool {
+    prev.image_type == ImageType::Tex2d
+        && prev.mip_levels == 1
+        && *prev != *desc
+        && ImageDesc {
+            extent: desc.extent,
+            ..*prev
+        } == *desc
+}
+
+/// How `get_or_create_temporal_with` treats a temporal resource.
+#[derive(Clone, Copy, Debug)]
+pub struct TemporalResourceOptions {
+    /// When the extent of the image changes, resample its previous contents into the new image
+    /// instead of starting from scratch, as `TemporalRenderGraph::rescale_temporal_history` does
+    /// for all images. Has no effect on buffers.
+    pub rescale_on_resize: bool,
+
+    /// The access the resource is in when first imported into a graph, right after it's
+    /// created. Images are transitioned to it on creation, so that their first use in the graph
+    /// waits on nothing; e.g. histories which are sampled before they're written. `Nothing` by
+    /// default, which discards the contents on first use.
+    pub initial_access: AccessType,
+}
+
+impl Default for TemporalResourceOptions {
+    fn default() -> Self {
+        Self {
+            rescale_on_resize: false,
+            initial_access: AccessType::Nothing,
+        }
+    }
+}
+
+impl TemporalResourceOptions {
+    pub fn rescale_on_resize(mut self, rescale_on_resize: bool) -> Self {
+        self.rescale_on_resize = rescale_on_resize;
+        self
+    }
+
+    pub fn initial_access(mut self, initial_access: AccessType) -> Self {
+        self.initial_access = initial_access;
+        self
+    }
+}
+
+// Moves a new image's layout to that of `access_type`, outside of any graph.
+fn transition_new_image(
+    device: &Device,
+    image: &Image,
+    access_type: AccessType,
+) -> anyhow::Result<()> {
+    if access_type == AccessType::Nothing {
+        return Ok(());
+    }
+
+    let aspect_mask = image_aspect_mask_from_access_type_and_format(access_type, image.desc.format)
+        .with_context(|| format!("Invalid image access {:?} :: {:?}", access_type, image.desc))?;
+
+    device.with_setup_cb(|cb| {
+        record_image_barrier(
+            device,
+            cb,
+            ImageBarrier::new(image.raw, AccessType::Nothing, access_type, aspect_mask)
+                .with_discard(true),
+        )
+    })?;
+
+    Ok(())
 }
 
 pub trait GetOrCreateTemporal<Desc: ResourceDesc> {
@@ -144,18 +299,77 @@ pub trait GetOrCreateTemporal<Desc: ResourceDesc> {
         desc: Desc,
         //) -> anyhow::Result<Handle<Image>> {
     ) -> anyhow::Result<Handle<<Desc as ResourceDesc>::Resource>>
+    where
+        Desc: TypeEquals<Other = <<Desc as ResourceDesc>::Resource as Resource>::Desc>,
+    {
+        self.get_or_create_temporal_with(key, desc, Default::default())
+    }
+
+    fn get_or_create_temporal_with(
+        &mut self,
+        key: impl Into<TemporalResourceKey>,
+        desc: Desc,
+        options: TemporalResourceOptions,
+    ) -> anyhow::Result<Handle<<Desc as ResourceDesc>::Resource>>
     where
         Desc: TypeEquals<Other = <<Desc as ResourceDesc>::Resource as Resource>::Desc>;
 }
 
 impl GetOrCreateTemporal<ImageDesc> for TemporalRenderGraph {
-    fn get_or_create_temporal(
+    fn get_or_create_temporal_with(
         &mut self,
         key: impl Into<TemporalResourceKey>,
         desc: ImageDesc,
-        //) -> anyhow::Result<Handle<Image>> {
+        options: TemporalResourceOptions,
     ) -> anyhow::Result<Handle<Image>> {
         let key = key.into();
+        let desc = temporal_image_desc(desc);
+
+        // If the desc changed (e.g. due to a resolution change), the history can't be used as-is.
+        // When only the extent differs, it can optionally be carried over by rescaling it;
+        // otherwise the resource is re-created, and its history lost.
+        if let Some(TemporalResourceState::Inert {
+            resource: TemporalResource::Image(image),
+            access_type,
+        }) = self.temporal_state.resources.get(&key)
+        {
+            if image.desc != desc {
+                let prev_image = image.clone();
+                let prev_access_type = *access_type;
+
+                // Frames in flight may still use the old image, and so may the blit below.
+                self.temporal_state.resources.remove(&key);
+                self.temporal_state.replaced_images.push(prev_image.clone());
+
+                if (self.rescale_temporal_history || options.rescale_on_resize)
+                    && can_rescale_history(&prev_image.desc, &desc)
+                    && self.supports_linear_blit(desc.format)
+                {
+                    log::debug!("Rescaling temporal image {:?}", key);
+
+                    let resource = Arc::new(
+                        self.device
+                            .create_image(desc, &key.0, vec![])
+                            .with_context(|| format!("Creating image {:?}", desc))?,
+                    );
+                    let prev_handle = self.rg.import(prev_image, prev_access_type);
+                    let mut handle = self.rg.import(resource.clone(), AccessType::Nothing);
+                    imageops::blit_rescale(&mut self.rg, &prev_handle, &mut handle);
+
+                    self.temporal_state.resources.insert(
+                        key,
+                        TemporalResourceState::Imported {
+                            resource: TemporalResource::Image(resource),
+                            handle: ExportableGraphResource::Image(handle.clone_unchecked()),
+                        },
+                    );
+
+                    return Ok(handle);
+                }
+
+                log::debug!("Re-creating temporal image {:?}: desc changed", key);
+            }
+        }
 
         match self.temporal_state.resources.entry(key.clone()) {
             hash_map::Entry::Occupied(mut entry) => {
@@ -181,7 +395,7 @@ impl GetOrCreateTemporal<ImageDesc> for TemporalRenderGraph {
 
                                 Ok(handle)
                             }
-                            TemporalResource::Buffer(_) => {
+                            TemporalResource::Buffer(_) | TemporalResource::BufferSlice { .. } => {
                                 anyhow::bail!(
                                     "Resource {:?} is a buffer, but an image was requested",
                                     key
@@ -201,10 +415,13 @@ impl GetOrCreateTemporal<ImageDesc> for TemporalRenderGraph {
             hash_map::Entry::Vacant(entry) => {
                 let resource = Arc::new(
                     self.device
-                        .create_image(desc, vec![])
+                        .create_image(desc, &key.0, vec![])
                         .with_context(|| format!("Creating image {:?}", desc))?,
                 );
-                let handle = self.rg.import(resource.clone(), AccessType::Nothing);
+                transition_new_image(&self.device, &resource, options.initial_access)
+                    .with_context(|| format!("Transitioning image {:?}", key))?;
+
+                let handle = self.rg.import(resource.clone(), options.initial_access);
                 entry.insert(TemporalResourceState::Imported {
                     resource: TemporalResource::Image(resource),
                     handle: ExportableGraphResource::Image(handle.clone_unchecked()),
@@ -216,14 +433,29 @@ impl GetOrCreateTemporal<ImageDesc> for TemporalRenderGraph {
 }
 
 impl GetOrCreateTemporal<BufferDesc> for TemporalRenderGraph {
-    fn get_or_create_temporal(
+    fn get_or_create_temporal_with(
         &mut self,
         key: impl Into<TemporalResourceKey>,
         desc: BufferDesc,
-        //) -> anyhow::Result<Handle<Image>> {
+        options: TemporalResourceOptions,
     ) -> anyhow::Result<Handle<Buffer>> {
         let key = key.into();
 
+        if let Some(TemporalResourceState::Inert {
+            resource: TemporalResource::Buffer(buffer),
+            ..
+        }) = self.temporal_state.resources.get(&key)
+        {
+            if buffer.desc != desc {
+                log::debug!("Re-creating temporal buffer {:?}: desc changed", key);
+
+                // Frames in flight may still use the old buffer.
+                let prev_buffer = buffer.clone();
+                self.temporal_state.resources.remove(&key);
+                self.temporal_state.replaced_buffers.push(prev_buffer);
+            }
+        }
+
         match self.temporal_state.resources.entry(key.clone()) {
             hash_map::Entry::Occupied(mut entry) => {
                 let state = entry.get_mut();
@@ -254,6 +486,12 @@ impl GetOrCreateTemporal<BufferDesc> for TemporalRenderGraph {
                                     key
                                 );
                             }
+                            TemporalResource::BufferSlice { .. } => {
+                                anyhow::bail!(
+                                    "Resource {:?} is a buffer slice; see `get_or_create_temporal_slice`",
+                                    key
+                                );
+                            }
                         }
                     }
                     TemporalResourceState::Imported { .. } => Err(anyhow::anyhow!(
@@ -266,8 +504,9 @@ impl GetOrCreateTemporal<BufferDesc> for TemporalRenderGraph {
                 }
             }
             hash_map::Entry::Vacant(entry) => {
+                // Buffers have no layout, and new ones no writes to wait on.
                 let resource = Arc::new(self.device.create_buffer(desc, &key.0, None)?);
-                let handle = self.rg.import(resource.clone(), AccessType::Nothing);
+                let handle = self.rg.import(resource.clone(), options.initial_access);
                 entry.insert(TemporalResourceState::Imported {
                     resource: TemporalResource::Buffer(resource),
                     handle: ExportableGraphResource::Buffer(handle.clone_unchecked()),
@@ -279,6 +518,137 @@ impl GetOrCreateTemporal<BufferDesc> for TemporalRenderGraph {
 }
 
 impl TemporalRenderGraph {
+    /// A temporal buffer of `size` bytes, suballocated from buffers shared with other slices,
+    /// for effects which keep many small histories. Bind it with its offset and size, e.g. via
+    /// `SimpleRenderPass::read_slice`. Up to `MAX_BUFFER_SLICE_SIZE` bytes.
+    pub fn get_or_create_temporal_slice(
+        &mut self,
+        key: impl Into<TemporalResourceKey>,
+        size: usize,
+    ) -> anyhow::Result<TemporalBufferSlice> {
+        let key = key.into();
+        let size = size as u64;
+
+        anyhow::ensure!(
+            size <= MAX_BUFFER_SLICE_SIZE,
+            "Temporal buffer slice {:?} of {} bytes is too large; use `get_or_create_temporal`",
+            key,
+            size
+        );
+
+        if let Some(TemporalResourceState::Inert {
+            resource:
+                TemporalResource::BufferSlice {
+                    slot,
+                    size: prev_size,
+                    ..
+                },
+            ..
+        }) = self.temporal_state.resources.get(&key)
+        {
+            if *prev_size != size {
+                log::debug!("Re-creating temporal buffer slice {:?}: size changed", key);
+
+                let slot = *slot;
+                self.temporal_state.resources.remove(&key);
+                self.temporal_state.buffer_pool.release(&self.device, slot);
+            }
+        }
+
+        match self.temporal_state.resources.entry(key.clone()) {
+            hash_map::Entry::Occupied(mut entry) => {
+                let state = entry.get_mut();
+
+                match state {
+                    TemporalResourceState::Inert {
+                        resource,
+                        access_type,
+                    } => {
+                        let resource = resource.clone();
+
+                        match &resource {
+                            TemporalResource::BufferSlice { buffer, slot, .. } => {
+                                let offset = slot.offset;
+                                let handle = self.rg.import(buffer.clone(), *access_type);
+
+                                *state = TemporalResourceState::Imported {
+                                    resource,
+                                    handle: ExportableGraphResource::Buffer(
+                                        handle.clone_unchecked(),
+                                    ),
+                                };
+
+                                Ok(TemporalBufferSlice {
+                                    buffer: handle,
+                                    offset,
+                                    size,
+                                })
+                            }
+                            TemporalResource::Image(_) | TemporalResource::Buffer(_) => {
+                                anyhow::bail!(
+                                    "Resource {:?} isn't a buffer slice, but one was requested",
+                                    key
+                                );
+                            }
+                        }
+                    }
+                    TemporalResourceState::Imported { .. } => Err(anyhow::anyhow!(
+                        "Temporal resource already taken: {:?}",
+                        key
+                    )),
+                    TemporalResourceState::Exported { .. } => {
+                        unreachable!()
+                    }
+                }
+            }
+            hash_map::Entry::Vacant(entry) => {
+                let (buffer, slot) = self
+                    .temporal_state
+                    .buffer_pool
+                    .allocate(&self.device, size)
+                    .with_context(|| format!("Allocating temporal buffer slice {:?}", key))?;
+
+                let handle = self.rg.import(buffer.clone(), AccessType::Nothing);
+                entry.insert(TemporalResourceState::Imported {
+                    resource: TemporalResource::BufferSlice { buffer, slot, size },
+                    handle: ExportableGraphResource::Buffer(handle.clone_unchecked()),
+                });
+
+                Ok(TemporalBufferSlice {
+                    buffer: handle,
+                    offset: slot.offset,
+                    size,
+                })
+            }
+        }
+    }
+
+    /// Releases a temporal resource which is no longer used, once frames in flight are done
+    /// with it. Does nothing if there's no such resource, or if it was already used this frame.
+    pub fn release_temporal(&mut self, key: impl Into<TemporalResourceKey>) {
+        let entry = match self.temporal_state.resources.entry(key.into()) {
+            hash_map::Entry::Occupied(entry) => entry,
+            hash_map::Entry::Vacant(_) => return,
+        };
+
+        if !matches!(entry.get(), TemporalResourceState::Inert { .. }) {
+            return;
+        }
+
+        match entry.remove() {
+            TemporalResourceState::Inert { resource, .. } => match resource {
+                TemporalResource::Image(image) => self.temporal_state.replaced_images.push(image),
+                TemporalResource::Buffer(buffer) => {
+                    self.temporal_state.replaced_buffers.push(buffer)
+                }
+                TemporalResource::BufferSlice { slot, .. } => {
+                    self.temporal_state.buffer_pool.release(&self.device, slot)
+                }
+            },
+            _ => unreachable!(),
+        }
+    }
+
     pub fn export_temporal(self) -> (RenderGraph, ExportedTemporalRenderGraphState) {
         let mut rg = self.rg;
         let mut state = self.temporal_state;
@@ -315,9 +685,21 @@ impl TemporalRenderGraph {
 }
 
 impl ExportedTemporalRenderGraphState {
-    pub fn retire_temporal(self, rg: &RetiredRenderGraph) -> TemporalRenderGraphState {
+    pub fn retire_temporal(
+        self,
+        device: &Device,
+        rg: &RetiredRenderGraph,
+    ) -> TemporalRenderGraphState {
         let mut state = self.0;
 
+        for image in state.replaced_images.drain(..) {
+            device.defer_release_image(&image);
+        }
+
+        for buffer in state.replaced_buffers.drain(..) {
+            device.defer_release_buffer(buffer);
+        }
+
         for state in state.resources.values_mut() {
             match state {
                 TemporalResourceState::Inert { .. } => {
@@ -346,3 +728,251 @@ impl ExportedTemporalRenderGraphState {
         state
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resize_stress_history_compatibility() {
+        let base = temporal_image_desc(
+            ImageDesc::new_2d(vk::Format::R16G16B16A16_SFLOAT, [1280, 720])
+                .usage(vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::STORAGE),
+        );
+        assert!(!can_rescale_history(&base, &base));
+
+        let mut prev = base;
+        for i in 1..1000u32 {
+            let extent = [1 + (i * 7919) % 3840, 1 + (i * 104729) % 2160];
+            let desc = base.extent([extent[0], extent[1], 1]);
+
+            assert_eq!(
+                can_rescale_history(&prev, &desc),
+                prev.extent != desc.extent
+            );
+            assert!(!can_rescale_history(
+                &prev,
+                &desc.format(vk::Format::R32G32B32A32_SFLOAT)
+            ));
+            assert!(!can_rescale_history(&prev, &desc.all_mip_levels()));
+            assert!(!can_rescale_history(
+                &prev.image_type(ImageType::Tex2dArray),
+                &desc.image_type(ImageType::Tex2dArray)
+            ));
+
+            prev = desc;
+        }
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn rescaling_on_resize_preserves_history() {
+        use crate::{
+            readback::AsyncReadback,
+            renderer::{FrameConstantsLayout, Renderer},
+        };
+        use kajiya_backend::{file::set_standard_vfs_mount_points, HeadlessRenderBackend};
+
+        set_standard_vfs_mount_points(concat!(env!("CARGO_MANIFEST_DIR"), "/../../.."));
+
+        let backend = HeadlessRenderBackend::new(Default::default()).unwrap();
+        let device = backend.device.clone();
+        let mut renderer = Renderer::new(&device).unwrap();
+        let mut readback = AsyncReadback::default();
+
+        let options = TemporalResourceOptions::default().rescale_on_resize(true);
+        let history_desc = |extent: [u32; 2]| {
+            ImageDesc::new_2d(vk::Format::R8G8B8A8_UNORM, extent)
+                .usage(vk::ImageUsageFlags::SAMPLED)
+        };
+
+        // The first frame fills the history, and the second one resizes it.
+        let mut token = None;
+        for extent in [[4, 4], [16, 8]] {
+            readback.begin_frame();
+
+            renderer
+                .prepare_frame(|rg| {
+                    let mut history = rg
+                        .get_or_create_temporal_with("history", history_desc(extent), options)
+                        .unwrap();
+
+                    if extent == [4, 4] {
+                        imageops::clear_color(rg, &mut history, [1.0, 0.0, 1.0, 1.0]);
+                    } else {
+                        token = Some(readback.copy_image(rg, &history, 4));
+                    }
+                })
+                .unwrap();
+            renderer
+                .draw_frame_headless(|_| FrameConstantsLayout {
+                    globals_offset: 0,
+                    instance_dynamic_parameters_offset: 0,
+                    triangle_lights_offset: 0,
+                })
+                .unwrap();
+            readback.retire_frame(device.frame_counter());
+        }
+
+        let texels = readback.wait(&device, token.unwrap()).unwrap();
+        assert_eq!(texels.len(), 16 * 8 * 4);
+        assert!(texels
+            .chunks_exact(4)
+            .all(|texel| texel == [255, 0, 255, 255]));
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn resizing_recreates_only_changed_temporal_images() {
+        use crate::{
+            readback::AsyncReadback,
+            renderer::{FrameConstantsLayout, Renderer},
+        };
+        use kajiya_backend::{file::set_standard_vfs_mount_points, HeadlessRenderBackend};
+
+        set_standard_vfs_mount_points(concat!(env!("CARGO_MANIFEST_DIR"), "/../../.."));
+
+        let backend = HeadlessRenderBackend::new(Default::default()).unwrap();
+        let device = backend.device.clone();
+        let mut renderer = Renderer::new(&device).unwrap();
+        let mut readback = AsyncReadback::default();
+
+        let image_desc = |extent: [u32; 2]| {
+            ImageDesc::new_2d(vk::Format::R8G8B8A8_UNORM, extent)
+                .usage(vk::ImageUsageFlags::SAMPLED)
+        };
+
+        // The first frame fills both images, and the second one resizes only one of them.
+        let mut tokens = None;
+        for resized_extent in [[4, 4], [16, 8]] {
+            readback.begin_frame();
+
+            renderer
+                .prepare_frame(|rg| {
+                    let mut resized = rg
+                        .get_or_create_temporal("resized", image_desc(resized_extent))
+                        .unwrap();
+                    let mut unchanged = rg
+                        .get_or_create_temporal("unchanged", image_desc([4, 4]))
+                        .unwrap();
+
+                    if resized_extent == [4, 4] {
+                        imageops::clear_color(rg, &mut resized, [1.0, 0.0, 1.0, 1.0]);
+                        imageops::clear_color(rg, &mut unchanged, [1.0, 0.0, 1.0, 1.0]);
+                    } else {
+                        tokens = Some((
+                            readback.copy_image(rg, &resized, 4),
+                            readback.copy_image(rg, &unchanged, 4),
+                        ));
+                    }
+                })
+                .unwrap();
+            renderer
+                .draw_frame_headless(|_| FrameConstantsLayout {
+                    globals_offset: 0,
+                    instance_dynamic_parameters_offset: 0,
+                    triangle_lights_offset: 0,
+                })
+                .unwrap();
+            readback.retire_frame(device.frame_counter());
+        }
+
+        let (resized, unchanged) = tokens.unwrap();
+        assert_eq!(readback.wait(&device, resized).unwrap().len(), 16 * 8 * 4);
+
+        // The image which kept its desc kept its history too.
+        let texels = readback.wait(&device, unchanged).unwrap();
+        assert_eq!(texels.len(), 4 * 4 * 4);
+        assert!(texels
+            .chunks_exact(4)
+            .all(|texel| texel == [255, 0, 255, 255]));
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn recreated_temporal_buffers_are_released() {
+        use crate::renderer::{FrameConstantsLayout, Renderer};
+        use kajiya_backend::{
+            file::set_standard_vfs_mount_points, vulkan::memory_stats::ResourceCategory,
+            HeadlessRenderBackend,
+        };
+
+        set_standard_vfs_mount_points(concat!(env!("CARGO_MANIFEST_DIR"), "/../../.."));
+
+        let backend = HeadlessRenderBackend::new(Default::default()).unwrap();
+        let device = backend.device.clone();
+        let mut renderer = Renderer::new(&device).unwrap();
+
+        let mut live_buffer_counts = Vec::new();
+        for frame in 0..8 {
+            // A different size every frame re-creates the buffer.
+            let desc =
+                BufferDesc::new_gpu_only(1024 * (frame + 1), vk::BufferUsageFlags::STORAGE_BUFFER);
+
+            renderer
+                .prepare_frame(|rg| {
+                    rg.get_or_create_temporal("history", desc).unwrap();
+                })
+                .unwrap();
+            renderer
+                .draw_frame_headless(|_| FrameConstantsLayout {
+                    globals_offset: 0,
+                    instance_dynamic_parameters_offset: 0,
+                    triangle_lights_offset: 0,
+                })
+                .unwrap();
+
+            live_buffer_counts.push(device.memory_stats().get(ResourceCategory::Buffer).count);
+        }
+
+        assert!(
+            live_buffer_counts.windows(2).all(|w| w[0] == w[1]),
+            "{:?}",
+            live_buffer_counts
+        );
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn recreated_temporal_images_are_released() {
+        use crate::renderer::{FrameConstantsLayout, Renderer};
+        use kajiya_backend::{
+            file::set_standard_vfs_mount_points, vulkan::memory_stats::ResourceCategory,
+            HeadlessRenderBackend,
+        };
+
+        set_standard_vfs_mount_points(concat!(env!("CARGO_MANIFEST_DIR"), "/../../.."));
+
+        let backend = HeadlessRenderBackend::new(Default::default()).unwrap();
+        let device = backend.device.clone();
+        let mut renderer = Renderer::new(&device).unwrap();
+
+        let mut live_image_counts = Vec::new();
+        for frame in 0..8 {
+            // A different extent every frame re-creates the image.
+            let desc = ImageDesc::new_2d(vk::Format::R8G8B8A8_UNORM, [16 * (frame + 1), 16])
+                .usage(vk::ImageUsageFlags::SAMPLED);
+
+            renderer
+                .prepare_frame(|rg| {
+                    rg.get_or_create_temporal("history", desc).unwrap();
+                })
+                .unwrap();
+            renderer
+                .draw_frame_headless(|_| FrameConstantsLayout {
+                    globals_offset: 0,
+                    instance_dynamic_parameters_offset: 0,
+                    triangle_lights_offset: 0,
+                })
+                .unwrap();
+
+            live_image_counts.push(device.memory_stats().get(ResourceCategory::Image).count);
+        }
+
+        assert!(
+            live_image_counts.windows(2).all(|w| w[0] == w[1]),
+            "{:?}",
+            live_image_counts
+        );
+    }
+}
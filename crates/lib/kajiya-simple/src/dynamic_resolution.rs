@@ -0,0 +1,121 @@
+/// Settings for `DynamicResolution`. Scales are relative to the nominal
+/// internal rendering resolution, and apply to each axis.
+#[derive(Clone, Copy, Debug)]
+pub struct DynamicResolutionDesc {
+    /// GPU frame time budget in milliseconds.
+    pub target_frame_ms: f32,
+    pub min_scale: f32,
+    pub max_scale: f32,
+}
+
+impl Default for DynamicResolutionDesc {
+    fn default() -> Self {
+        Self {
+            target_frame_ms: 1000.0 / 60.0,
+            min_scale: 0.5,
+            max_scale: 1.0,
+        }
+    }
+}
+
+/// Scales the internal rendering resolution to keep the GPU frame time under a budget.
+///
+/// Every scale change re-creates the resolution-dependent temporal resources,
+/// so the controller only reacts to sustained changes in frame time:
+/// * Frame times are smoothed with an exponential moving average;
+/// * Resolution is only raised if there's a comfortable margin below the budget;
+/// * Scales are quantized, and changes are followed by a cooldown period
+///   while the frame time settles at the new resolution.
+pub struct DynamicResolution {
+    pub desc: DynamicResolutionDesc,
+    scale: f32,
+    filtered_frame_ms: Option<f32>,
+    cooldown_frames: u32,
+}
+
+// Smoothing factor for the frame time moving average
+const FRAME_TIME_FILTER: f32 = 0.1;
+
+// Only scale up if the frame time is below this fraction of the budget
+const UPSCALE_THRESHOLD: f32 = 0.85;
+
+// When changing the scale, aim for this fraction of the budget
+const TARGET_HEADROOM: f32 = 0.95;
+
+// Frames to wait after a scale change before considering another one
+const COOLDOWN_FRAMES: u32 = 30;
+
+const SCALE_QUANTUM: f32 = 1.0 / 32.0;
+
+impl DynamicResolution {
+    pub fn new(desc: DynamicResolutionDesc) -> Self {
+        assert!(desc.target_frame_ms > 0.0);
+        assert!(desc.min_scale > 0.0 && desc.min_scale <= desc.max_scale);
+
+        Self {
+            desc,
+            scale: desc.max_scale,
+            filtered_frame_ms: None,
+            cooldown_frames: COOLDOWN_FRAMES,
+        }
+    }
+
+    /// The current per-axis resolution scale.
+    pub fn scale(&self) -> f32 {
+        self.scale
+    }
+
+    /// Smoothed GPU frame time in milliseconds.
+    pub fn filtered_frame_ms(&self) -> Option<f32> {
+        self.filtered_frame_ms
+    }
+
+    /// Feed the GPU time of the last completed frame, and get the scale for the next one.
+    pub fn update(&mut self, gpu_frame_ms: f32) -> f32 {
+        if gpu_frame_ms.is_nan() || gpu_frame_ms <= 0.0 {
+            return self.scale;
+        }
+
+        let filtered_ms = match self.filtered_frame_ms {
+            Some(prev) => prev + (gpu_frame_ms - prev) * FRAME_TIME_FILTER,
+            None => gpu_frame_ms,
+        };
+        self.filtered_frame_ms = Some(filtered_ms);
+
+        if self.cooldown_frames > 0 {
+            self.cooldown_frames -= 1;
+            return self.scale;
+        }
+
+        let target_ms = self.desc.target_frame_ms;
+        if filtered_ms > target_ms || filtered_ms < target_ms * UPSCALE_THRESHOLD {
+            // GPU time is roughly proportional to the pixel count, so the square of the scale.
+            let ideal = self.scale * (target_ms * TARGET_HEADROOM / filtered_ms).sqrt();
+            let quantized = (ideal / SCALE_QUANTUM).floor() * SCALE_QUANTUM;
+            let new_scale = quantized.clamp(self.desc.min_scale, self.desc.max_scale);
+
+            if new_scale != self.scale {
+                self.scale = new_scale;
+                self.cooldown_frames = COOLDOWN_FRAMES;
+
+                // The old measurements don't apply to the new resolution.
+                self.filtered_frame_ms = None;
+            }
+        }
+
+        self.scale
+    }
+
+    /// Apply the current scale to a nominal rendering extent.
+    pub fn scale_extent(&self, extent: [u32; 2]) -> [u32; 2] {
+        [
+            scale_extent_axis(extent[0], self.scale),
+            scale_extent_axis(extent[1], self.scale),
+        ]
+    }
+}
+
+// Round down to a multiple of 2 so that half-resolution passes line up.
+fn scale_extent_axis(extent: u32, scale: f32) -> u32 {
+    (((extent as f32 * scale) as u32) & !1).max(2)
+}
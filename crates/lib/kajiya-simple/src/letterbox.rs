@@ -0,0 +1,109 @@
+/// Keeps the rendered image at a fixed aspect ratio, regardless of the window's shape.
+/// The image is centered in the window, and the rest is filled with `bar_color`:
+/// bars above and below if the window is taller (letterbox), or to the sides if it's wider (pillarbox).
+#[derive(Clone, Copy, Debug)]
+pub struct Letterbox {
+    /// Width over height of the rendered image. Use the same for the camera's `CameraLens`.
+    pub aspect_ratio: f32,
+
+    /// Linear color of the bars, with 1.0 being SDR white.
+    pub bar_color: [f32; 3],
+}
+
+impl Letterbox {
+    pub fn new(aspect_ratio: f32) -> Self {
+        Self {
+            aspect_ratio,
+            bar_color: [0.0; 3],
+        }
+    }
+}
+
+/// The rectangle of the swapchain which the world renderer's output is shown in, in physical pixels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Viewport {
+    pub offset: [u32; 2],
+    pub extent: [u32; 2],
+}
+
+impl Viewport {
+    /// The whole of `output_extent`, or its centered part with the aspect ratio of `letterbox`.
+    pub fn new(output_extent: [u32; 2], letterbox: Option<&Letterbox>) -> Self {
+        let letterbox = if let Some(letterbox) = letterbox {
+            letterbox
+        } else {
+            return Self {
+                offset: [0, 0],
+                extent: output_extent,
+            };
+        };
+
+        let [width, height] = output_extent;
+        let extent = if width as f32 > height as f32 * letterbox.aspect_ratio {
+            [
+                ((height as f32 * letterbox.aspect_ratio).round() as u32).clamp(1, width),
+                height,
+            ]
+        } else {
+            [
+                width,
+                ((width as f32 / letterbox.aspect_ratio).round() as u32).clamp(1, height),
+            ]
+        };
+
+        Self {
+            offset: [(width - extent[0]) / 2, (height - extent[1]) / 2],
+            extent,
+        }
+    }
+
+    /// Maps a position in the swapchain, such as the cursor's, to normalized coordinates
+    /// within the image. `None` over the bars.
+    pub fn to_uv(&self, position: [f64; 2]) -> Option<[f32; 2]> {
+        let uv = [
+            ((position[0] - self.offset[0] as f64) / self.extent[0] as f64) as f32,
+            ((position[1] - self.offset[1] as f64) / self.extent[1] as f64) as f32,
+        ];
+
+        if uv.iter().all(|c| (0.0..1.0).contains(c)) {
+            Some(uv)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bars_surround_the_active_rect() {
+        // Cinemascope in a 16:9 window: bars above and below
+        let viewport = Viewport::new([1920, 1080], Some(&Letterbox::new(2.39)));
+        assert_eq!(viewport.extent, [1920, 803]);
+        assert_eq!(viewport.offset, [0, 138]);
+        assert_eq!(viewport.to_uv([960.0, 100.0]), None);
+        assert_eq!(viewport.to_uv([960.0, 1000.0]), None);
+        assert_eq!(viewport.to_uv([0.0, 138.0]), Some([0.0, 0.0]));
+
+        // 4:3 in the same window: bars to the sides
+        let viewport = Viewport::new([1920, 1080], Some(&Letterbox::new(4.0 / 3.0)));
+        assert_eq!(viewport.extent, [1440, 1080]);
+        assert_eq!(viewport.offset, [240, 0]);
+        assert_eq!(viewport.to_uv([100.0, 540.0]), None);
+        assert_eq!(viewport.to_uv([1800.0, 540.0]), None);
+        assert_eq!(viewport.to_uv([960.0, 540.0]), Some([0.5, 0.5]));
+
+        // Matching aspect ratios, or no letterbox: the whole window
+        let full = Viewport {
+            offset: [0, 0],
+            extent: [1920, 1080],
+        };
+        assert_eq!(
+            Viewport::new([1920, 1080], Some(&Letterbox::new(16.0 / 9.0))),
+            full
+        );
+        assert_eq!(Viewport::new([1920, 1080], None), full);
+    }
+}
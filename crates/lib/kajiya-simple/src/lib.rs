@@ -1,6 +1,9 @@
+mod dynamic_resolution;
 mod input;
+mod letterbox;
 mod main_loop;
 
+pub use dynamic_resolution::*;
 pub use glam::*;
 pub use input::*;
 pub use kajiya::{
@@ -9,10 +12,14 @@ pub use kajiya::{
         *,
     },
     camera::*,
-    frame_desc::WorldFrameDesc,
+    camera_path::*,
+    frame_desc::{FrameTime, WorldFrameDesc},
     math::*,
     world_renderer::{RenderDebugMode, RenderMode},
 };
+#[cfg(feature = "egui")]
+pub use kajiya_egui::egui;
+pub use letterbox::*;
 pub use log;
 pub use main_loop::*;
 pub use winit::{
@@ -1,8 +1,18 @@
 use std::collections::VecDeque;
 
+use crate::{
+    dynamic_resolution::{DynamicResolution, DynamicResolutionDesc},
+    letterbox::{Letterbox, Viewport},
+};
+
 use kajiya::{
-    backend::{vulkan::RenderBackendConfig, *},
-    frame_desc::WorldFrameDesc,
+    backend::{
+        vulkan::{device, RenderBackendConfig},
+        *,
+    },
+    camera::CameraMatrices,
+    frame_desc::{FrameTime, WorldFrameDesc},
+    math::Vec3,
     rg,
     ui_renderer::UiRenderer,
     world_renderer::WorldRenderer,
@@ -11,6 +21,9 @@ use kajiya::{
 #[cfg(feature = "dear-imgui")]
 use kajiya_imgui::ImGuiBackend;
 
+#[cfg(feature = "egui")]
+use kajiya_egui::{egui, EguiBackend};
+
 use turbosloth::*;
 
 use winit::{
@@ -22,18 +35,59 @@ use winit::{
 
 pub struct FrameContext<'a> {
     pub dt_filtered: f32,
+
+    /// Frame index and time derived from `dt_filtered`; pass it on in `WorldFrameDesc`,
+    /// or substitute a scripted one (e.g. `FrameTime::fixed_step`) for reproducible captures.
+    pub time: FrameTime,
+
+    /// Internal rendering resolution for this frame. Varies if dynamic resolution is enabled.
     pub render_extent: [u32; 2],
+
+    /// Size of the swapchain images. Mouse positions are in the same physical pixels.
+    pub swapchain_extent: [u32; 2],
+
+    /// Where in the swapchain the world renderer's output is shown; all of it, unless
+    /// `SimpleMainLoopBuilder::letterbox` is used. Map mouse positions with `Viewport::to_uv`.
+    pub viewport: Viewport,
+
     pub events: &'a [WindowEvent<'static>],
     pub world_renderer: &'a mut WorldRenderer,
 
     #[cfg(feature = "dear-imgui")]
     pub imgui: Option<ImguiContext<'a>>,
+
+    #[cfg(feature = "egui")]
+    pub egui: Option<EguiContext<'a>>,
+
+    /// The presentation mode currently in use by the swapchain.
+    pub present_mode: PresentMode,
+
+    requested_present_mode: &'a mut Option<PresentMode>,
+
+    /// Present if enabled via `SimpleMainLoopBuilder::dynamic_resolution`. The budget
+    /// and scale bounds can be adjusted through its `desc`.
+    pub dynamic_resolution: Option<&'a mut DynamicResolution>,
+
+    /// Frames submitted to the GPU but not finished yet as this frame started.
+    /// Always 0 with `SimpleMainLoopBuilder::low_latency`.
+    pub queued_frames: usize,
+
+    /// With `SimpleMainLoopBuilder::low_latency`: time from the start of the previous frame,
+    /// when its input was read, until the GPU finished rendering it. The time to present and scan
+    /// the image out isn't included, so the latency to photons is somewhat higher.
+    pub input_to_gpu_latency: Option<std::time::Duration>,
 }
 
 impl<'a> FrameContext<'a> {
     pub fn aspect_ratio(&self) -> f32 {
         self.render_extent[0] as f32 / self.render_extent[1] as f32
     }
+
+    /// Switch to a different presentation mode. The swapchain is re-created after this frame;
+    /// if the mode isn't supported, a fallback is chosen and reported in `present_mode`.
+    pub fn set_present_mode(&mut self, present_mode: PresentMode) {
+        *self.requested_present_mode = Some(present_mode);
+    }
 }
 
 #[cfg(feature = "dear-imgui")]
@@ -57,6 +111,19 @@ impl<'a> ImguiContext<'a> {
     }
 }
 
+#[cfg(feature = "egui")]
+pub struct EguiContext<'a> {
+    egui_backend: &'a mut EguiBackend,
+    window: &'a winit::window::Window,
+}
+
+#[cfg(feature = "egui")]
+impl<'a> EguiContext<'a> {
+    pub fn frame(self, callback: impl FnOnce(&egui::CtxRef)) {
+        self.egui_backend.frame(self.window, callback);
+    }
+}
+
 struct MainLoopOptional {
     #[cfg(feature = "dear-imgui")]
     imgui_backend: ImGuiBackend,
@@ -64,6 +131,9 @@ struct MainLoopOptional {
     #[cfg(feature = "dear-imgui")]
     imgui: imgui::Context,
 
+    #[cfg(feature = "egui")]
+    egui_backend: EguiBackend,
+
     #[cfg(feature = "puffin-server")]
     _puffin_server: puffin_http::Server,
 }
@@ -82,14 +152,40 @@ pub enum FullscreenMode {
     Exclusive,
 }
 
+/// Luminance of SDR white (and UI) when presenting in HDR; the BT.2408 reference white.
+const HDR_SDR_WHITE_NITS: f32 = 203.0;
+
+// Must match the constants in `final_blit.hlsl`
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct FinalBlitConstants {
+    main_tex_size: [f32; 4],
+    output_tex_size: [f32; 4],
+    output_encoding: u32,
+    sdr_white_nits: f32,
+    noise_frame: u32,
+    noise_amplitude: f32,
+    main_rect: [f32; 4],
+    bar_color: [f32; 4],
+}
+
 pub struct SimpleMainLoopBuilder {
     resolution: [u32; 2],
-    vsync: bool,
+    present_mode: PresentMode,
     fullscreen: Option<FullscreenMode>,
-    graphics_debugging: bool,
+    validation: ValidationConfig,
     default_log_level: log::LevelFilter,
     window_scale: WindowScale,
     temporal_upsampling: f32,
+    frames_in_flight: usize,
+    hdr_output: bool,
+    hdr_peak_nits: f32,
+    dynamic_resolution: Option<DynamicResolutionDesc>,
+    rescale_history_on_resize: bool,
+    low_latency: bool,
+    letterbox: Option<Letterbox>,
+    leak_check: bool,
+    descriptor_pools: DescriptorPoolConfig,
 }
 
 impl Default for SimpleMainLoopBuilder {
@@ -102,12 +198,21 @@ impl SimpleMainLoopBuilder {
     pub fn new() -> Self {
         SimpleMainLoopBuilder {
             resolution: [1280, 720],
-            vsync: true,
+            present_mode: PresentMode::FifoRelaxed,
             fullscreen: None,
-            graphics_debugging: false,
+            validation: Default::default(),
             default_log_level: log::LevelFilter::Warn,
             window_scale: WindowScale::SystemNative,
             temporal_upsampling: 1.0,
+            frames_in_flight: 2,
+            hdr_output: false,
+            hdr_peak_nits: 1000.0,
+            dynamic_resolution: None,
+            rescale_history_on_resize: false,
+            low_latency: false,
+            letterbox: None,
+            leak_check: false,
+            descriptor_pools: Default::default(),
         }
     }
 
@@ -116,13 +221,34 @@ impl SimpleMainLoopBuilder {
         self
     }
 
+    /// Shorthand for `present_mode`: `FifoRelaxed` with VSync, `Mailbox` without.
     pub fn vsync(mut self, vsync: bool) -> Self {
-        self.vsync = vsync;
+        self.present_mode = if vsync {
+            PresentMode::FifoRelaxed
+        } else {
+            PresentMode::Mailbox
+        };
         self
     }
 
+    /// Falls back to a supported mode if the preferred one isn't available.
+    pub fn present_mode(mut self, present_mode: PresentMode) -> Self {
+        self.present_mode = present_mode;
+        self
+    }
+
+    /// Shorthand for `validation`: `ErrorsOnly` when enabled.
     pub fn graphics_debugging(mut self, graphics_debugging: bool) -> Self {
-        self.graphics_debugging = graphics_debugging;
+        self.validation.level = if graphics_debugging {
+            ValidationLevel::ErrorsOnly
+        } else {
+            ValidationLevel::Off
+        };
+        self
+    }
+
+    pub fn validation(mut self, validation: ValidationConfig) -> Self {
+        self.validation = validation;
         self
     }
 
@@ -150,6 +276,74 @@ impl SimpleMainLoopBuilder {
         self
     }
 
+    /// Number of frames the CPU may record ahead of the GPU; clamped to 1..=3.
+    ///
+    /// 1 gives the lowest latency and deterministic captures, but the CPU and GPU
+    /// never overlap. 2 (the default) is a good balance. 3 smooths out frame time spikes
+    /// at the cost of an extra frame of latency and memory.
+    pub fn frames_in_flight(mut self, frames_in_flight: usize) -> Self {
+        self.frames_in_flight = frames_in_flight.clamp(1, device::MAX_FRAMES_IN_FLIGHT);
+        self
+    }
+
+    /// Present in HDR (HDR10 or scRGB) if the display supports it; falls back to SDR otherwise.
+    pub fn hdr_output(mut self, hdr_output: bool) -> Self {
+        self.hdr_output = hdr_output;
+        self
+    }
+
+    /// Peak luminance the display transform targets when presenting in HDR.
+    /// Should match the capabilities of the display.
+    pub fn hdr_peak_nits(mut self, hdr_peak_nits: f32) -> Self {
+        self.hdr_peak_nits = hdr_peak_nits.max(HDR_SDR_WHITE_NITS);
+        self
+    }
+
+    /// Scale the internal rendering resolution to stay within a GPU frame time budget.
+    /// Composes with `temporal_upsampling`: the scale applies on top of its resolution,
+    /// and TAA upsamples the result to the target resolution.
+    pub fn dynamic_resolution(mut self, dynamic_resolution: Option<DynamicResolutionDesc>) -> Self {
+        self.dynamic_resolution = dynamic_resolution;
+        self
+    }
+
+    /// When the window is resized (or dynamic resolution kicks in), resample temporal
+    /// history into the new resolution instead of resetting accumulation.
+    /// Reduces the visible flash, but may leave some artifacts for a few frames.
+    pub fn rescale_history_on_resize(mut self, rescale_history_on_resize: bool) -> Self {
+        self.rescale_history_on_resize = rescale_history_on_resize;
+        self
+    }
+
+    /// Wait for the GPU to finish the previous frame before reading input for the next one,
+    /// instead of letting up to `frames_in_flight` frames queue up. Reduces input latency
+    /// by up to `frames_in_flight - 1` frames when GPU-bound, but the CPU and GPU no longer
+    /// overlap, so the frame rate can drop when both are busy.
+    pub fn low_latency(mut self, low_latency: bool) -> Self {
+        self.low_latency = low_latency;
+        self
+    }
+
+    /// Log the GPU resources which were never freed at shutdown; see `Device::set_leak_check`.
+    pub fn leak_check(mut self, leak_check: bool) -> Self {
+        self.leak_check = leak_check;
+        self
+    }
+
+    /// The initial size of the per-frame descriptor pools, which grow when exhausted;
+    /// see `Device::descriptor_pool_stats`.
+    pub fn descriptor_pools(mut self, descriptor_pools: DescriptorPoolConfig) -> Self {
+        self.descriptor_pools = descriptor_pools;
+        self
+    }
+
+    /// Render at a fixed aspect ratio, with bars filling the rest of the window. The rendering
+    /// resolution is reduced to the size of the image within the window.
+    pub fn letterbox(mut self, letterbox: Option<Letterbox>) -> Self {
+        self.letterbox = letterbox;
+        self
+    }
+
     pub fn build(self, window_builder: WindowBuilder) -> anyhow::Result<SimpleMainLoop> {
         SimpleMainLoop::build(self, window_builder)
     }
@@ -166,6 +360,22 @@ pub struct SimpleMainLoop {
     render_backend: RenderBackend,
     rg_renderer: kajiya::rg::renderer::Renderer,
     render_extent: [u32; 2],
+    dynamic_resolution: Option<DynamicResolution>,
+    temporal_upsampling: f32,
+
+    // Ratio of the temporal upscale resolution to the physical window size,
+    // maintained when the window is resized.
+    resolution_per_window_pixel: [f32; 2],
+
+    rescale_history_on_resize: bool,
+    low_latency: bool,
+    letterbox: Option<Letterbox>,
+
+    // Collected by `pump_events` for the next frame
+    events: Vec<WindowEvent<'static>>,
+
+    last_error_text: Option<String>,
+    input_to_gpu_latency: Option<std::time::Duration>,
 }
 
 impl SimpleMainLoop {
@@ -210,10 +420,26 @@ impl SimpleMainLoop {
         // Physical window extent in pixels
         let swapchain_extent = [window.inner_size().width, window.inner_size().height];
 
+        let resolution_per_window_pixel = [
+            builder.resolution[0] as f32 / swapchain_extent[0] as f32,
+            builder.resolution[1] as f32 / swapchain_extent[1] as f32,
+        ];
+
+        // When letterboxed, only the image within the bars is rendered.
+        let temporal_upscale_extent = if builder.letterbox.is_some() {
+            let viewport = Viewport::new(swapchain_extent, builder.letterbox.as_ref());
+            [
+                ((viewport.extent[0] as f32 * resolution_per_window_pixel[0]) as u32).max(1),
+                ((viewport.extent[1] as f32 * resolution_per_window_pixel[1]) as u32).max(1),
+            ]
+        } else {
+            builder.resolution
+        };
+
         // Find the internal rendering resolution
         let render_extent = [
-            (builder.resolution[0] as f32 / builder.temporal_upsampling) as u32,
-            (builder.resolution[1] as f32 / builder.temporal_upsampling) as u32,
+            (temporal_upscale_extent[0] as f32 / builder.temporal_upsampling) as u32,
+            (temporal_upscale_extent[1] as f32 / builder.temporal_upsampling) as u32,
         ];
 
         log::info!(
@@ -222,8 +448,6 @@ impl SimpleMainLoop {
             render_extent[1]
         );
 
-        let temporal_upscale_extent = builder.resolution;
-
         if builder.temporal_upsampling != 1.0 {
             log::info!(
                 "Temporal upscaling extent: {}x{}",
@@ -236,21 +460,33 @@ impl SimpleMainLoop {
             &window,
             RenderBackendConfig {
                 swapchain_extent,
-                vsync: builder.vsync,
-                graphics_debugging: builder.graphics_debugging,
+                present_mode: builder.present_mode,
+                validation: builder.validation.clone(),
+                frames_in_flight: builder.frames_in_flight,
+                hdr_output: builder.hdr_output,
+                descriptor_pools: builder.descriptor_pools.clone(),
             },
         )?;
 
+        render_backend.device.set_leak_check(builder.leak_check);
+
+        let color_space = render_backend.swapchain.color_space();
+        log::info!("Swapchain color space: {:?}", color_space);
+
         let lazy_cache = LazyCache::create();
-        let world_renderer = WorldRenderer::new(
+        let mut world_renderer = WorldRenderer::new(
             render_extent,
             temporal_upscale_extent,
-            &render_backend,
+            &render_backend.device,
             &lazy_cache,
         )?;
         let ui_renderer = UiRenderer::default();
 
-        let rg_renderer = kajiya::rg::renderer::Renderer::new(&render_backend)?;
+        if color_space.is_hdr() {
+            world_renderer.display_max_brightness = builder.hdr_peak_nits / HDR_SDR_WHITE_NITS;
+        }
+
+        let rg_renderer = kajiya::rg::renderer::Renderer::new(&render_backend.device)?;
 
         #[cfg(feature = "dear-imgui")]
         let mut imgui = imgui::Context::create();
@@ -262,6 +498,9 @@ impl SimpleMainLoop {
         #[cfg(feature = "dear-imgui")]
         imgui_backend.create_graphics_resources(swapchain_extent);
 
+        #[cfg(feature = "egui")]
+        let egui_backend = EguiBackend::new(rg_renderer.device().clone(), &window);
+
         #[cfg(feature = "puffin-server")]
         let puffin_server = {
             let server_addr = format!("0.0.0.0:{}", puffin_http::DEFAULT_PORT);
@@ -276,6 +515,8 @@ impl SimpleMainLoop {
             imgui_backend,
             #[cfg(feature = "dear-imgui")]
             imgui,
+            #[cfg(feature = "egui")]
+            egui_backend,
             #[cfg(feature = "puffin-server")]
             _puffin_server: puffin_server,
         };
@@ -289,6 +530,15 @@ impl SimpleMainLoop {
             render_backend,
             rg_renderer,
             render_extent,
+            dynamic_resolution: builder.dynamic_resolution.map(DynamicResolution::new),
+            temporal_upsampling: builder.temporal_upsampling,
+            resolution_per_window_pixel,
+            rescale_history_on_resize: builder.rescale_history_on_resize,
+            low_latency: builder.low_latency,
+            letterbox: builder.letterbox,
+            events: Vec::new(),
+            last_error_text: None,
+            input_to_gpu_latency: None,
         })
     }
 
@@ -296,26 +546,50 @@ impl SimpleMainLoop {
         self.window.inner_size().width as f32 / self.window.inner_size().height as f32
     }
 
-    pub fn run<'a, FrameFn>(self, mut frame_fn: FrameFn) -> anyhow::Result<()>
+    /// Renders one frame through `camera_matrices`, independently of how input arrives; for apps
+    /// driving the camera from a gamepad, the network, or a scripted timeline. Window events
+    /// are pumped to keep the window responsive, but are otherwise ignored.
+    ///
+    /// Returns `Ok(false)` once the window has been closed. `run` is the equivalent driven by
+    /// winit input.
+    pub fn render(
+        &mut self,
+        camera_matrices: CameraMatrices,
+        sun_direction: Vec3,
+        time: FrameTime,
+    ) -> anyhow::Result<bool> {
+        let gpu_frame_start_ns = puffin::now_ns();
+        puffin::profile_scope!("render");
+        puffin::GlobalProfiler::lock().new_frame();
+
+        let frame_start = std::time::Instant::now();
+
+        if !self.pump_events() {
+            return Ok(false);
+        }
+        self.events.clear();
+
+        if !self.update_window_extent()? {
+            return Ok(true);
+        }
+
+        let frame_desc = WorldFrameDesc {
+            camera_matrices,
+            render_extent: self.frame_render_extent(),
+            sun_direction,
+            time,
+        };
+
+        self.draw_frame(&frame_desc, gpu_frame_start_ns, frame_start)?;
+
+        Ok(true)
+    }
+
+    pub fn run<'a, FrameFn>(mut self, mut frame_fn: FrameFn) -> anyhow::Result<()>
     where
         FrameFn: (FnMut(FrameContext) -> WorldFrameDesc) + 'a,
     {
-        #[allow(unused_variables, unused_mut)]
-        let SimpleMainLoop {
-            window,
-            mut world_renderer,
-            mut ui_renderer,
-            mut optional,
-            mut event_loop,
-            mut render_backend,
-            mut rg_renderer,
-            render_extent,
-        } = self;
-
-        let mut events = Vec::new();
-
         let mut last_frame_instant = std::time::Instant::now();
-        let mut last_error_text = None;
 
         // Delta times are filtered over _this many_ frames.
         const DT_FILTER_WIDTH: usize = 10;
@@ -327,48 +601,29 @@ impl SimpleMainLoop {
         // and pipelines are be compiled, so it will most likely have a spike.
         let mut fake_dt_countdown: i32 = 1;
 
-        let mut running = true;
-        while running {
+        let mut requested_present_mode = None;
+
+        // Advanced by `dt_filtered`; the first frame is at index and time 0.
+        let mut time: Option<FrameTime> = None;
+
+        loop {
             let gpu_frame_start_ns = puffin::now_ns();
             puffin::profile_scope!("main loop");
             puffin::GlobalProfiler::lock().new_frame();
 
-            event_loop.run_return(|event, _, control_flow| {
-                puffin::profile_scope!("event handler");
-
-                let _ = &render_backend;
-                #[cfg(feature = "dear-imgui")]
-                optional
-                    .imgui_backend
-                    .handle_event(&window, &mut optional.imgui, &event);
+            let queued_frames = self.render_backend.device.queued_frame_count();
+            let frame_start = std::time::Instant::now();
 
-                #[cfg(feature = "dear-imgui")]
-                let ui_wants_mouse = optional.imgui.io().want_capture_mouse;
-
-                #[cfg(not(feature = "dear-imgui"))]
-                let ui_wants_mouse = false;
-
-                *control_flow = ControlFlow::Poll;
-
-                match event {
-                    Event::WindowEvent { event, .. } => match event {
-                        WindowEvent::CloseRequested => {
-                            *control_flow = ControlFlow::Exit;
-                            running = false;
-                        }
-                        WindowEvent::CursorMoved { .. } | WindowEvent::MouseInput { .. }
-                            if ui_wants_mouse => {}
-                        _ => events.extend(event.to_static()),
-                    },
-                    Event::MainEventsCleared => {
-                        *control_flow = ControlFlow::Exit;
-                    }
-                    _ => (),
-                }
-            });
+            if !self.pump_events() {
+                break;
+            }
 
             puffin::profile_scope!("MainEventsCleared");
 
+            if !self.update_window_extent()? {
+                continue;
+            }
+
             // Filter the frame time before passing it to the application and renderer.
             // Fluctuations in frame rendering times cause stutter in animations,
             // and time-dependent effects (such as motion blur).
@@ -400,87 +655,357 @@ impl SimpleMainLoop {
                 }
             };
 
+            let frame_time = match time {
+                Some(time) => time.next(dt_filtered),
+                None => FrameTime {
+                    dt: dt_filtered,
+                    ..Default::default()
+                },
+            };
+            time = Some(frame_time);
+
+            let frame_render_extent = self.frame_render_extent();
+
             let frame_desc = frame_fn(FrameContext {
                 dt_filtered,
-                render_extent,
-                events: &events,
-                world_renderer: &mut world_renderer,
+                time: frame_time,
+                render_extent: frame_render_extent,
+                swapchain_extent: self.render_backend.swapchain.extent(),
+                viewport: self.viewport(),
+                events: &self.events,
+                world_renderer: &mut self.world_renderer,
 
                 #[cfg(feature = "dear-imgui")]
                 imgui: Some(ImguiContext {
-                    imgui: &mut optional.imgui,
-                    imgui_backend: &mut optional.imgui_backend,
-                    ui_renderer: &mut ui_renderer,
+                    imgui: &mut self.optional.imgui,
+                    imgui_backend: &mut self.optional.imgui_backend,
+                    ui_renderer: &mut self.ui_renderer,
                     dt_filtered,
-                    window: &window,
+                    window: &self.window,
+                }),
+
+                #[cfg(feature = "egui")]
+                egui: Some(EguiContext {
+                    egui_backend: &mut self.optional.egui_backend,
+                    window: &self.window,
                 }),
+
+                present_mode: self.render_backend.swapchain.present_mode(),
+                requested_present_mode: &mut requested_present_mode,
+                dynamic_resolution: self.dynamic_resolution.as_mut(),
+                queued_frames,
+                input_to_gpu_latency: self.input_to_gpu_latency,
             });
 
-            events.clear();
-
-            // Physical window extent in pixels
-            let swapchain_extent = [window.inner_size().width, window.inner_size().height];
-
-            let prepared_frame = {
-                puffin::profile_scope!("prepare_frame");
-                rg_renderer.prepare_frame(|rg| {
-                    rg.debug_hook = world_renderer.rg_debug_hook.take();
-                    let main_img = world_renderer.prepare_render_graph(rg, &frame_desc);
-                    let ui_img = ui_renderer.prepare_render_graph(rg);
-
-                    let mut swap_chain = rg.get_swap_chain();
-                    rg::SimpleRenderPass::new_compute(
-                        rg.add_pass("final blit"),
-                        "/shaders/final_blit.hlsl",
-                    )
-                    .read(&main_img)
-                    .read(&ui_img)
-                    .write(&mut swap_chain)
-                    .constants((
-                        main_img.desc().extent_inv_extent_2d(),
-                        [
-                            swapchain_extent[0] as f32,
-                            swapchain_extent[1] as f32,
-                            1.0 / swapchain_extent[0] as f32,
-                            1.0 / swapchain_extent[1] as f32,
-                        ],
-                    ))
-                    .dispatch([swapchain_extent[0], swapchain_extent[1], 1]);
-                })
+            self.events.clear();
+
+            self.draw_frame(&frame_desc, gpu_frame_start_ns, frame_start)?;
+
+            if let Some(present_mode) = requested_present_mode.take() {
+                self.render_backend.set_present_mode(present_mode)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Collects window events into `events`, and forwards them to the UI. Returns false once
+    // the window has been closed.
+    fn pump_events(&mut self) -> bool {
+        let Self {
+            event_loop,
+            optional,
+            events,
+            window,
+            ..
+        } = self;
+
+        let mut running = true;
+
+        event_loop.run_return(|event, _, control_flow| {
+            puffin::profile_scope!("event handler");
+
+            let _ = &window;
+            #[cfg(feature = "dear-imgui")]
+            optional
+                .imgui_backend
+                .handle_event(window, &mut optional.imgui, &event);
+
+            #[cfg(feature = "dear-imgui")]
+            let ui_wants_mouse = optional.imgui.io().want_capture_mouse;
+
+            #[cfg(not(feature = "dear-imgui"))]
+            let ui_wants_mouse = false;
+
+            // Events consumed by egui, such as typing into its text fields, aren't passed on.
+            #[cfg(feature = "egui")]
+            let egui_consumed = match &event {
+                Event::WindowEvent { event, .. } => optional.egui_backend.handle_event(event),
+                _ => false,
             };
 
-            match prepared_frame {
-                Ok(()) => {
-                    puffin::profile_scope!("draw_frame");
-                    rg_renderer.draw_frame(
-                        |dynamic_constants| {
-                            world_renderer.prepare_frame_constants(
-                                dynamic_constants,
-                                &frame_desc,
-                                dt_filtered,
-                            )
-                        },
-                        &mut render_backend.swapchain,
-                    );
-                    world_renderer.retire_frame();
-                    last_error_text = None;
-                }
-                Err(e) => {
-                    let error_text = Some(format!("{:?}", e));
-                    if error_text != last_error_text {
-                        println!("{}", error_text.as_ref().unwrap());
-                        last_error_text = error_text;
+            #[cfg(feature = "egui")]
+            let ui_wants_mouse = ui_wants_mouse || optional.egui_backend.wants_pointer_input();
+
+            #[cfg(not(feature = "egui"))]
+            let egui_consumed = false;
+
+            *control_flow = ControlFlow::Poll;
+
+            match event {
+                Event::WindowEvent { event, .. } => match event {
+                    WindowEvent::CloseRequested => {
+                        *control_flow = ControlFlow::Exit;
+                        running = false;
                     }
+                    WindowEvent::CursorMoved { .. } | WindowEvent::MouseInput { .. }
+                        if ui_wants_mouse => {}
+                    _ if egui_consumed => {}
+                    _ => events.extend(event.to_static()),
+                },
+                Event::MainEventsCleared => {
+                    *control_flow = ControlFlow::Exit;
                 }
+                _ => (),
             }
+        });
+
+        running
+    }
+
+    // Follows the window size with the swapchain and rendering resolution. Returns false
+    // if there's nothing to render to, such as while minimized.
+    fn update_window_extent(&mut self) -> anyhow::Result<bool> {
+        // Physical window extent in pixels
+        let window_extent = [
+            self.window.inner_size().width,
+            self.window.inner_size().height,
+        ];
 
-            report_gpu_stats_to_puffin(&gpu_profiler::get_stats(), gpu_frame_start_ns);
+        // Nothing to render to while minimized.
+        if window_extent.contains(&0) {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            return Ok(false);
+        }
+
+        if window_extent != self.render_backend.swapchain.extent() {
+            self.render_backend.resize_swapchain(window_extent)?;
+
+            #[cfg(feature = "dear-imgui")]
+            {
+                self.optional.imgui_backend.destroy_graphics_resources();
+                self.optional
+                    .imgui_backend
+                    .create_graphics_resources(self.render_backend.swapchain.extent());
+            }
+
+            // Keep the rendering resolution proportional to the window size. Temporal resources
+            // whose descs change as a result get re-created (or rescaled) by the render graph.
+            let viewport_extent = self.viewport().extent;
+            let temporal_upscale_extent = [
+                ((viewport_extent[0] as f32 * self.resolution_per_window_pixel[0]) as u32).max(1),
+                ((viewport_extent[1] as f32 * self.resolution_per_window_pixel[1]) as u32).max(1),
+            ];
+            self.render_extent = [
+                ((temporal_upscale_extent[0] as f32 / self.temporal_upsampling) as u32).max(1),
+                ((temporal_upscale_extent[1] as f32 / self.temporal_upsampling) as u32).max(1),
+            ];
+            self.world_renderer
+                .set_temporal_upscale_extent(temporal_upscale_extent);
+
+            log::info!(
+                "Resized; internal rendering extent: {}x{}",
+                self.render_extent[0],
+                self.render_extent[1]
+            );
+        }
+
+        Ok(true)
+    }
+
+    /// Where in the window the world renderer's output is shown; see `SimpleMainLoopBuilder::letterbox`.
+    pub fn viewport(&self) -> Viewport {
+        Viewport::new(
+            self.render_backend.swapchain.extent(),
+            self.letterbox.as_ref(),
+        )
+    }
+
+    // The rendering resolution for this frame, after dynamic resolution
+    fn frame_render_extent(&mut self) -> [u32; 2] {
+        if let Some(dynamic_resolution) = &mut self.dynamic_resolution {
+            let gpu_frame_ms: f64 = gpu_profiler::get_stats()
+                .get_ordered()
+                .iter()
+                .map(|(_, ms)| *ms)
+                .sum();
+            dynamic_resolution.update(gpu_frame_ms as f32);
+            dynamic_resolution.scale_extent(self.render_extent)
+        } else {
+            self.render_extent
+        }
+    }
+
+    // Renders the world and the UI, and presents. `frame_start` is when input for the frame was read.
+    fn draw_frame(
+        &mut self,
+        frame_desc: &WorldFrameDesc,
+        gpu_frame_start_ns: puffin::NanoSecond,
+        frame_start: std::time::Instant,
+    ) -> anyhow::Result<()> {
+        let Self {
+            world_renderer,
+            ui_renderer,
+            optional,
+            render_backend,
+            rg_renderer,
+            rescale_history_on_resize,
+            letterbox,
+            last_error_text,
+            ..
+        } = self;
+
+        #[cfg(feature = "egui")]
+        optional.egui_backend.update_textures(world_renderer);
+
+        let swapchain_extent = render_backend.swapchain.extent();
+        let swapchain_color_space = render_backend.swapchain.color_space();
+        let viewport = Viewport::new(swapchain_extent, letterbox.as_ref());
+        let bar_color = letterbox.map_or([0.0; 3], |letterbox| letterbox.bar_color);
+
+        let prepared_frame = {
+            puffin::profile_scope!("prepare_frame");
+            rg_renderer.prepare_frame(|rg| {
+                rg.debug_hook = world_renderer.rg_debug_hook.take();
+                rg.rescale_temporal_history = *rescale_history_on_resize;
+                let main_img = world_renderer.prepare_render_graph(rg, frame_desc);
+                let ui_img = ui_renderer.prepare_render_graph(rg);
+
+                #[cfg(feature = "egui")]
+                let ui_img = optional.egui_backend.render(
+                    rg,
+                    world_renderer.bindless_descriptor_set(),
+                    ui_img,
+                    swapchain_extent,
+                );
+
+                let mut swap_chain = rg.get_swap_chain();
+                final_blit(
+                    rg,
+                    world_renderer,
+                    &main_img,
+                    &ui_img,
+                    &mut swap_chain,
+                    FinalBlitOutput {
+                        extent: swapchain_extent,
+                        color_space: swapchain_color_space,
+                        viewport,
+                        bar_color,
+                    },
+                    frame_desc.time.frame_index,
+                );
+            })
+        };
+
+        match prepared_frame {
+            Ok(()) => {
+                puffin::profile_scope!("draw_frame");
+                rg_renderer.draw_frame(
+                    |dynamic_constants| {
+                        world_renderer.prepare_frame_constants(dynamic_constants, frame_desc)
+                    },
+                    &mut render_backend.swapchain,
+                )?;
+                world_renderer.retire_frame();
+                *last_error_text = None;
+            }
+            Err(e) => {
+                let error_text = Some(if matches!(e, KajiyaError::ShaderCompile { .. }) {
+                    shader_compiler::colorize_diagnostic(&e.to_string())
+                } else {
+                    format!("{:?}", anyhow::Error::new(e))
+                });
+                if error_text != *last_error_text {
+                    println!("{}", error_text.as_ref().unwrap());
+                    *last_error_text = error_text;
+                }
+            }
+        }
+
+        report_gpu_stats_to_puffin(&gpu_profiler::get_stats(), gpu_frame_start_ns);
+
+        // Wait right away, so that the next frame reads its input once the GPU is free.
+        if self.low_latency {
+            self.render_backend.device.wait_for_last_frame()?;
+            self.input_to_gpu_latency = Some(frame_start.elapsed());
         }
 
         Ok(())
     }
 }
 
+// Where, and how `final_blit` writes the frame
+struct FinalBlitOutput {
+    extent: [u32; 2],
+    color_space: SwapchainColorSpace,
+    viewport: Viewport,
+    bar_color: [f32; 3],
+}
+
+/// Composites `ui_img` over `main_img` into `output`, encoded for its color space, and with
+/// the `FilmGrain` of `world_renderer` added before quantization.
+fn final_blit(
+    rg: &mut rg::RenderGraph,
+    world_renderer: &WorldRenderer,
+    main_img: &rg::Handle<Image>,
+    ui_img: &rg::Handle<Image>,
+    output: &mut rg::Handle<Image>,
+    output_desc: FinalBlitOutput,
+    frame_index: u32,
+) {
+    let FinalBlitOutput {
+        extent,
+        color_space,
+        viewport,
+        bar_color,
+    } = output_desc;
+
+    // In steps of the swapchain's encoding between black and white
+    let noise_amplitude = match color_space {
+        SwapchainColorSpace::Sdr => world_renderer.film_grain.noise_amplitude(255.0),
+        SwapchainColorSpace::Hdr10 => world_renderer.film_grain.noise_amplitude(1023.0),
+        // Floating-point; doesn't band
+        SwapchainColorSpace::ScRgb => 0.0,
+    };
+
+    rg::SimpleRenderPass::new_compute(rg.add_pass("final blit"), "/shaders/final_blit.hlsl")
+        .read(main_img)
+        .read(ui_img)
+        .write(output)
+        .bindless(world_renderer.bindless_descriptor_set())
+        .constants(FinalBlitConstants {
+            main_tex_size: main_img.desc().extent_inv_extent_2d(),
+            output_tex_size: [
+                extent[0] as f32,
+                extent[1] as f32,
+                1.0 / extent[0] as f32,
+                1.0 / extent[1] as f32,
+            ],
+            output_encoding: color_space as u32,
+            sdr_white_nits: HDR_SDR_WHITE_NITS,
+            noise_frame: world_renderer.film_grain.noise_frame(frame_index),
+            noise_amplitude,
+            main_rect: [
+                viewport.offset[0] as f32,
+                viewport.offset[1] as f32,
+                viewport.extent[0] as f32,
+                viewport.extent[1] as f32,
+            ],
+            bar_color: [bar_color[0], bar_color[1], bar_color[2], 0.0],
+        })
+        .dispatch([extent[0], extent[1], 1]);
+}
+
 fn report_gpu_stats_to_puffin(
     gpu_stats: &gpu_profiler::GpuProfilerStats,
     gpu_frame_start_ns: puffin::NanoSecond,
@@ -513,3 +1038,165 @@ fn report_gpu_stats_to_puffin(
         .as_stream_into_ref(),
     );
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kajiya::{
+        backend::{ash::vk, file::set_standard_vfs_mount_points, vk_sync::AccessType},
+        camera::{CameraLens, LookThroughCamera},
+        math::Quat,
+        renderers::post::FilmGrain,
+    };
+    use rg::{readback::AsyncReadback, renderer::Renderer};
+    use std::sync::Arc;
+
+    const EXTENT: [u32; 2] = [256, 64];
+
+    // sRGB-encoded value of the gradient at column `x`, spanning a few 8-bit steps
+    fn gradient_at(x: u32) -> f32 {
+        0.3 + 0.02 * (x as f32 + 0.5) / EXTENT[0] as f32
+    }
+
+    // Blits a horizontal grey gradient to SDR, and returns the red channel of the output.
+    fn blit_gradient(film_grain: FilmGrain) -> Vec<u8> {
+        set_standard_vfs_mount_points(concat!(env!("CARGO_MANIFEST_DIR"), "/../../.."));
+
+        let backend = HeadlessRenderBackend::new(Default::default()).unwrap();
+        let device = backend.device.clone();
+        let mut renderer = Renderer::new(&device).unwrap();
+        let mut readback = AsyncReadback::default();
+
+        // For the blue noise
+        let mut world_renderer =
+            WorldRenderer::new(EXTENT, EXTENT, &device, &LazyCache::create()).unwrap();
+        world_renderer.film_grain = film_grain;
+
+        let linear: Vec<u8> = (0..EXTENT[1])
+            .flat_map(|_| 0..EXTENT[0])
+            .flat_map(|x| {
+                let encoded = gradient_at(x);
+                let linear = ((encoded + 0.055) / 1.055).powf(2.4);
+                [linear, linear, linear, 1.0]
+            })
+            .flat_map(f32::to_le_bytes)
+            .collect();
+        let main_img = Arc::new(
+            device
+                .create_image(
+                    ImageDesc::new_2d(vk::Format::R32G32B32A32_SFLOAT, EXTENT)
+                        .usage(vk::ImageUsageFlags::SAMPLED),
+                    "final blit test gradient",
+                    vec![ImageSubResourceData {
+                        data: &linear,
+                        row_pitch: EXTENT[0] as usize * 16,
+                        slice_pitch: 0,
+                    }],
+                )
+                .unwrap(),
+        );
+
+        // The world is rendered like in the main loop, which sets up the bindless textures,
+        // but its output is replaced with the gradient.
+        let frame_desc = WorldFrameDesc {
+            camera_matrices: (Vec3::ZERO, Quat::IDENTITY).through(&CameraLens::default()),
+            render_extent: EXTENT,
+            sun_direction: Vec3::Y,
+            time: FrameTime::fixed_step(0, 1.0 / 60.0),
+        };
+
+        readback.begin_frame();
+        let mut token = None;
+        renderer
+            .prepare_frame(|rg| {
+                world_renderer.prepare_render_graph(rg, &frame_desc);
+
+                let main_img = rg.import(
+                    main_img.clone(),
+                    AccessType::AnyShaderReadSampledImageOrUniformTexelBuffer,
+                );
+                let mut ui_img = rg.create(ImageDesc::new_2d(vk::Format::R8G8B8A8_UNORM, EXTENT));
+                rg::imageops::clear_color(rg, &mut ui_img, [0.0; 4]);
+
+                let mut output = rg.create(ImageDesc::new_2d(vk::Format::R8G8B8A8_UNORM, EXTENT));
+                final_blit(
+                    rg,
+                    &world_renderer,
+                    &main_img,
+                    &ui_img,
+                    &mut output,
+                    FinalBlitOutput {
+                        extent: EXTENT,
+                        color_space: SwapchainColorSpace::Sdr,
+                        viewport: Viewport::new(EXTENT, None),
+                        bar_color: [0.0; 3],
+                    },
+                    0,
+                );
+
+                token = Some(readback.copy_image(rg, &output, 4));
+            })
+            .unwrap();
+        renderer
+            .draw_frame_headless(|dynamic_constants| {
+                world_renderer.prepare_frame_constants(dynamic_constants, &frame_desc)
+            })
+            .unwrap();
+        readback.retire_frame(device.frame_counter());
+        world_renderer.retire_frame();
+
+        let bytes = readback.wait(&device, token.unwrap()).unwrap();
+        bytes.chunks_exact(4).map(|rgba| rgba[0]).collect()
+    }
+
+    // Largest difference between the mean output of 16 columns and the gradient, in 8-bit steps
+    fn max_block_error(output: &[u8]) -> f32 {
+        (0..EXTENT[0] / 16)
+            .map(|block| {
+                let columns = block * 16..(block + 1) * 16;
+                let expected = columns.clone().map(gradient_at).sum::<f32>() / 16.0 * 255.0;
+                let sum: f32 = (0..EXTENT[1])
+                    .flat_map(|y| columns.clone().map(move |x| (x, y)))
+                    .map(|(x, y)| output[(y * EXTENT[0] + x) as usize] as f32)
+                    .sum();
+                (sum / (16 * EXTENT[1]) as f32 - expected).abs()
+            })
+            .fold(0.0, f32::max)
+    }
+
+    fn distinct_values_in_column(output: &[u8], x: u32) -> usize {
+        let mut values: Vec<u8> = (0..EXTENT[1])
+            .map(|y| output[(y * EXTENT[0] + x) as usize])
+            .collect();
+        values.sort_unstable();
+        values.dedup();
+        values.len()
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn dither_breaks_up_banding_in_gradients() {
+        let banded = blit_gradient(FilmGrain::default());
+        let dithered = blit_gradient(FilmGrain {
+            dither: true,
+            ..Default::default()
+        });
+
+        // Without dither, every column quantizes to one value, and the bands are off
+        // from the gradient by up to half a step.
+        assert!((0..EXTENT[0]).all(|x| distinct_values_in_column(&banded, x) == 1));
+        assert!(
+            max_block_error(&banded) > 0.25,
+            "{}",
+            max_block_error(&banded)
+        );
+
+        // With it, pixels are spread over neighboring values, averaging out to the gradient.
+        assert!((0..EXTENT[0]).all(|x| distinct_values_in_column(&dithered, x) >= 2));
+        assert!(
+            max_block_error(&dithered) < 0.15,
+            "{}",
+            max_block_error(&dithered)
+        );
+    }
+}
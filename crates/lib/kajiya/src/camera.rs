@@ -1,5 +1,5 @@
 use crate::math::*;
-use rust_shaders_shared::camera::CameraMatrices;
+pub use rust_shaders_shared::camera::CameraMatrices;
 
 pub trait IntoCameraBodyMatrices {
     fn into_camera_body_matrices(self) -> CameraBodyMatrices;
@@ -37,9 +37,18 @@ where
     }
 }
 
+/// Perspective projection with reverse Z: depth is 1.0 at the near plane, and falls to 0.0
+/// at the far plane, which is infinitely far away by default. Depth targets are cleared to 0.0
+/// and tested with `GREATER_OR_EQUAL`; shaders linearize with `depth_to_view_z`, and treat 0.0
+/// as the background.
 #[derive(Clone, Copy)]
 pub struct CameraLens {
     pub near_plane_distance: f32,
+
+    /// `None` for an infinite far plane, which never clips distant geometry. With a finite one,
+    /// anything further away is clipped, and the sky and fog show through in its place.
+    pub far_plane_distance: Option<f32>,
+
     pub aspect_ratio: f32,
     pub vertical_fov: f32,
 }
@@ -48,6 +57,7 @@ impl Default for CameraLens {
     fn default() -> Self {
         Self {
             near_plane_distance: 0.01, // 1mm
+            far_plane_distance: None,
             aspect_ratio: 1.0,
             vertical_fov: 52.0,
         }
@@ -92,6 +102,12 @@ impl CameraLens {
         let h = (0.5 * fov).cos() / (0.5 * fov).sin();
         let w = h / self.aspect_ratio;
 
+        // Depth is `z_bias / distance - z_scale`, going from 1.0 at `znear` to 0.0 at the far plane.
+        let (z_scale, z_bias) = match self.far_plane_distance {
+            Some(zfar) => (znear / (zfar - znear), znear * zfar / (zfar - znear)),
+            None => (0.0, znear),
+        };
+
         /*let mut m = Mat4::ZERO;
         m.m11 = w;
         m.m22 = h;
@@ -101,8 +117,8 @@ impl CameraLens {
         let view_to_clip = Mat4::from_cols(
             Vec4::new(w, 0.0, 0.0, 0.0),
             Vec4::new(0.0, h, 0.0, 0.0),
-            Vec4::new(0.0, 0.0, 0.0, -1.0),
-            Vec4::new(0.0, 0.0, znear, 0.0),
+            Vec4::new(0.0, 0.0, z_scale, -1.0),
+            Vec4::new(0.0, 0.0, z_bias, 0.0),
         );
 
         /*let mut m = Mat4::ZERO;
@@ -114,8 +130,8 @@ impl CameraLens {
         let clip_to_view = Mat4::from_cols(
             Vec4::new(1.0 / w, 0.0, 0.0, 0.0),
             Vec4::new(0.0, 1.0 / h, 0.0, 0.0),
-            Vec4::new(0.0, 0.0, 0.0, 1.0 / znear),
-            Vec4::new(0.0, 0.0, -1.0, 0.0),
+            Vec4::new(0.0, 0.0, 0.0, 1.0 / z_bias),
+            Vec4::new(0.0, 0.0, -1.0, z_scale / z_bias),
         );
 
         CameraLensMatrices {
@@ -124,3 +140,68 @@ impl CameraLens {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clip_depth(view_to_clip: Mat4, distance: f32) -> f32 {
+        let clip = view_to_clip * Vec4::new(0.0, 0.0, -distance, 1.0);
+        clip.z / clip.w
+    }
+
+    #[test]
+    fn reverse_z_separates_distant_surfaces() {
+        let lens = CameraLens::default();
+        let view_to_clip = lens.calc_matrices().view_to_clip;
+
+        assert!((clip_depth(view_to_clip, lens.near_plane_distance) - 1.0).abs() < 1e-6);
+        assert!(clip_depth(view_to_clip, 1e30) > 0.0);
+
+        // Two surfaces 1m apart, 10km away
+        let (near, far) = (10_000.0, 10_001.0);
+        assert!(clip_depth(view_to_clip, near) > clip_depth(view_to_clip, far));
+
+        // A conventional projection with a 20km far plane can't tell them apart,
+        // as floats are densest near 0.0, where it maps the near plane.
+        let forward_depth = |distance: f32| {
+            let (n, f) = (lens.near_plane_distance, 20_000.0f32);
+            f / (f - n) - f * n / ((f - n) * distance)
+        };
+        assert_eq!(forward_depth(near), forward_depth(far));
+    }
+
+    #[test]
+    fn far_plane_clips_only_when_finite() {
+        let infinite = CameraLens::default().calc_matrices();
+        let finite = CameraLens {
+            far_plane_distance: Some(1000.0),
+            ..Default::default()
+        }
+        .calc_matrices();
+
+        for matrices in [&infinite, &finite] {
+            assert!(
+                (matrices.view_to_clip * matrices.clip_to_view).abs_diff_eq(Mat4::IDENTITY, 1e-4)
+            );
+
+            assert!((clip_depth(matrices.view_to_clip, 0.01) - 1.0).abs() < 1e-6);
+            for distance in [0.5, 10.0, 999.0] {
+                let depth = clip_depth(matrices.view_to_clip, distance);
+                let view_z = matrices
+                    .clip_to_view
+                    .project_point3(Vec3::new(0.0, 0.0, depth))
+                    .z;
+                assert!((view_z + distance).abs() < distance * 1e-4, "{}", view_z);
+            }
+        }
+
+        // Outside of the 0..=1 depth range gets clipped.
+        assert!(clip_depth(infinite.view_to_clip, 1e6) > 0.0);
+        assert!(clip_depth(finite.view_to_clip, 1000.0).abs() < 1e-6);
+        assert!(clip_depth(finite.view_to_clip, 1e6) < 0.0);
+
+        // Closer is still greater.
+        assert!(clip_depth(finite.view_to_clip, 500.0) > clip_depth(finite.view_to_clip, 501.0));
+    }
+}
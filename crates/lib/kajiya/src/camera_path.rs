@@ -0,0 +1,297 @@
+// Scripted camera motion: keyframes of where the camera is, what it looks at, and its field
+// of view, interpolated smoothly over time.
+//
+// Between keyframes, each of those follows a cubic Hermite spline whose tangents are
+// Catmull-Rom's, scaled for the time between keyframes, so that the speed stays continuous
+// even where keyframes are unevenly spaced. Sample with `FrameTime::time_seconds` for motion
+// which doesn't depend on the frame rate.
+
+use crate::{
+    camera::{CameraLens, CameraMatrices, LookThroughCamera},
+    math::*,
+};
+
+/// Remapping of the progress through a segment of a `CameraPath`. Anything but `Linear` slows
+/// the camera down at the ends it applies to, so the speed is no longer continuous there.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Easing {
+    Linear,
+    In,
+    Out,
+    InOut,
+}
+
+impl Easing {
+    pub fn apply(self, t: f32) -> f32 {
+        match self {
+            Easing::Linear => t,
+            Easing::In => t * t,
+            Easing::Out => t * (2.0 - t),
+            Easing::InOut => t * t * (3.0 - 2.0 * t),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct CameraKeyframe {
+    /// In seconds
+    pub time: f32,
+    pub position: Vec3,
+    pub target: Vec3,
+
+    /// In degrees, like `CameraLens::vertical_fov`
+    pub vertical_fov: f32,
+
+    /// Of the segment from this keyframe to the next one.
+    pub easing: Easing,
+}
+
+impl CameraKeyframe {
+    pub fn new(time: f32, position: Vec3, target: Vec3) -> Self {
+        Self {
+            time,
+            position,
+            target,
+            vertical_fov: CameraLens::default().vertical_fov,
+            easing: Easing::Linear,
+        }
+    }
+
+    pub fn vertical_fov(mut self, vertical_fov: f32) -> Self {
+        self.vertical_fov = vertical_fov;
+        self
+    }
+
+    pub fn easing(mut self, easing: Easing) -> Self {
+        self.easing = easing;
+        self
+    }
+}
+
+/// The state of a `CameraPath` at some time.
+#[derive(Clone, Copy, Debug)]
+pub struct CameraPathSample {
+    pub position: Vec3,
+    pub target: Vec3,
+    pub vertical_fov: f32,
+}
+
+impl CameraPathSample {
+    /// Looking from `position` at `target`, with no roll.
+    pub fn rotation(&self) -> Quat {
+        look_rotation(self.target - self.position)
+    }
+
+    /// Through `lens`, with its field of view replaced by the sampled one.
+    pub fn camera_matrices(&self, lens: &CameraLens) -> CameraMatrices {
+        let lens = CameraLens {
+            vertical_fov: self.vertical_fov,
+            ..*lens
+        };
+        (self.position, self.rotation()).through(&lens)
+    }
+}
+
+/// The rotation of a camera looking along `forward`, with no roll. Cameras look down -Z.
+pub fn look_rotation(forward: Vec3) -> Quat {
+    let forward = forward.normalize();
+    let yaw = (-forward.x).atan2(-forward.z);
+    let pitch = forward.y.clamp(-1.0, 1.0).asin();
+
+    Quat::from_rotation_y(yaw) * Quat::from_rotation_x(pitch)
+}
+
+pub struct CameraPath {
+    keyframes: Vec<CameraKeyframe>,
+
+    // If looping, the time after which the path starts over
+    loop_period: Option<f32>,
+}
+
+impl CameraPath {
+    /// The keyframes are sorted by time. Before the first one, and after the last one,
+    /// the camera stays at them. Panics if `keyframes` is empty.
+    pub fn new(mut keyframes: Vec<CameraKeyframe>) -> Self {
+        assert!(!keyframes.is_empty(), "a camera path needs keyframes");
+        keyframes.sort_by(|a, b| {
+            a.time
+                .partial_cmp(&b.time)
+                .expect("keyframe times can't be NaN")
+        });
+
+        Self {
+            keyframes,
+            loop_period: None,
+        }
+    }
+
+    /// Makes the path repeat every `period` seconds, going from the last keyframe back to the first
+    /// one, which it reaches `period` seconds after passing it. `period` must exceed the time from
+    /// the first keyframe to the last one.
+    pub fn looping(mut self, period: f32) -> Self {
+        assert!(period > self.keyframes.last().unwrap().time - self.keyframes[0].time);
+        self.loop_period = Some(period);
+        self
+    }
+
+    /// The time from the first keyframe to the last one, or the loop period.
+    pub fn duration(&self) -> f32 {
+        self.loop_period
+            .unwrap_or_else(|| self.keyframes.last().unwrap().time - self.keyframes[0].time)
+    }
+
+    pub fn keyframes(&self) -> &[CameraKeyframe] {
+        &self.keyframes
+    }
+
+    // The keyframe at `idx`, which wraps around if looping, with its time shifted by whole periods,
+    // and is clamped to the ends otherwise.
+    fn key(&self, idx: isize) -> (f32, &CameraKeyframe) {
+        let count = self.keyframes.len() as isize;
+
+        if let Some(period) = self.loop_period {
+            let key = &self.keyframes[idx.rem_euclid(count) as usize];
+            (key.time + idx.div_euclid(count) as f32 * period, key)
+        } else {
+            let key = &self.keyframes[idx.clamp(0, count - 1) as usize];
+            (key.time, key)
+        }
+    }
+
+    pub fn sample(&self, time: f32) -> CameraPathSample {
+        let first_time = self.keyframes[0].time;
+        let time = match self.loop_period {
+            Some(period) => first_time + (time - first_time).rem_euclid(period),
+            None => time.clamp(first_time, self.keyframes.last().unwrap().time),
+        };
+
+        // The last segment, which only exists when looping, goes back to the first keyframe.
+        let segment = self
+            .keyframes
+            .iter()
+            .rposition(|key| key.time <= time)
+            .unwrap_or(0) as isize;
+
+        let (t0, key0) = self.key(segment);
+        let (t1, key1) = self.key(segment + 1);
+        if t1 <= t0 {
+            return CameraPathSample {
+                position: key0.position,
+                target: key0.target,
+                vertical_fov: key0.vertical_fov,
+            };
+        }
+
+        let (t_prev, key_prev) = self.key(segment - 1);
+        let (t_next, key_next) = self.key(segment + 2);
+
+        let dt = t1 - t0;
+        let u = key0.easing.apply(((time - t0) / dt).clamp(0.0, 1.0));
+
+        // Tangents in units per second, from the neighboring keyframes; one-sided at the ends.
+        let tangent = |before: (f32, Vec3), after: (f32, Vec3)| {
+            if after.0 > before.0 {
+                (after.1 - before.1) / (after.0 - before.0)
+            } else {
+                Vec3::ZERO
+            }
+        };
+
+        let interpolate = |get: &dyn Fn(&CameraKeyframe) -> Vec3| {
+            let m0 = tangent((t_prev, get(key_prev)), (t1, get(key1)));
+            let m1 = tangent((t0, get(key0)), (t_next, get(key_next)));
+            hermite(get(key0), m0 * dt, get(key1), m1 * dt, u)
+        };
+
+        CameraPathSample {
+            position: interpolate(&|key| key.position),
+            target: interpolate(&|key| key.target),
+            vertical_fov: interpolate(&|key| Vec3::splat(key.vertical_fov)).x,
+        }
+    }
+
+    /// The camera at `time`, in seconds, through `lens` with the path's field of view.
+    pub fn camera_matrices(&self, time: f32, lens: &CameraLens) -> CameraMatrices {
+        self.sample(time).camera_matrices(lens)
+    }
+}
+
+fn hermite(p0: Vec3, m0: Vec3, p1: Vec3, m1: Vec3, t: f32) -> Vec3 {
+    let t2 = t * t;
+    let t3 = t2 * t;
+
+    p0 * (2.0 * t3 - 3.0 * t2 + 1.0)
+        + m0 * (t3 - 2.0 * t2 + t)
+        + p1 * (-2.0 * t3 + 3.0 * t2)
+        + m1 * (t3 - t2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path() -> CameraPath {
+        CameraPath::new(vec![
+            CameraKeyframe::new(0.0, Vec3::new(2.5, 1.0, 0.0), Vec3::ZERO),
+            // Out of order, and unevenly spaced
+            CameraKeyframe::new(3.0, Vec3::new(-2.5, 1.2, 0.0), Vec3::Y).vertical_fov(30.0),
+            CameraKeyframe::new(1.0, Vec3::new(0.0, 0.6, 2.5), Vec3::ZERO),
+            CameraKeyframe::new(4.0, Vec3::new(0.0, 1.8, -2.5), Vec3::ZERO).easing(Easing::InOut),
+        ])
+    }
+
+    fn velocity(path: &CameraPath, time: f32, dt: f32) -> Vec3 {
+        (path.sample(time + dt).position - path.sample(time).position) / dt
+    }
+
+    #[test]
+    fn path_is_continuous_at_keyframes() {
+        let eps = 1e-3;
+
+        for path in [path(), path().looping(6.0)] {
+            for key in path.keyframes() {
+                let at = path.sample(key.time);
+                assert!((at.position - key.position).length() < 1e-5);
+                assert!((at.target - key.target).length() < 1e-5);
+                assert!((at.vertical_fov - key.vertical_fov).abs() < 1e-4);
+
+                let before = path.sample(key.time - eps);
+                let after = path.sample(key.time + eps);
+                assert!((before.position - after.position).length() < 0.02);
+                assert!((before.vertical_fov - after.vertical_fov).abs() < 0.2);
+            }
+
+            // The speed is continuous across the inner keyframes, despite the uneven spacing.
+            for time in [1.0, 3.0] {
+                let incoming = velocity(&path, time - eps, eps);
+                let outgoing = velocity(&path, time, eps);
+                assert!(
+                    (incoming - outgoing).length() < 0.05 * incoming.length().max(1.0),
+                    "{} {:?} {:?}",
+                    time,
+                    incoming,
+                    outgoing
+                );
+            }
+        }
+
+        // Clamped at the ends, unless looping
+        let path = path();
+        assert!((path.sample(-1.0).position - Vec3::new(2.5, 1.0, 0.0)).length() < 1e-5);
+        assert!((path.sample(10.0).position - Vec3::new(0.0, 1.8, -2.5)).length() < 1e-5);
+        assert_eq!(path.duration(), 4.0);
+
+        // The last segment eases into the first keyframe, which looping returns to after six seconds
+        let looped = path.looping(6.0);
+        assert!(velocity(&looped, 6.0 - eps, eps).length() < 0.05);
+        assert!(
+            (looped.sample(6.5).position - looped.sample(0.5).position).length() < 1e-5,
+            "not periodic"
+        );
+
+        // Looks at the target
+        let sample = looped.sample(2.2);
+        let forward = sample.rotation() * -Vec3::Z;
+        assert!(forward.dot((sample.target - sample.position).normalize()) > 0.9999);
+    }
+}
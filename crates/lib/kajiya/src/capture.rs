@@ -0,0 +1,239 @@
+// Captures of rendered frames, as numbered image files or video, for turntables and demos.
+//
+// Frames come from `WorldRenderer::request_final_image_readback`, which are display-referred
+// and linear; PNGs and video get the sRGB encoding otherwise applied by the final blit,
+// while EXRs keep them linear.
+
+use std::{
+    io::Write as _,
+    path::PathBuf,
+    process::{Child, Command, Stdio},
+};
+
+use anyhow::Context as _;
+
+use crate::renderers::readback::ReadbackImage;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ImageSequenceFormat {
+    Png,
+    Exr,
+}
+
+impl ImageSequenceFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ImageSequenceFormat::Png => "png",
+            ImageSequenceFormat::Exr => "exr",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum CaptureOutput {
+    /// One file per frame in `directory`, numbered from zero, e.g. `0042.png`.
+    ImageSequence {
+        directory: PathBuf,
+        format: ImageSequenceFormat,
+    },
+
+    /// A video at `path`, encoded by piping raw frames to `ffmpeg`, which must be on the `PATH`.
+    /// The container follows the extension of `path`.
+    Ffmpeg { path: PathBuf, fps: f32 },
+}
+
+impl CaptureOutput {
+    /// An image sequence if `path` has no extension, or one of `png` and `exr`, in which case
+    /// it's the directory with the frames; a video otherwise.
+    pub fn from_path(path: impl Into<PathBuf>, fps: f32) -> Self {
+        let path = path.into();
+        let format = match path.extension().and_then(|ext| ext.to_str()) {
+            None => Some(ImageSequenceFormat::Png),
+            Some("png") => Some(ImageSequenceFormat::Png),
+            Some("exr") => Some(ImageSequenceFormat::Exr),
+            Some(_) => None,
+        };
+
+        match format {
+            Some(format) => CaptureOutput::ImageSequence {
+                directory: path.with_extension(""),
+                format,
+            },
+            None => CaptureOutput::Ffmpeg { path, fps },
+        }
+    }
+}
+
+/// Writes frames to a `CaptureOutput`, in the order they're given.
+pub struct FrameWriter {
+    output: CaptureOutput,
+    ffmpeg: Option<(Child, [u32; 2])>,
+    frame_count: usize,
+}
+
+impl FrameWriter {
+    pub fn new(output: CaptureOutput) -> anyhow::Result<Self> {
+        if let CaptureOutput::ImageSequence { directory, .. } = &output {
+            std::fs::create_dir_all(directory)
+                .with_context(|| format!("Can't create {:?}", directory))?;
+        }
+
+        Ok(Self {
+            output,
+            ffmpeg: None,
+            frame_count: 0,
+        })
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.frame_count
+    }
+
+    pub fn write_frame(&mut self, frame: &ReadbackImage) -> anyhow::Result<()> {
+        match &self.output {
+            CaptureOutput::ImageSequence { directory, format } => {
+                let path =
+                    directory.join(format!("{:04}.{}", self.frame_count, format.extension()));
+
+                match format {
+                    ImageSequenceFormat::Png => {
+                        image::RgbImage::from_raw(
+                            frame.extent[0],
+                            frame.extent[1],
+                            encode_srgb8(frame)?,
+                        )
+                        .unwrap()
+                        .save(&path)
+                        .with_context(|| format!("Can't write {:?}", path))?;
+                    }
+                    ImageSequenceFormat::Exr => frame.write_exr(&path)?,
+                }
+            }
+            CaptureOutput::Ffmpeg { path, fps } => {
+                if self.ffmpeg.is_none() {
+                    self.ffmpeg = Some((spawn_ffmpeg(path, *fps, frame.extent)?, frame.extent));
+                }
+
+                let (ffmpeg, extent) = self.ffmpeg.as_mut().unwrap();
+                anyhow::ensure!(
+                    *extent == frame.extent,
+                    "Frames of a video must be of the same size"
+                );
+
+                ffmpeg
+                    .stdin
+                    .as_mut()
+                    .unwrap()
+                    .write_all(&encode_srgb8(frame)?)
+                    .context("ffmpeg stopped accepting frames")?;
+            }
+        }
+
+        self.frame_count += 1;
+        Ok(())
+    }
+
+    /// Waits for the video to be encoded. Dropping the writer instead leaves `ffmpeg` running
+    /// to finish in the background.
+    pub fn finish(mut self) -> anyhow::Result<()> {
+        if let Some((mut ffmpeg, _)) = self.ffmpeg.take() {
+            // Closing the pipe ends the input
+            drop(ffmpeg.stdin.take());
+
+            let status = ffmpeg.wait()?;
+            anyhow::ensure!(status.success(), "ffmpeg failed: {}", status);
+        }
+
+        Ok(())
+    }
+}
+
+fn spawn_ffmpeg(path: &std::path::Path, fps: f32, extent: [u32; 2]) -> anyhow::Result<Child> {
+    Command::new("ffmpeg")
+        .args(["-y", "-loglevel", "error"])
+        .args(["-f", "rawvideo", "-pixel_format", "rgb24"])
+        .args(["-video_size", &format!("{}x{}", extent[0], extent[1])])
+        .args(["-framerate", &fps.to_string()])
+        .args(["-i", "-"])
+        // Widely playable; yuv420p needs even dimensions.
+        .args(["-c:v", "libx264", "-pix_fmt", "yuv420p"])
+        .args(["-vf", "pad=ceil(iw/2)*2:ceil(ih/2)*2"])
+        .arg(path)
+        .stdin(Stdio::piped())
+        .spawn()
+        .context("Can't run ffmpeg; is it installed?")
+}
+
+/// The texels of `frame` as tightly packed 8-bit sRGB, clamped to the displayable range.
+pub fn encode_srgb8(frame: &ReadbackImage) -> anyhow::Result<Vec<u8>> {
+    let texels = frame
+        .to_rgba_f32()
+        .with_context(|| format!("Can't decode images of format {:?}", frame.format))?;
+
+    Ok(texels
+        .iter()
+        .flat_map(|texel| [texel[0], texel[1], texel[2]])
+        .map(|c| (linear_to_srgb(c.clamp(0.0, 1.0)) * 255.0 + 0.5) as u8)
+        .collect())
+}
+
+// The sRGB OETF, from linear to encoded values. `sRGB_EOTF` in `inc/color/srgb.hlsl` computes
+// the same, despite its name; the names of the two functions there are swapped.
+fn linear_to_srgb(c: f32) -> f32 {
+    if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kajiya_backend::ash::vk;
+
+    #[test]
+    fn png_sequence_is_numbered_and_srgb_encoded() {
+        let directory = std::env::temp_dir().join(format!("kajiya-capture-{}", std::process::id()));
+        let output = CaptureOutput::from_path(&directory, 30.0);
+        assert!(matches!(
+            &output,
+            CaptureOutput::ImageSequence {
+                format: ImageSequenceFormat::Png,
+                ..
+            }
+        ));
+        assert!(matches!(
+            CaptureOutput::from_path("orbit.mp4", 30.0),
+            CaptureOutput::Ffmpeg { .. }
+        ));
+
+        // Black, grey, white, and out of range
+        let texels: [f32; 16] = [
+            0.0, 0.0, 0.0, 1.0, //
+            0.22, 0.22, 0.22, 1.0, //
+            1.0, 1.0, 1.0, 1.0, //
+            4.0, -1.0, 0.0, 1.0,
+        ];
+        let frame = ReadbackImage {
+            format: vk::Format::R32G32B32A32_SFLOAT,
+            extent: [2, 2],
+            bytes: texels.iter().flat_map(|t| t.to_le_bytes()).collect(),
+        };
+
+        let mut writer = FrameWriter::new(output).unwrap();
+        writer.write_frame(&frame).unwrap();
+        writer.write_frame(&frame).unwrap();
+        assert_eq!(writer.frame_count(), 2);
+        writer.finish().unwrap();
+
+        let png = image::open(directory.join("0001.png")).unwrap().to_rgb8();
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(png.dimensions(), (2, 2));
+        assert_eq!(png.get_pixel(0, 0).0, [0, 0, 0]);
+        assert_eq!(png.get_pixel(1, 0).0, [129, 129, 129]);
+        assert_eq!(png.get_pixel(0, 1).0, [255, 255, 255]);
+        assert_eq!(png.get_pixel(1, 1).0, [255, 0, 0]);
+    }
+}
@@ -5,7 +5,7 @@ use kajiya_asset::{
     image::LoadImage,
     mesh::{TexGamma, TexParams},
 };
-use kajiya_backend::vulkan::RenderBackend;
+use kajiya_backend::Device;
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
 use turbosloth::*;
@@ -14,10 +14,10 @@ impl WorldRenderer {
     pub fn new(
         render_extent: [u32; 2],
         temporal_upscale_extent: [u32; 2],
-        backend: &RenderBackend,
+        device: &Arc<Device>,
         lazy_cache: &Arc<LazyCache>,
     ) -> anyhow::Result<Self> {
-        let mut world_renderer = Self::new_empty(render_extent, temporal_upscale_extent, backend)?;
+        let mut world_renderer = Self::new_empty(render_extent, temporal_upscale_extent, device)?;
 
         // BINDLESS_LUT_BRDF_FG
         world_renderer.add_image_lut(crate::lut_renderers::BrdfFgLutComputer, 0);
@@ -32,7 +32,7 @@ impl WorldRenderer {
                         gamma: TexGamma::Linear,
                         use_mips: false,
                     },
-                    device: backend.device.clone(),
+                    device: device.clone(),
                 }
                 .into_lazy()
                 .eval(lazy_cache),
@@ -49,7 +49,7 @@ impl WorldRenderer {
         world_renderer.add_image_lut(crate::lut_renderers::BezoldBruckeLutComputer, 2);
 
         // Build an empty TLAS to create the resources. We'll update it at runtime.
-        if backend.device.ray_tracing_enabled() {
+        if device.ray_tracing_enabled() {
             world_renderer.build_ray_tracing_top_level_acceleration();
         }
 
@@ -9,4 +9,42 @@ pub struct WorldFrameDesc {
     pub render_extent: [u32; 2],
 
     pub sun_direction: Vec3,
+
+    pub time: FrameTime,
+}
+
+/// Time of a frame, supplied by the application rather than read from the clock.
+/// Everything time-dependent in the renderer (temporal jitter, exposure adaptation,
+/// particles, motion blur) derives from it, so that a sequence of frames rendered
+/// with the same times renders the same.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FrameTime {
+    /// Selects the temporal jitter and noise patterns.
+    pub frame_index: u32,
+
+    /// Since the start of the sequence; for animation.
+    pub time_seconds: f32,
+
+    /// Since the previous frame
+    pub dt: f32,
+}
+
+impl FrameTime {
+    /// Frame `frame_index` of a sequence rendered at a fixed time step; for captures.
+    pub fn fixed_step(frame_index: u32, dt: f32) -> Self {
+        Self {
+            frame_index,
+            time_seconds: frame_index as f32 * dt,
+            dt,
+        }
+    }
+
+    /// The frame following this one, `dt` seconds later.
+    pub fn next(self, dt: f32) -> Self {
+        Self {
+            frame_index: self.frame_index.wrapping_add(1),
+            time_seconds: self.time_seconds + dt,
+            dt,
+        }
+    }
 }
@@ -82,6 +82,8 @@ impl LazyWorker for UploadGpuImage {
             );
         }
 
-        Ok(self.device.create_image(desc, initial_data)?)
+        Ok(self
+            .device
+            .create_image(desc, "Uploaded texture", initial_data)?)
     }
 }
@@ -1,4 +1,6 @@
 pub mod camera;
+pub mod camera_path;
+pub mod capture;
 pub mod default_world_renderer;
 pub mod frame_desc;
 pub mod image_cache;
@@ -6,8 +8,12 @@ pub mod image_lut;
 pub mod logging;
 pub mod lut_renderers;
 pub mod math;
+pub mod mesh_lod;
 pub mod mmap;
+pub mod render_origin;
 pub mod renderers;
+pub mod scene_desc;
+pub mod scene_file;
 pub mod ui_renderer;
 pub mod world_render_passes;
 pub mod world_renderer;
@@ -15,6 +15,7 @@ impl ComputeImageLut for BrdfFgLutComputer {
             .create_image(
                 ImageDesc::new_2d(vk::Format::R16G16_SFLOAT, [64, 64])
                     .usage(ImageUsageFlags::STORAGE | ImageUsageFlags::SAMPLED),
+                "brdf_fg lut",
                 vec![],
             )
             .expect("image")
@@ -31,9 +32,15 @@ impl ComputeImageLut for BrdfFgLutComputer {
         let img_ref = pass.write(img, AccessType::ComputeShaderWrite);
 
         pass.render(move |api| {
-            let pipeline = api.bind_compute_pipeline(
-                pipeline.into_binding().descriptor_set(0, &[img_ref.bind()]),
-            );
+            let pipeline = match api
+                .bind_compute_pipeline(pipeline.into_binding().descriptor_set(0, &[img_ref.bind()]))
+            {
+                Ok(pipeline) => pipeline,
+                Err(err) => {
+                    log::error!("brdf_fg lut: {}", err);
+                    return;
+                }
+            };
 
             pipeline.dispatch(img_ref.desc().extent);
         });
@@ -46,6 +53,7 @@ impl ComputeImageLut for BezoldBruckeLutComputer {
             .create_image(
                 ImageDesc::new_2d(vk::Format::R16G16_SFLOAT, [64, 1])
                     .usage(ImageUsageFlags::STORAGE | ImageUsageFlags::SAMPLED),
+                "bezold_brucke lut",
                 vec![],
             )
             .expect("image")
@@ -62,11 +70,207 @@ impl ComputeImageLut for BezoldBruckeLutComputer {
         let img_ref = pass.write(img, AccessType::ComputeShaderWrite);
 
         pass.render(move |api| {
-            let pipeline = api.bind_compute_pipeline(
-                pipeline.into_binding().descriptor_set(0, &[img_ref.bind()]),
-            );
+            let pipeline = match api
+                .bind_compute_pipeline(pipeline.into_binding().descriptor_set(0, &[img_ref.bind()]))
+            {
+                Ok(pipeline) => pipeline,
+                Err(err) => {
+                    log::error!("bezold_brucke lut: {}", err);
+                    return;
+                }
+            };
 
             pipeline.dispatch(img_ref.desc().extent);
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        camera::{CameraLens, LookThroughCamera},
+        frame_desc::{FrameTime, WorldFrameDesc},
+        world_renderer::WorldRenderer,
+    };
+    use glam::{Quat, Vec3};
+    use kajiya_backend::{
+        ash::vk, file::set_standard_vfs_mount_points, vulkan::buffer::BufferDesc,
+        HeadlessRenderBackend,
+    };
+    use kajiya_rg::{readback::AsyncReadback, renderer::Renderer, SimpleRenderPass};
+    use turbosloth::LazyCache;
+
+    // Runs one of the BRDF test shaders in `tests/` on `thread_count` threads, and returns
+    // the `output_len` floats it writes. The world renderer computes the BRDF LUTs for it,
+    // and sets up the bindless descriptor set they're read through.
+    fn run_brdf_test_shader<T: bytemuck::Pod>(
+        shader: &str,
+        thread_count: u32,
+        output_len: usize,
+        push_constants: &T,
+    ) -> Vec<f32> {
+        set_standard_vfs_mount_points(concat!(env!("CARGO_MANIFEST_DIR"), "/../../.."));
+
+        let backend = HeadlessRenderBackend::new(Default::default()).unwrap();
+        let device = backend.device.clone();
+        let lazy_cache = LazyCache::create();
+        let mut world_renderer =
+            WorldRenderer::new([64, 64], [64, 64], &device, &lazy_cache).unwrap();
+        let mut renderer = Renderer::new(&device).unwrap();
+        let mut readback = AsyncReadback::default();
+
+        let frame_desc = WorldFrameDesc {
+            camera_matrices: (Vec3::ZERO, Quat::IDENTITY).through(&CameraLens::default()),
+            render_extent: [64, 64],
+            sun_direction: Vec3::Y,
+            time: FrameTime::fixed_step(0, 1.0 / 60.0),
+        };
+
+        readback.begin_frame();
+        let mut token = None;
+        renderer
+            .prepare_frame(|rg| {
+                world_renderer.prepare_render_graph(rg, &frame_desc);
+
+                let mut output = rg.create(BufferDesc::new_gpu_only(
+                    output_len * std::mem::size_of::<f32>(),
+                    vk::BufferUsageFlags::STORAGE_BUFFER,
+                ));
+                SimpleRenderPass::new_compute(rg.add_pass("brdf test"), shader)
+                    .write(&mut output)
+                    .bindless(world_renderer.bindless_descriptor_set())
+                    .push_constants(push_constants)
+                    .dispatch([thread_count, 1, 1]);
+
+                token = Some(readback.copy_buffer(rg, &output));
+            })
+            .unwrap();
+        renderer
+            .draw_frame_headless(|dynamic_constants| {
+                world_renderer.prepare_frame_constants(dynamic_constants, &frame_desc)
+            })
+            .unwrap();
+        world_renderer.retire_frame();
+        readback.retire_frame(device.frame_counter());
+
+        let bytes = readback.wait(&device, token.unwrap()).unwrap();
+        bytemuck::cast_slice::<u8, f32>(&bytes).to_vec()
+    }
+
+    // Runs `tests/white_furnace.hlsl` for a conductor with `f0`, against the `brdf_fg` LUT;
+    // returns its directional albedo without and with the multiple scattering compensation,
+    // at three roughness values, for three angles each.
+    fn white_furnace(f0: f32) -> Vec<[f32; 2]> {
+        run_brdf_test_shader("/shaders/tests/white_furnace.hlsl", 9, 18, &[f0])
+            .chunks_exact(2)
+            .map(|pair| [pair[0], pair[1]])
+            .collect()
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn rough_metal_passes_the_white_furnace() {
+        for (idx, &[single_scatter, compensated]) in white_furnace(1.0).iter().enumerate() {
+            assert!(
+                (compensated - 1.0).abs() < 0.02,
+                "case {}: {}",
+                idx,
+                compensated
+            );
+
+            // At roughness 1
+            if idx >= 6 {
+                assert!(single_scatter < 0.8, "case {}: {}", idx, single_scatter);
+            }
+        }
+
+        // A colored conductor gains energy too, but never more than it gets.
+        for (idx, &[single_scatter, compensated]) in white_furnace(0.54).iter().enumerate() {
+            assert!(compensated > single_scatter, "case {}", idx);
+            assert!(compensated < 1.0, "case {}: {}", idx, compensated);
+        }
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn anisotropic_highlights_stretch_along_the_tangent() {
+        // Half-widths of the isotropic highlight along X and Y, then the anisotropic one
+        let widths = run_brdf_test_shader(
+            "/shaders/tests/anisotropic_highlight.hlsl",
+            2,
+            4,
+            &[0.3f32, 0.8],
+        );
+        let (isotropic, anisotropic) = ([widths[0], widths[1]], [widths[2], widths[3]]);
+
+        assert!(
+            (isotropic[0] - isotropic[1]).abs() < 1e-3,
+            "{:?}",
+            isotropic
+        );
+
+        // Longer along the tangent, and shorter along the bitangent
+        assert!(
+            anisotropic[0] > isotropic[0] * 1.5,
+            "{:?} vs {:?}",
+            anisotropic,
+            isotropic
+        );
+        assert!(
+            anisotropic[1] < isotropic[1] / 1.5,
+            "{:?} vs {:?}",
+            anisotropic,
+            isotropic
+        );
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn thin_films_shift_hue_with_the_viewing_angle() {
+        // At cosines of 1.0, 0.8, 0.6, 0.4 and 0.2 with a 400nm film, then head-on without one
+        let fresnel: Vec<Vec3> = run_brdf_test_shader(
+            "/shaders/tests/thin_film_fresnel.hlsl",
+            6,
+            24,
+            &[1.3f32, 400.0],
+        )
+        .chunks_exact(4)
+        .map(|rgba| Vec3::new(rgba[0], rgba[1], rgba[2]))
+        .collect();
+        let (film, no_film) = (&fresnel[..5], fresnel[5]);
+
+        // Without a film, the base's grey reflectance
+        assert!(
+            (no_film - Vec3::splat(0.04)).abs().max_element() < 2e-3,
+            "{:?}",
+            no_film
+        );
+
+        // With one, colored, and the color changes with the angle.
+        let chromaticity = |rgb: Vec3| rgb / rgb.dot(Vec3::ONE);
+        assert!(
+            film[0].max_element() > film[0].min_element() * 1.5,
+            "{:?}",
+            film[0]
+        );
+        assert!(
+            (chromaticity(film[0]) - chromaticity(film[3]))
+                .abs()
+                .max_element()
+                > 0.1,
+            "{:?}",
+            film
+        );
+
+        let mut dominant_channels: Vec<usize> = film
+            .iter()
+            .map(|rgb| {
+                (0..3)
+                    .max_by(|&a, &b| rgb[a].partial_cmp(&rgb[b]).unwrap())
+                    .unwrap()
+            })
+            .collect();
+        dominant_channels.dedup();
+        assert!(dominant_channels.len() >= 2, "{:?}", film);
+    }
+}
@@ -0,0 +1,198 @@
+// Level of detail for mesh instances.
+//
+// A mesh can have coarser versions of itself, its LODs, set via `WorldRenderer::set_mesh_lods`.
+// Every frame, before the TLAS is rebuilt and meshes are rasterized, each instance draws the LOD
+// for how much of the screen its bounding sphere covers. Both the raster and ray traced views of
+// the instance change together, so the coarse LODs save BLAS traversal as much as raster work.
+//
+// Switching abruptly would pop. Instead, around each LOD's threshold, instances pick between it
+// and the finer one at random every frame, in proportion to how far into the transition they are.
+// Temporal anti-aliasing and denoisers blend the two, which fades one into the other.
+
+use glam::{Affine3A, Vec3};
+use rust_shaders_shared::camera::CameraMatrices;
+
+use crate::world_renderer::MeshHandle;
+
+/// A coarser version of a mesh, drawn in its place once instances get small on screen.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct MeshLod {
+    pub mesh: MeshHandle,
+
+    /// The LOD is used once the instance's bounding sphere covers less than this fraction
+    /// of the screen height.
+    pub screen_size: f32,
+}
+
+/// The fraction of the screen height covered by a sphere of `radius`, `distance` from the camera.
+pub fn projected_screen_size(camera: &CameraMatrices, radius: f32, distance: f32) -> f32 {
+    // Spheres around the camera cover it all.
+    if distance <= radius {
+        return f32::MAX;
+    }
+
+    radius * camera.view_to_clip.y_axis.y / distance
+}
+
+/// The screen size of the bounding sphere, of `mesh_radius` around the mesh origin,
+/// of an instance with `transform`.
+pub fn instance_screen_size(
+    camera: &CameraMatrices,
+    transform: &Affine3A,
+    mesh_radius: f32,
+) -> f32 {
+    let scale = Vec3::new(
+        transform.matrix3.x_axis.length(),
+        transform.matrix3.y_axis.length(),
+        transform.matrix3.z_axis.length(),
+    )
+    .max_element();
+    let distance = (Vec3::from(transform.translation) - camera.eye_position()).length();
+
+    projected_screen_size(camera, mesh_radius * scale, distance)
+}
+
+/// Picks a LOD from `lods`, ordered from the finest to the coarsest, for an instance covering
+/// `screen_size` of the screen height. `None` is the mesh itself.
+///
+/// Within `transition_width` (relative) of a threshold, the pick is random: `noise`, uniform in
+/// `[0, 1)`, is compared to how far the instance is into the transition.
+pub fn select_mesh_lod(
+    lods: &[MeshLod],
+    screen_size: f32,
+    transition_width: f32,
+    noise: f32,
+) -> Option<usize> {
+    let passed_thresholds = lods
+        .iter()
+        .take_while(|lod| {
+            let fade_start = lod.screen_size * (1.0 + transition_width);
+            let fade_end = lod.screen_size * (1.0 - transition_width);
+
+            if screen_size >= fade_start {
+                false
+            } else if screen_size <= fade_end {
+                true
+            } else {
+                noise < (fade_start - screen_size) / (fade_start - fade_end)
+            }
+        })
+        .count();
+
+    passed_thresholds.checked_sub(1)
+}
+
+/// Uniform noise in `[0, 1)`, for `select_mesh_lod`, which changes every frame.
+pub(crate) fn lod_selection_noise(instance: usize, frame_index: u32) -> f32 {
+    let hash = hash_u32(hash_u32(instance as u32) ^ frame_index);
+    (hash >> 8) as f32 / (1u32 << 24) as f32
+}
+
+fn hash_u32(mut x: u32) -> u32 {
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x7feb352d);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x846ca68b);
+    x ^= x >> 16;
+    x
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render_origin::{rebase_camera_matrices, rebase_transform, snap_render_origin};
+    use glam::{Mat4, Quat};
+
+    #[test]
+    fn distant_instances_select_coarser_lods() {
+        let view_to_clip = Mat4::perspective_infinite_reverse_rh(1.0, 16.0 / 9.0, 0.01);
+        let view_to_world = Mat4::from_rotation_translation(Quat::IDENTITY, Vec3::ZERO);
+        let camera = CameraMatrices {
+            view_to_clip,
+            clip_to_view: view_to_clip.inverse(),
+            world_to_view: view_to_world.inverse(),
+            view_to_world,
+        };
+
+        let lods = [
+            MeshLod {
+                mesh: MeshHandle(1),
+                screen_size: 0.25,
+            },
+            MeshLod {
+                mesh: MeshHandle(2),
+                screen_size: 0.05,
+            },
+        ];
+
+        let select = |camera: &CameraMatrices, transform: &Affine3A, noise: f32| {
+            let screen_size = instance_screen_size(camera, transform, 1.0);
+            select_mesh_lod(&lods, screen_size, 0.2, noise)
+        };
+
+        // A mesh with a bounding radius of 1, in front of the camera
+        let lod_at = |distance: f32, noise: f32| {
+            let transform = Affine3A::from_translation(Vec3::new(0.0, 0.0, -distance));
+            select(&camera, &transform, noise)
+        };
+
+        for noise in [0.0, 0.5, 0.999] {
+            assert_eq!(lod_at(2.0, noise), None);
+            assert_eq!(lod_at(20.0, noise), Some(0));
+            assert_eq!(lod_at(200.0, noise), Some(1));
+        }
+
+        // Right at a threshold, half of the picks go either way.
+        let threshold_distance = view_to_clip.y_axis.y / 0.25;
+        assert_eq!(lod_at(threshold_distance, 0.25), Some(0));
+        assert_eq!(lod_at(threshold_distance, 0.75), None);
+
+        // Scaling the instance up brings back detail.
+        let scaled = Affine3A::from_scale_rotation_translation(
+            Vec3::splat(10.0),
+            Quat::IDENTITY,
+            Vec3::new(0.0, 0.0, -20.0),
+        );
+        assert_eq!(select(&camera, &scaled, 0.5), None);
+
+        // Far from the world origin, instances are relative to the render origin, and so must
+        // be the camera; the LODs are then those of the same view near the origin.
+        let far_view_to_world = Mat4::from_rotation_translation(
+            Quat::IDENTITY,
+            Vec3::new(1.0e6, -2.0e5, 3.0e6 + 100.0),
+        );
+        let far_camera = CameraMatrices {
+            view_to_clip,
+            clip_to_view: view_to_clip.inverse(),
+            world_to_view: far_view_to_world.inverse(),
+            view_to_world: far_view_to_world,
+        };
+        let render_origin = snap_render_origin(far_camera.eye_position());
+        assert_ne!(render_origin, Vec3::ZERO);
+        let render_space_camera = rebase_camera_matrices(&far_camera, render_origin);
+
+        let render_space_instance_at = |distance: f32| {
+            let world = Affine3A::from_translation(
+                far_camera.eye_position() + Vec3::new(0.0, 0.0, -distance),
+            );
+            rebase_transform(world, render_origin)
+        };
+
+        for distance in [2.0, 20.0, 200.0] {
+            assert_eq!(
+                select(
+                    &render_space_camera,
+                    &render_space_instance_at(distance),
+                    0.5
+                ),
+                lod_at(distance, 0.5)
+            );
+        }
+
+        // The world space camera sees render space instances as far away as the origin is.
+        assert_eq!(
+            select(&far_camera, &render_space_instance_at(2.0), 0.5),
+            Some(1)
+        );
+    }
+}
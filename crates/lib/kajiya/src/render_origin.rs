@@ -0,0 +1,144 @@
+// Rendering relative to an origin near the camera, for precision far from the world origin.
+//
+// World positions around 1e6 units are only precise to about 0.06 units as `f32`. Geometry there
+// wobbles as the camera moves, and ray origins can't be moved off surfaces reliably, which shows up
+// as dropped shadow and GI rays. Instead, everything is translated by `-origin` before it reaches
+// the GPU: instance transforms (and so the TLAS), the camera, decals, debug draw, and particles.
+// Shaders then reconstruct positions relative to the origin, which are small, and precise.
+// The application keeps using world space throughout; see `WorldRenderer::rebase_origin`.
+//
+// The origin is snapped to a coarse grid, so it only moves once the camera travels far, and then
+// by a whole number of grid cells. It only moves horizontally: the atmosphere and height fog depend
+// on altitude, and worlds are rarely as tall as they are wide.
+
+use glam::{Affine3A, Mat4, Vec3, Vec3A, Vec4};
+use rust_shaders_shared::camera::CameraMatrices;
+
+/// The render origin moves in steps of this many world units.
+pub const RENDER_ORIGIN_GRID_SIZE: f32 = 1024.0;
+
+/// The render origin for a camera at `eye_position`: the nearest grid point, at zero height.
+pub fn snap_render_origin(eye_position: Vec3) -> Vec3 {
+    let snapped = (eye_position / RENDER_ORIGIN_GRID_SIZE).round() * RENDER_ORIGIN_GRID_SIZE;
+    Vec3::new(snapped.x, 0.0, snapped.z)
+}
+
+/// `transform` followed by a translation by `-origin`.
+pub fn rebase_transform(transform: Affine3A, origin: Vec3) -> Affine3A {
+    Affine3A {
+        translation: transform.translation - Vec3A::from(origin),
+        ..transform
+    }
+}
+
+/// The camera of `camera`, viewing the world translated by `-origin`.
+pub fn rebase_camera_matrices(camera: &CameraMatrices, origin: Vec3) -> CameraMatrices {
+    // The eye position is subtracted directly, rather than multiplying the matrices by
+    // a translation, which would cancel out large terms and lose the precision gained.
+    let mut view_to_world = camera.view_to_world;
+    view_to_world.w_axis -= origin.extend(0.0);
+
+    CameraMatrices {
+        view_to_clip: camera.view_to_clip,
+        clip_to_view: camera.clip_to_view,
+        world_to_view: rigid_inverse(&view_to_world),
+        view_to_world,
+    }
+}
+
+// The view transforms are rotations and translations.
+fn rigid_inverse(m: &Mat4) -> Mat4 {
+    let rotation = Mat4::from_cols(
+        m.x_axis.truncate().extend(0.0),
+        m.y_axis.truncate().extend(0.0),
+        m.z_axis.truncate().extend(0.0),
+        Vec4::W,
+    )
+    .transpose();
+
+    Mat4::from_cols(
+        rotation.x_axis,
+        rotation.y_axis,
+        rotation.z_axis,
+        (-rotation.transform_vector3(m.w_axis.truncate())).extend(1.0),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use glam::Quat;
+
+    // A camera at `eye_position`, looking down the -z axis, as `CameraMatrices` come from apps
+    fn camera_at(eye_position: Vec3) -> CameraMatrices {
+        let view_to_clip = Mat4::perspective_infinite_reverse_rh(1.0, 16.0 / 9.0, 0.01);
+        let view_to_world = Mat4::from_rotation_translation(Quat::IDENTITY, eye_position);
+
+        CameraMatrices {
+            view_to_clip,
+            clip_to_view: view_to_clip.inverse(),
+            world_to_view: view_to_world.inverse(),
+            view_to_world,
+        }
+    }
+
+    #[test]
+    fn geometry_far_from_the_world_origin_is_stable_once_rebased() {
+        let far = Vec3::new(1.0e6, 0.0, -1.0e6);
+        let origin = snap_render_origin(far + Vec3::new(0.5, 1.75, 5.0));
+        assert_eq!(origin, Vec3::new(1000448.0, 0.0, -1000448.0));
+
+        // The same small scene, near the origin and around 1e6 units away: a mesh instance,
+        // and a camera a few units from it, moving in steps of the precision of `f32` at 1e6.
+        // The positions are exact at 1e6 too, so the scene's view-space vertices are the same
+        // wherever it is. Without rebasing, the far ones snap to the precision of `f32` at 1e6.
+        let mesh_transform = Affine3A::from_scale_rotation_translation(
+            Vec3::splat(0.5),
+            Quat::from_rotation_y(0.7),
+            Vec3::new(0.25, 1.25, -2.0),
+        );
+        let vertices = [
+            Vec3::new(0.05, 0.0, 0.0),
+            Vec3::new(0.0, 0.05, 0.0),
+            Vec3::new(0.0, 0.0, 0.05),
+        ];
+
+        let mut rebased_error = 0.0f32;
+        let mut absolute_error = 0.0f32;
+
+        for step in 0..16 {
+            let eye_offset = Vec3::new(0.5 + step as f32 * 0.0625, 1.75, 5.0);
+            let near_camera = camera_at(eye_offset);
+
+            let far_transform = Affine3A {
+                translation: mesh_transform.translation + Vec3A::from(far),
+                ..mesh_transform
+            };
+            let far_camera = camera_at(far + eye_offset);
+            let rebased_camera = rebase_camera_matrices(&far_camera, origin);
+            let rebased_transform = rebase_transform(far_transform, origin);
+
+            for vertex in vertices {
+                let view_space = |camera: &CameraMatrices, transform: Affine3A| {
+                    (camera.world_to_view * transform.transform_point3(vertex).extend(1.0))
+                        .truncate()
+                };
+
+                let expected = view_space(&near_camera, mesh_transform);
+                rebased_error = rebased_error.max(
+                    (view_space(&rebased_camera, rebased_transform) - expected)
+                        .abs()
+                        .max_element(),
+                );
+                absolute_error = absolute_error.max(
+                    (view_space(&far_camera, far_transform) - expected)
+                        .abs()
+                        .max_element(),
+                );
+            }
+        }
+
+        assert!(rebased_error < 2e-4, "{}", rebased_error);
+        assert!(absolute_error > 5e-3, "{}", absolute_error);
+    }
+}
@@ -0,0 +1,179 @@
+// Analytic primitives: spheres, boxes, cylinders and planes, ray traced without meshes.
+//
+// Each shape has an intersection shader in `rt/analytic/`, which finds exact hits within the box
+// of a procedural BLAS (see `procedural.rs`), and all of them share a closest-hit shader writing
+// a plain material to the gbuffer payload. Each kind of shape registers one pair of hit groups;
+// the parameters and material of a primitive are the shader record data of a pair of hit
+// records of its own (see `ProceduralHitGroupRegistry::push_hit_records`), so adding primitives
+// doesn't change the ray tracing pipelines.
+
+use glam::{Vec2, Vec3};
+use kajiya_backend::vulkan::{ray_tracing::RayTracingAabb, shader::ShaderSource};
+use kajiya_rg::RayHitGroup;
+
+use super::procedural::ProceduralHitGroups;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AnalyticShape {
+    /// Centered at the origin
+    Sphere { radius: f32 },
+
+    /// Centered at the origin
+    Box { half_extents: Vec3 },
+
+    /// Along the Y axis and capped, centered at the origin
+    Cylinder { radius: f32, half_height: f32 },
+
+    /// A double-sided rectangle on the XZ plane, centered at the origin
+    Plane { half_extents: Vec2 },
+}
+
+impl AnalyticShape {
+    fn intersection_shader(&self) -> ShaderSource {
+        ShaderSource::hlsl(match self {
+            Self::Sphere { .. } => "/shaders/rt/analytic/sphere.rint.hlsl",
+            Self::Box { .. } => "/shaders/rt/analytic/box.rint.hlsl",
+            Self::Cylinder { .. } => "/shaders/rt/analytic/cylinder.rint.hlsl",
+            Self::Plane { .. } => "/shaders/rt/analytic/plane.rint.hlsl",
+        })
+    }
+
+    /// The hit groups of all shapes of this kind; parameters come from hit records.
+    pub fn hit_groups(&self) -> ProceduralHitGroups {
+        let intersection = self.intersection_shader();
+
+        ProceduralHitGroups::new(
+            RayHitGroup::procedural(intersection.clone()).closest_hit(ShaderSource::hlsl(
+                "/shaders/rt/analytic/analytic.rchit.hlsl",
+            )),
+            RayHitGroup::procedural(intersection),
+        )
+    }
+
+    // `params` of `AnalyticPrimitiveRecord`
+    fn params(&self) -> [f32; 4] {
+        match *self {
+            Self::Sphere { radius } => [radius, 0.0, 0.0, 0.0],
+            Self::Box { half_extents } => half_extents.extend(0.0).into(),
+            Self::Cylinder {
+                radius,
+                half_height,
+            } => [radius, half_height, 0.0, 0.0],
+            Self::Plane { half_extents } => [half_extents.x, half_extents.y, 0.0, 0.0],
+        }
+    }
+
+    /// The bounds of the shape, in the space of its instance
+    pub fn aabb(&self) -> RayTracingAabb {
+        let half_extents = match *self {
+            Self::Sphere { radius } => Vec3::splat(radius),
+            Self::Box { half_extents } => half_extents,
+            Self::Cylinder {
+                radius,
+                half_height,
+            } => Vec3::new(radius, half_height, radius),
+            // Boxes without volume are valid, but may be culled by some implementations.
+            Self::Plane { half_extents } => Vec3::new(
+                half_extents.x,
+                half_extents.max_element() * 1e-4,
+                half_extents.y,
+            ),
+        };
+
+        RayTracingAabb {
+            min: (-half_extents).into(),
+            max: half_extents.into(),
+        }
+    }
+}
+
+/// A shape with a plain material, for `WorldRenderer::add_analytic_primitive`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct AnalyticPrimitive {
+    pub shape: AnalyticShape,
+    pub albedo: Vec3,
+    pub roughness: f32,
+}
+
+impl AnalyticPrimitive {
+    pub fn new(shape: AnalyticShape) -> Self {
+        Self {
+            shape,
+            albedo: Vec3::splat(0.75),
+            roughness: 0.5,
+        }
+    }
+
+    pub fn albedo(mut self, albedo: Vec3) -> Self {
+        self.albedo = albedo;
+        self
+    }
+
+    pub fn roughness(mut self, roughness: f32) -> Self {
+        self.roughness = roughness;
+        self
+    }
+
+    pub fn hit_groups(&self) -> ProceduralHitGroups {
+        self.shape.hit_groups()
+    }
+
+    /// `AnalyticPrimitiveRecord` in `analytic.hlsl`, for the primitive's hit records
+    pub fn shader_record_data(&self) -> Vec<u8> {
+        let albedo_roughness: [f32; 4] = self.albedo.extend(self.roughness).into();
+
+        self.shape
+            .params()
+            .iter()
+            .chain(&albedo_roughness)
+            .flat_map(|v| v.to_le_bytes())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renderers::procedural::tests::trace_down_the_z_axis;
+    use glam::Affine3A;
+
+    #[test]
+    #[ignore = "needs a Vulkan device with ray tracing"]
+    fn analytic_spheres_have_outward_normals() {
+        const RADIUS: f32 = 1.5;
+        let center = Vec3::new(0.25, 0.0, -1.0);
+
+        let sphere = AnalyticPrimitive::new(AnalyticShape::Sphere { radius: RADIUS });
+        let hits = trace_down_the_z_axis(
+            sphere.hit_groups(),
+            Some(sphere.shader_record_data()),
+            sphere.shape.aabb(),
+            Affine3A::from_translation(center),
+        );
+
+        let mut hit_count = 0;
+        for hit in hits {
+            // Pixels near the silhouette could go either way.
+            let offset = hit.ray_origin.truncate() - center.truncate();
+            if (offset.length() - RADIUS).abs() < 0.05 {
+                continue;
+            }
+
+            if offset.length() < RADIUS {
+                let z = (RADIUS * RADIUS - offset.length_squared()).sqrt();
+                let expected_normal = offset.extend(z) / RADIUS;
+                assert!(
+                    (hit.normal - expected_normal).abs().max_element() < 1e-2,
+                    "{:?} should have a normal of {}",
+                    hit,
+                    expected_normal
+                );
+                hit_count += 1;
+            } else {
+                assert!(hit.t.is_none(), "{:?}", hit);
+            }
+        }
+
+        assert!(hit_count > 0);
+    }
+}
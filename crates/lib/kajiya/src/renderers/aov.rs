@@ -0,0 +1,351 @@
+// Arbitrary output variables: intermediate buffers of a frame, for compositing and debugging.
+//
+// AOVs are only written in frames which ask for them, via `WorldRenderer::aovs`
+// or `WorldRenderer::request_aov_readback`.
+
+use std::{collections::HashMap, task::Poll};
+
+use kajiya_backend::{ash::vk, vulkan::image::*, Device};
+use kajiya_rg::{self as rg, SimpleRenderPass};
+
+use super::{
+    readback::{ImageReadback, ReadbackImage, ReadbackToken},
+    GbufferDepth,
+};
+
+/// All AOVs are floating point, and none have a transfer function applied; the radiance ones are
+/// linear, scene-referred, and before exposure. Only `Albedo`, `Direct`, `Indirect`, `Reflections`,
+/// `RawGi`, and `Radiance` are colors; the others are data, which must not be color-managed. Where
+/// the depth is zero (the sky), everything but `Direct` and `Radiance` is zero, as is
+/// the Cryptomatte away from objects.
+///
+/// In reference mode, only `Radiance` and the ones which `is_from_gbuffer` are available;
+/// `Radiance` is only available there.
+///
+/// The discriminants must match the `AOV_` defines in `aov/gbuffer.hlsl`.
+#[derive(Clone, Copy, Hash, PartialEq, Eq, Debug)]
+pub enum AovKind {
+    /// Diffuse albedo, linear in `rgb`. Decoded from the gbuffer, which stores it square-root
+    /// encoded at 8 bits per channel.
+    Albedo = 0,
+
+    /// World-space shading normal in `xyz`, from -1 to 1.
+    Normal = 1,
+
+    /// Perceptual roughness in `r`, and metalness in `g`, from 0 to 1.
+    Roughness = 2,
+
+    /// Radiance from the sun, point lights and emissive surfaces, and of the sky where
+    /// it's visible, in `rgb`.
+    Direct = 3,
+
+    /// Diffuse radiance from GI, in `rgb`.
+    Indirect = 4,
+
+    /// Specular radiance from reflections, in `rgb`.
+    Reflections = 5,
+
+    /// GI irradiance before denoising, in `rgb`, at the half resolution which it's traced at.
+    /// Only available with ray-traced diffuse GI.
+    RawGi = 6,
+
+    /// View-space offset from each surface to where it was in the previous frame, in `xyz`,
+    /// in world units.
+    Velocity = 7,
+
+    /// Linear view-space depth in `r`, in world units.
+    Depth = 8,
+
+    /// The layers of the Cryptomatte, with the IDs and coverage of the objects at each pixel;
+    /// see `cryptomatte::CryptomatteRenderer`. IDs are hashes, which must stay bit-exact.
+    CryptoObject00 = 9,
+    CryptoObject01 = 10,
+    CryptoObject02 = 11,
+
+    /// The noisy radiance accumulated by the reference path tracer so far, in `rgb`,
+    /// and the number of samples per pixel in `a`.
+    Radiance = 12,
+}
+
+impl AovKind {
+    pub const ALL: [AovKind; 13] = [
+        AovKind::Albedo,
+        AovKind::Normal,
+        AovKind::Roughness,
+        AovKind::Direct,
+        AovKind::Indirect,
+        AovKind::Reflections,
+        AovKind::RawGi,
+        AovKind::Velocity,
+        AovKind::Depth,
+        AovKind::CryptoObject00,
+        AovKind::CryptoObject01,
+        AovKind::CryptoObject02,
+        AovKind::Radiance,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            AovKind::Albedo => "albedo",
+            AovKind::Normal => "normal",
+            AovKind::Roughness => "roughness",
+            AovKind::Direct => "direct",
+            AovKind::Indirect => "indirect",
+            AovKind::Reflections => "reflections",
+            AovKind::RawGi => "raw_gi",
+            AovKind::Velocity => "velocity",
+            AovKind::Depth => "depth",
+            AovKind::CryptoObject00 => "crypto_object00",
+            AovKind::CryptoObject01 => "crypto_object01",
+            AovKind::CryptoObject02 => "crypto_object02",
+            AovKind::Radiance => "radiance",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|kind| kind.name() == name)
+    }
+
+    /// Written by `light_gbuffer`, into `LightingAovs`.
+    pub fn is_lighting(self) -> bool {
+        matches!(
+            self,
+            AovKind::Direct | AovKind::Indirect | AovKind::Reflections
+        )
+    }
+
+    /// Decoded from the gbuffer by `decode_gbuffer_aov`.
+    pub fn is_from_gbuffer(self) -> bool {
+        matches!(
+            self,
+            AovKind::Albedo
+                | AovKind::Normal
+                | AovKind::Roughness
+                | AovKind::Velocity
+                | AovKind::Depth
+        )
+    }
+
+    /// Names of the channels in a multi-layer EXR, by the conventions of compositing tools,
+    /// for as many of the image's channels as are meaningful. Depth is the standard `Z`.
+    pub fn exr_channels(self) -> &'static [&'static str] {
+        match self {
+            AovKind::Albedo => &["albedo.R", "albedo.G", "albedo.B"],
+            AovKind::Normal => &["N.X", "N.Y", "N.Z"],
+            AovKind::Roughness => &["roughness.R", "metalness.R"],
+            AovKind::Direct => &["direct.R", "direct.G", "direct.B"],
+            AovKind::Indirect => &["indirect.R", "indirect.G", "indirect.B"],
+            AovKind::Reflections => &["reflections.R", "reflections.G", "reflections.B"],
+            AovKind::RawGi => &["raw_gi.R", "raw_gi.G", "raw_gi.B"],
+            AovKind::Velocity => &["velocity.X", "velocity.Y", "velocity.Z"],
+            AovKind::Depth => &["Z"],
+            AovKind::CryptoObject00 => &[
+                "CryptoObject00.R",
+                "CryptoObject00.G",
+                "CryptoObject00.B",
+                "CryptoObject00.A",
+            ],
+            AovKind::CryptoObject01 => &[
+                "CryptoObject01.R",
+                "CryptoObject01.G",
+                "CryptoObject01.B",
+                "CryptoObject01.A",
+            ],
+            AovKind::CryptoObject02 => &[
+                "CryptoObject02.R",
+                "CryptoObject02.G",
+                "CryptoObject02.B",
+                "CryptoObject02.A",
+            ],
+            AovKind::Radiance => &["radiance.R", "radiance.G", "radiance.B"],
+        }
+    }
+
+    /// The index of the Cryptomatte layer, written by `cryptomatte::CryptomatteRenderer`.
+    pub fn cryptomatte_layer(self) -> Option<usize> {
+        match self {
+            AovKind::CryptoObject00 => Some(0),
+            AovKind::CryptoObject01 => Some(1),
+            AovKind::CryptoObject02 => Some(2),
+            _ => None,
+        }
+    }
+}
+
+/// The parts of the lighting which `light_gbuffer` sums up.
+pub struct LightingAovs {
+    pub direct: rg::Handle<Image>,
+    pub indirect: rg::Handle<Image>,
+    pub reflections: rg::Handle<Image>,
+}
+
+impl LightingAovs {
+    pub fn new(rg: &mut rg::RenderGraph, extent: [u32; 2]) -> Self {
+        let desc = ImageDesc::new_2d(vk::Format::R16G16B16A16_SFLOAT, extent);
+        Self {
+            direct: rg.create(desc),
+            indirect: rg.create(desc),
+            reflections: rg.create(desc),
+        }
+    }
+
+    pub fn into_images(self) -> [(AovKind, rg::Handle<Image>); 3] {
+        [
+            (AovKind::Direct, self.direct),
+            (AovKind::Indirect, self.indirect),
+            (AovKind::Reflections, self.reflections),
+        ]
+    }
+}
+
+/// Writes one of the AOVs which `AovKind::is_from_gbuffer` into a new image.
+pub fn decode_gbuffer_aov(
+    rg: &mut rg::RenderGraph,
+    gbuffer_depth: &GbufferDepth,
+    velocity: &rg::Handle<Image>,
+    kind: AovKind,
+) -> rg::Handle<Image> {
+    assert!(kind.is_from_gbuffer());
+
+    let format = if kind == AovKind::Depth {
+        vk::Format::R32_SFLOAT
+    } else {
+        vk::Format::R16G16B16A16_SFLOAT
+    };
+    let mut output = rg.create(
+        gbuffer_depth
+            .gbuffer
+            .desc()
+            .usage(vk::ImageUsageFlags::empty())
+            .format(format),
+    );
+
+    SimpleRenderPass::new_compute(
+        rg.add_pass(&format!("aov {}", kind.name())),
+        "/shaders/aov/gbuffer.hlsl",
+    )
+    .read(&gbuffer_depth.gbuffer)
+    .read_aspect(&gbuffer_depth.depth, vk::ImageAspectFlags::DEPTH)
+    .read(velocity)
+    .write(&mut output)
+    .constants(kind as u32)
+    .dispatch(output.desc().extent);
+
+    output
+}
+
+#[derive(Clone, Copy, Hash, PartialEq, Eq, Debug)]
+pub struct AovReadbackToken(ReadbackToken);
+
+/// Readbacks of AOVs, and of the final image along with them. Each request reserves its
+/// readback up front, and is copied in the next frame which renders its AOV.
+#[derive(Default)]
+pub struct AovReadbacks {
+    queued: Vec<(ReadbackToken, AovKind)>,
+    queued_beauty: Vec<ReadbackToken>,
+    queued_hdr_beauty: Vec<ReadbackToken>,
+    readback: ImageReadback,
+}
+
+impl AovReadbacks {
+    pub fn request(&mut self, kind: AovKind) -> AovReadbackToken {
+        let token = self.readback.reserve();
+        self.queued.push((token, kind));
+        AovReadbackToken(token)
+    }
+
+    /// Reads back the final image of the next frame, as returned by `WorldRenderer::prepare_render_graph`.
+    pub fn request_beauty(&mut self) -> AovReadbackToken {
+        let token = self.readback.reserve();
+        self.queued_beauty.push(token);
+        AovReadbackToken(token)
+    }
+
+    /// Reads back the lit image of the next frame before exposure and tonemapping, in linear
+    /// scene-referred units like the radiance AOVs.
+    pub fn request_hdr_beauty(&mut self) -> AovReadbackToken {
+        let token = self.readback.reserve();
+        self.queued_hdr_beauty.push(token);
+        AovReadbackToken(token)
+    }
+
+    pub fn queued_kinds(&self) -> impl Iterator<Item = AovKind> + '_ {
+        self.queued.iter().map(|(_, kind)| *kind)
+    }
+
+    pub fn begin_frame(&mut self) {
+        self.readback.begin_frame();
+    }
+
+    /// Copies the queued AOVs to the host, from those in `aovs`.
+    pub fn copy_to_host(
+        &mut self,
+        rg: &mut rg::TemporalRenderGraph,
+        aovs: &HashMap<AovKind, rg::Handle<Image>>,
+    ) {
+        let mut queued = std::mem::take(&mut self.queued);
+        queued.retain(|(token, kind)| {
+            if let Some(image) = aovs.get(kind) {
+                self.readback.copy_reserved_to_host(*token, rg, image);
+                false
+            } else {
+                true
+            }
+        });
+        self.queued = queued;
+    }
+
+    pub fn copy_beauty_to_host(
+        &mut self,
+        rg: &mut rg::TemporalRenderGraph,
+        beauty: &rg::Handle<Image>,
+    ) {
+        for token in self.queued_beauty.drain(..) {
+            self.readback.copy_reserved_to_host(token, rg, beauty);
+        }
+    }
+
+    pub fn copy_hdr_beauty_to_host(
+        &mut self,
+        rg: &mut rg::TemporalRenderGraph,
+        hdr_beauty: &rg::Handle<Image>,
+    ) {
+        for token in self.queued_hdr_beauty.drain(..) {
+            self.readback.copy_reserved_to_host(token, rg, hdr_beauty);
+        }
+    }
+
+    /// Resolves the requests for AOVs which weren't rendered in the frame being recorded.
+    pub fn skip_remaining(&mut self) {
+        for (token, _) in self.queued.drain(..) {
+            self.readback.cancel(token);
+        }
+        for token in self
+            .queued_beauty
+            .drain(..)
+            .chain(self.queued_hdr_beauty.drain(..))
+        {
+            self.readback.cancel(token);
+        }
+    }
+
+    /// Drops a request whose result is no longer wanted, whether it's queued, in flight, or done.
+    pub fn discard(&mut self, token: AovReadbackToken) {
+        self.queued.retain(|(queued, _)| *queued != token.0);
+        self.queued_beauty.retain(|queued| *queued != token.0);
+        self.queued_hdr_beauty.retain(|queued| *queued != token.0);
+        self.readback.discard(token.0);
+    }
+
+    pub fn retire_frame(&mut self, frame_counter: u64) {
+        self.readback.retire_frame(frame_counter);
+    }
+
+    pub fn poll(
+        &mut self,
+        device: &Device,
+        token: AovReadbackToken,
+    ) -> Poll<Option<ReadbackImage>> {
+        self.readback.poll(device, token.0)
+    }
+}
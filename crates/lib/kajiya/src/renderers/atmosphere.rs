@@ -0,0 +1,424 @@
+// Physically based atmosphere, precomputed into small lookup tables.
+//
+// Follows "A Scalable and Production Ready Sky and Atmosphere Rendering Technique" (Hillaire 2020),
+// with the transmittance parameterization of "Precomputed Atmospheric Scattering" (Bruneton 2008).
+//
+// The transmittance and multiple scattering LUTs only depend on the medium, and are cached
+// until `SkyParams` change. The sky-view LUT and the aerial perspective volume depend on the sun
+// and the eye, but are tiny, and are rebuilt every frame.
+
+use glam::Vec3;
+use kajiya_backend::{ash::vk, vulkan::image::*};
+use kajiya_rg::{self as rg, GetOrCreateTemporal, SimpleRenderPass};
+
+use super::GbufferDepth;
+
+// Must match the `*_LUT_SIZE` defines in `atmosphere/common.hlsl`
+const TRANSMITTANCE_LUT_EXTENT: [u32; 2] = [256, 64];
+const MULTISCATTER_LUT_EXTENT: [u32; 2] = [32, 32];
+const SKY_VIEW_LUT_EXTENT: [u32; 2] = [192, 108];
+const AERIAL_PERSPECTIVE_EXTENT: [u32; 3] = [32, 32, 32];
+
+const SKY_CUBE_WIDTH: u32 = 32;
+
+// The ozone layer is a tent function of altitude, in kilometers.
+const OZONE_CENTER_HEIGHT: f32 = 25.0;
+const OZONE_HALF_WIDTH: f32 = 15.0;
+
+/// Describes the participating media of the atmosphere. Distances are in kilometers,
+/// and coefficients per kilometer. Defaults approximate the Earth.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SkyParams {
+    pub planet_radius: f32,
+
+    /// Thickness of the atmosphere above the ground.
+    pub atmosphere_height: f32,
+
+    /// Rayleigh scattering coefficient at ground level, per color channel.
+    pub rayleigh_scattering: Vec3,
+
+    /// Altitude over which the density of Rayleigh scatterers falls by a factor of `e`.
+    pub rayleigh_scale_height: f32,
+
+    /// Mie scattering coefficient at ground level.
+    pub mie_scattering: f32,
+
+    /// Mie absorption coefficient at ground level.
+    pub mie_absorption: f32,
+
+    /// Altitude over which the density of aerosols falls by a factor of `e`.
+    pub mie_scale_height: f32,
+
+    /// Anisotropy of the Cornette-Shanks Mie phase function, in (-1, 1).
+    pub mie_anisotropy: f32,
+
+    /// Ozone absorption coefficient at the peak of the ozone layer, per color channel.
+    pub ozone_absorption: Vec3,
+
+    /// Lambertian reflectance of the planet's surface, as seen from the sky.
+    pub ground_albedo: Vec3,
+}
+
+impl Default for SkyParams {
+    fn default() -> Self {
+        Self {
+            planet_radius: 6360.0,
+            atmosphere_height: 100.0,
+            rayleigh_scattering: Vec3::new(5.802e-3, 13.558e-3, 33.1e-3),
+            rayleigh_scale_height: 8.0,
+            mie_scattering: 3.996e-3,
+            mie_absorption: 0.444e-3,
+            mie_scale_height: 1.2,
+            mie_anisotropy: 0.8,
+            ozone_absorption: Vec3::new(0.650e-3, 1.881e-3, 0.085e-3),
+            ground_albedo: Vec3::splat(0.3),
+        }
+    }
+}
+
+impl SkyParams {
+    fn sanitized(&self) -> Self {
+        Self {
+            planet_radius: self.planet_radius.max(1.0),
+            atmosphere_height: self.atmosphere_height.max(0.1),
+            rayleigh_scattering: self.rayleigh_scattering.max(Vec3::ZERO),
+            rayleigh_scale_height: self.rayleigh_scale_height.max(1e-3),
+            mie_scattering: self.mie_scattering.max(0.0),
+            mie_absorption: self.mie_absorption.max(0.0),
+            mie_scale_height: self.mie_scale_height.max(1e-3),
+            mie_anisotropy: self.mie_anisotropy.clamp(-0.99, 0.99),
+            ozone_absorption: self.ozone_absorption.max(Vec3::ZERO),
+            ground_albedo: self.ground_albedo.max(Vec3::ZERO).min(Vec3::ONE),
+        }
+    }
+}
+
+// Must match `AtmosphereConstants` in `atmosphere/common.hlsl`
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct AtmosphereConstants {
+    rayleigh_scattering: [f32; 4],
+    mie: [f32; 4],
+    ozone_absorption: [f32; 4],
+    ground_albedo: [f32; 4],
+    bottom_radius: f32,
+    top_radius: f32,
+    world_scale: f32,
+    aerial_perspective_distance: f32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ApplyConstants {
+    output_tex_size: [f32; 4],
+    atmosphere: AtmosphereConstants,
+}
+
+pub struct AtmosphereRenderer {
+    pub params: SkyParams,
+
+    /// Kilometers per world-space unit. Aerial perspective is only noticeable
+    /// over long distances, so raising this makes it visible in small scenes.
+    pub world_scale: f32,
+
+    /// Distance in kilometers covered by the aerial perspective volume.
+    /// The scene beyond it receives the same amount of aerial perspective as at its end.
+    pub aerial_perspective_distance: f32,
+
+    computed_params: Option<SkyParams>,
+    // Those the LUTs are computed for in the frame being recorded; see `retire_frame`.
+    recorded_params: Option<SkyParams>,
+}
+
+impl Default for AtmosphereRenderer {
+    fn default() -> Self {
+        Self {
+            params: Default::default(),
+            world_scale: 0.001,
+            aerial_perspective_distance: 32.0,
+            computed_params: None,
+            recorded_params: None,
+        }
+    }
+}
+
+pub struct AtmosphereLuts {
+    pub transmittance: rg::Handle<Image>,
+    pub multiscatter: rg::Handle<Image>,
+    pub sky_view: rg::Handle<Image>,
+    constants: AtmosphereConstants,
+}
+
+impl AtmosphereRenderer {
+    /// Call once the frame last recorded is submitted. Until then, the LUTs computed in it
+    /// aren't relied upon, as the frame may still be dropped.
+    pub fn retire_frame(&mut self) {
+        if let Some(params) = self.recorded_params.take() {
+            self.computed_params = Some(params);
+        }
+    }
+
+    fn constants(&self, params: &SkyParams) -> AtmosphereConstants {
+        AtmosphereConstants {
+            rayleigh_scattering: params
+                .rayleigh_scattering
+                .extend(params.rayleigh_scale_height)
+                .into(),
+            mie: [
+                params.mie_scattering,
+                params.mie_scattering + params.mie_absorption,
+                params.mie_scale_height,
+                params.mie_anisotropy,
+            ],
+            ozone_absorption: params.ozone_absorption.extend(0.0).into(),
+            ground_albedo: params.ground_albedo.extend(0.0).into(),
+            bottom_radius: params.planet_radius,
+            top_radius: params.planet_radius + params.atmosphere_height,
+            world_scale: self.world_scale.max(0.0),
+            aerial_perspective_distance: self.aerial_perspective_distance.max(1e-3),
+        }
+    }
+
+    /// Builds the lookup tables, re-using the cached transmittance and multiple scattering
+    /// ones unless `params` changed since they were last computed.
+    pub fn prepare(&mut self, rg: &mut rg::TemporalRenderGraph) -> AtmosphereLuts {
+        let params = self.params.sanitized();
+        let constants = self.constants(&params);
+
+        let mut transmittance_lut = rg
+            .get_or_create_temporal(
+                "atmosphere.transmittance_lut",
+                ImageDesc::new_2d(vk::Format::R16G16B16A16_SFLOAT, TRANSMITTANCE_LUT_EXTENT)
+                    .usage(vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::STORAGE),
+            )
+            .unwrap();
+
+        let mut multiscatter_lut = rg
+            .get_or_create_temporal(
+                "atmosphere.multiscatter_lut",
+                ImageDesc::new_2d(vk::Format::R16G16B16A16_SFLOAT, MULTISCATTER_LUT_EXTENT)
+                    .usage(vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::STORAGE),
+            )
+            .unwrap();
+
+        if self.computed_params != Some(params) {
+            SimpleRenderPass::new_compute(
+                rg.add_pass("atmosphere transmittance"),
+                "/shaders/atmosphere/transmittance_lut.hlsl",
+            )
+            .write(&mut transmittance_lut)
+            .constants(constants)
+            .dispatch(transmittance_lut.desc().extent);
+
+            SimpleRenderPass::new_compute(
+                rg.add_pass("atmosphere multiscatter"),
+                "/shaders/atmosphere/multiscatter_lut.hlsl",
+            )
+            .read(&transmittance_lut)
+            .write(&mut multiscatter_lut)
+            .constants(constants)
+            .dispatch(multiscatter_lut.desc().extent);
+
+            self.recorded_params = Some(params);
+        } else {
+            self.recorded_params = None;
+        }
+
+        let mut sky_view_lut = rg.create(ImageDesc::new_2d(
+            vk::Format::R16G16B16A16_SFLOAT,
+            SKY_VIEW_LUT_EXTENT,
+        ));
+
+        SimpleRenderPass::new_compute(
+            rg.add_pass("atmosphere sky view"),
+            "/shaders/atmosphere/sky_view_lut.hlsl",
+        )
+        .read(&transmittance_lut)
+        .read(&multiscatter_lut)
+        .write(&mut sky_view_lut)
+        .constants(constants)
+        .dispatch(sky_view_lut.desc().extent);
+
+        AtmosphereLuts {
+            transmittance: transmittance_lut,
+            multiscatter: multiscatter_lut,
+            sky_view: sky_view_lut,
+            constants,
+        }
+    }
+}
+
+impl AtmosphereLuts {
+    /// Renders the visible sky into a cube map; a drop-in replacement for `sky::render_sky_cube`.
+    pub fn render_sky_cube(&self, rg: &mut rg::RenderGraph) -> rg::Handle<Image> {
+        let mut sky_tex = rg.create(ImageDesc::new_cube(
+            vk::Format::R16G16B16A16_SFLOAT,
+            SKY_CUBE_WIDTH,
+        ));
+
+        SimpleRenderPass::new_compute(
+            rg.add_pass("atmosphere sky cube"),
+            "/shaders/atmosphere/sky_cube.hlsl",
+        )
+        .read(&self.sky_view)
+        .write_view(
+            &mut sky_tex,
+            ImageViewDesc::builder().view_type(vk::ImageViewType::TYPE_2D_ARRAY),
+        )
+        .constants(self.constants)
+        .dispatch([SKY_CUBE_WIDTH, SKY_CUBE_WIDTH, 6]);
+
+        sky_tex
+    }
+
+    /// Attenuates the lit scene in `output` by the atmosphere between it and the eye,
+    /// and adds the light scattered towards the eye along the way.
+    pub fn apply_aerial_perspective(
+        &self,
+        rg: &mut rg::RenderGraph,
+        gbuffer_depth: &GbufferDepth,
+        output: &mut rg::Handle<Image>,
+    ) {
+        let mut aerial_perspective_tex = rg.create(ImageDesc::new_3d(
+            vk::Format::R16G16B16A16_SFLOAT,
+            AERIAL_PERSPECTIVE_EXTENT,
+        ));
+
+        SimpleRenderPass::new_compute(
+            rg.add_pass("atmosphere aerial perspective"),
+            "/shaders/atmosphere/aerial_perspective.hlsl",
+        )
+        .read(&self.transmittance)
+        .read(&self.multiscatter)
+        .write(&mut aerial_perspective_tex)
+        .constants(self.constants)
+        .dispatch(AERIAL_PERSPECTIVE_EXTENT);
+
+        SimpleRenderPass::new_compute(
+            rg.add_pass("atmosphere apply"),
+            "/shaders/atmosphere/apply_aerial_perspective.hlsl",
+        )
+        .read_aspect(&gbuffer_depth.depth, vk::ImageAspectFlags::DEPTH)
+        .read(&aerial_perspective_tex)
+        .read_write(output)
+        .constants(ApplyConstants {
+            output_tex_size: output.desc().extent_inv_extent_2d(),
+            atmosphere: self.constants,
+        })
+        .dispatch(output.desc().extent);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renderers::readback::ReadbackImage;
+    use kajiya_backend::{file::set_standard_vfs_mount_points, HeadlessRenderBackend};
+    use rg::{
+        readback::AsyncReadback,
+        renderer::{FrameConstantsLayout, Renderer},
+    };
+
+    // Texels of the transmittance LUT for rays leaving the ground. The first column maps to
+    // the zenith, and the last one to the horizon; see `transmittance_lut_r_mu_to_uv`.
+    const ZENITH_TEXEL: [u32; 2] = [0, 0];
+    const HORIZON_TEXEL: [u32; 2] = [TRANSMITTANCE_LUT_EXTENT[0] - 1, 0];
+
+    fn transmittance_lut_texels(params: SkyParams, texels: &[[u32; 2]]) -> Vec<Vec3> {
+        set_standard_vfs_mount_points(concat!(env!("CARGO_MANIFEST_DIR"), "/../../.."));
+
+        let backend = HeadlessRenderBackend::new(Default::default()).unwrap();
+        let device = backend.device.clone();
+        let mut renderer = Renderer::new(&device).unwrap();
+        let mut readback = AsyncReadback::default();
+
+        let mut atmosphere = AtmosphereRenderer {
+            params,
+            ..Default::default()
+        };
+
+        readback.begin_frame();
+        let mut token = None;
+        renderer
+            .prepare_frame(|rg| {
+                let luts = atmosphere.prepare(rg);
+                token = Some(readback.copy_image_texels(rg, &luts.transmittance, texels, 8));
+            })
+            .unwrap();
+        renderer
+            .draw_frame_headless(|_| FrameConstantsLayout {
+                globals_offset: 0,
+                instance_dynamic_parameters_offset: 0,
+                triangle_lights_offset: 0,
+            })
+            .unwrap();
+        readback.retire_frame(device.frame_counter());
+        atmosphere.retire_frame();
+
+        let image = ReadbackImage {
+            format: vk::Format::R16G16B16A16_SFLOAT,
+            extent: [texels.len() as u32, 1],
+            bytes: readback.wait(&device, token.unwrap()).unwrap(),
+        };
+        image
+            .to_rgba_f32()
+            .unwrap()
+            .into_iter()
+            .map(|[r, g, b, _]| Vec3::new(r, g, b))
+            .collect()
+    }
+
+    // The LUT is stored at half precision, which limits how closely thin optical depths match.
+    fn assert_optical_depth(transmittance: f32, expected_optical_depth: f32) {
+        let optical_depth = -transmittance.ln();
+        assert!(
+            (optical_depth / expected_optical_depth - 1.0).abs() < 1e-2,
+            "optical depth {} differs from the reference {}",
+            optical_depth,
+            expected_optical_depth
+        );
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn zenith_transmittance_matches_analytic_optical_depth() {
+        let params = SkyParams::default();
+        let transmittance = transmittance_lut_texels(params, &[ZENITH_TEXEL])[0];
+
+        // Exponential profiles integrate to their scale height (up to the top of the atmosphere),
+        // and the ozone tent integrates to its half-width.
+        let height = params.atmosphere_height;
+        let rayleigh_depth =
+            params.rayleigh_scale_height * (1.0 - (-height / params.rayleigh_scale_height).exp());
+        let mie_depth = params.mie_scale_height * (1.0 - (-height / params.mie_scale_height).exp());
+        let expected = params.rayleigh_scattering * rayleigh_depth
+            + Vec3::splat((params.mie_scattering + params.mie_absorption) * mie_depth)
+            + params.ozone_absorption * OZONE_HALF_WIDTH;
+
+        assert_optical_depth(transmittance.x, expected.x);
+        assert_optical_depth(transmittance.y, expected.y);
+        assert_optical_depth(transmittance.z, expected.z);
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn horizon_transmittance_matches_chapman_function() {
+        let params = SkyParams {
+            mie_scattering: 0.0,
+            mie_absorption: 0.0,
+            ozone_absorption: Vec3::ZERO,
+            ..Default::default()
+        };
+
+        // Grazing incidence Chapman function: Ch(x, 90°) ≈ sqrt(πx/2) * (1 + 1/(8x))
+        let scale_height = params.rayleigh_scale_height;
+        let x = params.planet_radius / scale_height;
+        let chapman = (std::f32::consts::PI * x / 2.0).sqrt() * (1.0 + 1.0 / (8.0 * x));
+        let expected = params.rayleigh_scattering * scale_height * chapman;
+
+        let transmittance = transmittance_lut_texels(params, &[HORIZON_TEXEL])[0];
+        assert_optical_depth(transmittance.x, expected.x);
+        assert_optical_depth(transmittance.y, expected.y);
+        assert_optical_depth(transmittance.z, expected.z);
+    }
+}
@@ -0,0 +1,368 @@
+// Color grading with a 3D lookup table, loaded from an Adobe/Resolve `.cube` file.
+//
+// Applied to the tonemapped image. Like most grading tools, `.cube` LUTs map sRGB-encoded
+// values, so the image is encoded before the lookup and decoded after it. LUTs only cover SDR;
+// in HDR output, values above SDR white are clipped by the grade.
+
+use std::{path::Path, sync::Arc};
+
+use anyhow::Context as _;
+use kajiya_backend::{ash::vk, vk_sync::AccessType, vulkan::image::*, Device};
+use kajiya_rg::{self as rg, SimpleRenderPass};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CubeLut {
+    pub title: Option<String>,
+
+    /// Entries along each axis
+    pub size: usize,
+
+    /// The input range mapped onto the table
+    pub domain_min: [f32; 3],
+    pub domain_max: [f32; 3],
+
+    /// `size`³ entries, red varying fastest, then green, then blue.
+    pub data: Vec<[f32; 3]>,
+}
+
+impl CubeLut {
+    /// The LUT which maps every color to itself
+    pub fn identity(size: usize) -> Self {
+        let max = (size - 1) as f32;
+        let data = (0..size * size * size)
+            .map(|idx| {
+                [
+                    (idx % size) as f32 / max,
+                    (idx / size % size) as f32 / max,
+                    (idx / (size * size)) as f32 / max,
+                ]
+            })
+            .collect();
+
+        Self {
+            title: None,
+            size,
+            domain_min: [0.0; 3],
+            domain_max: [1.0; 3],
+            data,
+        }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Reading LUT file {:?}", path))?;
+        Self::parse(&text).with_context(|| format!("Parsing LUT file {:?}", path))
+    }
+
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let mut title = None;
+        let mut size = None;
+        let mut domain_min = [0.0; 3];
+        let mut domain_max = [1.0; 3];
+        let mut data = Vec::new();
+
+        fn parse_triple<'a>(mut values: impl Iterator<Item = &'a str>) -> anyhow::Result<[f32; 3]> {
+            let mut res = [0.0; 3];
+            for value in &mut res {
+                *value = values
+                    .next()
+                    .context("Expected three values")?
+                    .parse()
+                    .context("Expected a number")?;
+            }
+            anyhow::ensure!(values.next().is_none(), "Expected three values");
+            Ok(res)
+        }
+
+        for (line_idx, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut tokens = line.split_whitespace();
+            let keyword = tokens.next().unwrap();
+
+            (|| -> anyhow::Result<()> {
+                match keyword {
+                    "TITLE" => {
+                        title = Some(line["TITLE".len()..].trim().trim_matches('"').to_owned());
+                    }
+                    "LUT_3D_SIZE" => {
+                        let value: usize = tokens
+                            .next()
+                            .context("Expected the size")?
+                            .parse()
+                            .context("Expected an integer size")?;
+                        anyhow::ensure!(
+                            (2..=256).contains(&value),
+                            "Unsupported LUT size {}",
+                            value
+                        );
+                        size = Some(value);
+                    }
+                    "LUT_1D_SIZE" => anyhow::bail!("1D LUTs are not supported"),
+                    "DOMAIN_MIN" => domain_min = parse_triple(tokens)?,
+                    "DOMAIN_MAX" => domain_max = parse_triple(tokens)?,
+                    _ if keyword
+                        .starts_with(|c: char| c.is_ascii_digit() || c == '-' || c == '.') =>
+                    {
+                        data.push(parse_triple(line.split_whitespace())?);
+                    }
+                    // Other keywords, like `LUT_3D_INPUT_RANGE`, are vendor extensions.
+                    _ => log::warn!("Ignoring unknown LUT keyword {:?}", keyword),
+                }
+                Ok(())
+            })()
+            .with_context(|| format!("Line {}: {:?}", line_idx + 1, line))?;
+        }
+
+        let size = size.context("Missing LUT_3D_SIZE")?;
+        anyhow::ensure!(
+            data.len() == size * size * size,
+            "Expected {} entries for LUT_3D_SIZE {}, but found {}",
+            size * size * size,
+            size,
+            data.len()
+        );
+        anyhow::ensure!(
+            (0..3).all(|i| domain_max[i] > domain_min[i]),
+            "DOMAIN_MAX must be greater than DOMAIN_MIN"
+        );
+
+        Ok(Self {
+            title,
+            size,
+            domain_min,
+            domain_max,
+            data,
+        })
+    }
+
+    /// Looks up `color` with trilinear interpolation, like the GPU does.
+    pub fn sample(&self, color: [f32; 3]) -> [f32; 3] {
+        let max = (self.size - 1) as f32;
+        let mut base = [0usize; 3];
+        let mut frac = [0.0f32; 3];
+
+        for i in 0..3 {
+            let t = ((color[i] - self.domain_min[i]) / (self.domain_max[i] - self.domain_min[i]))
+                .max(0.0)
+                .min(1.0)
+                * max;
+            base[i] = (t.floor() as usize).min(self.size - 2);
+            frac[i] = t - base[i] as f32;
+        }
+
+        let at = |r: usize, g: usize, b: usize| {
+            self.data
+                [(base[2] + b) * self.size * self.size + (base[1] + g) * self.size + base[0] + r]
+        };
+        let lerp = |a: [f32; 3], b: [f32; 3], t: f32| {
+            [
+                a[0] + (b[0] - a[0]) * t,
+                a[1] + (b[1] - a[1]) * t,
+                a[2] + (b[2] - a[2]) * t,
+            ]
+        };
+
+        let [fr, fg, fb] = frac;
+        lerp(
+            lerp(
+                lerp(at(0, 0, 0), at(1, 0, 0), fr),
+                lerp(at(0, 1, 0), at(1, 1, 0), fr),
+                fg,
+            ),
+            lerp(
+                lerp(at(0, 0, 1), at(1, 0, 1), fr),
+                lerp(at(0, 1, 1), at(1, 1, 1), fr),
+                fg,
+            ),
+            fb,
+        )
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ColorGradingConstants {
+    domain_min_strength: [f32; 4],
+    domain_max_size: [f32; 4],
+}
+
+struct UploadedLut {
+    image: Arc<Image>,
+    size: usize,
+    domain_min: [f32; 3],
+    domain_max: [f32; 3],
+}
+
+pub struct ColorGradingRenderer {
+    /// Blends between the ungraded (0) and graded (1) image.
+    pub strength: f32,
+
+    lut: Option<UploadedLut>,
+}
+
+impl Default for ColorGradingRenderer {
+    fn default() -> Self {
+        Self {
+            strength: 1.0,
+            lut: None,
+        }
+    }
+}
+
+impl ColorGradingRenderer {
+    /// Grading is off until a LUT is set. The previous LUT is released once frames in flight
+    /// are done with it.
+    pub fn set_lut(&mut self, device: &Device, lut: Option<&CubeLut>) -> anyhow::Result<()> {
+        let lut = match lut {
+            Some(lut) => lut,
+            None => {
+                self.release_lut(device);
+                return Ok(());
+            }
+        };
+
+        let size = lut.size as u32;
+        let texels: Vec<f32> = lut
+            .data
+            .iter()
+            .flat_map(|rgb| [rgb[0], rgb[1], rgb[2], 1.0])
+            .collect();
+        let bytes: &[u8] = bytemuck::cast_slice(&texels);
+
+        let image = device.create_image(
+            ImageDesc::new_3d(vk::Format::R32G32B32A32_SFLOAT, [size, size, size])
+                .usage(vk::ImageUsageFlags::SAMPLED),
+            "Color grading LUT",
+            vec![ImageSubResourceData {
+                data: bytes,
+                row_pitch: size as usize * 16,
+                slice_pitch: (size * size) as usize * 16,
+            }],
+        )?;
+
+        self.release_lut(device);
+        self.lut = Some(UploadedLut {
+            image: Arc::new(image),
+            size: lut.size,
+            domain_min: lut.domain_min,
+            domain_max: lut.domain_max,
+        });
+
+        Ok(())
+    }
+
+    fn release_lut(&mut self, device: &Device) {
+        if let Some(lut) = self.lut.take() {
+            device.defer_release_image(&lut.image);
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.lut.is_some() && self.strength > 0.0
+    }
+
+    /// Returns `input` unchanged if no LUT is set.
+    pub fn render(&self, rg: &mut rg::RenderGraph, input: rg::Handle<Image>) -> rg::Handle<Image> {
+        let lut = match &self.lut {
+            Some(lut) if self.is_active() => lut,
+            _ => return input,
+        };
+
+        let lut_img = rg.import(
+            lut.image.clone(),
+            AccessType::AnyShaderReadSampledImageOrUniformTexelBuffer,
+        );
+        let mut output = rg.create(*input.desc());
+
+        let [min_r, min_g, min_b] = lut.domain_min;
+        let [max_r, max_g, max_b] = lut.domain_max;
+
+        SimpleRenderPass::new_compute(rg.add_pass("color grading"), "/shaders/color_grading.hlsl")
+            .read(&input)
+            .read(&lut_img)
+            .write(&mut output)
+            .constants(ColorGradingConstants {
+                domain_min_strength: [min_r, min_g, min_b, self.strength.min(1.0)],
+                domain_max_size: [max_r, max_g, max_b, lut.size as f32],
+            })
+            .dispatch(output.desc().extent);
+
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A warming grade: red lifted, blue lowered
+    const WARM_CUBE: &str = r#"
+# Created by hand
+TITLE "Warm"
+LUT_3D_SIZE 2
+DOMAIN_MIN 0.0 0.0 0.0
+DOMAIN_MAX 1.0 1.0 1.0
+
+0.1 0.0 0.0
+1.0 0.0 0.0
+0.1 1.0 0.0
+1.0 1.0 0.0
+0.1 0.0 0.8
+1.0 0.0 0.8
+0.1 1.0 0.8
+1.0 1.0 0.8
+"#;
+
+    fn assert_close(actual: [f32; 3], expected: [f32; 3]) {
+        assert!(
+            (0..3).all(|i| (actual[i] - expected[i]).abs() < 1e-5),
+            "{:?} != {:?}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn parses_cube_files() {
+        let lut = CubeLut::parse(WARM_CUBE).unwrap();
+        assert_eq!(lut.title.as_deref(), Some("Warm"));
+        assert_eq!(lut.size, 2);
+        assert_eq!(lut.data.len(), 8);
+        assert_eq!(lut.data[4], [0.1, 0.0, 0.8]);
+
+        assert_close(lut.sample([0.0, 0.0, 0.0]), [0.1, 0.0, 0.0]);
+        assert_close(lut.sample([1.0, 1.0, 1.0]), [1.0, 1.0, 0.8]);
+        assert_close(lut.sample([0.5, 0.5, 0.5]), [0.55, 0.5, 0.4]);
+
+        let err = CubeLut::parse(&WARM_CUBE.replace("0.1 1.0 0.8\n", "")).unwrap_err();
+        assert!(format!("{:#}", err).contains("found 7"), "{:#}", err);
+        assert!(CubeLut::parse("LUT_1D_SIZE 16").is_err());
+    }
+
+    #[test]
+    fn identity_lut_passes_colors_through() {
+        for size in [17, 33] {
+            // Round-trip through the text format, as written by grading tools
+            let mut text = format!("LUT_3D_SIZE {}\n", size);
+            for [r, g, b] in CubeLut::identity(size).data {
+                text += &format!("{:.6} {:.6} {:.6}\n", r, g, b);
+            }
+            let lut = CubeLut::parse(&text).unwrap();
+            assert_eq!(lut.size, size);
+
+            for color in [
+                [0.0, 0.0, 0.0],
+                [1.0, 1.0, 1.0],
+                [0.18, 0.5, 0.9],
+                [0.33, 0.01, 0.77],
+            ] {
+                assert_close(lut.sample(color), color);
+            }
+        }
+    }
+}
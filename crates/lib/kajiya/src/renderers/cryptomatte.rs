@@ -0,0 +1,350 @@
+// Cryptomatte: per-pixel coverage of objects, identified by hashes of their names, for
+// building mattes in compositing.
+//
+// Each pixel gathers the object IDs rasterized at its neighbors, and weighs them by a filter
+// centered on the pixel, at the sample positions of the current jitter. The IDs are ranked
+// by their total weight, and the strongest `CRYPTOMATTE_RANKS` are written as pairs of
+// (hash as float, coverage), two pairs per RGBA32F layer, following the Cryptomatte
+// specification. The manifest which maps names back to hashes goes into the EXR header.
+//
+// A single frame only has one jittered sample per pixel, so the coverage is averaged over
+// frames, for as long as the view and the objects stay put.
+
+use std::collections::BTreeMap;
+
+use anyhow::Context as _;
+use kajiya_backend::{ash::vk, vulkan::image::*};
+use kajiya_rg::{self as rg, SimpleRenderPass};
+use rust_shaders_shared::camera::CameraMatrices;
+
+use super::{readback::ReadbackImage, GbufferDepth, PingPongTemporalResource};
+
+/// Name of the Cryptomatte, and the prefix of its layers: `CryptoObject00`, `CryptoObject01`, ...
+pub const CRYPTOMATTE_NAME: &str = "CryptoObject";
+
+/// Must match `RANK_COUNT` in `aov/cryptomatte.hlsl`
+pub const CRYPTOMATTE_RANKS: usize = 6;
+
+pub const CRYPTOMATTE_LAYER_COUNT: usize = CRYPTOMATTE_RANKS / 2;
+
+/// The ID of the object called `name`, as the bits of the float stored in the layers:
+/// its MurmurHash3, with the exponent nudged off of denormals, infinities, and NaNs.
+pub fn cryptomatte_hash(name: &str) -> u32 {
+    let hash = murmur3_32(name.as_bytes(), 0);
+    let exponent = (hash >> 23) & 0xff;
+    if exponent == 0 || exponent == 0xff {
+        hash ^ (1 << 23)
+    } else {
+        hash
+    }
+}
+
+fn murmur3_32(bytes: &[u8], seed: u32) -> u32 {
+    const C1: u32 = 0xcc9e_2d51;
+    const C2: u32 = 0x1b87_3593;
+
+    let mut hash = seed;
+    let mut chunks = bytes.chunks_exact(4);
+    for chunk in &mut chunks {
+        let k = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        hash ^= k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+        hash = hash
+            .rotate_left(13)
+            .wrapping_mul(5)
+            .wrapping_add(0xe654_6b64);
+    }
+
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        let k = tail
+            .iter()
+            .rev()
+            .fold(0u32, |k, &byte| (k << 8) | byte as u32);
+        hash ^= k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+    }
+
+    hash ^= bytes.len() as u32;
+    hash ^= hash >> 16;
+    hash = hash.wrapping_mul(0x85eb_ca6b);
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(0xc2b2_ae35);
+    hash ^ (hash >> 16)
+}
+
+/// The names of the objects in a Cryptomatte, by their hashes.
+pub struct CryptomatteManifest {
+    hashes: BTreeMap<String, u32>,
+}
+
+impl CryptomatteManifest {
+    pub fn new<'a>(names: impl IntoIterator<Item = &'a str>) -> Self {
+        Self {
+            hashes: names
+                .into_iter()
+                .map(|name| (name.to_owned(), cryptomatte_hash(name)))
+                .collect(),
+        }
+    }
+
+    pub fn hash(&self, name: &str) -> Option<u32> {
+        self.hashes.get(name).copied()
+    }
+
+    /// `{"name":"<hash as 8 hex digits>",...}`, sorted by name.
+    pub fn to_json(&self) -> String {
+        let entries: Vec<String> = self
+            .hashes
+            .iter()
+            .map(|(name, hash)| format!("{}:\"{:08x}\"", json_string(name), hash))
+            .collect();
+        format!("{{{}}}", entries.join(","))
+    }
+
+    /// The header attributes which identify the layers of the Cryptomatte to DCC tools.
+    pub fn exr_attributes(&self) -> Vec<(String, String)> {
+        let key = &format!("{:08x}", murmur3_32(CRYPTOMATTE_NAME.as_bytes(), 0))[..7];
+        let attribute =
+            |name: &str, value: String| (format!("cryptomatte/{}/{}", key, name), value);
+
+        vec![
+            attribute("name", CRYPTOMATTE_NAME.to_owned()),
+            attribute("hash", "MurmurHash3_32".to_owned()),
+            attribute("conversion", "uint32_to_float32".to_owned()),
+            attribute("manifest", self.to_json()),
+        ]
+    }
+
+    pub(super) fn insert_exr_attributes(
+        &self,
+        attributes: &mut exr::meta::header::LayerAttributes,
+    ) -> anyhow::Result<()> {
+        use exr::prelude::*;
+
+        for (name, value) in self.exr_attributes() {
+            let value = Text::new_or_none(&value)
+                .with_context(|| format!("Can't store {:?} in an EXR header", value))?;
+            attributes
+                .other
+                .insert(Text::from(name.as_str()), AttributeValue::Text(value));
+        }
+
+        Ok(())
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut res = String::with_capacity(s.len() + 2);
+    res.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            c if (c as u32) < 0x20 => res.push_str(&format!("\\u{:04x}", c as u32)),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+// What the accumulated coverage was rendered with
+struct CryptomatteAccumulation {
+    camera_matrices: CameraMatrices,
+    object_hashes: Vec<u32>,
+    frame_count: u32,
+}
+
+pub struct CryptomatteRenderer {
+    layers: [PingPongTemporalResource; CRYPTOMATTE_LAYER_COUNT],
+    accumulated: Option<CryptomatteAccumulation>,
+    recorded: Option<CryptomatteAccumulation>,
+}
+
+impl Default for CryptomatteRenderer {
+    fn default() -> Self {
+        Self {
+            layers: array_init::array_init(|i| {
+                PingPongTemporalResource::new(&format!("cryptomatte.layer{}", i))
+            }),
+            accumulated: None,
+            recorded: None,
+        }
+    }
+}
+
+impl CryptomatteRenderer {
+    /// Writes the `CRYPTOMATTE_LAYER_COUNT` layers of the Cryptomatte, from `object_hashes`
+    /// indexed by the object ID minus one. The coverage accumulates over the frames since
+    /// the camera, the hashes, or any object moved, as told by `objects_moved`.
+    pub fn render(
+        &mut self,
+        rg: &mut rg::TemporalRenderGraph,
+        gbuffer_depth: &GbufferDepth,
+        camera_matrices: &CameraMatrices,
+        object_hashes: Vec<u32>,
+        objects_moved: bool,
+    ) -> [rg::Handle<Image>; CRYPTOMATTE_LAYER_COUNT] {
+        let object_id_img = gbuffer_depth
+            .object_id
+            .as_ref()
+            .expect("the Cryptomatte needs object IDs");
+
+        // A frame which was recorded but never retired may have left the histories half-swapped.
+        let accumulated_frames = match (&self.accumulated, &self.recorded) {
+            (Some(accumulated), None)
+                if !objects_moved
+                    && accumulated.camera_matrices == *camera_matrices
+                    && accumulated.object_hashes == object_hashes =>
+            {
+                accumulated.frame_count
+            }
+            _ => 0,
+        };
+
+        self.recorded = Some(CryptomatteAccumulation {
+            camera_matrices: *camera_matrices,
+            object_hashes: object_hashes.clone(),
+            frame_count: accumulated_frames + 1,
+        });
+
+        // Storage buffers can't be empty.
+        let mut object_hashes = object_hashes;
+        if object_hashes.is_empty() {
+            object_hashes.push(0);
+        }
+
+        let desc = ImageDesc::new_2d(
+            vk::Format::R32G32B32A32_SFLOAT,
+            object_id_img.desc().extent_2d(),
+        )
+        .usage(vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::STORAGE);
+
+        let [(mut layer0, history0), (mut layer1, history1), (mut layer2, history2)] = {
+            let [l0, l1, l2] = &mut self.layers;
+            [
+                l0.get_output_and_history(rg, desc),
+                l1.get_output_and_history(rg, desc),
+                l2.get_output_and_history(rg, desc),
+            ]
+        };
+
+        SimpleRenderPass::new_compute(rg.add_pass("cryptomatte"), "/shaders/aov/cryptomatte.hlsl")
+            .read(object_id_img)
+            .dynamic_storage_buffer_vec(object_hashes)
+            .read(&history0)
+            .read(&history1)
+            .read(&history2)
+            .write(&mut layer0)
+            .write(&mut layer1)
+            .write(&mut layer2)
+            .constants((
+                object_id_img.desc().extent_inv_extent_2d(),
+                accumulated_frames,
+            ))
+            .dispatch(object_id_img.desc().extent);
+
+        [layer0, layer1, layer2]
+    }
+
+    /// Call once the frame last recorded is submitted. Until then, its coverage isn't
+    /// accumulated upon, as the frame may still be dropped.
+    pub fn retire_frame(&mut self) {
+        if let Some(recorded) = self.recorded.take() {
+            self.accumulated = Some(recorded);
+        }
+    }
+}
+
+/// Writes the layers of a Cryptomatte read back from `CryptomatteRenderer::render` to an OpenEXR file,
+/// along with its manifest.
+pub fn write_cryptomatte_exr(
+    path: impl AsRef<std::path::Path>,
+    layers: &[ReadbackImage],
+    manifest: &CryptomatteManifest,
+) -> anyhow::Result<()> {
+    use exr::prelude::*;
+
+    let extent = layers.first().context("No Cryptomatte layers")?.extent;
+
+    let mut channels = Vec::new();
+    for (layer_idx, layer) in layers.iter().enumerate() {
+        anyhow::ensure!(
+            layer.format == vk::Format::R32G32B32A32_SFLOAT && layer.extent == extent,
+            "Not a Cryptomatte layer"
+        );
+
+        let texels = layer.to_rgba_f32().unwrap();
+        for (channel_idx, channel) in ["R", "G", "B", "A"].iter().enumerate() {
+            channels.push(AnyChannel::new(
+                format!("{}{:02}.{}", CRYPTOMATTE_NAME, layer_idx, channel).as_str(),
+                FlatSamples::F32(texels.iter().map(|texel| texel[channel_idx]).collect()),
+            ));
+        }
+    }
+
+    let size = (extent[0] as usize, extent[1] as usize);
+    let mut attributes = LayerAttributes::default();
+    manifest.insert_exr_attributes(&mut attributes)?;
+
+    // Hashes must stay bit-exact, so the compression has to be lossless.
+    Image::from_layer(Layer::new(
+        size,
+        attributes,
+        Encoding::FAST_LOSSLESS,
+        AnyChannels::sort(channels.into()),
+    ))
+    .write()
+    .to_file(path)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifest_maps_object_names_to_hashes() {
+        // Reference MurmurHash3_x86_32 values
+        assert_eq!(murmur3_32(b"", 0), 0);
+        assert_eq!(murmur3_32(b"hello", 0), 0x248b_fa47);
+        assert_eq!(
+            murmur3_32(b"The quick brown fox jumps over the lazy dog", 0),
+            0x2e4f_f723
+        );
+
+        let names = ["car", "ground \"plane\"", "hello", "car"];
+        let manifest = CryptomatteManifest::new(names.iter().copied());
+
+        assert_eq!(manifest.hash("hello"), Some(0x248b_fa47));
+        assert_eq!(manifest.hash("car"), Some(cryptomatte_hash("car")));
+        assert_eq!(manifest.hash("sky"), None);
+
+        assert_eq!(
+            manifest.to_json(),
+            format!(
+                "{{\"car\":\"{:08x}\",\"ground \\\"plane\\\"\":\"{:08x}\",\"hello\":\"248bfa47\"}}",
+                cryptomatte_hash("car"),
+                cryptomatte_hash("ground \"plane\""),
+            )
+        );
+
+        // The IDs are stored as floats, which compositors compare for equality.
+        for name in names.iter().copied().chain(["instance0", "instance1", ""]) {
+            let id = f32::from_bits(cryptomatte_hash(name));
+            assert!(id.is_normal(), "{:?}", name);
+        }
+
+        let attributes = manifest.exr_attributes();
+        assert!(attributes
+            .iter()
+            .all(|(name, _)| name.starts_with("cryptomatte/3ae39a5/")));
+        assert!(attributes.contains(&(
+            "cryptomatte/3ae39a5/manifest".to_owned(),
+            manifest.to_json()
+        )));
+    }
+}
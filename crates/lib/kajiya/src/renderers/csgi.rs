@@ -20,7 +20,7 @@ use kajiya_rg::{
 
 use rust_shaders_shared::frame_constants::GiCascadeConstants;
 
-use super::GbufferDepth;
+use super::{procedural::ProceduralHitGroupRegistry, rt_hit_groups, GbufferDepth};
 
 // VOLUME_DIMS and CASCADE_COUNT must match GPU code.
 // Seach token: d4109bba-438f-425e-8667-19e591be9a56
@@ -42,6 +42,10 @@ pub struct CsgiRenderer {
     frame_idx: u32,
     cur_scroll: [CascadeScroll; CASCADE_COUNT],
     prev_scroll: [CascadeScroll; CASCADE_COUNT],
+
+    // Until a submitted frame clears the volume; see `reset`.
+    reset_pending: bool,
+    recorded_reset: bool,
 }
 
 impl Default for CsgiRenderer {
@@ -52,6 +56,8 @@ impl Default for CsgiRenderer {
             frame_idx: 0,
             cur_scroll: Default::default(),
             prev_scroll: Default::default(),
+            reset_pending: false,
+            recorded_reset: false,
         }
     }
 }
@@ -176,6 +182,19 @@ impl CsgiRenderer {
         })
     }
 
+    /// Clears the volume in the next frame, e.g. once the space it's in moves.
+    pub fn reset(&mut self) {
+        self.reset_pending = true;
+    }
+
+    /// Call once the frame last recorded is submitted. Until then, the volume isn't considered
+    /// cleared by it, as the frame may still be dropped.
+    pub fn retire_frame(&mut self) {
+        if std::mem::take(&mut self.recorded_reset) {
+            self.reset_pending = false;
+        }
+    }
+
     pub fn render(
         &mut self,
         _eye_position: Vec3,
@@ -183,6 +202,7 @@ impl CsgiRenderer {
         sky_cube: &rg::Handle<Image>,
         bindless_descriptor_set: vk::DescriptorSet,
         tlas: &rg::Handle<RayTracingAcceleration>,
+        procedural_hit_groups: &ProceduralHitGroupRegistry,
     ) -> CsgiVolume {
         let CsgiVolume {
             direct: mut direct_cascades,
@@ -191,6 +211,17 @@ impl CsgiRenderer {
             opacity: mut opacity_cascades,
         } = self.create_volume(rg);
 
+        if self.reset_pending {
+            for cascade in direct_cascades
+                .iter_mut()
+                .chain(indirect_combined_cascades.iter_mut())
+                .chain(indirect_cascades.iter_mut())
+            {
+                rg::imageops::clear_color(rg, cascade, [0.0; 4]);
+            }
+            self.recorded_reset = true;
+        }
+
         // Stagger cascade updates over frames
         //let cascade_update_mask = 1usize << (self.frame_idx as usize % CASCADE_COUNT);
         let cascade_update_mask = !0usize;
@@ -233,13 +264,14 @@ impl CsgiRenderer {
                     ShaderSource::hlsl("/shaders/rt/gbuffer.rmiss.hlsl"),
                     ShaderSource::hlsl("/shaders/rt/shadow.rmiss.hlsl"),
                 ],
-                [ShaderSource::hlsl("/shaders/rt/gbuffer.rchit.hlsl")],
+                rt_hit_groups(procedural_hit_groups),
             )
+            .hit_records(procedural_hit_groups.hit_records().to_vec())
             .read_array(&indirect_combined_cascades)
             .read(sky_cube)
             .write(&mut direct_cascades[cascade_i])
             .constants((sweep_vx_count, cascade_i as u32, quantum_idx))
-            .raw_descriptor_set(1, bindless_descriptor_set)
+            .bindless(bindless_descriptor_set)
             .trace_rays(
                 tlas,
                 [
@@ -371,7 +403,11 @@ impl CsgiRenderer {
                     vk::Format::R16G16B16A16_SFLOAT,
                     direct_cascade_dimensions,
                 )
-                .usage(vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::STORAGE),
+                .usage(
+                    vk::ImageUsageFlags::SAMPLED
+                        | vk::ImageUsageFlags::STORAGE
+                        | vk::ImageUsageFlags::TRANSFER_DST,
+                ),
             )
             .unwrap()
         });
@@ -384,7 +420,11 @@ impl CsgiRenderer {
                     //vk::Format::R16G16B16A16_SFLOAT,
                     indirect_cascade_dimensions,
                 )
-                .usage(vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::STORAGE),
+                .usage(
+                    vk::ImageUsageFlags::SAMPLED
+                        | vk::ImageUsageFlags::STORAGE
+                        | vk::ImageUsageFlags::TRANSFER_DST,
+                ),
             )
             .unwrap()
         });
@@ -398,7 +438,11 @@ impl CsgiRenderer {
                         //vk::Format::R16G16B16A16_SFLOAT,
                         indirect_combined_cascade_dimensions,
                     )
-                    .usage(vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::STORAGE),
+                    .usage(
+                        vk::ImageUsageFlags::SAMPLED
+                            | vk::ImageUsageFlags::STORAGE
+                            | vk::ImageUsageFlags::TRANSFER_DST,
+                    ),
                 )
                 .unwrap()
             });
@@ -486,13 +530,17 @@ impl CsgiVolume {
             api.set_default_view_and_scissor([width, height]);
 
             let constants_offset = api.dynamic_constants().push(&(cascade_idx as u32));
-            let _ = api.bind_raster_pipeline(pipeline.into_binding().descriptor_set(
+            if let Err(err) = api.bind_raster_pipeline(pipeline.into_binding().descriptor_set(
                 0,
                 &[
                     grid_ref.bind(),
                     rg::RenderPassBinding::DynamicConstants(constants_offset),
                 ],
-            ));
+            )) {
+                log::error!("csgi debug: {}", err);
+                api.end_render_pass();
+                return;
+            }
 
             unsafe {
                 let raw_device = &api.device().raw;
@@ -0,0 +1,924 @@
+// Cascaded shadow maps for the sun, for GPUs without ray tracing, or to save rays.
+//
+// The view frustum is split into slices along its depth, and each gets an orthographic shadow map,
+// fit around its bounding sphere. The cascades share a depth atlas, one tile per cascade side by side.
+// A compute pass then resolves the maps into a shadow mask, in the same `R8_UNORM` format as
+// `trace_sun_shadow_mask`, so that the denoiser and lighting can take either.
+//
+// The atlas persists across frames. Cascades are only re-rendered as their `ShadowUpdatePolicy`
+// requires, and at most `CsmDesc::max_cascade_updates` per frame; the rest keep the map and matrix
+// they were last rendered with.
+
+use std::sync::Arc;
+
+use glam::{Affine3A, Mat4, Vec2, Vec3, Vec4};
+use kajiya_backend::{
+    ash::vk,
+    vk_sync::AccessType,
+    vulkan::{image::*, shader::*},
+    Device,
+};
+use kajiya_rg::{self as rg, GetOrCreateTemporal};
+use rg::{IntoRenderPassPipelineBinding, RenderPassBinding, SimpleRenderPass};
+
+use crate::{camera::CameraMatrices, world_renderer::MeshHandle};
+
+use super::{
+    raster_meshes::{row_major_3x4, InstanceTransform, RasterMeshesData},
+    GbufferDepth,
+};
+
+/// Must match `MAX_CASCADES` in `csm/sample_csm.hlsl`
+pub const MAX_CASCADES: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShadowFilter {
+    /// Percentage-closer filtering over a fixed radius
+    Pcf,
+
+    /// Percentage-closer soft shadows: penumbrae widen with the distance between the occluder
+    /// and the receiver, following the angular size of the sun.
+    Pcss,
+}
+
+/// When cascades are re-rendered. Cascades which aren't keep their previous shadow map.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShadowUpdatePolicy {
+    /// Only once something the map depends on changes: a mesh instance is added, removed
+    /// or moved, or the cascade is re-fit because the camera or the sun moved.
+    Static,
+
+    /// Every frame, for scenes where geometry changes in ways the cache can't see.
+    Dynamic,
+
+    /// Once every this many frames, whether anything changed or not.
+    EveryNFrames(u32),
+}
+
+// Must match the push constants in `csm/shadow_map_vs.hlsl`
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ShadowMapPushConstants {
+    world_to_shadow: [f32; 16],
+    draw_index: u32,
+    mesh_index: u32,
+}
+
+unsafe impl bytemuck::Zeroable for ShadowMapPushConstants {}
+unsafe impl bytemuck::Pod for ShadowMapPushConstants {}
+
+// Must match the constants in `csm/sample_csm.hlsl`
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SampleCsmConstants {
+    world_to_shadow: [[f32; 16]; MAX_CASCADES],
+    cascade_far_distance: [f32; MAX_CASCADES],
+    cascade_radius: [f32; MAX_CASCADES],
+    cascade_depth_range: [f32; MAX_CASCADES],
+    cascade_count: u32,
+    filter: u32,
+    shadow_map_resolution: f32,
+    pcf_radius: f32,
+}
+
+/// The shadow map of one slice of the view frustum.
+#[derive(Clone, Copy, Debug)]
+pub struct ShadowCascade {
+    /// To the cascade's clip space: x and y in [-1, 1] across the map, and depth from 1.0 nearest
+    /// to the sun, to 0.0 furthest away.
+    pub world_to_shadow: Mat4,
+
+    /// View distance at which the next cascade takes over.
+    pub far_distance: f32,
+
+    /// Half of the width covered by the map, in world units.
+    pub radius: f32,
+
+    /// World distance between depths 1.0 and 0.0.
+    pub depth_range: f32,
+}
+
+impl ShadowCascade {
+    /// Width of a shadow map texel, in world units.
+    pub fn texel_size(&self, resolution: u32) -> f32 {
+        2.0 * self.radius / resolution as f32
+    }
+}
+
+/// View distances at which each of `count` cascades ends, between `near` and `far`.
+///
+/// `lambda` blends between uniform splits at 0.0, and logarithmic ones at 1.0. Logarithmic splits
+/// keep the shadow texel density on screen roughly constant, but the near cascades become tiny.
+pub fn cascade_split_distances(near: f32, far: f32, count: usize, lambda: f32) -> Vec<f32> {
+    let lambda = lambda.clamp(0.0, 1.0);
+
+    (1..=count)
+        .map(|i| {
+            let t = i as f32 / count as f32;
+            let log = near * (far / near).powf(t);
+            let uniform = near + (far - near) * t;
+            lambda * log + (1.0 - lambda) * uniform
+        })
+        .collect()
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct CsmDesc {
+    /// Number of cascades, at most `MAX_CASCADES`.
+    pub cascade_count: usize,
+
+    /// Blend between uniform (0.0) and logarithmic (1.0) cascade splits.
+    pub split_lambda: f32,
+
+    /// View distance covered by the cascades; surfaces beyond it are lit.
+    pub max_distance: f32,
+
+    /// Width and height of each cascade's shadow map.
+    pub resolution: u32,
+
+    pub filter: ShadowFilter,
+
+    /// Radius of the PCF kernel, and the minimum penumbra with PCSS, in shadow map texels.
+    pub pcf_radius: f32,
+
+    /// Constant and slope-scaled depth bias of shadow casters. Negative values push them
+    /// away from the sun, against shadow acne.
+    pub depth_bias: [f32; 2],
+
+    pub update_policy: ShadowUpdatePolicy,
+
+    /// Maximum number of cascades re-rendered per frame. Further cascades due for an update
+    /// wait for later frames, least recently rendered first.
+    pub max_cascade_updates: usize,
+}
+
+impl Default for CsmDesc {
+    fn default() -> Self {
+        Self {
+            cascade_count: 4,
+            split_lambda: 0.75,
+            max_distance: 100.0,
+            resolution: 2048,
+            filter: ShadowFilter::Pcss,
+            pcf_radius: 1.5,
+            depth_bias: [-1.0, -2.0],
+            update_policy: ShadowUpdatePolicy::Static,
+            max_cascade_updates: MAX_CASCADES,
+        }
+    }
+}
+
+impl CsmDesc {
+    /// Fits a shadow map around each slice of the view frustum, lit from `sun_direction`.
+    ///
+    /// Each map covers the bounding sphere of its slice, whose size doesn't change as the camera
+    /// turns, and is snapped to whole texels, so that shadow edges don't crawl as the camera moves.
+    pub fn cascades(
+        &self,
+        camera_matrices: &CameraMatrices,
+        sun_direction: Vec3,
+    ) -> Vec<ShadowCascade> {
+        let view_to_clip = camera_matrices.view_to_clip;
+
+        // Reverse Z: depth is `z_bias / distance - z_scale`, and 1.0 at the near plane.
+        let (z_scale, z_bias) = (view_to_clip.z_axis.z, view_to_clip.w_axis.z);
+        let near_distance = z_bias / (1.0 + z_scale);
+
+        // Half of the frustum's extent at unit distance
+        let tan_half_fov = Vec2::new(1.0 / view_to_clip.x_axis.x, 1.0 / view_to_clip.y_axis.y);
+
+        let light_z = sun_direction.normalize();
+        let light_x = if light_z.y.abs() < 0.99 {
+            Vec3::Y.cross(light_z)
+        } else {
+            Vec3::X.cross(light_z)
+        }
+        .normalize();
+        let light_y = light_z.cross(light_x);
+
+        let cascade_count = self.cascade_count.clamp(1, MAX_CASCADES);
+        let far_distances = cascade_split_distances(
+            near_distance,
+            self.max_distance.max(near_distance * 2.0),
+            cascade_count,
+            self.split_lambda,
+        );
+
+        let mut near_distance = near_distance;
+        far_distances
+            .into_iter()
+            .map(|far_distance| {
+                let mut corners = Vec::with_capacity(8);
+                for distance in [near_distance, far_distance] {
+                    for (x, y) in [(-1.0, -1.0), (1.0, -1.0), (-1.0, 1.0), (1.0, 1.0)] {
+                        let pos_vs = Vec3::new(
+                            x * tan_half_fov.x * distance,
+                            y * tan_half_fov.y * distance,
+                            -distance,
+                        );
+                        corners.push(camera_matrices.view_to_world.transform_point3(pos_vs));
+                    }
+                }
+                near_distance = far_distance;
+
+                let center = corners.iter().fold(Vec3::ZERO, |sum, &corner| sum + corner)
+                    / corners.len() as f32;
+                let radius = corners
+                    .iter()
+                    .map(|corner| corner.distance(center))
+                    .fold(0.0f32, f32::max);
+
+                // Snap the center to whole texels. Along the light too, so that the cascade
+                // doesn't need re-rendering while the camera moves within a texel.
+                let texel_size = 2.0 * radius / self.resolution as f32;
+                let snap = |x: f32| (x / texel_size).round() * texel_size;
+                let center_x = snap(center.dot(light_x));
+                let center_y = snap(center.dot(light_y));
+                let center_z = snap(center.dot(light_z));
+
+                // Casters between the slice and the sun, up to `max_distance` away, are included.
+                let z_min = center_z - radius;
+                let z_max = center_z + radius + self.max_distance;
+                let depth_range = z_max - z_min;
+
+                let world_to_shadow = Mat4::from_cols(
+                    (light_x / radius).extend(-center_x / radius),
+                    (light_y / radius).extend(-center_y / radius),
+                    (light_z / depth_range).extend(-z_min / depth_range),
+                    Vec4::W,
+                )
+                .transpose();
+
+                ShadowCascade {
+                    world_to_shadow,
+                    far_distance,
+                    radius,
+                    depth_range,
+                }
+            })
+            .collect()
+    }
+}
+
+// A cascade's shadow map, as last rendered
+#[derive(Clone, Copy)]
+struct CachedCascade {
+    cascade: ShadowCascade,
+    rendered_frame: u32,
+
+    // Something the map depends on changed since it was rendered.
+    dirty: bool,
+}
+
+// Tracks which cascades in the atlas are up to date.
+#[derive(Clone, Default)]
+struct ShadowCache {
+    // Resolution, cascade count and depth bias of the atlas; changing them invalidates all cascades.
+    atlas_key: Option<(u32, usize, [f32; 2])>,
+    cascades: Vec<CachedCascade>,
+}
+
+impl ShadowCache {
+    // Picks the cascades to re-render this frame, and records them as rendered with `cascades`.
+    fn update(
+        &mut self,
+        desc: &CsmDesc,
+        cascades: &[ShadowCascade],
+        casters_changed: bool,
+        frame_index: u32,
+    ) -> Vec<usize> {
+        let atlas_key = (desc.resolution.max(1), cascades.len(), desc.depth_bias);
+
+        // Nothing to reuse: render everything, regardless of the budget.
+        if self.atlas_key != Some(atlas_key) || self.cascades.len() != cascades.len() {
+            self.atlas_key = Some(atlas_key);
+            self.cascades = cascades
+                .iter()
+                .map(|&cascade| CachedCascade {
+                    cascade,
+                    rendered_frame: frame_index,
+                    dirty: false,
+                })
+                .collect();
+            return (0..cascades.len()).collect();
+        }
+
+        let mut due: Vec<usize> = Vec::with_capacity(cascades.len());
+        for (i, (cached, cascade)) in self.cascades.iter_mut().zip(cascades).enumerate() {
+            cached.dirty |=
+                casters_changed || cached.cascade.world_to_shadow != cascade.world_to_shadow;
+
+            let age = frame_index.wrapping_sub(cached.rendered_frame);
+            let is_due = match desc.update_policy {
+                ShadowUpdatePolicy::Static => cached.dirty,
+                ShadowUpdatePolicy::Dynamic => true,
+                ShadowUpdatePolicy::EveryNFrames(n) => age >= n.max(1),
+            };
+
+            if is_due {
+                due.push(i);
+            }
+        }
+
+        // Least recently rendered first; stable, so nearer cascades win ties.
+        due.sort_by_key(|&i| {
+            std::cmp::Reverse(frame_index.wrapping_sub(self.cascades[i].rendered_frame))
+        });
+        due.truncate(desc.max_cascade_updates.max(1));
+        due.sort_unstable();
+
+        for &i in &due {
+            self.cascades[i] = CachedCascade {
+                cascade: cascades[i],
+                rendered_frame: frame_index,
+                dirty: false,
+            };
+        }
+
+        due
+    }
+}
+
+// The state of the atlas after the frame being recorded; see `CsmRenderer::retire_frame`.
+struct RecordedShadows {
+    cache: ShadowCache,
+    casters: Vec<(Affine3A, MeshHandle)>,
+    mesh_count: usize,
+}
+
+pub struct CsmRenderer {
+    pub desc: CsmDesc,
+    render_pass: Arc<RenderPass>,
+    cache: ShadowCache,
+
+    // Shadow casters the cached maps were rendered with
+    casters: Vec<(Affine3A, MeshHandle)>,
+    mesh_count: usize,
+
+    recorded: Option<RecordedShadows>,
+    updated_cascade_count: usize,
+}
+
+impl CsmRenderer {
+    pub fn new(device: &Device) -> Self {
+        Self {
+            desc: Default::default(),
+            render_pass: create_render_pass(
+                device,
+                RenderPassDesc {
+                    color_attachments: &[],
+                    depth_attachment: Some(RenderPassAttachmentDesc::new(vk::Format::D32_SFLOAT)),
+                },
+            ),
+            cache: Default::default(),
+            casters: Vec::new(),
+            mesh_count: 0,
+            recorded: None,
+            updated_cascade_count: 0,
+        }
+    }
+
+    /// Number of cascades re-rendered in the last frame.
+    pub fn updated_cascade_count(&self) -> usize {
+        self.updated_cascade_count
+    }
+
+    /// Call once the frame last recorded is submitted. Until then, the cascades rendered in it
+    /// aren't considered up to date, as the frame may still be dropped.
+    pub fn retire_frame(&mut self) {
+        if let Some(recorded) = self.recorded.take() {
+            self.cache = recorded.cache;
+            self.casters = recorded.casters;
+            self.mesh_count = recorded.mesh_count;
+        }
+    }
+
+    // Whether any instance was added, removed or moved since the cached maps were rendered.
+    fn casters_changed(&self, mesh_data: &RasterMeshesData<'_>) -> bool {
+        self.mesh_count != mesh_data.meshes.len()
+            || !mesh_data
+                .instances
+                .iter()
+                .map(|inst| (inst.transformation, inst.mesh))
+                .eq(self.casters.iter().copied())
+    }
+
+    /// Re-renders the cascades which are due, and resolves them into a shadow mask for the gbuffer.
+    pub fn render(
+        &mut self,
+        rg: &mut rg::TemporalRenderGraph,
+        gbuffer_depth: &GbufferDepth,
+        mesh_data: RasterMeshesData<'_>,
+        camera_matrices: &CameraMatrices,
+        sun_direction: Vec3,
+        frame_index: u32,
+    ) -> rg::Handle<Image> {
+        let desc = self.desc;
+        let cascades = desc.cascades(camera_matrices, sun_direction);
+        let resolution = desc.resolution.max(1);
+        let bindless_descriptor_set = mesh_data.bindless_descriptor_set;
+
+        let casters_changed = self.casters_changed(&mesh_data);
+        let mut cache = self.cache.clone();
+        let updated_cascades = cache.update(&desc, &cascades, casters_changed, frame_index);
+        self.updated_cascade_count = updated_cascades.len();
+
+        // The cascades side by side
+        let mut shadow_map = rg
+            .get_or_create_temporal(
+                "csm.shadow_map",
+                ImageDesc::new_2d(
+                    vk::Format::D32_SFLOAT,
+                    [resolution * cascades.len() as u32, resolution],
+                )
+                .usage(
+                    vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
+                ),
+            )
+            .unwrap();
+
+        if !updated_cascades.is_empty() {
+            let mut pass = rg.add_pass("csm shadow maps");
+
+            let pipeline = pass.register_raster_pipeline(
+                &[PipelineShaderDesc::builder(ShaderPipelineStage::Vertex)
+                    .hlsl_source("/shaders/csm/shadow_map_vs.hlsl")
+                    .build()
+                    .unwrap()],
+                RasterPipelineDesc::builder()
+                    .render_pass(self.render_pass.clone())
+                    .face_cull(false)
+                    .depth_bias(desc.depth_bias)
+                    .push_constants_bytes(std::mem::size_of::<ShadowMapPushConstants>()),
+            );
+
+            let depth_ref = pass.raster(
+                &mut shadow_map,
+                AccessType::DepthAttachmentWriteStencilReadOnly,
+            );
+
+            let [width, height, _] = depth_ref.desc().extent;
+            let meshes = mesh_data.meshes.to_vec();
+            let instances = mesh_data.instances.to_vec();
+            let vertex_buffer = mesh_data.vertex_buffer.clone();
+            let render_pass = self.render_pass.clone();
+            let world_to_shadow: Vec<(usize, Mat4)> = updated_cascades
+                .iter()
+                .map(|&i| (i, cascades[i].world_to_shadow))
+                .collect();
+
+            pass.render(move |api| {
+                // Indexed by the position of the instance in `instances`, like in `raster_meshes`
+                let instance_transforms_offset =
+                    api.dynamic_constants()
+                        .push_from_iter(instances.iter().map(|inst| InstanceTransform {
+                            current: row_major_3x4(&inst.transformation),
+                            previous: row_major_3x4(&inst.prev_transformation),
+                        }));
+
+                api.begin_render_pass(
+                    &*render_pass,
+                    [width, height],
+                    &[],
+                    Some((
+                        depth_ref,
+                        &ImageViewDesc::builder()
+                            .aspect_mask(vk::ImageAspectFlags::DEPTH)
+                            .build()
+                            .unwrap(),
+                    )),
+                );
+
+                let pipeline = match api.bind_raster_pipeline(
+                    pipeline
+                        .into_binding()
+                        .storage_buffer(
+                            "instance_transforms_dyn",
+                            RenderPassBinding::DynamicConstantsStorageBuffer(
+                                instance_transforms_offset,
+                            ),
+                        )
+                        .bindless(bindless_descriptor_set),
+                ) {
+                    Ok(pipeline) => pipeline,
+                    Err(err) => {
+                        log::error!("csm: {}", err);
+                        api.end_render_pass();
+                        return;
+                    }
+                };
+
+                unsafe {
+                    let raw_device = &api.device().raw;
+                    let cb = api.cb;
+
+                    'cascades: for (cascade_idx, world_to_shadow) in world_to_shadow.iter() {
+                        let tile_offset = (*cascade_idx as u32 * resolution) as i32;
+                        let tile_rect = vk::Rect2D {
+                            offset: vk::Offset2D {
+                                x: tile_offset,
+                                y: 0,
+                            },
+                            extent: vk::Extent2D {
+                                width: resolution,
+                                height: resolution,
+                            },
+                        };
+
+                        // Only this cascade's tile; the others keep their cached maps.
+                        raw_device.cmd_clear_attachments(
+                            cb.raw,
+                            &[vk::ClearAttachment {
+                                aspect_mask: vk::ImageAspectFlags::DEPTH,
+                                color_attachment: 0,
+                                clear_value: vk::ClearValue {
+                                    depth_stencil: vk::ClearDepthStencilValue {
+                                        depth: 0.0,
+                                        stencil: 0,
+                                    },
+                                },
+                            }],
+                            &[vk::ClearRect {
+                                rect: tile_rect,
+                                base_array_layer: 0,
+                                layer_count: 1,
+                            }],
+                        );
+
+                        // Flipped like `set_default_view_and_scissor`, for `cs_to_uv` in sampling
+                        raw_device.cmd_set_viewport(
+                            cb.raw,
+                            0,
+                            &[vk::Viewport {
+                                x: tile_offset as f32,
+                                y: resolution as f32,
+                                width: resolution as f32,
+                                height: -(resolution as f32),
+                                min_depth: 0.0,
+                                max_depth: 1.0,
+                            }],
+                        );
+                        raw_device.cmd_set_scissor(cb.raw, 0, &[tile_rect]);
+
+                        // Transparent instances cast shadows too, like in ray tracing.
+                        for (draw_idx, instance) in instances.iter().enumerate() {
+                            let mesh = &meshes[instance.mesh.0];
+
+                            raw_device.cmd_bind_index_buffer(
+                                cb.raw,
+                                vertex_buffer.raw,
+                                mesh.index_buffer_offset,
+                                vk::IndexType::UINT32,
+                            );
+
+                            let push_constants = ShadowMapPushConstants {
+                                world_to_shadow: world_to_shadow.to_cols_array(),
+                                draw_index: draw_idx as u32,
+                                mesh_index: instance.mesh.0 as u32,
+                            };
+
+                            if let Err(err) = pipeline.push_constants(
+                                cb.raw,
+                                vk::ShaderStageFlags::ALL_GRAPHICS,
+                                0,
+                                bytemuck::bytes_of(&push_constants),
+                            ) {
+                                log::error!("csm: {}", err);
+                                break 'cascades;
+                            }
+
+                            raw_device.cmd_draw_indexed(cb.raw, mesh.index_count, 1, 0, 0, 0);
+                        }
+                    }
+                }
+
+                api.end_render_pass();
+            });
+        }
+
+        let mut constants = SampleCsmConstants {
+            world_to_shadow: [[0.0; 16]; MAX_CASCADES],
+            cascade_far_distance: [0.0; MAX_CASCADES],
+            cascade_radius: [0.0; MAX_CASCADES],
+            cascade_depth_range: [0.0; MAX_CASCADES],
+            cascade_count: cascades.len() as u32,
+            filter: match desc.filter {
+                ShadowFilter::Pcf => 0,
+                ShadowFilter::Pcss => 1,
+            },
+            shadow_map_resolution: resolution as f32,
+            pcf_radius: desc.pcf_radius.max(0.0),
+        };
+        for (i, (cascade, cached)) in cascades.iter().zip(&cache.cascades).enumerate() {
+            // Sampled with the matrix it was rendered with, but selected by the current splits
+            constants.world_to_shadow[i] = cached.cascade.world_to_shadow.to_cols_array();
+            constants.cascade_far_distance[i] = cascade.far_distance;
+            constants.cascade_radius[i] = cached.cascade.radius;
+            constants.cascade_depth_range[i] = cached.cascade.depth_range;
+        }
+
+        let mut output_img = rg.create(gbuffer_depth.depth.desc().format(vk::Format::R8_UNORM));
+
+        SimpleRenderPass::new_compute(rg.add_pass("sample csm"), "/shaders/csm/sample_csm.hlsl")
+            .read_aspect(&gbuffer_depth.depth, vk::ImageAspectFlags::DEPTH)
+            .read(&gbuffer_depth.geometric_normal)
+            .read_aspect(&shadow_map, vk::ImageAspectFlags::DEPTH)
+            .write(&mut output_img)
+            .bindless(bindless_descriptor_set)
+            .constants(constants)
+            .dispatch(output_img.desc().extent);
+
+        self.recorded = Some(RecordedShadows {
+            cache,
+            casters: mesh_data
+                .instances
+                .iter()
+                .map(|inst| (inst.transformation, inst.mesh))
+                .collect(),
+            mesh_count: mesh_data.meshes.len(),
+        });
+
+        output_img
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        camera::{CameraLens, LookThroughCamera},
+        frame_desc::{FrameTime, WorldFrameDesc},
+        renderers::aov::AovKind,
+        world_renderer::{AddMeshOptions, WorldRenderer},
+    };
+    use glam::Quat;
+    use kajiya_backend::{file::set_standard_vfs_mount_points, HeadlessRenderBackend};
+    use rg::renderer::Renderer;
+    use std::task::Poll;
+    use turbosloth::LazyCache;
+
+    const RESOLUTION: u32 = 512;
+    const EXTENT: [u32; 2] = [640, 360];
+
+    // Direct lighting and linear depth of a floor under a 2x2m slab at a height of 2m,
+    // with hard sun shadows from either cascaded shadow maps or ray tracing.
+    fn render_shadowed_floor(
+        backend: &HeadlessRenderBackend,
+        camera_matrices: CameraMatrices,
+        sun_direction: Vec3,
+        use_sun_shadow_maps: bool,
+    ) -> (Vec<[f32; 4]>, Vec<[f32; 4]>) {
+        let lazy_cache = LazyCache::create();
+        let mut world_renderer =
+            WorldRenderer::new(EXTENT, EXTENT, &backend.device, &lazy_cache).unwrap();
+
+        // A 20x2x20m box, with its top at 0
+        let floor = world_renderer
+            .add_baked_mesh("/baked/floor.mesh", AddMeshOptions::new())
+            .unwrap();
+        world_renderer.add_instance(floor, Affine3A::IDENTITY);
+        world_renderer.add_instance(
+            floor,
+            Affine3A::from_scale_rotation_translation(
+                Vec3::splat(0.1),
+                Quat::IDENTITY,
+                Vec3::new(0.0, 2.2, 0.0),
+            ),
+        );
+
+        // A point-like sun skips the shadow denoiser, and makes PCSS as hard as PCF.
+        world_renderer.sun_size_multiplier = 0.0;
+        world_renderer.use_sun_shadow_maps = use_sun_shadow_maps;
+        world_renderer.csm.desc = CsmDesc {
+            cascade_count: 3,
+            max_distance: 30.0,
+            resolution: RESOLUTION,
+            pcf_radius: 0.0,
+            ..Default::default()
+        };
+
+        let frame_desc = WorldFrameDesc {
+            camera_matrices,
+            render_extent: EXTENT,
+            sun_direction,
+            time: FrameTime::fixed_step(0, 1.0 / 60.0),
+        };
+
+        let mut renderer = Renderer::new(&backend.device).unwrap();
+        let tokens = [
+            world_renderer.request_aov_readback(AovKind::Direct),
+            world_renderer.request_aov_readback(AovKind::Depth),
+        ];
+        let mut images = [None, None];
+
+        for _ in 0..=backend.device.frames_in_flight() + 1 {
+            renderer
+                .prepare_frame(|rg| {
+                    world_renderer.prepare_render_graph(rg, &frame_desc);
+                })
+                .unwrap();
+            renderer
+                .draw_frame_headless(|dynamic_constants| {
+                    world_renderer.prepare_frame_constants(dynamic_constants, &frame_desc)
+                })
+                .unwrap();
+            world_renderer.retire_frame();
+
+            for (token, image) in tokens.iter().zip(&mut images) {
+                if image.is_none() {
+                    if let Poll::Ready(result) = world_renderer.poll_aov_readback(*token) {
+                        *image = Some(result.expect("AOV not available").to_rgba_f32().unwrap());
+                    }
+                }
+            }
+        }
+
+        let [direct, depth] = images;
+        (direct.expect("AOV not read back"), depth.unwrap())
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device with ray tracing, and `/baked/floor.mesh`"]
+    fn csm_agrees_with_ray_tracing() {
+        set_standard_vfs_mount_points(concat!(env!("CARGO_MANIFEST_DIR"), "/../../.."));
+        let backend = HeadlessRenderBackend::new(Default::default()).unwrap();
+
+        let sun_direction = Vec3::new(1.0, 2.0, 0.5).normalize();
+        let camera_matrices = (
+            Vec3::new(0.0, 3.0, 8.0),
+            Quat::from_rotation_x(-20.0f32.to_radians()),
+        )
+            .through(&CameraLens {
+                aspect_ratio: EXTENT[0] as f32 / EXTENT[1] as f32,
+                ..Default::default()
+            });
+
+        let (csm_direct, depth) =
+            render_shadowed_floor(&backend, camera_matrices, sun_direction, true);
+        let (rt_direct, _) = render_shadowed_floor(&backend, camera_matrices, sun_direction, false);
+
+        let luminance = |rgba: &[f32; 4]| Vec3::new(rgba[0], rgba[1], rgba[2]).dot(Vec3::ONE);
+        let max_luminance = rt_direct.iter().map(luminance).fold(0.0f32, f32::max);
+        assert!(max_luminance > 0.0);
+        let is_lit = |rgba: &[f32; 4]| luminance(rgba) > 0.1 * max_luminance;
+
+        // Surfaces within the range of the cascades; the sky has no depth.
+        let mut total = 0;
+        let mut agreeing = 0;
+        let mut shadowed = 0;
+        for ((csm, rt), depth) in csm_direct.iter().zip(&rt_direct).zip(&depth) {
+            if depth[0] <= 0.0 || depth[0] > 25.0 {
+                continue;
+            }
+
+            total += 1;
+            agreeing += (is_lit(csm) == is_lit(rt)) as u32;
+            shadowed += (!is_lit(rt)) as u32;
+        }
+
+        assert!(total > 0);
+        assert!(
+            shadowed as f32 > total as f32 * 0.01,
+            "{} / {}",
+            shadowed,
+            total
+        );
+        assert!(
+            agreeing as f32 > total as f32 * 0.97,
+            "{} / {}",
+            agreeing,
+            total
+        );
+    }
+
+    #[test]
+    fn cascades_split_the_view_up_to_max_distance() {
+        let sun_direction = Vec3::new(1.0, 2.0, 0.5).normalize();
+        let camera_matrices = (
+            Vec3::new(0.0, 3.0, 8.0),
+            Quat::from_rotation_x(-20.0f32.to_radians()),
+        )
+            .through(&CameraLens {
+                aspect_ratio: 16.0 / 9.0,
+                ..Default::default()
+            });
+
+        let desc = CsmDesc {
+            cascade_count: 3,
+            max_distance: 30.0,
+            resolution: RESOLUTION,
+            ..Default::default()
+        };
+
+        let cascades = desc.cascades(&camera_matrices, sun_direction);
+        assert_eq!(cascades.len(), 3);
+        assert!(cascades
+            .windows(2)
+            .all(|w| w[0].far_distance < w[1].far_distance));
+        assert!((cascades[2].far_distance - 30.0).abs() < 1e-3);
+    }
+
+    // Total cascade renders over `frame_count` frames of a static scene, with one instance
+    // moving on `moving_frame`, and the camera moving on `camera_frame`.
+    fn count_cascade_renders(
+        desc: &CsmDesc,
+        frame_count: u32,
+        moving_frame: u32,
+        camera_frame: u32,
+    ) -> usize {
+        let sun_direction = Vec3::new(1.0, 2.0, 0.5).normalize();
+        let lens = CameraLens {
+            aspect_ratio: 16.0 / 9.0,
+            ..Default::default()
+        };
+
+        let mut cache = ShadowCache::default();
+        (0..frame_count)
+            .map(|frame| {
+                let camera_z = if frame < camera_frame { 8.0 } else { 20.0 };
+                let camera_matrices =
+                    (Vec3::new(0.0, 3.0, camera_z), Quat::IDENTITY).through(&lens);
+                let cascades = desc.cascades(&camera_matrices, sun_direction);
+                cache
+                    .update(desc, &cascades, frame == moving_frame, frame)
+                    .len()
+            })
+            .sum()
+    }
+
+    #[test]
+    fn static_cascades_render_only_when_dirty() {
+        let dynamic = CsmDesc {
+            cascade_count: 4,
+            update_policy: ShadowUpdatePolicy::Dynamic,
+            ..Default::default()
+        };
+        let static_ = CsmDesc {
+            update_policy: ShadowUpdatePolicy::Static,
+            ..dynamic
+        };
+
+        // Nothing changes after the first frame
+        assert_eq!(
+            count_cascade_renders(&dynamic, 100, u32::MAX, u32::MAX),
+            400
+        );
+        assert_eq!(count_cascade_renders(&static_, 100, u32::MAX, u32::MAX), 4);
+
+        // A moving instance dirties every cascade, once
+        assert_eq!(count_cascade_renders(&static_, 100, 50, u32::MAX), 8);
+
+        // ... and under a budget of one cascade a frame, the updates are staggered.
+        let budgeted = CsmDesc {
+            max_cascade_updates: 1,
+            ..static_
+        };
+        assert_eq!(count_cascade_renders(&budgeted, 100, 50, u32::MAX), 8);
+        assert_eq!(count_cascade_renders(&budgeted, 52, 50, u32::MAX), 6);
+
+        // Moving the camera re-fits the cascades.
+        assert_eq!(count_cascade_renders(&static_, 100, u32::MAX, 50), 8);
+
+        // The budget doesn't hold back the first frame, which has nothing to reuse.
+        assert_eq!(count_cascade_renders(&budgeted, 1, u32::MAX, u32::MAX), 4);
+    }
+
+    #[test]
+    fn periodic_cascades_are_staggered_under_a_budget() {
+        let desc = CsmDesc {
+            cascade_count: 4,
+            update_policy: ShadowUpdatePolicy::EveryNFrames(4),
+            max_cascade_updates: 1,
+            ..Default::default()
+        };
+
+        let sun_direction = Vec3::new(1.0, 2.0, 0.5).normalize();
+        let camera_matrices = (Vec3::new(0.0, 3.0, 8.0), Quat::IDENTITY).through(&CameraLens {
+            aspect_ratio: 16.0 / 9.0,
+            ..Default::default()
+        });
+        let cascades = desc.cascades(&camera_matrices, sun_direction);
+
+        let mut cache = ShadowCache::default();
+        assert_eq!(cache.update(&desc, &cascades, false, 0), vec![0, 1, 2, 3]);
+
+        // All four become due at once, then settle into one per frame, each every 4 frames.
+        let updates: Vec<Vec<usize>> = (1..=12)
+            .map(|frame| cache.update(&desc, &cascades, false, frame))
+            .collect();
+        assert!(updates[..3].iter().all(Vec::is_empty));
+        assert_eq!(updates[3..7], [vec![0], vec![1], vec![2], vec![3]]);
+        assert_eq!(updates[7..11], [vec![0], vec![1], vec![2], vec![3]]);
+    }
+
+    #[test]
+    fn splits_blend_uniform_and_logarithmic() {
+        let uniform = cascade_split_distances(1.0, 100.0, 2, 0.0);
+        assert!((uniform[0] - 50.5).abs() < 1e-4);
+        assert!((uniform[1] - 100.0).abs() < 1e-4);
+
+        let log = cascade_split_distances(1.0, 100.0, 2, 1.0);
+        assert!((log[0] - 10.0).abs() < 1e-4);
+        assert!((log[1] - 100.0).abs() < 1e-4);
+    }
+}
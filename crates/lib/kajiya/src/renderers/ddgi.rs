@@ -0,0 +1,515 @@
+// World-space irradiance probe grid, following "Dynamic Diffuse Global Illumination
+// with Ray-Traced Irradiance Fields" (Majercik et al. 2019).
+//
+// Probes are placed on a regular grid which follows the camera. Every frame, each probe
+// traces a few rays, and blends their results into octahedral maps of irradiance, and of
+// distance to the nearest geometry. Surfaces interpolate irradiance from the eight probes
+// around them, and use the distances for a Chebyshev visibility test to avoid leaks.
+
+use glam::{IVec3, Mat3, Quat, Vec3};
+use kajiya_backend::{
+    ash::vk,
+    vulkan::{image::*, ray_tracing::RayTracingAcceleration, shader::ShaderSource},
+};
+use kajiya_rg::{self as rg, SimpleRenderPass};
+
+use super::{
+    procedural::ProceduralHitGroupRegistry, rt_hit_groups, GbufferDepth, PingPongTemporalResource,
+};
+
+// Must match GPU code. Search token: 5d2b3c0e-0f3a-4a4c-9e5b-2f4c8a1b7d61
+pub const IRRADIANCE_PROBE_RES: u32 = 6;
+pub const VISIBILITY_PROBE_RES: u32 = 14;
+
+/// Placement of the probes. The grid is re-centered around the camera every frame,
+/// in whole probe increments, so that probes stay put in world space.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DdgiGridDesc {
+    /// Number of probes along each axis. At least 2.
+    pub probe_counts: [u32; 3],
+
+    /// World-space distance between neighboring probes.
+    pub probe_spacing: f32,
+}
+
+impl Default for DdgiGridDesc {
+    fn default() -> Self {
+        Self {
+            probe_counts: [16, 8, 16],
+            probe_spacing: 1.0,
+        }
+    }
+}
+
+impl DdgiGridDesc {
+    fn sanitized(self) -> Self {
+        Self {
+            probe_counts: array_init::array_init(|i| self.probe_counts[i].max(2)),
+            probe_spacing: self.probe_spacing.max(1e-3),
+        }
+    }
+
+    pub fn probe_count(&self) -> u32 {
+        self.probe_counts.iter().product()
+    }
+
+    /// Absolute cell of the probe at the grid's minimum corner, when centered around `focus_position`.
+    fn origin_cell(&self, focus_position: Vec3) -> IVec3 {
+        let center = (focus_position / self.probe_spacing).round();
+        IVec3::new(center.x as i32, center.y as i32, center.z as i32)
+            - IVec3::from(self.probe_counts.map(|c| c as i32)) / 2
+    }
+
+    fn atlas_extent(&self, probe_res: u32) -> [u32; 2] {
+        let [x, y, z] = self.probe_counts;
+        [x * z * (probe_res + 2), y * (probe_res + 2)]
+    }
+}
+
+// Must match `DdgiConstants` in `ddgi/common.hlsl`
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DdgiConstants {
+    grid_origin: [f32; 4],
+    probe_counts: [u32; 4],
+    origin_cell: [i32; 4],
+    prev_origin_cell: [i32; 4],
+    ray_rotation: [[f32; 4]; 3],
+    hysteresis: f32,
+    max_visibility_distance: f32,
+    reset_history: u32,
+    pad0: u32,
+}
+
+/// Controls trading light leaking through thin walls against over-darkening, in lookups
+/// of the probe grid. Tune per scene; zeroing all three shows the raw, unbiased interpolation.
+/// RTDGI and CSGI, used without `WorldRenderer::use_ddgi`, don't apply these.
+///
+/// Must match `DdgiLeakReduction` in `ddgi/lookup.hlsl`. Defaults match `ddgi/common.hlsl`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DdgiLeakReduction {
+    /// Offset of the lookup position along the surface normal, in units of probe spacing.
+    /// Moves lookups off the surface, so that probes just behind it fail the visibility test.
+    /// Too high, and lookups land on the far side of thin walls, darkening or leaking again.
+    pub normal_bias: f32,
+
+    /// Offset of the lookup position towards the eye, in units of probe spacing.
+    /// Like `normal_bias`, but keeps lookups on the visible side of surfaces seen at grazing angles.
+    pub view_bias: f32,
+
+    /// Exponent applied to the Chebyshev visibility estimate. Higher values cut off
+    /// occluded probes more aggressively, reducing leaks, but darken contact areas and corners.
+    pub visibility_sharpness: f32,
+}
+
+impl Default for DdgiLeakReduction {
+    fn default() -> Self {
+        Self {
+            normal_bias: 0.2,
+            view_bias: 0.1,
+            visibility_sharpness: 3.0,
+        }
+    }
+}
+
+unsafe impl bytemuck::Zeroable for DdgiLeakReduction {}
+unsafe impl bytemuck::Pod for DdgiLeakReduction {}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DdgiSampleConstants {
+    output_tex_size: [f32; 4],
+    ddgi: DdgiConstants,
+}
+
+pub struct DdgiRenderer {
+    pub grid: DdgiGridDesc,
+
+    /// Rays traced by each probe per frame.
+    pub rays_per_probe: u32,
+
+    /// Fraction of the previous frame's probe data retained when blending in new rays.
+    /// Higher values are more stable, but react to lighting changes more slowly.
+    pub hysteresis: f32,
+
+    pub leak_reduction: DdgiLeakReduction,
+
+    irradiance_tex: PingPongTemporalResource,
+    visibility_tex: PingPongTemporalResource,
+    frame_idx: u32,
+    prev_origin_cell: IVec3,
+    prev_grid: Option<DdgiGridDesc>,
+    // The grid and origin cell of the frame being recorded; see `retire_frame`.
+    recorded_grid: Option<(DdgiGridDesc, IVec3)>,
+}
+
+impl Default for DdgiRenderer {
+    fn default() -> Self {
+        Self {
+            grid: Default::default(),
+            rays_per_probe: 64,
+            hysteresis: 0.97,
+            leak_reduction: Default::default(),
+            irradiance_tex: PingPongTemporalResource::new("ddgi.irradiance"),
+            visibility_tex: PingPongTemporalResource::new("ddgi.visibility"),
+            frame_idx: 0,
+            prev_origin_cell: IVec3::ZERO,
+            prev_grid: None,
+            recorded_grid: None,
+        }
+    }
+}
+
+pub struct DdgiVolume {
+    pub irradiance: rg::Handle<Image>,
+    pub visibility: rg::Handle<Image>,
+    pub leak_reduction: DdgiLeakReduction,
+    constants: DdgiConstants,
+}
+
+impl DdgiRenderer {
+    /// Call once the frame last recorded is submitted. Until then, the grid isn't considered
+    /// moved by it, as the frame may still be dropped.
+    pub fn retire_frame(&mut self) {
+        if let Some((grid, origin_cell)) = self.recorded_grid.take() {
+            self.prev_grid = Some(grid);
+            self.prev_origin_cell = origin_cell;
+        }
+    }
+
+    /// Starts the probes over in the next frame, e.g. once the space they're in moves.
+    pub fn reset(&mut self) {
+        self.prev_grid = None;
+    }
+
+    pub fn render(
+        &mut self,
+        eye_position: Vec3,
+        rg: &mut rg::TemporalRenderGraph,
+        sky_cube: &rg::Handle<Image>,
+        bindless_descriptor_set: vk::DescriptorSet,
+        tlas: &rg::Handle<RayTracingAcceleration>,
+        procedural_hit_groups: &ProceduralHitGroupRegistry,
+    ) -> DdgiVolume {
+        let grid = self.grid.sanitized();
+        let rays_per_probe = self.rays_per_probe.clamp(1, 1024);
+
+        // Changing the grid invalidates all the probes; they need to start over.
+        let reset_history = self.prev_grid != Some(grid);
+        let origin_cell = grid.origin_cell(eye_position);
+
+        let constants = {
+            let ray_rotation = Mat3::from_quat(random_rotation(self.frame_idx)).transpose();
+            let [x, y, z] = grid.probe_counts;
+
+            DdgiConstants {
+                grid_origin: [
+                    origin_cell.x as f32 * grid.probe_spacing,
+                    origin_cell.y as f32 * grid.probe_spacing,
+                    origin_cell.z as f32 * grid.probe_spacing,
+                    grid.probe_spacing,
+                ],
+                probe_counts: [x, y, z, rays_per_probe],
+                origin_cell: [origin_cell.x, origin_cell.y, origin_cell.z, 0],
+                prev_origin_cell: [
+                    self.prev_origin_cell.x,
+                    self.prev_origin_cell.y,
+                    self.prev_origin_cell.z,
+                    0,
+                ],
+                ray_rotation: [
+                    ray_rotation.x_axis.extend(0.0).into(),
+                    ray_rotation.y_axis.extend(0.0).into(),
+                    ray_rotation.z_axis.extend(0.0).into(),
+                ],
+                hysteresis: self.hysteresis.clamp(0.0, 1.0),
+                max_visibility_distance: grid.probe_spacing * 2.0,
+                reset_history: reset_history as u32,
+                pad0: 0,
+            }
+        };
+
+        self.frame_idx = self.frame_idx.wrapping_add(1);
+        self.recorded_grid = Some((grid, origin_cell));
+
+        let (mut irradiance_tex, irradiance_history_tex) =
+            self.irradiance_tex.get_output_and_history(
+                rg,
+                ImageDesc::new_2d(
+                    vk::Format::R16G16B16A16_SFLOAT,
+                    grid.atlas_extent(IRRADIANCE_PROBE_RES),
+                )
+                .usage(vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::STORAGE),
+            );
+
+        let (mut visibility_tex, visibility_history_tex) =
+            self.visibility_tex.get_output_and_history(
+                rg,
+                ImageDesc::new_2d(
+                    vk::Format::R16G16_SFLOAT,
+                    grid.atlas_extent(VISIBILITY_PROBE_RES),
+                )
+                .usage(vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::STORAGE),
+            );
+
+        let mut ray_tex = rg.create(ImageDesc::new_2d(
+            vk::Format::R16G16B16A16_SFLOAT,
+            [rays_per_probe, grid.probe_count()],
+        ));
+
+        SimpleRenderPass::new_rt(
+            rg.add_pass("ddgi trace"),
+            ShaderSource::hlsl("/shaders/ddgi/trace_probes.rgen.hlsl"),
+            [
+                ShaderSource::hlsl("/shaders/rt/gbuffer.rmiss.hlsl"),
+                ShaderSource::hlsl("/shaders/rt/shadow.rmiss.hlsl"),
+            ],
+            rt_hit_groups(procedural_hit_groups),
+        )
+        .hit_records(procedural_hit_groups.hit_records().to_vec())
+        .read(&irradiance_history_tex)
+        .read(&visibility_history_tex)
+        .read(sky_cube)
+        .write(&mut ray_tex)
+        .constants(constants)
+        .bindless(bindless_descriptor_set)
+        .trace_rays(tlas, ray_tex.desc().extent);
+
+        SimpleRenderPass::new_compute(
+            rg.add_pass("ddgi update irradiance"),
+            "/shaders/ddgi/update_irradiance.hlsl",
+        )
+        .read(&ray_tex)
+        .read(&irradiance_history_tex)
+        .write(&mut irradiance_tex)
+        .constants(constants)
+        .dispatch(irradiance_tex.desc().extent);
+
+        SimpleRenderPass::new_compute(
+            rg.add_pass("ddgi update visibility"),
+            "/shaders/ddgi/update_visibility.hlsl",
+        )
+        .read(&ray_tex)
+        .read(&visibility_history_tex)
+        .write(&mut visibility_tex)
+        .constants(constants)
+        .dispatch(visibility_tex.desc().extent);
+
+        DdgiVolume {
+            irradiance: irradiance_tex,
+            visibility: visibility_tex,
+            leak_reduction: self.leak_reduction,
+            constants,
+        }
+    }
+}
+
+impl DdgiVolume {
+    /// Looks up the probes for every pixel of the G-buffer. The result is a drop-in replacement
+    /// for the output of `RtdgiRenderer`.
+    pub fn sample_irradiance(
+        &self,
+        rg: &mut rg::RenderGraph,
+        gbuffer_depth: &GbufferDepth,
+    ) -> rg::Handle<Image> {
+        let mut output_tex = rg.create(
+            gbuffer_depth
+                .gbuffer
+                .desc()
+                .usage(vk::ImageUsageFlags::empty())
+                .format(vk::Format::R16G16B16A16_SFLOAT),
+        );
+
+        SimpleRenderPass::new_compute(
+            rg.add_pass("ddgi sample"),
+            "/shaders/ddgi/sample_irradiance.hlsl",
+        )
+        .read(&gbuffer_depth.gbuffer)
+        .read_aspect(&gbuffer_depth.depth, vk::ImageAspectFlags::DEPTH)
+        .read(&self.irradiance)
+        .read(&self.visibility)
+        .write(&mut output_tex)
+        .constants(DdgiSampleConstants {
+            output_tex_size: output_tex.desc().extent_inv_extent_2d(),
+            ddgi: self.constants,
+        })
+        .push_constants(&self.leak_reduction)
+        .dispatch(output_tex.desc().extent);
+
+        output_tex
+    }
+}
+
+// Must match `SPHERE_*` in `ddgi/raster_probes_vs.hlsl`
+#[cfg(feature = "gi-debug")]
+const DEBUG_SPHERE_SEGMENTS: u32 = 12;
+#[cfg(feature = "gi-debug")]
+const DEBUG_SPHERE_RINGS: u32 = 8;
+
+#[cfg(feature = "gi-debug")]
+impl DdgiVolume {
+    /// Draws every probe as a small sphere into the G-buffer, colored by the irradiance
+    /// it stores in each direction. Shows leaks, as well as which probes got updated.
+    pub fn debug_raster_probes(
+        &self,
+        rg: &mut rg::RenderGraph,
+        render_pass: std::sync::Arc<kajiya_backend::vulkan::shader::RenderPass>,
+        gbuffer_depth: &mut GbufferDepth,
+        velocity_img: &mut rg::Handle<Image>,
+    ) {
+        use kajiya_backend::{
+            vk_sync::AccessType,
+            vulkan::shader::{PipelineShaderDesc, RasterPipelineDesc, ShaderPipelineStage},
+        };
+        use rg::{BindRgRef, IntoRenderPassPipelineBinding};
+
+        let mut pass = rg.add_pass("raster ddgi probes");
+
+        let pipeline = pass.register_raster_pipeline(
+            &[
+                PipelineShaderDesc::builder(ShaderPipelineStage::Vertex)
+                    .hlsl_source("/shaders/ddgi/raster_probes_vs.hlsl")
+                    .build()
+                    .unwrap(),
+                PipelineShaderDesc::builder(ShaderPipelineStage::Pixel)
+                    .hlsl_source("/shaders/ddgi/raster_probes_ps.hlsl")
+                    .build()
+                    .unwrap(),
+            ],
+            RasterPipelineDesc::builder()
+                .render_pass(render_pass.clone())
+                .face_cull(false),
+        );
+
+        let depth_ref = pass.raster(
+            &mut gbuffer_depth.depth,
+            AccessType::DepthAttachmentWriteStencilReadOnly,
+        );
+
+        let geometric_normal_ref = pass.raster(
+            &mut gbuffer_depth.geometric_normal,
+            AccessType::ColorAttachmentWrite,
+        );
+        let gbuffer_ref = pass.raster(&mut gbuffer_depth.gbuffer, AccessType::ColorAttachmentWrite);
+        let velocity_ref = pass.raster(velocity_img, AccessType::ColorAttachmentWrite);
+
+        let irradiance_ref = pass.read(
+            &self.irradiance,
+            AccessType::AnyShaderReadSampledImageOrUniformTexelBuffer,
+        );
+
+        let constants = self.constants;
+        let [x, y, z, _] = constants.probe_counts;
+
+        pass.render(move |api| {
+            let [width, height, _] = gbuffer_ref.desc().extent;
+
+            api.begin_render_pass(
+                &*render_pass,
+                [width, height],
+                &[
+                    (geometric_normal_ref, &ImageViewDesc::default()),
+                    (gbuffer_ref, &ImageViewDesc::default()),
+                    (velocity_ref, &ImageViewDesc::default()),
+                ],
+                Some((
+                    depth_ref,
+                    &ImageViewDesc::builder()
+                        .aspect_mask(vk::ImageAspectFlags::DEPTH)
+                        .build()
+                        .unwrap(),
+                )),
+            );
+
+            api.set_default_view_and_scissor([width, height]);
+
+            let constants_offset = api.dynamic_constants().push(&constants);
+            if let Err(err) = api.bind_raster_pipeline(pipeline.into_binding().descriptor_set(
+                0,
+                &[
+                    irradiance_ref.bind(),
+                    rg::RenderPassBinding::DynamicConstants(constants_offset),
+                ],
+            )) {
+                log::error!("ddgi debug: {}", err);
+                api.end_render_pass();
+                return;
+            }
+
+            unsafe {
+                api.device().raw.cmd_draw(
+                    api.cb.raw,
+                    // 6 verts (two triangles) per quad
+                    6 * DEBUG_SPHERE_SEGMENTS * DEBUG_SPHERE_RINGS,
+                    x * y * z,
+                    0,
+                    0,
+                );
+            }
+
+            api.end_render_pass();
+        });
+    }
+}
+
+// Uniformly distributed random rotation. Shoemake, "Uniform random rotations" (1992)
+fn random_rotation(seed: u32) -> Quat {
+    let [u1, u2, u3]: [f32; 3] = array_init::array_init(|i| {
+        hash_u32(seed.wrapping_mul(3).wrapping_add(i as u32)) as f32 / u32::MAX as f32
+    });
+    let tau = std::f32::consts::TAU;
+
+    Quat::from_xyzw(
+        (1.0 - u1).sqrt() * (tau * u2).sin(),
+        (1.0 - u1).sqrt() * (tau * u2).cos(),
+        u1.sqrt() * (tau * u3).sin(),
+        u1.sqrt() * (tau * u3).cos(),
+    )
+    .normalize()
+}
+
+fn hash_u32(mut x: u32) -> u32 {
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x7feb352d);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x846ca68b);
+    x ^= x >> 16;
+    x
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grid_follows_the_eye_in_whole_probes() {
+        let grid = DdgiGridDesc {
+            probe_counts: [4, 2, 4],
+            probe_spacing: 0.5,
+        };
+
+        assert_eq!(grid.origin_cell(Vec3::ZERO), IVec3::new(-2, -1, -2));
+        assert_eq!(
+            grid.origin_cell(Vec3::new(0.2, 0.0, -0.2)),
+            IVec3::new(-2, -1, -2)
+        );
+        assert_eq!(
+            grid.origin_cell(Vec3::new(1.0, 0.0, 0.0)),
+            IVec3::new(0, -1, -2)
+        );
+        assert_eq!(grid.atlas_extent(IRRADIANCE_PROBE_RES), [4 * 4 * 8, 2 * 8]);
+    }
+
+    #[test]
+    fn sanitized_grid_is_never_degenerate() {
+        let grid = DdgiGridDesc {
+            probe_counts: [0, 1, 3],
+            probe_spacing: 0.0,
+        }
+        .sanitized();
+
+        assert_eq!(grid.probe_counts, [2, 2, 3]);
+        assert!(grid.probe_spacing > 0.0);
+    }
+}
@@ -0,0 +1,540 @@
+// Immediate-mode debug drawing.
+//
+// The application pushes lines and wireframe shapes into `DebugDraw` every frame. They are uploaded
+// through the per-frame dynamic constants, and rasterized in one pass into an overlay at the internal
+// resolution, where they can be depth-tested against the gbuffer. The overlay is then blended over
+// the final image, and the primitives are cleared.
+
+use std::sync::Arc;
+
+use glam::{Affine3A, Mat4, Quat, Vec3, Vec4};
+use kajiya_backend::{
+    ash::vk,
+    dynamic_constants::MAX_DYNAMIC_CONSTANTS_STORAGE_BUFFER_BYTES,
+    vk_sync::AccessType,
+    vulkan::{image::*, shader::*},
+    Device,
+};
+use kajiya_rg::{self as rg};
+use rg::{IntoRenderPassPipelineBinding, RenderPassBinding};
+
+use super::{composite_overlay, GbufferDepth};
+
+/// Vertices beyond this many per frame are dropped.
+pub const MAX_DEBUG_DRAW_VERTICES: usize =
+    MAX_DYNAMIC_CONSTANTS_STORAGE_BUFFER_BYTES / std::mem::size_of::<DebugVertex>();
+
+// Segments of circles and spheres
+const CIRCLE_SEGMENTS: usize = 32;
+
+// Must match `DebugVertex` in `debug_draw/lines_vs.hlsl`
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct DebugVertex {
+    position: [f32; 4],
+    color: [f32; 4],
+}
+
+/// Collects debug primitives for the next frame. Colors are linear RGBA,
+/// and primitives without a depth test are drawn on top of everything.
+#[derive(Default)]
+pub struct DebugDraw {
+    // Line lists
+    depth_tested: Vec<DebugVertex>,
+    overlaid: Vec<DebugVertex>,
+}
+
+impl DebugDraw {
+    pub fn is_empty(&self) -> bool {
+        self.depth_tested.is_empty() && self.overlaid.is_empty()
+    }
+
+    /// Drops the primitives pushed so far.
+    pub fn clear(&mut self) {
+        self.depth_tested.clear();
+        self.overlaid.clear();
+    }
+
+    pub fn line(&mut self, from: Vec3, to: Vec3, color: Vec4, depth_test: bool) {
+        let color = color.max(Vec4::ZERO).into();
+        let vertices = if depth_test {
+            &mut self.depth_tested
+        } else {
+            &mut self.overlaid
+        };
+
+        vertices.push(DebugVertex {
+            position: from.extend(1.0).into(),
+            color,
+        });
+        vertices.push(DebugVertex {
+            position: to.extend(1.0).into(),
+            color,
+        });
+    }
+
+    /// The edges of a box mapped from the unit cube centered at the origin by `transform`.
+    pub fn oriented_box(&mut self, transform: Affine3A, color: Vec4, depth_test: bool) {
+        let corners: Vec<Vec3> = (0..8)
+            .map(|i| {
+                transform.transform_point3(Vec3::new(
+                    (i & 1) as f32 - 0.5,
+                    ((i >> 1) & 1) as f32 - 0.5,
+                    ((i >> 2) & 1) as f32 - 0.5,
+                ))
+            })
+            .collect();
+
+        self.cube_edges(&corners, color, depth_test);
+    }
+
+    /// The edges of an axis-aligned box.
+    pub fn aabb(&mut self, min: Vec3, max: Vec3, color: Vec4, depth_test: bool) {
+        self.oriented_box(
+            Affine3A::from_scale_rotation_translation(max - min, Quat::IDENTITY, (min + max) * 0.5),
+            color,
+            depth_test,
+        );
+    }
+
+    /// A circle of `radius` around `center`, in the plane perpendicular to `normal`.
+    pub fn circle(
+        &mut self,
+        center: Vec3,
+        normal: Vec3,
+        radius: f32,
+        color: Vec4,
+        depth_test: bool,
+    ) {
+        let normal = normal.normalize();
+        let tangent = if normal.x.abs() < 0.9 {
+            Vec3::X
+        } else {
+            Vec3::Y
+        }
+        .cross(normal)
+        .normalize();
+        let bitangent = normal.cross(tangent);
+
+        let point_at = |i: usize| {
+            let angle = i as f32 / CIRCLE_SEGMENTS as f32 * std::f32::consts::TAU;
+            center + (tangent * angle.cos() + bitangent * angle.sin()) * radius
+        };
+
+        for i in 0..CIRCLE_SEGMENTS {
+            self.line(point_at(i), point_at(i + 1), color, depth_test);
+        }
+    }
+
+    /// Three great circles of a sphere, around each of the world axes.
+    pub fn sphere(&mut self, center: Vec3, radius: f32, color: Vec4, depth_test: bool) {
+        for axis in [Vec3::X, Vec3::Y, Vec3::Z] {
+            self.circle(center, axis, radius, color, depth_test);
+        }
+    }
+
+    /// The edges of the volume seen through a camera, such as `ViewConstants::clip_to_view`
+    /// combined with `view_to_world`. Depth is reversed, so the near plane is at z = 1.
+    pub fn frustum(&mut self, clip_to_world: Mat4, color: Vec4, depth_test: bool) {
+        let corners: Vec<Vec3> = (0..8)
+            .map(|i| {
+                let clip = clip_to_world
+                    * Vec4::new(
+                        ((i & 1) as f32) * 2.0 - 1.0,
+                        (((i >> 1) & 1) as f32) * 2.0 - 1.0,
+                        // Slightly off the far plane, which is at infinity
+                        if (i >> 2) & 1 == 0 { 1.0 } else { 1e-4 },
+                        1.0,
+                    );
+                clip.truncate() / clip.w
+            })
+            .collect();
+
+        self.cube_edges(&corners, color, depth_test);
+    }
+
+    // `corners` are indexed by three bits, one per axis; edges connect those differing in one.
+    fn cube_edges(&mut self, corners: &[Vec3], color: Vec4, depth_test: bool) {
+        for i in 0..8 {
+            for axis_bit in [1, 2, 4] {
+                if i & axis_bit == 0 {
+                    self.line(corners[i], corners[i | axis_bit], color, depth_test);
+                }
+            }
+        }
+    }
+}
+
+pub struct DebugDrawRenderer {
+    render_pass: Arc<RenderPass>,
+}
+
+impl DebugDrawRenderer {
+    pub fn new(device: &Device) -> Self {
+        Self {
+            render_pass: create_render_pass(
+                device,
+                RenderPassDesc {
+                    // Premultiplied color of the lines
+                    color_attachments: &[RenderPassAttachmentDesc::new(vk::Format::R8G8B8A8_UNORM)],
+                    depth_attachment: Some(RenderPassAttachmentDesc::new(vk::Format::D32_SFLOAT)),
+                },
+            ),
+        }
+    }
+
+    /// Draws the primitives of `debug_draw` over the final `output`, and clears them.
+    /// They're in world space, and drawn relative to `render_origin`; see `render_origin.rs`.
+    pub fn render(
+        &self,
+        rg: &mut rg::RenderGraph,
+        gbuffer_depth: &mut GbufferDepth,
+        debug_draw: &mut DebugDraw,
+        render_origin: Vec3,
+        output: &mut rg::Handle<Image>,
+    ) {
+        if debug_draw.is_empty() {
+            return;
+        }
+
+        // Depth-tested lines first, and the overlaid ones in the same buffer after them.
+        let mut vertices = std::mem::take(&mut debug_draw.depth_tested);
+        let depth_tested_count = vertices.len();
+        vertices.append(&mut debug_draw.overlaid);
+
+        if vertices.len() > MAX_DEBUG_DRAW_VERTICES {
+            log::warn!(
+                "Too many debug draw vertices ({}); only drawing the first {}",
+                vertices.len(),
+                MAX_DEBUG_DRAW_VERTICES
+            );
+            vertices.truncate(MAX_DEBUG_DRAW_VERTICES);
+        }
+
+        for vertex in &mut vertices {
+            vertex.position = (Vec4::from(vertex.position) - render_origin.extend(0.0)).into();
+        }
+
+        let depth_tested_count = depth_tested_count.min(vertices.len()) as u32;
+        let overlaid_count = vertices.len() as u32 - depth_tested_count;
+
+        let mut overlay_tex = rg.create(ImageDesc::new_2d(
+            vk::Format::R8G8B8A8_UNORM,
+            gbuffer_depth.depth.desc().extent_2d(),
+        ));
+        rg::imageops::clear_color(rg, &mut overlay_tex, [0.0; 4]);
+
+        {
+            let mut pass = rg.add_pass("debug draw");
+
+            let [depth_tested_pipeline, overlaid_pipeline] = [true, false].map(|depth_test| {
+                pass.register_raster_pipeline(
+                    &[
+                        PipelineShaderDesc::builder(ShaderPipelineStage::Vertex)
+                            .hlsl_source("/shaders/debug_draw/lines_vs.hlsl")
+                            .build()
+                            .unwrap(),
+                        PipelineShaderDesc::builder(ShaderPipelineStage::Pixel)
+                            .hlsl_source("/shaders/debug_draw/lines_ps.hlsl")
+                            .build()
+                            .unwrap(),
+                    ],
+                    RasterPipelineDesc::builder()
+                        .render_pass(self.render_pass.clone())
+                        .line_list(true)
+                        .depth_test(depth_test)
+                        .depth_write(false)
+                        .blend_mode(RasterBlendMode::PremultipliedAlpha),
+                )
+            });
+
+            // Depth testing only; the pipelines don't write it.
+            let depth_ref = pass.raster(
+                &mut gbuffer_depth.depth,
+                AccessType::DepthAttachmentWriteStencilReadOnly,
+            );
+            let overlay_ref = pass.raster(&mut overlay_tex, AccessType::ColorAttachmentReadWrite);
+
+            let [width, height, _] = overlay_ref.desc().extent;
+            let render_pass = self.render_pass.clone();
+
+            pass.render(move |api| {
+                let vertices_offset = api.dynamic_constants().push_from_iter(vertices.into_iter());
+
+                api.begin_render_pass(
+                    &*render_pass,
+                    [width, height],
+                    &[(overlay_ref, &ImageViewDesc::default())],
+                    Some((
+                        depth_ref,
+                        &ImageViewDesc::builder()
+                            .aspect_mask(vk::ImageAspectFlags::DEPTH)
+                            .build()
+                            .unwrap(),
+                    )),
+                );
+
+                api.set_default_view_and_scissor([width, height]);
+
+                for (pipeline, first_vertex, vertex_count) in [
+                    (depth_tested_pipeline, 0, depth_tested_count),
+                    (overlaid_pipeline, depth_tested_count, overlaid_count),
+                ] {
+                    if vertex_count == 0 {
+                        continue;
+                    }
+
+                    if let Err(err) =
+                        api.bind_raster_pipeline(pipeline.into_binding().storage_buffer(
+                            "vertices_dyn",
+                            RenderPassBinding::DynamicConstantsStorageBuffer(vertices_offset),
+                        ))
+                    {
+                        log::error!("debug draw: {}", err);
+                        continue;
+                    }
+
+                    unsafe {
+                        api.device()
+                            .raw
+                            .cmd_draw(api.cb.raw, vertex_count, 1, first_vertex, 0);
+                    }
+                }
+
+                api.end_render_pass();
+            });
+        }
+
+        composite_overlay(rg, "debug draw composite", &overlay_tex, output);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::{CameraLens, LookThroughCamera};
+    use glam::Vec2;
+    use kajiya_backend::{file::set_standard_vfs_mount_points, HeadlessRenderBackend};
+    use rg::{
+        imageops::ClearValue,
+        readback::AsyncReadback,
+        renderer::{FrameConstantsLayout, Renderer},
+    };
+    use rust_shaders_shared::{frame_constants::FrameConstants, view_constants::ViewConstants};
+
+    fn lines(vertices: &[DebugVertex]) -> Vec<(Vec3, Vec3)> {
+        vertices
+            .chunks(2)
+            .map(|line| {
+                (
+                    Vec4::from(line[0].position).truncate(),
+                    Vec4::from(line[1].position).truncate(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn box_has_twelve_axis_aligned_edges() {
+        let mut draw = DebugDraw::default();
+        draw.aabb(
+            Vec3::new(-1.0, 0.0, 2.0),
+            Vec3::new(1.0, 3.0, 6.0),
+            Vec4::ONE,
+            true,
+        );
+
+        assert!(draw.overlaid.is_empty());
+        let edges = lines(&draw.depth_tested);
+        assert_eq!(edges.len(), 12);
+
+        // Four edges along each axis, each spanning the extent of the box along it
+        for (axis, extent) in [(Vec3::X, 2.0), (Vec3::Y, 3.0), (Vec3::Z, 4.0)] {
+            let along_axis = edges
+                .iter()
+                .filter(|(from, to)| {
+                    let delta = *to - *from;
+                    (delta.dot(axis) - extent).abs() < 1e-5
+                        && (delta - axis * extent).length() < 1e-5
+                })
+                .count();
+            assert_eq!(along_axis, 4);
+        }
+
+        // Every corner has three edges.
+        for corner in [Vec3::new(-1.0, 0.0, 2.0), Vec3::new(1.0, 3.0, 6.0)] {
+            let touching = edges
+                .iter()
+                .filter(|(from, to)| {
+                    (*from - corner).length() < 1e-5 || (*to - corner).length() < 1e-5
+                })
+                .count();
+            assert_eq!(touching, 3);
+        }
+
+        draw.sphere(Vec3::ZERO, 1.0, Vec4::ONE, false);
+        assert_eq!(lines(&draw.overlaid).len(), 3 * CIRCLE_SEGMENTS);
+        assert!(lines(&draw.overlaid)
+            .iter()
+            .all(|(from, _)| (from.length() - 1.0).abs() < 1e-5));
+
+        draw.clear();
+        assert!(draw.is_empty());
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn boxes_are_hidden_by_depth_unless_overlaid() {
+        set_standard_vfs_mount_points(concat!(env!("CARGO_MANIFEST_DIR"), "/../../.."));
+
+        let backend = HeadlessRenderBackend::new(Default::default()).unwrap();
+        let device = backend.device.clone();
+        let mut renderer = Renderer::new(&device).unwrap();
+        let mut readback = AsyncReadback::default();
+        let debug_draw_renderer = DebugDrawRenderer::new(&device);
+
+        let extent = [128, 64];
+        let camera = (Vec3::ZERO, Quat::IDENTITY).through(&CameraLens {
+            aspect_ratio: 2.0,
+            ..Default::default()
+        });
+        let frame_constants = FrameConstants {
+            view_constants: ViewConstants::builder(camera, camera, extent).build(),
+            sun_direction: Vec4::Y,
+            frame_index: 0,
+            delta_time_seconds: 1.0 / 60.0,
+            sun_angular_radius_cos: 1.0,
+            triangle_light_count: 0,
+            sun_color_multiplier: Vec4::ONE,
+            sky_ambient: Vec4::ZERO,
+            world_gi_scale: 1.0,
+            ray_origin_offset_method: 0,
+            ray_origin_offset_scale: 0.0,
+            pad0: 0,
+            gi_cascades: Default::default(),
+        };
+
+        // The camera looks down -Z, at an opaque wall 5 units away.
+        let project = |p: Vec3| -> Vec3 {
+            let clip = camera.view_to_clip * camera.world_to_view * p.extend(1.0);
+            clip.truncate() / clip.w
+        };
+        let wall_depth = project(Vec3::new(0.0, 0.0, -5.0)).z;
+
+        // In front of the wall, behind it, and behind it but drawn on top
+        let red = Vec4::new(1.0, 0.0, 0.0, 1.0);
+        let green = Vec4::new(0.0, 1.0, 0.0, 1.0);
+        let blue = Vec4::new(0.0, 0.0, 1.0, 1.0);
+        let boxes = [
+            (Vec3::new(-1.5, 0.0, -3.0), 0.8, red, true),
+            (Vec3::new(0.0, 0.0, -7.5), 1.2, green, true),
+            (Vec3::new(4.0, 0.0, -7.5), 1.2, blue, false),
+        ];
+
+        let mut debug_draw = DebugDraw::default();
+        for &(center, size, color, depth_test) in &boxes {
+            debug_draw.aabb(
+                center - Vec3::splat(size * 0.5),
+                center + Vec3::splat(size * 0.5),
+                color,
+                depth_test,
+            );
+        }
+
+        readback.begin_frame();
+        let mut token = None;
+        renderer
+            .prepare_frame(|rg| {
+                let normal = rg.create(ImageDesc::new_2d(
+                    vk::Format::A2R10G10B10_UNORM_PACK32,
+                    extent,
+                ));
+                let gbuffer = rg.create(ImageDesc::new_2d(vk::Format::R32G32B32A32_SFLOAT, extent));
+                let mut depth = rg.create(ImageDesc::new_2d(vk::Format::D32_SFLOAT, extent));
+                rg::imageops::clear_image(
+                    rg,
+                    &mut depth,
+                    ClearValue::DepthStencil {
+                        depth: wall_depth,
+                        stencil: 0,
+                    },
+                );
+                let mut gbuffer_depth = GbufferDepth::new(normal, gbuffer, depth);
+
+                let mut output =
+                    rg.create(ImageDesc::new_2d(vk::Format::R32G32B32A32_SFLOAT, extent));
+                rg::imageops::clear_color(rg, &mut output, [0.0; 4]);
+                debug_draw_renderer.render(
+                    rg,
+                    &mut gbuffer_depth,
+                    &mut debug_draw,
+                    Vec3::ZERO,
+                    &mut output,
+                );
+
+                token = Some(readback.copy_image(rg, &output, 16));
+            })
+            .unwrap();
+        renderer
+            .draw_frame_headless(|dynamic_constants| FrameConstantsLayout {
+                globals_offset: dynamic_constants.push(&frame_constants),
+                instance_dynamic_parameters_offset: 0,
+                triangle_lights_offset: 0,
+            })
+            .unwrap();
+        readback.retire_frame(device.frame_counter());
+
+        let bytes = readback.wait(&device, token.unwrap()).unwrap();
+        let texels: Vec<Vec3> = bytemuck::cast_slice::<u8, [f32; 4]>(&bytes)
+            .iter()
+            .map(|&[r, g, b, _]| Vec3::new(r, g, b))
+            .collect();
+        assert!(debug_draw.is_empty());
+
+        // The viewport is flipped, so clip-space Y points up.
+        let pixel_at = |p: Vec3| {
+            let ndc = project(p);
+            let uv = Vec2::new(ndc.x * 0.5 + 0.5, 0.5 - ndc.y * 0.5);
+            let px = uv * Vec2::new(extent[0] as f32, extent[1] as f32);
+            [px.x as i32, px.y as i32]
+        };
+        let texel = |[x, y]: [i32; 2]| texels[(y * extent[0] as i32 + x) as usize];
+
+        // Lines are a pixel wide, and may land next to the projected point.
+        let drawn_near = |p: Vec3, color: Vec4| {
+            let [x, y] = pixel_at(p);
+            (-1..=1).any(|dy| {
+                (-1..=1).any(|dx| texel([x + dx, y + dy]).abs_diff_eq(color.truncate(), 0.01))
+            })
+        };
+
+        for &(center, size, color, depth_test) in &boxes {
+            let visible = !depth_test || center.z > -5.0;
+
+            // The middle of every edge; those of the back face are covered by the front one
+            // where they overlap, but have the same color.
+            for axis in 0..3 {
+                for corner in 0..4 {
+                    let mut offset = Vec3::ZERO;
+                    offset[(axis + 1) % 3] = if corner & 1 == 0 { -0.5 } else { 0.5 };
+                    offset[(axis + 2) % 3] = if corner & 2 == 0 { -0.5 } else { 0.5 };
+
+                    let midpoint = center + offset * size;
+                    assert_eq!(
+                        drawn_near(midpoint, color),
+                        visible,
+                        "edge at {} of the {} box",
+                        midpoint,
+                        color
+                    );
+                }
+            }
+
+            // Only the edges are drawn.
+            assert_eq!(texel(pixel_at(center)), Vec3::ZERO);
+        }
+
+        // Nothing of the hidden box shows anywhere.
+        assert!(texels.iter().all(|texel| texel.y == 0.0));
+    }
+}
@@ -0,0 +1,332 @@
+// Deferred decals.
+//
+// After the gbuffer is rasterized, decals are projected onto it: each pixel's position is reconstructed
+// from depth, and tested against every decal's box. Where it's inside, the decal's textures modify the
+// albedo, normal and roughness stored in the gbuffer, before any lighting is evaluated.
+
+use glam::{Affine3A, Vec2, Vec3};
+use kajiya_backend::{ash::vk, vulkan::image::*};
+use kajiya_rg::{self as rg, SimpleRenderPass};
+
+use crate::{render_origin::rebase_transform, world_renderer::BindlessImageHandle};
+
+use super::{raster_meshes::row_major_3x4, GbufferDepth};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DecalBlendMode {
+    /// Replaces the surface's albedo, in proportion to the decal's alpha. Posters, paint.
+    AlphaBlend,
+
+    /// Multiplies the surface's albedo, leaving bright areas of the decal transparent. Dirt, burn marks.
+    Multiply,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Decal {
+    /// Maps a unit cube centered at the origin onto the decal's box in the world. Textures are
+    /// laid out on the local XY plane (`u` along +X, `v` along -Y), and projected along -Z.
+    pub transform: Affine3A,
+
+    /// Multiplied by `tint`; its alpha masks the decal.
+    pub albedo_map: Option<BindlessImageHandle>,
+
+    /// Tangent-space normal map, perturbing the surface normal.
+    pub normal_map: Option<BindlessImageHandle>,
+
+    /// Replaces the surface's roughness where the decal covers it. Unchanged if `None`.
+    pub roughness: Option<f32>,
+
+    pub tint: Vec3,
+    pub opacity: f32,
+    pub blend_mode: DecalBlendMode,
+}
+
+impl Decal {
+    pub fn new(transform: Affine3A) -> Self {
+        Self {
+            transform,
+            albedo_map: None,
+            normal_map: None,
+            roughness: None,
+            tint: Vec3::ONE,
+            opacity: 1.0,
+            blend_mode: DecalBlendMode::AlphaBlend,
+        }
+    }
+
+    /// Texture coordinates of the decal at `position`, or `None` outside of its box.
+    /// Matches the projection of `apply_decals.hlsl`.
+    pub fn uv_at(&self, position: Vec3) -> Option<Vec2> {
+        let local = self.transform.inverse().transform_point3(position);
+
+        if local.abs().max_element() > 0.5 {
+            return None;
+        }
+
+        Some(Vec2::new(local.x + 0.5, 0.5 - local.y))
+    }
+}
+
+#[derive(Clone, Copy, Hash, PartialEq, Eq, Debug)]
+pub struct DecalHandle(pub usize);
+
+// Sentinel for missing textures in `DecalConstants`
+const NO_TEXTURE: u32 = u32::MAX;
+
+// Must match `DecalConstants` in `apply_decals.hlsl`
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DecalConstants {
+    world_to_decal: [f32; 12],
+    tint_opacity: [f32; 4],
+    roughness: f32,
+    albedo_map: u32,
+    normal_map: u32,
+    blend_mode: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ApplyDecalsConstants {
+    output_tex_size: [f32; 4],
+    decal_count: u32,
+}
+
+#[derive(Default)]
+pub struct DecalRenderer {
+    decals: Vec<(DecalHandle, Decal)>,
+    next_decal_handle: usize,
+}
+
+impl DecalRenderer {
+    pub fn add_decal(&mut self, decal: Decal) -> DecalHandle {
+        let handle = DecalHandle(self.next_decal_handle);
+        self.next_decal_handle += 1;
+
+        self.decals.push((handle, decal));
+        handle
+    }
+
+    /// Returns the removed decal, or `None` if there's no such decal.
+    pub fn remove_decal(&mut self, decal: DecalHandle) -> Option<Decal> {
+        let index = self
+            .decals
+            .iter()
+            .position(|(handle, _)| *handle == decal)?;
+
+        Some(self.decals.remove(index).1)
+    }
+
+    pub fn decal_mut(&mut self, decal: DecalHandle) -> Option<&mut Decal> {
+        self.decals
+            .iter_mut()
+            .find(|(handle, _)| *handle == decal)
+            .map(|(_, decal)| decal)
+    }
+
+    /// Projects all decals onto the gbuffer. Every decal is tested at every pixel,
+    /// which is fine for tens of decals, but not thousands. The gbuffer is relative
+    /// to `render_origin`; see `render_origin.rs`.
+    pub fn render(
+        &self,
+        rg: &mut rg::RenderGraph,
+        gbuffer_depth: &mut GbufferDepth,
+        bindless_descriptor_set: vk::DescriptorSet,
+        render_origin: Vec3,
+    ) {
+        if self.decals.is_empty() {
+            return;
+        }
+
+        let decals: Vec<DecalConstants> = self
+            .decals
+            .iter()
+            .map(|(_, decal)| DecalConstants {
+                world_to_decal: row_major_3x4(
+                    &rebase_transform(decal.transform, render_origin).inverse(),
+                ),
+                tint_opacity: decal
+                    .tint
+                    .max(Vec3::ZERO)
+                    .extend(decal.opacity.max(0.0).min(1.0))
+                    .into(),
+                roughness: decal.roughness.map_or(-1.0, |r| r.max(0.0).min(1.0)),
+                albedo_map: decal.albedo_map.map_or(NO_TEXTURE, |h| h.0),
+                normal_map: decal.normal_map.map_or(NO_TEXTURE, |h| h.0),
+                blend_mode: match decal.blend_mode {
+                    DecalBlendMode::AlphaBlend => 0,
+                    DecalBlendMode::Multiply => 1,
+                },
+            })
+            .collect();
+
+        let constants = ApplyDecalsConstants {
+            output_tex_size: gbuffer_depth.gbuffer.desc().extent_inv_extent_2d(),
+            decal_count: decals.len() as u32,
+        };
+
+        let extent = gbuffer_depth.gbuffer.desc().extent;
+
+        SimpleRenderPass::new_compute(
+            rg.add_pass("apply decals"),
+            "/shaders/decals/apply_decals.hlsl",
+        )
+        .read_aspect(&gbuffer_depth.depth, vk::ImageAspectFlags::DEPTH)
+        .read_write(&mut gbuffer_depth.gbuffer)
+        .dynamic_storage_buffer_vec(decals)
+        .constants(constants)
+        .bindless(bindless_descriptor_set)
+        .dispatch(extent);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        camera::{CameraLens, LookThroughCamera},
+        frame_desc::{FrameTime, WorldFrameDesc},
+        renderers::aov::AovKind,
+        world_renderer::{AddMeshOptions, WorldRenderer},
+    };
+    use glam::{Quat, Vec4};
+    use kajiya_backend::{file::set_standard_vfs_mount_points, HeadlessRenderBackend};
+    use rg::renderer::Renderer;
+    use std::task::Poll;
+    use turbosloth::LazyCache;
+
+    const EXTENT: [u32; 2] = [256, 256];
+
+    fn approx_eq(a: Vec2, b: Vec2) -> bool {
+        (a - b).abs().max_element() < 1e-5
+    }
+
+    // A 2x2 decal facing up, projected down onto the y = 0 plane, within a box 0.5 units tall.
+    fn floor_decal() -> Decal {
+        Decal::new(Affine3A::from_scale_rotation_translation(
+            Vec3::new(2.0, 2.0, 0.5),
+            Quat::from_rotation_x(-std::f32::consts::FRAC_PI_2),
+            Vec3::ZERO,
+        ))
+    }
+
+    #[test]
+    fn decal_projects_onto_flat_floor() {
+        let decal = floor_decal();
+
+        assert!(approx_eq(
+            decal.uv_at(Vec3::ZERO).unwrap(),
+            Vec2::new(0.5, 0.5)
+        ));
+
+        // Local +X is world +X, and local +Y is world -Z.
+        assert!(approx_eq(
+            decal.uv_at(Vec3::new(0.5, 0.0, 0.0)).unwrap(),
+            Vec2::new(0.75, 0.5)
+        ));
+        assert!(approx_eq(
+            decal.uv_at(Vec3::new(0.0, 0.0, -0.5)).unwrap(),
+            Vec2::new(0.5, 0.25)
+        ));
+
+        // Beyond the edges of the decal, and above its box
+        assert!(decal.uv_at(Vec3::new(1.01, 0.0, 0.0)).is_none());
+        assert!(decal.uv_at(Vec3::new(0.0, 0.3, 0.0)).is_none());
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device, and `/baked/floor.mesh`"]
+    fn decal_tints_its_footprint_in_the_gbuffer() {
+        set_standard_vfs_mount_points(concat!(env!("CARGO_MANIFEST_DIR"), "/../../.."));
+        let backend = HeadlessRenderBackend::new(Default::default()).unwrap();
+
+        let lazy_cache = LazyCache::create();
+        let mut world_renderer =
+            WorldRenderer::new(EXTENT, EXTENT, &backend.device, &lazy_cache).unwrap();
+
+        // A 20x2x20m box, with its top at 0
+        let floor = world_renderer
+            .add_baked_mesh("/baked/floor.mesh", AddMeshOptions::new())
+            .unwrap();
+        world_renderer.add_instance(floor, Affine3A::IDENTITY);
+
+        let decal = floor_decal();
+        world_renderer.decals.add_decal(Decal {
+            tint: Vec3::new(1.0, 0.0, 0.0),
+            ..decal
+        });
+
+        // Looking straight down at the floor
+        let camera_matrices = (
+            Vec3::new(0.0, 4.0, 0.0),
+            Quat::from_rotation_x(-std::f32::consts::FRAC_PI_2),
+        )
+            .through(&CameraLens::default());
+        let frame_desc = WorldFrameDesc {
+            camera_matrices,
+            render_extent: EXTENT,
+            sun_direction: Vec3::Y,
+            time: FrameTime::fixed_step(0, 1.0 / 60.0),
+        };
+
+        let mut renderer = Renderer::new(&backend.device).unwrap();
+        let token = world_renderer.request_aov_readback(AovKind::Albedo);
+        let mut albedo = None;
+
+        for _ in 0..=backend.device.frames_in_flight() + 1 {
+            renderer
+                .prepare_frame(|rg| {
+                    world_renderer.prepare_render_graph(rg, &frame_desc);
+                })
+                .unwrap();
+            renderer
+                .draw_frame_headless(|dynamic_constants| {
+                    world_renderer.prepare_frame_constants(dynamic_constants, &frame_desc)
+                })
+                .unwrap();
+            world_renderer.retire_frame();
+
+            if albedo.is_none() {
+                if let Poll::Ready(result) = world_renderer.poll_aov_readback(token) {
+                    albedo = Some(result.expect("AOV not available").to_rgba_f32().unwrap());
+                }
+            }
+        }
+        let albedo = albedo.expect("AOV not read back");
+
+        // Where the ray through `uv` hits the floor
+        let floor_at = |uv: Vec2| {
+            let cs = (uv - Vec2::splat(0.5)) * Vec2::new(2.0, -2.0);
+            let dir_vs = camera_matrices.clip_to_view * Vec4::new(cs.x, cs.y, 1.0, 1.0);
+            let dir_ws = camera_matrices
+                .view_to_world
+                .transform_vector3(dir_vs.truncate() / dir_vs.w);
+            let origin = camera_matrices.view_to_world.transform_point3(Vec3::ZERO);
+            origin + dir_ws * (-origin.y / dir_ws.y)
+        };
+
+        // Pixels straddling the edge of the decal go either way.
+        let pixel_size = 1.0 / EXTENT[0] as f32;
+        let mut covered = 0;
+        for (idx, texel) in albedo.iter().enumerate() {
+            let px = [idx as u32 % EXTENT[0], idx as u32 / EXTENT[0]];
+            let uv = (Vec2::new(px[0] as f32, px[1] as f32) + Vec2::splat(0.5)) * pixel_size;
+            let inside = [Vec2::ZERO, Vec2::X, -Vec2::X, Vec2::Y, -Vec2::Y]
+                .iter()
+                .map(|offset| decal.uv_at(floor_at(uv + *offset * pixel_size)).is_some())
+                .collect::<Vec<_>>();
+            if inside.iter().any(|&i| i != inside[0]) {
+                continue;
+            }
+
+            let color = Vec3::new(texel[0], texel[1], texel[2]);
+            if inside[0] {
+                assert!(color.abs_diff_eq(Vec3::X, 0.02), "{:?}: {}", px, color);
+                covered += 1;
+            } else {
+                assert!(color.y > 0.05, "{:?}: {}", px, color);
+            }
+        }
+        assert!(covered > 0);
+    }
+}
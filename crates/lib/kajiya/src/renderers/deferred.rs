@@ -1,12 +1,15 @@
 use kajiya_backend::{ash::vk, vulkan::image::*};
 use kajiya_rg::{self as rg};
-use rg::{RenderGraph, SimpleRenderPass};
+use rg::SimpleRenderPass;
 
-use super::GbufferDepth;
+use super::{aov::LightingAovs, GbufferDepth};
 
+/// Returns the diffuse part of the lighting at subsurface scattering pixels,
+/// for `subsurface::scatter_subsurface`, if `gbuffer_depth` has any.
+/// Also splits the lighting into `lighting_aovs`, if provided.
 #[allow(clippy::too_many_arguments)]
 pub fn light_gbuffer(
-    rg: &mut RenderGraph,
+    rg: &mut rg::TemporalRenderGraph,
     gbuffer_depth: &GbufferDepth,
     shadow_mask: &rg::Handle<Image>,
     ssgi: &rg::Handle<Image>,
@@ -19,7 +22,37 @@ pub fn light_gbuffer(
     convolved_sky_cube: &rg::Handle<Image>,
     bindless_descriptor_set: vk::DescriptorSet,
     debug_shading_mode: usize,
-) {
+    lighting_aovs: Option<&mut LightingAovs>,
+) -> Option<rg::Handle<Image>> {
+    let extent = gbuffer_depth.gbuffer.desc().extent_2d();
+
+    // Without subsurface scattering, the shader doesn't touch these, but they still need binding.
+    let mut subsurface_diffuse = if gbuffer_depth.subsurface.is_some() {
+        rg.create(ImageDesc::new_2d(vk::Format::R16G16B16A16_SFLOAT, extent))
+    } else {
+        dummy_image(rg, "subsurface_diffuse")
+    };
+    let subsurface_dummy;
+    let subsurface = if let Some(subsurface) = gbuffer_depth.subsurface.as_ref() {
+        subsurface
+    } else {
+        subsurface_dummy = dummy_image(rg, "subsurface");
+        &subsurface_dummy
+    };
+
+    let write_lighting_aovs = lighting_aovs.is_some();
+    let mut lighting_aovs_dummy;
+    let lighting_aovs = if let Some(lighting_aovs) = lighting_aovs {
+        lighting_aovs
+    } else {
+        lighting_aovs_dummy = LightingAovs {
+            direct: dummy_image(rg, "direct"),
+            indirect: dummy_image(rg, "indirect"),
+            reflections: dummy_image(rg, "reflections"),
+        };
+        &mut lighting_aovs_dummy
+    };
+
     SimpleRenderPass::new_compute(rg.add_pass("light gbuffer"), "/shaders/light_gbuffer.hlsl")
         .read(&gbuffer_depth.gbuffer)
         .read_aspect(&gbuffer_depth.depth, vk::ImageAspectFlags::DEPTH)
@@ -32,10 +65,32 @@ pub fn light_gbuffer(
         .read_array(&csgi_volume.indirect)
         .read(sky_cube)
         .read(convolved_sky_cube)
+        .read(subsurface)
+        .write(&mut subsurface_diffuse)
+        .write(&mut lighting_aovs.direct)
+        .write(&mut lighting_aovs.indirect)
+        .write(&mut lighting_aovs.reflections)
         .constants((
             gbuffer_depth.gbuffer.desc().extent_inv_extent_2d(),
             debug_shading_mode as u32,
+            gbuffer_depth.subsurface.is_some() as u32,
+            write_lighting_aovs as u32,
         ))
-        .raw_descriptor_set(1, bindless_descriptor_set)
+        .bindless(bindless_descriptor_set)
         .dispatch(gbuffer_depth.gbuffer.desc().extent);
+
+    gbuffer_depth
+        .subsurface
+        .is_some()
+        .then(|| subsurface_diffuse)
+}
+
+// A 1x1 image for a binding which the shader doesn't touch, kept across frames.
+fn dummy_image(rg: &mut rg::TemporalRenderGraph, name: &str) -> rg::Handle<Image> {
+    rg.get_or_create_temporal(
+        format!("light_gbuffer.dummy_{}", name),
+        ImageDesc::new_2d(vk::Format::R16G16B16A16_SFLOAT, [1, 1])
+            .usage(vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::STORAGE),
+    )
+    .unwrap()
 }
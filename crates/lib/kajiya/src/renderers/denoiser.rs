@@ -0,0 +1,337 @@
+// Controls shared by the temporal and spatial denoisers of ray traced effects,
+// and dumps of their signal before and after denoising, for comparisons.
+
+use std::task::Poll;
+
+use kajiya_backend::{vulkan::image::*, Device};
+use kajiya_rg::{self as rg};
+
+use super::readback::{ImageReadback, ReadbackImage, ReadbackToken};
+
+#[derive(Clone, Copy, Debug)]
+pub struct DenoiserSettings {
+    /// Routes the raw signal through when `false`. The histories keep being written,
+    /// so that re-enabling the denoiser converges as quickly as after a camera cut.
+    pub enabled: bool,
+
+    /// Scales the footprint of the spatial filters. Zero disables them.
+    pub strength: f32,
+
+    /// The most frames which the temporal filters accumulate. Lower values reduce ghosting
+    /// and lag behind lighting changes, at the cost of noise. Zero disables them.
+    pub max_history_frames: f32,
+}
+
+impl DenoiserSettings {
+    pub fn with_max_history_frames(max_history_frames: f32) -> Self {
+        Self {
+            enabled: true,
+            strength: 1.0,
+            max_history_frames,
+        }
+    }
+
+    /// `(strength, max_history_frames)` for the shaders, with both at zero when disabled.
+    pub(super) fn shader_params(&self) -> (f32, f32) {
+        if self.enabled {
+            (self.strength.max(0.0), self.max_history_frames.max(0.0))
+        } else {
+            (0.0, 0.0)
+        }
+    }
+}
+
+/// An effect whose denoiser is controlled by `DenoiserSettings`.
+#[derive(Clone, Copy, Hash, PartialEq, Eq, Debug)]
+pub enum DenoisedEffect {
+    /// Diffuse GI; `WorldRenderer::rtdgi`
+    Rtdgi,
+    /// Reflections; `WorldRenderer::rtr`
+    Rtr,
+}
+
+// The readbacks of the noisy and denoised signal
+#[derive(Clone, Copy, Hash, PartialEq, Eq, Debug)]
+pub struct DenoiserDumpToken(ReadbackToken, ReadbackToken);
+
+/// The signal of an effect before and after denoising, in the same frame.
+pub struct DenoiserDump {
+    /// For `Rtdgi`, at the half resolution which it's traced at.
+    pub noisy: ReadbackImage,
+    pub denoised: ReadbackImage,
+}
+
+#[derive(Default)]
+pub struct DenoiserDumps {
+    queued: Vec<(DenoiserDumpToken, DenoisedEffect)>,
+    readback: ImageReadback,
+}
+
+impl DenoiserDumps {
+    pub fn request(&mut self, effect: DenoisedEffect) -> DenoiserDumpToken {
+        let token = DenoiserDumpToken(self.readback.reserve(), self.readback.reserve());
+        self.queued.push((token, effect));
+        token
+    }
+
+    pub fn begin_frame(&mut self) {
+        self.readback.begin_frame();
+    }
+
+    /// Copies the signal of `effect` to the host, for the queued requests which name it.
+    pub fn copy_to_host(
+        &mut self,
+        rg: &mut rg::TemporalRenderGraph,
+        effect: DenoisedEffect,
+        noisy: &rg::Handle<Image>,
+        denoised: &rg::Handle<Image>,
+    ) {
+        let mut queued = std::mem::take(&mut self.queued);
+        queued.retain(|(token, queued_effect)| {
+            if *queued_effect != effect {
+                return true;
+            }
+
+            self.readback.copy_reserved_to_host(token.0, rg, noisy);
+            self.readback.copy_reserved_to_host(token.1, rg, denoised);
+            false
+        });
+        self.queued = queued;
+    }
+
+    /// Resolves the requests for effects which weren't rendered in the frame being recorded.
+    pub fn skip_remaining(&mut self) {
+        for (token, _) in self.queued.drain(..) {
+            self.readback.cancel(token.0);
+            self.readback.cancel(token.1);
+        }
+    }
+
+    pub fn retire_frame(&mut self, frame_counter: u64) {
+        self.readback.retire_frame(frame_counter);
+    }
+
+    pub fn poll(
+        &mut self,
+        device: &Device,
+        token: DenoiserDumpToken,
+    ) -> Poll<Option<DenoiserDump>> {
+        let DenoiserDumpToken(noisy, denoised) = token;
+
+        // Both are copied or cancelled in the same frame, so they become ready together.
+        let noisy = match self.readback.poll(device, noisy) {
+            Poll::Ready(noisy) => noisy,
+            Poll::Pending => return Poll::Pending,
+        };
+        let denoised = match self.readback.poll(device, denoised) {
+            Poll::Ready(denoised) => denoised,
+            Poll::Pending => return Poll::Pending,
+        };
+
+        Poll::Ready(
+            noisy
+                .zip(denoised)
+                .map(|(noisy, denoised)| DenoiserDump { noisy, denoised }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::{CameraLens, LookThroughCamera};
+    use glam::{Quat, Vec3, Vec4};
+    use kajiya_backend::{
+        ash::vk, file::set_standard_vfs_mount_points, vk_sync::AccessType, HeadlessRenderBackend,
+    };
+    use rg::{
+        imageops::ClearValue,
+        readback::AsyncReadback,
+        renderer::{FrameConstantsLayout, Renderer},
+        SimpleRenderPass,
+    };
+    use rust_shaders_shared::{frame_constants::FrameConstants, view_constants::ViewConstants};
+    use std::sync::Arc;
+
+    const EXTENT: [u32; 2] = [16, 16];
+
+    // Runs `rtr/temporal_filter2.hlsl` for `frames` frames on a static view of a flat surface,
+    // with a checkerboard signal which inverts every frame. Returns the center texel
+    // of the last two frames.
+    fn filter_flickering_signal(settings: DenoiserSettings, frames: usize) -> [[f32; 4]; 2] {
+        set_standard_vfs_mount_points(concat!(env!("CARGO_MANIFEST_DIR"), "/../../.."));
+
+        let backend = HeadlessRenderBackend::new(Default::default()).unwrap();
+        let device = backend.device.clone();
+        let mut renderer = Renderer::new(&device).unwrap();
+        let mut readback = AsyncReadback::default();
+
+        let camera = (Vec3::ZERO, Quat::IDENTITY).through(&CameraLens::default());
+        let frame_constants = FrameConstants {
+            view_constants: ViewConstants::builder(camera, camera, EXTENT).build(),
+            sun_direction: Vec4::Y,
+            frame_index: 0,
+            delta_time_seconds: 1.0 / 60.0,
+            sun_angular_radius_cos: 1.0,
+            triangle_light_count: 0,
+            sun_color_multiplier: Vec4::ONE,
+            sky_ambient: Vec4::ZERO,
+            world_gi_scale: 1.0,
+            ray_origin_offset_method: 0,
+            ray_origin_offset_scale: 0.0,
+            pad0: 0,
+            gi_cascades: Default::default(),
+        };
+
+        let checkerboards: Vec<Arc<Image>> = (0..2)
+            .map(|phase| {
+                let texels: Vec<f32> = (0..EXTENT[0] * EXTENT[1])
+                    .flat_map(|idx| {
+                        let v = ((idx % EXTENT[0] + idx / EXTENT[0] + phase) % 2) as f32;
+                        [v, v, v, 1.0]
+                    })
+                    .collect();
+                Arc::new(
+                    device
+                        .create_image(
+                            ImageDesc::new_2d(vk::Format::R32G32B32A32_SFLOAT, EXTENT)
+                                .usage(vk::ImageUsageFlags::SAMPLED),
+                            "denoiser test signal",
+                            vec![ImageSubResourceData {
+                                data: bytemuck::cast_slice(&texels),
+                                row_pitch: EXTENT[0] as usize * 16,
+                                slice_pitch: 0,
+                            }],
+                        )
+                        .unwrap(),
+                )
+            })
+            .collect();
+
+        let (_, max_history_frames) = settings.shader_params();
+
+        readback.begin_frame();
+        let mut tokens = Vec::new();
+        renderer
+            .prepare_frame(|rg| {
+                let checkerboards: Vec<_> = checkerboards
+                    .iter()
+                    .map(|img| {
+                        rg.import(
+                            img.clone(),
+                            AccessType::AnyShaderReadSampledImageOrUniformTexelBuffer,
+                        )
+                    })
+                    .collect();
+
+                let mut depth = rg.create(ImageDesc::new_2d(vk::Format::R32_SFLOAT, EXTENT));
+                rg::imageops::clear_image(rg, &mut depth, ClearValue::Color([0.5, 0.0, 0.0, 0.0]));
+                let mut ray_len = rg.create(ImageDesc::new_2d(vk::Format::R32_SFLOAT, EXTENT));
+                rg::imageops::clear_image(
+                    rg,
+                    &mut ray_len,
+                    ClearValue::Color([1.0, 0.0, 0.0, 0.0]),
+                );
+
+                // Fully valid reprojection. The nudge keeps the reflector motion rate finite.
+                let mut reprojection =
+                    rg.create(ImageDesc::new_2d(vk::Format::R32G32B32A32_SFLOAT, EXTENT));
+                rg::imageops::clear_image(
+                    rg,
+                    &mut reprojection,
+                    ClearValue::Color([1e-5, 0.0, 1.0, 0.0]),
+                );
+
+                let mut history =
+                    rg.create(ImageDesc::new_2d(vk::Format::R32G32B32A32_SFLOAT, EXTENT));
+                rg::imageops::clear_image(rg, &mut history, ClearValue::Color([0.0; 4]));
+
+                for frame in 0..frames {
+                    let mut output = rg.create(*history.desc());
+                    SimpleRenderPass::new_compute(
+                        rg.add_pass("denoiser test temporal"),
+                        "/shaders/rtr/temporal_filter2.hlsl",
+                    )
+                    .read(&checkerboards[frame % 2])
+                    .read(&history)
+                    .read(&depth)
+                    .read(&ray_len)
+                    .read(&reprojection)
+                    .write(&mut output)
+                    .constants((output.desc().extent_inv_extent_2d(), max_history_frames))
+                    .dispatch(output.desc().extent);
+
+                    if frame + 2 >= frames {
+                        tokens.push(readback.copy_image(rg, &output, 16));
+                    }
+                    history = output;
+                }
+            })
+            .unwrap();
+        renderer
+            .draw_frame_headless(|dynamic_constants| FrameConstantsLayout {
+                globals_offset: dynamic_constants.push(&frame_constants),
+                instance_dynamic_parameters_offset: 0,
+                triangle_lights_offset: 0,
+            })
+            .unwrap();
+        readback.retire_frame(device.frame_counter());
+
+        let center = (EXTENT[1] / 2 * EXTENT[0] + EXTENT[0] / 2) as usize;
+        let mut tokens = tokens.into_iter();
+        let mut center_texel = || {
+            let bytes = readback.wait(&device, tokens.next().unwrap()).unwrap();
+            bytemuck::cast_slice::<u8, [f32; 4]>(&bytes)[center]
+        };
+        [center_texel(), center_texel()]
+    }
+
+    #[test]
+    fn disabled_denoiser_zeroes_the_shader_params() {
+        let disabled = DenoiserSettings {
+            enabled: false,
+            ..DenoiserSettings::with_max_history_frames(32.0)
+        };
+        assert_eq!(disabled.shader_params(), (0.0, 0.0));
+
+        let negative = DenoiserSettings {
+            strength: -1.0,
+            ..DenoiserSettings::with_max_history_frames(-4.0)
+        };
+        assert_eq!(negative.shader_params(), (0.0, 0.0));
+        assert_eq!(
+            DenoiserSettings::with_max_history_frames(8.0).shader_params(),
+            (1.0, 8.0)
+        );
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn disabled_denoiser_passes_the_raw_signal_through() {
+        let disabled = DenoiserSettings {
+            enabled: false,
+            ..DenoiserSettings::with_max_history_frames(32.0)
+        };
+        let [prev, last] = filter_flickering_signal(disabled, 16);
+        assert_eq!((prev[0], last[0]), (0.0, 1.0));
+        assert_eq!(last[3], 1.0);
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn history_cap_trades_noise_for_responsiveness() {
+        let noise = |max_history_frames| {
+            let [prev, last] = filter_flickering_signal(
+                DenoiserSettings::with_max_history_frames(max_history_frames),
+                64,
+            );
+            // The sample count saturates at the cap.
+            assert_eq!(last[3], max_history_frames + 1.0);
+            (last[0] - prev[0]).abs()
+        };
+        let short = noise(4.0);
+        let long = noise(32.0);
+        assert!(long < short && short < 1.0, "{} vs {}", long, short);
+        assert!(long < 0.05, "{}", long);
+    }
+}
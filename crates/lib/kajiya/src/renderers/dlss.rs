@@ -1,7 +1,7 @@
 use std::{intrinsics::transmute, ptr};
 
 use glam::Vec2;
-use kajiya_backend::{ash::vk, vk_sync::AccessType, vulkan::image::*, RenderBackend};
+use kajiya_backend::{ash::vk, vk_sync::AccessType, vulkan::image::*, Device};
 use kajiya_rg::{self as rg};
 use ngx_dlss::*;
 use wchar::wchz;
@@ -11,6 +11,8 @@ pub struct DlssRenderer {
     ngx_params: *mut NVSDK_NGX_Parameter,
     pub current_supersample_offset: Vec2,
     frame_idx: u32,
+    input_resolution: [u32; 2],
+    target_resolution: [u32; 2],
 }
 
 macro_rules! ngx_checked {
@@ -20,11 +22,7 @@ macro_rules! ngx_checked {
 }
 
 impl DlssRenderer {
-    pub fn new(
-        backend: &RenderBackend,
-        input_resolution: [u32; 2],
-        target_resolution: [u32; 2],
-    ) -> Self {
+    pub fn new(device: &Device, input_resolution: [u32; 2], target_resolution: [u32; 2]) -> Self {
         unsafe {
             let mut inst_ext_count = 0;
             let mut inst_exts = ptr::null_mut();
@@ -78,9 +76,9 @@ impl DlssRenderer {
             ngx_checked!(NVSDK_NGX_VULKAN_Init(
                 0xcafebabe,
                 wchz!(".").as_ptr(),
-                transmute(backend.device.physical_device().instance.raw.handle()),
-                transmute(backend.device.physical_device().raw),
-                transmute(backend.device.raw.handle()),
+                transmute(device.physical_device().instance.raw.handle()),
+                transmute(device.physical_device().raw),
+                transmute(device.raw.handle()),
                 &ngx_common_info,
                 NVSDK_NGX_Version_NVSDK_NGX_Version_API,
             ));
@@ -104,157 +102,56 @@ impl DlssRenderer {
             ));
             assert_eq!(supersampling_available, 1);
 
-            let quality_preference_order = [
-                NVSDK_NGX_PerfQuality_Value_NVSDK_NGX_PerfQuality_Value_MaxQuality,
-                NVSDK_NGX_PerfQuality_Value_NVSDK_NGX_PerfQuality_Value_Balanced,
-                NVSDK_NGX_PerfQuality_Value_NVSDK_NGX_PerfQuality_Value_MaxPerf,
-                NVSDK_NGX_PerfQuality_Value_NVSDK_NGX_PerfQuality_Value_UltraPerformance,
-            ];
-
-            log::info!(
-                "Finding a DLSS mode to produce {:?} output from {:?} input",
-                target_resolution,
-                input_resolution
-            );
-
-            let supported_quality_modes = quality_preference_order
-                .iter()
-                .copied()
-                .filter_map(|quality_value| {
-                    let settings = DlssOptimalSettings::for_target_resolution_at_quality(
-                        ngx_params,
-                        target_resolution,
-                        quality_value,
-                    );
-
-                    if settings.supports_input_resolution(input_resolution) {
-                        Some((quality_value, settings))
-                    } else {
-                        None
-                    }
-                })
-                .collect::<Vec<_>>();
-
-            let optimal_settings: Option<(NVSDK_NGX_PerfQuality_Value, DlssOptimalSettings)> =
-                supported_quality_modes
-                    .iter()
-                    .find(|(_, settings)| {
-                        input_resolution[0] >= settings.optimal_render_extent[0]
-                            && input_resolution[1] >= settings.optimal_render_extent[1]
-                    })
-                    .copied()
-                    .or_else(|| supported_quality_modes.first().copied());
-
-            let (optimal_quality_value, optimal_settings) = if let Some(v) = optimal_settings {
-                v
-            } else {
-                panic!(
-                    "No DLSS quality mode can produce {:?} output from {:?} input",
-                    target_resolution, input_resolution
-                );
-            };
-
-            #[allow(non_upper_case_globals)]
-            let quality_value_str = match optimal_quality_value {
-                NVSDK_NGX_PerfQuality_Value_NVSDK_NGX_PerfQuality_Value_MaxPerf => "MaxPerf",
-                NVSDK_NGX_PerfQuality_Value_NVSDK_NGX_PerfQuality_Value_Balanced => "Balanced",
-                NVSDK_NGX_PerfQuality_Value_NVSDK_NGX_PerfQuality_Value_MaxQuality => "MaxQuality",
-                NVSDK_NGX_PerfQuality_Value_NVSDK_NGX_PerfQuality_Value_UltraPerformance => {
-                    "UltraPerformance"
-                }
-                NVSDK_NGX_PerfQuality_Value_NVSDK_NGX_PerfQuality_Value_UltraQuality => {
-                    "UltraQuality"
-                }
-                _ => "unknown",
-            };
-
-            log::info!(
-                "Using {} DLSS mode:\n{:#?}",
-                quality_value_str,
-                optimal_settings
-            );
-
-            let dlss_create_params = NVSDK_NGX_DLSS_Create_Params {
-                Feature: NVSDK_NGX_Feature_Create_Params {
-                    InWidth: optimal_settings.optimal_render_extent[0],
-                    InHeight: optimal_settings.optimal_render_extent[1],
-                    InTargetWidth: target_resolution[0],
-                    InTargetHeight: target_resolution[1],
-                    InPerfQualityValue: optimal_quality_value,
-                },
-                InFeatureCreateFlags:
-                    NVSDK_NGX_DLSS_Feature_Flags_NVSDK_NGX_DLSS_Feature_Flags_IsHDR
-                        | NVSDK_NGX_DLSS_Feature_Flags_NVSDK_NGX_DLSS_Feature_Flags_MVLowRes
-                        | NVSDK_NGX_DLSS_Feature_Flags_NVSDK_NGX_DLSS_Feature_Flags_DepthInverted,
-                InEnableOutputSubrects: false,
-            };
-            //dbg!(&dlss_create_params);
-
-            NVSDK_NGX_Parameter_SetUI(ngx_params, NVSDK_NGX_Parameter_CreationNodeMask, 1);
-            NVSDK_NGX_Parameter_SetUI(ngx_params, NVSDK_NGX_Parameter_VisibilityNodeMask, 1);
-            NVSDK_NGX_Parameter_SetUI(
-                ngx_params,
-                NVSDK_NGX_Parameter_Width,
-                dlss_create_params.Feature.InWidth,
-            );
-            NVSDK_NGX_Parameter_SetUI(
-                ngx_params,
-                NVSDK_NGX_Parameter_Height,
-                dlss_create_params.Feature.InHeight,
-            );
-            NVSDK_NGX_Parameter_SetUI(
-                ngx_params,
-                NVSDK_NGX_Parameter_OutWidth,
-                dlss_create_params.Feature.InTargetWidth,
-            );
-            NVSDK_NGX_Parameter_SetUI(
-                ngx_params,
-                NVSDK_NGX_Parameter_OutHeight,
-                dlss_create_params.Feature.InTargetHeight,
-            );
-            NVSDK_NGX_Parameter_SetI(
-                ngx_params,
-                NVSDK_NGX_Parameter_PerfQualityValue,
-                dlss_create_params.Feature.InPerfQualityValue,
-            );
-            NVSDK_NGX_Parameter_SetI(
-                ngx_params,
-                NVSDK_NGX_Parameter_DLSS_Feature_Create_Flags,
-                dlss_create_params.InFeatureCreateFlags,
-            );
-            NVSDK_NGX_Parameter_SetI(
-                ngx_params,
-                NVSDK_NGX_Parameter_DLSS_Enable_Output_Subrects,
-                if dlss_create_params.InEnableOutputSubrects {
-                    1
-                } else {
-                    0
-                },
-            );
-
-            let mut dlss_feature: *mut NVSDK_NGX_Handle = ptr::null_mut();
-            backend
-                .device
-                .with_setup_cb(|cb| {
-                    ngx_checked!(NVSDK_NGX_VULKAN_CreateFeature(
-                        transmute(cb),
-                        NVSDK_NGX_Feature_NVSDK_NGX_Feature_SuperSampling,
-                        ngx_params,
-                        &mut dlss_feature,
-                    ));
-                })
-                .map_err(|err| backend.device.report_error(err))
-                .expect("NVSDK_NGX_VULKAN_CreateFeature (DLSS) failed");
+            let dlss_feature =
+                create_dlss_feature(device, ngx_params, input_resolution, target_resolution);
 
             Self {
                 dlss_feature,
                 ngx_params,
                 current_supersample_offset: Vec2::ZERO,
                 frame_idx: 0,
+                input_resolution,
+                target_resolution,
             }
         }
     }
 
+    /// Re-creates the DLSS feature for a new output resolution, keeping the ratio of input
+    /// to output resolution the feature was created with.
+    pub fn set_target_resolution(&mut self, device: &Device, target_resolution: [u32; 2]) {
+        if target_resolution == self.target_resolution {
+            return;
+        }
+
+        let input_resolution = [
+            ((target_resolution[0] as u64 * self.input_resolution[0] as u64
+                / self.target_resolution[0] as u64) as u32)
+                .max(1),
+            ((target_resolution[1] as u64 * self.input_resolution[1] as u64
+                / self.target_resolution[1] as u64) as u32)
+                .max(1),
+        ];
+
+        unsafe {
+            // Frames in flight may still evaluate the old feature.
+            device
+                .raw
+                .device_wait_idle()
+                .map_err(|err| device.report_error(err.into()))
+                .expect("device_wait_idle");
+            ngx_checked!(NVSDK_NGX_VULKAN_ReleaseFeature(self.dlss_feature));
+
+            self.dlss_feature =
+                create_dlss_feature(device, self.ngx_params, input_resolution, target_resolution);
+        }
+
+        self.input_resolution = input_resolution;
+        self.target_resolution = target_resolution;
+
+        // The new feature has no history.
+        self.frame_idx = 0;
+    }
+
     pub fn render(
         &mut self,
         rg: &mut rg::TemporalRenderGraph,
@@ -371,6 +268,155 @@ impl DlssRenderer {
     }
 }
 
+// Picks the best quality mode producing `target_resolution` output from `input_resolution` input,
+// and creates a DLSS feature for it.
+unsafe fn create_dlss_feature(
+    device: &Device,
+    ngx_params: *mut NVSDK_NGX_Parameter,
+    input_resolution: [u32; 2],
+    target_resolution: [u32; 2],
+) -> *mut NVSDK_NGX_Handle {
+    let quality_preference_order = [
+        NVSDK_NGX_PerfQuality_Value_NVSDK_NGX_PerfQuality_Value_MaxQuality,
+        NVSDK_NGX_PerfQuality_Value_NVSDK_NGX_PerfQuality_Value_Balanced,
+        NVSDK_NGX_PerfQuality_Value_NVSDK_NGX_PerfQuality_Value_MaxPerf,
+        NVSDK_NGX_PerfQuality_Value_NVSDK_NGX_PerfQuality_Value_UltraPerformance,
+    ];
+
+    log::info!(
+        "Finding a DLSS mode to produce {:?} output from {:?} input",
+        target_resolution,
+        input_resolution
+    );
+
+    let supported_quality_modes = quality_preference_order
+        .iter()
+        .copied()
+        .filter_map(|quality_value| {
+            let settings = DlssOptimalSettings::for_target_resolution_at_quality(
+                ngx_params,
+                target_resolution,
+                quality_value,
+            );
+
+            if settings.supports_input_resolution(input_resolution) {
+                Some((quality_value, settings))
+            } else {
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let optimal_settings: Option<(NVSDK_NGX_PerfQuality_Value, DlssOptimalSettings)> =
+        supported_quality_modes
+            .iter()
+            .find(|(_, settings)| {
+                input_resolution[0] >= settings.optimal_render_extent[0]
+                    && input_resolution[1] >= settings.optimal_render_extent[1]
+            })
+            .copied()
+            .or_else(|| supported_quality_modes.first().copied());
+
+    let (optimal_quality_value, optimal_settings) = if let Some(v) = optimal_settings {
+        v
+    } else {
+        panic!(
+            "No DLSS quality mode can produce {:?} output from {:?} input",
+            target_resolution, input_resolution
+        );
+    };
+
+    #[allow(non_upper_case_globals)]
+    let quality_value_str = match optimal_quality_value {
+        NVSDK_NGX_PerfQuality_Value_NVSDK_NGX_PerfQuality_Value_MaxPerf => "MaxPerf",
+        NVSDK_NGX_PerfQuality_Value_NVSDK_NGX_PerfQuality_Value_Balanced => "Balanced",
+        NVSDK_NGX_PerfQuality_Value_NVSDK_NGX_PerfQuality_Value_MaxQuality => "MaxQuality",
+        NVSDK_NGX_PerfQuality_Value_NVSDK_NGX_PerfQuality_Value_UltraPerformance => {
+            "UltraPerformance"
+        }
+        NVSDK_NGX_PerfQuality_Value_NVSDK_NGX_PerfQuality_Value_UltraQuality => "UltraQuality",
+        _ => "unknown",
+    };
+
+    log::info!(
+        "Using {} DLSS mode:\n{:#?}",
+        quality_value_str,
+        optimal_settings
+    );
+
+    let dlss_create_params = NVSDK_NGX_DLSS_Create_Params {
+        Feature: NVSDK_NGX_Feature_Create_Params {
+            InWidth: optimal_settings.optimal_render_extent[0],
+            InHeight: optimal_settings.optimal_render_extent[1],
+            InTargetWidth: target_resolution[0],
+            InTargetHeight: target_resolution[1],
+            InPerfQualityValue: optimal_quality_value,
+        },
+        InFeatureCreateFlags: NVSDK_NGX_DLSS_Feature_Flags_NVSDK_NGX_DLSS_Feature_Flags_IsHDR
+            | NVSDK_NGX_DLSS_Feature_Flags_NVSDK_NGX_DLSS_Feature_Flags_MVLowRes
+            | NVSDK_NGX_DLSS_Feature_Flags_NVSDK_NGX_DLSS_Feature_Flags_DepthInverted,
+        InEnableOutputSubrects: false,
+    };
+    //dbg!(&dlss_create_params);
+
+    NVSDK_NGX_Parameter_SetUI(ngx_params, NVSDK_NGX_Parameter_CreationNodeMask, 1);
+    NVSDK_NGX_Parameter_SetUI(ngx_params, NVSDK_NGX_Parameter_VisibilityNodeMask, 1);
+    NVSDK_NGX_Parameter_SetUI(
+        ngx_params,
+        NVSDK_NGX_Parameter_Width,
+        dlss_create_params.Feature.InWidth,
+    );
+    NVSDK_NGX_Parameter_SetUI(
+        ngx_params,
+        NVSDK_NGX_Parameter_Height,
+        dlss_create_params.Feature.InHeight,
+    );
+    NVSDK_NGX_Parameter_SetUI(
+        ngx_params,
+        NVSDK_NGX_Parameter_OutWidth,
+        dlss_create_params.Feature.InTargetWidth,
+    );
+    NVSDK_NGX_Parameter_SetUI(
+        ngx_params,
+        NVSDK_NGX_Parameter_OutHeight,
+        dlss_create_params.Feature.InTargetHeight,
+    );
+    NVSDK_NGX_Parameter_SetI(
+        ngx_params,
+        NVSDK_NGX_Parameter_PerfQualityValue,
+        dlss_create_params.Feature.InPerfQualityValue,
+    );
+    NVSDK_NGX_Parameter_SetI(
+        ngx_params,
+        NVSDK_NGX_Parameter_DLSS_Feature_Create_Flags,
+        dlss_create_params.InFeatureCreateFlags,
+    );
+    NVSDK_NGX_Parameter_SetI(
+        ngx_params,
+        NVSDK_NGX_Parameter_DLSS_Enable_Output_Subrects,
+        if dlss_create_params.InEnableOutputSubrects {
+            1
+        } else {
+            0
+        },
+    );
+
+    let mut dlss_feature: *mut NVSDK_NGX_Handle = ptr::null_mut();
+    device
+        .with_setup_cb(|cb| {
+            ngx_checked!(NVSDK_NGX_VULKAN_CreateFeature(
+                transmute(cb),
+                NVSDK_NGX_Feature_NVSDK_NGX_Feature_SuperSampling,
+                ngx_params,
+                &mut dlss_feature,
+            ));
+        })
+        .map_err(|err| device.report_error(err))
+        .expect("NVSDK_NGX_VULKAN_CreateFeature (DLSS) failed");
+
+    dlss_feature
+}
+
 #[derive(Debug, Clone, Copy)]
 struct DlssOptimalSettings {
     optimal_render_extent: [u32; 2],
@@ -0,0 +1,206 @@
+// Lens effects: chromatic aberration and radial distortion.
+//
+// Applied to the tonemapped image, as a final resampling pass. Each output pixel samples the
+// input at a radially scaled position, with a slightly different scale per color channel.
+
+use glam::Vec2;
+use kajiya_backend::vulkan::image::*;
+use kajiya_rg::{self as rg, SimpleRenderPass};
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct LensConstants {
+    output_tex_size: [f32; 4],
+    aspect_ratio: f32,
+    chromatic_aberration: f32,
+    distortion_k1: f32,
+    distortion_k2: f32,
+}
+
+#[derive(Default)]
+pub struct LensRenderer {
+    /// Radial offset of the red and blue channels, relative to green; opposite in sign.
+    /// Around 0.005 is subtle.
+    pub chromatic_aberration: f32,
+
+    /// Radial distortion coefficients, of the squared and quartic distance from the center.
+    /// Positive values give barrel distortion, and negative ones pincushion.
+    pub distortion_k1: f32,
+    pub distortion_k2: f32,
+}
+
+impl LensRenderer {
+    pub fn is_active(&self) -> bool {
+        self.chromatic_aberration != 0.0 || self.distortion_k1 != 0.0 || self.distortion_k2 != 0.0
+    }
+
+    /// Where the green channel of the output at `uv` comes from in the undistorted image;
+    /// e.g. to find what is under the cursor.
+    pub fn source_uv(&self, uv: Vec2, aspect_ratio: f32) -> Vec2 {
+        self.channel_source_uv(uv, aspect_ratio, 1.0)
+    }
+
+    // Must match `source_uv` in `lens.hlsl`
+    fn channel_source_uv(&self, uv: Vec2, aspect_ratio: f32, channel_scale: f32) -> Vec2 {
+        let p = uv * 2.0 - Vec2::ONE;
+
+        let aspect = Vec2::new(aspect_ratio, 1.0);
+        let q = p * aspect / aspect.length();
+        let r2 = q.dot(q);
+        let distortion = 1.0 + self.distortion_k1 * r2 + self.distortion_k2 * r2 * r2;
+
+        (p * distortion * channel_scale * 0.5 + Vec2::splat(0.5))
+            .max(Vec2::ZERO)
+            .min(Vec2::ONE)
+    }
+
+    /// Returns `input` unchanged if no effect is enabled.
+    pub fn render(&self, rg: &mut rg::RenderGraph, input: rg::Handle<Image>) -> rg::Handle<Image> {
+        if !self.is_active() {
+            return input;
+        }
+
+        let mut output = rg.create(*input.desc());
+        let [width, height, _] = output.desc().extent;
+
+        SimpleRenderPass::new_compute(rg.add_pass("lens"), "/shaders/lens/lens.hlsl")
+            .read(&input)
+            .write(&mut output)
+            .constants(LensConstants {
+                output_tex_size: output.desc().extent_inv_extent_2d(),
+                aspect_ratio: width as f32 / height as f32,
+                chromatic_aberration: self.chromatic_aberration,
+                distortion_k1: self.distortion_k1,
+                distortion_k2: self.distortion_k2,
+            })
+            .dispatch(output.desc().extent);
+
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kajiya_backend::{
+        ash::vk, file::set_standard_vfs_mount_points, vk_sync::AccessType, HeadlessRenderBackend,
+    };
+    use rg::{
+        readback::AsyncReadback,
+        renderer::{FrameConstantsLayout, Renderer},
+    };
+    use std::sync::Arc;
+
+    const EXTENT: [u32; 2] = [64, 36];
+
+    // Runs `lens.hlsl` on a horizontal gradient, the same in all channels; returns RGB per pixel.
+    fn render_gradient(lens: &LensRenderer) -> Vec<[f32; 3]> {
+        set_standard_vfs_mount_points(concat!(env!("CARGO_MANIFEST_DIR"), "/../../.."));
+
+        let backend = HeadlessRenderBackend::new(Default::default()).unwrap();
+        let device = backend.device.clone();
+        let mut renderer = Renderer::new(&device).unwrap();
+        let mut readback = AsyncReadback::default();
+
+        let texels: Vec<f32> = (0..EXTENT[0] * EXTENT[1])
+            .flat_map(|idx| [(idx % EXTENT[0]) as f32 / (EXTENT[0] - 1) as f32; 4])
+            .collect();
+        let desc = ImageDesc::new_2d(vk::Format::R32G32B32A32_SFLOAT, EXTENT).usage(
+            vk::ImageUsageFlags::SAMPLED
+                | vk::ImageUsageFlags::STORAGE
+                | vk::ImageUsageFlags::TRANSFER_SRC,
+        );
+        let input = Arc::new(
+            device
+                .create_image(
+                    desc,
+                    "lens test input",
+                    vec![ImageSubResourceData {
+                        data: bytemuck::cast_slice(&texels),
+                        row_pitch: EXTENT[0] as usize * 16,
+                        slice_pitch: 0,
+                    }],
+                )
+                .unwrap(),
+        );
+
+        readback.begin_frame();
+        let mut token = None;
+        renderer
+            .prepare_frame(|rg| {
+                let input = rg.import(
+                    input.clone(),
+                    AccessType::AnyShaderReadSampledImageOrUniformTexelBuffer,
+                );
+                let output = lens.render(rg, input);
+                token = Some(readback.copy_image(rg, &output, 16));
+            })
+            .unwrap();
+        renderer
+            .draw_frame_headless(|_| FrameConstantsLayout {
+                globals_offset: 0,
+                instance_dynamic_parameters_offset: 0,
+                triangle_lights_offset: 0,
+            })
+            .unwrap();
+        readback.retire_frame(device.frame_counter());
+
+        let bytes = readback.wait(&device, token.unwrap()).unwrap();
+        bytemuck::cast_slice::<u8, [f32; 4]>(&bytes)
+            .iter()
+            .map(|&[r, g, b, _]| [r, g, b])
+            .collect()
+    }
+
+    // The bilinearly sampled gradient at `u`
+    fn gradient_at(u: f32) -> f32 {
+        ((u * EXTENT[0] as f32 - 0.5) / (EXTENT[0] - 1) as f32).clamp(0.0, 1.0)
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn green_comes_from_source_uv() {
+        let lens = LensRenderer {
+            chromatic_aberration: 0.1,
+            distortion_k1: 0.2,
+            distortion_k2: 0.05,
+        };
+        let after = render_gradient(&lens);
+
+        // What picking relies on: the green channel of each pixel shows the image at `source_uv`.
+        let aspect_ratio = EXTENT[0] as f32 / EXTENT[1] as f32;
+        for (idx, &[_, g, _]) in after.iter().enumerate() {
+            let px = [idx as u32 % EXTENT[0], idx as u32 / EXTENT[0]];
+            let uv = (Vec2::new(px[0] as f32, px[1] as f32) + Vec2::splat(0.5))
+                / Vec2::new(EXTENT[0] as f32, EXTENT[1] as f32);
+            let expected = gradient_at(lens.source_uv(uv, aspect_ratio).x);
+            assert!(
+                (g - expected).abs() < 1e-3,
+                "{:?}: {} vs {}",
+                px,
+                g,
+                expected
+            );
+        }
+
+        // Barrel distortion and aberration pull outer pixels towards the center of the source:
+        // near the left edge, red samples further out than green, and blue further in.
+        let left = (EXTENT[1] / 2 * EXTENT[0] + 1) as usize;
+        let [r, g, b] = after[left];
+        assert!(r <= g && g < b, "{:?}", after[left]);
+    }
+
+    #[test]
+    fn source_uv_is_clamped_to_the_image() {
+        let lens = LensRenderer {
+            chromatic_aberration: 0.0,
+            distortion_k1: 0.2,
+            distortion_k2: 0.05,
+        };
+        assert_eq!(lens.source_uv(Vec2::ZERO, 16.0 / 9.0), Vec2::ZERO);
+        assert_eq!(
+            lens.source_uv(Vec2::splat(0.5), 16.0 / 9.0),
+            Vec2::splat(0.5)
+        );
+    }
+}
@@ -4,7 +4,10 @@ use kajiya_backend::{
 };
 use kajiya_rg::{self as rg, SimpleRenderPass};
 
-use super::{rtr::SPATIAL_RESOLVE_OFFSETS, GbufferDepth};
+use super::{
+    procedural::ProceduralHitGroupRegistry, rt_hit_groups, rtr::SPATIAL_RESOLVE_OFFSETS,
+    GbufferDepth,
+};
 
 pub struct LightingRenderer {}
 
@@ -29,6 +32,7 @@ impl LightingRenderer {
         gbuffer_depth: &GbufferDepth,
         bindless_descriptor_set: vk::DescriptorSet,
         tlas: &rg::Handle<RayTracingAcceleration>,
+        procedural_hit_groups: &ProceduralHitGroupRegistry,
     ) {
         let gbuffer_desc = gbuffer_depth.gbuffer.desc();
 
@@ -53,14 +57,15 @@ impl LightingRenderer {
                 ShaderSource::hlsl("/shaders/rt/gbuffer.rmiss.hlsl"),
                 ShaderSource::hlsl("/shaders/rt/shadow.rmiss.hlsl"),
             ],
-            [ShaderSource::hlsl("/shaders/rt/gbuffer.rchit.hlsl")],
+            rt_hit_groups(procedural_hit_groups),
         )
+        .hit_records(procedural_hit_groups.hit_records().to_vec())
         .read_aspect(&gbuffer_depth.depth, vk::ImageAspectFlags::DEPTH)
         .write(&mut refl0_tex)
         .write(&mut refl1_tex)
         .write(&mut refl2_tex)
         .constants((gbuffer_desc.extent_inv_extent_2d(),))
-        .raw_descriptor_set(1, bindless_descriptor_set)
+        .bindless(bindless_descriptor_set)
         .trace_rays(tlas, refl0_tex.desc().extent);
 
         let half_view_normal_tex = gbuffer_depth.half_view_normal(rg);
@@ -78,7 +83,7 @@ impl LightingRenderer {
         .read(&*half_view_normal_tex)
         .read(&*half_depth_tex)
         .write(output_tex)
-        .raw_descriptor_set(1, bindless_descriptor_set)
+        .bindless(bindless_descriptor_set)
         .constants((
             output_tex.desc().extent_inv_extent_2d(),
             SPATIAL_RESOLVE_OFFSETS,
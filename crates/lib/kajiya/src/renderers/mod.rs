@@ -1,15 +1,34 @@
 use std::cell::{Ref, RefCell};
 
-use kajiya_backend::Image;
-use kajiya_rg::{self as rg, GetOrCreateTemporal};
+use kajiya_backend::{vulkan::shader::ShaderSource, Image};
+use kajiya_rg::{self as rg, GetOrCreateTemporal, RayHitGroup, SimpleRenderPass};
+use procedural::ProceduralHitGroupRegistry;
 
+pub mod analytic_primitives;
+pub mod aov;
+pub mod atmosphere;
+pub mod color_grading;
+pub mod cryptomatte;
 pub mod csgi;
+pub mod csm;
+pub mod ddgi;
+pub mod debug_draw;
+pub mod decals;
 pub mod deferred;
+pub mod denoiser;
 pub mod half_res;
+pub mod lens;
 pub mod lighting;
 pub mod motion_blur;
+pub mod multilayer_exr;
+pub mod outline;
+pub mod particles;
+pub mod picking;
+pub mod point_lights;
 pub mod post;
+pub mod procedural;
 pub mod raster_meshes;
+pub mod readback;
 pub mod reference;
 pub mod reprojection;
 pub mod rtdgi;
@@ -18,15 +37,36 @@ pub mod shadow_denoise;
 pub mod shadows;
 pub mod sky;
 pub mod ssgi;
+pub mod subsurface;
 pub mod taa;
+pub mod text;
+pub mod transparency;
+pub mod volumetric_fog;
+pub mod wireframe;
 
 #[cfg(feature = "dlss")]
 pub mod dlss;
 
+#[cfg(feature = "oidn")]
+pub mod oidn;
+
 pub struct GbufferDepth {
+    /// View-space normal of the rasterized triangles, for ray offsets and edge-aware filters.
     pub geometric_normal: rg::Handle<Image>,
+
+    /// Packed `GbufferData`, whose normal is the shading one, perturbed by normal maps.
     pub gbuffer: rg::Handle<Image>,
+
     pub depth: rg::Handle<Image>,
+
+    /// Index of the instance covering each pixel + 1, or zero for the background (R32_UINT).
+    /// Only written when requested, as it costs an extra render target.
+    pub object_id: Option<rg::Handle<Image>>,
+
+    /// How far light scatters under the surface, per channel and in world units, or zero for opaque
+    /// surfaces (R16G16B16A16_SFLOAT). Written along with `object_id`, while any instance in the scene uses a subsurface material.
+    pub subsurface: Option<rg::Handle<Image>>,
+
     half_view_normal: RefCell<Option<rg::Handle<Image>>>,
     half_depth: RefCell<Option<rg::Handle<Image>>>,
 }
@@ -41,6 +81,8 @@ impl GbufferDepth {
             geometric_normal,
             gbuffer,
             depth,
+            object_id: None,
+            subsurface: None,
             half_view_normal: Default::default(),
             half_depth: Default::default(),
         }
@@ -65,6 +107,58 @@ impl GbufferDepth {
     }
 }
 
+/// Blends `overlay`, holding premultiplied color at any resolution, over `output`.
+pub(super) fn composite_overlay(
+    rg: &mut rg::RenderGraph,
+    name: &'static str,
+    overlay: &rg::Handle<Image>,
+    output: &mut rg::Handle<Image>,
+) {
+    let extent = output.desc().extent;
+    let output_tex_size = output.desc().extent_inv_extent_2d();
+
+    SimpleRenderPass::new_compute(rg.add_pass(name), "/shaders/composite_overlay.hlsl")
+        .read(overlay)
+        .read_write(output)
+        .constants(output_tex_size)
+        .dispatch(extent);
+}
+
+/// Hit groups for rays traced via `rt.hlsl`: gbuffer rays use the first one, and shadow rays the second.
+/// Their any-hit shaders skip alpha-tested texels, and back faces of single-sided materials.
+/// Pairs of groups for procedural geometry follow; see `procedural.rs`.
+pub fn rt_hit_groups(procedural: &ProceduralHitGroupRegistry) -> Vec<RayHitGroup> {
+    let mut groups = vec![
+        RayHitGroup::new(ShaderSource::hlsl("/shaders/rt/gbuffer.rchit.hlsl"))
+            .any_hit(ShaderSource::hlsl("/shaders/rt/gbuffer.rahit.hlsl")),
+        shadow_hit_group(),
+    ];
+
+    for procedural in procedural.groups() {
+        groups.push(procedural.gbuffer.clone());
+        groups.push(procedural.shadow.clone());
+    }
+
+    groups
+}
+
+/// Hit groups for passes which only trace shadow rays.
+pub fn rt_shadow_hit_groups(procedural: &ProceduralHitGroupRegistry) -> Vec<RayHitGroup> {
+    // Duplicated because `rt.hlsl` hardcodes the shadow hit group to 1
+    let mut groups = vec![shadow_hit_group(), shadow_hit_group()];
+
+    for procedural in procedural.groups() {
+        groups.push(procedural.shadow.clone());
+        groups.push(procedural.shadow.clone());
+    }
+
+    groups
+}
+
+fn shadow_hit_group() -> RayHitGroup {
+    RayHitGroup::any_hit_only(ShaderSource::hlsl("/shaders/rt/shadow.rahit.hlsl"))
+}
+
 pub struct PingPongTemporalResource {
     pub output_tex: rg::TemporalResourceKey,
     pub history_tex: rg::TemporalResourceKey,
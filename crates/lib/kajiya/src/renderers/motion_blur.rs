@@ -2,11 +2,40 @@ use kajiya_backend::{ash::vk, vulkan::image::*};
 use kajiya_rg::{self as rg};
 use rg::{RenderGraph, SimpleRenderPass};
 
-pub fn motion_blur(
+pub struct MotionBlurRenderer {
+    /// Fraction of the frame during which the virtual shutter is open. Blur trails span
+    /// this much of the motion since the previous frame: 0.5 is a 180° film shutter,
+    /// and 0 disables the blur.
+    pub shutter: f32,
+}
+
+impl Default for MotionBlurRenderer {
+    fn default() -> Self {
+        Self { shutter: 1.0 }
+    }
+}
+
+impl MotionBlurRenderer {
+    /// Gathers `input` along the screen-space motion in `reprojection_map`, which covers
+    /// both camera and object motion. Velocity is reduced to per-tile maxima first, to bound
+    /// the gather range; depth keeps sharp foreground objects from smearing into the background.
+    pub fn render(
+        &self,
+        rg: &mut RenderGraph,
+        input: &rg::Handle<Image>,
+        depth: &rg::Handle<Image>,
+        reprojection_map: &rg::Handle<Image>,
+    ) -> rg::Handle<Image> {
+        motion_blur(rg, input, depth, reprojection_map, self.shutter.max(0.0))
+    }
+}
+
+fn motion_blur(
     rg: &mut RenderGraph,
     input: &rg::Handle<Image>,
     depth: &rg::Handle<Image>,
     reprojection_map: &rg::Handle<Image>,
+    motion_blur_scale: f32,
 ) -> rg::Handle<Image> {
     const VELOCITY_TILE_SIZE: u32 = 16;
 
@@ -52,9 +81,6 @@ pub fn motion_blur(
 
     let mut output = rg.create(*input.desc());
 
-    // TODO: account for framerate like the HLSL version did
-    let motion_blur_scale: f32 = 1.0;
-
     SimpleRenderPass::new_compute_rust(rg.add_pass("motion blur"), "motion_blur::motion_blur")
         .read(input)
         .read(reprojection_map)
@@ -0,0 +1,345 @@
+// Exports of the final image along with AOVs, as a single multi-layer OpenEXR file.
+//
+// All layers share one part, with channels named `<layer>.<channel>` (`albedo.R`, `N.X`, ...),
+// and the lit image in the unprefixed `R`, `G`, and `B`, which is the layout that
+// compositing tools expect of renders. Like the AOVs, the lit image is linear and
+// before exposure and tonemapping, so that the layers add up.
+
+use std::{collections::HashMap, path::PathBuf, task::Poll};
+
+use anyhow::Context as _;
+use kajiya_backend::Device;
+
+use super::{
+    aov::{AovKind, AovReadbackToken, AovReadbacks},
+    cryptomatte::CryptomatteManifest,
+    readback::ReadbackImage,
+};
+
+const BEAUTY_CHANNELS: [&str; 3] = ["R", "G", "B"];
+
+/// Writes `beauty` and `aovs` to a multi-layer EXR at `path`. Each AOV contributes the channels
+/// named by `AovKind::exr_channels`; AOVs of a different size than `beauty`, like `RawGi`,
+/// are resampled to it, nearest-neighbor. `cryptomatte` provides the manifest of any
+/// Cryptomatte layers.
+pub fn write_multilayer_exr(
+    path: impl AsRef<std::path::Path>,
+    beauty: &ReadbackImage,
+    aovs: &[(AovKind, ReadbackImage)],
+    cryptomatte: Option<&CryptomatteManifest>,
+) -> anyhow::Result<()> {
+    use exr::prelude::*;
+
+    let extent = beauty.extent;
+
+    let mut channels = Vec::new();
+    let mut add_channels = |image: &ReadbackImage, names: &[&str]| -> anyhow::Result<()> {
+        let texels = image
+            .to_rgba_f32()
+            .with_context(|| format!("Can't decode images of format {:?}", image.format))?;
+        let texels = resample_nearest(&texels, image.extent, extent);
+
+        for (channel_idx, name) in names.iter().enumerate() {
+            channels.push(AnyChannel::new(
+                *name,
+                FlatSamples::F32(texels.iter().map(|texel| texel[channel_idx]).collect()),
+            ));
+        }
+
+        Ok(())
+    };
+
+    add_channels(beauty, &BEAUTY_CHANNELS)?;
+    for (kind, image) in aovs {
+        add_channels(image, kind.exr_channels())?;
+    }
+
+    let mut attributes = LayerAttributes::default();
+    if aovs
+        .iter()
+        .any(|(kind, _)| kind.cryptomatte_layer().is_some())
+    {
+        cryptomatte
+            .context("The Cryptomatte needs a manifest")?
+            .insert_exr_attributes(&mut attributes)?;
+    }
+
+    // Lossless, so that Cryptomatte IDs stay bit-exact
+    Image::from_layer(Layer::new(
+        (extent[0] as usize, extent[1] as usize),
+        attributes,
+        Encoding::FAST_LOSSLESS,
+        AnyChannels::sort(channels.into()),
+    ))
+    .write()
+    .to_file(path)?;
+
+    Ok(())
+}
+
+fn resample_nearest(texels: &[[f32; 4]], from: [u32; 2], to: [u32; 2]) -> Vec<[f32; 4]> {
+    if from == to {
+        return texels.to_vec();
+    }
+
+    (0..to[1] as u64)
+        .flat_map(|y| (0..to[0] as u64).map(move |x| (x, y)))
+        .map(|(x, y)| {
+            let src_x = x * from[0] as u64 / to[0] as u64;
+            let src_y = y * from[1] as u64 / to[1] as u64;
+            texels[(src_y * from[0] as u64 + src_x) as usize]
+        })
+        .collect()
+}
+
+#[derive(Clone, Copy, Hash, PartialEq, Eq, Debug)]
+pub struct ExrExportToken(u64);
+
+struct PendingExport {
+    path: PathBuf,
+    // Each image is `None` until it's read back, and `Some(None)` if it wasn't available.
+    beauty: (AovReadbackToken, Option<Option<ReadbackImage>>),
+    aovs: Vec<(AovKind, AovReadbackToken, Option<Option<ReadbackImage>>)>,
+    cryptomatte: Option<CryptomatteManifest>,
+}
+
+/// Multi-layer EXRs waiting for their images to be read back.
+#[derive(Default)]
+pub struct MultilayerExrExports {
+    pending: HashMap<ExrExportToken, PendingExport>,
+    next_token: u64,
+}
+
+impl MultilayerExrExports {
+    /// Reads back the lit image before tonemapping, and `aovs`, of the next frame via `readbacks`.
+    pub fn request(
+        &mut self,
+        readbacks: &mut AovReadbacks,
+        path: PathBuf,
+        aovs: &[AovKind],
+        cryptomatte: Option<CryptomatteManifest>,
+    ) -> ExrExportToken {
+        let token = ExrExportToken(self.next_token);
+        self.next_token += 1;
+
+        let export = PendingExport {
+            path,
+            beauty: (readbacks.request_hdr_beauty(), None),
+            aovs: aovs
+                .iter()
+                .map(|kind| (*kind, readbacks.request(*kind), None))
+                .collect(),
+            cryptomatte,
+        };
+        self.pending.insert(token, export);
+
+        token
+    }
+
+    /// Writes the file once all of its images are read back. AOVs which weren't available
+    /// in the frame are left out, with a warning. Fails if the lit image wasn't available,
+    /// or for unknown tokens.
+    pub fn poll(
+        &mut self,
+        device: &Device,
+        readbacks: &mut AovReadbacks,
+        token: ExrExportToken,
+    ) -> Poll<anyhow::Result<()>> {
+        let export = if let Some(export) = self.pending.get_mut(&token) {
+            export
+        } else {
+            return Poll::Ready(Err(anyhow::anyhow!("No such EXR export")));
+        };
+
+        let mut ready = true;
+        let images = std::iter::once((export.beauty.0, &mut export.beauty.1)).chain(
+            export
+                .aovs
+                .iter_mut()
+                .map(|(_, token, image)| (*token, image)),
+        );
+
+        for (readback, image) in images {
+            if image.is_some() {
+                continue;
+            }
+
+            match readbacks.poll(device, readback) {
+                Poll::Ready(readback) => *image = Some(readback),
+                Poll::Pending => ready = false,
+            }
+        }
+
+        if !ready {
+            return Poll::Pending;
+        }
+
+        let export = self.pending.remove(&token).unwrap();
+        let beauty = if let Some(beauty) = export.beauty.1.flatten() {
+            beauty
+        } else {
+            return Poll::Ready(Err(anyhow::anyhow!("The lit image wasn't available")));
+        };
+
+        let aovs: Vec<(AovKind, ReadbackImage)> = export
+            .aovs
+            .into_iter()
+            .filter_map(|(kind, _, image)| {
+                let image = image.flatten();
+                if image.is_none() {
+                    log::warn!(
+                        "The {} AOV wasn't available; leaving it out of {:?}",
+                        kind.name(),
+                        export.path
+                    );
+                }
+                image.map(|image| (kind, image))
+            })
+            .collect();
+
+        Poll::Ready(write_multilayer_exr(
+            &export.path,
+            &beauty,
+            &aovs,
+            export.cryptomatte.as_ref(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renderers::cryptomatte::cryptomatte_hash;
+    use kajiya_backend::ash::vk;
+
+    fn image(format: vk::Format, extent: [u32; 2], texels: &[f32]) -> ReadbackImage {
+        let bytes = texels.iter().flat_map(|t| t.to_le_bytes()).collect();
+        ReadbackImage {
+            format,
+            extent,
+            bytes,
+        }
+    }
+
+    #[test]
+    fn multilayer_exr_round_trips_the_layers() {
+        let extent = [4, 2];
+        let texel_count = (extent[0] * extent[1]) as usize;
+
+        let beauty = image(
+            vk::Format::R32G32B32A32_SFLOAT,
+            extent,
+            &(0..texel_count * 4).map(|i| i as f32).collect::<Vec<_>>(),
+        );
+        let normal = image(
+            vk::Format::R32G32B32A32_SFLOAT,
+            extent,
+            &[0.0, 1.0, 0.0, 1.0].repeat(texel_count),
+        );
+        let depth = image(
+            vk::Format::R32_SFLOAT,
+            extent,
+            &(0..texel_count)
+                .map(|i| 10.0 + i as f32)
+                .collect::<Vec<_>>(),
+        );
+        // At half the resolution; one texel per 2x2 block
+        let raw_gi = image(
+            vk::Format::R32G32B32A32_SFLOAT,
+            [2, 1],
+            &[1.0, 1.0, 1.0, 1.0, 2.0, 2.0, 2.0, 1.0],
+        );
+        let car_id = f32::from_bits(cryptomatte_hash("car"));
+        let cryptomatte = image(
+            vk::Format::R32G32B32A32_SFLOAT,
+            extent,
+            &[car_id, 0.75, 0.0, 0.0].repeat(texel_count),
+        );
+        let manifest = CryptomatteManifest::new(["car"]);
+
+        let path = std::env::temp_dir().join(format!(
+            "kajiya-multilayer-round-trip-{}.exr",
+            std::process::id()
+        ));
+        write_multilayer_exr(
+            &path,
+            &beauty,
+            &[
+                (AovKind::Normal, normal),
+                (AovKind::Depth, depth),
+                (AovKind::RawGi, raw_gi),
+                (AovKind::CryptoObject00, cryptomatte),
+            ],
+            Some(&manifest),
+        )
+        .unwrap();
+
+        let image = exr::prelude::read_all_flat_layers_from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(image.layer_data.len(), 1);
+        let layer = &image.layer_data[0];
+        assert_eq!((layer.size.0, layer.size.1), (4, 2));
+
+        let channels: HashMap<String, Vec<f32>> = layer
+            .channel_data
+            .list
+            .iter()
+            .map(|channel| {
+                let samples = match &channel.sample_data {
+                    exr::prelude::FlatSamples::F32(samples) => samples.clone(),
+                    _ => panic!("{} isn't f32", channel.name),
+                };
+                (channel.name.to_string(), samples)
+            })
+            .collect();
+
+        // Three channels for the beauty and normals, one for depth, four for the Cryptomatte
+        let mut names: Vec<&str> = channels.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        assert_eq!(
+            names,
+            [
+                "B",
+                "CryptoObject00.A",
+                "CryptoObject00.B",
+                "CryptoObject00.G",
+                "CryptoObject00.R",
+                "G",
+                "N.X",
+                "N.Y",
+                "N.Z",
+                "R",
+                "Z",
+                "raw_gi.B",
+                "raw_gi.G",
+                "raw_gi.R",
+            ]
+        );
+
+        assert_eq!(channels["R"][5], 20.0);
+        assert_eq!(channels["B"][5], 22.0);
+        assert!(channels["N.Y"].iter().all(|&y| y == 1.0));
+        assert_eq!(channels["Z"][7], 17.0);
+        assert_eq!(
+            channels["raw_gi.R"],
+            [1.0, 1.0, 2.0, 2.0, 1.0, 1.0, 2.0, 2.0]
+        );
+        assert!(channels["CryptoObject00.R"]
+            .iter()
+            .all(|id| id.to_bits() == car_id.to_bits()));
+        assert!(channels["CryptoObject00.G"].iter().all(|&c| c == 0.75));
+
+        let manifest_attribute = layer
+            .attributes
+            .other
+            .iter()
+            .find(|(name, _)| name.to_string().ends_with("/manifest"))
+            .map(|(_, value)| value);
+        assert!(matches!(
+            manifest_attribute,
+            Some(exr::meta::attribute::AttributeValue::Text(manifest))
+                if manifest.to_string() == CryptomatteManifest::new(["car"]).to_json()
+        ));
+    }
+}
@@ -0,0 +1,443 @@
+// Denoising of reference renders with Intel Open Image Denoise, behind the `oidn` feature.
+//
+// The library is loaded at runtime, so that builds with the feature still run where it isn't
+// installed; `OidnDenoiser::load` fails instead. The noisy radiance of the reference path tracer,
+// along with the albedo and normal AOVs as guides, is read back to the host, filtered there, and
+// uploaded again, to be post-processed in place of the accumulation.
+
+use std::{
+    collections::HashMap,
+    ffi::{c_void, CStr},
+    os::raw::c_char,
+    sync::Arc,
+    task::Poll,
+};
+
+use anyhow::Context as _;
+use kajiya_backend::{
+    ash::vk,
+    vk_sync::AccessType,
+    vulkan::{buffer::*, image::*},
+    Device,
+};
+use kajiya_rg::{self as rg, GetOrCreateTemporal};
+
+use super::{
+    aov::{AovKind, AovReadbackToken, AovReadbacks},
+    readback::ReadbackImage,
+};
+
+#[cfg(target_os = "windows")]
+const LIBRARY_NAMES: &[&str] = &["OpenImageDenoise.dll"];
+
+#[cfg(target_os = "macos")]
+const LIBRARY_NAMES: &[&str] = &["libOpenImageDenoise.dylib"];
+
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+const LIBRARY_NAMES: &[&str] = &[
+    "libOpenImageDenoise.so",
+    "libOpenImageDenoise.so.2",
+    "libOpenImageDenoise.so.1",
+];
+
+// From `OpenImageDenoise/oidn.h`
+type OidnDevice = *mut c_void;
+type OidnFilter = *mut c_void;
+
+const OIDN_DEVICE_TYPE_DEFAULT: i32 = 0;
+const OIDN_ERROR_NONE: i32 = 0;
+const OIDN_FORMAT_FLOAT3: i32 = 3;
+
+struct OidnFns {
+    new_device: unsafe extern "C" fn(i32) -> OidnDevice,
+    commit_device: unsafe extern "C" fn(OidnDevice),
+    get_device_error: unsafe extern "C" fn(OidnDevice, *mut *const c_char) -> i32,
+    release_device: unsafe extern "C" fn(OidnDevice),
+    new_filter: unsafe extern "C" fn(OidnDevice, *const c_char) -> OidnFilter,
+    set_shared_filter_image: unsafe extern "C" fn(
+        OidnFilter,
+        *const c_char,
+        *mut c_void,
+        i32,
+        usize,
+        usize,
+        usize,
+        usize,
+        usize,
+    ),
+    // `oidnSetFilterBool` since 2.0, and `oidnSetFilter1b` before
+    set_filter_bool: unsafe extern "C" fn(OidnFilter, *const c_char, bool),
+    commit_filter: unsafe extern "C" fn(OidnFilter),
+    execute_filter: unsafe extern "C" fn(OidnFilter),
+    release_filter: unsafe extern "C" fn(OidnFilter),
+}
+
+/// A device of Open Image Denoise, running its `RT` filter on the CPU, or whichever device
+/// the library picks by default.
+pub struct OidnDenoiser {
+    fns: OidnFns,
+    device: OidnDevice,
+
+    // Must outlive the function pointers
+    _library: libloading::Library,
+}
+
+impl OidnDenoiser {
+    /// Loads the library and creates a device; fails if it's not installed.
+    pub fn load() -> anyhow::Result<Self> {
+        let library = LIBRARY_NAMES
+            .iter()
+            .find_map(|name| unsafe { libloading::Library::new(name) }.ok())
+            .with_context(|| format!("Open Image Denoise not found; tried {:?}", LIBRARY_NAMES))?;
+
+        unsafe {
+            let fns = OidnFns {
+                new_device: *library.get(b"oidnNewDevice\0")?,
+                commit_device: *library.get(b"oidnCommitDevice\0")?,
+                get_device_error: *library.get(b"oidnGetDeviceError\0")?,
+                release_device: *library.get(b"oidnReleaseDevice\0")?,
+                new_filter: *library.get(b"oidnNewFilter\0")?,
+                set_shared_filter_image: *library.get(b"oidnSetSharedFilterImage\0")?,
+                set_filter_bool: *library
+                    .get(b"oidnSetFilterBool\0")
+                    .or_else(|_| library.get(b"oidnSetFilter1b\0"))?,
+                commit_filter: *library.get(b"oidnCommitFilter\0")?,
+                execute_filter: *library.get(b"oidnExecuteFilter\0")?,
+                release_filter: *library.get(b"oidnReleaseFilter\0")?,
+            };
+
+            let device = (fns.new_device)(OIDN_DEVICE_TYPE_DEFAULT);
+            anyhow::ensure!(
+                !device.is_null(),
+                "Failed to create an Open Image Denoise device"
+            );
+            (fns.commit_device)(device);
+
+            let denoiser = Self {
+                fns,
+                device,
+                _library: library,
+            };
+            denoiser.check_error()?;
+
+            Ok(denoiser)
+        }
+    }
+
+    fn check_error(&self) -> anyhow::Result<()> {
+        let mut message: *const c_char = std::ptr::null();
+        let error = unsafe { (self.fns.get_device_error)(self.device, &mut message) };
+
+        if error == OIDN_ERROR_NONE {
+            Ok(())
+        } else if message.is_null() {
+            Err(anyhow::anyhow!("Open Image Denoise error {}", error))
+        } else {
+            let message = unsafe { CStr::from_ptr(message) }.to_string_lossy();
+            Err(anyhow::anyhow!(
+                "Open Image Denoise error {}: {}",
+                error,
+                message
+            ))
+        }
+    }
+
+    /// Filters the HDR `color`, guided by `albedo` and `normal`, which must be of the same size.
+    /// Returns the result as RGBA32F, with an alpha of one.
+    pub fn denoise(
+        &self,
+        color: &ReadbackImage,
+        albedo: &ReadbackImage,
+        normal: &ReadbackImage,
+    ) -> anyhow::Result<ReadbackImage> {
+        let extent = color.extent;
+        anyhow::ensure!(
+            albedo.extent == extent && normal.extent == extent,
+            "The denoiser's inputs differ in size"
+        );
+
+        let decode = |image: &ReadbackImage| {
+            image
+                .to_rgba_f32()
+                .with_context(|| format!("Can't decode images of format {:?}", image.format))
+        };
+        let mut color = decode(color)?;
+        let mut albedo = decode(albedo)?;
+        let mut normal = decode(normal)?;
+        let mut output = vec![[0.0f32; 4]; color.len()];
+
+        // The filter reads the first three floats of each texel.
+        let pixel_stride = std::mem::size_of::<[f32; 4]>();
+        let [width, height] = [extent[0] as usize, extent[1] as usize];
+
+        unsafe {
+            let filter = (self.fns.new_filter)(self.device, b"RT\0".as_ptr() as _);
+            anyhow::ensure!(!filter.is_null(), "Failed to create the RT filter");
+
+            let images: [(&[u8], &mut Vec<[f32; 4]>); 4] = [
+                (b"color\0", &mut color),
+                (b"albedo\0", &mut albedo),
+                (b"normal\0", &mut normal),
+                (b"output\0", &mut output),
+            ];
+
+            for (name, texels) in images {
+                (self.fns.set_shared_filter_image)(
+                    filter,
+                    name.as_ptr() as _,
+                    texels.as_mut_ptr() as _,
+                    OIDN_FORMAT_FLOAT3,
+                    width,
+                    height,
+                    0,
+                    pixel_stride,
+                    0,
+                );
+            }
+
+            (self.fns.set_filter_bool)(filter, b"hdr\0".as_ptr() as _, true);
+            (self.fns.commit_filter)(filter);
+            (self.fns.execute_filter)(filter);
+            (self.fns.release_filter)(filter);
+        }
+
+        self.check_error()?;
+
+        Ok(ReadbackImage {
+            format: vk::Format::R32G32B32A32_SFLOAT,
+            extent,
+            bytes: output
+                .iter()
+                .flat_map(|[r, g, b, _]| [*r, *g, *b, 1.0])
+                .flat_map(f32::to_le_bytes)
+                .collect(),
+        })
+    }
+}
+
+impl Drop for OidnDenoiser {
+    fn drop(&mut self) {
+        unsafe { (self.fns.release_device)(self.device) };
+    }
+}
+
+#[derive(Clone, Copy, Hash, PartialEq, Eq, Debug)]
+pub struct OidnDenoiseToken(u64);
+
+/// The AOVs which the denoiser needs, in the order of its arguments.
+const INPUTS: [AovKind; 3] = [AovKind::Radiance, AovKind::Albedo, AovKind::Normal];
+
+type PendingDenoise = [(AovReadbackToken, Option<ReadbackImage>); 3];
+
+/// Denoises of the reference accumulation, waiting for their inputs to be read back,
+/// and the latest result.
+#[derive(Default)]
+pub struct OidnRenderer {
+    denoiser: Option<OidnDenoiser>,
+    pending: HashMap<OidnDenoiseToken, PendingDenoise>,
+    next_token: u64,
+
+    // The latest result, until a frame which uploads it retires
+    upload: Option<ReadbackImage>,
+
+    // Extent of the result in the temporal image, if there's one since the last reset
+    denoised_extent: Option<[u32; 2]>,
+
+    // Extent of the result uploaded in the frame being recorded; see `retire_frame`
+    recorded_extent: Option<[u32; 2]>,
+}
+
+impl OidnRenderer {
+    /// Reads back the inputs of the denoiser in the next frame.
+    pub fn request(&mut self, readbacks: &mut AovReadbacks) -> OidnDenoiseToken {
+        let token = OidnDenoiseToken(self.next_token);
+        self.next_token += 1;
+
+        let pending = INPUTS.map(|kind| (readbacks.request(kind), None));
+        self.pending.insert(token, pending);
+
+        token
+    }
+
+    /// Forgets the latest result, and any denoises in flight, whose inputs would be stale.
+    /// Their readbacks are dropped along with them.
+    pub fn reset(&mut self, readbacks: &mut AovReadbacks) {
+        for (_, pending) in self.pending.drain() {
+            for (token, _) in pending {
+                readbacks.discard(token);
+            }
+        }
+
+        self.upload = None;
+        self.denoised_extent = None;
+        self.recorded_extent = None;
+    }
+
+    /// Runs the denoiser once all of its inputs are read back, loading the library on first use,
+    /// and uploads the result in the next frame, for `prepare`. Fails if the frame wasn't rendered
+    /// in reference mode, the accumulation was reset since the request, or the library can't be loaded.
+    pub fn poll(
+        &mut self,
+        device: &Device,
+        readbacks: &mut AovReadbacks,
+        token: OidnDenoiseToken,
+    ) -> Poll<anyhow::Result<ReadbackImage>> {
+        let pending = if let Some(pending) = self.pending.get_mut(&token) {
+            pending
+        } else {
+            return Poll::Ready(Err(anyhow::anyhow!(
+                "No such denoise, or the accumulation was reset since"
+            )));
+        };
+
+        let mut ready = true;
+        for (kind, (readback, image)) in INPUTS.iter().zip(pending.iter_mut()) {
+            if image.is_some() {
+                continue;
+            }
+
+            match readbacks.poll(device, *readback) {
+                Poll::Ready(Some(readback)) => *image = Some(readback),
+                Poll::Ready(None) => {
+                    // The other inputs won't be wanted either
+                    for (token, _) in self.pending.remove(&token).unwrap() {
+                        readbacks.discard(token);
+                    }
+
+                    return Poll::Ready(Err(anyhow::anyhow!(
+                        "The {} AOV wasn't available; the denoiser needs reference mode",
+                        kind.name()
+                    )));
+                }
+                Poll::Pending => ready = false,
+            }
+        }
+
+        if !ready {
+            return Poll::Pending;
+        }
+
+        let [color, albedo, normal] = self
+            .pending
+            .remove(&token)
+            .unwrap()
+            .map(|(_, image)| image.unwrap());
+
+        Poll::Ready(self.denoise(&color, &albedo, &normal))
+    }
+
+    fn denoise(
+        &mut self,
+        color: &ReadbackImage,
+        albedo: &ReadbackImage,
+        normal: &ReadbackImage,
+    ) -> anyhow::Result<ReadbackImage> {
+        if self.denoiser.is_none() {
+            self.denoiser = Some(OidnDenoiser::load()?);
+        }
+
+        let denoised = self
+            .denoiser
+            .as_ref()
+            .unwrap()
+            .denoise(color, albedo, normal)?;
+        self.upload = Some(denoised.clone());
+
+        Ok(denoised)
+    }
+
+    /// The latest result, to be post-processed instead of the accumulation, or `None` if there's
+    /// none of `extent` since the last reset. A new result is uploaded in the frame being recorded,
+    /// into the same image as the previous one unless the extent changed.
+    pub(crate) fn prepare(
+        &mut self,
+        rg: &mut rg::TemporalRenderGraph,
+        extent: [u32; 2],
+    ) -> Option<rg::Handle<Image>> {
+        self.recorded_extent = None;
+
+        let latest_extent = self
+            .upload
+            .as_ref()
+            .map(|upload| upload.extent)
+            .or(self.denoised_extent);
+        if latest_extent != Some(extent) {
+            return None;
+        }
+
+        let mut image = rg
+            .get_or_create_temporal(
+                "oidn.denoised",
+                ImageDesc::new_2d(vk::Format::R32G32B32A32_SFLOAT, extent)
+                    .usage(vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST),
+            )
+            .unwrap();
+
+        if let Some(upload) = self.upload.as_ref() {
+            upload_image(rg, &mut image, &upload.bytes);
+            self.recorded_extent = Some(extent);
+        }
+
+        Some(image)
+    }
+
+    /// Call once the frame last recorded is submitted. Until then, the result uploaded in it
+    /// isn't considered to be in the image, as the frame may still be dropped.
+    pub fn retire_frame(&mut self) {
+        if let Some(extent) = self.recorded_extent.take() {
+            self.denoised_extent = Some(extent);
+            self.upload = None;
+        }
+    }
+}
+
+// Copies `bytes`, tightly packed texels of the first mip, into `image` through a staging buffer,
+// which is released once the frame is done with it.
+fn upload_image(rg: &mut rg::TemporalRenderGraph, image: &mut rg::Handle<Image>, bytes: &[u8]) {
+    let mut staging = rg
+        .device()
+        .create_buffer(
+            BufferDesc::new_cpu_to_gpu(bytes.len(), vk::BufferUsageFlags::TRANSFER_SRC),
+            "oidn denoised upload",
+            None,
+        )
+        .expect("oidn upload buffer");
+    staging.allocation.mapped_slice_mut().unwrap()[..bytes.len()].copy_from_slice(bytes);
+
+    let staging = Arc::new(staging);
+    let staging_handle = rg.import(staging.clone(), AccessType::Nothing);
+    rg.device().defer_release_buffer(staging);
+
+    let [width, height] = image.desc().extent_2d();
+
+    let mut pass = rg.add_pass("oidn upload");
+    let src_ref = pass.read(&staging_handle, AccessType::TransferRead);
+    let dst_ref = pass.write(image, AccessType::TransferWrite);
+
+    pass.render(move |api| {
+        let src = api.resources.buffer(src_ref);
+        let dst = api.resources.image(dst_ref);
+
+        unsafe {
+            api.device().raw.cmd_copy_buffer_to_image(
+                api.cb.raw,
+                src.raw,
+                dst.raw,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                &[vk::BufferImageCopy::builder()
+                    .image_subresource(
+                        vk::ImageSubresourceLayers::builder()
+                            .aspect_mask(vk::ImageAspectFlags::COLOR)
+                            .layer_count(1)
+                            .build(),
+                    )
+                    .image_extent(vk::Extent3D {
+                        width,
+                        height,
+                        depth: 1,
+                    })
+                    .build()],
+            );
+        }
+    });
+}
@@ -235,7 +235,7 @@ impl SurfelGiRenderState {
         .read(&self.surfel_spatial_buf)
         .write(&mut self.surfel_irradiance_buf)
         .write(&mut self.surfel_sh_buf)
-        .raw_descriptor_set(1, bindless_descriptor_set)
+        .bindless(bindless_descriptor_set)
         .trace_rays_indirect(tlas, &indirect_args_buf, 0);
     }
 }
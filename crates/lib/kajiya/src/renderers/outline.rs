@@ -0,0 +1,300 @@
+// Selection outlines.
+//
+// The gbuffer pass writes the draw index of every opaque instance into an object ID buffer.
+// After post-processing, pixels near the silhouette of a highlighted instance are blended
+// towards its highlight color. Creases inside of highlighted instances can optionally be traced
+// too, by running an edge detector over their depth and normals.
+
+use glam::Vec3;
+use kajiya_backend::{ash::vk, vulkan::image::*};
+use kajiya_rg::{self as rg, SimpleRenderPass};
+
+use crate::world_renderer::MeshInstance;
+
+use super::GbufferDepth;
+
+/// Outlines wider than this many pixels are clamped.
+pub const MAX_OUTLINE_WIDTH: f32 = 8.0;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct OutlineConstants {
+    input_tex_size: [f32; 4],
+    output_tex_size: [f32; 4],
+    width: f32,
+    inner_edge_strength: f32,
+}
+
+pub struct OutlineRenderer {
+    /// Colors selected by `MeshInstance::highlight`, allowing multiple selections to be told apart.
+    /// Out-of-range indices wrap around.
+    pub highlight_colors: Vec<Vec3>,
+
+    /// Width of the silhouette outline, in output pixels.
+    pub width: f32,
+
+    /// Opacity of the edges detected inside of highlighted instances. Zero only draws silhouettes.
+    pub inner_edge_strength: f32,
+}
+
+impl Default for OutlineRenderer {
+    fn default() -> Self {
+        Self {
+            highlight_colors: vec![
+                Vec3::new(1.0, 0.45, 0.05),
+                Vec3::new(0.1, 0.65, 1.0),
+                Vec3::new(0.35, 1.0, 0.2),
+            ],
+            width: 2.0,
+            inner_edge_strength: 0.0,
+        }
+    }
+}
+
+impl OutlineRenderer {
+    /// Whether any of `instances` is outlined, requiring the object ID buffer.
+    pub fn is_active(&self, instances: &[MeshInstance]) -> bool {
+        self.highlight_table(instances).is_some()
+    }
+
+    /// Per-draw highlight, indexed by the object ID minus one: zero for instances without
+    /// an outline, and the highlight color index plus one otherwise.
+    /// `None` if there is nothing to outline.
+    fn highlight_table(&self, instances: &[MeshInstance]) -> Option<Vec<u32>> {
+        if self.highlight_colors.is_empty() || self.width <= 0.0 {
+            return None;
+        }
+
+        let table: Vec<u32> = instances
+            .iter()
+            .map(|inst| {
+                inst.highlight
+                    .map_or(0, |idx| (idx % self.highlight_colors.len()) as u32 + 1)
+            })
+            .collect();
+
+        if table.iter().all(|&h| h == 0) {
+            None
+        } else {
+            Some(table)
+        }
+    }
+
+    /// Draws outlines around the highlighted instances on top of the post-processed `output`.
+    /// Transparent instances don't write object IDs, and are never outlined.
+    pub fn render(
+        &self,
+        rg: &mut rg::RenderGraph,
+        gbuffer_depth: &GbufferDepth,
+        instances: &[MeshInstance],
+        output: &mut rg::Handle<Image>,
+    ) {
+        let (highlights, object_id_img) = match (
+            self.highlight_table(instances),
+            gbuffer_depth.object_id.as_ref(),
+        ) {
+            (Some(highlights), Some(object_id_img)) => (highlights, object_id_img),
+            _ => return,
+        };
+
+        let colors: Vec<[f32; 4]> = self
+            .highlight_colors
+            .iter()
+            .map(|color| color.max(Vec3::ZERO).extend(1.0).into())
+            .collect();
+
+        let constants = OutlineConstants {
+            input_tex_size: object_id_img.desc().extent_inv_extent_2d(),
+            output_tex_size: output.desc().extent_inv_extent_2d(),
+            width: self.width.min(MAX_OUTLINE_WIDTH),
+            inner_edge_strength: self.inner_edge_strength.max(0.0).min(1.0),
+        };
+
+        let extent = output.desc().extent;
+
+        SimpleRenderPass::new_compute(rg.add_pass("outline"), "/shaders/outline/outline.hlsl")
+            .read(object_id_img)
+            .read_aspect(&gbuffer_depth.depth, vk::ImageAspectFlags::DEPTH)
+            .read(&gbuffer_depth.geometric_normal)
+            .dynamic_storage_buffer_vec(highlights)
+            .dynamic_storage_buffer_vec(colors)
+            .read_write(output)
+            .constants(constants)
+            .dispatch(extent);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world_renderer::MeshHandle;
+    use glam::Affine3A;
+    use kajiya_backend::{
+        file::set_standard_vfs_mount_points, vk_sync::AccessType, HeadlessRenderBackend,
+    };
+    use rg::{
+        imageops::ClearValue,
+        readback::AsyncReadback,
+        renderer::{FrameConstantsLayout, Renderer},
+    };
+    use std::sync::Arc;
+
+    fn instance(highlight: Option<usize>) -> MeshInstance {
+        MeshInstance {
+            highlight,
+            ..MeshInstance::new(MeshHandle(0), Affine3A::IDENTITY)
+        }
+    }
+
+    // Runs `outline.hlsl` over `object_ids`, on a black image of the same size; returns RGB per pixel.
+    fn render_outlines(
+        renderer: &OutlineRenderer,
+        instances: &[MeshInstance],
+        object_ids: &[u32],
+        extent: [u32; 2],
+    ) -> Vec<Vec3> {
+        set_standard_vfs_mount_points(concat!(env!("CARGO_MANIFEST_DIR"), "/../../.."));
+
+        let backend = HeadlessRenderBackend::new(Default::default()).unwrap();
+        let device = backend.device.clone();
+        let mut frame_renderer = Renderer::new(&device).unwrap();
+        let mut readback = AsyncReadback::default();
+
+        let object_id_img = Arc::new(
+            device
+                .create_image(
+                    ImageDesc::new_2d(vk::Format::R32_UINT, extent)
+                        .usage(vk::ImageUsageFlags::SAMPLED),
+                    "outline test object ids",
+                    vec![ImageSubResourceData {
+                        data: bytemuck::cast_slice(object_ids),
+                        row_pitch: extent[0] as usize * 4,
+                        slice_pitch: 0,
+                    }],
+                )
+                .unwrap(),
+        );
+
+        readback.begin_frame();
+        let mut token = None;
+        frame_renderer
+            .prepare_frame(|rg| {
+                let object_id_img = rg.import(
+                    object_id_img.clone(),
+                    AccessType::AnyShaderReadSampledImageOrUniformTexelBuffer,
+                );
+
+                let mut normal = rg.create(ImageDesc::new_2d(
+                    vk::Format::A2R10G10B10_UNORM_PACK32,
+                    extent,
+                ));
+                rg::imageops::clear_color(rg, &mut normal, [0.5, 0.5, 1.0, 0.0]);
+                let gbuffer = rg.create(ImageDesc::new_2d(vk::Format::R32G32B32A32_SFLOAT, extent));
+                let mut depth_img = rg.create(ImageDesc::new_2d(vk::Format::D32_SFLOAT, extent));
+                rg::imageops::clear_image(
+                    rg,
+                    &mut depth_img,
+                    ClearValue::DepthStencil {
+                        depth: 0.5,
+                        stencil: 0,
+                    },
+                );
+
+                let mut gbuffer_depth = GbufferDepth::new(normal, gbuffer, depth_img);
+                gbuffer_depth.object_id = Some(object_id_img);
+
+                let mut output =
+                    rg.create(ImageDesc::new_2d(vk::Format::R32G32B32A32_SFLOAT, extent));
+                rg::imageops::clear_color(rg, &mut output, [0.0; 4]);
+                renderer.render(rg, &gbuffer_depth, instances, &mut output);
+
+                token = Some(readback.copy_image(rg, &output, 16));
+            })
+            .unwrap();
+        frame_renderer
+            .draw_frame_headless(|_| FrameConstantsLayout {
+                globals_offset: 0,
+                instance_dynamic_parameters_offset: 0,
+                triangle_lights_offset: 0,
+            })
+            .unwrap();
+        readback.retire_frame(device.frame_counter());
+
+        let bytes = readback.wait(&device, token.unwrap()).unwrap();
+        bytemuck::cast_slice::<u8, [f32; 4]>(&bytes)
+            .iter()
+            .map(|&[r, g, b, _]| Vec3::new(r, g, b))
+            .collect()
+    }
+
+    #[test]
+    fn nothing_to_outline_without_highlights() {
+        let renderer = OutlineRenderer::default();
+        assert!(!renderer.is_active(&[instance(None), instance(None)]));
+        assert!(renderer.is_active(&[instance(None), instance(Some(0))]));
+        assert_eq!(
+            renderer.highlight_table(&[instance(None), instance(Some(4)), instance(Some(0))]),
+            Some(vec![0, 2, 1])
+        );
+
+        let no_width = OutlineRenderer {
+            width: 0.0,
+            ..Default::default()
+        };
+        assert!(!no_width.is_active(&[instance(Some(0))]));
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn outlines_only_the_highlighted_object() {
+        let renderer = OutlineRenderer {
+            width: 1.0,
+            ..Default::default()
+        };
+
+        // Three objects, of which the middle one is selected. Out-of-range indices wrap around.
+        let instances = [
+            instance(None),
+            instance(Some(renderer.highlight_colors.len() + 1)),
+            instance(None),
+        ];
+        let color = renderer.highlight_colors[1];
+
+        // 3x3 squares of objects 1, 2 and 3 on a background, with a pixel between them.
+        let extent = [15u32, 5u32];
+        let object_ids: Vec<u32> = (0..extent[0] * extent[1])
+            .map(|i| {
+                let (x, y) = (i % extent[0], i / extent[0]);
+                match (x, y) {
+                    (1..=3, 1..=3) => 1,
+                    (5..=7, 1..=3) => 2,
+                    (9..=11, 1..=3) => 3,
+                    _ => 0,
+                }
+            })
+            .collect();
+
+        let output = render_outlines(&renderer, &instances, &object_ids, extent);
+        let outline = |x: u32, y: u32| output[(y * extent[0] + x) as usize];
+
+        // Directly around the selected object, in its highlight color
+        for (x, y) in [(4, 2), (8, 2), (6, 0), (6, 4)] {
+            assert!(
+                outline(x, y).abs_diff_eq(color, 1e-5),
+                "{:?}: {}",
+                (x, y),
+                outline(x, y)
+            );
+        }
+
+        // Diagonally off the corner, partially covered
+        let corner = outline(4, 0);
+        assert!(corner.abs_diff_eq(color * (corner.x / color.x), 1e-5));
+        assert!(corner.x > 0.0 && corner.x < color.x, "{}", corner);
+
+        // Not inside of it, nor around the other objects
+        for (x, y) in [(6, 2), (0, 2), (2, 0), (12, 2), (10, 4)] {
+            assert_eq!(outline(x, y), Vec3::ZERO, "{:?}", (x, y));
+        }
+    }
+}
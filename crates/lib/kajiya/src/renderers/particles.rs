@@ -0,0 +1,648 @@
+// GPU particles.
+//
+// Each emitter keeps its particles in persistent buffers. Every frame, an emit pass works out how many
+// particles to spawn, a simulation pass spawns and integrates them, and, with ray tracing available,
+// a pass traces sun visibility for each lit particle. The particles are then drawn as camera-facing
+// billboards by `TransparencyRenderer`, in either of its modes.
+
+use std::sync::Arc;
+
+use glam::Vec3;
+use kajiya_backend::{
+    ash::vk,
+    vk_sync::AccessType,
+    vulkan::{buffer::*, image::*, ray_tracing::RayTracingAcceleration, shader::*},
+};
+use kajiya_rg::{self as rg, BindRgRef, GetOrCreateTemporal, SimpleRenderPass};
+use rg::IntoRenderPassPipelineBinding;
+
+use super::{procedural::ProceduralHitGroupRegistry, rt_shadow_hit_groups};
+
+// Size of `Particle` in `particles/common.hlsl`
+const PARTICLE_STRIDE: usize = 48;
+
+// Size of `ParticleEmitterState` in `particles/common.hlsl`
+const EMITTER_STATE_SIZE: usize = 16;
+
+pub const MAX_PARTICLES_PER_EMITTER: u32 = 1 << 16;
+
+#[derive(Clone, Copy, Debug)]
+pub struct ParticleEmitterDesc {
+    pub position: Vec3,
+
+    /// Particles spawn within a sphere of this radius around `position`.
+    pub emission_radius: f32,
+
+    /// Particles spawned per second.
+    pub emission_rate: f32,
+
+    /// Seconds each particle lives for.
+    pub lifetime: f32,
+
+    /// Capacity of the emitter. Once `emission_rate * lifetime` exceeds it, the oldest particles
+    /// are recycled early. At most `MAX_PARTICLES_PER_EMITTER`.
+    pub max_particles: u32,
+
+    pub initial_velocity: Vec3,
+
+    /// Magnitude of a random velocity added to `initial_velocity` on spawn.
+    pub velocity_randomness: f32,
+
+    /// Constant acceleration, such as gravity, wind or buoyancy.
+    pub acceleration: Vec3,
+
+    /// Fraction of the velocity lost per second, approximately; higher values slow particles down faster.
+    pub drag: f32,
+
+    /// Billboard diameters at spawn and at the end of the lifetime.
+    pub start_size: f32,
+    pub end_size: f32,
+
+    /// Albedo of lit particles, or emitted radiance of unlit ones.
+    pub color: Vec3,
+
+    /// Peak opacity; particles fade in after spawning, and out towards the end of their lifetime.
+    pub opacity: f32,
+
+    /// Shade with the sun and sky, with ray traced sun shadows. Unlit particles are emissive.
+    pub lit: bool,
+}
+
+impl Default for ParticleEmitterDesc {
+    fn default() -> Self {
+        Self {
+            position: Vec3::ZERO,
+            emission_radius: 0.1,
+            emission_rate: 50.0,
+            lifetime: 2.0,
+            max_particles: 1024,
+            initial_velocity: Vec3::new(0.0, 1.0, 0.0),
+            velocity_randomness: 0.5,
+            acceleration: Vec3::new(0.0, -9.81, 0.0),
+            drag: 0.0,
+            start_size: 0.05,
+            end_size: 0.05,
+            color: Vec3::ONE,
+            opacity: 1.0,
+            lit: false,
+        }
+    }
+}
+
+impl ParticleEmitterDesc {
+    /// Slowly rising, expanding, sun-lit puffs.
+    pub fn smoke(position: Vec3) -> Self {
+        Self {
+            position,
+            emission_radius: 0.2,
+            emission_rate: 40.0,
+            lifetime: 8.0,
+            max_particles: 512,
+            initial_velocity: Vec3::new(0.0, 0.4, 0.0),
+            velocity_randomness: 0.15,
+            acceleration: Vec3::new(0.1, 0.15, 0.0),
+            drag: 0.3,
+            start_size: 0.3,
+            end_size: 1.5,
+            color: Vec3::splat(0.6),
+            opacity: 0.25,
+            lit: true,
+        }
+    }
+
+    fn sanitized(&self) -> Self {
+        Self {
+            emission_radius: self.emission_radius.max(0.0),
+            emission_rate: self.emission_rate.max(0.0),
+            lifetime: self.lifetime.max(1e-3),
+            max_particles: self.max_particles.max(1).min(MAX_PARTICLES_PER_EMITTER),
+            velocity_randomness: self.velocity_randomness.max(0.0),
+            drag: self.drag.max(0.0),
+            start_size: self.start_size.max(0.0),
+            end_size: self.end_size.max(0.0),
+            color: self.color.max(Vec3::ZERO),
+            opacity: self.opacity.max(0.0).min(1.0),
+            ..*self
+        }
+    }
+}
+
+#[derive(Clone, Copy, Hash, PartialEq, Eq, Debug)]
+pub struct ParticleEmitterHandle(pub usize);
+
+// Must match `ParticleEmitterConstants` in `particles/common.hlsl`
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ParticleEmitterConstants {
+    position_radius: [f32; 4],
+    initial_velocity_randomness: [f32; 4],
+    acceleration_drag: [f32; 4],
+    emission_rate: f32,
+    lifetime: f32,
+    start_size: f32,
+    end_size: f32,
+    max_particles: u32,
+    reset: u32,
+    seed: u32,
+    pad0: u32,
+
+    // Subtracted from the positions of live particles, as the render origin moved
+    origin_shift: [f32; 4],
+}
+
+// Must match `ParticleDrawConstants` in `particles/billboard_common.hlsl`
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ParticleDrawConstants {
+    color_opacity: [f32; 4],
+    oit_weight: [f32; 4],
+    lit: u32,
+}
+
+unsafe impl bytemuck::Zeroable for ParticleDrawConstants {}
+unsafe impl bytemuck::Pod for ParticleDrawConstants {}
+
+struct ParticleEmitter {
+    handle: ParticleEmitterHandle,
+    desc: ParticleEmitterDesc,
+
+    // Capacity the GPU buffers were last initialized with
+    initialized_capacity: Option<u32>,
+    // That of the frame being recorded; see `ParticleSystem::retire_frame`.
+    recorded_capacity: Option<u32>,
+}
+
+#[derive(Default)]
+pub struct ParticleSystem {
+    emitters: Vec<ParticleEmitter>,
+    next_emitter_handle: usize,
+
+    // Their GPU buffers are released by the next frame; see `ParticleSystem::retire_frame`.
+    removed_emitters: Vec<ParticleEmitterHandle>,
+    recorded_removals: usize,
+
+    // That of the last submitted frame; particles are simulated relative to it.
+    render_origin: Vec3,
+    // That of the frame being recorded; see `ParticleSystem::retire_frame`.
+    recorded_render_origin: Option<Vec3>,
+}
+
+impl ParticleSystem {
+    pub fn add_emitter(&mut self, desc: ParticleEmitterDesc) -> ParticleEmitterHandle {
+        let handle = ParticleEmitterHandle(self.next_emitter_handle);
+        self.next_emitter_handle += 1;
+
+        self.emitters.push(ParticleEmitter {
+            handle,
+            desc,
+            initialized_capacity: None,
+            recorded_capacity: None,
+        });
+
+        handle
+    }
+
+    /// Returns the description of the removed emitter, or `None` if there's no such emitter.
+    pub fn remove_emitter(
+        &mut self,
+        emitter: ParticleEmitterHandle,
+    ) -> Option<ParticleEmitterDesc> {
+        let index = self.emitters.iter().position(|e| e.handle == emitter)?;
+
+        self.removed_emitters.push(emitter);
+        Some(self.emitters.remove(index).desc)
+    }
+
+    pub fn emitter_desc_mut(
+        &mut self,
+        emitter: ParticleEmitterHandle,
+    ) -> Option<&mut ParticleEmitterDesc> {
+        self.emitters
+            .iter_mut()
+            .find(|e| e.handle == emitter)
+            .map(|e| &mut e.desc)
+    }
+
+    pub fn emitters(&self) -> impl Iterator<Item = (ParticleEmitterHandle, &ParticleEmitterDesc)> {
+        self.emitters.iter().map(|e| (e.handle, &e.desc))
+    }
+
+    /// Spawns and integrates particles of all emitters. Sun visibility is only traced when `tlas` is provided;
+    /// otherwise lit particles are fully exposed to the sun.
+    ///
+    /// Emitters are in world space, but particles are simulated relative to `render_origin`,
+    /// and follow it when it moves; see `render_origin.rs`.
+    pub fn simulate(
+        &mut self,
+        rg: &mut rg::TemporalRenderGraph,
+        tlas: Option<&rg::Handle<RayTracingAcceleration>>,
+        procedural_hit_groups: &ProceduralHitGroupRegistry,
+        bindless_descriptor_set: vk::DescriptorSet,
+        render_origin: Vec3,
+    ) -> SimulatedParticles {
+        let mut simulated = SimulatedParticles {
+            emitters: Vec::with_capacity(self.emitters.len()),
+        };
+
+        let origin_shift = render_origin - self.render_origin;
+        self.recorded_render_origin = Some(render_origin);
+
+        // Until the frame is submitted, the removals need to be repeated, as it may be dropped.
+        for emitter in &self.removed_emitters {
+            rg.release_temporal(format!("particles.{}.state", emitter.0));
+            rg.release_temporal(format!("particles.{}.particles", emitter.0));
+        }
+        self.recorded_removals = self.removed_emitters.len();
+
+        for emitter in &mut self.emitters {
+            let desc = emitter.desc.sanitized();
+            let max_particles = desc.max_particles;
+
+            // Freshly created buffers contain garbage, and need to be initialized on the GPU.
+            let reset = emitter.initialized_capacity != Some(max_particles);
+            emitter.recorded_capacity = Some(max_particles);
+
+            let constants = ParticleEmitterConstants {
+                position_radius: (desc.position - render_origin)
+                    .extend(desc.emission_radius)
+                    .into(),
+                initial_velocity_randomness: desc
+                    .initial_velocity
+                    .extend(desc.velocity_randomness)
+                    .into(),
+                acceleration_drag: desc.acceleration.extend(desc.drag).into(),
+                emission_rate: desc.emission_rate,
+                lifetime: desc.lifetime,
+                start_size: desc.start_size,
+                end_size: desc.end_size,
+                max_particles,
+                reset: reset as u32,
+                seed: emitter.handle.0 as u32,
+                pad0: 0,
+                origin_shift: origin_shift.extend(0.0).into(),
+            };
+
+            let mut state_buf = rg
+                .get_or_create_temporal(
+                    format!("particles.{}.state", emitter.handle.0),
+                    BufferDesc::new_gpu_only(
+                        EMITTER_STATE_SIZE,
+                        vk::BufferUsageFlags::STORAGE_BUFFER,
+                    ),
+                )
+                .unwrap();
+
+            let mut particles_buf = rg
+                .get_or_create_temporal(
+                    format!("particles.{}.particles", emitter.handle.0),
+                    BufferDesc::new_gpu_only(
+                        PARTICLE_STRIDE * max_particles as usize,
+                        vk::BufferUsageFlags::STORAGE_BUFFER,
+                    ),
+                )
+                .unwrap();
+
+            SimpleRenderPass::new_compute(
+                rg.add_pass("particle emit"),
+                "/shaders/particles/emit.hlsl",
+            )
+            .read_write(&mut state_buf)
+            .constants(constants)
+            .dispatch([1, 1, 1]);
+
+            SimpleRenderPass::new_compute(
+                rg.add_pass("particle simulate"),
+                "/shaders/particles/simulate.hlsl",
+            )
+            .read(&state_buf)
+            .read_write(&mut particles_buf)
+            .constants(constants)
+            .dispatch([max_particles, 1, 1]);
+
+            if let (Some(tlas), true) = (tlas, desc.lit) {
+                SimpleRenderPass::new_rt(
+                    rg.add_pass("particle sun visibility"),
+                    ShaderSource::hlsl("/shaders/particles/sun_visibility.rgen.hlsl"),
+                    [
+                        // Duplicated because `rt.hlsl` hardcodes miss index to 1
+                        ShaderSource::hlsl("/shaders/rt/shadow.rmiss.hlsl"),
+                        ShaderSource::hlsl("/shaders/rt/shadow.rmiss.hlsl"),
+                    ],
+                    rt_shadow_hit_groups(procedural_hit_groups),
+                )
+                .hit_records(procedural_hit_groups.hit_records().to_vec())
+                .read_write(&mut particles_buf)
+                .bindless(bindless_descriptor_set)
+                .trace_rays(tlas, [max_particles, 1, 1]);
+            }
+
+            simulated.emitters.push(SimulatedEmitter {
+                particles_buf,
+                max_particles,
+                color_opacity: desc.color.extend(desc.opacity).into(),
+                lit: desc.lit,
+            });
+        }
+
+        simulated
+    }
+
+    /// Call once the frame last recorded is submitted. Until then, emitters aren't considered
+    /// initialized by it, nor particles moved along with the render origin, as the frame may
+    /// still be dropped.
+    pub fn retire_frame(&mut self) {
+        self.removed_emitters.drain(..self.recorded_removals);
+        self.recorded_removals = 0;
+
+        if let Some(render_origin) = self.recorded_render_origin.take() {
+            self.render_origin = render_origin;
+        }
+
+        for emitter in &mut self.emitters {
+            if let Some(capacity) = emitter.recorded_capacity.take() {
+                emitter.initialized_capacity = Some(capacity);
+            }
+        }
+    }
+}
+
+struct SimulatedEmitter {
+    particles_buf: rg::Handle<Buffer>,
+    max_particles: u32,
+    color_opacity: [f32; 4],
+    lit: bool,
+}
+
+/// Particle buffers after this frame's simulation, ready to be drawn.
+pub struct SimulatedParticles {
+    emitters: Vec<SimulatedEmitter>,
+}
+
+impl SimulatedParticles {
+    pub fn is_empty(&self) -> bool {
+        self.emitters.is_empty()
+    }
+
+    /// Draws billboards of all particles into `targets`, depth-tested against `depth`.
+    /// Particles aren't sorted, neither among themselves nor with other transparent surfaces.
+    #[allow(clippy::too_many_arguments)]
+    pub(super) fn raster(
+        &self,
+        rg: &mut rg::RenderGraph,
+        render_pass: Arc<RenderPass>,
+        pixel_shader: &'static str,
+        blend_mode: RasterBlendMode,
+        oit_weight: [f32; 4],
+        scene_depth: &rg::Handle<Image>,
+        sky_cube: &rg::Handle<Image>,
+        depth: &mut rg::Handle<Image>,
+        targets: &mut [&mut rg::Handle<Image>],
+    ) {
+        if self.is_empty() {
+            return;
+        }
+
+        let mut pass = rg.add_pass("particle billboards");
+
+        let pipeline = pass.register_raster_pipeline(
+            &[
+                PipelineShaderDesc::builder(ShaderPipelineStage::Vertex)
+                    .hlsl_source("/shaders/particles/billboard_vs.hlsl")
+                    .build()
+                    .unwrap(),
+                PipelineShaderDesc::builder(ShaderPipelineStage::Pixel)
+                    .hlsl_source(pixel_shader)
+                    .build()
+                    .unwrap(),
+            ],
+            RasterPipelineDesc::builder()
+                .render_pass(render_pass.clone())
+                .face_cull(false)
+                .depth_write(false)
+                .blend_mode(blend_mode),
+        );
+
+        let particles_refs: Vec<_> = self
+            .emitters
+            .iter()
+            .map(|emitter| pass.read(&emitter.particles_buf, AccessType::AnyShaderReadOther))
+            .collect();
+        let scene_depth_ref = pass.read(
+            scene_depth,
+            AccessType::AnyShaderReadSampledImageOrUniformTexelBuffer,
+        );
+        let sky_cube_ref = pass.read(
+            sky_cube,
+            AccessType::AnyShaderReadSampledImageOrUniformTexelBuffer,
+        );
+
+        // Depth testing only, like transparent surfaces
+        let depth_ref = pass.raster(depth, AccessType::DepthStencilAttachmentRead);
+        let target_refs: Vec<_> = targets
+            .iter_mut()
+            .map(|target| pass.raster(&mut **target, AccessType::ColorAttachmentReadWrite))
+            .collect();
+
+        let [width, height, _] = target_refs[0].desc().extent;
+        let draws: Vec<(u32, ParticleDrawConstants)> = self
+            .emitters
+            .iter()
+            .map(|emitter| {
+                (
+                    emitter.max_particles,
+                    ParticleDrawConstants {
+                        color_opacity: emitter.color_opacity,
+                        oit_weight,
+                        lit: emitter.lit as u32,
+                    },
+                )
+            })
+            .collect();
+
+        pass.render(move |api| {
+            let color_view_desc = ImageViewDesc::default();
+            let color_attachments: Vec<_> = target_refs
+                .into_iter()
+                .map(|target_ref| (target_ref, &color_view_desc))
+                .collect();
+
+            api.begin_render_pass(
+                &*render_pass,
+                [width, height],
+                &color_attachments,
+                Some((
+                    depth_ref,
+                    &ImageViewDesc::builder()
+                        .aspect_mask(vk::ImageAspectFlags::DEPTH)
+                        .build()
+                        .unwrap(),
+                )),
+            );
+
+            api.set_default_view_and_scissor([width, height]);
+
+            // Each emitter has its own particle buffer, so the pipeline is re-bound for every draw.
+            for (particles_ref, (max_particles, draw_constants)) in
+                particles_refs.iter().zip(draws.iter())
+            {
+                let bound_pipeline = match api.bind_raster_pipeline(
+                    pipeline
+                        .into_binding()
+                        .named_binding("particles_buf", particles_ref.bind())
+                        .named_binding("scene_depth_tex", scene_depth_ref.bind())
+                        .named_binding("sky_cube_tex", sky_cube_ref.bind()),
+                ) {
+                    Ok(pipeline) => pipeline,
+                    Err(err) => {
+                        log::error!("particle billboards: {}", err);
+                        break;
+                    }
+                };
+
+                unsafe {
+                    let raw_device = &api.device().raw;
+                    let cb = api.cb;
+
+                    if let Err(err) = bound_pipeline.push_constants(
+                        cb.raw,
+                        vk::ShaderStageFlags::ALL_GRAPHICS,
+                        0,
+                        bytemuck::bytes_of(draw_constants),
+                    ) {
+                        log::error!("particle billboards: {}", err);
+                        break;
+                    }
+
+                    raw_device.cmd_draw(cb.raw, max_particles * 6, 1, 0, 0);
+                }
+            }
+
+            api.end_render_pass();
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::{CameraLens, LookThroughCamera};
+    use glam::{Quat, Vec4};
+    use kajiya_backend::{file::set_standard_vfs_mount_points, HeadlessRenderBackend};
+    use rg::{
+        readback::AsyncReadback,
+        renderer::{FrameConstantsLayout, Renderer},
+    };
+    use rust_shaders_shared::{frame_constants::FrameConstants, view_constants::ViewConstants};
+
+    // Runs `particles/emit.hlsl` `steps` times at `emission_rate`, frame time `dt`, and capacity `max_particles`,
+    // starting from a reset emitter. Returns the emitter state after each step, as
+    // `(next_slot, spawn_first, spawn_count)`.
+    fn emit_on_gpu(
+        emission_rate: f32,
+        dt: f32,
+        max_particles: u32,
+        steps: usize,
+    ) -> Vec<(u32, u32, u32)> {
+        set_standard_vfs_mount_points(concat!(env!("CARGO_MANIFEST_DIR"), "/../../.."));
+
+        let backend = HeadlessRenderBackend::new(Default::default()).unwrap();
+        let device = backend.device.clone();
+        let mut renderer = Renderer::new(&device).unwrap();
+        let mut readback = AsyncReadback::default();
+
+        let camera = (Vec3::ZERO, Quat::IDENTITY).through(&CameraLens::default());
+        let frame_constants = FrameConstants {
+            view_constants: ViewConstants::builder(camera, camera, [1, 1]).build(),
+            sun_direction: Vec4::Y,
+            frame_index: 0,
+            delta_time_seconds: dt,
+            sun_angular_radius_cos: 1.0,
+            triangle_light_count: 0,
+            sun_color_multiplier: Vec4::ONE,
+            sky_ambient: Vec4::ZERO,
+            world_gi_scale: 1.0,
+            ray_origin_offset_method: 0,
+            ray_origin_offset_scale: 0.0,
+            pad0: 0,
+            gi_cascades: Default::default(),
+        };
+
+        readback.begin_frame();
+        let mut tokens = Vec::with_capacity(steps);
+        renderer
+            .prepare_frame(|rg| {
+                let mut state_buf = rg.create(BufferDesc::new_gpu_only(
+                    EMITTER_STATE_SIZE,
+                    vk::BufferUsageFlags::STORAGE_BUFFER,
+                ));
+
+                for step in 0..steps {
+                    let constants = ParticleEmitterConstants {
+                        position_radius: [0.0; 4],
+                        initial_velocity_randomness: [0.0; 4],
+                        acceleration_drag: [0.0; 4],
+                        emission_rate,
+                        lifetime: 1.0,
+                        start_size: 1.0,
+                        end_size: 1.0,
+                        max_particles,
+                        reset: (step == 0) as u32,
+                        seed: 0,
+                        pad0: 0,
+                        origin_shift: [0.0; 4],
+                    };
+
+                    SimpleRenderPass::new_compute(
+                        rg.add_pass("particle emit"),
+                        "/shaders/particles/emit.hlsl",
+                    )
+                    .read_write(&mut state_buf)
+                    .constants(constants)
+                    .dispatch([1, 1, 1]);
+
+                    tokens.push(readback.copy_buffer(rg, &state_buf));
+                }
+            })
+            .unwrap();
+        renderer
+            .draw_frame_headless(|dynamic_constants| FrameConstantsLayout {
+                globals_offset: dynamic_constants.push(&frame_constants),
+                instance_dynamic_parameters_offset: 0,
+                triangle_lights_offset: 0,
+            })
+            .unwrap();
+        readback.retire_frame(device.frame_counter());
+
+        tokens
+            .into_iter()
+            .map(|token| {
+                let bytes = readback.wait(&device, token).unwrap();
+                let state: &[u32] = bytemuck::cast_slice(&bytes);
+                (state[1], state[2], state[3])
+            })
+            .collect()
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn emission_follows_rate_across_frames() {
+        // 25 particles per second at 60 fps is less than one per frame.
+        let states = emit_on_gpu(25.0, 1.0 / 60.0, 1024, 120);
+        let spawned: u32 = states.iter().map(|&(_, _, count)| count).sum();
+
+        // Two seconds' worth, give or take rounding of the fractional leftover
+        assert!((49..=50).contains(&spawned), "{}", spawned);
+        assert_eq!(states.last().unwrap().0, spawned);
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn spawn_slots_wrap_around_the_particle_buffer() {
+        let states = emit_on_gpu(6.0, 1.0, 8, 2);
+        assert_eq!(states, vec![(6, 0, 6), (4, 6, 6)]);
+
+        // Never more than the buffer holds in a single frame
+        let states = emit_on_gpu(100.0, 1.0, 8, 2);
+        assert_eq!(states, vec![(0, 0, 8), (0, 0, 8)]);
+    }
+}
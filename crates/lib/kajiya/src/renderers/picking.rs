@@ -0,0 +1,227 @@
+// Picking of instances via readbacks of the object ID buffer.
+//
+// Pick requests are batched into the next frame, which copies the requested texels of its object IDs
+// to the host through an `AsyncReadback`. The results are read once the GPU is done with that frame,
+// a few frames later, so picking never stalls the pipeline.
+
+use std::{collections::HashMap, task::Poll};
+
+use kajiya_backend::{vulkan::image::*, Device};
+use kajiya_rg::{
+    self as rg,
+    readback::{AsyncReadback, ReadbackToken},
+};
+
+use crate::world_renderer::InstanceHandle;
+
+/// Requests beyond this many per frame are deferred to the following frames.
+pub const MAX_PICKS_PER_FRAME: usize = 64;
+
+#[derive(Clone, Copy, Hash, PartialEq, Eq, Debug)]
+pub struct PickToken(u64);
+
+// The picks copied in one frame, one texel each
+struct PickBatch {
+    readback: ReadbackToken,
+    picks: Vec<(PickToken, [f32; 2])>,
+
+    // Indexed by object ID - 1, as of the frame of the copy
+    instance_handles: Vec<InstanceHandle>,
+}
+
+#[derive(Default)]
+pub struct PickingReadback {
+    readback: AsyncReadback,
+
+    // Copied in the frame being recorded, until it's retired
+    recorded: Option<PickBatch>,
+    in_flight: Vec<PickBatch>,
+
+    // Positions as fractions of the output image
+    queued: Vec<(PickToken, [f32; 2])>,
+    results: HashMap<PickToken, Option<InstanceHandle>>,
+    next_token: u64,
+}
+
+impl PickingReadback {
+    /// Queues a pick at `uv` within the output image. Positions outside of it resolve to `None` immediately.
+    pub fn request(&mut self, uv: [f32; 2]) -> PickToken {
+        let token = PickToken(self.next_token);
+        self.next_token += 1;
+
+        if uv_in_bounds(uv) {
+            self.queued.push((token, uv));
+        } else {
+            self.results.insert(token, None);
+        }
+
+        token
+    }
+
+    /// Starts recording a new frame. The picks of a frame which failed to render are tried again.
+    pub fn begin_frame(&mut self) {
+        if let Some(mut batch) = self.recorded.take() {
+            self.readback.discard(batch.readback);
+            batch.picks.append(&mut self.queued);
+            self.queued = batch.picks;
+        }
+
+        self.readback.begin_frame();
+    }
+
+    /// Whether the next frame needs to write object IDs.
+    pub fn has_queued_requests(&self) -> bool {
+        !self.queued.is_empty()
+    }
+
+    /// Copies the object IDs at queued pick positions to the host.
+    pub fn copy_to_host(
+        &mut self,
+        rg: &mut rg::TemporalRenderGraph,
+        object_id_img: &rg::Handle<Image>,
+        instance_handles: &[InstanceHandle],
+    ) {
+        if self.queued.is_empty() {
+            return;
+        }
+
+        let extent = object_id_img.desc().extent_2d();
+        let batch_len = self.queued.len().min(MAX_PICKS_PER_FRAME);
+        let picks: Vec<_> = self.queued.drain(..batch_len).collect();
+
+        let texels: Vec<[u32; 2]> = picks.iter().map(|(_, uv)| uv_to_px(*uv, extent)).collect();
+        let readback =
+            self.readback
+                .copy_image_texels(rg, object_id_img, &texels, std::mem::size_of::<u32>());
+
+        self.recorded = Some(PickBatch {
+            readback,
+            picks,
+            instance_handles: instance_handles.to_vec(),
+        });
+    }
+
+    /// Marks the copy scheduled by `copy_to_host` as submitted in the frame `frame_counter`.
+    pub fn retire_frame(&mut self, frame_counter: u64) {
+        self.readback.retire_frame(frame_counter);
+        self.in_flight.extend(self.recorded.take());
+    }
+
+    /// The instance picked by `token`, or `None` over the background. Each result is returned once;
+    /// unknown tokens resolve to `None`.
+    pub fn poll(&mut self, device: &Device, token: PickToken) -> Poll<Option<InstanceHandle>> {
+        self.harvest(device);
+
+        if let Some(result) = self.results.remove(&token) {
+            return Poll::Ready(result);
+        }
+
+        let is_in_flight = self
+            .queued
+            .iter()
+            .chain(self.recorded.iter().flat_map(|batch| batch.picks.iter()))
+            .chain(self.in_flight.iter().flat_map(|batch| batch.picks.iter()))
+            .any(|(pick, _)| *pick == token);
+
+        if is_in_flight {
+            Poll::Pending
+        } else {
+            Poll::Ready(None)
+        }
+    }
+
+    // Moves the results out of the batches which the GPU is done copying.
+    fn harvest(&mut self, device: &Device) {
+        for batch in std::mem::take(&mut self.in_flight) {
+            let bytes = match self.readback.poll(device, batch.readback) {
+                Poll::Ready(bytes) => bytes.unwrap_or_default(),
+                Poll::Pending => {
+                    self.in_flight.push(batch);
+                    continue;
+                }
+            };
+
+            // Vecs of bytes aren't necessarily aligned for `bytemuck::cast_slice`.
+            let object_ids: Vec<u32> = bytes
+                .chunks_exact(4)
+                .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+                .collect();
+
+            for (i, (token, _)) in batch.picks.into_iter().enumerate() {
+                let result = object_ids
+                    .get(i)
+                    .and_then(|&object_id| decode_object_id(object_id, &batch.instance_handles));
+                self.results.insert(token, result);
+            }
+        }
+    }
+}
+
+fn uv_in_bounds(uv: [f32; 2]) -> bool {
+    (0.0..1.0).contains(&uv[0]) && (0.0..1.0).contains(&uv[1])
+}
+
+fn uv_to_px(uv: [f32; 2], extent: [u32; 2]) -> [u32; 2] {
+    [
+        ((uv[0] * extent[0] as f32) as u32).min(extent[0] - 1),
+        ((uv[1] * extent[1] as f32) as u32).min(extent[1] - 1),
+    ]
+}
+
+/// The instance drawn with `object_id`, or `None` for the background.
+fn decode_object_id(object_id: u32, instance_handles: &[InstanceHandle]) -> Option<InstanceHandle> {
+    let draw_index = object_id.checked_sub(1)?;
+    instance_handles.get(draw_index as usize).copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_each_of_two_objects() {
+        // Two instances drawn over the background: a box on the left, partially covered by one on the right.
+        let handles = [InstanceHandle(7), InstanceHandle(3)];
+        let extent = [8, 4];
+        let object_ids: Vec<u32> = (0..extent[0] * extent[1])
+            .map(|i| match (i % extent[0], i / extent[0]) {
+                (4..=6, 0..=2) => 2,
+                (1..=4, 1..=3) => 1,
+                _ => 0,
+            })
+            .collect();
+
+        let pick = |uv: [f32; 2]| {
+            if !uv_in_bounds(uv) {
+                return None;
+            }
+
+            let px = uv_to_px(uv, extent);
+            decode_object_id(object_ids[(px[1] * extent[0] + px[0]) as usize], &handles)
+        };
+
+        assert_eq!(pick([0.2, 0.6]), Some(InstanceHandle(7)));
+        assert_eq!(pick([0.7, 0.2]), Some(InstanceHandle(3)));
+
+        // Where they overlap, the ID buffer holds the one in front.
+        assert_eq!(pick([4.5 / 8.0, 0.3]), Some(InstanceHandle(3)));
+
+        assert_eq!(pick([0.01, 0.01]), None);
+        assert_eq!(pick([0.95, 0.95]), None);
+        assert_eq!(pick([1.5, 0.5]), None);
+        assert_eq!(pick([0.5, -0.1]), None);
+    }
+
+    #[test]
+    fn out_of_bounds_requests_resolve_immediately() {
+        let mut picking = PickingReadback::default();
+
+        let outside = picking.request([-0.25, 0.5]);
+        let inside = picking.request([0.25, 0.5]);
+        assert_ne!(outside, inside);
+
+        assert!(picking.has_queued_requests());
+        assert_eq!(picking.results.remove(&outside), Some(None));
+        assert!(!picking.results.contains_key(&inside));
+    }
+}
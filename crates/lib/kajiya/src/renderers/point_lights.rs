@@ -0,0 +1,824 @@
+// Point lights, with cube shadow maps, which work without ray tracing.
+//
+// Each light renders depth to the six faces of a cube around it, through a 90 degree perspective
+// projection per face. The faces of all lights share a depth atlas: a row of six tiles per light,
+// at that light's resolution, with the rows stacked top to bottom. A compute pass then adds the
+// light of each, filtered with PCF against its cube, to the lit gbuffer.
+//
+// Like the sun's cascades, the atlas persists across frames, and a light's faces are only
+// re-rendered as its `ShadowUpdatePolicy` requires. Distant or static lights can update
+// less often than the ones next to the camera.
+
+use std::sync::Arc;
+
+use glam::{Affine3A, Mat4, Vec3};
+use kajiya_backend::{
+    ash::vk,
+    vk_sync::AccessType,
+    vulkan::{image::*, shader::*},
+    Device,
+};
+use kajiya_rg::{self as rg, GetOrCreateTemporal};
+use rg::{IntoRenderPassPipelineBinding, RenderPassBinding, SimpleRenderPass};
+
+use crate::world_renderer::MeshHandle;
+
+use super::{
+    csm::ShadowUpdatePolicy,
+    raster_meshes::{row_major_3x4, InstanceTransform, RasterMeshesData},
+    GbufferDepth,
+};
+
+/// Must match `MAX_POINT_LIGHTS` in `point_lights/light_point_lights.hlsl`. Further lights
+/// are ignored.
+pub const MAX_POINT_LIGHTS: usize = 8;
+
+/// Distance from a light at which its shadow maps start. Nearer casters don't shadow it.
+pub const SHADOW_NEAR_DISTANCE: f32 = 0.05;
+
+// Directions of the cube faces, in the order of their tiles, with the up vector of each
+const CUBE_FACES: [([f32; 3], [f32; 3]); 6] = [
+    ([1.0, 0.0, 0.0], [0.0, 1.0, 0.0]),
+    ([-1.0, 0.0, 0.0], [0.0, 1.0, 0.0]),
+    ([0.0, 1.0, 0.0], [0.0, 0.0, 1.0]),
+    ([0.0, -1.0, 0.0], [0.0, 0.0, 1.0]),
+    ([0.0, 0.0, 1.0], [0.0, 1.0, 0.0]),
+    ([0.0, 0.0, -1.0], [0.0, 1.0, 0.0]),
+];
+
+#[derive(Clone, Copy, Debug)]
+pub struct PointLight {
+    pub position: Vec3,
+
+    /// Radiance of the light in the units of the sun's color, scaled by the inverse square
+    /// of the distance to it.
+    pub color: Vec3,
+
+    /// Distance at which the light fades out. Also bounds its shadow maps.
+    pub range: f32,
+
+    /// Width and height of each face of the light's cube shadow map.
+    pub shadow_resolution: u32,
+
+    /// All six faces are re-rendered together.
+    pub shadow_update_policy: ShadowUpdatePolicy,
+}
+
+impl PointLight {
+    pub fn new(position: Vec3, color: Vec3, range: f32) -> Self {
+        Self {
+            position,
+            color,
+            range,
+            shadow_resolution: 512,
+            shadow_update_policy: ShadowUpdatePolicy::Static,
+        }
+    }
+
+    /// To the clip space of each face of the cube around the light: x and y in [-1, 1] across
+    /// the face, and depth from 1.0 at `SHADOW_NEAR_DISTANCE` to 0.0 at `range`.
+    pub fn world_to_shadow(&self) -> [Mat4; 6] {
+        // Near and far swapped, for reverse Z
+        let projection = Mat4::perspective_rh(
+            std::f32::consts::FRAC_PI_2,
+            1.0,
+            self.range.max(SHADOW_NEAR_DISTANCE * 2.0),
+            SHADOW_NEAR_DISTANCE,
+        );
+
+        CUBE_FACES.map(|(direction, up)| {
+            let target = self.position + Vec3::from(direction);
+            projection * Mat4::look_at_rh(self.position, target, Vec3::from(up))
+        })
+    }
+}
+
+/// Where the faces of each light go in the atlas.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CubeShadowAtlas {
+    pub extent: [u32; 2],
+
+    /// Top-left corner of the row of six tiles of each light, in texels
+    pub offsets: Vec<[u32; 2]>,
+}
+
+impl CubeShadowAtlas {
+    pub fn new(lights: &[PointLight]) -> Self {
+        let mut height = 0;
+        let offsets = lights
+            .iter()
+            .map(|light| {
+                let offset = [0, height];
+                height += light.shadow_resolution.max(1);
+                offset
+            })
+            .collect();
+
+        let width = lights
+            .iter()
+            .map(|light| 6 * light.shadow_resolution.max(1))
+            .max()
+            .unwrap_or(0);
+
+        Self {
+            extent: [width, height],
+            offsets,
+        }
+    }
+}
+
+// Must match the push constants in `csm/shadow_map_vs.hlsl`
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ShadowMapPushConstants {
+    world_to_shadow: [f32; 16],
+    draw_index: u32,
+    mesh_index: u32,
+}
+
+unsafe impl bytemuck::Zeroable for ShadowMapPushConstants {}
+unsafe impl bytemuck::Pod for ShadowMapPushConstants {}
+
+// Must match `PointLight` in `point_lights/light_point_lights.hlsl`
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct PointLightConstants {
+    world_to_shadow: [[f32; 16]; 6],
+    position_range: [f32; 4],
+    color: [f32; 4],
+    atlas_offset: [u32; 2],
+    shadow_map_resolution: f32,
+    pad0: u32,
+}
+
+// Must match the constants in `point_lights/light_point_lights.hlsl`
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct LightPointLightsConstants {
+    lights: [PointLightConstants; MAX_POINT_LIGHTS],
+    light_count: u32,
+    write_direct_aov: u32,
+    pcf_radius: f32,
+    pad0: u32,
+}
+
+// A light's faces, as last rendered
+#[derive(Clone, Copy)]
+struct CachedCube {
+    position: Vec3,
+    range: f32,
+    rendered_frame: u32,
+}
+
+// Tracks which lights in the atlas are up to date.
+#[derive(Clone, Default)]
+struct CubeShadowCache {
+    // Layout and depth bias of the atlas; changing them invalidates all lights.
+    atlas_key: Option<(CubeShadowAtlas, [f32; 2])>,
+    cubes: Vec<CachedCube>,
+}
+
+impl CubeShadowCache {
+    // Picks the lights to re-render this frame, and records them as rendered.
+    fn update(
+        &mut self,
+        lights: &[PointLight],
+        atlas: &CubeShadowAtlas,
+        depth_bias: [f32; 2],
+        casters_changed: bool,
+        frame_index: u32,
+    ) -> Vec<usize> {
+        let rendered = |light: &PointLight| CachedCube {
+            position: light.position,
+            range: light.range,
+            rendered_frame: frame_index,
+        };
+
+        let atlas_key = Some((atlas.clone(), depth_bias));
+        if self.atlas_key != atlas_key {
+            self.atlas_key = atlas_key;
+            self.cubes = lights.iter().map(rendered).collect();
+            return (0..lights.len()).collect();
+        }
+
+        let due: Vec<usize> = lights
+            .iter()
+            .zip(&self.cubes)
+            .enumerate()
+            .filter(|(_, (light, cached))| {
+                let moved = cached.position != light.position || cached.range != light.range;
+                let age = frame_index.wrapping_sub(cached.rendered_frame);
+
+                match light.shadow_update_policy {
+                    ShadowUpdatePolicy::Static => moved || casters_changed,
+                    ShadowUpdatePolicy::Dynamic => true,
+                    ShadowUpdatePolicy::EveryNFrames(n) => age >= n.max(1),
+                }
+            })
+            .map(|(i, _)| i)
+            .collect();
+
+        for &i in &due {
+            self.cubes[i] = rendered(&lights[i]);
+        }
+
+        due
+    }
+}
+
+// The state of the atlas after the frame being recorded; see `PointLightRenderer::retire_frame`.
+struct RecordedShadows {
+    cache: CubeShadowCache,
+    casters: Vec<(Affine3A, MeshHandle)>,
+    mesh_count: usize,
+}
+
+pub struct PointLightRenderer {
+    pub lights: Vec<PointLight>,
+
+    /// Radius of the PCF kernel, in shadow map texels.
+    pub pcf_radius: f32,
+
+    /// Constant and slope-scaled depth bias of shadow casters. Negative values push them
+    /// away from the light, against shadow acne.
+    pub depth_bias: [f32; 2],
+
+    render_pass: Arc<RenderPass>,
+    cache: CubeShadowCache,
+
+    // Shadow casters the cached maps were rendered with
+    casters: Vec<(Affine3A, MeshHandle)>,
+    mesh_count: usize,
+
+    recorded: Option<RecordedShadows>,
+    updated_light_count: usize,
+}
+
+impl PointLightRenderer {
+    pub fn new(device: &Device) -> Self {
+        Self {
+            lights: Vec::new(),
+            pcf_radius: 1.5,
+            depth_bias: [-1.0, -2.0],
+            render_pass: create_render_pass(
+                device,
+                RenderPassDesc {
+                    color_attachments: &[],
+                    depth_attachment: Some(RenderPassAttachmentDesc::new(vk::Format::D32_SFLOAT)),
+                },
+            ),
+            cache: Default::default(),
+            casters: Vec::new(),
+            mesh_count: 0,
+            recorded: None,
+            updated_light_count: 0,
+        }
+    }
+
+    /// Number of lights whose shadow maps were re-rendered in the last frame.
+    pub fn updated_light_count(&self) -> usize {
+        self.updated_light_count
+    }
+
+    /// Call once the frame last recorded is submitted. Until then, the maps rendered in it
+    /// aren't considered up to date, as the frame may still be dropped.
+    pub fn retire_frame(&mut self) {
+        if let Some(recorded) = self.recorded.take() {
+            self.cache = recorded.cache;
+            self.casters = recorded.casters;
+            self.mesh_count = recorded.mesh_count;
+        }
+    }
+
+    // Whether any instance was added, removed or moved since the cached maps were rendered.
+    fn casters_changed(&self, mesh_data: &RasterMeshesData<'_>) -> bool {
+        self.mesh_count != mesh_data.meshes.len()
+            || !mesh_data
+                .instances
+                .iter()
+                .map(|inst| (inst.transformation, inst.mesh))
+                .eq(self.casters.iter().copied())
+    }
+
+    /// Re-renders the shadow maps which are due, and adds the light of every point light
+    /// to `output`, as well as to `direct_aov` if given.
+    pub fn render(
+        &mut self,
+        rg: &mut rg::TemporalRenderGraph,
+        gbuffer_depth: &GbufferDepth,
+        mesh_data: RasterMeshesData<'_>,
+        output: &mut rg::Handle<Image>,
+        direct_aov: Option<&mut rg::Handle<Image>>,
+        frame_index: u32,
+    ) {
+        let lights: Vec<PointLight> = self.lights.iter().copied().take(MAX_POINT_LIGHTS).collect();
+        self.updated_light_count = 0;
+
+        if lights.is_empty() {
+            return;
+        }
+
+        let atlas = CubeShadowAtlas::new(&lights);
+        let bindless_descriptor_set = mesh_data.bindless_descriptor_set;
+
+        let casters_changed = self.casters_changed(&mesh_data);
+        let mut cache = self.cache.clone();
+        let updated_lights = cache.update(
+            &lights,
+            &atlas,
+            self.depth_bias,
+            casters_changed,
+            frame_index,
+        );
+        self.updated_light_count = updated_lights.len();
+
+        let mut shadow_map = rg
+            .get_or_create_temporal(
+                "point_lights.shadow_map",
+                ImageDesc::new_2d(vk::Format::D32_SFLOAT, atlas.extent).usage(
+                    vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
+                ),
+            )
+            .unwrap();
+
+        if !updated_lights.is_empty() {
+            let mut pass = rg.add_pass("point light shadow maps");
+
+            let pipeline = pass.register_raster_pipeline(
+                &[PipelineShaderDesc::builder(ShaderPipelineStage::Vertex)
+                    .hlsl_source("/shaders/csm/shadow_map_vs.hlsl")
+                    .build()
+                    .unwrap()],
+                RasterPipelineDesc::builder()
+                    .render_pass(self.render_pass.clone())
+                    .face_cull(false)
+                    .depth_bias(self.depth_bias)
+                    .push_constants_bytes(std::mem::size_of::<ShadowMapPushConstants>()),
+            );
+
+            let depth_ref = pass.raster(
+                &mut shadow_map,
+                AccessType::DepthAttachmentWriteStencilReadOnly,
+            );
+
+            let meshes = mesh_data.meshes.to_vec();
+            let instances = mesh_data.instances.to_vec();
+            let vertex_buffer = mesh_data.vertex_buffer.clone();
+            let render_pass = self.render_pass.clone();
+            let atlas_extent = atlas.extent;
+
+            // Tile rectangle and matrix of each face to render
+            let faces: Vec<(vk::Rect2D, Mat4)> = updated_lights
+                .iter()
+                .flat_map(|&i| {
+                    let resolution = lights[i].shadow_resolution.max(1);
+                    let [x, y] = atlas.offsets[i];
+                    lights[i].world_to_shadow().into_iter().enumerate().map(
+                        move |(face, world_to_shadow)| {
+                            let rect = vk::Rect2D {
+                                offset: vk::Offset2D {
+                                    x: (x + face as u32 * resolution) as i32,
+                                    y: y as i32,
+                                },
+                                extent: vk::Extent2D {
+                                    width: resolution,
+                                    height: resolution,
+                                },
+                            };
+                            (rect, world_to_shadow)
+                        },
+                    )
+                })
+                .collect();
+
+            pass.render(move |api| {
+                // Indexed by the position of the instance in `instances`, like in `raster_meshes`
+                let instance_transforms_offset =
+                    api.dynamic_constants()
+                        .push_from_iter(instances.iter().map(|inst| InstanceTransform {
+                            current: row_major_3x4(&inst.transformation),
+                            previous: row_major_3x4(&inst.prev_transformation),
+                        }));
+
+                api.begin_render_pass(
+                    &*render_pass,
+                    atlas_extent,
+                    &[],
+                    Some((
+                        depth_ref,
+                        &ImageViewDesc::builder()
+                            .aspect_mask(vk::ImageAspectFlags::DEPTH)
+                            .build()
+                            .unwrap(),
+                    )),
+                );
+
+                let pipeline = match api.bind_raster_pipeline(
+                    pipeline
+                        .into_binding()
+                        .storage_buffer(
+                            "instance_transforms_dyn",
+                            RenderPassBinding::DynamicConstantsStorageBuffer(
+                                instance_transforms_offset,
+                            ),
+                        )
+                        .bindless(bindless_descriptor_set),
+                ) {
+                    Ok(pipeline) => pipeline,
+                    Err(err) => {
+                        log::error!("point light shadows: {}", err);
+                        api.end_render_pass();
+                        return;
+                    }
+                };
+
+                unsafe {
+                    let raw_device = &api.device().raw;
+                    let cb = api.cb;
+
+                    'faces: for (tile_rect, world_to_shadow) in faces.iter() {
+                        // Only this face's tile; the others keep their cached maps.
+                        raw_device.cmd_clear_attachments(
+                            cb.raw,
+                            &[vk::ClearAttachment {
+                                aspect_mask: vk::ImageAspectFlags::DEPTH,
+                                color_attachment: 0,
+                                clear_value: vk::ClearValue {
+                                    depth_stencil: vk::ClearDepthStencilValue {
+                                        depth: 0.0,
+                                        stencil: 0,
+                                    },
+                                },
+                            }],
+                            &[vk::ClearRect {
+                                rect: *tile_rect,
+                                base_array_layer: 0,
+                                layer_count: 1,
+                            }],
+                        );
+
+                        // Flipped like `set_default_view_and_scissor`, for `cs_to_uv` in lighting
+                        raw_device.cmd_set_viewport(
+                            cb.raw,
+                            0,
+                            &[vk::Viewport {
+                                x: tile_rect.offset.x as f32,
+                                y: (tile_rect.offset.y as u32 + tile_rect.extent.height) as f32,
+                                width: tile_rect.extent.width as f32,
+                                height: -(tile_rect.extent.height as f32),
+                                min_depth: 0.0,
+                                max_depth: 1.0,
+                            }],
+                        );
+                        raw_device.cmd_set_scissor(cb.raw, 0, &[*tile_rect]);
+
+                        for (draw_idx, instance) in instances.iter().enumerate() {
+                            let mesh = &meshes[instance.mesh.0];
+
+                            raw_device.cmd_bind_index_buffer(
+                                cb.raw,
+                                vertex_buffer.raw,
+                                mesh.index_buffer_offset,
+                                vk::IndexType::UINT32,
+                            );
+
+                            let push_constants = ShadowMapPushConstants {
+                                world_to_shadow: world_to_shadow.to_cols_array(),
+                                draw_index: draw_idx as u32,
+                                mesh_index: instance.mesh.0 as u32,
+                            };
+
+                            if let Err(err) = pipeline.push_constants(
+                                cb.raw,
+                                vk::ShaderStageFlags::ALL_GRAPHICS,
+                                0,
+                                bytemuck::bytes_of(&push_constants),
+                            ) {
+                                log::error!("point light shadows: {}", err);
+                                break 'faces;
+                            }
+
+                            raw_device.cmd_draw_indexed(cb.raw, mesh.index_count, 1, 0, 0, 0);
+                        }
+                    }
+                }
+
+                api.end_render_pass();
+            });
+        }
+
+        let mut constants = LightPointLightsConstants {
+            lights: [PointLightConstants {
+                world_to_shadow: [[0.0; 16]; 6],
+                position_range: [0.0; 4],
+                color: [0.0; 4],
+                atlas_offset: [0; 2],
+                shadow_map_resolution: 0.0,
+                pad0: 0,
+            }; MAX_POINT_LIGHTS],
+            light_count: lights.len() as u32,
+            write_direct_aov: direct_aov.is_some() as u32,
+            pcf_radius: self.pcf_radius.max(0.0),
+            pad0: 0,
+        };
+        for (i, (light, cached)) in lights.iter().zip(&cache.cubes).enumerate() {
+            // Shadowed from where the maps were rendered, which may lag behind the light.
+            let shadow_light = PointLight {
+                position: cached.position,
+                range: cached.range,
+                ..*light
+            };
+
+            constants.lights[i] = PointLightConstants {
+                world_to_shadow: shadow_light.world_to_shadow().map(|m| m.to_cols_array()),
+                position_range: light.position.extend(light.range).to_array(),
+                color: light.color.extend(0.0).to_array(),
+                atlas_offset: atlas.offsets[i],
+                shadow_map_resolution: light.shadow_resolution.max(1) as f32,
+                pad0: 0,
+            };
+        }
+
+        let mut direct_aov_dummy;
+        let direct_aov = if let Some(direct_aov) = direct_aov {
+            direct_aov
+        } else {
+            direct_aov_dummy = rg
+                .get_or_create_temporal(
+                    "point_lights.dummy_direct_aov",
+                    ImageDesc::new_2d(vk::Format::R16G16B16A16_SFLOAT, [1, 1])
+                        .usage(vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::STORAGE),
+                )
+                .unwrap();
+            &mut direct_aov_dummy
+        };
+
+        let extent = output.desc().extent;
+        SimpleRenderPass::new_compute(
+            rg.add_pass("light point lights"),
+            "/shaders/point_lights/light_point_lights.hlsl",
+        )
+        .read(&gbuffer_depth.gbuffer)
+        .read_aspect(&gbuffer_depth.depth, vk::ImageAspectFlags::DEPTH)
+        .read(&gbuffer_depth.geometric_normal)
+        .read_aspect(&shadow_map, vk::ImageAspectFlags::DEPTH)
+        .write(output)
+        .write(direct_aov)
+        .bindless(bindless_descriptor_set)
+        .constants(constants)
+        .dispatch(extent);
+
+        self.recorded = Some(RecordedShadows {
+            cache,
+            casters: mesh_data
+                .instances
+                .iter()
+                .map(|inst| (inst.transformation, inst.mesh))
+                .collect(),
+            mesh_count: mesh_data.meshes.len(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        camera::{CameraLens, CameraMatrices, LookThroughCamera},
+        frame_desc::{FrameTime, WorldFrameDesc},
+        renderers::aov::AovKind,
+        world_renderer::{AddMeshOptions, WorldRenderer},
+    };
+    use glam::{Quat, Vec4};
+    use kajiya_backend::{file::set_standard_vfs_mount_points, HeadlessRenderBackend};
+    use rg::renderer::Renderer;
+    use std::task::Poll;
+    use turbosloth::LazyCache;
+
+    const EXTENT: [u32; 2] = [640, 360];
+
+    #[test]
+    fn faces_cover_every_direction_out_to_the_range() {
+        let light = PointLight::new(Vec3::new(1.0, 2.0, -3.0), Vec3::ONE, 10.0);
+        let faces = light.world_to_shadow();
+
+        let project = |face: usize, p: Vec3| {
+            let clip = faces[face] * p.extend(1.0);
+            clip.truncate() / clip.w
+        };
+
+        // The face along the major axis of each direction sees it, with depth falling
+        // with distance.
+        for direction in [
+            Vec3::new(1.0, 0.2, -0.3),
+            Vec3::new(-0.9, 0.8, 0.1),
+            Vec3::new(0.3, 1.0, 0.99),
+            Vec3::new(-0.5, -1.0, 0.4),
+            Vec3::new(0.7, -0.1, 1.0),
+            Vec3::new(0.0, 0.6, -1.0),
+        ] {
+            let direction = direction.normalize();
+            let abs = direction.abs();
+            let face = if abs.x >= abs.y && abs.x >= abs.z {
+                (direction.x < 0.0) as usize
+            } else if abs.y >= abs.z {
+                2 + (direction.y < 0.0) as usize
+            } else {
+                4 + (direction.z < 0.0) as usize
+            };
+
+            let near = project(
+                face,
+                light.position + direction * SHADOW_NEAR_DISTANCE * 2.0,
+            );
+            let far = project(face, light.position + direction * light.range * 0.5);
+            for p in [near, far] {
+                assert!(p.x.abs() <= 1.0 && p.y.abs() <= 1.0, "{:?}", p);
+                assert!(p.z > 0.0 && p.z < 1.0, "{:?}", p);
+            }
+            assert!(near.z > far.z);
+        }
+
+        let edge = project(0, light.position + Vec3::X * SHADOW_NEAR_DISTANCE);
+        assert!((edge.z - 1.0).abs() < 1e-4);
+        let edge = project(0, light.position + Vec3::X * light.range);
+        assert!(edge.z.abs() < 1e-4);
+    }
+
+    #[test]
+    fn atlas_stacks_a_row_of_faces_per_light() {
+        let light = |shadow_resolution| PointLight {
+            shadow_resolution,
+            ..PointLight::new(Vec3::ZERO, Vec3::ONE, 10.0)
+        };
+
+        let atlas = CubeShadowAtlas::new(&[light(256), light(512), light(128)]);
+        assert_eq!(atlas.extent, [6 * 512, 256 + 512 + 128]);
+        assert_eq!(atlas.offsets, vec![[0, 0], [0, 256], [0, 768]]);
+    }
+
+    #[test]
+    fn lights_update_as_their_policy_requires() {
+        let near = PointLight::new(Vec3::ZERO, Vec3::ONE, 10.0);
+        let distant = PointLight {
+            shadow_update_policy: ShadowUpdatePolicy::EveryNFrames(4),
+            ..PointLight::new(Vec3::new(50.0, 0.0, 0.0), Vec3::ONE, 10.0)
+        };
+        let mut lights = [near, distant];
+        let atlas = CubeShadowAtlas::new(&lights);
+
+        let mut cache = CubeShadowCache::default();
+        assert_eq!(
+            cache.update(&lights, &atlas, [0.0; 2], false, 0),
+            vec![0, 1]
+        );
+
+        // The static light waits for a change; the distant one renders every 4 frames.
+        let updates: Vec<Vec<usize>> = (1..=8)
+            .map(|frame| cache.update(&lights, &atlas, [0.0; 2], false, frame))
+            .collect();
+        assert!(updates.iter().enumerate().all(|(i, due)| if i % 4 == 3 {
+            due == &[1]
+        } else {
+            due.is_empty()
+        }));
+
+        // Moving re-renders the static light at once, and other casters moving too.
+        lights[0].position.y += 1.0;
+        assert_eq!(cache.update(&lights, &atlas, [0.0; 2], false, 9), vec![0]);
+        assert_eq!(cache.update(&lights, &atlas, [0.0; 2], true, 10), vec![0]);
+
+        // A new resolution re-packs the atlas.
+        lights[1].shadow_resolution = 128;
+        let atlas = CubeShadowAtlas::new(&lights);
+        assert_eq!(
+            cache.update(&lights, &atlas, [0.0; 2], false, 11),
+            vec![0, 1]
+        );
+    }
+
+    // The direct lighting AOV of a floor under a 2x2m slab at a height of 2m
+    fn render_direct(
+        backend: &HeadlessRenderBackend,
+        camera_matrices: CameraMatrices,
+        lights: Vec<PointLight>,
+    ) -> Vec<[f32; 4]> {
+        let lazy_cache = LazyCache::create();
+        let mut world_renderer =
+            WorldRenderer::new(EXTENT, EXTENT, &backend.device, &lazy_cache).unwrap();
+
+        // A 20x2x20m box, with its top at 0
+        let floor = world_renderer
+            .add_baked_mesh("/baked/floor.mesh", AddMeshOptions::new())
+            .unwrap();
+        world_renderer.add_instance(floor, Affine3A::IDENTITY);
+        world_renderer.add_instance(
+            floor,
+            Affine3A::from_scale_rotation_translation(
+                Vec3::splat(0.1),
+                Quat::IDENTITY,
+                Vec3::new(0.0, 2.2, 0.0),
+            ),
+        );
+
+        // No ray tracing needed
+        world_renderer.use_sun_shadow_maps = true;
+        world_renderer.sun_size_multiplier = 0.0;
+        world_renderer.point_lights.lights = lights;
+
+        let frame_desc = WorldFrameDesc {
+            camera_matrices,
+            render_extent: EXTENT,
+            sun_direction: Vec3::new(1.0, 2.0, 0.5).normalize(),
+            time: FrameTime::fixed_step(0, 1.0 / 60.0),
+        };
+
+        let mut renderer = Renderer::new(&backend.device).unwrap();
+        let token = world_renderer.request_aov_readback(AovKind::Direct);
+        let mut image = None;
+
+        for _ in 0..=backend.device.frames_in_flight() + 1 {
+            renderer
+                .prepare_frame(|rg| {
+                    world_renderer.prepare_render_graph(rg, &frame_desc);
+                })
+                .unwrap();
+            renderer
+                .draw_frame_headless(|dynamic_constants| {
+                    world_renderer.prepare_frame_constants(dynamic_constants, &frame_desc)
+                })
+                .unwrap();
+            world_renderer.retire_frame();
+
+            if image.is_none() {
+                if let Poll::Ready(result) = world_renderer.poll_aov_readback(token) {
+                    image = Some(result.expect("AOV not available").to_rgba_f32().unwrap());
+                }
+            }
+        }
+
+        image.expect("AOV not read back")
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device, and `/baked/floor.mesh`"]
+    fn point_light_casts_cube_mapped_shadow() {
+        set_standard_vfs_mount_points(concat!(env!("CARGO_MANIFEST_DIR"), "/../../.."));
+        let backend = HeadlessRenderBackend::new(Default::default()).unwrap();
+
+        let camera_matrices = (
+            Vec3::new(0.0, 3.0, 8.0),
+            Quat::from_rotation_x(-20.0f32.to_radians()),
+        )
+            .through(&CameraLens {
+                aspect_ratio: EXTENT[0] as f32 / EXTENT[1] as f32,
+                ..Default::default()
+            });
+
+        // Above the middle of the slab, which shadows the floor out to about 2m from the center.
+        // Lit through the bottom face of the cube, and the side ones further out.
+        let light = PointLight::new(Vec3::new(0.0, 4.0, 0.0), Vec3::splat(20.0), 20.0);
+
+        // Only the light's contribution; the sun lights both renders the same.
+        let with_light = render_direct(&backend, camera_matrices, vec![light]);
+        let without_light = render_direct(&backend, camera_matrices, Vec::new());
+
+        let point_light_at = |p: Vec3| -> f32 {
+            let clip = camera_matrices.view_to_clip * camera_matrices.world_to_view * p.extend(1.0);
+            let ndc = clip.truncate() / clip.w;
+
+            // The viewport is flipped, so clip-space Y points up.
+            let x = ((ndc.x * 0.5 + 0.5) * EXTENT[0] as f32) as usize;
+            let y = ((0.5 - ndc.y * 0.5) * EXTENT[1] as f32) as usize;
+            let i = y * EXTENT[0] as usize + x;
+
+            (Vec4::from(with_light[i]) - Vec4::from(without_light[i]))
+                .truncate()
+                .dot(Vec3::ONE)
+        };
+
+        let shadowed = [
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(1.2, 0.0, 0.5),
+            Vec3::new(-0.8, 0.0, -1.2),
+            Vec3::new(0.3, 0.0, 1.5),
+        ]
+        .map(point_light_at);
+        let lit = [
+            Vec3::new(3.0, 0.0, 0.0),
+            Vec3::new(-3.0, 0.0, 0.5),
+            Vec3::new(0.0, 0.0, 3.5),
+            Vec3::new(2.8, 0.0, -2.8),
+        ]
+        .map(point_light_at);
+
+        let min_lit = lit.iter().copied().fold(f32::MAX, f32::min);
+        assert!(min_lit > 0.0, "{:?}", lit);
+        assert!(
+            shadowed.iter().all(|&radiance| radiance < min_lit * 0.05),
+            "shadowed: {:?}, lit: {:?}",
+            shadowed,
+            lit
+        );
+    }
+}
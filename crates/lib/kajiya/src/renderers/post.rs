@@ -2,6 +2,91 @@ use kajiya_backend::{ash::vk, vulkan::image::*};
 use kajiya_rg::{self as rg};
 use rg::{RenderGraph, SimpleRenderPass};
 
+/// Noise added by the final blit, before quantization to the output format. Breaks up banding
+/// in smooth gradients, such as the sky and fog. Not applied to scRGB output, which is floating-point.
+#[derive(Clone, Copy, Debug)]
+pub struct FilmGrain {
+    /// Blue-noise dither of one quantization step of the output format. Off by default,
+    /// which keeps the output unchanged.
+    pub dither: bool,
+
+    /// Film grain on top of the dither, in quantization steps of 8-bit output
+    pub intensity: f32,
+
+    /// Changes the noise every frame, following `FrameTime::frame_index`.
+    /// Static noise can show as a pattern fixed to the screen.
+    pub animated: bool,
+}
+
+impl Default for FilmGrain {
+    fn default() -> Self {
+        Self {
+            dither: false,
+            intensity: 0.0,
+            animated: true,
+        }
+    }
+}
+
+impl FilmGrain {
+    /// Peak amplitude of the triangular noise, in units of the output encoding
+    /// with `quantization_steps` steps between black and white.
+    pub fn noise_amplitude(&self, quantization_steps: f32) -> f32 {
+        let dither = if self.dither {
+            1.0 / quantization_steps
+        } else {
+            0.0
+        };
+
+        dither + self.intensity.max(0.0) / 255.0
+    }
+
+    /// Selects the blue noise pattern for the frame.
+    pub fn noise_frame(&self, frame_index: u32) -> u32 {
+        if self.animated {
+            frame_index
+        } else {
+            0
+        }
+    }
+}
+
+/// Scale applied to scene radiance before tone mapping, in stops.
+///
+/// EV follows the photographic convention: each stop up halves the light reaching the image,
+/// so the scene is scaled by `2^-ev`. `Manual(0.0)` leaves the radiance unscaled.
+/// Exposure compensation goes the other way: each stop up doubles the brightness.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Exposure {
+    /// A fixed exposure value, independent of the scene. Use it for reference captures,
+    /// and anything else that must not drift with the content on screen.
+    Manual(f32),
+
+    /// Exposure picked by the renderer, adjusted by `compensation` stops.
+    /// There is no metering yet, so this currently exposes at EV 0.
+    Auto { compensation: f32 },
+}
+
+impl Default for Exposure {
+    fn default() -> Self {
+        Self::Auto { compensation: 0.0 }
+    }
+}
+
+impl Exposure {
+    /// Log2 of the multiplier applied to scene radiance; `ev_shift` in `post_combine.hlsl`.
+    pub fn ev_shift(&self) -> f32 {
+        match *self {
+            Self::Manual(ev) => -ev,
+            Self::Auto { compensation } => compensation,
+        }
+    }
+
+    pub fn multiplier(&self) -> f32 {
+        self.ev_shift().exp2()
+    }
+}
+
 pub fn blur_pyramid(rg: &mut RenderGraph, input: &rg::Handle<Image>) -> rg::Handle<Image> {
     let skip_n_bottom_mips = 1;
     let mut pyramid_desc = input
@@ -108,6 +193,7 @@ pub fn post_process(
     //debug_input: &rg::Handle<Image>,
     bindless_descriptor_set: vk::DescriptorSet,
     ev_shift: f32,
+    display_max_brightness: f32,
 ) -> rg::Handle<Image> {
     let blur_pyramid = blur_pyramid(rg, input);
     let rev_blur_pyramid = rev_blur_pyramid(rg, &blur_pyramid);
@@ -127,9 +213,186 @@ pub fn post_process(
         .read(&rev_blur_pyramid)
         //.read(&blurred_luminance)
         .write(&mut output)
-        .raw_descriptor_set(1, bindless_descriptor_set)
-        .constants((output.desc().extent_inv_extent_2d(), ev_shift))
+        .bindless(bindless_descriptor_set)
+        .constants((
+            output.desc().extent_inv_extent_2d(),
+            ev_shift,
+            display_max_brightness,
+        ))
         .dispatch(output.desc().extent);
 
     output
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        camera::{CameraLens, LookThroughCamera},
+        frame_desc::{FrameTime, WorldFrameDesc},
+        renderers::readback::ReadbackImage,
+        world_renderer::WorldRenderer,
+    };
+    use glam::{Quat, Vec3};
+    use kajiya_backend::{file::set_standard_vfs_mount_points, HeadlessRenderBackend};
+    use rg::{readback::AsyncReadback, renderer::Renderer};
+    use turbosloth::LazyCache;
+
+    const EXTENT: [u32; 2] = [128, 128];
+
+    // Post-processes flat grey images of the given radiance and EV shift, and returns the mean
+    // luminance of the middle of each output. Vignetting is the same across inputs there,
+    // and the blue-noise dither averages out.
+    fn post_process_flat_images(inputs: &[(f32, f32)]) -> Vec<f32> {
+        set_standard_vfs_mount_points(concat!(env!("CARGO_MANIFEST_DIR"), "/../../.."));
+
+        let backend = HeadlessRenderBackend::new(Default::default()).unwrap();
+        let device = backend.device.clone();
+        let mut renderer = Renderer::new(&device).unwrap();
+        let mut readback = AsyncReadback::default();
+
+        // For the bindless blue noise and tonemapping LUTs
+        let mut world_renderer =
+            WorldRenderer::new(EXTENT, EXTENT, &device, &LazyCache::create()).unwrap();
+        let frame_desc = WorldFrameDesc {
+            camera_matrices: (Vec3::ZERO, Quat::IDENTITY).through(&CameraLens::default()),
+            render_extent: EXTENT,
+            sun_direction: Vec3::Y,
+            time: FrameTime::fixed_step(0, 1.0 / 60.0),
+        };
+
+        readback.begin_frame();
+        let mut tokens = Vec::new();
+        renderer
+            .prepare_frame(|rg| {
+                world_renderer.prepare_render_graph(rg, &frame_desc);
+
+                for &(radiance, ev_shift) in inputs {
+                    let mut input =
+                        rg.create(ImageDesc::new_2d(vk::Format::R16G16B16A16_SFLOAT, EXTENT));
+                    rg::imageops::clear_color(rg, &mut input, [radiance, radiance, radiance, 1.0]);
+
+                    let output = post_process(
+                        rg,
+                        &input,
+                        world_renderer.bindless_descriptor_set(),
+                        ev_shift,
+                        1.0,
+                    );
+                    tokens.push(readback.copy_image(rg, &output, 4));
+                }
+            })
+            .unwrap();
+        renderer
+            .draw_frame_headless(|dynamic_constants| {
+                world_renderer.prepare_frame_constants(dynamic_constants, &frame_desc)
+            })
+            .unwrap();
+        readback.retire_frame(device.frame_counter());
+        world_renderer.retire_frame();
+
+        tokens
+            .into_iter()
+            .map(|token| {
+                let texels = ReadbackImage {
+                    format: vk::Format::B10G11R11_UFLOAT_PACK32,
+                    extent: EXTENT,
+                    bytes: readback.wait(&device, token).unwrap(),
+                }
+                .to_rgba_f32()
+                .unwrap();
+
+                let middle = EXTENT[0] / 2 - 8..EXTENT[0] / 2 + 8;
+                let sum: f32 = middle
+                    .clone()
+                    .flat_map(|y| middle.clone().map(move |x| (x, y)))
+                    .map(|(x, y)| {
+                        let [r, g, b, _] = texels[(y * EXTENT[0] + x) as usize];
+                        Vec3::new(r, g, b).dot(Vec3::new(0.2126, 0.7152, 0.0722))
+                    })
+                    .sum();
+                sum / (16 * 16) as f32
+            })
+            .collect()
+    }
+
+    #[test]
+    fn dither_spans_one_quantization_step() {
+        // Off by default, which leaves the output unchanged
+        assert_eq!(FilmGrain::default().noise_amplitude(255.0), 0.0);
+
+        let dithered = FilmGrain {
+            dither: true,
+            ..Default::default()
+        };
+        assert_eq!(dithered.noise_amplitude(255.0), 1.0 / 255.0);
+        assert_eq!(dithered.noise_amplitude(1023.0), 1.0 / 1023.0);
+
+        // Grain is in 8-bit steps, whatever the output.
+        let grainy = FilmGrain {
+            intensity: 2.0,
+            ..dithered
+        };
+        assert!((grainy.noise_amplitude(1023.0) - (1.0 / 1023.0 + 2.0 / 255.0)).abs() < 1e-7);
+
+        assert_eq!(FilmGrain::default().noise_frame(7), 7);
+        let static_grain = FilmGrain {
+            animated: false,
+            ..Default::default()
+        };
+        assert_eq!(static_grain.noise_frame(7), 0);
+    }
+
+    #[test]
+    fn exposure_stops_double_brightness() {
+        let ratio = Exposure::Manual(1.0).multiplier() / Exposure::Manual(3.0).multiplier();
+        assert!((ratio - 4.0).abs() < 1e-6, "{}", ratio);
+
+        let ratio = Exposure::Auto { compensation: 0.5 }.multiplier()
+            / Exposure::Auto { compensation: -0.5 }.multiplier();
+        assert!((ratio - 2.0).abs() < 1e-6, "{}", ratio);
+
+        assert_eq!(Exposure::Manual(0.0).multiplier(), 1.0);
+        assert_eq!(
+            Exposure::Manual(2.0).ev_shift(),
+            Exposure::Auto { compensation: -2.0 }.ev_shift()
+        );
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn manual_exposure_scales_radiance_before_tonemapping() {
+        // The tonemapper is not linear, so its curve is sampled at EV 0 in quarter stops,
+        // and displayed values are mapped back to the radiance that produced them.
+        let ladder: Vec<f32> = (-16..=16).map(|i| 2f32.powf(i as f32 / 4.0)).collect();
+        let exposures = [Exposure::Manual(-1.0), Exposure::Manual(1.0)];
+
+        let inputs: Vec<(f32, f32)> = ladder
+            .iter()
+            .map(|&radiance| (radiance, 0.0))
+            .chain(exposures.iter().map(|exposure| (1.0, exposure.ev_shift())))
+            .collect();
+        let outputs = post_process_flat_images(&inputs);
+        let (curve, exposed) = outputs.split_at(ladder.len());
+
+        assert!(
+            curve.windows(2).all(|pair| pair[1] > pair[0]),
+            "{:?}",
+            curve
+        );
+
+        let radiance_displayed_as = |displayed: f32| -> f32 {
+            let i = curve
+                .windows(2)
+                .position(|pair| (pair[0]..=pair[1]).contains(&displayed))
+                .unwrap_or_else(|| panic!("{} outside of {:?}", displayed, curve));
+            let t = (displayed - curve[i]) / (curve[i + 1] - curve[i]);
+            ladder[i] * (ladder[i + 1] / ladder[i]).powf(t)
+        };
+
+        // Two stops apart
+        let ratio = radiance_displayed_as(exposed[0]) / radiance_displayed_as(exposed[1]);
+        assert!((ratio - 4.0).abs() < 4.0 * 0.03, "{}", ratio);
+        assert!(exposed[0] > exposed[1]);
+    }
+}
@@ -0,0 +1,381 @@
+// Procedural geometry: ray traced boxes, whose contents intersection shaders define.
+//
+// A procedural BLAS holds boxes instead of triangles (see `WorldRenderer::add_procedural_blas`).
+// Rays entering a box run the intersection shader of the instance's hit group, which reports
+// hits on whatever the box contains: an analytic sphere, an SDF, a voxel brick.
+//
+// Every ray tracing pass needs to know the hit groups of all the geometry it may hit, so hit groups
+// for procedural geometry are registered with the world renderer, and appended after those of
+// meshes by `rt_hit_groups` and `rt_shadow_hit_groups`. Like meshes, each registration has a pair of groups:
+// one for gbuffer rays, and one for shadow rays, which `rt.hlsl` traces with hit group offsets
+// of 0 and 1. Instances then select their pair with the instance's hit group offset.
+//
+// Instances may also share a pair of hit groups, but differ in parameters, which the shaders
+// read from shader records: each such instance gets a pair of hit records of its own, appended
+// after all hit groups, using the shaders of the shared groups with the instance's data.
+//
+// Procedural geometry is only ray traced; it doesn't appear in the rasterized gbuffer.
+// `analytic_primitives.rs` builds on it.
+
+use kajiya_backend::vulkan::{ray_tracing::HitShaderRecord, shader::ShaderSource};
+use kajiya_rg::RayHitGroup;
+
+/// The hit groups of mesh instances come first.
+const MESH_HIT_GROUP_COUNT: u32 = 2;
+
+/// Hit groups for one kind of procedural geometry. Both need an intersection shader.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ProceduralHitGroups {
+    /// For gbuffer rays; its closest-hit shader writes a `GbufferRayPayload`.
+    pub gbuffer: RayHitGroup,
+
+    /// For shadow rays, which are occluded by any hit the group accepts.
+    pub shadow: RayHitGroup,
+}
+
+impl ProceduralHitGroups {
+    pub fn new(gbuffer: RayHitGroup, shadow: RayHitGroup) -> Self {
+        assert!(
+            gbuffer.intersection.is_some() && shadow.intersection.is_some(),
+            "Procedural hit groups need intersection shaders"
+        );
+
+        Self { gbuffer, shadow }
+    }
+
+    /// A unit sphere at the origin of the instance, in a `[-1, 1]` box. Scale and move it
+    /// with the instance's transform.
+    pub fn analytic_sphere() -> Self {
+        let intersection = ShaderSource::hlsl("/shaders/rt/procedural/sphere.rint.hlsl");
+
+        Self::new(
+            RayHitGroup::procedural(intersection.clone()).closest_hit(ShaderSource::hlsl(
+                "/shaders/rt/procedural/sphere.rchit.hlsl",
+            )),
+            RayHitGroup::procedural(intersection),
+        )
+    }
+}
+
+/// Registered procedural hit groups, for `WorldRenderer::add_procedural_instance`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct ProceduralHitGroupsHandle(u32);
+
+impl ProceduralHitGroupsHandle {
+    /// The instance hit group offset selecting the groups.
+    pub fn hit_group_offset(self) -> u32 {
+        self.0
+    }
+}
+
+/// The procedural hit groups of a world renderer; see `WorldRenderer::register_procedural_hit_groups`.
+#[derive(Default)]
+pub struct ProceduralHitGroupRegistry {
+    groups: Vec<ProceduralHitGroups>,
+    hit_records: Vec<HitShaderRecord>,
+}
+
+impl ProceduralHitGroupRegistry {
+    /// Makes ray tracing passes include `groups`. Registering the same groups again returns
+    /// the same handle. Passes get new pipelines once they include new groups.
+    ///
+    /// New groups move the hit records, which follow them, so those are cleared.
+    pub fn register(&mut self, groups: ProceduralHitGroups) -> ProceduralHitGroupsHandle {
+        let index = if let Some(index) = self.groups.iter().position(|g| *g == groups) {
+            index
+        } else {
+            self.groups.push(groups);
+            self.hit_records.clear();
+            self.groups.len() - 1
+        };
+
+        ProceduralHitGroupsHandle(MESH_HIT_GROUP_COUNT + 2 * index as u32)
+    }
+
+    /// Whether `handle` was returned by `register` of this registry.
+    pub fn contains(&self, handle: ProceduralHitGroupsHandle) -> bool {
+        let index = handle.0.wrapping_sub(MESH_HIT_GROUP_COUNT);
+        index % 2 == 0 && ((index / 2) as usize) < self.groups.len()
+    }
+
+    /// The registered groups, in the order of their handles.
+    pub fn groups(&self) -> &[ProceduralHitGroups] {
+        &self.groups
+    }
+
+    /// Gives an instance of `groups` its own shader record data, in a pair of hit records
+    /// after all hit groups. Returns the instance hit group offset selecting the records.
+    pub fn push_hit_records(&mut self, groups: ProceduralHitGroupsHandle, data: Vec<u8>) -> u32 {
+        let offset =
+            MESH_HIT_GROUP_COUNT + 2 * self.groups.len() as u32 + self.hit_records.len() as u32;
+
+        for hit_group in [groups.0, groups.0 + 1] {
+            self.hit_records.push(HitShaderRecord {
+                hit_group,
+                data: data.clone(),
+            });
+        }
+
+        offset
+    }
+
+    /// Drops the records of `push_hit_records`, before those of the next frame's instances.
+    pub fn clear_hit_records(&mut self) {
+        self.hit_records.clear();
+    }
+
+    /// The records of `push_hit_records`, for `SimpleRenderPass::hit_records` of ray tracing
+    /// passes with the hit groups of `rt_hit_groups` or `rt_shadow_hit_groups`.
+    pub fn hit_records(&self) -> &[HitShaderRecord] {
+        &self.hit_records
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::renderers::analytic_primitives::{AnalyticPrimitive, AnalyticShape};
+    use glam::{Affine3A, Vec2, Vec3};
+    use kajiya_backend::{
+        ash::vk,
+        file::set_standard_vfs_mount_points,
+        vk_sync::AccessType,
+        vulkan::{buffer::BufferDesc, ray_tracing::*},
+        HeadlessRenderBackend,
+    };
+    use kajiya_rg::{
+        readback::AsyncReadback,
+        renderer::{FrameConstantsLayout, Renderer},
+        SimpleRenderPass,
+    };
+    use std::sync::Arc;
+
+    #[derive(Debug)]
+    pub(crate) struct TracedHit {
+        pub ray_origin: Vec3,
+        pub normal: Vec3,
+        pub t: Option<f32>,
+    }
+
+    const TRACE_EXTENT: u32 = 32;
+
+    /// Traces a grid of parallel rays down the -z axis, from z = 4 over `[-2, 2]` in x and y,
+    /// at an instance of a BLAS holding `aabb`, with `groups` as its only hit groups. With
+    /// `shader_record_data`, the instance uses it through a pair of hit records.
+    pub(crate) fn trace_down_the_z_axis(
+        groups: ProceduralHitGroups,
+        shader_record_data: Option<Vec<u8>>,
+        aabb: RayTracingAabb,
+        transform: Affine3A,
+    ) -> Vec<TracedHit> {
+        set_standard_vfs_mount_points(concat!(env!("CARGO_MANIFEST_DIR"), "/../../.."));
+
+        let backend = HeadlessRenderBackend::new(Default::default()).unwrap();
+        let device = backend.device.clone();
+        assert!(device.ray_tracing_enabled());
+
+        let mut renderer = Renderer::new(&device).unwrap();
+        let mut readback = AsyncReadback::default();
+        let accel_scratch = device
+            .create_ray_tracing_acceleration_scratch_buffer()
+            .unwrap();
+
+        let aabb_buffer = device
+            .create_ray_tracing_aabb_buffer(&[aabb], "test AABB")
+            .unwrap();
+        let blas = device
+            .create_ray_tracing_bottom_acceleration(
+                &RayTracingBottomAccelerationDesc {
+                    geometries: vec![RayTracingGeometryDesc::aabbs(
+                        aabb_buffer.device_address(&device),
+                        1,
+                    )],
+                },
+                "test BLAS",
+                &accel_scratch,
+            )
+            .unwrap();
+        let tlas = device
+            .create_ray_tracing_top_acceleration(
+                &RayTracingTopAccelerationDesc {
+                    instances: vec![RayTracingInstanceDesc {
+                        blas: Arc::new(blas),
+                        transformation: transform,
+                        mesh_index: 0,
+                        opaque: true,
+                        double_sided: true,
+                        hit_group_offset: if shader_record_data.is_some() { 2 } else { 0 },
+                    }],
+                    preallocate_bytes: 0,
+                },
+                "test TLAS",
+                &accel_scratch,
+            )
+            .unwrap();
+        let tlas = Arc::new(tlas);
+
+        let hit_records: Vec<HitShaderRecord> = shader_record_data
+            .into_iter()
+            .flat_map(|data| {
+                [0, 1].map(|hit_group| HitShaderRecord {
+                    hit_group,
+                    data: data.clone(),
+                })
+            })
+            .collect();
+
+        readback.begin_frame();
+        let mut token = None;
+        renderer
+            .prepare_frame(|rg| {
+                let tlas = rg.import(tlas.clone(), AccessType::AnyShaderReadOther);
+                let mut output = rg.create(BufferDesc::new_gpu_only(
+                    (TRACE_EXTENT * TRACE_EXTENT) as usize * std::mem::size_of::<[f32; 4]>(),
+                    vk::BufferUsageFlags::STORAGE_BUFFER,
+                ));
+
+                SimpleRenderPass::new_rt(
+                    rg.add_pass("trace procedural"),
+                    ShaderSource::hlsl("/shaders/tests/trace_procedural.rgen.hlsl"),
+                    [ShaderSource::hlsl("/shaders/rt/gbuffer.rmiss.hlsl")],
+                    [groups.gbuffer, groups.shadow],
+                )
+                .hit_records(hit_records)
+                .write(&mut output)
+                .trace_rays(&tlas, [TRACE_EXTENT, TRACE_EXTENT, 1]);
+
+                token = Some(readback.copy_buffer(rg, &output));
+            })
+            .unwrap();
+        renderer
+            .draw_frame_headless(|_| FrameConstantsLayout {
+                globals_offset: 0,
+                instance_dynamic_parameters_offset: 0,
+                triangle_lights_offset: 0,
+            })
+            .unwrap();
+        readback.retire_frame(device.frame_counter());
+
+        let floats: Vec<f32> = readback
+            .wait(&device, token.unwrap())
+            .unwrap()
+            .chunks_exact(4)
+            .map(|word| f32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+            .collect();
+
+        floats
+            .chunks_exact(4)
+            .enumerate()
+            .map(|(idx, hit)| {
+                let px = Vec2::new(
+                    (idx as u32 % TRACE_EXTENT) as f32,
+                    (idx as u32 / TRACE_EXTENT) as f32,
+                );
+                let xy = (px + 0.5) / TRACE_EXTENT as f32 * 4.0 - 2.0;
+
+                TracedHit {
+                    ray_origin: xy.extend(4.0),
+                    normal: Vec3::new(hit[0], hit[1], hit[2]),
+                    t: (hit[3] >= 0.0).then(|| hit[3]),
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn procedural_hit_groups_follow_those_of_meshes_in_pairs() {
+        let groups = ProceduralHitGroups::analytic_sphere();
+
+        let mut registry = ProceduralHitGroupRegistry::default();
+        let handle = registry.register(groups.clone());
+        assert_eq!(registry.register(groups.clone()), handle);
+        assert_eq!(handle.hit_group_offset(), 2);
+        assert!(registry.contains(handle));
+
+        // Registries are independent of each other.
+        assert!(!ProceduralHitGroupRegistry::default().contains(handle));
+
+        let rt_groups = super::super::rt_hit_groups(&registry);
+        let offset = handle.hit_group_offset() as usize;
+        assert_eq!(
+            rt_groups[offset..offset + 2],
+            [groups.gbuffer, groups.shadow]
+        );
+    }
+
+    #[test]
+    fn hit_records_follow_all_hit_groups() {
+        let mut registry = ProceduralHitGroupRegistry::default();
+        let sphere = registry.register(ProceduralHitGroups::analytic_sphere());
+        let box_ = registry.register(
+            AnalyticPrimitive::new(AnalyticShape::Box {
+                half_extents: Vec3::ONE,
+            })
+            .hit_groups(),
+        );
+
+        assert_eq!(registry.push_hit_records(sphere, vec![1]), 6);
+        assert_eq!(registry.push_hit_records(box_, vec![2]), 8);
+        assert_eq!(
+            registry
+                .hit_records()
+                .iter()
+                .map(|record| (record.hit_group, record.data[0]))
+                .collect::<Vec<_>>(),
+            [(2, 1), (3, 1), (4, 2), (5, 2)]
+        );
+
+        // Shapes of a kind share their hit groups, whatever their parameters.
+        let small_box = AnalyticPrimitive::new(AnalyticShape::Box {
+            half_extents: Vec3::splat(0.5),
+        });
+        assert_eq!(registry.register(small_box.hit_groups()), box_);
+        assert_eq!(registry.hit_records().len(), 4);
+
+        // Registering new groups moves the records.
+        registry
+            .register(AnalyticPrimitive::new(AnalyticShape::Sphere { radius: 1.0 }).hit_groups());
+        assert!(registry.hit_records().is_empty());
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device with ray tracing"]
+    fn intersection_shaders_trace_an_analytic_sphere() {
+        // The unit sphere, scaled by the instance to a radius of 1.5, and moved below the plane
+        // the rays start from.
+        const RADIUS: f32 = 1.5;
+        const CENTER_Z: f32 = -1.0;
+
+        let hits = trace_down_the_z_axis(
+            ProceduralHitGroups::analytic_sphere(),
+            None,
+            RayTracingAabb {
+                min: [-1.0; 3],
+                max: [1.0; 3],
+            },
+            Affine3A::from_scale_rotation_translation(
+                Vec3::splat(RADIUS),
+                glam::Quat::IDENTITY,
+                Vec3::new(0.0, 0.0, CENTER_Z),
+            ),
+        );
+
+        // Rays head down the -z axis, so they hit the sphere's near side.
+        for hit in hits {
+            let r2 = hit.ray_origin.truncate().length_squared();
+
+            // Pixels near the silhouette could go either way.
+            if (r2.sqrt() - RADIUS).abs() < 0.05 {
+                continue;
+            }
+
+            if r2 < RADIUS * RADIUS {
+                let expected_t = hit.ray_origin.z - CENTER_Z - (RADIUS * RADIUS - r2).sqrt();
+                let t = hit.t.expect("should hit");
+                assert!((t - expected_t).abs() < 1e-3, "{:?}", hit);
+            } else {
+                assert!(hit.t.is_none(), "{:?}", hit);
+            }
+        }
+    }
+}
@@ -1,14 +1,16 @@
 use std::sync::Arc;
 
+use glam::Affine3A;
 use kajiya_backend::{
     ash::vk,
+    shader_variants::{ShaderFeatures, ShaderVariant},
     vk_sync::AccessType,
     vulkan::{buffer::*, image::*, shader::*},
 };
 use kajiya_rg::{self as rg};
 use rg::{IntoRenderPassPipelineBinding, RenderGraph, RenderPassBinding};
 
-use crate::world_renderer::MeshInstance;
+use crate::world_renderer::{MeshHandle, MeshInstance};
 
 use super::GbufferDepth;
 
@@ -25,11 +27,163 @@ pub struct RasterMeshesData<'a> {
     pub bindless_descriptor_set: vk::DescriptorSet,
 }
 
+// Matches `InstanceTransform` in `raster_simple_vs.hlsl`
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(super) struct InstanceTransform {
+    pub current: [f32; 12],
+    pub previous: [f32; 12],
+}
+
+/// Units of the velocity written by `raster_meshes`; see `VelocityEncoding`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum VelocityUnits {
+    /// The change in view-space position, in xyz. What the renderer's own reprojection
+    /// map, and with it TAA, expects.
+    ViewSpace,
+
+    /// The change in UV, in xy; z holds the linear depth of the surface.
+    Uv,
+
+    /// The change in pixels of the render extent, in xy; z holds the linear depth.
+    Pixels,
+}
+
+/// How `raster_meshes` encodes velocity. Velocity points from where a surface is in this frame
+/// to where it was in the previous one, so adding it to a pixel's position finds its history.
+/// Screen-space velocity has +y down, like UVs. See `inc/velocity.hlsl`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct VelocityEncoding {
+    pub units: VelocityUnits,
+
+    /// Measures from the unjittered position in this frame, rather than the jittered sample,
+    /// so that static geometry has zero velocity. Temporal filters reprojecting unjittered
+    /// history need it. Only affects the screen-space units.
+    pub remove_jitter: bool,
+}
+
+impl Default for VelocityEncoding {
+    fn default() -> Self {
+        Self {
+            units: VelocityUnits::ViewSpace,
+            remove_jitter: true,
+        }
+    }
+}
+
+impl VelocityEncoding {
+    pub fn new(units: VelocityUnits) -> Self {
+        Self {
+            units,
+            ..Default::default()
+        }
+    }
+
+    pub fn remove_jitter(mut self, remove_jitter: bool) -> Self {
+        self.remove_jitter = remove_jitter;
+        self
+    }
+
+    fn shader_features() -> ShaderFeatures {
+        ShaderFeatures::new()
+            .enumeration("VELOCITY_UNITS", &["VIEW_SPACE", "UV", "PIXELS"])
+            .bool("VELOCITY_REMOVE_JITTER")
+    }
+
+    fn shader_variant(&self) -> ShaderVariant {
+        ShaderVariant::new()
+            .value(
+                "VELOCITY_UNITS",
+                match self.units {
+                    VelocityUnits::ViewSpace => "VIEW_SPACE",
+                    VelocityUnits::Uv => "UV",
+                    VelocityUnits::Pixels => "PIXELS",
+                },
+            )
+            .feature("VELOCITY_REMOVE_JITTER", self.remove_jitter)
+    }
+}
+
+// Matches `push_constants` in `raster_simple_vs.hlsl` and `raster_simple_ps.hlsl`
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DrawPushConstants {
+    first_draw_instance: u32,
+    mesh_index: u32,
+    render_extent: [f32; 2],
+}
+
+unsafe impl bytemuck::Zeroable for DrawPushConstants {}
+unsafe impl bytemuck::Pod for DrawPushConstants {}
+
+/// One instanced draw of a mesh, covering `instance_count` entries of `DrawBatches::instances`
+/// from `first_instance`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(super) struct DrawBatch {
+    pub mesh: MeshHandle,
+    pub first_instance: u32,
+    pub instance_count: u32,
+}
+
+pub(super) struct DrawBatches {
+    /// Indices of the drawn instances, grouped by mesh
+    pub instances: Vec<u32>,
+    pub batches: Vec<DrawBatch>,
+}
+
+/// Groups the opaque instances by mesh, so that each mesh is drawn once, instanced.
+/// Transparent instances are drawn in a separate forward pass.
+pub(super) fn batch_instances_by_mesh(instances: &[MeshInstance]) -> DrawBatches {
+    let mut opaque: Vec<u32> = (0..instances.len() as u32)
+        .filter(|&idx| instances[idx as usize].transparency.is_none())
+        .collect();
+
+    // Stable, so that instances of a mesh are drawn in the order they were added.
+    opaque.sort_by_key(|&idx| instances[idx as usize].mesh.0);
+
+    let mut batches: Vec<DrawBatch> = Vec::new();
+    for (draw_idx, &inst_idx) in opaque.iter().enumerate() {
+        let mesh = instances[inst_idx as usize].mesh;
+
+        match batches.last_mut() {
+            Some(batch) if batch.mesh == mesh => batch.instance_count += 1,
+            _ => batches.push(DrawBatch {
+                mesh,
+                first_instance: draw_idx as u32,
+                instance_count: 1,
+            }),
+        }
+    }
+
+    DrawBatches {
+        instances: opaque,
+        batches,
+    }
+}
+
+pub(super) fn row_major_3x4(xform: &Affine3A) -> [f32; 12] {
+    [
+        xform.x_axis.x,
+        xform.y_axis.x,
+        xform.z_axis.x,
+        xform.translation.x,
+        xform.x_axis.y,
+        xform.y_axis.y,
+        xform.z_axis.y,
+        xform.translation.y,
+        xform.x_axis.z,
+        xform.y_axis.z,
+        xform.z_axis.z,
+        xform.translation.z,
+    ]
+}
+
 pub fn raster_meshes(
     rg: &mut RenderGraph,
     render_pass: Arc<RenderPass>,
     gbuffer_depth: &mut GbufferDepth,
     velocity_img: &mut rg::Handle<Image>,
+    velocity_encoding: VelocityEncoding,
     mesh_data: RasterMeshesData<'_>,
 ) {
     let mut pass = rg.add_pass("raster simple");
@@ -43,14 +197,25 @@ pub fn raster_meshes(
                 .unwrap(),
             PipelineShaderDesc::builder(ShaderPipelineStage::Pixel)
                 // .rust_source("raster_simple::raster_simple_fs")
-                .hlsl_source("/shaders/raster_simple_ps.hlsl")
+                .source(
+                    ShaderSource::hlsl_variant(
+                        if gbuffer_depth.object_id.is_some() {
+                            "/shaders/raster_simple_object_id_ps.hlsl"
+                        } else {
+                            "/shaders/raster_simple_ps.hlsl"
+                        },
+                        &VelocityEncoding::shader_features(),
+                        &velocity_encoding.shader_variant(),
+                    )
+                    .unwrap(),
+                )
                 .build()
                 .unwrap(),
         ],
         RasterPipelineDesc::builder()
             .render_pass(render_pass.clone())
             .face_cull(true)
-            .push_constants_bytes(2 * std::mem::size_of::<u32>()),
+            .push_constants_bytes(std::mem::size_of::<DrawPushConstants>()),
     );
 
     let meshes: Vec<UploadedTriMesh> = mesh_data.meshes.to_vec();
@@ -67,6 +232,14 @@ pub fn raster_meshes(
     );
     let gbuffer_ref = pass.raster(&mut gbuffer_depth.gbuffer, AccessType::ColorAttachmentWrite);
     let velocity_ref = pass.raster(velocity_img, AccessType::ColorAttachmentWrite);
+    let object_id_ref = gbuffer_depth
+        .object_id
+        .as_mut()
+        .map(|object_id| pass.raster(object_id, AccessType::ColorAttachmentReadWrite));
+    let subsurface_ref = gbuffer_depth
+        .subsurface
+        .as_mut()
+        .map(|subsurface| pass.raster(subsurface, AccessType::ColorAttachmentReadWrite));
 
     let vertex_buffer = mesh_data.vertex_buffer.clone();
     let bindless_descriptor_set = mesh_data.bindless_descriptor_set;
@@ -76,48 +249,29 @@ pub fn raster_meshes(
 
         let instance_transforms_offset =
             api.dynamic_constants()
-                .push_from_iter(instances.iter().map(|inst| {
-                    let transform = [
-                        inst.transformation.x_axis.x,
-                        inst.transformation.y_axis.x,
-                        inst.transformation.z_axis.x,
-                        inst.transformation.translation.x,
-                        inst.transformation.x_axis.y,
-                        inst.transformation.y_axis.y,
-                        inst.transformation.z_axis.y,
-                        inst.transformation.translation.y,
-                        inst.transformation.x_axis.z,
-                        inst.transformation.y_axis.z,
-                        inst.transformation.z_axis.z,
-                        inst.transformation.translation.z,
-                    ];
-
-                    let prev_transform = [
-                        inst.prev_transformation.x_axis.x,
-                        inst.prev_transformation.y_axis.x,
-                        inst.prev_transformation.z_axis.x,
-                        inst.prev_transformation.translation.x,
-                        inst.prev_transformation.x_axis.y,
-                        inst.prev_transformation.y_axis.y,
-                        inst.prev_transformation.z_axis.y,
-                        inst.prev_transformation.translation.y,
-                        inst.prev_transformation.x_axis.z,
-                        inst.prev_transformation.y_axis.z,
-                        inst.prev_transformation.z_axis.z,
-                        inst.prev_transformation.translation.z,
-                    ];
-
-                    (transform, prev_transform)
+                .push_from_iter(instances.iter().map(|inst| InstanceTransform {
+                    current: row_major_3x4(&inst.transformation),
+                    previous: row_major_3x4(&inst.prev_transformation),
                 }));
 
+        let draw_batches = batch_instances_by_mesh(&instances);
+        let draw_instances_offset = api
+            .dynamic_constants()
+            .push_from_iter(draw_batches.instances.iter().copied());
+
+        let view_desc = ImageViewDesc::default();
+        let mut color_attachments = vec![
+            (geometric_normal_ref, &view_desc),
+            (gbuffer_ref, &view_desc),
+            (velocity_ref, &view_desc),
+        ];
+        color_attachments.extend(object_id_ref.map(|object_id_ref| (object_id_ref, &view_desc)));
+        color_attachments.extend(subsurface_ref.map(|subsurface_ref| (subsurface_ref, &view_desc)));
+
         api.begin_render_pass(
             &*render_pass,
             [width, height],
-            &[
-                (geometric_normal_ref, &ImageViewDesc::default()),
-                (gbuffer_ref, &ImageViewDesc::default()),
-                (velocity_ref, &ImageViewDesc::default()),
-            ],
+            &color_attachments,
             Some((
                 depth_ref,
                 &ImageViewDesc::builder()
@@ -129,24 +283,33 @@ pub fn raster_meshes(
 
         api.set_default_view_and_scissor([width, height]);
 
-        let pipeline = api.bind_raster_pipeline(
+        let pipeline = match api.bind_raster_pipeline(
             pipeline
                 .into_binding()
-                .descriptor_set(
-                    0,
-                    &[RenderPassBinding::DynamicConstantsStorageBuffer(
-                        instance_transforms_offset,
-                    )],
+                .storage_buffer(
+                    "instance_transforms_dyn",
+                    RenderPassBinding::DynamicConstantsStorageBuffer(instance_transforms_offset),
                 )
-                .raw_descriptor_set(1, bindless_descriptor_set),
-        );
+                .storage_buffer(
+                    "draw_instances_dyn",
+                    RenderPassBinding::DynamicConstantsStorageBuffer(draw_instances_offset),
+                )
+                .bindless(bindless_descriptor_set),
+        ) {
+            Ok(pipeline) => pipeline,
+            Err(err) => {
+                log::error!("raster_meshes: {}", err);
+                api.end_render_pass();
+                return;
+            }
+        };
 
         unsafe {
             let raw_device = &api.device().raw;
             let cb = api.cb;
 
-            for (draw_idx, instance) in instances.into_iter().enumerate() {
-                let mesh = &meshes[instance.mesh.0];
+            for batch in draw_batches.batches {
+                let mesh = &meshes[batch.mesh.0];
 
                 raw_device.cmd_bind_index_buffer(
                     cb.raw,
@@ -155,22 +318,339 @@ pub fn raster_meshes(
                     vk::IndexType::UINT32,
                 );
 
-                let push_constants = (draw_idx as u32, instance.mesh.0 as u32);
+                let push_constants = DrawPushConstants {
+                    first_draw_instance: batch.first_instance,
+                    mesh_index: batch.mesh.0 as u32,
+                    render_extent: [width as f32, height as f32],
+                };
 
-                pipeline.push_constants(
+                if let Err(err) = pipeline.push_constants(
                     cb.raw,
                     vk::ShaderStageFlags::ALL_GRAPHICS,
                     0,
-                    std::slice::from_raw_parts(
-                        &push_constants as *const _ as *const u8,
-                        std::mem::size_of_val(&push_constants),
-                    ),
-                );
+                    bytemuck::bytes_of(&push_constants),
+                ) {
+                    log::error!("raster_meshes: {}", err);
+                    break;
+                }
 
-                raw_device.cmd_draw_indexed(cb.raw, mesh.index_count, 1, 0, 0, 0);
+                raw_device.cmd_draw_indexed(
+                    cb.raw,
+                    mesh.index_count,
+                    batch.instance_count,
+                    0,
+                    0,
+                    0,
+                );
             }
         }
 
         api.end_render_pass();
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        camera::{CameraLens, LookThroughCamera},
+        frame_desc::{FrameTime, WorldFrameDesc},
+        renderers::{aov::AovKind, transparency::TransparentMaterial},
+        world_renderer::{AddMeshOptions, WorldRenderer},
+    };
+    use glam::{Quat, Vec2, Vec3, Vec4};
+    use kajiya_backend::{
+        file::set_standard_vfs_mount_points, struct_layout, struct_layout::StructLayout,
+        HeadlessRenderBackend,
+    };
+    use rg::{
+        readback::AsyncReadback,
+        renderer::{FrameConstantsLayout, Renderer},
+        SimpleRenderPass,
+    };
+    use rust_shaders_shared::{frame_constants::FrameConstants, view_constants::ViewConstants};
+    use std::task::Poll;
+    use turbosloth::LazyCache;
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn static_geometry_has_no_velocity_once_jitter_is_removed() {
+        set_standard_vfs_mount_points(concat!(env!("CARGO_MANIFEST_DIR"), "/../../.."));
+
+        let backend = HeadlessRenderBackend::new(Default::default()).unwrap();
+        let device = backend.device.clone();
+        let mut renderer = Renderer::new(&device).unwrap();
+        let mut readback = AsyncReadback::default();
+
+        const POINT_COUNT: usize = 64;
+        let render_extent = [1280, 720];
+        let jitter = Vec2::new(0.3, -0.2);
+
+        // A camera which doesn't move, with a jittered projection
+        let camera = (Vec3::new(1.0, 2.0, 3.0), Quat::from_rotation_y(0.5)).through(&CameraLens {
+            aspect_ratio: 16.0 / 9.0,
+            ..Default::default()
+        });
+        let frame_constants = FrameConstants {
+            view_constants: ViewConstants::builder(camera, camera, render_extent)
+                .pixel_offset(jitter)
+                .build(),
+            sun_direction: Vec4::Y,
+            frame_index: 0,
+            delta_time_seconds: 1.0 / 60.0,
+            sun_angular_radius_cos: 1.0,
+            triangle_light_count: 0,
+            sun_color_multiplier: Vec4::ONE,
+            sky_ambient: Vec4::ZERO,
+            world_gi_scale: 1.0,
+            ray_origin_offset_method: 0,
+            ray_origin_offset_scale: 0.0,
+            pad0: 0,
+            gi_cascades: Default::default(),
+        };
+
+        // The velocity of static points across the view, in `encoding`
+        let mut encode = |encoding: VelocityEncoding| -> Vec<[f32; 4]> {
+            readback.begin_frame();
+
+            let mut token = None;
+            renderer
+                .prepare_frame(|rg| {
+                    let mut output = rg.create(BufferDesc::new_gpu_only(
+                        POINT_COUNT * std::mem::size_of::<[f32; 4]>(),
+                        vk::BufferUsageFlags::STORAGE_BUFFER,
+                    ));
+                    SimpleRenderPass::new_compute_variant(
+                        rg.add_pass("encode velocity"),
+                        "/shaders/tests/encode_velocity.hlsl",
+                        &VelocityEncoding::shader_features(),
+                        &encoding.shader_variant(),
+                    )
+                    .write(&mut output)
+                    .push_constants(&[render_extent[0] as f32, render_extent[1] as f32])
+                    .dispatch([POINT_COUNT as u32, 1, 1]);
+
+                    token = Some(readback.copy_buffer(rg, &output));
+                })
+                .unwrap();
+            renderer
+                .draw_frame_headless(|dynamic_constants| FrameConstantsLayout {
+                    globals_offset: dynamic_constants.push(&frame_constants),
+                    instance_dynamic_parameters_offset: 0,
+                    triangle_lights_offset: 0,
+                })
+                .unwrap();
+            readback.retire_frame(device.frame_counter());
+
+            let bytes = readback.wait(&device, token.unwrap()).unwrap();
+            let floats: Vec<f32> = bytes
+                .chunks_exact(4)
+                .map(|word| f32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+                .collect();
+            floats
+                .chunks_exact(4)
+                .map(|v| [v[0], v[1], v[2], v[3]])
+                .collect()
+        };
+
+        let assert_near = |velocity: &[f32; 4], expected: Vec2, tolerance: f32| {
+            assert!(
+                (Vec2::new(velocity[0], velocity[1]) - expected)
+                    .abs()
+                    .max_element()
+                    < tolerance,
+                "{:?} should be {}",
+                velocity,
+                expected
+            );
+        };
+
+        for velocity in encode(VelocityEncoding::default()) {
+            assert_eq!(velocity, [0.0; 4]);
+        }
+
+        for velocity in encode(VelocityEncoding::new(VelocityUnits::Uv)) {
+            assert_near(&velocity, Vec2::ZERO, 1e-5);
+
+            // Linear depth
+            assert!(velocity[2] > 0.0, "{:?}", velocity);
+        }
+
+        for velocity in encode(VelocityEncoding::new(VelocityUnits::Pixels)) {
+            assert_near(&velocity, Vec2::ZERO, 1e-2);
+        }
+
+        // With the jitter kept, static geometry moves by the offset of the sample,
+        // with +y flipped from clip space to pixels.
+        for velocity in encode(VelocityEncoding::new(VelocityUnits::Pixels).remove_jitter(false)) {
+            assert_near(&velocity, Vec2::new(jitter.x, -jitter.y), 1e-2);
+        }
+    }
+
+    #[test]
+    #[ignore = "needs the DXC shader compiler"]
+    fn instance_transforms_match_the_shader_layout() {
+        set_standard_vfs_mount_points(concat!(env!("CARGO_MANIFEST_DIR"), "/../../.."));
+
+        let cpu = struct_layout!(InstanceTransform { current, previous });
+
+        // Every shader reading the transforms declares its own copy of the struct.
+        for (path, profile) in [
+            ("/shaders/raster_simple_vs.hlsl", "vs"),
+            ("/shaders/raster_simple_ps.hlsl", "ps"),
+            ("/shaders/csm/shadow_map_vs.hlsl", "vs"),
+            ("/shaders/wireframe/wireframe_vs.hlsl", "vs"),
+            ("/shaders/transparency/forward_vs.hlsl", "vs"),
+        ] {
+            StructLayout::from_hlsl(path, profile, "InstanceTransform")
+                .and_then(|shader| shader.check(&cpu))
+                .unwrap_or_else(|err| panic!("{}: {:#}", path, err));
+        }
+    }
+
+    #[test]
+    fn instances_of_a_mesh_are_drawn_in_one_instanced_draw() {
+        let instance = |mesh: usize, transparency: Option<TransparentMaterial>| MeshInstance {
+            transparency,
+            ..MeshInstance::new(MeshHandle(mesh), Affine3A::IDENTITY)
+        };
+
+        let instances = vec![instance(3, None); 10_000];
+        let draws = batch_instances_by_mesh(&instances);
+        assert_eq!(
+            draws.batches,
+            [DrawBatch {
+                mesh: MeshHandle(3),
+                first_instance: 0,
+                instance_count: 10_000,
+            }]
+        );
+        assert!(draws.instances.iter().copied().eq(0..10_000));
+
+        // Interleaved meshes are grouped, and transparent instances skipped.
+        let instances = [
+            instance(1, None),
+            instance(0, None),
+            instance(1, Some(Default::default())),
+            instance(1, None),
+        ];
+        let draws = batch_instances_by_mesh(&instances);
+        assert_eq!(draws.instances, [1, 0, 3]);
+        assert_eq!(
+            draws.batches,
+            [
+                DrawBatch {
+                    mesh: MeshHandle(0),
+                    first_instance: 0,
+                    instance_count: 1,
+                },
+                DrawBatch {
+                    mesh: MeshHandle(1),
+                    first_instance: 1,
+                    instance_count: 2,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device, and the baked `normal_mapped_panel` and `flat_panel` meshes"]
+    fn normal_maps_show_detail_under_grazing_light() {
+        set_standard_vfs_mount_points(concat!(env!("CARGO_MANIFEST_DIR"), "/../../.."));
+        let backend = HeadlessRenderBackend::new(Default::default()).unwrap();
+
+        const EXTENT: [u32; 2] = [640, 360];
+        let mut world_renderer =
+            WorldRenderer::new(EXTENT, EXTENT, &backend.device, &LazyCache::create()).unwrap();
+
+        // A 2x2m panel facing +Z, with a grid of bumps in its normal map, from x = -1 to 1
+        let panel = world_renderer
+            .add_baked_mesh("/baked/normal_mapped_panel.mesh", AddMeshOptions::new())
+            .unwrap();
+        world_renderer.add_instance(panel, Affine3A::IDENTITY);
+
+        // Next to it, from x = 2 to 4, the same panel and material without the normal map
+        let flat_panel = world_renderer
+            .add_baked_mesh("/baked/flat_panel.mesh", AddMeshOptions::new())
+            .unwrap();
+        world_renderer.add_instance(
+            flat_panel,
+            Affine3A::from_translation(Vec3::new(3.0, 0.0, 0.0)),
+        );
+
+        // No ray tracing needed
+        world_renderer.use_sun_shadow_maps = true;
+        world_renderer.sun_size_multiplier = 0.0;
+
+        let camera_matrices = (Vec3::new(1.5, 1.0, 6.0), Quat::IDENTITY).through(&CameraLens {
+            aspect_ratio: EXTENT[0] as f32 / EXTENT[1] as f32,
+            ..Default::default()
+        });
+
+        // Grazing, like in `--scene normal_mapped_panel`: the bumps' slopes facing the sun catch
+        // several times the light of the others.
+        let frame_desc = WorldFrameDesc {
+            camera_matrices,
+            render_extent: EXTENT,
+            sun_direction: Vec3::new(-0.9, 0.3, 0.3).normalize(),
+            time: FrameTime::fixed_step(0, 1.0 / 60.0),
+        };
+
+        let mut renderer = Renderer::new(&backend.device).unwrap();
+        let token = world_renderer.request_aov_readback(AovKind::Direct);
+        let mut direct = None;
+        for _ in 0..=backend.device.frames_in_flight() + 1 {
+            renderer
+                .prepare_frame(|rg| {
+                    world_renderer.prepare_render_graph(rg, &frame_desc);
+                })
+                .unwrap();
+            renderer
+                .draw_frame_headless(|dynamic_constants| {
+                    world_renderer.prepare_frame_constants(dynamic_constants, &frame_desc)
+                })
+                .unwrap();
+            world_renderer.retire_frame();
+
+            if direct.is_none() {
+                if let Poll::Ready(result) = world_renderer.poll_aov_readback(token) {
+                    direct = Some(result.expect("AOV not available").to_rgba_f32().unwrap());
+                }
+            }
+        }
+        let direct = direct.expect("AOV not read back");
+
+        // Mean luminance, and its relative standard deviation, over a grid of points
+        // inside the square at z = 0 from `min` to `min + 1.4`
+        let luminance_stats = |min: Vec2| -> (f32, f32) {
+            let luminance: Vec<f32> = (0..24)
+                .flat_map(|y| (0..24).map(move |x| Vec2::new(x as f32, y as f32)))
+                .map(|xy| {
+                    let p = (min + xy * (1.4 / 23.0)).extend(0.0);
+                    let clip = camera_matrices.view_to_clip
+                        * camera_matrices.world_to_view
+                        * p.extend(1.0);
+                    let ndc = clip.truncate() / clip.w;
+
+                    // The viewport is flipped, so clip-space Y points up.
+                    let px = ((ndc.x * 0.5 + 0.5) * EXTENT[0] as f32) as usize;
+                    let py = ((0.5 - ndc.y * 0.5) * EXTENT[1] as f32) as usize;
+                    let [r, g, b, _] = direct[py * EXTENT[0] as usize + px];
+                    Vec3::new(r, g, b).dot(Vec3::new(0.2126, 0.7152, 0.0722))
+                })
+                .collect();
+
+            let count = luminance.len() as f32;
+            let mean = luminance.iter().sum::<f32>() / count;
+            let variance = luminance.iter().map(|l| (l - mean).powi(2)).sum::<f32>() / count;
+            (mean, variance.sqrt() / mean)
+        };
+
+        let (bumpy_mean, bumpy_variation) = luminance_stats(Vec2::new(-0.7, 0.3));
+        let (flat_mean, flat_variation) = luminance_stats(Vec2::new(2.3, 0.3));
+
+        assert!(bumpy_mean > 0.0 && flat_mean > 0.0);
+        assert!(flat_variation < 0.05, "{}", flat_variation);
+        assert!(bumpy_variation > 0.2, "{}", bumpy_variation);
+    }
+}
@@ -0,0 +1,245 @@
+// Readbacks of whole images, for dumps of intermediate buffers.
+//
+// Copies are made by `kajiya_rg::readback::AsyncReadback`, and read once the GPU is done with their
+// frame, a few frames later, so that they never stall the pipeline. Call `begin_frame` before
+// recording any copies into a frame, and `retire_frame` once it's submitted.
+
+use std::{collections::HashMap, task::Poll};
+
+use anyhow::Context as _;
+
+use kajiya_backend::{ash::vk, vulkan::image::*, Device};
+use kajiya_rg::{self as rg, readback::AsyncReadback};
+
+pub use kajiya_rg::readback::ReadbackToken;
+
+/// An image copied to the host, with tightly packed rows of texels in its original format.
+#[derive(Clone)]
+pub struct ReadbackImage {
+    pub format: vk::Format,
+    pub extent: [u32; 2],
+    pub bytes: Vec<u8>,
+}
+
+impl ReadbackImage {
+    /// The texels as linear RGBA, row by row, or `None` for formats other than the float and unorm
+    /// color formats used by the renderer. Missing channels are zero, and missing alpha is one.
+    pub fn to_rgba_f32(&self) -> Option<Vec<[f32; 4]>> {
+        let texel_size = bytes_per_texel(self.format)?;
+        let decode: fn(&[u8]) -> [f32; 4] = match self.format {
+            vk::Format::R32G32B32A32_SFLOAT => |t| {
+                let c = |i: usize| f32::from_le_bytes([t[i], t[i + 1], t[i + 2], t[i + 3]]);
+                [c(0), c(4), c(8), c(12)]
+            },
+            vk::Format::R32_SFLOAT => {
+                |t| [f32::from_le_bytes([t[0], t[1], t[2], t[3]]), 0.0, 0.0, 1.0]
+            }
+            vk::Format::R16G16B16A16_SFLOAT => |t| {
+                let c = |i: usize| f16_to_f32(u16::from_le_bytes([t[i], t[i + 1]]));
+                [c(0), c(2), c(4), c(6)]
+            },
+            vk::Format::R16G16_SFLOAT => |t| {
+                let c = |i: usize| f16_to_f32(u16::from_le_bytes([t[i], t[i + 1]]));
+                [c(0), c(2), 0.0, 1.0]
+            },
+            vk::Format::R8G8B8A8_UNORM => |t| {
+                let c = |i: usize| t[i] as f32 / 255.0;
+                [c(0), c(1), c(2), c(3)]
+            },
+            vk::Format::B10G11R11_UFLOAT_PACK32 => |t| {
+                let [r, g, b] = unpack_b10g11r11(u32::from_le_bytes([t[0], t[1], t[2], t[3]]));
+                [r, g, b, 1.0]
+            },
+            _ => return None,
+        };
+
+        Some(self.bytes.chunks_exact(texel_size).map(decode).collect())
+    }
+
+    /// Writes the texels to an RGBA OpenEXR file, as decoded by `to_rgba_f32`.
+    pub fn write_exr(&self, path: impl AsRef<std::path::Path>) -> anyhow::Result<()> {
+        let texels = self
+            .to_rgba_f32()
+            .with_context(|| format!("Can't decode images of format {:?}", self.format))?;
+        let width = self.extent[0] as usize;
+
+        exr::prelude::write_rgba_file(path, width, self.extent[1] as usize, |x, y| {
+            let [r, g, b, a] = texels[y * width + x];
+            (r, g, b, a)
+        })?;
+
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct ImageReadback {
+    readback: AsyncReadback,
+
+    // Format and extent of the images being copied
+    images: HashMap<ReadbackToken, (vk::Format, [u32; 2])>,
+}
+
+impl ImageReadback {
+    /// Starts recording a new frame. Copies recorded into a frame which failed to render never retire;
+    /// they resolve to `None`.
+    pub fn begin_frame(&mut self) {
+        self.readback.begin_frame();
+    }
+
+    /// Copies the first mip of `image` to the host, at its position in the frame being recorded.
+    /// Formats which `to_rgba_f32` doesn't know the size of resolve to `None` immediately.
+    pub fn copy_to_host(
+        &mut self,
+        rg: &mut rg::TemporalRenderGraph,
+        image: &rg::Handle<Image>,
+    ) -> ReadbackToken {
+        let token = self.reserve();
+        self.copy_reserved_to_host(token, rg, image);
+        token
+    }
+
+    /// A token for a copy recorded in a later frame by `copy_reserved_to_host`, or resolved
+    /// to `None` by `cancel`; pending until then.
+    pub fn reserve(&mut self) -> ReadbackToken {
+        self.readback.reserve()
+    }
+
+    /// Like `copy_to_host`, for a token from `reserve`.
+    pub fn copy_reserved_to_host(
+        &mut self,
+        token: ReadbackToken,
+        rg: &mut rg::TemporalRenderGraph,
+        image: &rg::Handle<Image>,
+    ) {
+        let desc = image.desc();
+        let texel_size = if let Some(texel_size) = bytes_per_texel(desc.format) {
+            texel_size
+        } else {
+            self.readback.cancel(token);
+            return;
+        };
+
+        self.readback
+            .copy_image_reserved(token, rg, image, texel_size);
+        self.images.insert(token, (desc.format, desc.extent_2d()));
+    }
+
+    /// Resolves a token from `reserve` to `None`.
+    pub fn cancel(&mut self, token: ReadbackToken) {
+        self.readback.cancel(token);
+    }
+
+    /// Forgets `token`, whose image is no longer wanted, whether its copy is reserved,
+    /// in flight, or done.
+    pub fn discard(&mut self, token: ReadbackToken) {
+        self.readback.discard(token);
+        self.images.remove(&token);
+    }
+
+    /// Marks the copies recorded since `begin_frame` as submitted in the frame `frame_counter`.
+    pub fn retire_frame(&mut self, frame_counter: u64) {
+        self.readback.retire_frame(frame_counter);
+    }
+
+    /// The image copied for `token`, or `None` if it couldn't be. Each result is returned once;
+    /// unknown tokens resolve to `None`.
+    pub fn poll(&mut self, device: &Device, token: ReadbackToken) -> Poll<Option<ReadbackImage>> {
+        self.readback.poll(device, token).map(|bytes| {
+            let (format, extent) = self.images.remove(&token)?;
+
+            Some(ReadbackImage {
+                format,
+                extent,
+                bytes: bytes?,
+            })
+        })
+    }
+}
+
+fn bytes_per_texel(format: vk::Format) -> Option<usize> {
+    match format {
+        vk::Format::R32G32B32A32_SFLOAT => Some(16),
+        vk::Format::R16G16B16A16_SFLOAT => Some(8),
+        vk::Format::R32_SFLOAT
+        | vk::Format::R16G16_SFLOAT
+        | vk::Format::R8G8B8A8_UNORM
+        | vk::Format::B10G11R11_UFLOAT_PACK32 => Some(4),
+        _ => None,
+    }
+}
+
+fn f16_to_f32(bits: u16) -> f32 {
+    let sign = if bits & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = (bits >> 10) & 0x1f;
+    let fraction = (bits & 0x3ff) as f32 / 1024.0;
+
+    sign * match exponent {
+        0 => fraction * 2f32.powi(-14),
+        31 => {
+            if fraction == 0.0 {
+                f32::INFINITY
+            } else {
+                f32::NAN
+            }
+        }
+        _ => (1.0 + fraction) * 2f32.powi(exponent as i32 - 15),
+    }
+}
+
+// Unsigned 11, 11, and 10-bit floats with 5-bit exponents, red in the lowest bits.
+fn unpack_b10g11r11(packed: u32) -> [f32; 3] {
+    fn unpack(bits: u32, mantissa_bits: u32) -> f32 {
+        let mantissa = bits & ((1 << mantissa_bits) - 1);
+        let exponent = bits >> mantissa_bits;
+        let fraction = mantissa as f32 / (1 << mantissa_bits) as f32;
+
+        match exponent {
+            0 => fraction * 2f32.powi(-14),
+            31 => {
+                if mantissa == 0 {
+                    f32::INFINITY
+                } else {
+                    f32::NAN
+                }
+            }
+            _ => (1.0 + fraction) * 2f32.powi(exponent as i32 - 15),
+        }
+    }
+
+    [
+        unpack(packed & 0x7ff, 6),
+        unpack((packed >> 11) & 0x7ff, 6),
+        unpack(packed >> 22, 5),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_half_float_texels() {
+        // 1.0, -2.0, 0.5, and the smallest subnormal, in binary16
+        let texels: [u16; 8] = [
+            0x3c00, 0xc000, 0x3800, 0x0001, 0x7c00, 0x0000, 0x7bff, 0x3c00,
+        ];
+        let image = ReadbackImage {
+            format: vk::Format::R16G16B16A16_SFLOAT,
+            extent: [2, 1],
+            bytes: texels.iter().flat_map(|t| t.to_le_bytes()).collect(),
+        };
+
+        let rgba = image.to_rgba_f32().unwrap();
+        assert_eq!(rgba.len(), 2);
+        assert_eq!(rgba[0][..3], [1.0, -2.0, 0.5]);
+        assert_eq!(rgba[0][3], 2f32.powi(-24));
+        assert_eq!(rgba[1], [f32::INFINITY, 0.0, 65504.0, 1.0]);
+
+        let unknown = ReadbackImage {
+            format: vk::Format::D32_SFLOAT,
+            ..image
+        };
+        assert!(unknown.to_rgba_f32().is_none());
+    }
+}
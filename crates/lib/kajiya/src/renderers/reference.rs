@@ -1,15 +1,80 @@
+use glam::Vec3;
 use kajiya_backend::{
     ash::vk,
     vulkan::{image::*, ray_tracing::RayTracingAcceleration, shader::ShaderSource},
 };
-use kajiya_rg::{self as rg};
+use kajiya_rg::{self as rg, GetOrCreateTemporal};
 use rg::{RenderGraph, SimpleRenderPass};
 
+use super::{procedural::ProceduralHitGroupRegistry, rt_hit_groups};
+
+// Must match `ENVIRONMENT_CDF_WIDTH` and `ENVIRONMENT_CDF_HEIGHT` in `inc/environment_cdf.hlsl`.
+// The extra column holds the marginal distribution of the rows.
+const ENVIRONMENT_CDF_EXTENT: [u32; 2] = [64 + 1, 32];
+
+/// What the sky seen by the reference path tracer depends on.
+#[derive(Clone, Copy, PartialEq)]
+pub struct ReferenceEnvironment {
+    pub sun_direction: Vec3,
+    pub sun_color_multiplier: Vec3,
+    pub sky_ambient: Vec3,
+}
+
+/// A distribution over the sky's luminance, which the reference path tracer importance-samples it with.
+#[derive(Default)]
+pub struct EnvironmentCdf {
+    computed_for: Option<ReferenceEnvironment>,
+    // The environment it's built for in the frame being recorded; see `retire_frame`.
+    recorded_for: Option<ReferenceEnvironment>,
+}
+
+impl EnvironmentCdf {
+    /// Returns the distribution, rebuilding it if `environment` changed since it was last built.
+    pub fn prepare(
+        &mut self,
+        rg: &mut rg::TemporalRenderGraph,
+        environment: ReferenceEnvironment,
+    ) -> rg::Handle<Image> {
+        let mut cdf_tex = rg
+            .get_or_create_temporal(
+                "refpt.environment_cdf",
+                ImageDesc::new_2d(vk::Format::R32_SFLOAT, ENVIRONMENT_CDF_EXTENT)
+                    .usage(vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::STORAGE),
+            )
+            .unwrap();
+
+        if self.computed_for != Some(environment) {
+            SimpleRenderPass::new_compute(
+                rg.add_pass("environment cdf"),
+                "/shaders/build_environment_cdf.hlsl",
+            )
+            .write(&mut cdf_tex)
+            .dispatch([ENVIRONMENT_CDF_EXTENT[1], 1, 1]);
+
+            self.recorded_for = Some(environment);
+        } else {
+            self.recorded_for = None;
+        }
+
+        cdf_tex
+    }
+
+    /// Call once the frame last recorded is submitted. Until then, the distribution built in it
+    /// isn't relied upon, as the frame may still be dropped.
+    pub fn retire_frame(&mut self) {
+        if let Some(environment) = self.recorded_for.take() {
+            self.computed_for = Some(environment);
+        }
+    }
+}
+
 pub fn reference_path_trace(
     rg: &mut RenderGraph,
     output_img: &mut rg::Handle<Image>,
+    environment_cdf: &rg::Handle<Image>,
     bindless_descriptor_set: vk::DescriptorSet,
     tlas: &rg::Handle<RayTracingAcceleration>,
+    procedural_hit_groups: &ProceduralHitGroupRegistry,
 ) {
     SimpleRenderPass::new_rt(
         rg.add_pass("reference pt"),
@@ -18,9 +83,126 @@ pub fn reference_path_trace(
             ShaderSource::hlsl("/shaders/rt/gbuffer.rmiss.hlsl"),
             ShaderSource::hlsl("/shaders/rt/shadow.rmiss.hlsl"),
         ],
-        [ShaderSource::hlsl("/shaders/rt/gbuffer.rchit.hlsl")],
+        rt_hit_groups(procedural_hit_groups),
     )
+    .hit_records(procedural_hit_groups.hit_records().to_vec())
     .write(output_img)
-    .raw_descriptor_set(1, bindless_descriptor_set)
+    .read(environment_cdf)
+    .bindless(bindless_descriptor_set)
     .trace_rays(tlas, output_img.desc().extent);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::{CameraLens, LookThroughCamera};
+    use glam::Quat;
+    use kajiya_backend::{file::set_standard_vfs_mount_points, HeadlessRenderBackend};
+    use rg::{
+        readback::AsyncReadback,
+        renderer::{FrameConstantsLayout, Renderer},
+    };
+    use rust_shaders_shared::{frame_constants::FrameConstants, view_constants::ViewConstants};
+    use std::f32::consts::PI;
+
+    const WIDTH: usize = ENVIRONMENT_CDF_EXTENT[0] as usize - 1;
+    const HEIGHT: usize = ENVIRONMENT_CDF_EXTENT[1] as usize;
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn environment_cdf_concentrates_around_the_sun() {
+        set_standard_vfs_mount_points(concat!(env!("CARGO_MANIFEST_DIR"), "/../../.."));
+
+        let backend = HeadlessRenderBackend::new(Default::default()).unwrap();
+        let device = backend.device.clone();
+        let mut renderer = Renderer::new(&device).unwrap();
+        let mut readback = AsyncReadback::default();
+        let mut environment_cdf = EnvironmentCdf::default();
+
+        let environment = ReferenceEnvironment {
+            sun_direction: Vec3::new(0.6, 1.0, 0.8).normalize(),
+            sun_color_multiplier: Vec3::ONE,
+            sky_ambient: Vec3::ZERO,
+        };
+
+        let camera = (Vec3::ZERO, Quat::IDENTITY).through(&CameraLens::default());
+        let frame_constants = FrameConstants {
+            view_constants: ViewConstants::builder(camera, camera, [1, 1]).build(),
+            sun_direction: environment.sun_direction.extend(0.0),
+            frame_index: 0,
+            delta_time_seconds: 1.0 / 60.0,
+            sun_angular_radius_cos: 0.5f32.to_radians().cos(),
+            triangle_light_count: 0,
+            sun_color_multiplier: environment.sun_color_multiplier.extend(0.0),
+            sky_ambient: environment.sky_ambient.extend(0.0),
+            world_gi_scale: 1.0,
+            ray_origin_offset_method: 0,
+            ray_origin_offset_scale: 0.0,
+            pad0: 0,
+            gi_cascades: Default::default(),
+        };
+
+        readback.begin_frame();
+        let mut token = None;
+        renderer
+            .prepare_frame(|rg| {
+                let cdf_tex = environment_cdf.prepare(rg, environment);
+                token = Some(readback.copy_image(rg, &cdf_tex, 4));
+            })
+            .unwrap();
+
+        // Not relied upon until the frame is submitted
+        assert!(environment_cdf.computed_for.is_none());
+
+        renderer
+            .draw_frame_headless(|dynamic_constants| FrameConstantsLayout {
+                globals_offset: dynamic_constants.push(&frame_constants),
+                instance_dynamic_parameters_offset: 0,
+                triangle_lights_offset: 0,
+            })
+            .unwrap();
+        readback.retire_frame(device.frame_counter());
+        environment_cdf.retire_frame();
+        assert!(environment_cdf.computed_for == Some(environment));
+
+        let bytes = readback.wait(&device, token.unwrap()).unwrap();
+        let texels: &[f32] = bytemuck::cast_slice(&bytes);
+        let at = |x: usize, y: usize| texels[y * (WIDTH + 1) + x];
+
+        // Rows hold conditional CDFs, and the last column the marginal one; all of them end at 1.
+        for y in 0..HEIGHT {
+            for x in 1..WIDTH {
+                assert!(at(x, y) >= at(x - 1, y), "{:?}", (x, y));
+            }
+            assert_eq!(at(WIDTH - 1, y), 1.0);
+            if y > 0 {
+                assert!(at(WIDTH, y) >= at(WIDTH, y - 1));
+            }
+        }
+        assert_eq!(at(WIDTH, HEIGHT - 1), 1.0);
+
+        // The most likely texel is the one with the sun in it.
+        let texel_prob = |x: usize, y: usize| {
+            let row_start = if y > 0 { at(WIDTH, y - 1) } else { 0.0 };
+            let column_start = if x > 0 { at(x - 1, y) } else { 0.0 };
+            (at(WIDTH, y) - row_start) * (at(x, y) - column_start)
+        };
+        let (most_likely, _) = (0..WIDTH * HEIGHT)
+            .map(|idx| (idx, texel_prob(idx % WIDTH, idx / WIDTH)))
+            .fold(
+                (0, 0.0),
+                |best, cur| if cur.1 > best.1 { cur } else { best },
+            );
+
+        let sun = environment.sun_direction;
+        let sun_uv = (
+            (sun.z.atan2(sun.x) / (2.0 * PI) + 1.0).fract(),
+            sun.y.acos() / PI,
+        );
+        let sun_texel = (
+            (sun_uv.0 * WIDTH as f32) as usize,
+            (sun_uv.1 * HEIGHT as f32) as usize,
+        );
+        assert_eq!((most_likely % WIDTH, most_likely / WIDTH), sun_texel);
+    }
+}
@@ -8,15 +8,67 @@ use kajiya_backend::{
 };
 use kajiya_rg::{self as rg, SimpleRenderPass};
 
-use super::{csgi, GbufferDepth, PingPongTemporalResource};
+use super::{
+    csgi, denoiser::DenoiserSettings, procedural::ProceduralHitGroupRegistry, rt_hit_groups,
+    GbufferDepth, PingPongTemporalResource,
+};
 
 use blue_noise_sampler::spp64::*;
 
+/// Must match `ADAPTIVE_SAMPLING_TILE_SIZE` in the shaders.
+const ADAPTIVE_SAMPLING_TILE_SIZE: u32 = 8;
+
+/// Controls how many rays are traced per pixel, based on the variance
+/// of GI in the previous frames, estimated per tile.
+#[derive(Clone, Copy, Debug)]
+pub struct RtdgiAdaptiveSampling {
+    /// Rays per pixel in converged regions. At least 1.
+    pub min_samples: u32,
+
+    /// Rays per pixel in the noisiest regions. Equal to `min_samples` to trace uniformly.
+    pub max_samples: u32,
+
+    /// Scales the relative standard deviation of GI; `max_samples` is reached at `1 / variance_scale`.
+    pub variance_scale: f32,
+}
+
+impl Default for RtdgiAdaptiveSampling {
+    fn default() -> Self {
+        Self {
+            min_samples: 1,
+            max_samples: 1,
+            variance_scale: 2.0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct AdaptiveSampleCountConstants {
+    variance_tex_size: [f32; 4],
+    min_sample_count: u32,
+    max_sample_count: u32,
+    variance_scale: f32,
+}
+
+/// Diffuse GI, before and after denoising.
+pub struct RtdgiOutput {
+    /// At full resolution.
+    pub denoised: rg::ReadOnlyHandle<Image>,
+
+    /// The traced irradiance without any filtering, at the half resolution of the trace.
+    pub noisy: rg::Handle<Image>,
+}
+
 pub struct RtdgiRenderer {
+    pub adaptive_sampling: RtdgiAdaptiveSampling,
+    pub denoiser: DenoiserSettings,
+
     temporal_tex: PingPongTemporalResource,
     temporal2_tex: PingPongTemporalResource,
     temporal2_variance_tex: PingPongTemporalResource,
     cv_temporal_tex: PingPongTemporalResource,
+    tile_variance_tex: PingPongTemporalResource,
 
     ranking_tile_buf: Arc<Buffer>,
     scambling_tile_buf: Arc<Buffer>,
@@ -43,10 +95,13 @@ fn make_lut_buffer<T: Copy>(device: &Device, v: &[T]) -> Result<Arc<Buffer>, Bac
 impl RtdgiRenderer {
     pub fn new(device: &Device) -> Result<Self, BackendError> {
         Ok(Self {
+            adaptive_sampling: Default::default(),
+            denoiser: DenoiserSettings::with_max_history_frames(32.0),
             temporal_tex: PingPongTemporalResource::new("rtdgi.temporal"),
             temporal2_tex: PingPongTemporalResource::new("rtdgi.temporal2"),
             temporal2_variance_tex: PingPongTemporalResource::new("rtdgi.temporal2_var"),
             cv_temporal_tex: PingPongTemporalResource::new("rtdgi.cv"),
+            tile_variance_tex: PingPongTemporalResource::new("rtdgi.tile_variance"),
             ranking_tile_buf: make_lut_buffer(device, RANKING_TILE)?,
             scambling_tile_buf: make_lut_buffer(device, SCRAMBLING_TILE)?,
             sobol_buf: make_lut_buffer(device, SOBOL)?,
@@ -68,7 +123,7 @@ impl RtdgiRenderer {
         reprojection_map: &rg::Handle<Image>,
         csgi_volume: &csgi::CsgiVolume,
         sky_cube: &rg::Handle<Image>,
-    ) -> rg::Handle<Image> {
+    ) -> (rg::Handle<Image>, rg::Handle<Image>) {
         let half_view_normal_tex = gbuffer_depth.half_view_normal(rg);
         let half_depth_tex = gbuffer_depth.half_depth(rg);
         let half_res_extent = half_view_normal_tex.desc().extent_2d();
@@ -90,6 +145,9 @@ impl RtdgiRenderer {
                 .format(vk::Format::R16G16B16A16_SFLOAT),
         );
 
+        let mut noisy_tex = rg.create(*temporal_filtered_tex.desc());
+        let (_, max_history_frames) = self.denoiser.shader_params();
+
         SimpleRenderPass::new_compute(
             rg.add_pass("rtdgi temporal"),
             "/shaders/rtdgi/temporal_filter.hlsl",
@@ -105,13 +163,15 @@ impl RtdgiRenderer {
         .write(&mut temporal_output_tex)
         .write(&mut cv_temporal_output_tex)
         .write(&mut temporal_filtered_tex)
+        .write(&mut noisy_tex)
         .constants((
             temporal_output_tex.desc().extent_inv_extent_2d(),
             gbuffer_depth.gbuffer.desc().extent_inv_extent_2d(),
+            max_history_frames,
         ))
         .dispatch(temporal_output_tex.desc().extent);
 
-        temporal_filtered_tex
+        (temporal_filtered_tex, noisy_tex)
     }
 
     fn temporal2(
@@ -122,14 +182,9 @@ impl RtdgiRenderer {
         reprojection_map: &rg::Handle<Image>,
         reprojected_history_tex: &rg::Handle<Image>,
         mut temporal_output_tex: rg::Handle<Image>,
+        variance_history_tex: &rg::Handle<Image>,
+        mut temporal_variance_output_tex: rg::Handle<Image>,
     ) -> rg::Handle<Image> {
-        let (mut temporal_variance_output_tex, variance_history_tex) =
-            self.temporal2_variance_tex.get_output_and_history(
-                rg,
-                ImageDesc::new_2d(vk::Format::R16G16_SFLOAT, input_color.desc().extent_2d())
-                    .usage(vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::STORAGE),
-            );
-
         let mut temporal_filtered_tex = rg.create(
             gbuffer_depth
                 .gbuffer
@@ -138,13 +193,15 @@ impl RtdgiRenderer {
                 .format(vk::Format::R16G16B16A16_SFLOAT),
         );
 
+        let (_, max_history_frames) = self.denoiser.shader_params();
+
         SimpleRenderPass::new_compute(
             rg.add_pass("rtdgi temporal2"),
             "/shaders/rtdgi/temporal_filter2.hlsl",
         )
         .read(input_color)
         .read(reprojected_history_tex)
-        .read(&variance_history_tex)
+        .read(variance_history_tex)
         .read(reprojection_map)
         .write(&mut temporal_output_tex)
         .write(&mut temporal_variance_output_tex)
@@ -152,13 +209,58 @@ impl RtdgiRenderer {
         .constants((
             temporal_output_tex.desc().extent_inv_extent_2d(),
             gbuffer_depth.gbuffer.desc().extent_inv_extent_2d(),
+            max_history_frames,
         ))
         .dispatch(temporal_output_tex.desc().extent);
 
         temporal_filtered_tex
     }
 
+    /// Picks the number of rays to trace per tile of the half-res trace.
+    fn adaptive_sample_count(
+        &mut self,
+        rg: &mut rg::TemporalRenderGraph,
+        variance_history_tex: &rg::Handle<Image>,
+        trace_extent: [u32; 2],
+    ) -> rg::Handle<Image> {
+        let tile_extent = [
+            (trace_extent[0] + ADAPTIVE_SAMPLING_TILE_SIZE - 1) / ADAPTIVE_SAMPLING_TILE_SIZE,
+            (trace_extent[1] + ADAPTIVE_SAMPLING_TILE_SIZE - 1) / ADAPTIVE_SAMPLING_TILE_SIZE,
+        ];
+
+        let (mut tile_variance_output_tex, tile_variance_history_tex) =
+            self.tile_variance_tex.get_output_and_history(
+                rg,
+                ImageDesc::new_2d(vk::Format::R32_SFLOAT, tile_extent)
+                    .usage(vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::STORAGE),
+            );
+
+        let mut sample_count_tex = rg.create(ImageDesc::new_2d(vk::Format::R32_UINT, tile_extent));
+
+        let min_samples = self.adaptive_sampling.min_samples.max(1);
+        let max_samples = self.adaptive_sampling.max_samples.max(min_samples);
+
+        SimpleRenderPass::new_compute(
+            rg.add_pass("rtdgi sample count"),
+            "/shaders/rtdgi/adaptive_sample_count.hlsl",
+        )
+        .read(variance_history_tex)
+        .read(&tile_variance_history_tex)
+        .write(&mut tile_variance_output_tex)
+        .write(&mut sample_count_tex)
+        .constants(AdaptiveSampleCountConstants {
+            variance_tex_size: variance_history_tex.desc().extent_inv_extent_2d(),
+            min_sample_count: min_samples,
+            max_sample_count: max_samples,
+            variance_scale: self.adaptive_sampling.variance_scale,
+        })
+        .dispatch(sample_count_tex.desc().extent);
+
+        sample_count_tex
+    }
+
     fn spatial(
+        &self,
         rg: &mut rg::TemporalRenderGraph,
         input_color: &rg::Handle<Image>,
         gbuffer_depth: &GbufferDepth,
@@ -171,6 +273,8 @@ impl RtdgiRenderer {
             half_view_normal_tex.desc().extent_2d(),
         ));
 
+        let (strength, _) = self.denoiser.shader_params();
+
         SimpleRenderPass::new_compute(
             rg.add_pass("rtdgi spatial"),
             "/shaders/rtdgi/spatial_filter.hlsl",
@@ -183,6 +287,7 @@ impl RtdgiRenderer {
         .constants((
             spatial_filtered_tex.desc().extent_inv_extent_2d(),
             super::rtr::SPATIAL_RESOLVE_OFFSETS,
+            strength,
         ))
         .dispatch(spatial_filtered_tex.desc().extent);
 
@@ -198,11 +303,12 @@ impl RtdgiRenderer {
         sky_cube: &rg::Handle<Image>,
         bindless_descriptor_set: vk::DescriptorSet,
         tlas: &rg::Handle<RayTracingAcceleration>,
+        procedural_hit_groups: &ProceduralHitGroupRegistry,
         csgi_volume: &csgi::CsgiVolume,
 
         // TODO: calculate specialized SSAO
         ssao_img: &rg::Handle<Image>,
-    ) -> rg::ReadOnlyHandle<Image> {
+    ) -> RtdgiOutput {
         let gbuffer_desc = gbuffer_depth.gbuffer.desc();
 
         let (temporal_output_tex, history_tex) = self
@@ -229,6 +335,16 @@ impl RtdgiRenderer {
                 .format(vk::Format::R16G16B16A16_SFLOAT),
         );
 
+        let (temporal_variance_output_tex, variance_history_tex) =
+            self.temporal2_variance_tex.get_output_and_history(
+                rg,
+                ImageDesc::new_2d(vk::Format::R16G16_SFLOAT, gbuffer_desc.extent_2d())
+                    .usage(vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::STORAGE),
+            );
+
+        let sample_count_tex =
+            self.adaptive_sample_count(rg, &variance_history_tex, hit0_tex.desc().extent_2d());
+
         let ranking_tile_buf = rg.import(
             self.ranking_tile_buf.clone(),
             vk_sync::AccessType::ComputeShaderReadSampledImageOrUniformTexelBuffer,
@@ -249,8 +365,9 @@ impl RtdgiRenderer {
                 ShaderSource::hlsl("/shaders/rt/gbuffer.rmiss.hlsl"),
                 ShaderSource::hlsl("/shaders/rt/shadow.rmiss.hlsl"),
             ],
-            [ShaderSource::hlsl("/shaders/rt/gbuffer.rchit.hlsl")],
+            rt_hit_groups(procedural_hit_groups),
         )
+        .hit_records(procedural_hit_groups.hit_records().to_vec())
         .read(&gbuffer_depth.gbuffer)
         .read_aspect(&gbuffer_depth.depth, vk::ImageAspectFlags::DEPTH)
         .read(&reprojected_history_tex)
@@ -264,10 +381,11 @@ impl RtdgiRenderer {
         .read_array(&csgi_volume.opacity)
         .read(sky_cube)
         .constants((gbuffer_desc.extent_inv_extent_2d(),))
-        .raw_descriptor_set(1, bindless_descriptor_set)
+        .read(&sample_count_tex)
+        .bindless(bindless_descriptor_set)
         .trace_rays(tlas, hit0_tex.desc().extent);
 
-        let filtered_tex = self.temporal(
+        let (filtered_tex, noisy_tex) = self.temporal(
             rg,
             &hit0_tex,
             gbuffer_depth,
@@ -275,7 +393,7 @@ impl RtdgiRenderer {
             csgi_volume,
             sky_cube,
         );
-        let filtered_tex = Self::spatial(rg, &filtered_tex, gbuffer_depth, ssao_img);
+        let filtered_tex = self.spatial(rg, &filtered_tex, gbuffer_depth, ssao_img);
 
         // Not correct with control variates:
         /*let filtered_tex = Self::spatial(rg, &hit0_tex, gbuffer_depth, ssao_img);
@@ -306,7 +424,7 @@ impl RtdgiRenderer {
             upsampled_tex.desc().extent_inv_extent_2d(),
             super::rtr::SPATIAL_RESOLVE_OFFSETS,
         ))
-        .raw_descriptor_set(1, bindless_descriptor_set)
+        .bindless(bindless_descriptor_set)
         .dispatch(upsampled_tex.desc().extent);
 
         let filtered_tex = self.temporal2(
@@ -316,8 +434,13 @@ impl RtdgiRenderer {
             reprojection_map,
             &reprojected_history_tex,
             temporal_output_tex,
+            &variance_history_tex,
+            temporal_variance_output_tex,
         );
 
-        filtered_tex.into()
+        RtdgiOutput {
+            denoised: filtered_tex.into(),
+            noisy: noisy_tex,
+        }
     }
 }
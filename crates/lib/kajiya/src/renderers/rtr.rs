@@ -8,11 +8,16 @@ use kajiya_backend::{
 };
 use kajiya_rg::{self as rg, SimpleRenderPass};
 
-use super::{csgi, GbufferDepth, PingPongTemporalResource};
+use super::{
+    csgi, denoiser::DenoiserSettings, procedural::ProceduralHitGroupRegistry, rt_hit_groups,
+    GbufferDepth, PingPongTemporalResource,
+};
 
 use blue_noise_sampler::spp64::*;
 
 pub struct RtrRenderer {
+    pub denoiser: DenoiserSettings,
+
     temporal_tex: PingPongTemporalResource,
     temporal2_tex: PingPongTemporalResource,
     ray_len_tex: PingPongTemporalResource,
@@ -42,6 +47,7 @@ fn make_lut_buffer<T: Copy>(device: &Device, v: &[T]) -> Result<Arc<Buffer>, Bac
 impl RtrRenderer {
     pub fn new(device: &Device) -> Result<Self, BackendError> {
         Ok(Self {
+            denoiser: DenoiserSettings::with_max_history_frames(16.0),
             temporal_tex: PingPongTemporalResource::new("rtr.temporal"),
             temporal2_tex: PingPongTemporalResource::new("rtr.temporal2"),
             ray_len_tex: PingPongTemporalResource::new("rtr.ray_len"),
@@ -58,6 +64,15 @@ pub struct TracedRtr<'a> {
     history_tex: rg::Handle<Image>,
     ray_len_tex: rg::Handle<Image>,
     temporal2_tex: &'a mut PingPongTemporalResource,
+    denoiser: DenoiserSettings,
+}
+
+/// Reflections, before and after denoising.
+pub struct RtrOutput {
+    pub denoised: rg::Handle<Image>,
+
+    /// The resolved reflections without any filtering.
+    pub noisy: rg::Handle<Image>,
 }
 
 impl RtrRenderer {
@@ -75,6 +90,7 @@ impl RtrRenderer {
         sky_cube: &rg::Handle<Image>,
         bindless_descriptor_set: vk::DescriptorSet,
         tlas: &rg::Handle<RayTracingAcceleration>,
+        procedural_hit_groups: &ProceduralHitGroupRegistry,
         csgi_volume: &csgi::CsgiVolume,
         rtdgi: &rg::Handle<Image>,
     ) -> TracedRtr {
@@ -114,8 +130,9 @@ impl RtrRenderer {
                 ShaderSource::hlsl("/shaders/rt/gbuffer.rmiss.hlsl"),
                 ShaderSource::hlsl("/shaders/rt/shadow.rmiss.hlsl"),
             ],
-            [ShaderSource::hlsl("/shaders/rt/gbuffer.rchit.hlsl")],
+            rt_hit_groups(procedural_hit_groups),
         )
+        .hit_records(procedural_hit_groups.hit_records().to_vec())
         .read(&gbuffer_depth.gbuffer)
         .read_aspect(&gbuffer_depth.depth, vk::ImageAspectFlags::DEPTH)
         .read(&ranking_tile_buf)
@@ -128,7 +145,8 @@ impl RtrRenderer {
         .write(&mut refl1_tex)
         .write(&mut refl2_tex)
         .constants((gbuffer_desc.extent_inv_extent_2d(),))
-        .raw_descriptor_set(1, bindless_descriptor_set)
+        .read(&gbuffer_depth.geometric_normal)
+        .bindless(bindless_descriptor_set)
         .trace_rays(tlas, refl0_tex.desc().extent);
 
         let mut resolved_tex = rg.create(
@@ -169,7 +187,7 @@ impl RtrRenderer {
         .read(&ray_len_history_tex)
         .write(&mut resolved_tex)
         .write(&mut ray_len_output_tex)
-        .raw_descriptor_set(1, bindless_descriptor_set)
+        .bindless(bindless_descriptor_set)
         .constants((
             resolved_tex.desc().extent_inv_extent_2d(),
             SPATIAL_RESOLVE_OFFSETS,
@@ -182,6 +200,7 @@ impl RtrRenderer {
             history_tex,
             ray_len_tex: ray_len_output_tex,
             temporal2_tex: &mut self.temporal2_tex,
+            denoiser: self.denoiser,
         }
     }
 
@@ -216,6 +235,7 @@ impl RtrRenderer {
             history_tex,
             ray_len_tex: ray_len_output_tex,
             temporal2_tex: &mut self.temporal2_tex,
+            denoiser: self.denoiser,
         }
     }
 }
@@ -227,8 +247,9 @@ impl<'a> TracedRtr<'a> {
         rg: &mut rg::TemporalRenderGraph,
         gbuffer_depth: &GbufferDepth,
         reprojection_map: &rg::Handle<Image>,
-    ) -> rg::Handle<Image> {
+    ) -> RtrOutput {
         let gbuffer_desc = gbuffer_depth.gbuffer.desc();
+        let (strength, max_history_frames) = self.denoiser.shader_params();
 
         SimpleRenderPass::new_compute(
             rg.add_pass("reflection temporal"),
@@ -240,7 +261,10 @@ impl<'a> TracedRtr<'a> {
         .read(&self.ray_len_tex)
         .read(reprojection_map)
         .write(&mut self.temporal_output_tex)
-        .constants(self.temporal_output_tex.desc().extent_inv_extent_2d())
+        .constants((
+            self.temporal_output_tex.desc().extent_inv_extent_2d(),
+            max_history_frames,
+        ))
         .dispatch(self.resolved_tex.desc().extent);
 
         let (mut temporal2_output_tex, history2_tex) = self
@@ -257,9 +281,14 @@ impl<'a> TracedRtr<'a> {
         .read(&self.ray_len_tex)
         .read(reprojection_map)
         .write(&mut temporal2_output_tex)
-        .constants(temporal2_output_tex.desc().extent_inv_extent_2d())
+        .constants((
+            temporal2_output_tex.desc().extent_inv_extent_2d(),
+            max_history_frames,
+        ))
         .dispatch(self.resolved_tex.desc().extent);
 
+        let mut cleaned_up_tex = rg.create(*self.resolved_tex.desc());
+
         SimpleRenderPass::new_compute(
             rg.add_pass("reflection cleanup"),
             "/shaders/rtr/spatial_cleanup.hlsl",
@@ -267,11 +296,14 @@ impl<'a> TracedRtr<'a> {
         .read(&temporal2_output_tex)
         .read_aspect(&gbuffer_depth.depth, vk::ImageAspectFlags::DEPTH)
         .read(&gbuffer_depth.geometric_normal)
-        .write(&mut self.resolved_tex) // reuse
-        .constants(SPATIAL_RESOLVE_OFFSETS)
-        .dispatch(self.resolved_tex.desc().extent);
+        .write(&mut cleaned_up_tex)
+        .constants((SPATIAL_RESOLVE_OFFSETS, strength, max_history_frames))
+        .dispatch(cleaned_up_tex.desc().extent);
 
-        self.resolved_tex
+        RtrOutput {
+            denoised: cleaned_up_tex,
+            noisy: self.resolved_tex,
+        }
     }
 }
 
@@ -5,12 +5,13 @@ use kajiya_backend::{
 use kajiya_rg::{self as rg};
 use rg::{RenderGraph, SimpleRenderPass};
 
-use super::GbufferDepth;
+use super::{procedural::ProceduralHitGroupRegistry, rt_shadow_hit_groups, GbufferDepth};
 
 pub fn trace_sun_shadow_mask(
     rg: &mut RenderGraph,
     gbuffer_depth: &GbufferDepth,
     tlas: &rg::Handle<RayTracingAcceleration>,
+    procedural_hit_groups: &ProceduralHitGroupRegistry,
     bindless_descriptor_set: vk::DescriptorSet,
 ) -> rg::Handle<Image> {
     let mut output_img = rg.create(gbuffer_depth.depth.desc().format(vk::Format::R8_UNORM));
@@ -23,12 +24,13 @@ pub fn trace_sun_shadow_mask(
             ShaderSource::hlsl("/shaders/rt/shadow.rmiss.hlsl"),
             ShaderSource::hlsl("/shaders/rt/shadow.rmiss.hlsl"),
         ],
-        std::iter::empty(),
+        rt_shadow_hit_groups(procedural_hit_groups),
     )
+    .hit_records(procedural_hit_groups.hit_records().to_vec())
     .read_aspect(&gbuffer_depth.depth, vk::ImageAspectFlags::DEPTH)
     .read(&gbuffer_depth.geometric_normal)
     .write(&mut output_img)
-    .raw_descriptor_set(1, bindless_descriptor_set)
+    .bindless(bindless_descriptor_set)
     .trace_rays(tlas, output_img.desc().extent);
 
     output_img
@@ -0,0 +1,231 @@
+use kajiya_backend::{ash::vk, vulkan::image::*};
+use kajiya_rg::{self as rg};
+use rg::{RenderGraph, SimpleRenderPass};
+
+use super::GbufferDepth;
+
+/// Replaces the diffuse lighting at subsurface scattering pixels of `output` with its blurred version,
+/// approximating light which enters the surface, and leaves it elsewhere. `diffuse` is that lighting,
+/// as returned by `light_gbuffer`. The blur is separable, and weighted by Burley's normalized diffusion
+/// profile, stretched per channel by the scattering distance in `GbufferDepth::subsurface`.
+pub fn scatter_subsurface(
+    rg: &mut RenderGraph,
+    gbuffer_depth: &GbufferDepth,
+    diffuse: &rg::Handle<Image>,
+    output: &mut rg::Handle<Image>,
+) {
+    let subsurface = if let Some(subsurface) = gbuffer_depth.subsurface.as_ref() {
+        subsurface
+    } else {
+        return;
+    };
+
+    let extent = diffuse.desc().extent;
+    let output_tex_size = diffuse.desc().extent_inv_extent_2d();
+
+    let mut scattered_x = rg.create(
+        diffuse
+            .desc()
+            .usage(vk::ImageUsageFlags::empty())
+            .format(vk::Format::R16G16B16A16_SFLOAT),
+    );
+
+    SimpleRenderPass::new_compute(
+        rg.add_pass("subsurface scatter x"),
+        "/shaders/subsurface/scatter.hlsl",
+    )
+    .read(diffuse)
+    .read(diffuse)
+    .read(subsurface)
+    .read(&gbuffer_depth.gbuffer)
+    .read_aspect(&gbuffer_depth.depth, vk::ImageAspectFlags::DEPTH)
+    .write(&mut scattered_x)
+    .constants((output_tex_size, [1i32, 0i32], 0u32))
+    .dispatch(extent);
+
+    SimpleRenderPass::new_compute(
+        rg.add_pass("subsurface scatter y"),
+        "/shaders/subsurface/scatter.hlsl",
+    )
+    .read(&scattered_x)
+    .read(diffuse)
+    .read(subsurface)
+    .read(&gbuffer_depth.gbuffer)
+    .read_aspect(&gbuffer_depth.depth, vk::ImageAspectFlags::DEPTH)
+    .read_write(output)
+    .constants((output_tex_size, [0i32, 1i32], 1u32))
+    .dispatch(extent);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::{CameraLens, LookThroughCamera};
+    use glam::{Quat, Vec3, Vec4};
+    use kajiya_backend::{
+        file::set_standard_vfs_mount_points, vk_sync::AccessType, HeadlessRenderBackend,
+    };
+    use rg::{
+        imageops::ClearValue,
+        readback::AsyncReadback,
+        renderer::{FrameConstantsLayout, Renderer},
+    };
+    use rust_shaders_shared::{frame_constants::FrameConstants, view_constants::ViewConstants};
+    use std::sync::Arc;
+
+    const EXTENT: [u32; 2] = [256, 64];
+
+    // Runs `scatter_subsurface` on a flat surface facing the camera, 1mm per pixel, lit on the left half,
+    // with a hard shadow on the right. Every pixel scatters by `scattering_distance`, and has zero albedo.
+    // Returns the red and blue channels of the middle row.
+    fn scatter_shadow_edge(scattering_distance: [f32; 3]) -> (Vec<f32>, Vec<f32>) {
+        set_standard_vfs_mount_points(concat!(env!("CARGO_MANIFEST_DIR"), "/../../.."));
+
+        let backend = HeadlessRenderBackend::new(Default::default()).unwrap();
+        let device = backend.device.clone();
+        let mut renderer = Renderer::new(&device).unwrap();
+        let mut readback = AsyncReadback::default();
+
+        let camera = (Vec3::ZERO, Quat::IDENTITY).through(&CameraLens {
+            aspect_ratio: EXTENT[0] as f32 / EXTENT[1] as f32,
+            ..Default::default()
+        });
+
+        // The distance at which a pixel covers 1mm, and the depth of a plane there
+        let pixel_size = 0.001;
+        let distance = pixel_size * camera.view_to_clip.y_axis.y * EXTENT[1] as f32 / 2.0;
+        let depth = camera
+            .view_to_clip
+            .project_point3(Vec3::new(0.0, 0.0, -distance))
+            .z;
+
+        let frame_constants = FrameConstants {
+            view_constants: ViewConstants::builder(camera, camera, EXTENT).build(),
+            sun_direction: Vec4::Y,
+            frame_index: 0,
+            delta_time_seconds: 1.0 / 60.0,
+            sun_angular_radius_cos: 1.0,
+            triangle_light_count: 0,
+            sun_color_multiplier: Vec4::ONE,
+            sky_ambient: Vec4::ZERO,
+            world_gi_scale: 1.0,
+            ray_origin_offset_method: 0,
+            ray_origin_offset_scale: 0.0,
+            pad0: 0,
+            gi_cascades: Default::default(),
+        };
+
+        let texels: Vec<f32> = (0..EXTENT[0] * EXTENT[1])
+            .flat_map(|idx| {
+                let lit = if idx % EXTENT[0] < EXTENT[0] / 2 {
+                    1.0
+                } else {
+                    0.0
+                };
+                [lit, lit, lit, 1.0]
+            })
+            .collect();
+        let diffuse = Arc::new(
+            device
+                .create_image(
+                    ImageDesc::new_2d(vk::Format::R32G32B32A32_SFLOAT, EXTENT)
+                        .usage(vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_SRC),
+                    "subsurface test diffuse",
+                    vec![ImageSubResourceData {
+                        data: bytemuck::cast_slice(&texels),
+                        row_pitch: EXTENT[0] as usize * 16,
+                        slice_pitch: 0,
+                    }],
+                )
+                .unwrap(),
+        );
+
+        readback.begin_frame();
+        let mut token = None;
+        renderer
+            .prepare_frame(|rg| {
+                let diffuse = rg.import(
+                    diffuse.clone(),
+                    AccessType::AnyShaderReadSampledImageOrUniformTexelBuffer,
+                );
+
+                let normal = rg.create(ImageDesc::new_2d(
+                    vk::Format::A2R10G10B10_UNORM_PACK32,
+                    EXTENT,
+                ));
+                let mut gbuffer =
+                    rg.create(ImageDesc::new_2d(vk::Format::R32G32B32A32_SFLOAT, EXTENT));
+                rg::imageops::clear_image(rg, &mut gbuffer, ClearValue::ColorUint([0; 4]));
+                let mut depth_img = rg.create(ImageDesc::new_2d(vk::Format::D32_SFLOAT, EXTENT));
+                rg::imageops::clear_image(
+                    rg,
+                    &mut depth_img,
+                    ClearValue::DepthStencil { depth, stencil: 0 },
+                );
+                let mut subsurface =
+                    rg.create(ImageDesc::new_2d(vk::Format::R16G16B16A16_SFLOAT, EXTENT));
+                let [r, g, b] = scattering_distance;
+                rg::imageops::clear_image(rg, &mut subsurface, ClearValue::Color([r, g, b, 0.0]));
+
+                let mut gbuffer_depth = GbufferDepth::new(normal, gbuffer, depth_img);
+                gbuffer_depth.subsurface = Some(subsurface);
+
+                // Composited onto the diffuse lighting alone, so that the output is just the scattered light.
+                let mut output = rg.create(*diffuse.desc());
+                rg::imageops::copy_image(rg, &diffuse, &mut output);
+                scatter_subsurface(rg, &gbuffer_depth, &diffuse, &mut output);
+
+                token = Some(readback.copy_image(rg, &output, 16));
+            })
+            .unwrap();
+        renderer
+            .draw_frame_headless(|dynamic_constants| FrameConstantsLayout {
+                globals_offset: dynamic_constants.push(&frame_constants),
+                instance_dynamic_parameters_offset: 0,
+                triangle_lights_offset: 0,
+            })
+            .unwrap();
+        readback.retire_frame(device.frame_counter());
+
+        let bytes = readback.wait(&device, token.unwrap()).unwrap();
+        let texels = bytemuck::cast_slice::<u8, [f32; 4]>(&bytes);
+        let row = &texels[(EXTENT[1] / 2 * EXTENT[0]) as usize..][..EXTENT[0] as usize];
+        (
+            row.iter().map(|texel| texel[0]).collect(),
+            row.iter().map(|texel| texel[2]).collect(),
+        )
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn light_bleeds_across_a_shadow_edge() {
+        // Skin-like scattering, which reaches further in red
+        let (red, blue) = scatter_shadow_edge([0.01, 0.005, 0.002]);
+        let edge = EXTENT[0] as usize / 2;
+
+        // Light bleeds into the shadow, fading with the distance from the edge,
+        // and the lit side darkens near the edge by as much.
+        assert!(red[edge] > 0.05, "{}", red[edge]);
+        assert!(red[edge] > red[edge + 5] && red[edge + 5] > red[edge + 20]);
+        assert!(red[edge - 1] < 0.95, "{}", red[edge - 1]);
+        let bled: f32 = red[edge..].iter().sum();
+        let lost: f32 = red[..edge].iter().map(|v| 1.0 - v).sum();
+        assert!((bled - lost).abs() < 0.1 * bled, "{} vs {}", bled, lost);
+
+        // The longer scattering distance in red tints the penumbra.
+        assert!(red[edge + 5] > blue[edge + 5]);
+
+        // Far from the edge, the surface is unaffected.
+        assert!((red[10] - 1.0).abs() < 1e-3, "{}", red[10]);
+        assert!(red[EXTENT[0] as usize - 10] < 1e-3);
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn surfaces_without_scattering_are_left_alone() {
+        let (red, _) = scatter_shadow_edge([0.0; 3]);
+        let edge = EXTENT[0] as usize / 2;
+        assert!(red[..edge].iter().all(|&v| v == 1.0));
+        assert!(red[edge..].iter().all(|&v| v == 0.0));
+    }
+}
@@ -0,0 +1,479 @@
+// Text overlay, for on-screen stats.
+//
+// Strings pushed into `TextOverlay` are laid out as one quad per character, uploaded through the
+// per-frame dynamic constants, and rasterized at the output resolution with a small bitmap font
+// baked into this file. The text is then blended over the final image, and cleared.
+
+use std::sync::Arc;
+
+use glam::{Vec2, Vec4};
+use kajiya_backend::{
+    ash::vk,
+    dynamic_constants::MAX_DYNAMIC_CONSTANTS_STORAGE_BUFFER_BYTES,
+    vk_sync::AccessType,
+    vulkan::{image::*, shader::*},
+    Device,
+};
+use kajiya_rg::{self as rg};
+use rg::{IntoRenderPassPipelineBinding, RenderPassBinding};
+
+use super::composite_overlay;
+
+// Must match `text/text_common.hlsl`
+pub const GLYPH_WIDTH: u32 = 5;
+pub const GLYPH_HEIGHT: u32 = 7;
+
+/// Horizontal distance between characters, in font pixels.
+pub const GLYPH_ADVANCE: u32 = GLYPH_WIDTH + 1;
+
+/// Vertical distance between lines, in font pixels.
+pub const LINE_HEIGHT: u32 = GLYPH_HEIGHT + 3;
+
+// Must match `text/text_ps.hlsl`
+const SHADOW_OPACITY: f32 = 0.75;
+
+/// Characters beyond this many per frame are dropped.
+pub const MAX_TEXT_GLYPHS: usize =
+    MAX_DYNAMIC_CONSTANTS_STORAGE_BUFFER_BYTES / std::mem::size_of::<GlyphQuad>();
+
+// Must match `GlyphQuad` in `text/text_common.hlsl`
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct GlyphQuad {
+    // Top-left corner, in output pixels
+    position: [f32; 2],
+    scale: f32,
+    glyph: u32,
+    color: [f32; 4],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct TextPushConstants {
+    output_tex_size: [f32; 4],
+}
+
+unsafe impl bytemuck::Zeroable for TextPushConstants {}
+unsafe impl bytemuck::Pod for TextPushConstants {}
+
+/// Collects text to draw over the next frame.
+#[derive(Default)]
+pub struct TextOverlay {
+    glyphs: Vec<GlyphQuad>,
+}
+
+impl TextOverlay {
+    pub fn is_empty(&self) -> bool {
+        self.glyphs.is_empty()
+    }
+
+    /// Drops the text pushed so far.
+    pub fn clear(&mut self) {
+        self.glyphs.clear();
+    }
+
+    /// Draws `text` with its top-left corner at `position`, in pixels of the output image.
+    /// Each font pixel covers `scale` by `scale` output pixels. Lines are separated by `'\n'`;
+    /// lowercase letters are drawn as uppercase, and other characters missing from the font as `'?'`.
+    pub fn text(&mut self, position: Vec2, scale: u32, color: Vec4, text: &str) {
+        let scale = scale.max(1) as f32;
+        let color = color.max(Vec4::ZERO).into();
+
+        let mut line_start = position;
+        let mut cursor = position;
+
+        for c in text.chars() {
+            if c == '\n' {
+                line_start.y += (LINE_HEIGHT as f32) * scale;
+                cursor = line_start;
+                continue;
+            }
+
+            if !c.is_whitespace() {
+                self.glyphs.push(GlyphQuad {
+                    position: cursor.into(),
+                    scale,
+                    glyph: glyph_index(c),
+                    color,
+                });
+            }
+
+            cursor.x += (GLYPH_ADVANCE as f32) * scale;
+        }
+    }
+
+    /// Size of `text` drawn at `scale`, in output pixels, as laid out by `text`.
+    pub fn measure(text: &str, scale: u32) -> Vec2 {
+        let scale = scale.max(1) as f32;
+        let line_count = text.split('\n').count();
+        let longest_line = text
+            .split('\n')
+            .map(|line| line.chars().count())
+            .max()
+            .unwrap_or(0);
+
+        Vec2::new(
+            (longest_line as u32 * GLYPH_ADVANCE) as f32,
+            ((line_count as u32 - 1) * LINE_HEIGHT + GLYPH_HEIGHT) as f32,
+        ) * scale
+    }
+}
+
+pub struct TextOverlayRenderer {
+    render_pass: Arc<RenderPass>,
+}
+
+impl TextOverlayRenderer {
+    pub fn new(device: &Device) -> Self {
+        Self {
+            render_pass: create_render_pass(
+                device,
+                RenderPassDesc {
+                    // Premultiplied color of the text
+                    color_attachments: &[RenderPassAttachmentDesc::new(vk::Format::R8G8B8A8_UNORM)],
+                    depth_attachment: None,
+                },
+            ),
+        }
+    }
+
+    /// Draws the text of `overlay` over the final `output`, and clears it.
+    pub fn render(
+        &self,
+        rg: &mut rg::RenderGraph,
+        overlay: &mut TextOverlay,
+        output: &mut rg::Handle<Image>,
+    ) {
+        if overlay.is_empty() {
+            return;
+        }
+
+        let mut glyphs = std::mem::take(&mut overlay.glyphs);
+        if glyphs.len() > MAX_TEXT_GLYPHS {
+            log::warn!(
+                "Too many text overlay characters ({}); only drawing the first {}",
+                glyphs.len(),
+                MAX_TEXT_GLYPHS
+            );
+            glyphs.truncate(MAX_TEXT_GLYPHS);
+        }
+
+        let mut overlay_tex = rg.create(ImageDesc::new_2d(
+            vk::Format::R8G8B8A8_UNORM,
+            output.desc().extent_2d(),
+        ));
+        rg::imageops::clear_color(rg, &mut overlay_tex, [0.0; 4]);
+
+        {
+            let mut pass = rg.add_pass("text overlay");
+
+            let pipeline = pass.register_raster_pipeline(
+                &[
+                    PipelineShaderDesc::builder(ShaderPipelineStage::Vertex)
+                        .hlsl_source("/shaders/text/text_vs.hlsl")
+                        .build()
+                        .unwrap(),
+                    PipelineShaderDesc::builder(ShaderPipelineStage::Pixel)
+                        .hlsl_source("/shaders/text/text_ps.hlsl")
+                        .build()
+                        .unwrap(),
+                ],
+                RasterPipelineDesc::builder()
+                    .render_pass(self.render_pass.clone())
+                    .depth_test(false)
+                    .depth_write(false)
+                    .blend_mode(RasterBlendMode::PremultipliedAlpha)
+                    .push_constants_bytes(std::mem::size_of::<TextPushConstants>()),
+            );
+
+            let overlay_ref = pass.raster(&mut overlay_tex, AccessType::ColorAttachmentReadWrite);
+
+            let [width, height, _] = overlay_ref.desc().extent;
+            let render_pass = self.render_pass.clone();
+            let font_bits: Vec<[u32; 2]> = FONT.iter().map(|(_, bits)| *bits).collect();
+            let push_constants = TextPushConstants {
+                output_tex_size: overlay_ref.desc().extent_inv_extent_2d(),
+            };
+
+            pass.render(move |api| {
+                let glyphs_offset = api
+                    .dynamic_constants()
+                    .push_from_iter(glyphs.iter().copied());
+                let font_offset = api
+                    .dynamic_constants()
+                    .push_from_iter(font_bits.into_iter());
+
+                api.begin_render_pass(
+                    &*render_pass,
+                    [width, height],
+                    &[(overlay_ref, &ImageViewDesc::default())],
+                    None,
+                );
+
+                api.set_default_view_and_scissor([width, height]);
+
+                let pipeline = match api.bind_raster_pipeline(
+                    pipeline
+                        .into_binding()
+                        .storage_buffer(
+                            "glyphs_dyn",
+                            RenderPassBinding::DynamicConstantsStorageBuffer(glyphs_offset),
+                        )
+                        .storage_buffer(
+                            "font_dyn",
+                            RenderPassBinding::DynamicConstantsStorageBuffer(font_offset),
+                        ),
+                ) {
+                    Ok(pipeline) => pipeline,
+                    Err(err) => {
+                        log::error!("text overlay: {}", err);
+                        api.end_render_pass();
+                        return;
+                    }
+                };
+
+                unsafe {
+                    let cb = api.cb;
+
+                    if let Err(err) = pipeline.push_constants(
+                        cb.raw,
+                        vk::ShaderStageFlags::ALL_GRAPHICS,
+                        0,
+                        bytemuck::bytes_of(&push_constants),
+                    ) {
+                        log::error!("text overlay: {}", err);
+                    } else {
+                        // Two triangles per character
+                        api.device()
+                            .raw
+                            .cmd_draw(cb.raw, glyphs.len() as u32 * 6, 1, 0, 0);
+                    }
+                }
+
+                api.end_render_pass();
+            });
+        }
+
+        composite_overlay(rg, "text overlay composite", &overlay_tex, output);
+    }
+}
+
+lazy_static::lazy_static! {
+    // The characters of `FONT_ATLAS` in reading order, with one bit per texel, row by row,
+    // split into two words.
+    static ref FONT: Vec<(char, [u32; 2])> = font_glyphs()
+        .map(|(c, rows)| {
+            let mut bits = 0u64;
+            for (y, row) in rows.iter().enumerate() {
+                for (x, texel) in row.chars().enumerate() {
+                    if texel == '#' {
+                        bits |= 1 << (y * GLYPH_WIDTH as usize + x);
+                    }
+                }
+            }
+
+            (c, [bits as u32, (bits >> 32) as u32])
+        })
+        .collect();
+}
+
+/// Index of the glyph drawn for `c` in `FONT`.
+fn glyph_index(c: char) -> u32 {
+    let c = c.to_ascii_uppercase();
+
+    FONT.iter()
+        .position(|(glyph, _)| *glyph == c)
+        .or_else(|| FONT.iter().position(|(glyph, _)| *glyph == '?'))
+        .unwrap() as u32
+}
+
+// The characters of `FONT_ATLAS` and their rows of texels, in reading order.
+fn font_glyphs() -> impl Iterator<Item = (char, Vec<&'static str>)> {
+    let stride = GLYPH_ADVANCE as usize;
+
+    FONT_ATLAS
+        .trim_start_matches('\n')
+        .split("\n\n")
+        .flat_map(move |block| {
+            let mut lines = block.lines();
+            let labels: Vec<char> = lines.next().unwrap().chars().step_by(stride).collect();
+            let rows: Vec<&'static str> = lines.collect();
+
+            labels.into_iter().enumerate().map(move |(i, label)| {
+                let glyph_rows = rows
+                    .iter()
+                    .map(|row| &row[i * stride..i * stride + GLYPH_WIDTH as usize])
+                    .collect();
+                (label, glyph_rows)
+            })
+        })
+}
+
+// Each block lists the characters, and below them their texels.
+const FONT_ATLAS: &str = r#"
+!     "     #     %     '     (     )     *     +     ,
+..#.. .#.#. .#.#. ##... ..#.. ...#. .#... ..... ..... .....
+..#.. .#.#. .#.#. ##..# ..#.. ..#.. ..#.. ..#.. ..#.. .....
+..#.. ..... ##### ...#. ..... .#... ...#. #.#.# ..#.. .....
+..#.. ..... .#.#. ..#.. ..... .#... ...#. .###. ##### .....
+..#.. ..... ##### .#... ..... .#... ...#. #.#.# ..#.. .##..
+..... ..... .#.#. #..## ..... ..#.. ..#.. ..#.. ..#.. ..#..
+..#.. ..... .#.#. ...## ..... ...#. .#... ..... ..... .#...
+
+-     .     /     0     1     2     3     4     5     6
+..... ..... ..... .###. ..#.. .###. ##### ...#. ##### ..##.
+..... ..... ....# #...# .##.. #...# ...#. ..##. #.... .#...
+..... ..... ...#. #..## ..#.. ....# ..#.. .#.#. ####. #....
+##### ..... ..#.. #.#.# ..#.. ...#. ...#. #..#. ....# ####.
+..... ..... .#... ##..# ..#.. ..#.. ....# ##### ....# #...#
+..... .##.. #.... #...# ..#.. .#... #...# ...#. #...# #...#
+..... .##.. ..... .###. .###. ##### .###. ...#. .###. .###.
+
+7     8     9     :     ;     <     =     >     ?     A
+##### .###. .###. ..... ..... ...#. ..... .#... .###. .###.
+....# #...# #...# .##.. .##.. ..#.. ..... ..#.. #...# #...#
+...#. #...# #...# .##.. .##.. .#... ##### ...#. ....# #...#
+..#.. .###. .#### ..... ..... #.... ..... ....# ...#. #####
+.#... #...# ....# .##.. .##.. .#... ##### ...#. ..#.. #...#
+.#... #...# ...#. .##.. ..#.. ..#.. ..... ..#.. ..... #...#
+.#... .###. .##.. ..... .#... ...#. ..... .#... ..#.. #...#
+
+B     C     D     E     F     G     H     I     J     K
+####. .###. ###.. ##### ##### .###. #...# .###. ..### #...#
+#...# #...# #..#. #.... #.... #...# #...# ..#.. ...#. #..#.
+#...# #.... #...# #.... #.... #.... #...# ..#.. ...#. #.#..
+####. #.... #...# ####. ####. #.### ##### ..#.. ...#. ##...
+#...# #.... #...# #.... #.... #...# #...# ..#.. ...#. #.#..
+#...# #...# #..#. #.... #.... #...# #...# ..#.. #..#. #..#.
+####. .###. ###.. ##### #.... .#### #...# .###. .##.. #...#
+
+L     M     N     O     P     Q     R     S     T     U
+#.... #...# #...# .###. ####. .###. ####. .#### ##### #...#
+#.... ##.## #...# #...# #...# #...# #...# #.... ..#.. #...#
+#.... #.#.# ##..# #...# #...# #...# #...# #.... ..#.. #...#
+#.... #.#.# #.#.# #...# ####. #...# ####. .###. ..#.. #...#
+#.... #...# #..## #...# #.... #.#.# #.#.. ....# ..#.. #...#
+#.... #...# #...# #...# #.... #..#. #..#. ....# ..#.. #...#
+##### #...# #...# .###. #.... .##.# #...# ####. ..#.. .###.
+
+V     W     X     Y     Z     [     \     ]     _     |
+#...# #...# #...# #...# ##### .###. ..... .###. ..... ..#..
+#...# #...# #...# #...# ....# .#... #.... ...#. ..... ..#..
+#...# #...# .#.#. .#.#. ...#. .#... .#... ...#. ..... ..#..
+#...# #.#.# ..#.. ..#.. ..#.. .#... ..#.. ...#. ..... ..#..
+#...# #.#.# .#.#. ..#.. .#... .#... ...#. ...#. ..... ..#..
+.#.#. #.#.# #...# ..#.. #.... .#... ....# ...#. ..... ..#..
+..#.. .#.#. #...# ..#.. ##### .###. ..... .###. ##### ..#.."#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kajiya_backend::{file::set_standard_vfs_mount_points, HeadlessRenderBackend};
+    use rg::{
+        readback::AsyncReadback,
+        renderer::{FrameConstantsLayout, Renderer},
+    };
+
+    // Draws `overlay` over a grey image; returns the red channel per pixel.
+    fn render_overlay(overlay: &mut TextOverlay, extent: [u32; 2]) -> Vec<f32> {
+        set_standard_vfs_mount_points(concat!(env!("CARGO_MANIFEST_DIR"), "/../../.."));
+
+        let backend = HeadlessRenderBackend::new(Default::default()).unwrap();
+        let device = backend.device.clone();
+        let mut renderer = Renderer::new(&device).unwrap();
+        let mut readback = AsyncReadback::default();
+        let text_renderer = TextOverlayRenderer::new(&device);
+
+        readback.begin_frame();
+        let mut token = None;
+        renderer
+            .prepare_frame(|rg| {
+                let mut output =
+                    rg.create(ImageDesc::new_2d(vk::Format::R32G32B32A32_SFLOAT, extent));
+                rg::imageops::clear_color(rg, &mut output, [0.5, 0.5, 0.5, 1.0]);
+                text_renderer.render(rg, overlay, &mut output);
+                token = Some(readback.copy_image(rg, &output, 16));
+            })
+            .unwrap();
+        renderer
+            .draw_frame_headless(|_| FrameConstantsLayout {
+                globals_offset: 0,
+                instance_dynamic_parameters_offset: 0,
+                triangle_lights_offset: 0,
+            })
+            .unwrap();
+        readback.retire_frame(device.frame_counter());
+
+        let bytes = readback.wait(&device, token.unwrap()).unwrap();
+        bytemuck::cast_slice::<u8, [f32; 4]>(&bytes)
+            .iter()
+            .map(|texel| texel[0])
+            .collect()
+    }
+
+    #[test]
+    fn font_atlas_has_every_glyph() {
+        assert_eq!(FONT.len(), 60);
+        assert!(font_glyphs().all(|(_, rows)| rows.len() == GLYPH_HEIGHT as usize));
+
+        assert_eq!(FONT[glyph_index('a') as usize].0, 'A');
+        assert_eq!(FONT[glyph_index('7') as usize].0, '7');
+        assert_eq!(FONT[glyph_index('~') as usize].0, '?');
+
+        // Every glyph other than blank space has texels.
+        assert!(FONT.iter().all(|(_, bits)| bits != &[0, 0]));
+    }
+
+    #[test]
+    fn lays_out_a_quad_per_character() {
+        let mut overlay = TextOverlay::default();
+        overlay.text(Vec2::new(2.0, 2.0), 1, Vec4::ONE, "FPS: 60\nGPU: 16.7ms");
+
+        // Spaces don't make quads.
+        assert_eq!(overlay.glyphs.len(), 16);
+        assert_eq!(
+            TextOverlay::measure("FPS: 60\nGPU: 16.7ms", 1),
+            Vec2::new(
+                (11 * GLYPH_ADVANCE) as f32,
+                (LINE_HEIGHT + GLYPH_HEIGHT) as f32
+            )
+        );
+
+        overlay.clear();
+        overlay.text(Vec2::ZERO, 2, Vec4::ONE, " \n ");
+        assert!(overlay.is_empty());
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn renders_a_string() {
+        let extent = [80, 32];
+        let width = extent[0] as usize;
+
+        let mut overlay = TextOverlay::default();
+        overlay.text(Vec2::new(2.0, 2.0), 1, Vec4::ONE, "FPS: 60\nGPU: 16.7ms");
+
+        let red = render_overlay(&mut overlay, extent);
+        assert!(overlay.is_empty());
+
+        let covered = red.iter().filter(|&&r| r != 0.5).count();
+        assert!(covered > 100);
+
+        // The top stem of the 'F', and the pixel diagonally inside of its corner, which is shadow.
+        assert_eq!(red[2 * width + 2], 1.0);
+        let shadowed = 0.5 * (1.0 - SHADOW_OPACITY);
+        assert!(
+            (red[3 * width + 3] - shadowed).abs() < 0.01,
+            "{}",
+            red[3 * width + 3]
+        );
+
+        // Nothing below the second line, nor on the right.
+        assert!(
+            red[(2 + LINE_HEIGHT as usize + GLYPH_HEIGHT as usize + 2) * width..]
+                .iter()
+                .all(|&r| r == 0.5)
+        );
+        assert!((0..extent[1] as usize).all(|y| red[y * width + width - 1] == 0.5));
+    }
+}
@@ -0,0 +1,798 @@
+// Forward rendering of transparent surfaces, such as glass and water.
+//
+// Transparent instances are skipped by the gbuffer pass, and instead drawn over the lit
+// opaque scene with depth testing but no depth writes. They are either sorted back-to-front
+// and alpha blended, or accumulated in any order with weighted blended OIT, and resolved after.
+
+use std::sync::Arc;
+
+use glam::Vec3;
+use kajiya_backend::{
+    ash::vk,
+    vk_sync::AccessType,
+    vulkan::{buffer::*, image::*, shader::*},
+    Device,
+};
+use kajiya_rg::{self as rg, BindRgRef, SimpleRenderPass};
+use rg::{IntoRenderPassPipelineBinding, RenderPassBinding};
+
+use crate::world_renderer::MeshInstance;
+
+use super::{
+    particles::SimulatedParticles,
+    raster_meshes::{row_major_3x4, InstanceTransform, UploadedTriMesh},
+    GbufferDepth,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TransparentMaterial {
+    /// Filters the light seen through the surface.
+    pub tint: Vec3,
+
+    /// Fraction of the surface's own (refracted and reflected) light which replaces
+    /// what's behind it. The rest is blended over without refraction, which lets
+    /// other transparent surfaces behind this one show through.
+    pub opacity: f32,
+
+    /// Index of refraction; controls the strength of Fresnel reflections.
+    pub ior: f32,
+
+    /// Screen-space offset of the refracted image, as a fraction of the screen per unit of normal tilt.
+    pub refraction_strength: f32,
+
+    /// Distance in world units over which the surface fades out where it meets opaque geometry.
+    pub soft_intersection_distance: f32,
+}
+
+impl Default for TransparentMaterial {
+    fn default() -> Self {
+        Self {
+            tint: Vec3::ONE,
+            opacity: 1.0,
+            ior: 1.5,
+            refraction_strength: 0.03,
+            soft_intersection_distance: 0.1,
+        }
+    }
+}
+
+impl TransparentMaterial {
+    pub fn water() -> Self {
+        Self {
+            tint: Vec3::new(0.6, 0.85, 0.9),
+            ior: 1.33,
+            soft_intersection_distance: 0.3,
+            ..Default::default()
+        }
+    }
+}
+
+// Matches `TransparentPushConstants` in `transparency/forward_common.hlsl`
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct TransparentPushConstants {
+    tint_opacity: [f32; 4],
+    oit_weight: [f32; 4],
+    ior: f32,
+    refraction_strength: f32,
+    soft_intersection_distance: f32,
+    draw_index: u32,
+    mesh_index: u32,
+}
+
+unsafe impl bytemuck::Zeroable for TransparentPushConstants {}
+unsafe impl bytemuck::Pod for TransparentPushConstants {}
+
+pub struct TransparentMeshesData<'a> {
+    pub meshes: &'a [UploadedTriMesh],
+    pub instances: &'a [MeshInstance],
+    pub vertex_buffer: Arc<Buffer>,
+    pub bindless_descriptor_set: vk::DescriptorSet,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TransparencyMode {
+    /// Instances are sorted back-to-front, and alpha blended in order.
+    Sorted,
+
+    /// Weighted blended order-independent transparency (McGuire and Bavoil 2013).
+    /// No sorting is needed, making it suitable for many overlapping layers, such as
+    /// foliage or particles, at the cost of approximate compositing.
+    WeightedBlended,
+}
+
+/// Weights of surfaces in weighted blended OIT: `alpha * clamp((depth_scale / distance)^depth_exponent, min_weight, max_weight)`.
+/// Closer surfaces get larger weights, approximating the dominance of the front-most layers in sorted blending.
+#[derive(Clone, Copy, Debug)]
+pub struct OitWeightParams {
+    /// View distance, in world units, at which the depth term of the weight is one.
+    pub depth_scale: f32,
+
+    /// How quickly the weight falls off with distance. Higher values better separate
+    /// layers at different depths, but risk precision issues in the accumulation targets.
+    pub depth_exponent: f32,
+
+    pub min_weight: f32,
+    pub max_weight: f32,
+}
+
+impl Default for OitWeightParams {
+    fn default() -> Self {
+        Self {
+            depth_scale: 5.0,
+            depth_exponent: 3.0,
+            min_weight: 1e-2,
+            max_weight: 3e3,
+        }
+    }
+}
+
+impl OitWeightParams {
+    fn sanitized(&self) -> Self {
+        let min_weight = self.min_weight.max(1e-6);
+        Self {
+            depth_scale: self.depth_scale.max(1e-3),
+            depth_exponent: self.depth_exponent.max(0.0),
+            min_weight,
+            max_weight: self.max_weight.max(min_weight),
+        }
+    }
+
+    // Layout of the `oit_weight` shader parameters
+    fn packed(&self) -> [f32; 4] {
+        let params = self.sanitized();
+        [
+            params.depth_scale,
+            params.depth_exponent,
+            params.min_weight,
+            params.max_weight,
+        ]
+    }
+}
+
+pub struct TransparencyRenderer {
+    pub mode: TransparencyMode,
+    pub oit_weight: OitWeightParams,
+    sorted_render_pass: Arc<RenderPass>,
+    oit_render_pass: Arc<RenderPass>,
+}
+
+// Everything the transparent surface shader samples; see `transparency/shading.hlsl`.
+struct ShadingInputs<'a> {
+    scene_color: rg::Handle<Image>,
+    scene_depth: rg::Handle<Image>,
+    reflection: &'a rg::Handle<Image>,
+    sky_cube: &'a rg::Handle<Image>,
+}
+
+impl TransparencyRenderer {
+    pub fn new(device: &Device) -> Self {
+        Self {
+            mode: TransparencyMode::Sorted,
+            oit_weight: Default::default(),
+            sorted_render_pass: create_render_pass(
+                device,
+                RenderPassDesc {
+                    color_attachments: &[RenderPassAttachmentDesc::new(
+                        vk::Format::R16G16B16A16_SFLOAT,
+                    )],
+                    depth_attachment: Some(
+                        RenderPassAttachmentDesc::new(vk::Format::D32_SFLOAT).read_only(),
+                    ),
+                },
+            ),
+            oit_render_pass: create_render_pass(
+                device,
+                RenderPassDesc {
+                    color_attachments: &[
+                        // rgb: sum of weighted premultiplied color; a: sum of weighted alpha
+                        RenderPassAttachmentDesc::new(vk::Format::R16G16B16A16_SFLOAT),
+                        // sum of -ln(1 - alpha)
+                        RenderPassAttachmentDesc::new(vk::Format::R16_SFLOAT),
+                    ],
+                    depth_attachment: Some(
+                        RenderPassAttachmentDesc::new(vk::Format::D32_SFLOAT).read_only(),
+                    ),
+                },
+            ),
+        }
+    }
+
+    /// Draws the transparent instances from `mesh_data`, and then `particles`, over `output`, which
+    /// should contain the lit opaque scene. `reflection` is the filtered specular of the opaque surfaces.
+    #[allow(clippy::too_many_arguments)]
+    pub fn render(
+        &self,
+        rg: &mut rg::RenderGraph,
+        gbuffer_depth: &mut GbufferDepth,
+        reflection: &rg::Handle<Image>,
+        sky_cube: &rg::Handle<Image>,
+        eye_position: Vec3,
+        mesh_data: TransparentMeshesData<'_>,
+        particles: &SimulatedParticles,
+        output: &mut rg::Handle<Image>,
+    ) {
+        let draw_order = sort_back_to_front(mesh_data.instances, eye_position);
+        if draw_order.is_empty() && particles.is_empty() {
+            return;
+        }
+
+        // The opaque scene is sampled for refraction; it can't be read while bound as a render target.
+        let mut scene_color = rg.create(*output.desc());
+        SimpleRenderPass::new_compute(rg.add_pass("copy scene color"), "/shaders/copy_color.hlsl")
+            .read(output)
+            .write(&mut scene_color)
+            .dispatch(output.desc().extent);
+
+        // Likewise for depth, used to soften intersections with opaque geometry.
+        let mut scene_depth = rg.create(gbuffer_depth.depth.desc().format(vk::Format::R32_SFLOAT));
+        SimpleRenderPass::new_compute_rust(
+            rg.add_pass("copy scene depth"),
+            "copy_depth_to_r::copy_depth_to_r_cs",
+        )
+        .read_aspect(&gbuffer_depth.depth, vk::ImageAspectFlags::DEPTH)
+        .write(&mut scene_depth)
+        .dispatch(scene_depth.desc().extent);
+
+        let inputs = ShadingInputs {
+            scene_color,
+            scene_depth,
+            reflection,
+            sky_cube,
+        };
+
+        match self.mode {
+            TransparencyMode::Sorted => {
+                self.raster_instances(
+                    rg,
+                    "forward transparency",
+                    self.sorted_render_pass.clone(),
+                    "/shaders/transparency/forward_ps.hlsl",
+                    RasterBlendMode::PremultipliedAlpha,
+                    &inputs,
+                    &mut gbuffer_depth.depth,
+                    &mut [&mut *output],
+                    draw_order,
+                    mesh_data,
+                );
+
+                particles.raster(
+                    rg,
+                    self.sorted_render_pass.clone(),
+                    "/shaders/particles/billboard_ps.hlsl",
+                    RasterBlendMode::PremultipliedAlpha,
+                    self.oit_weight.packed(),
+                    &inputs.scene_depth,
+                    sky_cube,
+                    &mut gbuffer_depth.depth,
+                    &mut [output],
+                );
+            }
+            TransparencyMode::WeightedBlended => {
+                let mut oit_targets = OitTargets::new(rg, output.desc().extent_2d());
+
+                self.raster_instances(
+                    rg,
+                    "oit accumulate",
+                    self.oit_render_pass.clone(),
+                    "/shaders/transparency/oit_accumulate_ps.hlsl",
+                    RasterBlendMode::Additive,
+                    &inputs,
+                    &mut gbuffer_depth.depth,
+                    &mut [&mut oit_targets.accum, &mut oit_targets.optical_depth],
+                    draw_order,
+                    mesh_data,
+                );
+
+                particles.raster(
+                    rg,
+                    self.oit_render_pass.clone(),
+                    "/shaders/particles/billboard_oit_ps.hlsl",
+                    RasterBlendMode::Additive,
+                    self.oit_weight.packed(),
+                    &inputs.scene_depth,
+                    sky_cube,
+                    &mut gbuffer_depth.depth,
+                    &mut [&mut oit_targets.accum, &mut oit_targets.optical_depth],
+                );
+
+                oit_targets.resolve(rg, output);
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn raster_instances(
+        &self,
+        rg: &mut rg::RenderGraph,
+        name: &'static str,
+        render_pass: Arc<RenderPass>,
+        pixel_shader: &'static str,
+        blend_mode: RasterBlendMode,
+        inputs: &ShadingInputs,
+        depth: &mut rg::Handle<Image>,
+        targets: &mut [&mut rg::Handle<Image>],
+        draw_order: Vec<usize>,
+        mesh_data: TransparentMeshesData<'_>,
+    ) {
+        if draw_order.is_empty() {
+            return;
+        }
+
+        let mut pass = rg.add_pass(name);
+
+        let pipeline = pass.register_raster_pipeline(
+            &[
+                PipelineShaderDesc::builder(ShaderPipelineStage::Vertex)
+                    .hlsl_source("/shaders/transparency/forward_vs.hlsl")
+                    .build()
+                    .unwrap(),
+                PipelineShaderDesc::builder(ShaderPipelineStage::Pixel)
+                    .hlsl_source(pixel_shader)
+                    .build()
+                    .unwrap(),
+            ],
+            RasterPipelineDesc::builder()
+                .render_pass(render_pass.clone())
+                .face_cull(false)
+                .depth_write(false)
+                .blend_mode(blend_mode),
+        );
+
+        let scene_color_ref = pass.read(
+            &inputs.scene_color,
+            AccessType::AnyShaderReadSampledImageOrUniformTexelBuffer,
+        );
+        let scene_depth_ref = pass.read(
+            &inputs.scene_depth,
+            AccessType::AnyShaderReadSampledImageOrUniformTexelBuffer,
+        );
+        let reflection_ref = pass.read(
+            inputs.reflection,
+            AccessType::AnyShaderReadSampledImageOrUniformTexelBuffer,
+        );
+        let sky_cube_ref = pass.read(
+            inputs.sky_cube,
+            AccessType::AnyShaderReadSampledImageOrUniformTexelBuffer,
+        );
+
+        // Depth testing only; the pipeline doesn't write it.
+        let depth_ref = pass.raster(depth, AccessType::DepthStencilAttachmentRead);
+
+        // Blending reads the previous contents.
+        let target_refs: Vec<_> = targets
+            .iter_mut()
+            .map(|target| pass.raster(&mut **target, AccessType::ColorAttachmentReadWrite))
+            .collect();
+
+        let [width, height, _] = target_refs[0].desc().extent;
+        let meshes: Vec<UploadedTriMesh> = mesh_data.meshes.to_vec();
+        let instances: Vec<MeshInstance> = mesh_data.instances.to_vec();
+        let vertex_buffer = mesh_data.vertex_buffer.clone();
+        let bindless_descriptor_set = mesh_data.bindless_descriptor_set;
+        let oit_weight = self.oit_weight.packed();
+
+        pass.render(move |api| {
+            // Indexed by the position of the instance in `instances`, like in `raster_meshes`
+            let instance_transforms_offset =
+                api.dynamic_constants()
+                    .push_from_iter(instances.iter().map(|inst| InstanceTransform {
+                        current: row_major_3x4(&inst.transformation),
+                        previous: row_major_3x4(&inst.prev_transformation),
+                    }));
+
+            let color_view_desc = ImageViewDesc::default();
+            let color_attachments: Vec<_> = target_refs
+                .into_iter()
+                .map(|target_ref| (target_ref, &color_view_desc))
+                .collect();
+
+            api.begin_render_pass(
+                &*render_pass,
+                [width, height],
+                &color_attachments,
+                Some((
+                    depth_ref,
+                    &ImageViewDesc::builder()
+                        .aspect_mask(vk::ImageAspectFlags::DEPTH)
+                        .build()
+                        .unwrap(),
+                )),
+            );
+
+            api.set_default_view_and_scissor([width, height]);
+
+            let pipeline = match api.bind_raster_pipeline(
+                pipeline
+                    .into_binding()
+                    .storage_buffer(
+                        "instance_transforms_dyn",
+                        RenderPassBinding::DynamicConstantsStorageBuffer(
+                            instance_transforms_offset,
+                        ),
+                    )
+                    .named_binding("scene_color_tex", scene_color_ref.bind())
+                    .named_binding("scene_depth_tex", scene_depth_ref.bind())
+                    .named_binding("reflection_tex", reflection_ref.bind())
+                    .named_binding("sky_cube_tex", sky_cube_ref.bind())
+                    .bindless(bindless_descriptor_set),
+            ) {
+                Ok(pipeline) => pipeline,
+                Err(err) => {
+                    log::error!("{}: {}", name, err);
+                    api.end_render_pass();
+                    return;
+                }
+            };
+
+            unsafe {
+                let raw_device = &api.device().raw;
+                let cb = api.cb;
+
+                for draw_idx in draw_order {
+                    let instance = &instances[draw_idx];
+                    let material = instance.transparency.unwrap();
+                    let mesh = &meshes[instance.mesh.0];
+
+                    raw_device.cmd_bind_index_buffer(
+                        cb.raw,
+                        vertex_buffer.raw,
+                        mesh.index_buffer_offset,
+                        vk::IndexType::UINT32,
+                    );
+
+                    let push_constants = TransparentPushConstants {
+                        tint_opacity: material
+                            .tint
+                            .max(Vec3::ZERO)
+                            .extend(material.opacity.clamp(0.0, 1.0))
+                            .into(),
+                        oit_weight,
+                        ior: material.ior.max(1.0),
+                        refraction_strength: material.refraction_strength,
+                        soft_intersection_distance: material.soft_intersection_distance.max(0.0),
+                        draw_index: draw_idx as u32,
+                        mesh_index: instance.mesh.0 as u32,
+                    };
+
+                    if let Err(err) = pipeline.push_constants(
+                        cb.raw,
+                        vk::ShaderStageFlags::ALL_GRAPHICS,
+                        0,
+                        bytemuck::bytes_of(&push_constants),
+                    ) {
+                        log::error!("{}: {}", name, err);
+                        break;
+                    }
+
+                    raw_device.cmd_draw_indexed(cb.raw, mesh.index_count, 1, 0, 0, 0);
+                }
+            }
+
+            api.end_render_pass();
+        });
+    }
+}
+
+// Accumulation targets of weighted blended OIT, in the layout of `oit_render_pass`
+struct OitTargets {
+    // rgb: sum of weighted premultiplied color; a: sum of weighted alpha
+    accum: rg::Handle<Image>,
+    // sum of -ln(1 - alpha)
+    optical_depth: rg::Handle<Image>,
+}
+
+impl OitTargets {
+    fn new(rg: &mut rg::RenderGraph, extent: [u32; 2]) -> Self {
+        let mut accum = rg.create(ImageDesc::new_2d(vk::Format::R16G16B16A16_SFLOAT, extent));
+        rg::imageops::clear_color(rg, &mut accum, [0.0; 4]);
+
+        let mut optical_depth = rg.create(ImageDesc::new_2d(vk::Format::R16_SFLOAT, extent));
+        rg::imageops::clear_color(rg, &mut optical_depth, [0.0; 4]);
+
+        Self {
+            accum,
+            optical_depth,
+        }
+    }
+
+    /// Composites the accumulated layers over `output`.
+    fn resolve(&self, rg: &mut rg::RenderGraph, output: &mut rg::Handle<Image>) {
+        SimpleRenderPass::new_compute(
+            rg.add_pass("oit resolve"),
+            "/shaders/transparency/oit_resolve.hlsl",
+        )
+        .read(&self.accum)
+        .read(&self.optical_depth)
+        .read_write(output)
+        .dispatch(output.desc().extent);
+    }
+}
+
+/// Indices of the transparent instances, ordered from the farthest to the nearest.
+///
+/// Instances are sorted as a whole by the distance of their origins to the eye;
+/// surfaces which interpenetrate, or large ones close to small ones, can still blend out of order.
+fn sort_back_to_front(instances: &[MeshInstance], eye_position: Vec3) -> Vec<usize> {
+    let mut order: Vec<(usize, f32)> = instances
+        .iter()
+        .enumerate()
+        .filter(|(_, inst)| inst.transparency.is_some())
+        .map(|(idx, inst)| {
+            let origin = Vec3::from(inst.transformation.translation);
+            (idx, (origin - eye_position).length_squared())
+        })
+        .collect();
+
+    order.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    order.into_iter().map(|(idx, _)| idx).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renderers::readback::ReadbackImage;
+    use glam::Affine3A;
+    use kajiya_backend::{file::set_standard_vfs_mount_points, HeadlessRenderBackend};
+    use rg::{
+        imageops::ClearValue,
+        readback::AsyncReadback,
+        renderer::{FrameConstantsLayout, Renderer},
+    };
+
+    fn instance_at(translation: Vec3, transparency: Option<TransparentMaterial>) -> MeshInstance {
+        MeshInstance {
+            transparency,
+            ..MeshInstance::new(
+                crate::world_renderer::MeshHandle(0),
+                Affine3A::from_translation(translation),
+            )
+        }
+    }
+
+    #[test]
+    fn overlapping_quads_draw_back_to_front() {
+        let glass = Some(TransparentMaterial::default());
+
+        // Quads stacked along the view direction, in shuffled order, with an opaque one in the mix.
+        let instances = [
+            instance_at(Vec3::new(0.0, 0.0, -2.0), glass),
+            instance_at(Vec3::new(0.0, 0.0, -5.0), glass),
+            instance_at(Vec3::new(0.0, 0.0, -3.0), None),
+            instance_at(Vec3::new(0.1, 0.0, -1.0), glass),
+            instance_at(Vec3::new(-0.1, 0.0, -4.0), glass),
+        ];
+
+        assert_eq!(sort_back_to_front(&instances, Vec3::ZERO), vec![1, 4, 0, 3]);
+
+        // From the other side, the order flips.
+        assert_eq!(
+            sort_back_to_front(&instances, Vec3::new(0.0, 0.0, -6.0)),
+            vec![3, 0, 4, 1]
+        );
+    }
+
+    // Must match `TranslucentLayer` in `tests/translucent_layer_common.hlsl`
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    struct TranslucentLayer {
+        rect: [f32; 4],
+        color_alpha: [f32; 4],
+        oit_weight: [f32; 4],
+        view_distance: f32,
+    }
+
+    unsafe impl bytemuck::Zeroable for TranslucentLayer {}
+    unsafe impl bytemuck::Pod for TranslucentLayer {}
+
+    fn raster_layers(
+        rg: &mut rg::RenderGraph,
+        render_pass: Arc<RenderPass>,
+        pixel_shader: &'static str,
+        blend_mode: RasterBlendMode,
+        layers: &[TranslucentLayer],
+        depth: &mut rg::Handle<Image>,
+        targets: &mut [&mut rg::Handle<Image>],
+    ) {
+        let mut pass = rg.add_pass("translucent layers");
+
+        let pipeline = pass.register_raster_pipeline(
+            &[
+                PipelineShaderDesc::builder(ShaderPipelineStage::Vertex)
+                    .hlsl_source("/shaders/tests/translucent_layer_vs.hlsl")
+                    .build()
+                    .unwrap(),
+                PipelineShaderDesc::builder(ShaderPipelineStage::Pixel)
+                    .hlsl_source(pixel_shader)
+                    .build()
+                    .unwrap(),
+            ],
+            RasterPipelineDesc::builder()
+                .render_pass(render_pass.clone())
+                .face_cull(false)
+                .depth_write(false)
+                .blend_mode(blend_mode),
+        );
+
+        let depth_ref = pass.raster(depth, AccessType::DepthStencilAttachmentRead);
+        let target_refs: Vec<_> = targets
+            .iter_mut()
+            .map(|target| pass.raster(&mut **target, AccessType::ColorAttachmentReadWrite))
+            .collect();
+
+        let [width, height, _] = target_refs[0].desc().extent;
+        let layers = layers.to_vec();
+
+        pass.render(move |api| {
+            let color_view_desc = ImageViewDesc::default();
+            let color_attachments: Vec<_> = target_refs
+                .into_iter()
+                .map(|target_ref| (target_ref, &color_view_desc))
+                .collect();
+
+            api.begin_render_pass(
+                &*render_pass,
+                [width, height],
+                &color_attachments,
+                Some((
+                    depth_ref,
+                    &ImageViewDesc::builder()
+                        .aspect_mask(vk::ImageAspectFlags::DEPTH)
+                        .build()
+                        .unwrap(),
+                )),
+            );
+
+            api.set_default_view_and_scissor([width, height]);
+
+            let pipeline = api.bind_raster_pipeline(pipeline.into_binding()).unwrap();
+            for layer in &layers {
+                pipeline
+                    .push_constants(
+                        api.cb.raw,
+                        vk::ShaderStageFlags::ALL_GRAPHICS,
+                        0,
+                        bytemuck::bytes_of(layer),
+                    )
+                    .unwrap();
+
+                unsafe {
+                    api.device().raw.cmd_draw(api.cb.raw, 6, 1, 0, 0);
+                }
+            }
+
+            api.end_render_pass();
+        });
+    }
+
+    // Draws `layers`, given back-to-front, over `background` in `mode`.
+    // Returns the RGB of every pixel.
+    fn composite_layers(
+        mode: TransparencyMode,
+        layers: &[TranslucentLayer],
+        background: [f32; 4],
+        extent: [u32; 2],
+    ) -> Vec<Vec3> {
+        set_standard_vfs_mount_points(concat!(env!("CARGO_MANIFEST_DIR"), "/../../.."));
+
+        let backend = HeadlessRenderBackend::new(Default::default()).unwrap();
+        let device = backend.device.clone();
+        let mut renderer = Renderer::new(&device).unwrap();
+        let mut readback = AsyncReadback::default();
+        let transparency = TransparencyRenderer::new(&device);
+
+        readback.begin_frame();
+        let mut token = None;
+        renderer
+            .prepare_frame(|rg| {
+                let mut output =
+                    rg.create(ImageDesc::new_2d(vk::Format::R16G16B16A16_SFLOAT, extent));
+                rg::imageops::clear_color(rg, &mut output, background);
+
+                // The layers are in front of everything
+                let mut depth = rg.create(ImageDesc::new_2d(vk::Format::D32_SFLOAT, extent));
+                rg::imageops::clear_image(
+                    rg,
+                    &mut depth,
+                    ClearValue::DepthStencil {
+                        depth: 0.0,
+                        stencil: 0,
+                    },
+                );
+
+                match mode {
+                    TransparencyMode::Sorted => raster_layers(
+                        rg,
+                        transparency.sorted_render_pass.clone(),
+                        "/shaders/tests/translucent_layer_ps.hlsl",
+                        RasterBlendMode::PremultipliedAlpha,
+                        layers,
+                        &mut depth,
+                        &mut [&mut output],
+                    ),
+                    TransparencyMode::WeightedBlended => {
+                        let mut oit_targets = OitTargets::new(rg, extent);
+                        raster_layers(
+                            rg,
+                            transparency.oit_render_pass.clone(),
+                            "/shaders/tests/translucent_layer_oit_ps.hlsl",
+                            RasterBlendMode::Additive,
+                            layers,
+                            &mut depth,
+                            &mut [&mut oit_targets.accum, &mut oit_targets.optical_depth],
+                        );
+                        oit_targets.resolve(rg, &mut output);
+                    }
+                }
+
+                token = Some(readback.copy_image(rg, &output, 8));
+            })
+            .unwrap();
+        renderer
+            .draw_frame_headless(|_| FrameConstantsLayout {
+                globals_offset: 0,
+                instance_dynamic_parameters_offset: 0,
+                triangle_lights_offset: 0,
+            })
+            .unwrap();
+        readback.retire_frame(device.frame_counter());
+
+        let image = ReadbackImage {
+            format: vk::Format::R16G16B16A16_SFLOAT,
+            extent,
+            bytes: readback.wait(&device, token.unwrap()).unwrap(),
+        };
+        image
+            .to_rgba_f32()
+            .unwrap()
+            .into_iter()
+            .map(|[r, g, b, _]| Vec3::new(r, g, b))
+            .collect()
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn weighted_blended_oit_approximates_sorted_blending() {
+        const LAYER_COUNT: usize = 16;
+        let extent = [64, 16];
+        let background = [0.1, 0.1, 0.1, 1.0];
+
+        // Back-to-front, from 8 to 2 units away, with the color drifting from blue to red.
+        // Nearer layers are narrower, so that columns are covered by different numbers of them.
+        let layers: Vec<TranslucentLayer> = (0..LAYER_COUNT)
+            .map(|i| {
+                let t = i as f32 / (LAYER_COUNT - 1) as f32;
+                TranslucentLayer {
+                    rect: [-1.0, -1.0, 1.0 - i as f32 / 8.0, 1.0],
+                    color_alpha: [t, 0.2 + 0.2 * t, 1.0 - t, 0.25],
+                    oit_weight: OitWeightParams::default().packed(),
+                    view_distance: 8.0 - 0.4 * i as f32,
+                }
+            })
+            .collect();
+
+        let sorted = composite_layers(TransparencyMode::Sorted, &layers, background, extent);
+        let oit = composite_layers(
+            TransparencyMode::WeightedBlended,
+            &layers,
+            background,
+            extent,
+        );
+
+        for (idx, (sorted, oit)) in sorted.iter().zip(oit.iter()).enumerate() {
+            let px = [idx as u32 % extent[0], idx as u32 / extent[0]];
+            assert!(
+                sorted.abs_diff_eq(*oit, 0.05),
+                "{:?}: sorted {}, oit {}",
+                px,
+                sorted,
+                oit
+            );
+        }
+
+        // Under all the layers, the background is nearly hidden, and the nearest ones dominate.
+        let covered_by_all = sorted[0];
+        assert!(covered_by_all.x > 0.6, "{}", covered_by_all);
+        assert!(oit[0].x > 0.6, "{}", oit[0]);
+    }
+}
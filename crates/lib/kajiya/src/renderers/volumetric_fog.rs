@@ -0,0 +1,173 @@
+// Froxel-based volumetric fog.
+//
+// Light scattered by participating media is evaluated in a camera-aligned volume ("froxels"),
+// accumulated over time, integrated along view rays, and finally composited over the lit image.
+
+use glam::Vec3;
+use kajiya_backend::{ash::vk, vulkan::image::*};
+use kajiya_rg::{self as rg, SimpleRenderPass};
+
+use super::{GbufferDepth, PingPongTemporalResource};
+
+// Size of the screen-space tiles covered by each froxel
+const FROXEL_TILE_SIZE: u32 = 8;
+const FROXEL_SLICE_COUNT: u32 = 64;
+const NEAR_DISTANCE: f32 = 0.1;
+
+#[derive(Clone, Copy, Debug)]
+pub struct VolumetricFogParams {
+    /// Density of the media. Scales both the scattering and absorption coefficients.
+    pub density: f32,
+
+    /// Fraction of light scattered per unit of distance at unit density, per color channel.
+    pub scattering: Vec3,
+
+    /// Fraction of light absorbed per unit of distance at unit density.
+    pub absorption: f32,
+
+    /// Henyey-Greenstein anisotropy (g) in (-1, 1). Positive values scatter light forward,
+    /// brightening light shafts when looking towards the sun.
+    pub anisotropy: f32,
+
+    /// Distance from the camera at which the froxel volume ends. Beyond it, there is no more fog.
+    pub max_distance: f32,
+}
+
+impl Default for VolumetricFogParams {
+    fn default() -> Self {
+        Self {
+            density: 0.02,
+            scattering: Vec3::ONE,
+            absorption: 0.1,
+            anisotropy: 0.6,
+            max_distance: 64.0,
+        }
+    }
+}
+
+// Must match `VolumetricFogConstants` in `volumetric_fog/common.hlsl`
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct VolumetricFogConstants {
+    froxel_dims: [u32; 4],
+    scattering_extinction: [f32; 4],
+    near_distance: f32,
+    max_distance: f32,
+    anisotropy: f32,
+    reset_history: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct CompositeConstants {
+    output_tex_size: [f32; 4],
+    fog: VolumetricFogConstants,
+}
+
+pub struct VolumetricFogRenderer {
+    pub params: VolumetricFogParams,
+    scattering_tex: PingPongTemporalResource,
+    prev_froxel_dims: Option<[u32; 3]>,
+}
+
+impl Default for VolumetricFogRenderer {
+    fn default() -> Self {
+        Self {
+            params: Default::default(),
+            scattering_tex: PingPongTemporalResource::new("volumetric_fog.scattering"),
+            prev_froxel_dims: None,
+        }
+    }
+}
+
+impl VolumetricFogRenderer {
+    fn constants(&self, froxel_dims: [u32; 3], reset_history: bool) -> VolumetricFogConstants {
+        let params = &self.params;
+        let density = params.density.max(0.0);
+        let scattering = params.scattering.max(Vec3::ZERO) * density;
+
+        // Extinction is kept monochromatic, so that a single transmittance value is enough.
+        let extinction = (scattering.x + scattering.y + scattering.z) / 3.0
+            + params.absorption.max(0.0) * density;
+
+        VolumetricFogConstants {
+            froxel_dims: [froxel_dims[0], froxel_dims[1], froxel_dims[2], 0],
+            scattering_extinction: scattering.extend(extinction).into(),
+            near_distance: NEAR_DISTANCE,
+            max_distance: params.max_distance.max(NEAR_DISTANCE * 2.0),
+            anisotropy: params.anisotropy.clamp(-0.99, 0.99),
+            reset_history: reset_history as u32,
+        }
+    }
+
+    /// Applies fog to `output`, which should contain the lit scene, before any temporal anti-aliasing.
+    ///
+    /// The sun is shadowed via `sun_shadow_mask`, following sunlight past each froxel to the
+    /// surface it lights on screen. Occluders between the two, or off screen, are missed.
+    pub fn render(
+        &mut self,
+        rg: &mut rg::TemporalRenderGraph,
+        gbuffer_depth: &GbufferDepth,
+        sun_shadow_mask: &rg::Handle<Image>,
+        sky_cube: &rg::Handle<Image>,
+        output: &mut rg::Handle<Image>,
+    ) {
+        let [width, height] = output.desc().extent_2d();
+        let froxel_dims = [
+            (width + FROXEL_TILE_SIZE - 1) / FROXEL_TILE_SIZE,
+            (height + FROXEL_TILE_SIZE - 1) / FROXEL_TILE_SIZE,
+            FROXEL_SLICE_COUNT,
+        ];
+
+        let reset_history = self.prev_froxel_dims != Some(froxel_dims);
+        self.prev_froxel_dims = Some(froxel_dims);
+
+        let constants = self.constants(froxel_dims, reset_history);
+
+        let (mut scattering_tex, scattering_history_tex) =
+            self.scattering_tex.get_output_and_history(
+                rg,
+                ImageDesc::new_3d(vk::Format::R16G16B16A16_SFLOAT, froxel_dims)
+                    .usage(vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::STORAGE),
+            );
+
+        SimpleRenderPass::new_compute(
+            rg.add_pass("fog inject"),
+            "/shaders/volumetric_fog/inject.hlsl",
+        )
+        .read(&scattering_history_tex)
+        .read(sky_cube)
+        .read_aspect(&gbuffer_depth.depth, vk::ImageAspectFlags::DEPTH)
+        .read(sun_shadow_mask)
+        .write(&mut scattering_tex)
+        .constants(constants)
+        .dispatch(froxel_dims);
+
+        let mut integrated_tex = rg.create(ImageDesc::new_3d(
+            vk::Format::R16G16B16A16_SFLOAT,
+            froxel_dims,
+        ));
+
+        SimpleRenderPass::new_compute(
+            rg.add_pass("fog integrate"),
+            "/shaders/volumetric_fog/integrate.hlsl",
+        )
+        .read(&scattering_tex)
+        .write(&mut integrated_tex)
+        .constants(constants)
+        .dispatch([froxel_dims[0], froxel_dims[1], 1]);
+
+        SimpleRenderPass::new_compute(
+            rg.add_pass("fog composite"),
+            "/shaders/volumetric_fog/composite.hlsl",
+        )
+        .read_aspect(&gbuffer_depth.depth, vk::ImageAspectFlags::DEPTH)
+        .read(&integrated_tex)
+        .read_write(output)
+        .constants(CompositeConstants {
+            output_tex_size: output.desc().extent_inv_extent_2d(),
+            fog: constants,
+        })
+        .dispatch(output.desc().extent);
+    }
+}
@@ -0,0 +1,442 @@
+// Wireframe overlay, for inspecting geometry.
+//
+// The edges of instances' triangles are rasterized into an overlay at the internal resolution,
+// depth-tested against the gbuffer, and blended over the post-processed image. Lines are drawn with
+// `VK_POLYGON_MODE_LINE` where `fillModeNonSolid` is supported; elsewhere, filled triangles only keep
+// the pixels near their edges, found via barycentric coordinates.
+
+use std::sync::Arc;
+
+use glam::Vec3;
+use kajiya_backend::{
+    ash::vk,
+    vk_sync::AccessType,
+    vulkan::{image::*, shader::*},
+    Device,
+};
+use kajiya_rg::{self as rg};
+use rg::{IntoRenderPassPipelineBinding, RenderPassBinding};
+
+use crate::world_renderer::MeshInstance;
+
+use super::{
+    composite_overlay,
+    raster_meshes::{row_major_3x4, InstanceTransform, RasterMeshesData},
+    GbufferDepth,
+};
+
+// Must match the push constants in `wireframe_vs.hlsl` and `wireframe_ps.hlsl`
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct WireframePushConstants {
+    color_opacity: [f32; 4],
+    draw_index: u32,
+    mesh_index: u32,
+}
+
+unsafe impl bytemuck::Zeroable for WireframePushConstants {}
+unsafe impl bytemuck::Pod for WireframePushConstants {}
+
+pub struct WireframeRenderer {
+    /// Draws the edges of all instances. When disabled, only instances with
+    /// `MeshInstance::wireframe` set are drawn.
+    pub enabled: bool,
+
+    pub color: Vec3,
+    pub opacity: f32,
+
+    /// Constant and slope-scaled depth bias, pulling the lines towards the camera,
+    /// so that they don't z-fight with the surfaces they lie on.
+    pub depth_bias: [f32; 2],
+
+    render_pass: Arc<RenderPass>,
+    use_line_mode: bool,
+}
+
+impl WireframeRenderer {
+    pub fn new(device: &Device) -> Self {
+        Self {
+            enabled: false,
+            color: Vec3::new(0.1, 1.0, 0.3),
+            opacity: 0.8,
+            depth_bias: [16.0, 2.0],
+            render_pass: create_render_pass(
+                device,
+                RenderPassDesc {
+                    // Premultiplied color of the lines
+                    color_attachments: &[RenderPassAttachmentDesc::new(vk::Format::R8G8B8A8_UNORM)],
+                    depth_attachment: Some(RenderPassAttachmentDesc::new(vk::Format::D32_SFLOAT)),
+                },
+            ),
+            use_line_mode: device.fill_mode_non_solid_supported(),
+        }
+    }
+
+    /// Draws the edges of the selected instances over the post-processed `output`. Lines are hidden
+    /// by opaque geometry in the gbuffer, but not by transparent surfaces.
+    pub fn render(
+        &self,
+        rg: &mut rg::RenderGraph,
+        gbuffer_depth: &mut GbufferDepth,
+        mesh_data: RasterMeshesData<'_>,
+        output: &mut rg::Handle<Image>,
+    ) {
+        let draw_order = instances_to_draw(self.enabled, mesh_data.instances);
+        if draw_order.is_empty() || self.opacity <= 0.0 {
+            return;
+        }
+
+        let mut overlay_tex = rg.create(ImageDesc::new_2d(
+            vk::Format::R8G8B8A8_UNORM,
+            gbuffer_depth.depth.desc().extent_2d(),
+        ));
+        rg::imageops::clear_color(rg, &mut overlay_tex, [0.0; 4]);
+
+        {
+            let mut pass = rg.add_pass("wireframe");
+
+            let pipeline = pass.register_raster_pipeline(
+                &[
+                    PipelineShaderDesc::builder(ShaderPipelineStage::Vertex)
+                        .hlsl_source("/shaders/wireframe/wireframe_vs.hlsl")
+                        .build()
+                        .unwrap(),
+                    PipelineShaderDesc::builder(ShaderPipelineStage::Pixel)
+                        .hlsl_source(self.pixel_shader())
+                        .build()
+                        .unwrap(),
+                ],
+                self.pipeline_desc(),
+            );
+
+            // Depth testing only; the pipeline doesn't write it.
+            let depth_ref = pass.raster(
+                &mut gbuffer_depth.depth,
+                AccessType::DepthAttachmentWriteStencilReadOnly,
+            );
+            let overlay_ref = pass.raster(&mut overlay_tex, AccessType::ColorAttachmentReadWrite);
+
+            let [width, height, _] = overlay_ref.desc().extent;
+            let meshes = mesh_data.meshes.to_vec();
+            let instances = mesh_data.instances.to_vec();
+            let bindless_descriptor_set = mesh_data.bindless_descriptor_set;
+            let render_pass = self.render_pass.clone();
+            let color_opacity: [f32; 4] = self
+                .color
+                .max(Vec3::ZERO)
+                .extend(self.opacity.clamp(0.0, 1.0))
+                .into();
+
+            pass.render(move |api| {
+                // Indexed by the position of the instance in `instances`, like in `raster_meshes`
+                let instance_transforms_offset =
+                    api.dynamic_constants()
+                        .push_from_iter(instances.iter().map(|inst| InstanceTransform {
+                            current: row_major_3x4(&inst.transformation),
+                            previous: row_major_3x4(&inst.prev_transformation),
+                        }));
+
+                api.begin_render_pass(
+                    &*render_pass,
+                    [width, height],
+                    &[(overlay_ref, &ImageViewDesc::default())],
+                    Some((
+                        depth_ref,
+                        &ImageViewDesc::builder()
+                            .aspect_mask(vk::ImageAspectFlags::DEPTH)
+                            .build()
+                            .unwrap(),
+                    )),
+                );
+
+                api.set_default_view_and_scissor([width, height]);
+
+                let pipeline = match api.bind_raster_pipeline(
+                    pipeline
+                        .into_binding()
+                        .storage_buffer(
+                            "instance_transforms_dyn",
+                            RenderPassBinding::DynamicConstantsStorageBuffer(
+                                instance_transforms_offset,
+                            ),
+                        )
+                        .bindless(bindless_descriptor_set),
+                ) {
+                    Ok(pipeline) => pipeline,
+                    Err(err) => {
+                        log::error!("wireframe: {}", err);
+                        api.end_render_pass();
+                        return;
+                    }
+                };
+
+                unsafe {
+                    let raw_device = &api.device().raw;
+                    let cb = api.cb;
+
+                    for draw_idx in draw_order {
+                        let instance = &instances[draw_idx];
+                        let mesh = &meshes[instance.mesh.0];
+
+                        let push_constants = WireframePushConstants {
+                            color_opacity,
+                            draw_index: draw_idx as u32,
+                            mesh_index: instance.mesh.0 as u32,
+                        };
+
+                        if let Err(err) = pipeline.push_constants(
+                            cb.raw,
+                            vk::ShaderStageFlags::ALL_GRAPHICS,
+                            0,
+                            bytemuck::bytes_of(&push_constants),
+                        ) {
+                            log::error!("wireframe: {}", err);
+                            break;
+                        }
+
+                        // Indices are fetched in the vertex shader, giving each triangle its own corners.
+                        raw_device.cmd_draw(cb.raw, mesh.index_count, 1, 0, 0);
+                    }
+                }
+
+                api.end_render_pass();
+            });
+        }
+
+        composite_overlay(rg, "wireframe composite", &overlay_tex, output);
+    }
+
+    fn pixel_shader(&self) -> &'static str {
+        if self.use_line_mode {
+            "/shaders/wireframe/wireframe_ps.hlsl"
+        } else {
+            "/shaders/wireframe/wireframe_barycentric_ps.hlsl"
+        }
+    }
+
+    fn pipeline_desc(&self) -> RasterPipelineDescBuilder {
+        RasterPipelineDesc::builder()
+            .render_pass(self.render_pass.clone())
+            .face_cull(false)
+            .depth_write(false)
+            .wireframe(self.use_line_mode)
+            .depth_bias(self.depth_bias)
+            .blend_mode(RasterBlendMode::PremultipliedAlpha)
+            .push_constants_bytes(std::mem::size_of::<WireframePushConstants>())
+    }
+}
+
+/// Indices of the instances whose edges are drawn: all of them if `enabled`,
+/// and otherwise those with `MeshInstance::wireframe` set.
+fn instances_to_draw(enabled: bool, instances: &[MeshInstance]) -> Vec<usize> {
+    instances
+        .iter()
+        .enumerate()
+        .filter(|(_, inst)| enabled || inst.wireframe)
+        .map(|(idx, _)| idx)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world_renderer::MeshHandle;
+    use glam::{Affine3A, Vec2};
+    use kajiya_backend::{file::set_standard_vfs_mount_points, HeadlessRenderBackend};
+    use rg::{
+        imageops::ClearValue,
+        readback::AsyncReadback,
+        renderer::{FrameConstantsLayout, Renderer},
+    };
+
+    fn instance(wireframe: bool) -> MeshInstance {
+        MeshInstance {
+            wireframe,
+            ..MeshInstance::new(MeshHandle(0), Affine3A::IDENTITY)
+        }
+    }
+
+    #[test]
+    fn draws_edges_of_selected_or_all_instances() {
+        let instances = [instance(false), instance(true), instance(false)];
+        assert_eq!(instances_to_draw(false, &instances), vec![1]);
+        assert_eq!(instances_to_draw(true, &instances), vec![0, 1, 2]);
+        assert!(instances_to_draw(false, &[instance(false)]).is_empty());
+    }
+
+    const EXTENT: [u32; 2] = [64, 64];
+
+    // Must match the triangle in `tests/wireframe_triangle_vs.hlsl`, in pixels.
+    // The viewport is flipped, so clip-space Y points up.
+    const TRIANGLE: [[f32; 2]; 3] = [[6.4, 6.4], [57.6, 6.4], [32.0, 57.6]];
+
+    // Rasterizes `TRIANGLE` with the pixel shader and pipeline state of `renderer`.
+    // Returns the opacity of the overlay at every pixel.
+    fn render_triangle_overlay(device: &Arc<Device>, renderer: &WireframeRenderer) -> Vec<f32> {
+        let mut frame_renderer = Renderer::new(device).unwrap();
+        let mut readback = AsyncReadback::default();
+
+        readback.begin_frame();
+        let mut token = None;
+        frame_renderer
+            .prepare_frame(|rg| {
+                let mut overlay_tex =
+                    rg.create(ImageDesc::new_2d(vk::Format::R8G8B8A8_UNORM, EXTENT));
+                rg::imageops::clear_color(rg, &mut overlay_tex, [0.0; 4]);
+
+                // Nothing occludes the triangle
+                let mut depth = rg.create(ImageDesc::new_2d(vk::Format::D32_SFLOAT, EXTENT));
+                rg::imageops::clear_image(
+                    rg,
+                    &mut depth,
+                    ClearValue::DepthStencil {
+                        depth: 0.0,
+                        stencil: 0,
+                    },
+                );
+
+                let mut pass = rg.add_pass("wireframe triangle");
+                let pipeline = pass.register_raster_pipeline(
+                    &[
+                        PipelineShaderDesc::builder(ShaderPipelineStage::Vertex)
+                            .hlsl_source("/shaders/tests/wireframe_triangle_vs.hlsl")
+                            .build()
+                            .unwrap(),
+                        PipelineShaderDesc::builder(ShaderPipelineStage::Pixel)
+                            .hlsl_source(renderer.pixel_shader())
+                            .build()
+                            .unwrap(),
+                    ],
+                    renderer.pipeline_desc(),
+                );
+
+                let depth_ref =
+                    pass.raster(&mut depth, AccessType::DepthAttachmentWriteStencilReadOnly);
+                let overlay_ref =
+                    pass.raster(&mut overlay_tex, AccessType::ColorAttachmentReadWrite);
+                let render_pass = renderer.render_pass.clone();
+                let push_constants = WireframePushConstants {
+                    color_opacity: [1.0; 4],
+                    draw_index: 0,
+                    mesh_index: 0,
+                };
+
+                pass.render(move |api| {
+                    api.begin_render_pass(
+                        &*render_pass,
+                        EXTENT,
+                        &[(overlay_ref, &ImageViewDesc::default())],
+                        Some((
+                            depth_ref,
+                            &ImageViewDesc::builder()
+                                .aspect_mask(vk::ImageAspectFlags::DEPTH)
+                                .build()
+                                .unwrap(),
+                        )),
+                    );
+                    api.set_default_view_and_scissor(EXTENT);
+
+                    let pipeline = api.bind_raster_pipeline(pipeline.into_binding()).unwrap();
+                    pipeline
+                        .push_constants(
+                            api.cb.raw,
+                            vk::ShaderStageFlags::ALL_GRAPHICS,
+                            0,
+                            bytemuck::bytes_of(&push_constants),
+                        )
+                        .unwrap();
+
+                    unsafe {
+                        api.device().raw.cmd_draw(api.cb.raw, 3, 1, 0, 0);
+                    }
+
+                    api.end_render_pass();
+                });
+
+                token = Some(readback.copy_image(rg, &overlay_tex, 4));
+            })
+            .unwrap();
+        frame_renderer
+            .draw_frame_headless(|_| FrameConstantsLayout {
+                globals_offset: 0,
+                instance_dynamic_parameters_offset: 0,
+                triangle_lights_offset: 0,
+            })
+            .unwrap();
+        readback.retire_frame(device.frame_counter());
+
+        let bytes = readback.wait(device, token.unwrap()).unwrap();
+        bytes
+            .chunks_exact(4)
+            .map(|rgba| rgba[3] as f32 / 255.0)
+            .collect()
+    }
+
+    // Signed distance in pixels from `p` to the nearest edge of `TRIANGLE`; positive inside.
+    fn edge_distance(p: Vec2) -> f32 {
+        (0..3)
+            .map(|i| {
+                let [a, b, c] = [i, i + 1, i + 2].map(|j| Vec2::from(TRIANGLE[j % 3]));
+                let edge = (b - a).normalize();
+                let inward = c - a;
+                let normal = Vec2::new(-edge.y, edge.x);
+                let normal = if normal.dot(inward) < 0.0 {
+                    -normal
+                } else {
+                    normal
+                };
+                normal.dot(p - a)
+            })
+            .fold(f32::MAX, f32::min)
+    }
+
+    fn assert_covers_only_edges(opacity: &[f32]) {
+        let mut edge_pixels = 0;
+        let mut drawn_edge_pixels = 0;
+
+        for (idx, &alpha) in opacity.iter().enumerate() {
+            let px = [idx as u32 % EXTENT[0], idx as u32 / EXTENT[0]];
+            let distance = edge_distance(Vec2::new(px[0] as f32 + 0.5, px[1] as f32 + 0.5));
+
+            // Deep inside, or well outside of the triangle
+            if distance > 3.0 || distance < -2.0 {
+                assert_eq!(alpha, 0.0, "{:?} is {} px from an edge", px, distance);
+            }
+
+            if (0.0..0.5).contains(&distance) {
+                edge_pixels += 1;
+                if alpha > 0.5 {
+                    drawn_edge_pixels += 1;
+                }
+            }
+        }
+
+        assert!(
+            drawn_edge_pixels * 10 >= edge_pixels * 9,
+            "only {} of {} edge pixels drawn",
+            drawn_edge_pixels,
+            edge_pixels
+        );
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn overlay_covers_only_triangle_edges() {
+        set_standard_vfs_mount_points(concat!(env!("CARGO_MANIFEST_DIR"), "/../../.."));
+
+        let backend = HeadlessRenderBackend::new(Default::default()).unwrap();
+        let device = backend.device.clone();
+
+        // The barycentric fallback works everywhere; line mode only where it's supported.
+        let mut renderer = WireframeRenderer::new(&device);
+        let line_mode_supported = renderer.use_line_mode;
+
+        renderer.use_line_mode = false;
+        assert_covers_only_edges(&render_triangle_overlay(&device, &renderer));
+
+        if line_mode_supported {
+            renderer.use_line_mode = true;
+            assert_covers_only_edges(&render_triangle_overlay(&device, &renderer));
+        }
+    }
+}
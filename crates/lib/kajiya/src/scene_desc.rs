@@ -0,0 +1,632 @@
+// Hand-authored scene descriptions in RON, such as `assets/scenes/*.ron`.
+//
+// A scene lists baked meshes with per-instance transforms and material overrides, particle emitters,
+// and optionally the sun and the camera. Unknown fields are rejected; parse errors report the line
+// and column of the offending field, and load errors the index of the instance.
+// See `assets/scenes/sample.ron` for all the fields.
+//
+// `LiveSceneDesc` watches the file, and applies edits to the running world.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::Context as _;
+use glam::{Affine3A, Quat, Vec3};
+use kajiya_backend::asset_watcher::AssetWatcher;
+use serde::Deserialize;
+
+use crate::{
+    renderers::{
+        particles::{ParticleEmitterDesc, ParticleEmitterHandle},
+        transparency::TransparentMaterial,
+    },
+    world_renderer::{AddMeshOptions, InstanceHandle, MeshHandle, WorldRenderer},
+};
+
+/// Files with a newer `version` are rejected.
+pub const SCENE_DESC_VERSION: u32 = 1;
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SceneDesc {
+    #[serde(default = "default_version")]
+    pub version: u32,
+    #[serde(default)]
+    pub instances: Vec<SceneInstanceDesc>,
+    #[serde(default)]
+    pub emitters: Vec<SceneEmitterDesc>,
+    #[serde(default)]
+    pub sun: Option<SceneSunDesc>,
+    #[serde(default)]
+    pub camera: Option<SceneCameraDesc>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SceneInstanceDesc {
+    /// Name of a mesh baked to `/baked/{mesh}.mesh`, or the VFS path of a baked mesh.
+    pub mesh: String,
+    pub position: [f32; 3],
+
+    /// Degrees about the X, Y, and Z axes, applied in Z, X, Y order.
+    #[serde(default)]
+    pub rotation: [f32; 3],
+
+    #[serde(default = "default_scale")]
+    pub scale: f32,
+
+    #[serde(default)]
+    pub transparency: Option<SceneTransparency>,
+    #[serde(default)]
+    pub highlight: Option<usize>,
+    #[serde(default)]
+    pub wireframe: bool,
+
+    #[serde(default = "default_emissive_multiplier")]
+    pub emissive_multiplier: f32,
+
+    /// Samples the emissive triangles of the mesh as lights; see `AddMeshOptions::use_lights`.
+    #[serde(default)]
+    pub lights: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub enum SceneTransparency {
+    Glass,
+    Water,
+    Custom {
+        tint: [f32; 3],
+        opacity: f32,
+        ior: f32,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SceneEmitterDesc {
+    pub position: [f32; 3],
+    pub kind: SceneEmitterKind,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub enum SceneEmitterKind {
+    Smoke,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SceneSunDesc {
+    /// Towards the sun; doesn't need to be normalized.
+    pub direction: [f32; 3],
+    #[serde(default)]
+    pub size_multiplier: Option<f32>,
+    #[serde(default)]
+    pub color_multiplier: Option<[f32; 3]>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SceneCameraDesc {
+    pub position: [f32; 3],
+
+    /// Degrees about the Y axis
+    #[serde(default)]
+    pub yaw: f32,
+
+    /// Degrees about the X axis; positive looks up.
+    #[serde(default)]
+    pub pitch: f32,
+
+    /// Degrees
+    #[serde(default)]
+    pub vertical_fov: Option<f32>,
+}
+
+fn default_version() -> u32 {
+    SCENE_DESC_VERSION
+}
+
+fn default_scale() -> f32 {
+    1.0
+}
+
+fn default_emissive_multiplier() -> f32 {
+    1.0
+}
+
+impl SceneDesc {
+    pub fn from_ron_str(text: &str) -> anyhow::Result<Self> {
+        let scene: Self = ron::de::from_str(text)?;
+
+        if scene.version > SCENE_DESC_VERSION {
+            anyhow::bail!(
+                "version: {} is newer than the supported {}",
+                scene.version,
+                SCENE_DESC_VERSION
+            );
+        }
+
+        Ok(scene)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Reading scene file {:?}", path))?;
+        Self::from_ron_str(&text).with_context(|| format!("Parsing scene file {:?}", path))
+    }
+}
+
+impl SceneInstanceDesc {
+    pub fn mesh_path(&self) -> PathBuf {
+        if self.mesh.starts_with('/') {
+            PathBuf::from(&self.mesh)
+        } else {
+            PathBuf::from(format!("/baked/{}.mesh", self.mesh))
+        }
+    }
+
+    pub fn transformation(&self) -> Affine3A {
+        let [x, y, z] = self.rotation;
+        let rotation = Quat::from_rotation_y(y.to_radians())
+            * Quat::from_rotation_x(x.to_radians())
+            * Quat::from_rotation_z(z.to_radians());
+
+        Affine3A::from_scale_rotation_translation(
+            Vec3::splat(self.scale),
+            rotation,
+            self.position.into(),
+        )
+    }
+
+    pub fn transparent_material(&self) -> Option<TransparentMaterial> {
+        self.transparency
+            .as_ref()
+            .map(|transparency| match transparency {
+                SceneTransparency::Glass => TransparentMaterial::default(),
+                SceneTransparency::Water => TransparentMaterial::water(),
+                SceneTransparency::Custom { tint, opacity, ior } => TransparentMaterial {
+                    tint: (*tint).into(),
+                    opacity: *opacity,
+                    ior: *ior,
+                    ..Default::default()
+                },
+            })
+    }
+}
+
+impl SceneCameraDesc {
+    pub fn rotation(&self) -> Quat {
+        Quat::from_rotation_y(self.yaw.to_radians())
+            * Quat::from_rotation_x(self.pitch.to_radians())
+    }
+}
+
+/// The result of `WorldRenderer::load_scene_desc`.
+pub struct LoadedSceneDesc {
+    /// In the order of `SceneDesc::instances`
+    pub instances: Vec<InstanceHandle>,
+
+    /// In the order of `SceneDesc::emitters`
+    pub emitters: Vec<ParticleEmitterHandle>,
+
+    /// Normalized; pass it in `WorldFrameDesc`.
+    pub sun_direction: Option<Vec3>,
+
+    pub camera: Option<SceneCameraDesc>,
+}
+
+impl WorldRenderer {
+    /// Loads a RON scene description, and adds its meshes, instances, and emitters.
+    pub fn load_scene_desc(&mut self, path: impl AsRef<Path>) -> anyhow::Result<LoadedSceneDesc> {
+        let path = path.as_ref();
+        let scene = SceneDesc::load(path)?;
+        self.add_scene_desc(&scene)
+            .with_context(|| format!("Loading scene file {:?}", path))
+    }
+
+    pub fn add_scene_desc(&mut self, scene: &SceneDesc) -> anyhow::Result<LoadedSceneDesc> {
+        let meshes = self.load_scene_desc_meshes(scene, &mut Default::default())?;
+        Ok(self.add_scene_desc_with_meshes(scene, &meshes))
+    }
+
+    // Loads the mesh of every instance, reusing the ones in `cache`.
+    fn load_scene_desc_meshes(
+        &mut self,
+        scene: &SceneDesc,
+        cache: &mut SceneMeshCache,
+    ) -> anyhow::Result<Vec<MeshHandle>> {
+        scene
+            .instances
+            .iter()
+            .enumerate()
+            .map(|(idx, instance)| {
+                let key = (instance.mesh_path(), instance.lights);
+                if let Some(&mesh) = cache.get(&key) {
+                    return Ok(mesh);
+                }
+
+                let mesh = self
+                    .add_baked_mesh(&key.0, AddMeshOptions::new().use_lights(instance.lights))
+                    .with_context(|| format!("instances[{}].mesh: {:?}", idx, instance.mesh))?;
+                cache.insert(key, mesh);
+                Ok(mesh)
+            })
+            .collect()
+    }
+
+    // `meshes` are from `load_scene_desc_meshes`
+    fn add_scene_desc_with_meshes(
+        &mut self,
+        scene: &SceneDesc,
+        meshes: &[MeshHandle],
+    ) -> LoadedSceneDesc {
+        let instances = scene
+            .instances
+            .iter()
+            .zip(meshes)
+            .map(|(instance, &mesh)| self.add_scene_desc_instance(mesh, instance))
+            .collect();
+
+        let emitters = self.add_scene_desc_emitters(&scene.emitters);
+        self.apply_scene_desc_sun(scene.sun.as_ref());
+
+        LoadedSceneDesc {
+            instances,
+            emitters,
+            sun_direction: scene_desc_sun_direction(scene),
+            camera: scene.camera.clone(),
+        }
+    }
+
+    fn add_scene_desc_instance(
+        &mut self,
+        mesh: MeshHandle,
+        instance: &SceneInstanceDesc,
+    ) -> InstanceHandle {
+        let handle = self.add_instance(mesh, instance.transformation());
+        self.apply_scene_desc_instance_params(handle, instance);
+        handle
+    }
+
+    // Everything but the mesh and the transform
+    fn apply_scene_desc_instance_params(
+        &mut self,
+        handle: InstanceHandle,
+        instance: &SceneInstanceDesc,
+    ) {
+        self.get_instance_dynamic_parameters_mut(handle)
+            .emissive_multiplier = instance.emissive_multiplier;
+        self.set_instance_transparency(handle, instance.transparent_material());
+        self.set_instance_highlight(handle, instance.highlight);
+        self.set_instance_wireframe(handle, instance.wireframe);
+    }
+
+    fn add_scene_desc_emitters(
+        &mut self,
+        emitters: &[SceneEmitterDesc],
+    ) -> Vec<ParticleEmitterHandle> {
+        emitters
+            .iter()
+            .map(|emitter| {
+                let position = Vec3::from(emitter.position);
+                self.particles.add_emitter(match emitter.kind {
+                    SceneEmitterKind::Smoke => ParticleEmitterDesc::smoke(position),
+                })
+            })
+            .collect()
+    }
+
+    fn apply_scene_desc_sun(&mut self, sun: Option<&SceneSunDesc>) {
+        if let Some(sun) = sun {
+            if let Some(size_multiplier) = sun.size_multiplier {
+                self.sun_size_multiplier = size_multiplier;
+            }
+            if let Some(color_multiplier) = sun.color_multiplier {
+                self.sun_color_multiplier = color_multiplier.into();
+            }
+        }
+    }
+}
+
+// Baked meshes by path and `use_lights`
+type SceneMeshCache = HashMap<(PathBuf, bool), MeshHandle>;
+
+fn scene_desc_sun_direction(scene: &SceneDesc) -> Option<Vec3> {
+    scene
+        .sun
+        .as_ref()
+        .map(|sun| Vec3::from(sun.direction).normalize())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum InstanceUpdate {
+    Keep,
+    // Same mesh; update the transform and parameters in place
+    Update,
+    // Different mesh; remove and add again
+    Replace,
+    Add,
+    Remove,
+}
+
+// Matches instances by their index in the file.
+fn plan_instance_updates(
+    old: &[SceneInstanceDesc],
+    new: &[SceneInstanceDesc],
+) -> Vec<InstanceUpdate> {
+    (0..old.len().max(new.len()))
+        .map(|idx| match (old.get(idx), new.get(idx)) {
+            (Some(old), Some(new)) if old == new => InstanceUpdate::Keep,
+            (Some(old), Some(new))
+                if old.mesh_path() == new.mesh_path() && old.lights == new.lights =>
+            {
+                InstanceUpdate::Update
+            }
+            (Some(_), Some(_)) => InstanceUpdate::Replace,
+            (None, Some(_)) => InstanceUpdate::Add,
+            (Some(_), None) => InstanceUpdate::Remove,
+            (None, None) => unreachable!(),
+        })
+        .collect()
+}
+
+/// A scene description which follows edits to its file, like shaders do.
+///
+/// Edited instances, emitters, and sun parameters are updated in place; added and removed instances
+/// are added to and removed from the world, and with it the TLAS. Instances are matched by their
+/// index in the file. If the file's `version` changes, the whole scene is reloaded instead.
+/// The camera is only used at load time.
+pub struct LiveSceneDesc {
+    path: PathBuf,
+    desc: SceneDesc,
+    loaded: LoadedSceneDesc,
+    meshes: SceneMeshCache,
+    changed: Arc<AtomicBool>,
+}
+
+impl LiveSceneDesc {
+    pub fn load(
+        world_renderer: &mut WorldRenderer,
+        path: impl Into<PathBuf>,
+    ) -> anyhow::Result<Self> {
+        let path = path.into();
+        let desc = SceneDesc::load(&path)?;
+
+        let mut meshes = SceneMeshCache::default();
+        let instance_meshes = world_renderer
+            .load_scene_desc_meshes(&desc, &mut meshes)
+            .with_context(|| format!("Loading scene file {:?}", path))?;
+        let loaded = world_renderer.add_scene_desc_with_meshes(&desc, &instance_meshes);
+
+        let changed = Arc::new(AtomicBool::new(false));
+        AssetWatcher::global().watch(&path, {
+            let changed = changed.clone();
+            move || changed.store(true, Ordering::Release)
+        })?;
+
+        Ok(Self {
+            path,
+            desc,
+            loaded,
+            meshes,
+            changed,
+        })
+    }
+
+    pub fn desc(&self) -> &SceneDesc {
+        &self.desc
+    }
+
+    pub fn loaded(&self) -> &LoadedSceneDesc {
+        &self.loaded
+    }
+
+    /// Applies edits made to the file since the last call, and returns true if anything changed.
+    /// If the new file can't be loaded, the error is returned, and the scene is left as it was.
+    pub fn reload_if_changed(
+        &mut self,
+        world_renderer: &mut WorldRenderer,
+    ) -> anyhow::Result<bool> {
+        if !self.changed.swap(false, Ordering::AcqRel) {
+            return Ok(false);
+        }
+
+        let desc = SceneDesc::load(&self.path)?;
+
+        // Editors can write the file several times per save.
+        if desc == self.desc {
+            return Ok(false);
+        }
+
+        // All the meshes are loaded before the world is touched, so that errors leave it as it was.
+        let meshes = world_renderer
+            .load_scene_desc_meshes(&desc, &mut self.meshes)
+            .with_context(|| format!("Reloading scene file {:?}", self.path))?;
+
+        if desc.version != self.desc.version {
+            log::warn!(
+                "Scene file {:?} changed its version from {} to {}; reloading the whole scene",
+                self.path,
+                self.desc.version,
+                desc.version
+            );
+
+            for &inst in &self.loaded.instances {
+                world_renderer.remove_instance(inst);
+            }
+            for &emitter in &self.loaded.emitters {
+                world_renderer.particles.remove_emitter(emitter);
+            }
+
+            self.loaded = world_renderer.add_scene_desc_with_meshes(&desc, &meshes);
+            self.desc = desc;
+            return Ok(true);
+        }
+
+        let mut instances = Vec::with_capacity(desc.instances.len());
+        for (idx, update) in plan_instance_updates(&self.desc.instances, &desc.instances)
+            .into_iter()
+            .enumerate()
+        {
+            match update {
+                InstanceUpdate::Keep => instances.push(self.loaded.instances[idx]),
+                InstanceUpdate::Update => {
+                    let inst = self.loaded.instances[idx];
+                    let instance = &desc.instances[idx];
+                    world_renderer.set_instance_transform(inst, instance.transformation());
+                    world_renderer.apply_scene_desc_instance_params(inst, instance);
+                    instances.push(inst);
+                }
+                InstanceUpdate::Replace => {
+                    world_renderer.remove_instance(self.loaded.instances[idx]);
+                    instances.push(
+                        world_renderer.add_scene_desc_instance(meshes[idx], &desc.instances[idx]),
+                    );
+                }
+                InstanceUpdate::Add => instances.push(
+                    world_renderer.add_scene_desc_instance(meshes[idx], &desc.instances[idx]),
+                ),
+                InstanceUpdate::Remove => {
+                    world_renderer.remove_instance(self.loaded.instances[idx]);
+                }
+            }
+        }
+
+        if desc.emitters != self.desc.emitters {
+            for &emitter in &self.loaded.emitters {
+                world_renderer.particles.remove_emitter(emitter);
+            }
+            self.loaded.emitters = world_renderer.add_scene_desc_emitters(&desc.emitters);
+        }
+
+        world_renderer.apply_scene_desc_sun(desc.sun.as_ref());
+
+        self.loaded.instances = instances;
+        self.loaded.sun_direction = scene_desc_sun_direction(&desc);
+        self.loaded.camera = desc.camera.clone();
+        self.desc = desc;
+
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_scene_descriptions() {
+        let scene =
+            SceneDesc::from_ron_str(include_str!("../../../../assets/scenes/sample.ron")).unwrap();
+
+        assert_eq!(scene.instances.len(), 3);
+        assert_eq!(
+            scene.instances[0].mesh_path(),
+            PathBuf::from("/baked/floor.mesh")
+        );
+        assert_eq!(scene.instances[0].scale, 1.0);
+        assert_eq!(
+            scene.instances[2].transparent_material(),
+            Some(TransparentMaterial::water())
+        );
+        assert_eq!(scene.emitters.len(), 1);
+        assert!(scene.sun.is_some());
+        assert!(scene.camera.is_some());
+
+        let rotated = SceneInstanceDesc {
+            rotation: [0.0, 90.0, 0.0],
+            scale: 2.0,
+            ..scene.instances[0].clone()
+        };
+        assert!(rotated
+            .transformation()
+            .transform_vector3(Vec3::X)
+            .abs_diff_eq(Vec3::new(0.0, 0.0, -2.0), 1e-5));
+
+        // Existing scenes only list instances.
+        let minimal = SceneDesc::from_ron_str(
+            r#"(instances: [(position: (0, 0, 0), mesh: "floor", transparency: Some(Glass))])"#,
+        )
+        .unwrap();
+        assert_eq!(minimal.instances[0].emissive_multiplier, 1.0);
+        assert_eq!(minimal.version, SCENE_DESC_VERSION);
+        assert!(minimal.sun.is_none());
+
+        let err = SceneDesc::from_ron_str("(version: 1000)").unwrap_err();
+        assert!(err.to_string().contains("version"), "{}", err);
+
+        // Errors name the field.
+        let err = SceneDesc::from_ron_str(
+            r#"(instances: [(position: (0, 0, 0), mesh: "floor", wirefrmae: true)])"#,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("wirefrmae"), "{}", err);
+
+        let err = SceneDesc::from_ron_str(r#"(instances: [(position: (0, 0, 0))])"#).unwrap_err();
+        assert!(err.to_string().contains("mesh"), "{}", err);
+    }
+
+    #[test]
+    fn plans_instance_updates() {
+        let instance = |mesh: &str, x: f32| SceneInstanceDesc {
+            mesh: mesh.to_owned(),
+            position: [x, 0.0, 0.0],
+            rotation: [0.0; 3],
+            scale: 1.0,
+            transparency: None,
+            highlight: None,
+            wireframe: false,
+            emissive_multiplier: 1.0,
+            lights: false,
+        };
+
+        let old = [
+            instance("floor", 0.0),
+            instance("floor", 1.0),
+            instance("floor", 2.0),
+            instance("car", 3.0),
+        ];
+        let new = [
+            instance("floor", 0.0),
+            // Moved
+            instance("floor", 5.0),
+            // Same mesh by its path
+            instance("/baked/floor.mesh", 2.0),
+        ];
+
+        assert_eq!(
+            plan_instance_updates(&old, &new),
+            vec![
+                InstanceUpdate::Keep,
+                InstanceUpdate::Update,
+                InstanceUpdate::Update,
+                InstanceUpdate::Remove
+            ]
+        );
+
+        let newer = [
+            instance("car", 0.0),
+            instance("floor", 1.0),
+            instance("floor", 2.0),
+            instance("car", 3.0),
+            instance("car", 4.0),
+        ];
+        assert_eq!(
+            plan_instance_updates(&old, &newer),
+            vec![
+                InstanceUpdate::Replace,
+                InstanceUpdate::Keep,
+                InstanceUpdate::Keep,
+                InstanceUpdate::Keep,
+                InstanceUpdate::Add
+            ]
+        );
+    }
+}
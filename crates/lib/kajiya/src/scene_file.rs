@@ -0,0 +1,441 @@
+// Binary snapshots of the world renderer's scene: meshes, instances, and lighting.
+//
+// Meshes are referenced by the paths of their baked files, which are mmapped and uploaded directly
+// on load, without importing the source glTF again. Files start with a magic number and a version;
+// files of any other version are rejected, and need to be saved again.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Context as _;
+use glam::{Affine3A, Vec3};
+
+use crate::{
+    renderers::transparency::TransparentMaterial,
+    world_renderer::{AddMeshOptions, InstanceHandle, MeshHandle, WorldRenderer},
+};
+
+const SCENE_FILE_MAGIC: [u8; 8] = *b"KJSCENE\0";
+
+/// Bump whenever the layout changes.
+pub const SCENE_FILE_VERSION: u32 = 1;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SceneMesh {
+    /// VFS path of the baked mesh, as passed to `WorldRenderer::add_baked_mesh`
+    pub path: PathBuf,
+    pub use_lights: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SceneInstance {
+    /// Index into `SceneSnapshot::meshes`
+    pub mesh: usize,
+    pub transformation: Affine3A,
+    pub emissive_multiplier: f32,
+    pub transparency: Option<TransparentMaterial>,
+    pub highlight: Option<usize>,
+    pub wireframe: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SceneLighting {
+    pub sun_direction: Vec3,
+    pub sun_size_multiplier: f32,
+    pub sun_color_multiplier: Vec3,
+    pub sky_ambient: Vec3,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SceneSnapshot {
+    pub meshes: Vec<SceneMesh>,
+    pub instances: Vec<SceneInstance>,
+    pub lighting: SceneLighting,
+}
+
+/// The result of `WorldRenderer::load_scene`.
+pub struct LoadedScene {
+    /// In the order of `SceneSnapshot::instances`
+    pub instances: Vec<InstanceHandle>,
+
+    /// Not stored in the world renderer; pass it in `WorldFrameDesc`.
+    pub sun_direction: Vec3,
+}
+
+impl SceneSnapshot {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = SceneWriter(SCENE_FILE_MAGIC.to_vec());
+        writer.u32(SCENE_FILE_VERSION);
+
+        writer.vec3(self.lighting.sun_direction);
+        writer.f32(self.lighting.sun_size_multiplier);
+        writer.vec3(self.lighting.sun_color_multiplier);
+        writer.vec3(self.lighting.sky_ambient);
+
+        writer.u32(self.meshes.len() as u32);
+        for mesh in &self.meshes {
+            writer.bytes(mesh.path.to_string_lossy().as_bytes());
+            writer.bool(mesh.use_lights);
+        }
+
+        writer.u32(self.instances.len() as u32);
+        for instance in &self.instances {
+            writer.u32(instance.mesh as u32);
+            for v in instance.transformation.to_cols_array() {
+                writer.f32(v);
+            }
+            writer.f32(instance.emissive_multiplier);
+
+            writer.bool(instance.transparency.is_some());
+            if let Some(material) = &instance.transparency {
+                writer.vec3(material.tint);
+                writer.f32(material.opacity);
+                writer.f32(material.ior);
+                writer.f32(material.refraction_strength);
+                writer.f32(material.soft_intersection_distance);
+            }
+
+            writer.bool(instance.highlight.is_some());
+            if let Some(highlight) = instance.highlight {
+                writer.u32(highlight as u32);
+            }
+
+            writer.bool(instance.wireframe);
+        }
+
+        writer.0
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let mut reader = SceneReader { bytes, offset: 0 };
+
+        if reader.take(SCENE_FILE_MAGIC.len())? != SCENE_FILE_MAGIC {
+            anyhow::bail!("Not a kajiya scene file");
+        }
+
+        let version = reader.u32()?;
+        if version != SCENE_FILE_VERSION {
+            anyhow::bail!(
+                "Unsupported scene file version {} (expected {}); save the scene again",
+                version,
+                SCENE_FILE_VERSION
+            );
+        }
+
+        let lighting = SceneLighting {
+            sun_direction: reader.vec3()?,
+            sun_size_multiplier: reader.f32()?,
+            sun_color_multiplier: reader.vec3()?,
+            sky_ambient: reader.vec3()?,
+        };
+
+        let mesh_count = reader.u32()? as usize;
+        let meshes = (0..mesh_count)
+            .map(|_| {
+                Ok(SceneMesh {
+                    path: PathBuf::from(
+                        String::from_utf8(reader.bytes()?.to_vec()).context("Mesh path")?,
+                    ),
+                    use_lights: reader.bool()?,
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let instance_count = reader.u32()? as usize;
+        let instances = (0..instance_count)
+            .map(|_| {
+                let mesh = reader.u32()? as usize;
+                if mesh >= meshes.len() {
+                    anyhow::bail!("Instance refers to mesh {} of {}", mesh, meshes.len());
+                }
+
+                let mut cols = [0.0f32; 12];
+                for v in &mut cols {
+                    *v = reader.f32()?;
+                }
+
+                let emissive_multiplier = reader.f32()?;
+
+                let transparency = if reader.bool()? {
+                    Some(TransparentMaterial {
+                        tint: reader.vec3()?,
+                        opacity: reader.f32()?,
+                        ior: reader.f32()?,
+                        refraction_strength: reader.f32()?,
+                        soft_intersection_distance: reader.f32()?,
+                    })
+                } else {
+                    None
+                };
+
+                let highlight = if reader.bool()? {
+                    Some(reader.u32()? as usize)
+                } else {
+                    None
+                };
+
+                Ok(SceneInstance {
+                    mesh,
+                    transformation: Affine3A::from_cols_array(&cols),
+                    emissive_multiplier,
+                    transparency,
+                    highlight,
+                    wireframe: reader.bool()?,
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        if reader.offset != bytes.len() {
+            anyhow::bail!("Unexpected data at the end of the scene file");
+        }
+
+        Ok(Self {
+            meshes,
+            instances,
+            lighting,
+        })
+    }
+}
+
+impl WorldRenderer {
+    /// Captures the instances of meshes added via `add_baked_mesh`, along with the lighting.
+    /// Fails if an instance uses a mesh which wasn't loaded from a file.
+    pub fn scene_snapshot(&self, sun_direction: Vec3) -> anyhow::Result<SceneSnapshot> {
+        let mut meshes = Vec::new();
+
+        // `MeshHandle` to index into `meshes`, for the meshes in use
+        let mut mesh_indices = vec![None; self.mesh_sources.len()];
+
+        let instances = self
+            .instances
+            .iter()
+            .map(|instance| {
+                let mesh_idx = instance.base_mesh.0;
+                let mesh = match mesh_indices[mesh_idx] {
+                    Some(mesh) => mesh,
+                    None => {
+                        let source = self.mesh_sources[mesh_idx].clone().with_context(|| {
+                            format!("Mesh {} wasn't loaded from a baked file", mesh_idx)
+                        })?;
+
+                        meshes.push(source);
+                        mesh_indices[mesh_idx] = Some(meshes.len() - 1);
+                        meshes.len() - 1
+                    }
+                };
+
+                Ok(SceneInstance {
+                    mesh,
+                    transformation: self.world_space_transform(instance.transformation),
+                    emissive_multiplier: instance.dynamic_parameters.emissive_multiplier,
+                    transparency: instance.transparency,
+                    highlight: instance.highlight,
+                    wireframe: instance.wireframe,
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(SceneSnapshot {
+            meshes,
+            instances,
+            lighting: SceneLighting {
+                sun_direction,
+                sun_size_multiplier: self.sun_size_multiplier,
+                sun_color_multiplier: self.sun_color_multiplier,
+                sky_ambient: self.sky_ambient,
+            },
+        })
+    }
+
+    pub fn save_scene(&self, path: impl AsRef<Path>, sun_direction: Vec3) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        std::fs::write(path, self.scene_snapshot(sun_direction)?.to_bytes())
+            .with_context(|| format!("Writing scene file {:?}", path))
+    }
+
+    /// Adds the meshes and instances of a scene file, and applies its lighting.
+    pub fn load_scene(&mut self, path: impl AsRef<Path>) -> anyhow::Result<LoadedScene> {
+        let path = path.as_ref();
+        let bytes =
+            std::fs::read(path).with_context(|| format!("Reading scene file {:?}", path))?;
+        let scene = SceneSnapshot::from_bytes(&bytes)
+            .with_context(|| format!("Loading scene file {:?}", path))?;
+
+        self.add_scene(&scene)
+    }
+
+    pub fn add_scene(&mut self, scene: &SceneSnapshot) -> anyhow::Result<LoadedScene> {
+        let meshes = scene
+            .meshes
+            .iter()
+            .map(|mesh| {
+                self.add_baked_mesh(
+                    &mesh.path,
+                    AddMeshOptions::new().use_lights(mesh.use_lights),
+                )
+            })
+            .collect::<anyhow::Result<Vec<MeshHandle>>>()?;
+
+        let instances = scene
+            .instances
+            .iter()
+            .map(|instance| {
+                let handle = self.add_instance(meshes[instance.mesh], instance.transformation);
+
+                self.get_instance_dynamic_parameters_mut(handle)
+                    .emissive_multiplier = instance.emissive_multiplier;
+                self.set_instance_transparency(handle, instance.transparency);
+                self.set_instance_highlight(handle, instance.highlight);
+                self.set_instance_wireframe(handle, instance.wireframe);
+
+                handle
+            })
+            .collect();
+
+        self.sun_size_multiplier = scene.lighting.sun_size_multiplier;
+        self.sun_color_multiplier = scene.lighting.sun_color_multiplier;
+        self.sky_ambient = scene.lighting.sky_ambient;
+
+        Ok(LoadedScene {
+            instances,
+            sun_direction: scene.lighting.sun_direction,
+        })
+    }
+}
+
+struct SceneWriter(Vec<u8>);
+
+impl SceneWriter {
+    fn u32(&mut self, v: u32) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn f32(&mut self, v: f32) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn bool(&mut self, v: bool) {
+        self.0.push(v as u8);
+    }
+
+    fn vec3(&mut self, v: Vec3) {
+        self.f32(v.x);
+        self.f32(v.y);
+        self.f32(v.z);
+    }
+
+    // Length-prefixed
+    fn bytes(&mut self, v: &[u8]) {
+        self.u32(v.len() as u32);
+        self.0.extend_from_slice(v);
+    }
+}
+
+struct SceneReader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> SceneReader<'a> {
+    fn take(&mut self, len: usize) -> anyhow::Result<&'a [u8]> {
+        let end = self.offset + len;
+        if end > self.bytes.len() {
+            anyhow::bail!("Unexpected end of the scene file");
+        }
+
+        let bytes = &self.bytes[self.offset..end];
+        self.offset = end;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> anyhow::Result<u32> {
+        let mut v = [0; 4];
+        v.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(v))
+    }
+
+    fn f32(&mut self) -> anyhow::Result<f32> {
+        Ok(f32::from_bits(self.u32()?))
+    }
+
+    fn bool(&mut self) -> anyhow::Result<bool> {
+        match self.take(1)?[0] {
+            0 => Ok(false),
+            1 => Ok(true),
+            v => anyhow::bail!("Invalid boolean {} in the scene file", v),
+        }
+    }
+
+    fn vec3(&mut self) -> anyhow::Result<Vec3> {
+        Ok(Vec3::new(self.f32()?, self.f32()?, self.f32()?))
+    }
+
+    fn bytes(&mut self) -> anyhow::Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use glam::Quat;
+
+    fn scene() -> SceneSnapshot {
+        SceneSnapshot {
+            meshes: vec![
+                SceneMesh {
+                    path: "/baked/336_lrm.mesh".into(),
+                    use_lights: false,
+                },
+                SceneMesh {
+                    path: "/baked/emissive-triangle.mesh".into(),
+                    use_lights: true,
+                },
+            ],
+            instances: vec![
+                SceneInstance {
+                    mesh: 0,
+                    transformation: Affine3A::from_rotation_translation(
+                        Quat::from_rotation_y(0.5),
+                        Vec3::new(1.0, -2.0, 3.0),
+                    ),
+                    emissive_multiplier: 1.0,
+                    transparency: None,
+                    highlight: Some(2),
+                    wireframe: false,
+                },
+                SceneInstance {
+                    mesh: 1,
+                    transformation: Affine3A::from_scale(Vec3::splat(0.25)),
+                    emissive_multiplier: 8.0,
+                    transparency: Some(TransparentMaterial::water()),
+                    highlight: None,
+                    wireframe: true,
+                },
+            ],
+            lighting: SceneLighting {
+                sun_direction: Vec3::new(4.0, 1.0, 1.0).normalize(),
+                sun_size_multiplier: 1.5,
+                sun_color_multiplier: Vec3::new(1.0, 0.9, 0.8),
+                sky_ambient: Vec3::ZERO,
+            },
+        }
+    }
+
+    #[test]
+    fn scene_round_trips_and_rejects_other_versions() {
+        let scene = scene();
+        let bytes = scene.to_bytes();
+        assert_eq!(SceneSnapshot::from_bytes(&bytes).unwrap(), scene);
+
+        // The version follows the magic number.
+        let mut old = bytes.clone();
+        old[SCENE_FILE_MAGIC.len()..SCENE_FILE_MAGIC.len() + 4]
+            .copy_from_slice(&(SCENE_FILE_VERSION - 1).to_le_bytes());
+        let err = SceneSnapshot::from_bytes(&old).unwrap_err();
+        assert!(err.to_string().contains("version"));
+
+        assert!(SceneSnapshot::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(SceneSnapshot::from_bytes(b"not a scene").is_err());
+    }
+}
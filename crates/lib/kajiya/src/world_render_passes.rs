@@ -1,15 +1,34 @@
 use crate::{
     frame_desc::WorldFrameDesc,
     renderers::{
-        deferred::light_gbuffer, motion_blur::motion_blur, post::post_process, raster_meshes::*,
-        reference::reference_path_trace, shadows::trace_sun_shadow_mask, GbufferDepth,
+        aov::{decode_gbuffer_aov, AovKind, LightingAovs},
+        cryptomatte::cryptomatte_hash,
+        deferred::light_gbuffer,
+        denoiser::DenoisedEffect,
+        post::post_process,
+        raster_meshes::*,
+        reference::{reference_path_trace, ReferenceEnvironment},
+        shadows::trace_sun_shadow_mask,
+        subsurface::scatter_subsurface,
+        transparency::TransparentMeshesData,
+        GbufferDepth,
     },
     world_renderer::{RenderDebugMode, WorldRenderer},
 };
 use kajiya_backend::{ash::vk, vulkan::image::*};
 use kajiya_rg::{self as rg, GetOrCreateTemporal};
+use std::collections::HashSet;
 
 impl WorldRenderer {
+    // The AOVs to write in the frame being recorded
+    fn wanted_aovs(&self) -> HashSet<AovKind> {
+        self.aovs
+            .iter()
+            .copied()
+            .chain(self.aov_readbacks.queued_kinds())
+            .collect()
+    }
+
     pub(super) fn prepare_render_graph_standard(
         &mut self,
         rg: &mut rg::TemporalRenderGraph,
@@ -32,7 +51,17 @@ impl WorldRenderer {
             )
             .unwrap();
 
-        let sky_cube = crate::renderers::sky::render_sky_cube(rg);
+        let atmosphere_luts = if self.use_atmosphere {
+            Some(self.atmosphere.prepare(rg))
+        } else {
+            None
+        };
+
+        let sky_cube = if let Some(atmosphere_luts) = atmosphere_luts.as_ref() {
+            atmosphere_luts.render_sky_cube(rg)
+        } else {
+            crate::renderers::sky::render_sky_cube(rg)
+        };
         let convolved_sky_cube = crate::renderers::sky::convolve_cube(rg, &sky_cube);
 
         let csgi_volume = if let Some(tlas) = tlas.as_ref() {
@@ -42,12 +71,31 @@ impl WorldRenderer {
                 &convolved_sky_cube,
                 self.bindless_descriptor_set,
                 tlas,
+                &self.procedural_hit_groups,
             )
         } else {
             self.csgi.create_dummy_volume(rg)
         };
 
-        let (gbuffer_depth, velocity_img) = {
+        let ddgi_volume = match tlas.as_ref() {
+            Some(tlas) if self.use_ddgi => Some(self.ddgi.render(
+                frame_desc.camera_matrices.eye_position(),
+                rg,
+                &sky_cube,
+                self.bindless_descriptor_set,
+                tlas,
+                &self.procedural_hit_groups,
+            )),
+            _ => None,
+        };
+
+        let wanted_aovs = self.wanted_aovs();
+
+        let wants_cryptomatte = wanted_aovs
+            .iter()
+            .any(|kind| kind.cryptomatte_layer().is_some());
+
+        let (mut gbuffer_depth, velocity_img) = {
             let mut gbuffer_depth = {
                 let normal = rg.create(ImageDesc::new_2d(
                     vk::Format::A2R10G10B10_UNORM_PACK32,
@@ -73,12 +121,49 @@ impl WorldRenderer {
                 frame_desc.render_extent,
             ));
 
+            let write_object_ids = self.picking.has_queued_requests()
+                || self.outline.is_active(self.instances.as_slice())
+                || wants_cryptomatte;
+
+            let write_subsurface = self
+                .instances
+                .iter()
+                .any(|inst| inst.transparency.is_none() && self.mesh_uses_subsurface[inst.mesh.0]);
+
+            // Both are written by the same extended gbuffer pass
+            let write_gbuffer_extras = write_object_ids || write_subsurface;
+
+            if write_gbuffer_extras {
+                let mut object_id_img = rg.create(ImageDesc::new_2d(
+                    vk::Format::R32_UINT,
+                    frame_desc.render_extent,
+                ));
+                rg::imageops::clear_image(
+                    rg,
+                    &mut object_id_img,
+                    rg::imageops::ClearValue::ColorUint([0; 4]),
+                );
+                gbuffer_depth.object_id = Some(object_id_img);
+
+                let mut subsurface_img = rg.create(ImageDesc::new_2d(
+                    vk::Format::R16G16B16A16_SFLOAT,
+                    frame_desc.render_extent,
+                ));
+                rg::imageops::clear_color(rg, &mut subsurface_img, [0.0f32; 4]);
+                gbuffer_depth.subsurface = Some(subsurface_img);
+            }
+
             if !matches!(self.debug_mode, RenderDebugMode::CsgiVoxelGrid { .. }) {
                 raster_meshes(
                     rg,
-                    self.raster_simple_render_pass.clone(),
+                    if write_gbuffer_extras {
+                        self.raster_gbuffer_render_pass.clone()
+                    } else {
+                        self.raster_simple_render_pass.clone()
+                    },
                     &mut gbuffer_depth,
                     &mut velocity_img,
+                    VelocityEncoding::default(),
                     RasterMeshesData {
                         meshes: self.meshes.as_slice(),
                         instances: self.instances.as_slice(),
@@ -98,24 +183,101 @@ impl WorldRenderer {
                 );
             }
 
+            #[cfg(feature = "gi-debug")]
+            if let (RenderDebugMode::DdgiProbes, Some(ddgi_volume)) =
+                (self.debug_mode, ddgi_volume.as_ref())
+            {
+                ddgi_volume.debug_raster_probes(
+                    rg,
+                    self.raster_simple_render_pass.clone(),
+                    &mut gbuffer_depth,
+                    &mut velocity_img,
+                );
+            }
+
             (gbuffer_depth, velocity_img)
         };
 
+        if let Some(object_id_img) = gbuffer_depth.object_id.as_ref() {
+            self.picking
+                .copy_to_host(rg, object_id_img, self.instance_handles.as_slice());
+        }
+
+        if wants_cryptomatte {
+            let object_hashes = self
+                .instance_handles
+                .iter()
+                .map(|inst| cryptomatte_hash(&self.instance_name(*inst)))
+                .collect();
+            let objects_moved = self
+                .instances
+                .iter()
+                .any(|inst| inst.transformation != inst.prev_transformation);
+            let layers = self.cryptomatte.render(
+                rg,
+                &gbuffer_depth,
+                &frame_desc.camera_matrices,
+                object_hashes,
+                objects_moved,
+            );
+
+            for (kind, layer) in AovKind::ALL
+                .iter()
+                .filter(|kind| kind.cryptomatte_layer().is_some())
+                .zip(layers)
+            {
+                if wanted_aovs.contains(kind) {
+                    self.frame_aovs.insert(*kind, layer);
+                }
+            }
+        }
+
+        self.decals.render(
+            rg,
+            &mut gbuffer_depth,
+            self.bindless_descriptor_set,
+            self.render_origin,
+        );
+
         let reprojection_map = crate::renderers::reprojection::calculate_reprojection_map(
             rg,
             &gbuffer_depth,
             &velocity_img,
         );
 
+        for kind in AovKind::ALL {
+            if kind.is_from_gbuffer() && wanted_aovs.contains(&kind) {
+                let aov = decode_gbuffer_aov(rg, &gbuffer_depth, &velocity_img, kind);
+                self.frame_aovs.insert(kind, aov);
+            }
+        }
+
         let ssgi_tex = self
             .ssgi
             .render(rg, &gbuffer_depth, &reprojection_map, &accum_img);
         //let ssgi_tex = rg.create(ImageDesc::new_2d(vk::Format::R8_UNORM, [1, 1]));
 
-        let sun_shadow_mask = if let Some(tlas) = tlas.as_ref() {
-            trace_sun_shadow_mask(rg, &gbuffer_depth, tlas, self.bindless_descriptor_set)
-        } else {
-            rg.create(gbuffer_depth.depth.desc().format(vk::Format::R8_UNORM))
+        let sun_shadow_mask = match tlas.as_ref() {
+            Some(tlas) if !self.use_sun_shadow_maps => trace_sun_shadow_mask(
+                rg,
+                &gbuffer_depth,
+                tlas,
+                &self.procedural_hit_groups,
+                self.bindless_descriptor_set,
+            ),
+            _ => self.csm.render(
+                rg,
+                &gbuffer_depth,
+                RasterMeshesData {
+                    meshes: self.meshes.as_slice(),
+                    instances: self.instances.as_slice(),
+                    vertex_buffer: self.vertex_buffer.lock().clone(),
+                    bindless_descriptor_set: self.bindless_descriptor_set,
+                },
+                &frame_desc.camera_matrices,
+                frame_desc.sun_direction,
+                frame_desc.time.frame_index,
+            ),
         };
 
         let denoised_shadow_mask = if self.sun_size_multiplier > 0.0f32 {
@@ -125,20 +287,37 @@ impl WorldRenderer {
             sun_shadow_mask.into()
         };
 
-        let rtdgi = if let Some(tlas) = tlas.as_ref() {
-            self.rtdgi.render(
-                rg,
-                &gbuffer_depth,
-                &reprojection_map,
-                &sky_cube,
-                self.bindless_descriptor_set,
-                tlas,
-                &csgi_volume,
-                &ssgi_tex,
-            )
-        } else {
-            rg.create(ImageDesc::new_2d(vk::Format::R8G8B8A8_UNORM, [1, 1]))
-                .into()
+        let rtdgi = match (tlas.as_ref(), ddgi_volume.as_ref()) {
+            (_, Some(ddgi_volume)) => ddgi_volume.sample_irradiance(rg, &gbuffer_depth).into(),
+            (Some(tlas), None) => {
+                let rtdgi = self.rtdgi.render(
+                    rg,
+                    &gbuffer_depth,
+                    &reprojection_map,
+                    &sky_cube,
+                    self.bindless_descriptor_set,
+                    tlas,
+                    &self.procedural_hit_groups,
+                    &csgi_volume,
+                    &ssgi_tex,
+                );
+
+                self.denoiser_dumps.copy_to_host(
+                    rg,
+                    DenoisedEffect::Rtdgi,
+                    &rtdgi.noisy,
+                    &rtdgi.denoised,
+                );
+
+                if wanted_aovs.contains(&AovKind::RawGi) {
+                    self.frame_aovs.insert(AovKind::RawGi, rtdgi.noisy);
+                }
+
+                rtdgi.denoised
+            }
+            (None, None) => rg
+                .create(ImageDesc::new_2d(vk::Format::R8G8B8A8_UNORM, [1, 1]))
+                .into(),
         };
 
         // TODO: don't iter over all the things
@@ -155,6 +334,7 @@ impl WorldRenderer {
                 &sky_cube,
                 self.bindless_descriptor_set,
                 tlas,
+                &self.procedural_hit_groups,
                 &csgi_volume,
                 &rtdgi,
             )
@@ -171,18 +351,29 @@ impl WorldRenderer {
                     &gbuffer_depth,
                     self.bindless_descriptor_set,
                     tlas,
+                    &self.procedural_hit_groups,
                 );
             }
         }
 
         let rtr = rtr.filter_temporal(rg, &gbuffer_depth, &reprojection_map);
+        if tlas.is_some() {
+            self.denoiser_dumps
+                .copy_to_host(rg, DenoisedEffect::Rtr, &rtr.noisy, &rtr.denoised);
+        }
+        let rtr = rtr.denoised;
 
         let mut debug_out_tex = rg.create(ImageDesc::new_2d(
             vk::Format::R16G16B16A16_SFLOAT,
             gbuffer_depth.gbuffer.desc().extent_2d(),
         ));
 
-        light_gbuffer(
+        let mut lighting_aovs = wanted_aovs
+            .iter()
+            .any(|kind| kind.is_lighting())
+            .then(|| LightingAovs::new(rg, gbuffer_depth.gbuffer.desc().extent_2d()));
+
+        let subsurface_diffuse = light_gbuffer(
             rg,
             &gbuffer_depth,
             &denoised_shadow_mask,
@@ -196,8 +387,76 @@ impl WorldRenderer {
             &convolved_sky_cube,
             self.bindless_descriptor_set,
             self.debug_shading_mode,
+            lighting_aovs.as_mut(),
+        );
+
+        self.point_lights.render(
+            rg,
+            &gbuffer_depth,
+            RasterMeshesData {
+                meshes: self.meshes.as_slice(),
+                instances: self.instances.as_slice(),
+                vertex_buffer: self.vertex_buffer.lock().clone(),
+                bindless_descriptor_set: self.bindless_descriptor_set,
+            },
+            &mut debug_out_tex,
+            lighting_aovs.as_mut().map(|aovs| &mut aovs.direct),
+            frame_desc.time.frame_index,
+        );
+
+        for (kind, aov) in lighting_aovs
+            .into_iter()
+            .flat_map(LightingAovs::into_images)
+        {
+            if wanted_aovs.contains(&kind) {
+                self.frame_aovs.insert(kind, aov);
+            }
+        }
+
+        self.aov_readbacks.copy_to_host(rg, &self.frame_aovs);
+
+        if let Some(subsurface_diffuse) = subsurface_diffuse.as_ref() {
+            scatter_subsurface(rg, &gbuffer_depth, subsurface_diffuse, &mut debug_out_tex);
+        }
+
+        let particles = self.particles.simulate(
+            rg,
+            tlas.as_ref(),
+            &self.procedural_hit_groups,
+            self.bindless_descriptor_set,
+            self.render_origin,
         );
 
+        self.transparency.render(
+            rg,
+            &mut gbuffer_depth,
+            &rtr,
+            &sky_cube,
+            frame_desc.camera_matrices.eye_position(),
+            TransparentMeshesData {
+                meshes: self.meshes.as_slice(),
+                instances: self.instances.as_slice(),
+                vertex_buffer: self.vertex_buffer.lock().clone(),
+                bindless_descriptor_set: self.bindless_descriptor_set,
+            },
+            &particles,
+            &mut debug_out_tex,
+        );
+
+        if let Some(atmosphere_luts) = atmosphere_luts.as_ref() {
+            atmosphere_luts.apply_aerial_perspective(rg, &gbuffer_depth, &mut debug_out_tex);
+        }
+
+        if self.use_volumetric_fog {
+            self.volumetric_fog.render(
+                rg,
+                &gbuffer_depth,
+                &denoised_shadow_mask,
+                &convolved_sky_cube,
+                &mut debug_out_tex,
+            );
+        }
+
         #[allow(unused_mut)]
         let mut anti_aliased = None;
 
@@ -224,21 +483,63 @@ impl WorldRenderer {
                 .this_frame_out
         });
 
-        let mut final_post_input =
-            motion_blur(rg, &anti_aliased, &gbuffer_depth.depth, &reprojection_map);
+        let mut final_post_input = if self.use_motion_blur {
+            self.motion_blur
+                .render(rg, &anti_aliased, &gbuffer_depth.depth, &reprojection_map)
+        } else {
+            anti_aliased
+        };
 
         if self.debug_mode == RenderDebugMode::CsgiRadiance {
             csgi_volume.fullscreen_debug_radiance(rg, &mut final_post_input);
         }
 
+        self.aov_readbacks
+            .copy_hdr_beauty_to_host(rg, &final_post_input);
+
         let post_processed = post_process(
             rg,
             &final_post_input,
             //&anti_aliased,
             self.bindless_descriptor_set,
-            self.ev_shift,
+            self.exposure.ev_shift(),
+            self.display_max_brightness,
+        );
+
+        let mut post_processed = self.color_grading.render(rg, post_processed);
+
+        self.wireframe.render(
+            rg,
+            &mut gbuffer_depth,
+            RasterMeshesData {
+                meshes: self.meshes.as_slice(),
+                instances: self.instances.as_slice(),
+                vertex_buffer: self.vertex_buffer.lock().clone(),
+                bindless_descriptor_set: self.bindless_descriptor_set,
+            },
+            &mut post_processed,
         );
 
+        self.outline.render(
+            rg,
+            &gbuffer_depth,
+            self.instances.as_slice(),
+            &mut post_processed,
+        );
+
+        self.debug_draw_renderer.render(
+            rg,
+            &mut gbuffer_depth,
+            &mut self.debug_draw,
+            self.render_origin,
+            &mut post_processed,
+        );
+
+        let mut post_processed = self.lens.render(rg, post_processed);
+
+        self.text_overlay_renderer
+            .render(rg, &mut self.text_overlay, &mut post_processed);
+
         rg.debugged_resource.take().unwrap_or(post_processed)
     }
 
@@ -261,20 +562,111 @@ impl WorldRenderer {
         if self.reset_reference_accumulation {
             self.reset_reference_accumulation = false;
             rg::imageops::clear_color(rg, &mut accum_img, [0.0, 0.0, 0.0, 0.0]);
+
+            #[cfg(feature = "oidn")]
+            self.oidn.reset(&mut self.aov_readbacks);
         }
 
         if rg.device().ray_tracing_enabled() {
             let tlas = self.prepare_top_level_acceleration(rg);
 
-            reference_path_trace(rg, &mut accum_img, self.bindless_descriptor_set, &tlas);
+            let environment_cdf = self.environment_cdf.prepare(
+                rg,
+                ReferenceEnvironment {
+                    sun_direction: frame_desc.sun_direction,
+                    sun_color_multiplier: self.sun_color_multiplier,
+                    sky_ambient: self.sky_ambient,
+                },
+            );
+
+            reference_path_trace(
+                rg,
+                &mut accum_img,
+                &environment_cdf,
+                self.bindless_descriptor_set,
+                &tlas,
+                &self.procedural_hit_groups,
+            );
+        }
+
+        let wanted_aovs = self.wanted_aovs();
+
+        if wanted_aovs.contains(&AovKind::Radiance) {
+            let mut radiance = rg.create(*accum_img.desc());
+            rg.copy_image(&accum_img, &mut radiance);
+            self.frame_aovs.insert(AovKind::Radiance, radiance);
+        }
+
+        // Rasterized rather than taken from the path tracer's primary hits, which are jittered
+        if wanted_aovs.iter().any(|kind| kind.is_from_gbuffer()) {
+            let mut gbuffer_depth = GbufferDepth::new(
+                rg.create(ImageDesc::new_2d(
+                    vk::Format::A2R10G10B10_UNORM_PACK32,
+                    frame_desc.render_extent,
+                )),
+                rg.create(ImageDesc::new_2d(
+                    vk::Format::R32G32B32A32_SFLOAT,
+                    frame_desc.render_extent,
+                )),
+                rg.create(ImageDesc::new_2d(
+                    vk::Format::D32_SFLOAT,
+                    frame_desc.render_extent,
+                )),
+            );
+            rg::imageops::clear_depth(rg, &mut gbuffer_depth.depth);
+
+            let mut velocity_img = rg.create(ImageDesc::new_2d(
+                vk::Format::R16G16B16A16_SFLOAT,
+                frame_desc.render_extent,
+            ));
+
+            raster_meshes(
+                rg,
+                self.raster_simple_render_pass.clone(),
+                &mut gbuffer_depth,
+                &mut velocity_img,
+                VelocityEncoding::default(),
+                RasterMeshesData {
+                    meshes: self.meshes.as_slice(),
+                    instances: self.instances.as_slice(),
+                    vertex_buffer: self.vertex_buffer.lock().clone(),
+                    bindless_descriptor_set: self.bindless_descriptor_set,
+                },
+            );
+
+            for kind in AovKind::ALL {
+                if kind.is_from_gbuffer() && wanted_aovs.contains(&kind) {
+                    let aov = decode_gbuffer_aov(rg, &gbuffer_depth, &velocity_img, kind);
+                    self.frame_aovs.insert(kind, aov);
+                }
+            }
         }
 
-        post_process(
+        self.aov_readbacks.copy_to_host(rg, &self.frame_aovs);
+
+        #[cfg(feature = "oidn")]
+        let denoised = self.oidn.prepare(rg, frame_desc.render_extent);
+        #[cfg(not(feature = "oidn"))]
+        let denoised: Option<rg::Handle<Image>> = None;
+
+        let hdr_beauty = denoised.as_ref().unwrap_or(&accum_img);
+        self.aov_readbacks.copy_hdr_beauty_to_host(rg, hdr_beauty);
+
+        let post_processed = post_process(
             rg,
-            &accum_img,
+            hdr_beauty,
             //&accum_img, // hack
             self.bindless_descriptor_set,
-            self.ev_shift,
-        )
+            self.exposure.ev_shift(),
+            self.display_max_brightness,
+        );
+
+        let post_processed = self.color_grading.render(rg, post_processed);
+        let mut post_processed = self.lens.render(rg, post_processed);
+
+        self.text_overlay_renderer
+            .render(rg, &mut self.text_overlay, &mut post_processed);
+
+        post_processed
     }
 }
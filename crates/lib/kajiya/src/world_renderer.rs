@@ -3,18 +3,54 @@ use crate::{
     buffer_builder::BufferBuilder,
     frame_desc::WorldFrameDesc,
     image_lut::{ComputeImageLut, ImageLut},
+    mesh_lod::{instance_screen_size, lod_selection_noise, select_mesh_lod, MeshLod},
+    render_origin::{rebase_camera_matrices, rebase_transform, snap_render_origin},
     renderers::{
-        csgi::CsgiRenderer, lighting::LightingRenderer, raster_meshes::*, rtdgi::RtdgiRenderer,
-        rtr::*, shadow_denoise::ShadowDenoiseRenderer, ssgi::*, taa::TaaRenderer,
+        analytic_primitives::AnalyticPrimitive,
+        aov::{AovKind, AovReadbackToken, AovReadbacks},
+        atmosphere::AtmosphereRenderer,
+        color_grading::{ColorGradingRenderer, CubeLut},
+        cryptomatte::{CryptomatteManifest, CryptomatteRenderer},
+        csgi::CsgiRenderer,
+        csm::CsmRenderer,
+        ddgi::DdgiRenderer,
+        debug_draw::{DebugDraw, DebugDrawRenderer},
+        decals::DecalRenderer,
+        denoiser::{DenoisedEffect, DenoiserDump, DenoiserDumpToken, DenoiserDumps},
+        lens::LensRenderer,
+        lighting::LightingRenderer,
+        motion_blur::MotionBlurRenderer,
+        multilayer_exr::{ExrExportToken, MultilayerExrExports},
+        outline::OutlineRenderer,
+        particles::ParticleSystem,
+        picking::{PickToken, PickingReadback},
+        point_lights::PointLightRenderer,
+        post::{Exposure, FilmGrain},
+        procedural::{ProceduralHitGroupRegistry, ProceduralHitGroups, ProceduralHitGroupsHandle},
+        raster_meshes::*,
+        readback::ReadbackImage,
+        reference::EnvironmentCdf,
+        rtdgi::RtdgiRenderer,
+        rtr::*,
+        shadow_denoise::ShadowDenoiseRenderer,
+        ssgi::*,
+        taa::TaaRenderer,
+        text::{TextOverlay, TextOverlayRenderer},
+        transparency::{TransparencyRenderer, TransparentMaterial},
+        volumetric_fog::VolumetricFogRenderer,
+        wireframe::WireframeRenderer,
     },
+    scene_file::SceneMesh,
 };
 use glam::{Affine3A, Vec2, Vec3};
-use kajiya_asset::mesh::{AssetRef, GpuImage, MeshMaterialFlags, PackedTriMesh, PackedVertex};
+use kajiya_asset::mesh::{
+    AssetRef, GpuImage, MeshMaterial, MeshMaterialFlags, PackedTriMesh, PackedVertex,
+};
 use kajiya_backend::{
     ash::vk::{self, ImageView},
     dynamic_constants::DynamicConstants,
     vk_sync::{self, AccessType},
-    vulkan::{self, device, image::*, ray_tracing::*, shader::*, RenderBackend},
+    vulkan::{self, device, image::*, ray_tracing::*, shader::*},
     BackendError,
 };
 use kajiya_rg::{self as rg};
@@ -27,12 +63,21 @@ use rust_shaders_shared::{
     frame_constants::{FrameConstants, GiCascadeConstants, MAX_CSGI_CASCADE_COUNT},
     view_constants::ViewConstants,
 };
-use std::{collections::HashMap, mem::size_of, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    mem::size_of,
+    path::PathBuf,
+    sync::Arc,
+    task::Poll,
+};
 use vulkan::buffer::{Buffer, BufferDesc};
 
 #[cfg(feature = "dlss")]
 use crate::renderers::dlss::DlssRenderer;
 
+#[cfg(feature = "oidn")]
+use crate::renderers::oidn::{OidnDenoiseToken, OidnRenderer};
+
 #[repr(C)]
 #[derive(Copy, Clone)]
 struct GpuMesh {
@@ -46,12 +91,39 @@ struct GpuMesh {
     index_offset: u32,
 }
 
+// How the materials of a mesh affect ray traversal
+#[derive(Clone, Copy)]
+struct MeshRayTracingFlags {
+    // No alpha testing, nor a mix of single- and double-sided materials for any-hit to sort out
+    opaque: bool,
+    double_sided: bool,
+}
+
+impl MeshRayTracingFlags {
+    fn new(materials: &[MeshMaterial]) -> Self {
+        let any_double_sided = materials.iter().any(MeshMaterial::is_double_sided);
+        let all_double_sided = materials.iter().all(MeshMaterial::is_double_sided);
+        let any_alpha_tested = materials.iter().any(MeshMaterial::is_alpha_tested);
+
+        Self {
+            opaque: !any_alpha_tested && (all_double_sided || !any_double_sided),
+            double_sided: any_double_sided,
+        }
+    }
+}
+
 #[derive(Clone, Copy, Hash, PartialEq, Eq, Debug)]
 pub struct MeshHandle(pub usize);
 
 #[derive(Clone, Copy, Hash, PartialEq, Eq, Debug)]
 pub struct InstanceHandle(pub usize);
 
+#[derive(Clone, Copy, Hash, PartialEq, Eq, Debug)]
+pub struct ProceduralBlasHandle(pub usize);
+
+#[derive(Clone, Copy, Hash, PartialEq, Eq, Debug)]
+pub struct ProceduralInstanceHandle(pub usize);
+
 const MAX_GPU_MESHES: usize = 1024;
 const VERTEX_BUFFER_CAPACITY: usize = 1024 * 1024 * 512;
 const TLAS_PREALLOCATE_BYTES: usize = 1024 * 1024 * 32;
@@ -73,15 +145,70 @@ impl Default for InstanceDynamicParameters {
 pub struct MeshInstance {
     pub transformation: Affine3A,
     pub prev_transformation: Affine3A,
+
+    /// The mesh drawn this frame: `base_mesh`, or one of its LODs; see `mesh_lod.rs`.
     pub mesh: MeshHandle,
+
+    /// The mesh the instance was added with.
+    pub base_mesh: MeshHandle,
     pub dynamic_parameters: InstanceDynamicParameters,
+
+    /// Transparent instances are drawn in a forward pass after opaque lighting, instead of the gbuffer.
+    /// Ray traced effects still treat them as opaque.
+    pub transparency: Option<TransparentMaterial>,
+
+    /// Draws a selection outline around the instance, using the `OutlineRenderer` color at this index.
+    pub highlight: Option<usize>,
+
+    /// Draws the edges of the instance's triangles over the image, even if the global wireframe is disabled.
+    pub wireframe: bool,
+}
+
+impl MeshInstance {
+    /// An opaque, static instance of `mesh`, with no highlight or wireframe.
+    pub fn new(mesh: MeshHandle, transformation: Affine3A) -> Self {
+        Self {
+            transformation,
+            prev_transformation: transformation,
+            mesh,
+            base_mesh: mesh,
+            dynamic_parameters: InstanceDynamicParameters::default(),
+            transparency: None,
+            highlight: None,
+            wireframe: false,
+        }
+    }
+}
+
+// A BLAS of boxes, whose contents intersection shaders define; see `renderers/procedural.rs`.
+struct ProceduralBlas {
+    blas: Arc<RayTracingAcceleration>,
+
+    // Kept for as long as the BLAS
+    _aabb_buffer: Buffer,
+}
+
+#[derive(Clone)]
+struct ProceduralInstance {
+    handle: ProceduralInstanceHandle,
+    transformation: Affine3A,
+    blas: ProceduralBlasHandle,
+    hit_groups: ProceduralHitGroupsHandle,
+
+    // For hit records of the instance's own; see `ProceduralHitGroupRegistry::push_hit_records`
+    shader_record_data: Option<Vec<u8>>,
 }
 
 #[derive(Clone, Copy, PartialEq, Eq)]
 pub enum RenderDebugMode {
     None,
-    CsgiVoxelGrid { cascade_idx: usize },
+    CsgiVoxelGrid {
+        cascade_idx: usize,
+    },
     CsgiRadiance,
+    /// Draws the probes of the probe grid GI; shows nothing unless `use_ddgi` is set.
+    #[cfg(feature = "gi-debug")]
+    DdgiProbes,
 }
 
 #[derive(Clone, Copy)]
@@ -123,11 +250,24 @@ pub struct WorldRenderer {
     device: Arc<device::Device>,
 
     pub(super) raster_simple_render_pass: Arc<RenderPass>,
+    pub(super) raster_gbuffer_render_pass: Arc<RenderPass>,
     pub(super) bindless_descriptor_set: vk::DescriptorSet,
     pub(super) meshes: Vec<UploadedTriMesh>,
 
     pub(super) mesh_lights: Vec<MeshLightSet>,
 
+    // Indexed by `MeshHandle`; set for meshes added from files, which scene files can refer to.
+    pub(super) mesh_sources: Vec<Option<SceneMesh>>,
+
+    // Indexed by `MeshHandle`; whether any of the mesh's materials scatter light under the surface.
+    pub(super) mesh_uses_subsurface: Vec<bool>,
+
+    // Indexed by `MeshHandle`; set by `set_mesh_lods`, from the finest to the coarsest.
+    mesh_lods: Vec<Vec<MeshLod>>,
+
+    // Indexed by `MeshHandle`; the radius of a sphere around the mesh origin containing the mesh.
+    mesh_bounding_radii: Vec<f32>,
+
     // ----
     // SoA
     pub(super) instances: Vec<MeshInstance>,
@@ -137,21 +277,32 @@ pub struct WorldRenderer {
     // The `usize` indexes into `instances` and `instance_handles`
     pub(super) instance_handle_to_index: HashMap<InstanceHandle, usize>,
 
+    // Set by `set_instance_name`, for the Cryptomatte
+    instance_names: HashMap<InstanceHandle, String>,
+
     pub(super) vertex_buffer: Mutex<Arc<Buffer>>,
     vertex_buffer_written: u64,
 
     mesh_buffer: Mutex<Arc<Buffer>>,
 
     mesh_blas: Vec<Arc<RayTracingAcceleration>>,
+    mesh_rt_flags: Vec<MeshRayTracingFlags>,
     tlas: Option<Arc<RayTracingAcceleration>>,
     accel_scratch: RayTracingAccelerationScratchBuffer,
 
+    procedural_blas: Vec<ProceduralBlas>,
+    procedural_instances: Vec<ProceduralInstance>,
+    next_procedural_instance_handle: usize,
+    pub(super) procedural_hit_groups: ProceduralHitGroupRegistry,
+
+    // BLASes of `add_analytic_primitive`, by the bounds of their shapes
+    analytic_primitive_blas: Vec<(RayTracingAabb, ProceduralBlasHandle)>,
+
     bindless_images: Vec<Arc<Image>>,
     next_bindless_image_id: usize,
     next_instance_handle: usize,
 
     image_luts: Vec<ImageLut>,
-    frame_idx: u32,
     prev_camera_matrices: Option<CameraMatrices>,
     pub(crate) temporal_upscale_extent: [u32; 2],
 
@@ -160,28 +311,134 @@ pub struct WorldRenderer {
     pub rg_debug_hook: Option<rg::GraphDebugHook>,
     pub render_mode: RenderMode,
     pub reset_reference_accumulation: bool,
+    pub(super) environment_cdf: EnvironmentCdf,
+    pub(super) cryptomatte: CryptomatteRenderer,
 
     pub ssgi: SsgiRenderer,
     pub rtr: RtrRenderer,
     pub lighting: LightingRenderer,
     pub rtdgi: RtdgiRenderer,
     pub csgi: CsgiRenderer,
+    pub ddgi: DdgiRenderer,
+
+    /// Use the world-space probe grid for diffuse GI instead of `rtdgi`.
+    pub use_ddgi: bool,
+
     pub taa: TaaRenderer,
     pub shadow_denoise: ShadowDenoiseRenderer,
 
+    /// Cascaded shadow maps for the sun. Used instead of ray traced sun shadows
+    /// where ray tracing isn't available, or with `use_sun_shadow_maps`.
+    pub csm: CsmRenderer,
+    pub use_sun_shadow_maps: bool,
+
+    /// Point lights, with cube shadow maps
+    pub point_lights: PointLightRenderer,
+
+    pub volumetric_fog: VolumetricFogRenderer,
+    pub use_volumetric_fog: bool,
+    pub motion_blur: MotionBlurRenderer,
+    pub use_motion_blur: bool,
+    pub color_grading: ColorGradingRenderer,
+    pub lens: LensRenderer,
+
+    /// Applied by the final blit to the swapchain, in `kajiya-simple`
+    pub film_grain: FilmGrain,
+    pub decals: DecalRenderer,
+    pub transparency: TransparencyRenderer,
+    pub particles: ParticleSystem,
+    pub outline: OutlineRenderer,
+    pub wireframe: WireframeRenderer,
+
+    /// Lines and shapes to draw over the next frame; cleared after it.
+    pub debug_draw: DebugDraw,
+    pub(super) debug_draw_renderer: DebugDrawRenderer,
+
+    /// Text to draw over the next frame, in output pixels; cleared after it.
+    pub text_overlay: TextOverlay,
+    pub(super) text_overlay_renderer: TextOverlayRenderer,
+
+    pub(super) picking: PickingReadback,
+    pub(super) denoiser_dumps: DenoiserDumps,
+
+    /// AOVs to write in every frame, for `get_aov`.
+    pub aovs: HashSet<AovKind>,
+    pub(super) frame_aovs: HashMap<AovKind, rg::Handle<Image>>,
+    pub(super) aov_readbacks: AovReadbacks,
+    exr_exports: MultilayerExrExports,
+
+    pub atmosphere: AtmosphereRenderer,
+
+    /// Use the precomputed physically based atmosphere for the sky and aerial perspective
+    /// instead of the procedural sky.
+    pub use_atmosphere: bool,
+
     #[cfg(feature = "dlss")]
     pub dlss: DlssRenderer,
     #[cfg(feature = "dlss")]
     pub use_dlss: bool,
 
+    #[cfg(feature = "oidn")]
+    pub(super) oidn: OidnRenderer,
+
     pub debug_mode: RenderDebugMode,
     pub debug_shading_mode: usize,
-    pub ev_shift: f32,
+    exposure: Exposure,
+
+    /// Brightest value the display transform outputs, relative to SDR white.
+    /// 1.0 for SDR; raise it to `peak nits / SDR white nits` when presenting in HDR.
+    pub display_max_brightness: f32,
 
     pub world_gi_scale: f32,
     pub sun_size_multiplier: f32,
     pub sun_color_multiplier: Vec3,
     pub sky_ambient: Vec3,
+
+    /// How ray traced shadows, reflections, and the reference path tracer keep rays
+    /// from hitting the surfaces they start on.
+    pub ray_origin_offset: RayOriginOffset,
+
+    /// How far around each LOD threshold, relative to it, instances fade between LODs.
+    pub lod_transition_width: f32,
+
+    /// Renders relative to an origin near the camera, for precision in large worlds.
+    /// Transparent to the application, which keeps using world space; see `render_origin.rs`.
+    pub rebase_origin: bool,
+
+    // Subtracted from world-space positions before they reach the GPU. Instance transforms
+    // are stored relative to it.
+    pub(super) render_origin: Vec3,
+}
+
+/// Moves ray origins off the surfaces they start on, along the geometric normal, so that rays
+/// don't hit those surfaces again and cause acne. Positions reconstructed from depth are also
+/// moved by a fraction of their distance from the camera. See `inc/ray_origin.hlsl`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RayOriginOffset {
+    /// By a fixed distance in world units. Falls below float precision far from the world
+    /// origin, so surfaces there shadow themselves.
+    Epsilon(f32),
+
+    /// By a number of ulps of each coordinate of the origin, which scale with its magnitude;
+    /// "A Fast and Robust Method for Avoiding Self-Intersection" (Ray Tracing Gems, chapter 6).
+    /// The value scales the offset; `1.0` is that of the paper.
+    Robust(f32),
+}
+
+impl Default for RayOriginOffset {
+    fn default() -> Self {
+        Self::Robust(1.0)
+    }
+}
+
+impl RayOriginOffset {
+    // Matches `RAY_ORIGIN_OFFSET_*` in `ray_origin.hlsl`
+    fn method_and_scale(self) -> (u32, f32) {
+        match self {
+            Self::Epsilon(epsilon) => (0, epsilon),
+            Self::Robust(scale) => (1, scale),
+        }
+    }
 }
 
 #[derive(Clone, Copy, PartialEq, Eq)]
@@ -197,11 +454,8 @@ fn load_gpu_image_asset(
     device: Arc<kajiya_backend::Device>,
     asset: AssetRef<GpuImage::Flat>,
 ) -> Arc<Image> {
-    let asset = crate::mmap::mmapped_asset::<GpuImage::Flat, _>(&format!(
-        "/baked/{:8.8x}.image",
-        asset.identity()
-    ))
-    .unwrap();
+    let path = format!("/baked/{:8.8x}.image", asset.identity());
+    let asset = crate::mmap::mmapped_asset::<GpuImage::Flat, _>(&path).unwrap();
 
     let desc = ImageDesc::new_2d(asset.format, [asset.extent[0], asset.extent[1]])
         .usage(vk::ImageUsageFlags::SAMPLED)
@@ -218,7 +472,7 @@ fn load_gpu_image_asset(
         })
         .collect::<Vec<_>>();
 
-    Arc::new(device.create_image(desc, initial_data).unwrap())
+    Arc::new(device.create_image(desc, path, initial_data).unwrap())
 }
 
 #[derive(Default)]
@@ -242,10 +496,10 @@ impl WorldRenderer {
         // Internal render resolution, before any upsampling
         #[allow(unused_variables)] render_extent: [u32; 2],
         temporal_upscale_extent: [u32; 2],
-        backend: &RenderBackend,
+        device: &Arc<device::Device>,
     ) -> Result<Self, BackendError> {
         let raster_simple_render_pass = create_render_pass(
-            &*backend.device,
+            &**device,
             RenderPassDesc {
                 color_attachments: &[
                     // view-space geometry normal; * 2 - 1 to decode
@@ -260,7 +514,26 @@ impl WorldRenderer {
             },
         );
 
-        let mesh_buffer = backend.device.create_buffer(
+        // Same as `raster_simple_render_pass`, plus per-object IDs for the selection outline,
+        // and subsurface scattering distances
+        let raster_gbuffer_render_pass = create_render_pass(
+            &**device,
+            RenderPassDesc {
+                color_attachments: &[
+                    RenderPassAttachmentDesc::new(vk::Format::A2R10G10B10_UNORM_PACK32)
+                        .garbage_input(),
+                    RenderPassAttachmentDesc::new(vk::Format::R32G32B32A32_SFLOAT).garbage_input(),
+                    RenderPassAttachmentDesc::new(vk::Format::R16G16B16A16_SFLOAT).garbage_input(),
+                    // object id; draw index + 1, or 0 for the background
+                    RenderPassAttachmentDesc::new(vk::Format::R32_UINT),
+                    // subsurface scattering distance per channel, or 0 for opaque surfaces
+                    RenderPassAttachmentDesc::new(vk::Format::R16G16B16A16_SFLOAT),
+                ],
+                depth_attachment: Some(RenderPassAttachmentDesc::new(vk::Format::D32_SFLOAT)),
+            },
+        );
+
+        let mesh_buffer = device.create_buffer(
             BufferDesc::new_cpu_to_gpu(
                 MAX_GPU_MESHES * size_of::<GpuMesh>(),
                 vk::BufferUsageFlags::STORAGE_BUFFER,
@@ -269,7 +542,7 @@ impl WorldRenderer {
             None,
         )?;
 
-        let vertex_buffer = backend.device.create_buffer(
+        let vertex_buffer = device.create_buffer(
             BufferDesc::new_gpu_only(
                 VERTEX_BUFFER_CAPACITY,
                 vk::BufferUsageFlags::STORAGE_BUFFER
@@ -282,21 +555,11 @@ impl WorldRenderer {
             None,
         )?;
 
-        let bindless_descriptor_set = create_bindless_descriptor_set(backend.device.as_ref());
+        let bindless_descriptor_set = create_bindless_descriptor_set(device.as_ref());
 
-        Self::write_descriptor_set_buffer(
-            &backend.device.raw,
-            bindless_descriptor_set,
-            0,
-            &mesh_buffer,
-        );
+        Self::write_descriptor_set_buffer(&device.raw, bindless_descriptor_set, 0, &mesh_buffer);
 
-        Self::write_descriptor_set_buffer(
-            &backend.device.raw,
-            bindless_descriptor_set,
-            1,
-            &vertex_buffer,
-        );
+        Self::write_descriptor_set_buffer(&device.raw, bindless_descriptor_set, 1, &vertex_buffer);
 
         let supersample_count = 128;
         let supersample_offsets = (1..=supersample_count)
@@ -310,30 +573,43 @@ impl WorldRenderer {
             Vec2::new(-0.25, -0.25),
         ];*/
 
-        let accel_scratch = backend
-            .device
-            .create_ray_tracing_acceleration_scratch_buffer()?;
+        let accel_scratch = device.create_ray_tracing_acceleration_scratch_buffer()?;
 
         #[cfg(feature = "dlss")]
-        let dlss = DlssRenderer::new(backend, render_extent, temporal_upscale_extent);
+        let dlss = DlssRenderer::new(device, render_extent, temporal_upscale_extent);
 
         Ok(Self {
             raster_simple_render_pass,
+            raster_gbuffer_render_pass,
 
             reset_reference_accumulation: false,
+            environment_cdf: Default::default(),
+            cryptomatte: Default::default(),
             //cube_index_buffer: Arc::new(cube_index_buffer),
-            device: backend.device.clone(),
+            device: device.clone(),
             meshes: Default::default(),
             instances: Default::default(),
             instance_handles: Default::default(),
             instance_handle_to_index: Default::default(),
+            instance_names: Default::default(),
 
             mesh_lights: Default::default(),
+            mesh_sources: Default::default(),
+            mesh_uses_subsurface: Default::default(),
+            mesh_lods: Default::default(),
+            mesh_bounding_radii: Default::default(),
 
             mesh_blas: Default::default(),
+            mesh_rt_flags: Default::default(),
             tlas: Default::default(),
             accel_scratch,
 
+            procedural_blas: Default::default(),
+            procedural_instances: Default::default(),
+            next_procedural_instance_handle: 0,
+            procedural_hit_groups: Default::default(),
+            analytic_primitive_blas: Default::default(),
+
             mesh_buffer: Mutex::new(Arc::new(mesh_buffer)),
             vertex_buffer: Mutex::new(Arc::new(vertex_buffer)),
             vertex_buffer_written: 0,
@@ -346,38 +622,74 @@ impl WorldRenderer {
 
             rg_debug_hook: None,
             render_mode: RenderMode::Standard,
-            frame_idx: 0u32,
             prev_camera_matrices: None,
 
             supersample_offsets,
 
             ssgi: Default::default(),
-            rtr: RtrRenderer::new(backend.device.as_ref())?,
+            rtr: RtrRenderer::new(device.as_ref())?,
             lighting: LightingRenderer::new(),
             csgi: CsgiRenderer::default(),
-            rtdgi: RtdgiRenderer::new(backend.device.as_ref())?,
+            ddgi: Default::default(),
+            use_ddgi: false,
+            rtdgi: RtdgiRenderer::new(device.as_ref())?,
             taa: TaaRenderer::new(),
             shadow_denoise: Default::default(),
+            csm: CsmRenderer::new(device.as_ref()),
+            use_sun_shadow_maps: false,
+            point_lights: PointLightRenderer::new(device.as_ref()),
+            volumetric_fog: Default::default(),
+            use_volumetric_fog: false,
+            motion_blur: Default::default(),
+            use_motion_blur: true,
+            color_grading: Default::default(),
+            lens: Default::default(),
+            film_grain: Default::default(),
+            decals: Default::default(),
+            transparency: TransparencyRenderer::new(device.as_ref()),
+            particles: Default::default(),
+            outline: Default::default(),
+            wireframe: WireframeRenderer::new(device.as_ref()),
+            debug_draw: Default::default(),
+            debug_draw_renderer: DebugDrawRenderer::new(device.as_ref()),
+            text_overlay: Default::default(),
+            text_overlay_renderer: TextOverlayRenderer::new(device.as_ref()),
+            picking: Default::default(),
+            denoiser_dumps: Default::default(),
+            aovs: Default::default(),
+            frame_aovs: Default::default(),
+            aov_readbacks: Default::default(),
+            exr_exports: Default::default(),
+            atmosphere: Default::default(),
+            use_atmosphere: false,
 
             #[cfg(feature = "dlss")]
             dlss,
             #[cfg(feature = "dlss")]
             use_dlss: true,
 
+            #[cfg(feature = "oidn")]
+            oidn: Default::default(),
+
             temporal_upscale_extent,
 
             debug_mode: RenderDebugMode::None,
-            debug_shading_mode: if backend.device.ray_tracing_enabled() {
+            debug_shading_mode: if device.ray_tracing_enabled() {
                 0
             } else {
                 // RTX OFF; HACK: reflections buffers currently smear without ray tracing.
                 4
             },
-            ev_shift: 0.0,
+            exposure: Default::default(),
+            display_max_brightness: 1.0,
             world_gi_scale: 1.0,
             sun_size_multiplier: 1.0, // Sun as seen from Earth
             sun_color_multiplier: Vec3::ONE,
             sky_ambient: Vec3::ZERO,
+            ray_origin_offset: Default::default(),
+            lod_transition_width: 0.2,
+            rebase_origin: true,
+            render_origin: Vec3::ZERO,
         })
     }
 
@@ -452,6 +764,25 @@ impl WorldRenderer {
         handle
     }
 
+    /// Grades the output with `lut`, or stops grading if `None`.
+    pub fn set_color_grading_lut(&mut self, lut: Option<&CubeLut>) -> anyhow::Result<()> {
+        self.color_grading.set_lut(&self.device, lut)
+    }
+
+    /// Sets how the scene is exposed before tone mapping. See `Exposure` for the EV convention.
+    pub fn set_exposure(&mut self, exposure: Exposure) {
+        self.exposure = exposure;
+    }
+
+    pub fn exposure(&self) -> Exposure {
+        self.exposure
+    }
+
+    /// The descriptor set of bindless meshes and images, for passes rendered outside of `WorldRenderer`.
+    pub fn bindless_descriptor_set(&self) -> vk::DescriptorSet {
+        self.bindless_descriptor_set
+    }
+
     pub fn add_mesh(
         &mut self,
         mesh: &'static PackedTriMesh::Flat,
@@ -570,11 +901,14 @@ impl WorldRenderer {
                             }],
                         }],
                     },
+                    format!("mesh {} BLAS", mesh_idx),
                     &self.accel_scratch,
                 )
                 .expect("blas");
 
             self.mesh_blas.push(Arc::new(blas));
+            self.mesh_rt_flags
+                .push(MeshRayTracingFlags::new(mesh.materials.as_slice()));
         }
 
         mesh_buffer_dst[mesh_idx] = GpuMesh {
@@ -626,22 +960,47 @@ impl WorldRenderer {
             lights: mesh_lights,
         });
 
+        self.mesh_sources.push(None);
+        self.mesh_lods.push(Vec::new());
+        self.mesh_bounding_radii.push(
+            mesh.verts
+                .as_slice()
+                .iter()
+                .map(|v| Vec3::from(v.pos).length())
+                .fold(0.0, f32::max),
+        );
+        self.mesh_uses_subsurface.push(
+            mesh.materials
+                .as_slice()
+                .iter()
+                .any(MeshMaterial::uses_subsurface),
+        );
+
         MeshHandle(mesh_idx)
     }
 
+    /// Coarser versions of `mesh`, which its instances draw instead once they get small on screen.
+    /// `lods` go from the finest to the coarsest; see `mesh_lod.rs`.
+    pub fn set_mesh_lods(&mut self, mesh: MeshHandle, lods: Vec<MeshLod>) {
+        assert!(
+            lods.windows(2)
+                .all(|pair| pair[0].screen_size > pair[1].screen_size),
+            "LODs must get coarser at smaller screen sizes"
+        );
+        assert!(lods.iter().all(|lod| lod.mesh.0 < self.meshes.len()));
+
+        self.mesh_lods[mesh.0] = lods;
+    }
+
     pub fn add_instance(&mut self, mesh: MeshHandle, transform: Affine3A) -> InstanceHandle {
         let handle = self.next_instance_handle;
         self.next_instance_handle += 1;
         let handle = InstanceHandle(handle);
 
         let index = self.instances.len();
+        let transform = rebase_transform(transform, self.render_origin);
 
-        self.instances.push(MeshInstance {
-            transformation: transform,
-            prev_transformation: transform,
-            mesh,
-            dynamic_parameters: InstanceDynamicParameters::default(),
-        });
+        self.instances.push(MeshInstance::new(mesh, transform));
         self.instance_handles.push(handle);
 
         assert_eq!(self.instances.len(), self.instance_handles.len());
@@ -658,6 +1017,7 @@ impl WorldRenderer {
             .expect("no such instance");
         self.instances.swap_remove(index);
         self.instance_handles.swap_remove(index);
+        self.instance_names.remove(&inst);
 
         // A new instance could have been moved into this slot in the vec.
         // Make sure `instance_handle_to_index` reflects this.
@@ -668,7 +1028,387 @@ impl WorldRenderer {
 
     pub fn set_instance_transform(&mut self, inst: InstanceHandle, transform: Affine3A) {
         let index = self.instance_handle_to_index[&inst];
-        self.instances[index].transformation = transform;
+        self.instances[index].transformation = rebase_transform(transform, self.render_origin);
+    }
+
+    /// The world-space transform of the instance.
+    pub fn instance_transform(&self, inst: InstanceHandle) -> Affine3A {
+        let index = self.instance_handle_to_index[&inst];
+        self.world_space_transform(self.instances[index].transformation)
+    }
+
+    pub(crate) fn world_space_transform(&self, transform: Affine3A) -> Affine3A {
+        rebase_transform(transform, -self.render_origin)
+    }
+
+    /// Builds a BLAS of procedural geometry from `aabbs`, in the space of its instances.
+    /// Rays entering a box run the intersection shader of the instance's hit groups, with the
+    /// index of the box as `PrimitiveIndex()`. Fails without ray tracing, or without boxes.
+    pub fn add_procedural_blas(
+        &mut self,
+        aabbs: &[RayTracingAabb],
+    ) -> anyhow::Result<ProceduralBlasHandle> {
+        anyhow::ensure!(
+            self.device.ray_tracing_enabled(),
+            "Procedural geometry is only ray traced"
+        );
+        anyhow::ensure!(
+            !aabbs.is_empty(),
+            "A procedural BLAS needs at least one box"
+        );
+
+        let handle = ProceduralBlasHandle(self.procedural_blas.len());
+
+        let aabb_buffer = self
+            .device
+            .create_ray_tracing_aabb_buffer(aabbs, format!("procedural {} AABBs", handle.0))?;
+
+        let blas = self.device.create_ray_tracing_bottom_acceleration(
+            &RayTracingBottomAccelerationDesc {
+                geometries: vec![RayTracingGeometryDesc::aabbs(
+                    aabb_buffer.device_address(&self.device),
+                    aabbs.len(),
+                )],
+            },
+            format!("procedural {} BLAS", handle.0),
+            &self.accel_scratch,
+        )?;
+
+        self.procedural_blas.push(ProceduralBlas {
+            blas: Arc::new(blas),
+            _aabb_buffer: aabb_buffer,
+        });
+
+        Ok(handle)
+    }
+
+    /// Makes ray tracing passes include `groups`, for `add_procedural_instance`. Registering
+    /// the same groups again returns the same handle. Passes get new pipelines once they
+    /// include new groups.
+    pub fn register_procedural_hit_groups(
+        &mut self,
+        groups: ProceduralHitGroups,
+    ) -> ProceduralHitGroupsHandle {
+        self.procedural_hit_groups.register(groups)
+    }
+
+    /// Places `blas` in the ray traced scene, with the hit groups of `hit_groups`, which must
+    /// have been registered with this renderer.
+    pub fn add_procedural_instance(
+        &mut self,
+        blas: ProceduralBlasHandle,
+        hit_groups: ProceduralHitGroupsHandle,
+        transform: Affine3A,
+    ) -> ProceduralInstanceHandle {
+        self.push_procedural_instance(blas, hit_groups, transform, None)
+    }
+
+    fn push_procedural_instance(
+        &mut self,
+        blas: ProceduralBlasHandle,
+        hit_groups: ProceduralHitGroupsHandle,
+        transform: Affine3A,
+        shader_record_data: Option<Vec<u8>>,
+    ) -> ProceduralInstanceHandle {
+        assert!(
+            blas.0 < self.procedural_blas.len(),
+            "no such procedural BLAS"
+        );
+        assert!(
+            self.procedural_hit_groups.contains(hit_groups),
+            "procedural hit groups registered elsewhere"
+        );
+
+        let handle = ProceduralInstanceHandle(self.next_procedural_instance_handle);
+        self.next_procedural_instance_handle += 1;
+
+        self.procedural_instances.push(ProceduralInstance {
+            handle,
+            transformation: rebase_transform(transform, self.render_origin),
+            blas,
+            hit_groups,
+            shader_record_data,
+        });
+
+        handle
+    }
+
+    /// Places an analytic primitive in the ray traced scene, like a procedural instance.
+    /// Primitives with the same shape share a BLAS, and those of a kind share hit groups.
+    /// Fails without ray tracing.
+    pub fn add_analytic_primitive(
+        &mut self,
+        primitive: AnalyticPrimitive,
+        transform: Affine3A,
+    ) -> anyhow::Result<ProceduralInstanceHandle> {
+        let aabb = primitive.shape.aabb();
+        let blas = if let Some((_, blas)) = self
+            .analytic_primitive_blas
+            .iter()
+            .find(|(existing, _)| *existing == aabb)
+        {
+            *blas
+        } else {
+            let blas = self.add_procedural_blas(&[aabb])?;
+            self.analytic_primitive_blas.push((aabb, blas));
+            blas
+        };
+
+        let hit_groups = self.register_procedural_hit_groups(primitive.hit_groups());
+        Ok(self.push_procedural_instance(
+            blas,
+            hit_groups,
+            transform,
+            Some(primitive.shader_record_data()),
+        ))
+    }
+
+    /// Returns whether there was such an instance.
+    pub fn remove_procedural_instance(&mut self, inst: ProceduralInstanceHandle) -> bool {
+        if let Some(index) = self.procedural_instance_index(inst) {
+            self.procedural_instances.swap_remove(index);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Does nothing for instances which were removed.
+    pub fn set_procedural_instance_transform(
+        &mut self,
+        inst: ProceduralInstanceHandle,
+        transform: Affine3A,
+    ) {
+        if let Some(index) = self.procedural_instance_index(inst) {
+            self.procedural_instances[index].transformation =
+                rebase_transform(transform, self.render_origin);
+        }
+    }
+
+    fn procedural_instance_index(&self, inst: ProceduralInstanceHandle) -> Option<usize> {
+        self.procedural_instances
+            .iter()
+            .position(|procedural| procedural.handle == inst)
+    }
+
+    // Moves the render origin near the camera if it's strayed far from it, and translates
+    // the instances to match. The previous frame's transforms move too, so motion vectors
+    // and temporal reprojection are unaffected.
+    fn update_render_origin(&mut self, frame_desc: &WorldFrameDesc) {
+        let origin = if self.rebase_origin {
+            snap_render_origin(frame_desc.camera_matrices.eye_position())
+        } else {
+            Vec3::ZERO
+        };
+
+        let shift = origin - self.render_origin;
+        if shift == Vec3::ZERO {
+            return;
+        }
+
+        debug!("Moving the render origin to {:?}", origin);
+
+        for inst in &mut self.instances {
+            inst.transformation = rebase_transform(inst.transformation, shift);
+            inst.prev_transformation = rebase_transform(inst.prev_transformation, shift);
+        }
+        for inst in &mut self.procedural_instances {
+            inst.transformation = rebase_transform(inst.transformation, shift);
+        }
+        self.render_origin = origin;
+
+        // The GI volumes hold radiance in render space, which no longer lines up with the scene.
+        self.csgi.reset();
+        self.ddgi.reset();
+    }
+
+    // Picks the mesh each instance draws this frame. The reference path tracer accumulates
+    // over many frames, and always uses the meshes the instances were added with.
+    //
+    // Instance transforms are relative to the render origin, so the camera must be too;
+    // see `render_space_frame_desc`.
+    fn select_mesh_lods(&mut self, render_space_camera: &CameraMatrices, frame_index: u32) {
+        for (inst, handle) in self.instances.iter_mut().zip(&self.instance_handles) {
+            let lods = &self.mesh_lods[inst.base_mesh.0];
+
+            inst.mesh = if lods.is_empty() || self.render_mode == RenderMode::Reference {
+                inst.base_mesh
+            } else {
+                let screen_size = instance_screen_size(
+                    render_space_camera,
+                    &inst.transformation,
+                    self.mesh_bounding_radii[inst.base_mesh.0],
+                );
+                let noise = lod_selection_noise(handle.0, frame_index);
+
+                select_mesh_lod(lods, screen_size, self.lod_transition_width, noise)
+                    .map_or(inst.base_mesh, |lod| lods[lod].mesh)
+            };
+        }
+    }
+
+    // `frame_desc` as seen from the render origin
+    fn render_space_frame_desc(&self, frame_desc: &WorldFrameDesc) -> WorldFrameDesc {
+        WorldFrameDesc {
+            camera_matrices: rebase_camera_matrices(
+                &frame_desc.camera_matrices,
+                self.render_origin,
+            ),
+            render_extent: frame_desc.render_extent,
+            sun_direction: frame_desc.sun_direction,
+            time: frame_desc.time,
+        }
+    }
+
+    pub fn set_instance_transparency(
+        &mut self,
+        inst: InstanceHandle,
+        transparency: Option<TransparentMaterial>,
+    ) {
+        let index = self.instance_handle_to_index[&inst];
+        self.instances[index].transparency = transparency;
+    }
+
+    /// Outlines the instance with one of the `outline` renderer's highlight colors, or removes its outline.
+    pub fn set_instance_highlight(&mut self, inst: InstanceHandle, highlight: Option<usize>) {
+        let index = self.instance_handle_to_index[&inst];
+        self.instances[index].highlight = highlight;
+    }
+
+    /// Overlays the edges of the instance's triangles; see `WireframeRenderer`.
+    pub fn set_instance_wireframe(&mut self, inst: InstanceHandle, wireframe: bool) {
+        let index = self.instance_handle_to_index[&inst];
+        self.instances[index].wireframe = wireframe;
+    }
+
+    /// Names the instance in the Cryptomatte; unnamed instances are called `instance<N>`,
+    /// after their handles. Handles of removed instances are ignored.
+    pub fn set_instance_name(&mut self, inst: InstanceHandle, name: impl Into<String>) {
+        if self.instance_handle_to_index.contains_key(&inst) {
+            self.instance_names.insert(inst, name.into());
+        }
+    }
+
+    pub fn instance_name(&self, inst: InstanceHandle) -> String {
+        self.instance_names
+            .get(&inst)
+            .cloned()
+            .unwrap_or_else(|| format!("instance{}", inst.0))
+    }
+
+    /// The names and Cryptomatte IDs of the current instances, for `write_cryptomatte_exr`.
+    pub fn cryptomatte_manifest(&self) -> CryptomatteManifest {
+        let names: Vec<String> = self
+            .instance_handles
+            .iter()
+            .map(|inst| self.instance_name(*inst))
+            .collect();
+        CryptomatteManifest::new(names.iter().map(String::as_str))
+    }
+
+    /// Schedules a readback of the instance visible at pixel `[x, y]` of the output image,
+    /// accounting for the distortion of `lens`. The result is available from `poll_pick`
+    /// once the GPU is done with the next frame.
+    pub fn request_pick(&mut self, x: i32, y: i32) -> PickToken {
+        let [width, height] = self.temporal_upscale_extent;
+        let mut uv = Vec2::new(
+            (x as f32 + 0.5) / width as f32,
+            (y as f32 + 0.5) / height as f32,
+        );
+
+        // `source_uv` clamps to the image, so positions outside of it are left as they are.
+        if self.lens.is_active() && uv.cmpge(Vec2::ZERO).all() && uv.cmple(Vec2::ONE).all() {
+            uv = self.lens.source_uv(uv, width as f32 / height as f32);
+        }
+
+        self.picking.request(uv.into())
+    }
+
+    /// `Poll::Ready` with the instance picked by `token`, or `None` over the background and outside
+    /// of the image; `Poll::Pending` until the GPU is done with the frame which read it.
+    pub fn poll_pick(&mut self, token: PickToken) -> Poll<Option<InstanceHandle>> {
+        self.picking.poll(self.device.as_ref(), token)
+    }
+
+    /// Schedules a readback of the signal of `effect` before and after denoising, in the next frame.
+    /// The denoiser is controlled by `rtdgi.denoiser` and `rtr.denoiser`.
+    pub fn request_denoiser_dump(&mut self, effect: DenoisedEffect) -> DenoiserDumpToken {
+        self.denoiser_dumps.request(effect)
+    }
+
+    /// `Poll::Ready` with the buffers dumped for `token`, or `None` if the effect wasn't rendered
+    /// in that frame; `Poll::Pending` until the GPU is done with it.
+    pub fn poll_denoiser_dump(&mut self, token: DenoiserDumpToken) -> Poll<Option<DenoiserDump>> {
+        self.denoiser_dumps.poll(self.device.as_ref(), token)
+    }
+
+    /// The image of the `kind` AOV in the frame being recorded, if it's in `aovs`, and available
+    /// in the current render mode. Call after `prepare_render_graph`, in the same render graph.
+    /// Each AOV is returned once per frame.
+    pub fn get_aov(&mut self, kind: AovKind) -> Option<rg::Handle<Image>> {
+        self.frame_aovs.remove(&kind)
+    }
+
+    /// Schedules a readback of the `kind` AOV in the next frame, whether or not it's in `aovs`.
+    /// `ReadbackImage::write_exr` exports the result.
+    pub fn request_aov_readback(&mut self, kind: AovKind) -> AovReadbackToken {
+        self.aov_readbacks.request(kind)
+    }
+
+    /// `Poll::Ready` with the AOV read back for `token`, or `None` if it wasn't available
+    /// in that frame; `Poll::Pending` until the GPU is done with it.
+    pub fn poll_aov_readback(&mut self, token: AovReadbackToken) -> Poll<Option<ReadbackImage>> {
+        self.aov_readbacks.poll(self.device.as_ref(), token)
+    }
+
+    /// Schedules a readback of the next frame's final image, as returned by `prepare_render_graph`,
+    /// for `poll_aov_readback`.
+    pub fn request_final_image_readback(&mut self) -> AovReadbackToken {
+        self.aov_readbacks.request_beauty()
+    }
+
+    /// Schedules an export of the next frame's lit image before tonemapping, along with `aovs`,
+    /// to a multi-layer OpenEXR file at `path`. The file is written by `poll_exr_export` once all
+    /// the images are read back; see `multilayer_exr::write_multilayer_exr` for its layout.
+    pub fn export_multilayer_exr(
+        &mut self,
+        path: impl Into<PathBuf>,
+        aovs: &[AovKind],
+    ) -> ExrExportToken {
+        let cryptomatte = aovs
+            .iter()
+            .any(|kind| kind.cryptomatte_layer().is_some())
+            .then(|| self.cryptomatte_manifest());
+
+        self.exr_exports
+            .request(&mut self.aov_readbacks, path.into(), aovs, cryptomatte)
+    }
+
+    /// `Poll::Ready` with the result of writing the file for `token`, or `Poll::Pending`
+    /// until the GPU is done with its frame.
+    pub fn poll_exr_export(&mut self, token: ExrExportToken) -> Poll<anyhow::Result<()>> {
+        self.exr_exports
+            .poll(self.device.as_ref(), &mut self.aov_readbacks, token)
+    }
+
+    /// Schedules a denoise of the reference accumulation as of the next frame, with Open Image
+    /// Denoise, guided by the albedo and normal AOVs. Once `poll_oidn_denoise` has the result,
+    /// it's shown instead of the accumulation, until that's reset.
+    #[cfg(feature = "oidn")]
+    pub fn request_oidn_denoise(&mut self) -> OidnDenoiseToken {
+        self.oidn.request(&mut self.aov_readbacks)
+    }
+
+    /// `Poll::Ready` with the denoised radiance for `token`, or an error if the next frame wasn't
+    /// rendered in reference mode, or the library isn't installed; `Poll::Pending` until the GPU
+    /// is done with the frame.
+    #[cfg(feature = "oidn")]
+    pub fn poll_oidn_denoise(
+        &mut self,
+        token: OidnDenoiseToken,
+    ) -> Poll<anyhow::Result<ReadbackImage>> {
+        self.oidn
+            .poll(self.device.as_ref(), &mut self.aov_readbacks, token)
     }
 
     pub fn get_instance_dynamic_parameters(
@@ -687,23 +1427,60 @@ impl WorldRenderer {
         &mut self.instances[index].dynamic_parameters
     }
 
+    fn ray_tracing_instance_desc(&self, inst: &MeshInstance) -> RayTracingInstanceDesc {
+        let flags = self.mesh_rt_flags[inst.mesh.0];
+
+        RayTracingInstanceDesc {
+            blas: self.mesh_blas[inst.mesh.0].clone(),
+            transformation: inst.transformation,
+            mesh_index: inst.mesh.0 as u32,
+            opaque: flags.opaque,
+            double_sided: flags.double_sided,
+            hit_group_offset: 0,
+        }
+    }
+
+    // Mesh instances first, so that `InstanceIndex()` indexes their dynamic parameters.
+    // Also gathers the hit records of procedural instances, which the offsets refer to.
+    fn ray_tracing_instance_descs(&mut self) -> Vec<RayTracingInstanceDesc> {
+        let mut descs: Vec<RayTracingInstanceDesc> = self
+            .instances
+            .iter()
+            .map(|inst| self.ray_tracing_instance_desc(inst))
+            .collect();
+
+        self.procedural_hit_groups.clear_hit_records();
+        for inst in &self.procedural_instances {
+            let hit_group_offset = match &inst.shader_record_data {
+                Some(data) => self
+                    .procedural_hit_groups
+                    .push_hit_records(inst.hit_groups, data.clone()),
+                None => inst.hit_groups.hit_group_offset(),
+            };
+
+            descs.push(RayTracingInstanceDesc {
+                blas: self.procedural_blas[inst.blas.0].blas.clone(),
+                transformation: inst.transformation,
+                mesh_index: 0,
+                opaque: true,
+                double_sided: true,
+                hit_group_offset,
+            });
+        }
+
+        descs
+    }
+
     pub(crate) fn build_ray_tracing_top_level_acceleration(&mut self) {
         let tlas = self
             .device
             .create_ray_tracing_top_acceleration(
                 &RayTracingTopAccelerationDesc {
                     //instances: self.mesh_blas.iter().collect::<Vec<_>>(),
-                    instances: self
-                        .instances
-                        .iter()
-                        .map(|inst| RayTracingInstanceDesc {
-                            blas: self.mesh_blas[inst.mesh.0].clone(),
-                            transformation: inst.transformation,
-                            mesh_index: inst.mesh.0 as u32,
-                        })
-                        .collect::<Vec<_>>(),
+                    instances: self.ray_tracing_instance_descs(),
                     preallocate_bytes: TLAS_PREALLOCATE_BYTES,
                 },
+                "TLAS",
                 &self.accel_scratch,
             )
             .expect("tlas");
@@ -711,9 +1488,20 @@ impl WorldRenderer {
         self.tlas = Some(Arc::new(tlas));
     }
 
-    #[allow(dead_code)]
-    pub fn reset_frame_idx(&mut self) {
-        self.frame_idx = 0;
+    /// Resolution of the output image, after temporal upsampling.
+    pub fn temporal_upscale_extent(&self) -> [u32; 2] {
+        self.temporal_upscale_extent
+    }
+
+    /// Changes the output resolution of temporal upsampling, e.g. after a window resize.
+    /// Temporal resources are re-created on the next frame as their descs change.
+    pub fn set_temporal_upscale_extent(&mut self, temporal_upscale_extent: [u32; 2]) {
+        self.temporal_upscale_extent = temporal_upscale_extent;
+
+        // The DLSS feature is created for a fixed output resolution.
+        #[cfg(feature = "dlss")]
+        self.dlss
+            .set_target_resolution(&self.device, temporal_upscale_extent);
     }
 
     pub(super) fn prepare_top_level_acceleration(
@@ -725,15 +1513,7 @@ impl WorldRenderer {
             vk_sync::AccessType::AnyShaderReadOther,
         );
 
-        let instances = self
-            .instances
-            .iter()
-            .map(|inst| RayTracingInstanceDesc {
-                blas: self.mesh_blas[inst.mesh.0].clone(),
-                transformation: inst.transformation,
-                mesh_index: inst.mesh.0 as u32,
-            })
-            .collect::<Vec<_>>();
+        let instances = self.ray_tracing_instance_descs();
 
         let mut pass = rg.add_pass("rebuild tlas");
         let tlas_ref = pass.write(&mut tlas, AccessType::TransferWrite);
@@ -774,7 +1554,7 @@ impl WorldRenderer {
         frame_desc: &WorldFrameDesc,
     ) -> rg::Handle<Image> {
         rg.predefined_descriptor_set_layouts.insert(
-            1,
+            rg::BINDLESS_DESCRIPTOR_SET_IDX,
             rg::PredefinedDescriptorSet {
                 bindings: BINDLESS_DESCRIPTOR_SET_LAYOUT.clone(),
             },
@@ -784,10 +1564,19 @@ impl WorldRenderer {
             image_lut.compute_if_needed(rg);
         }
 
-        match self.render_mode {
+        self.picking.begin_frame();
+        self.denoiser_dumps.begin_frame();
+        self.aov_readbacks.begin_frame();
+        self.frame_aovs.clear();
+
+        self.update_render_origin(frame_desc);
+        let frame_desc = &self.render_space_frame_desc(frame_desc);
+        self.select_mesh_lods(&frame_desc.camera_matrices, frame_desc.time.frame_index);
+
+        let output = match self.render_mode {
             RenderMode::Standard => {
                 self.taa.current_supersample_offset = self.supersample_offsets
-                    [self.frame_idx as usize % self.supersample_offsets.len()];
+                    [frame_desc.time.frame_index as usize % self.supersample_offsets.len()];
                 //self.taa.current_supersample_offset = Vec2::ZERO;
 
                 #[cfg(feature = "dlss")]
@@ -805,21 +1594,36 @@ impl WorldRenderer {
                     self.dlss.current_supersample_offset = self.taa.current_supersample_offset;
                 }
 
+                // Not drawn over the reference
+                self.debug_draw.clear();
+
                 self.prepare_render_graph_reference(rg, frame_desc)
             }
-        }
+        };
+
+        self.aov_readbacks.copy_beauty_to_host(rg, &output);
+
+        self.denoiser_dumps.skip_remaining();
+        self.aov_readbacks.skip_remaining();
+
+        output
     }
 
     pub fn prepare_frame_constants(
         &mut self,
         dynamic_constants: &mut DynamicConstants,
         frame_desc: &WorldFrameDesc,
-        delta_time_seconds: f32,
     ) -> FrameConstantsLayout {
+        // The previous camera is kept in world space, as the render origin may have moved since.
+        let prev_camera_matrices = self
+            .prev_camera_matrices
+            .unwrap_or(frame_desc.camera_matrices);
+        self.prev_camera_matrices = Some(frame_desc.camera_matrices);
+
+        let frame_desc = &self.render_space_frame_desc(frame_desc);
         let mut view_constants = ViewConstants::builder(
             frame_desc.camera_matrices,
-            self.prev_camera_matrices
-                .unwrap_or(frame_desc.camera_matrices),
+            rebase_camera_matrices(&prev_camera_matrices, self.render_origin),
             frame_desc.render_extent,
         )
         .build();
@@ -886,21 +1690,23 @@ impl WorldRenderer {
         }
 
         let real_sun_angular_radius = 0.53f32.to_radians() * 0.5;
+        let (ray_origin_offset_method, ray_origin_offset_scale) =
+            self.ray_origin_offset.method_and_scale();
 
         let globals_offset = dynamic_constants.push(&FrameConstants {
             view_constants,
             sun_direction: frame_desc.sun_direction.extend(0.0),
-            frame_index: self.frame_idx,
-            delta_time_seconds,
+            frame_index: frame_desc.time.frame_index,
+            delta_time_seconds: frame_desc.time.dt,
             sun_angular_radius_cos: (self.sun_size_multiplier * real_sun_angular_radius).cos(),
 
             sun_color_multiplier: self.sun_color_multiplier.extend(0.0),
             sky_ambient: self.sky_ambient.extend(0.0),
             triangle_light_count: triangle_lights.len() as _,
             world_gi_scale: self.world_gi_scale,
+            ray_origin_offset_method,
+            ray_origin_offset_scale,
             pad0: 0,
-            pad1: 0,
-            pad2: 0,
             gi_cascades,
         });
 
@@ -910,8 +1716,6 @@ impl WorldRenderer {
         let triangle_lights_offset: u32 =
             dynamic_constants.push_from_iter(triangle_lights.into_iter());
 
-        self.prev_camera_matrices = Some(frame_desc.camera_matrices);
-
         rg::renderer::FrameConstantsLayout {
             globals_offset,
             instance_dynamic_parameters_offset,
@@ -920,7 +1724,20 @@ impl WorldRenderer {
     }
 
     pub fn retire_frame(&mut self) {
-        self.frame_idx = self.frame_idx.overflowing_add(1).0;
+        self.picking.retire_frame(self.device.frame_counter());
+        self.denoiser_dumps
+            .retire_frame(self.device.frame_counter());
+        self.aov_readbacks.retire_frame(self.device.frame_counter());
+        self.atmosphere.retire_frame();
+        self.particles.retire_frame();
+        self.csgi.retire_frame();
+        self.ddgi.retire_frame();
+        self.csm.retire_frame();
+        self.point_lights.retire_frame();
+        self.environment_cdf.retire_frame();
+        self.cryptomatte.retire_frame();
+        #[cfg(feature = "oidn")]
+        self.oidn.retire_frame();
         self.store_prev_mesh_transforms();
     }
 }
@@ -939,3 +1756,137 @@ fn radical_inverse(mut n: u32, base: u32) -> f32 {
 
     val
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kajiya_backend::{
+        file::set_standard_vfs_mount_points, struct_layout, struct_layout::StructLayout,
+        HeadlessRenderBackend,
+    };
+    use rg::{readback::AsyncReadback, renderer::Renderer, SimpleRenderPass};
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn robust_ray_origin_offsets_clear_surfaces_at_large_coordinates() {
+        set_standard_vfs_mount_points(concat!(env!("CARGO_MANIFEST_DIR"), "/../../.."));
+
+        let backend = HeadlessRenderBackend::new(Default::default()).unwrap();
+        let device = backend.device.clone();
+        let mut renderer = Renderer::new(&device).unwrap();
+        let mut readback = AsyncReadback::default();
+
+        const POINT_COUNT: usize = 64;
+
+        // Distances of offset ray origins above a plane a million units away
+        let mut distances = |offset: RayOriginOffset| -> Vec<f32> {
+            readback.begin_frame();
+
+            let mut token = None;
+            renderer
+                .prepare_frame(|rg| {
+                    let mut output = rg.create(BufferDesc::new_gpu_only(
+                        POINT_COUNT * size_of::<f32>(),
+                        vk::BufferUsageFlags::STORAGE_BUFFER,
+                    ));
+                    let (method, scale) = offset.method_and_scale();
+                    SimpleRenderPass::new_compute(
+                        rg.add_pass("offset ray origins"),
+                        "/shaders/tests/ray_origin_offset.hlsl",
+                    )
+                    .write(&mut output)
+                    .push_constants(&[method, scale.to_bits()])
+                    .dispatch([POINT_COUNT as u32, 1, 1]);
+
+                    token = Some(readback.copy_buffer(rg, &output));
+                })
+                .unwrap();
+            renderer
+                .draw_frame_headless(|_| FrameConstantsLayout {
+                    globals_offset: 0,
+                    instance_dynamic_parameters_offset: 0,
+                    triangle_lights_offset: 0,
+                })
+                .unwrap();
+            readback.retire_frame(device.frame_counter());
+
+            readback
+                .wait(&device, token.unwrap())
+                .unwrap()
+                .chunks_exact(4)
+                .map(|word| f32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+                .collect()
+        };
+
+        // Far below the precision of the coordinates, a fixed offset leaves origins
+        // where they were, and those rounded below the surface hit it.
+        let epsilon = distances(RayOriginOffset::Epsilon(1e-4));
+        assert!(
+            epsilon.iter().any(|&distance| distance <= 0.0),
+            "{:?}",
+            epsilon
+        );
+
+        let robust = distances(RayOriginOffset::default());
+        assert!(
+            robust.iter().all(|&distance| distance > 0.0),
+            "{:?}",
+            robust
+        );
+    }
+
+    #[test]
+    #[ignore = "needs the DXC shader compiler"]
+    fn frame_constants_match_the_shader_layout() {
+        set_standard_vfs_mount_points(concat!(env!("CARGO_MANIFEST_DIR"), "/../../.."));
+
+        // Padding is left out; the offsets of the members after it, and the sizes, check it.
+        for cpu in [
+            struct_layout!(FrameConstants {
+                view_constants,
+                sun_direction,
+                frame_index,
+                delta_time_seconds,
+                sun_angular_radius_cos,
+                triangle_light_count,
+                sun_color_multiplier,
+                sky_ambient,
+                world_gi_scale,
+                ray_origin_offset_method,
+                ray_origin_offset_scale,
+                gi_cascades,
+            }),
+            struct_layout!(ViewConstants {
+                view_to_clip,
+                clip_to_view,
+                view_to_sample,
+                sample_to_view,
+                world_to_view,
+                view_to_world,
+                clip_to_prev_clip,
+                prev_view_to_prev_clip,
+                prev_clip_to_prev_view,
+                prev_world_to_prev_view,
+                prev_view_to_prev_world,
+                sample_offset_pixels,
+                sample_offset_clip,
+            }),
+            // Also checks the stride of `gi_cascades`
+            struct_layout!(GiCascadeConstants {
+                scroll_frac,
+                scroll_int,
+                voxels_scrolled_this_frame,
+                volume_size,
+                voxel_size,
+            }),
+        ] {
+            StructLayout::from_hlsl(
+                "/shaders/tests/frame_constants_layout.hlsl",
+                "cs",
+                &cpu.name,
+            )
+            .and_then(|shader| shader.check(&cpu))
+            .unwrap_or_else(|err| panic!("{:#}", err));
+        }
+    }
+}
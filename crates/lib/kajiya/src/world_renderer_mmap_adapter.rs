@@ -1,6 +1,9 @@
 use kajiya_asset::mesh::PackedTriMesh;
 
-use crate::world_renderer::{AddMeshOptions, MeshHandle, WorldRenderer};
+use crate::{
+    scene_file::SceneMesh,
+    world_renderer::{AddMeshOptions, MeshHandle, WorldRenderer},
+};
 
 impl WorldRenderer {
     pub fn add_baked_mesh(
@@ -8,9 +11,16 @@ impl WorldRenderer {
         path: impl Into<std::path::PathBuf>,
         opts: AddMeshOptions,
     ) -> anyhow::Result<MeshHandle> {
-        Ok(self.add_mesh(
-            crate::mmap::mmapped_asset::<PackedTriMesh::Flat, _>(path)?,
+        let path = path.into();
+        let use_lights = opts.use_lights;
+
+        let mesh = self.add_mesh(
+            crate::mmap::mmapped_asset::<PackedTriMesh::Flat, _>(&path)?,
             opts,
-        ))
+        );
+
+        self.mesh_sources[mesh.0] = Some(SceneMesh { path, use_lights });
+
+        Ok(mesh)
     }
 }
@@ -31,9 +31,9 @@ pub struct FrameConstants {
     pub sky_ambient: Vec4,
 
     pub world_gi_scale: f32,
+    pub ray_origin_offset_method: u32,
+    pub ray_origin_offset_scale: f32,
     pub pad0: u32,
-    pub pad1: u32,
-    pub pad2: u32,
 
     pub gi_cascades: [GiCascadeConstants; MAX_CSGI_CASCADE_COUNT],
 }
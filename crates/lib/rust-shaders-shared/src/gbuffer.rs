@@ -19,8 +19,18 @@ pub struct GbufferData {
     pub normal: Vec3,
     pub roughness: f32,
     pub metalness: f32,
+    pub anisotropy: f32,
+    pub iridescence: f32,
+    pub iridescence_ior: f32,
+    pub iridescence_thickness: f32,
 }
 
+// Matches `gbuffer.hlsl`
+const GBUFFER_MAX_IRIDESCENCE_THICKNESS: f32 = 1200.0;
+const GBUFFER_MIN_IRIDESCENCE_IOR: f32 = 1.0;
+const GBUFFER_MAX_IRIDESCENCE_IOR: f32 = 2.5;
+const GBUFFER_IRIDESCENCE_BIT: u32 = 0x80;
+
 pub fn roughness_to_perceptual_roughness(r: f32) -> f32 {
     r.sqrt()
 }
@@ -34,15 +44,36 @@ impl GbufferData {
     pub fn pack(&self) -> GbufferDataPacked {
         #[cfg(not(target_arch = "spirv"))]
         return GbufferDataPacked { v: UVec4::ZERO };
+
+        // The anisotropy direction isn't mirrored, so it's left at the first axis of the basis.
+        #[cfg(target_arch = "spirv")]
+        let (albedo_extra, extra) = if self.iridescence > 0.0 {
+            (
+                pack_unorm(
+                    self.iridescence_thickness / GBUFFER_MAX_IRIDESCENCE_THICKNESS,
+                    8,
+                ) << 24,
+                GBUFFER_IRIDESCENCE_BIT
+                    | (pack_unorm(self.iridescence, 3).max(1) << 4)
+                    | pack_unorm(
+                        (self.iridescence_ior - GBUFFER_MIN_IRIDESCENCE_IOR)
+                            / (GBUFFER_MAX_IRIDESCENCE_IOR - GBUFFER_MIN_IRIDESCENCE_IOR),
+                        4,
+                    ),
+            )
+        } else {
+            (0, pack_unorm(self.anisotropy, 7))
+        };
+
         #[cfg(target_arch = "spirv")]
         GbufferDataPacked {
             v: UVec4::new(
-                pack_color_888(self.albedo),
+                pack_color_888(self.albedo) | albedo_extra,
                 pack_normal_11_10_11(self.normal).to_bits(),
-                vec2_to_f16x2(vec2(
-                    roughness_to_perceptual_roughness(self.roughness),
-                    self.metalness,
-                )),
+                (vec2_to_f16x2(vec2(roughness_to_perceptual_roughness(self.roughness), 0.0))
+                    & 0xffff)
+                    | (pack_unorm(self.metalness, 8) << 16)
+                    | (extra << 24),
                 float3_to_rgb9e5(self.emissive),
             ),
         }
@@ -52,16 +83,41 @@ impl GbufferData {
 impl GbufferDataPacked {
     pub fn unpack(&self) -> GbufferData {
         #[cfg(not(target_arch = "spirv"))]
-        let roughness_metalness: Vec2 = Vec2::new(0.0, 0.0);
+        let perceptual_roughness: f32 = 0.0;
         #[cfg(target_arch = "spirv")]
-        let roughness_metalness: Vec2 = f16x2_to_vec2(self.v.z);
+        let perceptual_roughness: f32 = f16x2_to_vec2(self.v.z & 0xffff).x;
+
+        let extra = self.v.z >> 24;
+        let iridescent = extra & GBUFFER_IRIDESCENCE_BIT != 0;
 
         GbufferData {
             albedo: self.unpack_albedo(),
             emissive: rgb9e5_to_float3(self.v.w),
             normal: self.unpack_normal(),
-            roughness: perceptual_roughness_to_roughness(roughness_metalness.x),
-            metalness: roughness_metalness.y,
+            roughness: perceptual_roughness_to_roughness(perceptual_roughness),
+            metalness: unpack_unorm(self.v.z >> 16, 8),
+            anisotropy: if iridescent {
+                0.0
+            } else {
+                unpack_unorm(extra, 7)
+            },
+            iridescence: if iridescent {
+                unpack_unorm(extra >> 4, 3)
+            } else {
+                0.0
+            },
+            iridescence_ior: if iridescent {
+                GBUFFER_MIN_IRIDESCENCE_IOR
+                    + (GBUFFER_MAX_IRIDESCENCE_IOR - GBUFFER_MIN_IRIDESCENCE_IOR)
+                        * unpack_unorm(extra, 4)
+            } else {
+                1.0
+            },
+            iridescence_thickness: if iridescent {
+                unpack_unorm(self.v.x >> 24, 8) * GBUFFER_MAX_IRIDESCENCE_THICKNESS
+            } else {
+                0.0
+            },
         }
     }
 
@@ -68,21 +68,19 @@ pub fn signum_f32(x: f32) -> f32 {
 }
 
 pub fn depth_to_view_z(depth: f32, frame_constants: &FrameConstants) -> f32 {
-    (depth
-        * -frame_constants
-            .view_constants
-            .clip_to_view
-            .to_cols_array_2d()[2][3])
-        .recip()
+    let clip_to_view = frame_constants
+        .view_constants
+        .clip_to_view
+        .to_cols_array_2d();
+    (-(depth * clip_to_view[2][3] + clip_to_view[3][3])).recip()
 }
 
 pub fn depth_to_view_z_vec4(depth: Vec4, frame_constants: &FrameConstants) -> Vec4 {
-    (depth
-        * -frame_constants
-            .view_constants
-            .clip_to_view
-            .to_cols_array_2d()[2][3])
-        .recip()
+    let clip_to_view = frame_constants
+        .view_constants
+        .clip_to_view
+        .to_cols_array_2d();
+    (-(depth * clip_to_view[2][3] + Vec4::splat(clip_to_view[3][3]))).recip()
 }
 
 // Note: `const_mat3` is initialized with columns, while `float3x3` in HLSL is row-order,
@@ -146,12 +144,12 @@ pub fn cs_to_uv(cs: Vec2) -> Vec2 {
     cs * Vec2::new(0.5, -0.5) + Vec2::new(0.5, 0.5)
 }
 
-fn pack_unorm(val: f32, bit_count: u32) -> u32 {
+pub fn pack_unorm(val: f32, bit_count: u32) -> u32 {
     let max_val = (1u32 << bit_count) - 1;
     (val.clamp(0.0, 1.0) * max_val as f32) as u32
 }
 
-fn unpack_unorm(pckd: u32, bit_count: u32) -> f32 {
+pub fn unpack_unorm(pckd: u32, bit_count: u32) -> f32 {
     let max_val = (1u32 << bit_count) - 1;
     (pckd & max_val) as f32 / max_val as f32
 }